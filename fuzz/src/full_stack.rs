@@ -14,6 +14,7 @@
 //! This test has been very useful, though due to its complexity good starting inputs are critical.
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::opcodes;
@@ -262,10 +263,10 @@ impl KeysInterface for KeyProvider {
 		PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap())
 	}
 
-	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> EnforcingChannelKeys {
+	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> Result<EnforcingChannelKeys, ()> {
 		let ctr = self.counter.fetch_add(1, Ordering::Relaxed) as u8;
 		let secp_ctx = Secp256k1::signing_only();
-		EnforcingChannelKeys::new(if inbound {
+		Ok(EnforcingChannelKeys::new(if inbound {
 			InMemoryChannelKeys::new(
 				&secp_ctx,
 				SecretKey::from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, ctr]).unwrap(),
@@ -289,7 +290,7 @@ impl KeysInterface for KeyProvider {
 				channel_value_satoshis,
 				(0, 0),
 			)
-		})
+		}))
 	}
 
 	fn get_secure_random_bytes(&self) -> [u8; 32] {
@@ -342,7 +343,7 @@ pub fn do_test(data: &[u8], logger: &Arc<dyn Logger>) {
 	config.peer_channel_config_limits.min_dust_limit_satoshis = 0;
 	let channelmanager = Arc::new(ChannelManager::new(Network::Bitcoin, fee_est.clone(), monitor.clone(), broadcast.clone(), Arc::clone(&logger), keys_manager.clone(), config, 0));
 	let our_id = PublicKey::from_secret_key(&Secp256k1::signing_only(), &keys_manager.get_node_secret());
-	let net_graph_msg_handler = Arc::new(NetGraphMsgHandler::new(None, Arc::clone(&logger)));
+	let net_graph_msg_handler = Arc::new(NetGraphMsgHandler::new(genesis_block(Network::Bitcoin).header.block_hash(), None, Arc::clone(&logger)));
 
 	let peers = RefCell::new([false; 256]);
 	let mut loss_detector = MoneyLossDetector::new(&peers, channelmanager.clone(), monitor.clone(), PeerManager::new(MessageHandler {
@@ -564,6 +565,8 @@ pub fn do_test(data: &[u8], logger: &Arc<dyn Logger>) {
 					should_forward = true;
 				},
 				Event::SpendableOutputs {..} => {},
+				Event::ProbeSuccessful {..} => {},
+				Event::ProbeFailed {..} => {},
 			}
 		}
 	}
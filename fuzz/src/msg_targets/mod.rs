@@ -17,15 +17,15 @@ pub mod msg_update_fee;
 pub mod msg_update_fulfill_htlc;
 pub mod msg_channel_announcement;
 pub mod msg_node_announcement;
-pub mod msg_query_short_channel_ids;
 pub mod msg_reply_short_channel_ids_end;
-pub mod msg_query_channel_range;
-pub mod msg_reply_channel_range;
 pub mod msg_gossip_timestamp_filter;
-pub mod msg_update_add_htlc;
 pub mod msg_error_message;
 pub mod msg_channel_update;
 pub mod msg_init;
 pub mod msg_onion_hop_data;
 pub mod msg_ping;
 pub mod msg_pong;
+pub mod msg_query_short_channel_ids;
+pub mod msg_query_channel_range;
+pub mod msg_reply_channel_range;
+pub mod msg_update_add_htlc;
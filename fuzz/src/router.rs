@@ -7,9 +7,11 @@
 // You may not use this file except in accordance with one or both of these
 // licenses.
 
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::script::Builder;
 use bitcoin::blockdata::transaction::TxOut;
 use bitcoin::hash_types::BlockHash;
+use bitcoin::network::constants::Network;
 
 use lightning::chain;
 use lightning::ln::channelmanager::ChannelDetails;
@@ -159,7 +161,7 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 	};
 
 	let our_pubkey = get_pubkey!();
-	let net_graph_msg_handler = NetGraphMsgHandler::new(chain_source, Arc::clone(&logger));
+	let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Bitcoin).header.block_hash(), chain_source, Arc::clone(&logger));
 
 	loop {
 		match get_slice!(1)[0] {
@@ -226,6 +228,7 @@ pub fn do_test<Out: test_logger::Output>(data: &[u8], out: Out) {
 							},
 							cltv_expiry_delta: slice_to_be16(get_slice!(2)),
 							htlc_minimum_msat: slice_to_be64(get_slice!(8)),
+							htlc_maximum_msat: None,
 						});
 					}
 					&last_hops_vec[..]
@@ -538,6 +538,14 @@ mod tests {
 		fn get_next_channel_announcements(&self, _starting_point: u64, _batch_amount: u8) -> Vec<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> { Vec::new() }
 		fn get_next_node_announcements(&self, _starting_point: Option<&PublicKey>, _batch_amount: u8) -> Vec<NodeAnnouncement> { Vec::new() }
 		fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool { false }
+		fn sync_routing_table(&self, _their_node_id: &PublicKey, _full_sync: bool) -> (Option<GossipTimestampFilter>, Option<QueryChannelRange>) { (None, None) }
+		fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, LightningError> { Ok(Vec::new()) }
+		fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &ReplyChannelRange) -> Result<Option<QueryShortChannelIds>, LightningError> { Ok(None) }
+		fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(Vec<(Option<ChannelAnnouncement>, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>, ReplyShortChannelIdsEnd), LightningError> {
+			Ok((Vec::new(), Vec::new(), ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: false }))
+		}
+		fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &ReplyShortChannelIdsEnd) -> Result<Option<QueryShortChannelIds>, LightningError> { Ok(None) }
+		fn handle_gossip_timestamp_filter(&self, _their_node_id: &PublicKey, _msg: &GossipTimestampFilter) -> Result<(), LightningError> { Ok(()) }
 	}
 	impl ChannelMessageHandler for MsgHandler {
 		fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &OpenChannel) {}
@@ -565,6 +573,7 @@ mod tests {
 				self.pubkey_connected.clone().try_send(()).unwrap();
 			}
 		}
+		fn provided_init_features(&self) -> InitFeatures { InitFeatures::known() }
 		fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {}
 		fn handle_error(&self, _their_node_id: &PublicKey, _msg: &ErrorMessage) {}
 	}
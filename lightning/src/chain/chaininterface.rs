@@ -11,7 +11,8 @@
 //! blockchain.
 //!
 //! Includes traits for monitoring and receiving notifications of new blocks and block
-//! disconnections, transaction broadcasting, and feerate information requests.
+//! disconnections, transaction broadcasting, and feerate information requests, as well as the
+//! policy used to bump the fee of on-chain claims.
 
 use bitcoin::blockdata::transaction::Transaction;
 
@@ -23,6 +24,7 @@ pub trait BroadcasterInterface: Sync + Send {
 
 /// An enum that represents the speed at which we want a transaction to confirm used for feerate
 /// estimation.
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ConfirmationTarget {
 	/// We are happy with this transaction confirming slowly when feerate drops some.
 	Background,
@@ -51,3 +53,70 @@ pub trait FeeEstimator: Sync + Send {
 
 /// Minimum relay fee as required by bitcoin network mempool policy.
 pub const MIN_RELAY_FEE_SAT_PER_1000_WEIGHT: u64 = 4000;
+
+/// A trait which can be implemented to control how the time-sensitive transactions claiming
+/// channel outputs on-chain (e.g. justice transactions or HTLC claims) are fee-bumped until they
+/// confirm, trading off the fees paid against the risk of our counterparty claiming the outputs
+/// first once a timelock expires.
+///
+/// Each method is given the number of blocks left before the soonest timelock among the claimed
+/// outputs expires (0 if it already has), allowing policies to get more aggressive as it
+/// approaches. All feerates are in satoshis per 1000 Weight-Units.
+///
+/// If the [`FeeEstimator`] gives back a higher feerate for the [`initial_confirmation_target`] than
+/// the one of the previous version of a claim transaction, it is used instead of bumping. Either
+/// way, new versions always pay at least the minimum increase required by BIP 125.
+///
+/// See [`DefaultFeeBumpPolicy`] for the policy used unless another one is provided.
+///
+/// [`FeeEstimator`]: trait.FeeEstimator.html
+/// [`initial_confirmation_target`]: trait.FeeBumpPolicy.html#tymethod.initial_confirmation_target
+/// [`DefaultFeeBumpPolicy`]: struct.DefaultFeeBumpPolicy.html
+pub trait FeeBumpPolicy: Sync + Send {
+	/// Gets the confirmation target used to estimate the feerate of the first version of a claim
+	/// transaction. Lower targets are used instead if the estimated fee would exceed [`max_fee`].
+	///
+	/// [`max_fee`]: trait.FeeBumpPolicy.html#tymethod.max_fee
+	fn initial_confirmation_target(&self, blocks_to_expiry: u32) -> ConfirmationTarget;
+	/// Gets the feerate of a new version of a claim transaction which didn't confirm in time, given
+	/// the feerate of its previous version.
+	fn bumped_feerate(&self, previous_feerate: u64, blocks_to_expiry: u32) -> u64;
+	/// Gets the maximum fee, in satoshis, we're willing to pay for a claim transaction spending
+	/// outputs worth `claimed_value` satoshis. Claim transactions which would pay more are not
+	/// broadcast.
+	fn max_fee(&self, claimed_value: u64, blocks_to_expiry: u32) -> u64;
+	/// Gets the number of blocks to wait for a claim transaction to confirm before broadcasting a
+	/// bumped version of it. Values lower than 1 are treated as 1.
+	fn blocks_before_bump(&self, blocks_to_expiry: u32) -> u32;
+}
+
+/// The default [`FeeBumpPolicy`], which starts from a [`HighPriority`] feerate, increases it by a
+/// third on each bump and is willing to spend up to the whole claimed value in fees.
+///
+/// Claims are bumped every 15 blocks, every 3 blocks once their timelock expires in 15 blocks or
+/// less, and on every block once it expires in 3 blocks or less.
+///
+/// [`FeeBumpPolicy`]: trait.FeeBumpPolicy.html
+/// [`HighPriority`]: enum.ConfirmationTarget.html#variant.HighPriority
+pub struct DefaultFeeBumpPolicy {}
+
+impl FeeBumpPolicy for DefaultFeeBumpPolicy {
+	fn initial_confirmation_target(&self, _blocks_to_expiry: u32) -> ConfirmationTarget {
+		ConfirmationTarget::HighPriority
+	}
+	fn bumped_feerate(&self, previous_feerate: u64, _blocks_to_expiry: u32) -> u64 {
+		previous_feerate * 1000 / 750
+	}
+	fn max_fee(&self, claimed_value: u64, _blocks_to_expiry: u32) -> u64 {
+		claimed_value
+	}
+	fn blocks_before_bump(&self, blocks_to_expiry: u32) -> u32 {
+		if blocks_to_expiry <= 3 {
+			1
+		} else if blocks_to_expiry <= 15 {
+			3
+		} else {
+			15
+		}
+	}
+}
@@ -23,26 +23,34 @@
 //! events. The remote server would make use of `ChainMonitor` for block processing and for
 //! servicing `ChannelMonitor` updates from the client.
 //!
+//! After a mass force-close, `ChainMonitor` may also save on fees by claiming the outputs of
+//! several channels in shared transactions, see [`ChainMonitor::enable_claim_aggregation`].
+//!
 //! [`ChainMonitor`]: struct.ChainMonitor.html
+//! [`ChainMonitor::enable_claim_aggregation`]: struct.ChainMonitor.html#method.enable_claim_aggregation
 //! [`chain::Filter`]: ../trait.Filter.html
 //! [`chain::Watch`]: ../trait.Watch.html
 //! [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
 //! [`MonitorEvent`]: ../channelmonitor/enum.MonitorEvent.html
 
 use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::hash_types::Txid;
 
 use chain;
 use chain::Filter;
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
-use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, MonitorEvent, MonitorUpdateError};
+use chain::chaininterface::{BroadcasterInterface, FeeEstimator, FeeBumpPolicy, DefaultFeeBumpPolicy};
+use chain::channelmonitor::{Balance, ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, MonitorEvent, MonitorUpdateError, Persist};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::ChannelKeys;
+use ln::onchaintx::ClaimAggregator;
 use util::logger::Logger;
 use util::events;
 use util::events::Event;
 
 use std::collections::{HashMap, hash_map};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::ops::Deref;
 
 /// An implementation of [`chain::Watch`] for monitoring channels.
@@ -66,7 +74,10 @@ pub struct ChainMonitor<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L
 	chain_source: Option<C>,
 	broadcaster: T,
 	logger: L,
-	fee_estimator: F
+	fee_estimator: F,
+	fee_bump_policy: Mutex<Arc<FeeBumpPolicy>>,
+	/// The aggregators of shared claims by destination script, if claim aggregation is enabled.
+	claim_aggregators: Mutex<Option<HashMap<Script, ClaimAggregator>>>,
 }
 
 impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonitor<ChanSigner, C, T, F, L>
@@ -89,18 +100,10 @@ impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonit
 	/// [`chain::Watch::release_pending_monitor_events`]: ../trait.Watch.html#tymethod.release_pending_monitor_events
 	/// [`chain::Filter`]: ../trait.Filter.html
 	pub fn block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
-		let mut monitors = self.monitors.lock().unwrap();
-		for monitor in monitors.values_mut() {
-			let mut txn_outputs = monitor.block_connected(header, txdata, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
-
-			if let Some(ref chain_source) = self.chain_source {
-				for (txid, outputs) in txn_outputs.drain(..) {
-					for (idx, output) in outputs.iter().enumerate() {
-						chain_source.register_output(&OutPoint { txid, index: idx as u16 }, &output.script_pubkey);
-					}
-				}
-			}
-		}
+		self.process_chain_data(|monitor| {
+			monitor.block_connected(header, txdata, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger)
+		});
+		self.aggregate_shared_claims(height);
 	}
 
 	/// Dispatches to per-channel monitors, which are responsible for updating their on-chain view
@@ -115,6 +118,213 @@ impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonit
 		}
 	}
 
+	/// Dispatches to per-channel monitors the transactions confirmed in the block with the given
+	/// header and height. See [`ChannelMonitor::transactions_confirmed`] for details.
+	///
+	/// For use by clients which learn of individual confirmed transactions rather than full blocks,
+	/// such as those backed by an Electrum or Esplora server. The transactions of interest are
+	/// those registered with the [`chain::Filter`], which is called back here as in
+	/// [`block_connected`] if any monitor indicated new outputs to watch. Must be called along with
+	/// [`best_block_updated`] whenever the chain tip changes.
+	///
+	/// [`ChannelMonitor::transactions_confirmed`]: ../channelmonitor/struct.ChannelMonitor.html#method.transactions_confirmed
+	/// [`chain::Filter`]: ../trait.Filter.html
+	/// [`block_connected`]: #method.block_connected
+	/// [`best_block_updated`]: #method.best_block_updated
+	pub fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		self.process_chain_data(|monitor| {
+			monitor.transactions_confirmed(header, txdata, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger)
+		});
+	}
+
+	/// Dispatches to per-channel monitors a transaction which was reorganized out of the chain.
+	/// See [`ChannelMonitor::transaction_unconfirmed`] for details.
+	///
+	/// Transactions to check for reorgs are given by [`get_relevant_txids`].
+	///
+	/// [`ChannelMonitor::transaction_unconfirmed`]: ../channelmonitor/struct.ChannelMonitor.html#method.transaction_unconfirmed
+	/// [`get_relevant_txids`]: #method.get_relevant_txids
+	pub fn transaction_unconfirmed(&self, txid: &Txid) {
+		let mut monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values_mut() {
+			monitor.transaction_unconfirmed(txid, &*self.broadcaster, &*self.fee_estimator, &*self.logger);
+		}
+	}
+
+	/// Dispatches to per-channel monitors a new best chain tip. See
+	/// [`ChannelMonitor::best_block_updated`] for details.
+	///
+	/// Calls back to [`chain::Filter`] if any monitor indicated new outputs to watch.
+	///
+	/// [`ChannelMonitor::best_block_updated`]: ../channelmonitor/struct.ChannelMonitor.html#method.best_block_updated
+	/// [`chain::Filter`]: ../trait.Filter.html
+	pub fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		self.process_chain_data(|monitor| {
+			monitor.best_block_updated(header, height, &*self.broadcaster, &*self.fee_estimator, &*self.logger)
+		});
+		self.aggregate_shared_claims(height);
+	}
+
+	/// Returns the set of txids across all monitors that should be monitored for reorganization
+	/// out of the chain. See [`ChannelMonitor::get_relevant_txids`] for details.
+	///
+	/// [`ChannelMonitor::get_relevant_txids`]: ../channelmonitor/struct.ChannelMonitor.html#method.get_relevant_txids
+	pub fn get_relevant_txids(&self) -> Vec<Txid> {
+		let mut txids = Vec::new();
+		let monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values() {
+			txids.append(&mut monitor.get_relevant_txids());
+		}
+		txids.sort_unstable();
+		txids.dedup();
+		txids
+	}
+
+	/// Gets the balances of the on-chain funds tracked by all monitors. See
+	/// [`ChannelMonitor::get_claimable_balances`] for details.
+	///
+	/// [`ChannelMonitor::get_claimable_balances`]: ../channelmonitor/struct.ChannelMonitor.html#method.get_claimable_balances
+	pub fn get_claimable_balances(&self) -> Vec<Balance> {
+		let mut balances = Vec::new();
+		let monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values() {
+			balances.append(&mut monitor.get_claimable_balances());
+		}
+		balances
+	}
+
+	/// Archives the monitors of channels which are fully resolved on-chain through the given
+	/// persister, and removes them so that they no longer process each block. Returns the funding
+	/// outpoints of the archived channels. See [`ChannelMonitor::is_fully_resolved`] for when a
+	/// monitor is considered resolved.
+	///
+	/// Monitors the persister fails to archive are kept, and archiving them is attempted again on
+	/// the next call. Any later update for an archived channel fails as for an unknown channel.
+	///
+	/// Any pending events should be processed before calling this, as monitors with events
+	/// pending are not considered resolved.
+	///
+	/// [`ChannelMonitor::is_fully_resolved`]: ../channelmonitor/struct.ChannelMonitor.html#method.is_fully_resolved
+	pub fn archive_fully_resolved_channel_monitors<P: Deref>(&self, persister: P) -> Vec<OutPoint>
+		where P::Target: Persist<ChanSigner>
+	{
+		let mut monitors = self.monitors.lock().unwrap();
+		let resolved_funding_txos: Vec<OutPoint> = monitors.iter()
+			.filter(|&(_, monitor)| monitor.is_fully_resolved())
+			.map(|(funding_txo, _)| *funding_txo)
+			.collect();
+		let mut archived = Vec::with_capacity(resolved_funding_txos.len());
+		for funding_txo in resolved_funding_txos {
+			{
+				let monitor = monitors.get(&funding_txo).unwrap();
+				if persister.archive_persisted_channel(funding_txo, monitor).is_err() {
+					log_error!(self.logger, "Failed to archive fully resolved monitor for channel {}, will retry", log_funding_info!(monitor));
+					continue;
+				}
+				log_info!(self.logger, "Archived fully resolved monitor for channel {}", log_funding_info!(monitor));
+			}
+			monitors.remove(&funding_txo);
+			archived.push(funding_txo);
+		}
+		archived
+	}
+
+	/// Enables claiming the outputs of several channels in shared transactions, which saves on fees
+	/// after many channels were force-closed at once.
+	///
+	/// Once enabled, claims which aren't time-critical, i.e. justice claims and preimage claims on
+	/// counterparty commitment transactions whose timelock isn't close to expiring, are handed over
+	/// by each [`ChannelMonitor`] and merged into a single transaction, bumped with RBF until all
+	/// its inputs are resolved. Time-critical claims, such as HTLC-timeout claims or claims whose
+	/// timelock is about to expire, are still broadcast separately by each monitor.
+	///
+	/// Only claims of monitors sharing the same destination script are merged, so that each claim
+	/// pays to the script its channel was set up with.
+	///
+	/// The shared claims are persisted with each monitor. If a monitor is reloaded by a
+	/// `ChainMonitor` without aggregation enabled, they are claimed by the monitor itself again.
+	///
+	/// [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+	pub fn enable_claim_aggregation(&self) {
+		let mut claim_aggregators = self.claim_aggregators.lock().unwrap();
+		if claim_aggregators.is_none() {
+			*claim_aggregators = Some(HashMap::new());
+			for monitor in self.monitors.lock().unwrap().values_mut() {
+				monitor.set_defer_shared_claims(true);
+			}
+		}
+	}
+
+	/// Sets the policy used to set and bump the fee of the transactions claiming channel outputs
+	/// on-chain, for all current and future monitors as well as shared claims. Defaults to
+	/// [`DefaultFeeBumpPolicy`].
+	///
+	/// [`DefaultFeeBumpPolicy`]: ../chaininterface/struct.DefaultFeeBumpPolicy.html
+	pub fn set_fee_bump_policy(&self, fee_bump_policy: Arc<FeeBumpPolicy>) {
+		let mut policy = self.fee_bump_policy.lock().unwrap();
+		for monitor in self.monitors.lock().unwrap().values_mut() {
+			monitor.set_fee_bump_policy(fee_bump_policy.clone());
+		}
+		*policy = fee_bump_policy;
+	}
+
+	/// Builds, signs and broadcasts, for each destination script, a transaction claiming the
+	/// unspent shared claims of all monitors paying to it if claim aggregation is enabled and the
+	/// previous one needs to be bumped or its set of inputs has changed.
+	fn aggregate_shared_claims(&self, height: u32) {
+		let fee_bump_policy = self.fee_bump_policy.lock().unwrap();
+		let mut claim_aggregators = self.claim_aggregators.lock().unwrap();
+		let claim_aggregators = match *claim_aggregators {
+			Some(ref mut claim_aggregators) => claim_aggregators,
+			None => return,
+		};
+		let monitors = self.monitors.lock().unwrap();
+		let mut claims_by_script = HashMap::new();
+		let mut claim_monitors = HashMap::new();
+		for (funding_txo, monitor) in monitors.iter() {
+			for claim in monitor.get_unspent_shared_claims() {
+				claim_monitors.insert(claim.outpoint, *funding_txo);
+				claims_by_script.entry(monitor.get_destination_script().clone()).or_insert_with(Vec::new).push(claim);
+			}
+		}
+		claim_aggregators.retain(|destination_script, _| claims_by_script.contains_key(destination_script));
+
+		'claim_txn: for (destination_script, claims) in claims_by_script.drain() {
+			let claim_aggregator = claim_aggregators.entry(destination_script.clone()).or_insert_with(ClaimAggregator::new);
+			if let Some((mut claim_tx, feerate)) = claim_aggregator.update_claims(claims, &destination_script, height, &**fee_bump_policy, &*self.fee_estimator, &*self.logger) {
+				for input_idx in 0..claim_tx.input.len() {
+					let monitor = monitors.get(&claim_monitors[&claim_tx.input[input_idx].previous_output]).unwrap();
+					if !monitor.sign_shared_claim_input(&mut claim_tx, input_idx, feerate, &&*self.logger) {
+						log_error!(self.logger, "Failed to sign shared claim transaction input for channel {}", log_funding_info!(monitor));
+						continue 'claim_txn;
+					}
+				}
+				log_info!(self.logger, "Broadcasting shared claim transaction {} spending {} outpoints", claim_tx.txid(), claim_tx.input.len());
+				self.broadcaster.broadcast_transaction(&claim_tx);
+			}
+		}
+	}
+
+	/// Applies `process` to each monitor, registering any returned outputs to watch with the chain
+	/// source.
+	fn process_chain_data<FN>(&self, process: FN)
+	where
+		FN: Fn(&mut ChannelMonitor<ChanSigner>) -> Vec<(Txid, Vec<TxOut>)>
+	{
+		let mut monitors = self.monitors.lock().unwrap();
+		for monitor in monitors.values_mut() {
+			let mut txn_outputs = process(monitor);
+
+			if let Some(ref chain_source) = self.chain_source {
+				for (txid, outputs) in txn_outputs.drain(..) {
+					for (idx, output) in outputs.iter().enumerate() {
+						chain_source.register_output(&OutPoint { txid, index: idx as u16 }, &output.script_pubkey);
+					}
+				}
+			}
+		}
+	}
+
 	/// Creates a new `ChainMonitor` used to watch on-chain activity pertaining to channels.
 	///
 	/// When an optional chain source implementing [`chain::Filter`] is provided, the chain monitor
@@ -131,6 +341,8 @@ impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonit
 			broadcaster,
 			logger,
 			fee_estimator: feeest,
+			fee_bump_policy: Mutex::new(Arc::new(DefaultFeeBumpPolicy {})),
+			claim_aggregators: Mutex::new(None),
 		}
 	}
 
@@ -139,7 +351,9 @@ impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonit
 	/// Calls back to [`chain::Filter`] with the funding transaction and outputs to watch.
 	///
 	/// [`chain::Filter`]: ../trait.Filter.html
-	fn add_monitor(&self, outpoint: OutPoint, monitor: ChannelMonitor<ChanSigner>) -> Result<(), MonitorUpdateError> {
+	fn add_monitor(&self, outpoint: OutPoint, mut monitor: ChannelMonitor<ChanSigner>) -> Result<(), MonitorUpdateError> {
+		let fee_bump_policy = self.fee_bump_policy.lock().unwrap();
+		let claim_aggregators = self.claim_aggregators.lock().unwrap();
 		let mut monitors = self.monitors.lock().unwrap();
 		let entry = match monitors.entry(outpoint) {
 			hash_map::Entry::Occupied(_) => return Err(MonitorUpdateError("Channel monitor for given outpoint is already present")),
@@ -158,6 +372,8 @@ impl<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref> ChainMonit
 				}
 			}
 		}
+		monitor.set_fee_bump_policy(fee_bump_policy.clone());
+		monitor.set_defer_shared_claims(claim_aggregators.is_some());
 		entry.insert(monitor);
 		Ok(())
 	}
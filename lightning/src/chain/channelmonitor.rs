@@ -41,18 +41,19 @@ use ln::msgs::DecodeError;
 use ln::chan_utils;
 use ln::chan_utils::{CounterpartyCommitmentSecrets, HTLCOutputInCommitment, HolderCommitmentTransaction, HTLCType};
 use ln::channelmanager::{HTLCSource, PaymentPreimage, PaymentHash};
-use ln::onchaintx::{OnchainTxHandler, InputDescriptors};
-use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use ln::onchaintx::{OnchainTxHandler, InputDescriptors, SharedClaimInput};
+use chain::chaininterface::{BroadcasterInterface, FeeEstimator, FeeBumpPolicy};
 use chain::transaction::{OutPoint, TransactionData};
 use chain::keysinterface::{SpendableOutputDescriptor, ChannelKeys};
 use util::logger::Logger;
-use util::ser::{Readable, MaybeReadable, Writer, Writeable, U48};
+use util::ser::{Readable, ReadableArgs, MaybeReadable, Writer, Writeable, U48};
 use util::byte_utils;
 use util::events::Event;
 
-use std::collections::{HashMap, HashSet, hash_map};
+use std::collections::{HashMap, HashSet};
 use std::{cmp, mem};
 use std::ops::Deref;
+use std::sync::Arc;
 use std::io::Error;
 
 /// An update generated by the underlying Channel itself which contains some new information the
@@ -163,6 +164,26 @@ pub enum ChannelMonitorUpdateErr {
 #[derive(Debug)]
 pub struct MonitorUpdateError(pub &'static str);
 
+/// The `Persist` trait defines behavior for managing the storage of [`ChannelMonitor`]s beyond
+/// what [`chain::Watch`] covers, such as archiving those of channels which are fully resolved.
+///
+/// [`ChannelMonitor`]: struct.ChannelMonitor.html
+/// [`chain::Watch`]: ../trait.Watch.html
+pub trait Persist<Keys: ChannelKeys>: Send + Sync {
+	/// Archives the monitor of a channel which is fully resolved on-chain, see
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`].
+	///
+	/// The monitor should be moved out of the storage monitors are loaded from on restart, though
+	/// it may be kept elsewhere, e.g. for auditing. Any later update for the channel fails as for
+	/// an unknown channel.
+	///
+	/// If this returns an Err, the monitor is kept and archiving it is attempted again on the next
+	/// call to `archive_fully_resolved_channel_monitors`.
+	///
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`]: ../chainmonitor/struct.ChainMonitor.html#method.archive_fully_resolved_channel_monitors
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, monitor: &ChannelMonitor<Keys>) -> Result<(), ()>;
+}
+
 /// An event to be processed by the ChannelManager.
 #[derive(PartialEq)]
 pub enum MonitorEvent {
@@ -186,6 +207,64 @@ pub struct HTLCUpdate {
 }
 impl_writeable!(HTLCUpdate, 0, { payment_hash, payment_preimage, source });
 
+/// Details about an amount of on-chain funds a ChannelMonitor is tracking for us, as returned by
+/// [`ChannelMonitor::get_claimable_balances`].
+///
+/// Amounts are given before the fees of the transactions claiming them are paid, except for
+/// outputs which were already claimed and are awaiting confirmations.
+///
+/// [`ChannelMonitor::get_claimable_balances`]: struct.ChannelMonitor.html#method.get_claimable_balances
+#[derive(Clone, Debug, PartialEq)]
+pub enum Balance {
+	/// The channel is not yet closed (or the transaction closing it has not yet confirmed). The
+	/// given amount is the value of our output in our current commitment transaction, which we
+	/// could claim if we broadcast it.
+	ClaimableOnChannelClose {
+		/// The amount available to claim, in satoshis.
+		claimable_amount_satoshis: u64,
+	},
+	/// An output paying to us which is locked behind a relative timelock (CSV), i.e. our output
+	/// on our own commitment transaction or on one of our HTLC transactions. Note that it is handed
+	/// over through an [`Event::SpendableOutputs`] once it has [`ANTI_REORG_DELAY`] confirmations,
+	/// before the timelock expires.
+	///
+	/// [`ANTI_REORG_DELAY`]: constant.ANTI_REORG_DELAY.html
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	ClaimableAwaitingCsv {
+		/// The amount available to claim, in satoshis.
+		claimable_amount_satoshis: u64,
+		/// The height at which the relative timelock expires and the output becomes spendable.
+		claimable_height: u32,
+	},
+	/// An output paying to us which is waiting on [`ANTI_REORG_DELAY`] confirmations before it is
+	/// handed over through an [`Event::SpendableOutputs`].
+	///
+	/// [`ANTI_REORG_DELAY`]: constant.ANTI_REORG_DELAY.html
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	ClaimableAwaitingConfirmations {
+		/// The amount available to claim, in satoshis.
+		claimable_amount_satoshis: u64,
+		/// The height at which the output will have enough confirmations to be handed over.
+		confirmation_height: u32,
+	},
+	/// An output we are trying to claim which our counterparty may also claim once a timelock
+	/// expires, i.e. an HTLC for which we have the preimage or an output of a revoked transaction.
+	ContentiousClaimable {
+		/// The amount available to claim, in satoshis.
+		claimable_amount_satoshis: u64,
+		/// The height at which our counterparty may start claiming the output.
+		timeout_height: u32,
+	},
+	/// An HTLC we offered, which we will be able to claim back once it times out unless our
+	/// counterparty claims it with the preimage first.
+	MaybeClaimableHTLCAwaitingTimeout {
+		/// The amount we may claim, in satoshis.
+		claimable_amount_satoshis: u64,
+		/// The height at which the HTLC times out and we may claim it.
+		claimable_height: u32,
+	},
+}
+
 /// If an HTLC expires within this many blocks, don't try to claim it in a shared transaction,
 /// instead claiming it in its own individual transaction.
 pub(crate) const CLTV_SHARED_CLAIM_BUFFER: u32 = 12;
@@ -250,6 +329,33 @@ struct HolderSignedTx {
 	htlc_outputs: Vec<(HTLCOutputInCommitment, Option<Signature>, Option<HTLCSource>)>,
 }
 
+/// A non-time-critical claim deferred to our ChainMonitor to be aggregated with other channels'
+/// claims, see ChainMonitor::enable_claim_aggregation.
+#[derive(Clone, PartialEq)]
+struct SharedClaim {
+	request: ClaimRequest,
+	/// The height at which the output to claim was confirmed.
+	height: u32,
+	/// The transaction spending the output to claim and the height at which it was confirmed, if
+	/// any. The claim is forgotten once the spend reaches ANTI_REORG_DELAY confirmations.
+	spending_tx: Option<(Txid, u32)>,
+}
+
+/// Tracks the updates applied by a ChannelMonitor in replication mode.
+struct ReplicationState {
+	/// The latest update_id applied by this or any other replica we know of.
+	latest_known_update_id: u64,
+	/// Updates applied since replication was enabled, for exchange with other replicas.
+	applied_updates: Vec<ChannelMonitorUpdate>,
+}
+
+impl PartialEq for ReplicationState {
+	fn eq(&self, other: &Self) -> bool {
+		self.latest_known_update_id == other.latest_known_update_id &&
+			self.applied_updates.iter().map(|update| update.update_id).eq(other.applied_updates.iter().map(|update| update.update_id))
+	}
+}
+
 /// We use this to track counterparty commitment transactions and htlcs outputs and
 /// use it to generate any justice or 2nd-stage preimage/timeout transactions.
 #[derive(PartialEq)]
@@ -435,6 +541,7 @@ impl Readable for InputMaterial {
 /// onchain txn leaked from a channel and handed over to OnchainTxHandler which
 /// is responsible for opportunistic aggregation, selecting and enforcing
 /// bumping logic, building and signing transactions.
+#[derive(Clone, PartialEq)]
 pub(crate) struct ClaimRequest {
 	// Block height before which claiming is exclusive to one party,
 	// after reaching it, claiming may be contentious.
@@ -467,8 +574,70 @@ enum OnchainEvent {
 	},
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+/// An onchain event along with the transaction which triggered it and the height at which that
+/// transaction was confirmed. Tracking the txid allows us to drop the event if the transaction is
+/// reorged out even if we never see the block which disconnected it.
+#[derive(Clone, PartialEq)]
+struct OnchainEventEntry {
+	txid: Txid,
+	height: u32,
+	event: OnchainEvent,
+}
+
+impl OnchainEventEntry {
+	fn confirmation_threshold(&self) -> u32 {
+		self.height + ANTI_REORG_DELAY - 1
+	}
+
+	fn has_reached_confirmation_threshold(&self, height: u32) -> bool {
+		self.confirmation_threshold() <= height
+	}
+}
+
+impl Writeable for OnchainEvent {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match *self {
+			OnchainEvent::HTLCUpdate { ref htlc_update } => {
+				0u8.write(writer)?;
+				htlc_update.0.write(writer)?;
+				htlc_update.1.write(writer)?;
+			},
+			OnchainEvent::MaturingOutput { ref descriptor } => {
+				1u8.write(writer)?;
+				descriptor.write(writer)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for OnchainEvent {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		match <u8 as Readable>::read(reader)? {
+			0 => {
+				let htlc_source = Readable::read(reader)?;
+				let hash = Readable::read(reader)?;
+				Ok(OnchainEvent::HTLCUpdate {
+					htlc_update: (htlc_source, hash)
+				})
+			},
+			1 => {
+				let descriptor = Readable::read(reader)?;
+				Ok(OnchainEvent::MaturingOutput {
+					descriptor
+				})
+			},
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+// Version 2 tracks onchain events by the transaction which triggered them, see
+// OnchainEventEntry, and records the height of our last block, the current counterparty
+// commitment transaction, our replication state, shared claims and the confirmed spend of the
+// funding output.
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 #[cfg_attr(test, derive(PartialEq))]
 #[derive(Clone)]
@@ -615,6 +784,9 @@ pub struct ChannelMonitor<ChanSigner: ChannelKeys> {
 	funding_info: (OutPoint, Script),
 	current_counterparty_commitment_txid: Option<Txid>,
 	prev_counterparty_commitment_txid: Option<Txid>,
+	/// The unsigned current counterparty commitment transaction, kept so that a watchtower client
+	/// may be seeded with it when it starts tracking this channel.
+	current_counterparty_commitment_tx: Option<Transaction>,
 
 	counterparty_tx_cache: CounterpartyCommitmentTransaction,
 	funding_redeemscript: Script,
@@ -660,7 +832,7 @@ pub struct ChannelMonitor<ChanSigner: ChannelKeys> {
 	// Used to track onchain events, i.e transactions parts of channels confirmed on chain, on which
 	// we have to take actions once they reach enough confs. Key is a block height timer, i.e we enforce
 	// actions when we receive a block with given height. Actions depend on OnchainEvent type.
-	onchain_events_waiting_threshold_conf: HashMap<u32, Vec<OnchainEvent>>,
+	onchain_events_waiting_threshold_conf: Vec<OnchainEventEntry>,
 
 	// If we get serialized out and re-read, we need to make sure that the chain monitoring
 	// interface knows about the TXOs that we want to be notified of spends of. We could probably
@@ -687,12 +859,34 @@ pub struct ChannelMonitor<ChanSigner: ChannelKeys> {
 	// remote monitor out-of-order with regards to the block view.
 	holder_tx_signed: bool,
 
+	// Set when this monitor is one of several replicas of the same channel. See
+	// enable_replication.
+	replication: Option<ReplicationState>,
+
+	// Set by a ChainMonitor aggregating claims across channels, in which case non-time-critical
+	// claims are moved to shared_claims instead of being handed to our OnchainTxHandler. Set
+	// again by the ChainMonitor we're added to, if it's unset any shared claims are handed back
+	// to our OnchainTxHandler.
+	defer_shared_claims: bool,
+	shared_claims: Vec<SharedClaim>,
+
+	// The transaction spending our funding output, i.e. a commitment or closing transaction, and
+	// the height at which it was confirmed. Only partially recovered when reading monitors written
+	// before version 2, see recover_legacy_funding_spend.
+	#[cfg(test)]
+	pub funding_spend_confirmed: Option<(Txid, u32)>,
+	#[cfg(not(test))]
+	funding_spend_confirmed: Option<(Txid, u32)>,
+
 	// We simply modify last_block_hash in Channel's block_connected so that serialization is
 	// consistent but hopefully the users' copy handles block_connected in a consistent way.
 	// (we do *not*, however, update them in update_monitor to ensure any local user copies keep
 	// their last_block_hash from its state and not based on updated copies that didn't run through
 	// the full block_connected).
 	last_block_hash: BlockHash,
+	// The height of the best block we've seen, used to determine when onchain events have reached
+	// ANTI_REORG_DELAY confirmations when transactions are provided out-of-band of full blocks.
+	last_block_height: u32,
 	secp_ctx: Secp256k1<secp256k1::All>, //TODO: dedup this a bit...
 }
 
@@ -709,6 +903,7 @@ impl<ChanSigner: ChannelKeys> PartialEq for ChannelMonitor<ChanSigner> {
 			self.keys.pubkeys() != other.keys.pubkeys() ||
 			self.funding_info != other.funding_info ||
 			self.current_counterparty_commitment_txid != other.current_counterparty_commitment_txid ||
+			self.current_counterparty_commitment_tx != other.current_counterparty_commitment_tx ||
 			self.prev_counterparty_commitment_txid != other.prev_counterparty_commitment_txid ||
 			self.counterparty_tx_cache != other.counterparty_tx_cache ||
 			self.funding_redeemscript != other.funding_redeemscript ||
@@ -729,7 +924,12 @@ impl<ChanSigner: ChannelKeys> PartialEq for ChannelMonitor<ChanSigner> {
 			self.onchain_events_waiting_threshold_conf != other.onchain_events_waiting_threshold_conf ||
 			self.outputs_to_watch != other.outputs_to_watch ||
 			self.lockdown_from_offchain != other.lockdown_from_offchain ||
-			self.holder_tx_signed != other.holder_tx_signed
+			self.last_block_height != other.last_block_height ||
+			self.holder_tx_signed != other.holder_tx_signed ||
+			self.replication != other.replication ||
+			self.defer_shared_claims != other.defer_shared_claims ||
+			self.shared_claims != other.shared_claims ||
+			self.funding_spend_confirmed != other.funding_spend_confirmed
 		{
 			false
 		} else {
@@ -776,6 +976,7 @@ impl<ChanSigner: ChannelKeys + Writeable> ChannelMonitor<ChanSigner> {
 		self.funding_info.1.write(writer)?;
 		self.current_counterparty_commitment_txid.write(writer)?;
 		self.prev_counterparty_commitment_txid.write(writer)?;
+		self.current_counterparty_commitment_tx.write(writer)?;
 
 		self.counterparty_tx_cache.write(writer)?;
 		self.funding_redeemscript.write(writer)?;
@@ -894,23 +1095,13 @@ impl<ChanSigner: ChannelKeys + Writeable> ChannelMonitor<ChanSigner> {
 
 		self.last_block_hash.write(writer)?;
 
+		self.last_block_height.write(writer)?;
+
 		writer.write_all(&byte_utils::be64_to_array(self.onchain_events_waiting_threshold_conf.len() as u64))?;
-		for (ref target, ref events) in self.onchain_events_waiting_threshold_conf.iter() {
-			writer.write_all(&byte_utils::be32_to_array(**target))?;
-			writer.write_all(&byte_utils::be64_to_array(events.len() as u64))?;
-			for ev in events.iter() {
-				match *ev {
-					OnchainEvent::HTLCUpdate { ref htlc_update } => {
-						0u8.write(writer)?;
-						htlc_update.0.write(writer)?;
-						htlc_update.1.write(writer)?;
-					},
-					OnchainEvent::MaturingOutput { ref descriptor } => {
-						1u8.write(writer)?;
-						descriptor.write(writer)?;
-					},
-				}
-			}
+		for ref entry in self.onchain_events_waiting_threshold_conf.iter() {
+			entry.txid.write(writer)?;
+			writer.write_all(&byte_utils::be32_to_array(entry.height))?;
+			entry.event.write(writer)?;
 		}
 
 		(self.outputs_to_watch.len() as u64).write(writer)?;
@@ -926,6 +1117,45 @@ impl<ChanSigner: ChannelKeys + Writeable> ChannelMonitor<ChanSigner> {
 		self.lockdown_from_offchain.write(writer)?;
 		self.holder_tx_signed.write(writer)?;
 
+		match self.replication {
+			Some(ref replication) => {
+				1u8.write(writer)?;
+				replication.latest_known_update_id.write(writer)?;
+				(replication.applied_updates.len() as u64).write(writer)?;
+				for update in replication.applied_updates.iter() {
+					update.write(writer)?;
+				}
+			},
+			None => 0u8.write(writer)?,
+		}
+
+		self.defer_shared_claims.write(writer)?;
+		(self.shared_claims.len() as u64).write(writer)?;
+		for claim in self.shared_claims.iter() {
+			claim.request.absolute_timelock.write(writer)?;
+			claim.request.aggregable.write(writer)?;
+			claim.request.outpoint.write(writer)?;
+			claim.request.witness_data.write(writer)?;
+			claim.height.write(writer)?;
+			match claim.spending_tx {
+				Some((ref txid, ref height)) => {
+					1u8.write(writer)?;
+					txid.write(writer)?;
+					height.write(writer)?;
+				},
+				None => 0u8.write(writer)?,
+			}
+		}
+
+		match self.funding_spend_confirmed {
+			Some((ref txid, ref height)) => {
+				1u8.write(writer)?;
+				txid.write(writer)?;
+				height.write(writer)?;
+			},
+			None => 0u8.write(writer)?,
+		}
+
 		Ok(())
 	}
 }
@@ -977,6 +1207,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			keys,
 			funding_info,
 			current_counterparty_commitment_txid: None,
+			current_counterparty_commitment_tx: None,
 			prev_counterparty_commitment_txid: None,
 
 			counterparty_tx_cache,
@@ -1000,15 +1231,22 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			pending_monitor_events: Vec::new(),
 			pending_events: Vec::new(),
 
-			onchain_events_waiting_threshold_conf: HashMap::new(),
+			onchain_events_waiting_threshold_conf: Vec::new(),
 			outputs_to_watch,
 
 			onchain_tx_handler,
 
 			lockdown_from_offchain: false,
 			holder_tx_signed: false,
+			replication: None,
+
+			defer_shared_claims: false,
+			shared_claims: Vec::new(),
+
+			funding_spend_confirmed: None,
 
 			last_block_hash: Default::default(),
+			last_block_height: 0,
 			secp_ctx: Secp256k1::new(),
 		}
 	}
@@ -1082,6 +1320,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		log_trace!(logger, "New potential counterparty commitment transaction: {}", encode::serialize_hex(unsigned_commitment_tx));
 		self.prev_counterparty_commitment_txid = self.current_counterparty_commitment_txid.take();
 		self.current_counterparty_commitment_txid = Some(new_txid);
+		self.current_counterparty_commitment_tx = Some(unsigned_commitment_tx.clone());
 		self.counterparty_claimable_outpoints.insert(new_txid, htlc_outputs.clone());
 		self.current_counterparty_commitment_number = commitment_number;
 		//TODO: Merge this into the other per-counterparty-transaction output storage stuff
@@ -1168,6 +1407,12 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		if self.latest_update_id + 1 != updates.update_id {
 			panic!("Attempted to apply ChannelMonitorUpdates out of order, check the update_id before passing an update to update_monitor!");
 		}
+		// Only record the update once applied, so that we never hand out one we failed to apply
+		let mut applied_update = None;
+		if let Some(ref mut replication) = self.replication {
+			replication.latest_known_update_id = cmp::max(replication.latest_known_update_id, updates.update_id);
+			applied_update = Some(updates.clone());
+		}
 		for update in updates.updates.drain(..) {
 			match update {
 				ChannelMonitorUpdateStep::LatestHolderCommitmentTXInfo { commitment_tx, htlc_outputs } => {
@@ -1191,6 +1436,9 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 			}
 		}
 		self.latest_update_id = updates.update_id;
+		if let (Some(replication), Some(update)) = (self.replication.as_mut(), applied_update) {
+			replication.applied_updates.push(update);
+		}
 		Ok(())
 	}
 
@@ -1200,11 +1448,112 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		self.latest_update_id
 	}
 
+	/// Puts this ChannelMonitor in replication mode, for use when several copies of it are kept
+	/// (e.g. by distributed watchers), each of which may independently be asked to broadcast our
+	/// holder commitment transaction.
+	///
+	/// In replication mode, the monitor records every update it applies and tracks the latest
+	/// update_id known to any replica, as given by [`note_latest_update_id`]. While behind it
+	/// refuses to sign our holder commitment transaction, as a newer one may exist and the one
+	/// we know of may have been revoked. A replica which has fallen behind may catch up by
+	/// [`reconcile`]-ing with the updates returned by another replica's [`get_updates_after`].
+	///
+	/// Only updates applied after replication is enabled are recorded, thus it should be enabled
+	/// on each copy before any of them diverge.
+	///
+	/// [`note_latest_update_id`]: #method.note_latest_update_id
+	/// [`reconcile`]: #method.reconcile
+	/// [`get_updates_after`]: #method.get_updates_after
+	pub fn enable_replication(&mut self) {
+		if self.replication.is_none() {
+			self.replication = Some(ReplicationState { latest_known_update_id: self.latest_update_id, applied_updates: Vec::new() });
+		}
+	}
+
+	/// Informs this replica that another replica has applied updates up to `update_id`.
+	///
+	/// Does nothing if replication is not enabled.
+	pub fn note_latest_update_id(&mut self, update_id: u64) {
+		if let Some(ref mut replication) = self.replication {
+			replication.latest_known_update_id = cmp::max(replication.latest_known_update_id, update_id);
+		}
+	}
+
+	/// Returns true if this monitor is in replication mode and another replica is known to have
+	/// applied a newer update.
+	pub fn is_stale_replica(&self) -> bool {
+		match self.replication {
+			Some(ref replication) => replication.latest_known_update_id > self.latest_update_id,
+			None => false,
+		}
+	}
+
+	/// Returns the recorded updates with an update_id greater than `update_id`, in order, such
+	/// that another replica at `update_id` may [`reconcile`] with them.
+	///
+	/// [`reconcile`]: #method.reconcile
+	pub fn get_updates_after(&self, update_id: u64) -> Vec<ChannelMonitorUpdate> {
+		match self.replication {
+			Some(ref replication) => replication.applied_updates.iter().filter(|update| update.update_id > update_id).cloned().collect(),
+			None => Vec::new(),
+		}
+	}
+
+	/// Forgets recorded updates up to and including `update_id`, e.g. once all replicas are
+	/// known to have applied them.
+	pub fn prune_updates(&mut self, update_id: u64) {
+		if let Some(ref mut replication) = self.replication {
+			replication.applied_updates.retain(|update| update.update_id > update_id);
+		}
+	}
+
+	/// Applies the updates exchanged with another replica which this monitor hasn't yet seen,
+	/// skipping those already applied. Fails if replication is not enabled or if the updates
+	/// leave a gap after our latest update_id, in which case none of the updates after the gap
+	/// are applied.
+	pub fn reconcile<B: Deref, L: Deref>(&mut self, updates: &[ChannelMonitorUpdate], broadcaster: &B, logger: &L) -> Result<(), MonitorUpdateError>
+		where B::Target: BroadcasterInterface,
+		      L::Target: Logger,
+	{
+		if self.replication.is_none() {
+			return Err(MonitorUpdateError("Replication is not enabled for this monitor"));
+		}
+		for update in updates.iter() {
+			if update.update_id <= self.latest_update_id {
+				continue;
+			}
+			if update.update_id != self.latest_update_id + 1 {
+				return Err(MonitorUpdateError("Replicated updates are not contiguous with our latest update"));
+			}
+			log_trace!(logger, "Reconciling replicated update {} for channel {}", update.update_id, log_funding_info!(self));
+			self.update_monitor(update.clone(), broadcaster, logger)?;
+		}
+		Ok(())
+	}
+
 	/// Gets the funding transaction outpoint of the channel this ChannelMonitor is monitoring for.
 	pub fn get_funding_txo(&self) -> &(OutPoint, Script) {
 		&self.funding_info
 	}
 
+	/// Gets the unsigned current counterparty commitment transaction along with its commitment
+	/// number and HTLC outputs, if one has been provided.
+	pub(crate) fn get_current_counterparty_commitment_tx(&self) -> Option<(Transaction, u64, Vec<HTLCOutputInCommitment>)> {
+		let tx = match self.current_counterparty_commitment_tx {
+			Some(ref tx) => tx,
+			None => return None,
+		};
+		let htlcs = self.counterparty_tx_cache.per_htlc.get(&tx.txid()).cloned().unwrap_or(Vec::new());
+		Some((tx.clone(), self.current_counterparty_commitment_number, htlcs))
+	}
+
+	/// Gets the channel keys and counterparty parameters (delayed payment base key, HTLC base key
+	/// and to_self_delay) needed to build justice transactions for revoked counterparty
+	/// commitment transactions.
+	pub(crate) fn get_justice_parameters(&self) -> (ChanSigner, PublicKey, PublicKey, u16) {
+		(self.keys.clone(), self.counterparty_tx_cache.counterparty_delayed_payment_base_key, self.counterparty_tx_cache.counterparty_htlc_base_key, self.counterparty_tx_cache.on_counterparty_tx_csv)
+	}
+
 	/// Gets a list of txids, with their output scripts (in the order they appear in the
 	/// transaction), which we must learn about spends of via block_connected().
 	///
@@ -1219,6 +1568,16 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		&self.outputs_to_watch
 	}
 
+	/// Sets the policy used to set and bump the fee of the transactions claiming our outputs
+	/// on-chain. Defaults to [`DefaultFeeBumpPolicy`].
+	///
+	/// The policy isn't persisted, so it must be set again after deserialization.
+	///
+	/// [`DefaultFeeBumpPolicy`]: ../chaininterface/struct.DefaultFeeBumpPolicy.html
+	pub fn set_fee_bump_policy(&mut self, fee_bump_policy: Arc<FeeBumpPolicy>) {
+		self.onchain_tx_handler.set_fee_bump_policy(fee_bump_policy);
+	}
+
 	/// Get the list of HTLCs who's status has been updated on chain. This should be called by
 	/// ChannelManager via [`chain::Watch::release_pending_monitor_events`].
 	///
@@ -1328,23 +1687,20 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 							for &(ref htlc, ref source_option) in outpoints.iter() {
 								if let &Some(ref source) = source_option {
 									log_info!(logger, "Failing HTLC with payment_hash {} from {} counterparty commitment tx due to broadcast of revoked counterparty commitment transaction, waiting for confirmation (at height {})", log_bytes!(htlc.payment_hash.0), $commitment_tx, height + ANTI_REORG_DELAY - 1);
-									match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-										hash_map::Entry::Occupied(mut entry) => {
-											let e = entry.get_mut();
-											e.retain(|ref event| {
-												match **event {
-													OnchainEvent::HTLCUpdate { ref htlc_update } => {
-														return htlc_update.0 != **source
-													},
-													_ => true
-												}
-											});
-											e.push(OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())});
-										}
-										hash_map::Entry::Vacant(entry) => {
-											entry.insert(vec![OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())}]);
+									self.onchain_events_waiting_threshold_conf.retain(|ref entry| {
+										if entry.height != height { return true; }
+										match entry.event {
+											OnchainEvent::HTLCUpdate { ref htlc_update } => {
+												htlc_update.0 != **source
+											},
+											_ => true
 										}
-									}
+									});
+									self.onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+										txid: commitment_txid,
+										height,
+										event: OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())},
+									});
 								}
 							}
 						}
@@ -1391,23 +1747,20 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 									}
 								}
 								log_trace!(logger, "Failing HTLC with payment_hash {} from {} counterparty commitment tx due to broadcast of counterparty commitment transaction", log_bytes!(htlc.payment_hash.0), $commitment_tx);
-								match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-									hash_map::Entry::Occupied(mut entry) => {
-										let e = entry.get_mut();
-										e.retain(|ref event| {
-											match **event {
-												OnchainEvent::HTLCUpdate { ref htlc_update } => {
-													return htlc_update.0 != **source
-												},
-												_ => true
-											}
-										});
-										e.push(OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())});
+								self.onchain_events_waiting_threshold_conf.retain(|ref entry| {
+									if entry.height != height { return true; }
+									match entry.event {
+										OnchainEvent::HTLCUpdate { ref htlc_update } => {
+											htlc_update.0 != **source
+										},
+										_ => true
 									}
-									hash_map::Entry::Vacant(entry) => {
-										entry.insert(vec![OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())}]);
-									}
-								}
+								});
+								self.onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+									txid: commitment_txid,
+									height,
+									event: OnchainEvent::HTLCUpdate { htlc_update: ((**source).clone(), htlc.payment_hash.clone())},
+								});
 							}
 						}
 					}
@@ -1520,23 +1873,20 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		macro_rules! wait_threshold_conf {
 			($height: expr, $source: expr, $commitment_tx: expr, $payment_hash: expr) => {
 				log_trace!(logger, "Failing HTLC with payment_hash {} from {} holder commitment tx due to broadcast of transaction, waiting confirmation (at height{})", log_bytes!($payment_hash.0), $commitment_tx, height + ANTI_REORG_DELAY - 1);
-				match self.onchain_events_waiting_threshold_conf.entry($height + ANTI_REORG_DELAY - 1) {
-					hash_map::Entry::Occupied(mut entry) => {
-						let e = entry.get_mut();
-						e.retain(|ref event| {
-							match **event {
-								OnchainEvent::HTLCUpdate { ref htlc_update } => {
-									return htlc_update.0 != $source
-								},
-								_ => true
-							}
-						});
-						e.push(OnchainEvent::HTLCUpdate { htlc_update: ($source, $payment_hash)});
-					}
-					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec![OnchainEvent::HTLCUpdate { htlc_update: ($source, $payment_hash)}]);
+				self.onchain_events_waiting_threshold_conf.retain(|ref entry| {
+					if entry.height != $height { return true; }
+					match entry.event {
+						OnchainEvent::HTLCUpdate { ref htlc_update } => {
+							htlc_update.0 != $source
+						},
+						_ => true
 					}
-				}
+				});
+				self.onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+					txid: commitment_txid,
+					height: $height,
+					event: OnchainEvent::HTLCUpdate { htlc_update: ($source, $payment_hash)},
+				});
 			}
 		}
 
@@ -1596,7 +1946,14 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 	/// substantial amount of time (a month or even a year) to get back funds. Best may be to contact
 	/// out-of-band the other node operator to coordinate with him if option is available to you.
 	/// In any-case, choice is up to the user.
+	///
+	/// In replication mode, returns nothing if another replica is known to have applied a newer
+	/// update, as our latest holder commitment transaction may have been revoked.
 	pub fn get_latest_holder_commitment_txn<L: Deref>(&mut self, logger: &L) -> Vec<Transaction> where L::Target: Logger {
+		if self.is_stale_replica() {
+			log_error!(logger, "Refusing to sign holder commitment transaction as a replica with update {} is behind update {}", self.latest_update_id, self.replication.as_ref().unwrap().latest_known_update_id);
+			return Vec::new();
+		}
 		log_trace!(logger, "Getting signed latest holder commitment transaction!");
 		self.holder_tx_signed = true;
 		if let Some(commitment_tx) = self.onchain_tx_handler.get_fully_signed_holder_tx(&self.funding_redeemscript) {
@@ -1665,7 +2022,58 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 	pub fn block_connected<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, txdata: &TransactionData, height: u32, broadcaster: B, fee_estimator: F, logger: L)-> Vec<(Txid, Vec<TxOut>)>
 		where B::Target: BroadcasterInterface,
 		      F::Target: FeeEstimator,
-					L::Target: Logger,
+		      L::Target: Logger,
+	{
+		let block_hash = header.block_hash();
+		log_trace!(logger, "New best block {} at height {}", block_hash, height);
+		self.last_block_hash = block_hash;
+		self.last_block_height = height;
+
+		self.transactions_confirmed(header, txdata, height, broadcaster, fee_estimator, logger)
+	}
+
+	/// Determines if the disconnected block contained any transactions of interest and updates
+	/// appropriately.
+	pub fn block_disconnected<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, height: u32, broadcaster: B, fee_estimator: F, logger: L)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let block_hash = header.block_hash();
+		log_trace!(logger, "Block {} at height {} disconnected", block_hash, height);
+
+		//We may discard:
+		//- htlc update there as failure-trigger tx (revoked commitment tx, non-revoked commitment tx, HTLC-timeout tx) has been disconnected
+		//- maturing spendable output has transaction paying us has been disconnected
+		self.onchain_events_waiting_threshold_conf.retain(|ref entry| entry.height < height);
+		self.shared_claims_disconnected(height);
+		if self.funding_spend_confirmed.map(|(_, spend_height)| spend_height >= height).unwrap_or(false) {
+			self.funding_spend_confirmed = None;
+		}
+
+		self.onchain_tx_handler.block_disconnected(height, broadcaster, fee_estimator, logger);
+
+		self.last_block_hash = block_hash;
+		self.last_block_height = height - 1;
+	}
+
+	/// Processes transactions confirmed in a block with the given header and height, returning new
+	/// outputs to watch. See [`block_connected`] for details.
+	///
+	/// Used instead of [`block_connected`] by clients that are notified of transactions rather
+	/// than blocks. May be called before or after [`best_block_updated`] for the corresponding
+	/// block, and for a block which is not the best block (e.g. when a transaction is found to
+	/// have been confirmed some blocks back). Transactions not relevant to this monitor are
+	/// ignored, so `txdata` may be limited to those matching [`get_outputs_to_watch`] and the
+	/// funding transaction.
+	///
+	/// [`block_connected`]: #method.block_connected
+	/// [`best_block_updated`]: #method.best_block_updated
+	/// [`get_outputs_to_watch`]: #method.get_outputs_to_watch
+	pub fn transactions_confirmed<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, txdata: &TransactionData, height: u32, broadcaster: B, fee_estimator: F, logger: L)-> Vec<(Txid, Vec<TxOut>)>
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
 	{
 		let txn_matched = self.filter_block(txdata);
 		for tx in &txn_matched {
@@ -1690,6 +2098,7 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 				// filters.
 				let prevout = &tx.input[0].previous_output;
 				if prevout.txid == self.funding_info.0.txid && prevout.vout == self.funding_info.0.index as u32 {
+					self.funding_spend_confirmed = Some((tx.txid(), height));
 					if (tx.input[0].sequence >> 8*3) as u8 == 0x80 && (tx.lock_time >> 8*3) as u8 == 0x20 {
 						let (mut new_outpoints, new_outputs) = self.check_spend_counterparty_transaction(&tx, height, &logger);
 						if !new_outputs.1.is_empty() {
@@ -1721,7 +2130,275 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 
 			self.is_paying_spendable_output(&tx, height, &logger);
 		}
-		let should_broadcast = self.would_broadcast_at_height(height, &logger);
+
+		self.block_confirmed(height, txn_matched, watch_outputs, claimable_outpoints, broadcaster, fee_estimator, logger)
+	}
+
+	/// Processes a transaction that was reorganized out of the chain.
+	///
+	/// Used instead of [`block_disconnected`] by clients that are notified of transactions rather
+	/// than blocks. Any onchain events pending on the transaction are dropped and any claims it
+	/// resolved are resurrected. See [`get_relevant_txids`] for the set of transactions which
+	/// should be checked for reorgs.
+	///
+	/// [`block_disconnected`]: #method.block_disconnected
+	/// [`get_relevant_txids`]: #method.get_relevant_txids
+	pub fn transaction_unconfirmed<B: Deref, F: Deref, L: Deref>(&mut self, txid: &Txid, broadcaster: B, fee_estimator: F, logger: L)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		log_trace!(logger, "Transaction {} unconfirmed", txid);
+		self.onchain_events_waiting_threshold_conf.retain(|ref entry| entry.txid != *txid);
+		if self.funding_spend_confirmed.map(|(spend_txid, _)| spend_txid == *txid).unwrap_or(false) {
+			self.funding_spend_confirmed = None;
+		}
+		self.shared_claims.retain(|claim| claim.request.outpoint.txid != *txid);
+		for claim in self.shared_claims.iter_mut() {
+			if claim.spending_tx.map(|(spending_txid, _)| spending_txid == *txid).unwrap_or(false) {
+				claim.spending_tx = None;
+			}
+		}
+		self.onchain_tx_handler.transaction_unconfirmed(txid, broadcaster, fee_estimator, logger);
+	}
+
+	/// Updates the monitor's view of the best chain tip, which may result in:
+	/// - force closing the channel if HTLCs are near expiration
+	/// - passing HTLC resolutions and spendable outputs upstream once buried deep enough
+	/// - bumping any in-flight claims
+	///
+	/// Used instead of [`block_connected`] by clients that are notified of transactions rather
+	/// than blocks, and should be called whenever the best chain tip changes. If `height` is lower
+	/// than the previously seen best block height, any onchain events confirmed above it are
+	/// dropped as they must have been reorged out.
+	///
+	/// Returns any new outputs to watch, which may happen if the holder commitment transaction is
+	/// broadcast as a result of the new height.
+	///
+	/// [`block_connected`]: #method.block_connected
+	pub fn best_block_updated<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, height: u32, broadcaster: B, fee_estimator: F, logger: L) -> Vec<(Txid, Vec<TxOut>)>
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let block_hash = header.block_hash();
+		log_trace!(logger, "New best block {} at height {}", block_hash, height);
+		self.last_block_hash = block_hash;
+
+		if height > self.last_block_height {
+			self.last_block_height = height;
+			self.block_confirmed(height, Vec::new(), Vec::new(), Vec::new(), broadcaster, fee_estimator, logger)
+		} else {
+			self.last_block_height = height;
+			self.onchain_events_waiting_threshold_conf.retain(|ref entry| entry.height <= height);
+			self.shared_claims_disconnected(height + 1);
+			if self.funding_spend_confirmed.map(|(_, spend_height)| spend_height > height).unwrap_or(false) {
+				self.funding_spend_confirmed = None;
+			}
+			self.onchain_tx_handler.block_disconnected(height + 1, broadcaster, fee_estimator, logger);
+			Vec::new()
+		}
+	}
+
+	/// Returns the set of txids that should be monitored for reorganization out of the chain, as
+	/// they have been confirmed but have not yet reached [`ANTI_REORG_DELAY`] confirmations.
+	///
+	/// [`ANTI_REORG_DELAY`]: constant.ANTI_REORG_DELAY.html
+	pub fn get_relevant_txids(&self) -> Vec<Txid> {
+		let mut txids: Vec<Txid> = self.onchain_events_waiting_threshold_conf
+			.iter()
+			.map(|entry| entry.txid)
+			.chain(self.onchain_tx_handler.get_relevant_txids().into_iter())
+			.chain(self.shared_claims.iter().filter_map(|claim| claim.spending_tx.map(|(txid, _)| txid)))
+			.chain(self.shared_claims.iter().map(|claim| claim.request.outpoint.txid))
+			.chain(self.funding_spend_confirmed.iter()
+				.filter(|&&(_, height)| height + ANTI_REORG_DELAY - 1 > self.last_block_height)
+				.map(|&(txid, _)| txid))
+			// Events read from before version 2 don't know their transaction
+			.filter(|txid| *txid != Default::default())
+			.collect();
+		txids.sort_unstable();
+		txids.dedup();
+		txids
+	}
+
+	/// Gets the balances of the on-chain funds this monitor is tracking for us, e.g. our balance
+	/// in the channel while it is open, or the outputs we are claiming or waiting on after it was
+	/// closed.
+	///
+	/// Outputs are no longer reported once they have been handed over through an
+	/// [`Event::SpendableOutputs`], nor once our counterparty claimed them. Thus, once the channel
+	/// is closed and no balances are returned, the monitor has nothing left to claim.
+	///
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	pub fn get_claimable_balances(&self) -> Vec<Balance> {
+		let mut res = Vec::new();
+
+		if self.funding_spend_confirmed.is_none() {
+			let holder_tx = &self.current_holder_commitment_tx;
+			let to_self_script = chan_utils::get_revokeable_redeemscript(&holder_tx.revocation_key, self.on_holder_tx_csv, &holder_tx.delayed_payment_key).to_v0_p2wsh();
+			if let Some(commitment_tx) = self.onchain_tx_handler.get_unsigned_holder_commitment_tx() {
+				let claimable_amount_satoshis = commitment_tx.output.iter().filter(|outp| outp.script_pubkey == to_self_script).map(|outp| outp.value).sum();
+				res.push(Balance::ClaimableOnChannelClose { claimable_amount_satoshis });
+			}
+		}
+
+		// Looks up the expiry of an HTLC output of one of our holder commitment transactions.
+		let holder_htlc_cltv_expiry = |outpoint: &BitcoinOutPoint| {
+			let mut holder_txn = vec![&self.current_holder_commitment_tx];
+			if let Some(ref prev_holder_tx) = self.prev_holder_signed_commitment_tx {
+				holder_txn.push(prev_holder_tx);
+			}
+			holder_txn.iter().filter(|holder_tx| holder_tx.txid == outpoint.txid)
+				.flat_map(|holder_tx| holder_tx.htlc_outputs.iter())
+				.find(|&&(ref htlc, _, _)| htlc.transaction_output_index == Some(outpoint.vout))
+				.map(|&(ref htlc, _, _)| htlc.cltv_expiry)
+		};
+		// Claims may be aggregated with others expiring sooner, so look up the actual timeout of
+		// revoked HTLC outputs and of the revoked to_holder output of the commitment transaction.
+		let revoked_output_timeout = |outpoint: &BitcoinOutPoint, htlc: &Option<HTLCOutputInCommitment>, on_counterparty_tx_csv: u16| {
+			if let &Some(ref htlc) = htlc {
+				return Some(htlc.cltv_expiry);
+			}
+			match self.funding_spend_confirmed {
+				Some((txid, height)) if txid == outpoint.txid => Some(height + on_counterparty_tx_csv as u32),
+				_ => None,
+			}
+		};
+		for (outpoint, input_material, soonest_timelock) in self.onchain_tx_handler.get_unresolved_claims() {
+			match input_material {
+				&InputMaterial::Revoked { ref amount, ref htlc, ref on_counterparty_tx_csv, .. } => {
+					let timeout_height = revoked_output_timeout(outpoint, htlc, *on_counterparty_tx_csv).unwrap_or(soonest_timelock);
+					res.push(Balance::ContentiousClaimable { claimable_amount_satoshis: *amount, timeout_height });
+				},
+				&InputMaterial::CounterpartyHTLC { ref preimage, ref htlc, .. } => {
+					if preimage.is_some() {
+						res.push(Balance::ContentiousClaimable { claimable_amount_satoshis: htlc.amount_msat / 1000, timeout_height: htlc.cltv_expiry });
+					} else {
+						res.push(Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_amount_satoshis: htlc.amount_msat / 1000, claimable_height: htlc.cltv_expiry });
+					}
+				},
+				&InputMaterial::HolderHTLC { ref preimage, ref amount } => {
+					let cltv_expiry = holder_htlc_cltv_expiry(outpoint).unwrap_or(soonest_timelock);
+					if preimage.is_some() {
+						res.push(Balance::ContentiousClaimable { claimable_amount_satoshis: amount / 1000, timeout_height: cltv_expiry });
+					} else {
+						res.push(Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_amount_satoshis: amount / 1000, claimable_height: cltv_expiry });
+					}
+				},
+				// Our commitment transaction is yet to confirm, we already reported our balance
+				// as claimable on close.
+				&InputMaterial::Funding { .. } => {},
+			}
+		}
+
+		for claim in self.shared_claims.iter().filter(|claim| claim.spending_tx.is_none()) {
+			match claim.request.witness_data {
+				InputMaterial::Revoked { ref amount, ref htlc, ref on_counterparty_tx_csv, .. } => {
+					let timeout_height = revoked_output_timeout(&claim.request.outpoint, htlc, *on_counterparty_tx_csv).unwrap_or(claim.request.absolute_timelock);
+					res.push(Balance::ContentiousClaimable { claimable_amount_satoshis: *amount, timeout_height });
+				},
+				InputMaterial::CounterpartyHTLC { ref htlc, .. } => {
+					res.push(Balance::ContentiousClaimable { claimable_amount_satoshis: htlc.amount_msat / 1000, timeout_height: htlc.cltv_expiry });
+				},
+				_ => {},
+			}
+		}
+
+		for entry in self.onchain_events_waiting_threshold_conf.iter() {
+			if let OnchainEvent::MaturingOutput { ref descriptor } = entry.event {
+				match descriptor {
+					&SpendableOutputDescriptor::DynamicOutputP2WSH { ref output, ref to_self_delay, .. } => {
+						res.push(Balance::ClaimableAwaitingCsv {
+							claimable_amount_satoshis: output.value,
+							claimable_height: entry.height + *to_self_delay as u32,
+						});
+					},
+					&SpendableOutputDescriptor::StaticOutput { ref output, .. }|&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref output, .. } => {
+						res.push(Balance::ClaimableAwaitingConfirmations {
+							claimable_amount_satoshis: output.value,
+							confirmation_height: entry.confirmation_threshold(),
+						});
+					},
+				}
+			}
+		}
+
+		res
+	}
+
+	/// Monitors written before version 2 didn't track the transaction spending the funding output.
+	/// A counterparty commitment transaction only has its outputs watched once it confirmed, so we
+	/// recover it from those. Its height is that at which we registered claims on its outputs or
+	/// otherwise that of the earliest on-chain event pending, which was triggered by the commitment
+	/// transaction or a later one. If neither exists there is nothing left to claim and we use 0.
+	///
+	/// Our own commitment transactions have their outputs watched as soon as we broadcast them and
+	/// closing transactions aren't watched at all, so neither can be recovered. Such channels keep
+	/// reporting a balance claimable on close until the funding spend is seen again in a block, or
+	/// forever if the spend was confirmed before the monitor was upgraded.
+	pub(crate) fn recover_legacy_funding_spend(&mut self) {
+		let counterparty_claimable_outpoints = &self.counterparty_claimable_outpoints;
+		let spend_txid = match self.outputs_to_watch.keys().find(|&txid| counterparty_claimable_outpoints.contains_key(txid)) {
+			Some(txid) => *txid,
+			None => return,
+		};
+		let spend_height = self.onchain_tx_handler.get_claim_height(&spend_txid)
+			.or(self.onchain_events_waiting_threshold_conf.iter().map(|entry| entry.height).min())
+			.unwrap_or(0);
+		self.funding_spend_confirmed = Some((spend_txid, spend_height));
+	}
+
+	/// Returns true once the channel was closed on-chain and there is nothing left for this monitor
+	/// to do, i.e. the funding spend and all our claims reached ANTI_REORG_DELAY confirmations, all
+	/// outputs paying us were handed over through an [`Event::SpendableOutputs`] and all pending
+	/// events were consumed. HTLCs paid to us on the confirmed commitment must also have expired,
+	/// as we may still learn their preimage and claim them on-chain until then.
+	///
+	/// Such a monitor may be archived and stopped being given blocks, see
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`].
+	///
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	/// [`ChainMonitor::archive_fully_resolved_channel_monitors`]: ../chainmonitor/struct.ChainMonitor.html#method.archive_fully_resolved_channel_monitors
+	pub fn is_fully_resolved(&self) -> bool {
+		let (spend_txid, spend_height) = match self.funding_spend_confirmed {
+			Some(funding_spend) => funding_spend,
+			None => return false,
+		};
+		if spend_height + ANTI_REORG_DELAY - 1 > self.last_block_height { return false; }
+
+		// Offered HTLCs on a counterparty commitment and received HTLCs on one of ours are the ones
+		// we may claim with a preimage.
+		let claimable_with_preimage_pending = |htlc: &HTLCOutputInCommitment, counterparty_tx: bool| {
+			htlc.offered == counterparty_tx && htlc.transaction_output_index.is_some() && htlc.cltv_expiry > self.last_block_height
+		};
+		if let Some(htlc_outputs) = self.counterparty_claimable_outpoints.get(&spend_txid) {
+			if htlc_outputs.iter().any(|&(ref htlc, _)| claimable_with_preimage_pending(htlc, true)) { return false; }
+		}
+		for holder_tx in self.prev_holder_signed_commitment_tx.iter().chain(Some(&self.current_holder_commitment_tx)) {
+			if holder_tx.txid == spend_txid && holder_tx.htlc_outputs.iter().any(|&(ref htlc, _, _)| claimable_with_preimage_pending(htlc, false)) {
+				return false;
+			}
+		}
+
+		self.onchain_events_waiting_threshold_conf.is_empty() && self.shared_claims.is_empty() &&
+			self.pending_monitor_events.is_empty() && self.pending_events.is_empty() &&
+			!self.onchain_tx_handler.has_pending_claims() && self.get_claimable_balances().is_empty()
+	}
+
+	/// Common processing once transactions at `height` have been checked for relevant spends:
+	/// broadcasts our holder commitment transaction if needed, passes matured onchain events
+	/// upstream and updates claims in the `OnchainTxHandler`.
+	fn block_confirmed<B: Deref, F: Deref, L: Deref>(&mut self, height: u32, txn_matched: Vec<&Transaction>, mut watch_outputs: Vec<(Txid, Vec<TxOut>)>, mut claimable_outpoints: Vec<ClaimRequest>, broadcaster: B, fee_estimator: F, logger: L) -> Vec<(Txid, Vec<TxOut>)>
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let mut should_broadcast = self.would_broadcast_at_height(self.last_block_height, &logger);
+		if should_broadcast && self.is_stale_replica() {
+			log_error!(logger, "Not broadcasting holder commitment transaction as a replica with update {} is behind update {}", self.latest_update_id, self.replication.as_ref().unwrap().latest_known_update_id);
+			should_broadcast = false;
+		}
 		if should_broadcast {
 			claimable_outpoints.push(ClaimRequest { absolute_timelock: height, aggregable: false, outpoint: BitcoinOutPoint { txid: self.funding_info.0.txid.clone(), vout: self.funding_info.0.index as u32 }, witness_data: InputMaterial::Funding { funding_redeemscript: self.funding_redeemscript.clone() }});
 		}
@@ -1736,29 +2413,33 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 				claimable_outpoints.append(&mut new_outpoints);
 			}
 		}
-		if let Some(events) = self.onchain_events_waiting_threshold_conf.remove(&height) {
-			for ev in events {
-				match ev {
-					OnchainEvent::HTLCUpdate { htlc_update } => {
-						log_trace!(logger, "HTLC {} failure update has got enough confirmations to be passed upstream", log_bytes!((htlc_update.1).0));
-						self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
-							payment_hash: htlc_update.1,
-							payment_preimage: None,
-							source: htlc_update.0,
-						}));
-					},
-					OnchainEvent::MaturingOutput { descriptor } => {
-						log_trace!(logger, "Descriptor {} has got enough confirmations to be passed upstream", log_spendable!(descriptor));
-						self.pending_events.push(Event::SpendableOutputs {
-							outputs: vec![descriptor]
-						});
-					}
+
+		// Find which onchain events have reached their confirmation threshold.
+		let last_block_height = self.last_block_height;
+		let (onchain_events_reaching_threshold_conf, onchain_events_waiting_threshold_conf): (Vec<_>, Vec<_>) =
+			self.onchain_events_waiting_threshold_conf.drain(..).partition(|entry| entry.has_reached_confirmation_threshold(last_block_height));
+		self.onchain_events_waiting_threshold_conf = onchain_events_waiting_threshold_conf;
+		for entry in onchain_events_reaching_threshold_conf {
+			match entry.event {
+				OnchainEvent::HTLCUpdate { htlc_update } => {
+					log_trace!(logger, "HTLC {} failure update has got enough confirmations to be passed upstream", log_bytes!((htlc_update.1).0));
+					self.pending_monitor_events.push(MonitorEvent::HTLCEvent(HTLCUpdate {
+						payment_hash: htlc_update.1,
+						payment_preimage: None,
+						source: htlc_update.0,
+					}));
+				},
+				OnchainEvent::MaturingOutput { descriptor } => {
+					log_trace!(logger, "Descriptor {} has got enough confirmations to be passed upstream", log_spendable!(descriptor));
+					self.pending_events.push(Event::SpendableOutputs {
+						outputs: vec![descriptor]
+					});
 				}
 			}
 		}
 
-		self.onchain_tx_handler.block_connected(&txn_matched, claimable_outpoints, height, &*broadcaster, &*fee_estimator, &*logger);
-		self.last_block_hash = block_hash;
+		self.update_shared_claims(height, &txn_matched, &mut claimable_outpoints);
+		self.onchain_tx_handler.update_claims_view(&txn_matched, claimable_outpoints, height, last_block_height, &*broadcaster, &*fee_estimator, &*logger);
 
 		// Determine new outputs to watch by comparing against previously known outputs to watch,
 		// updating the latter in the process.
@@ -1769,25 +2450,93 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		watch_outputs
 	}
 
-	/// Determines if the disconnected block contained any transactions of interest and updates
-	/// appropriately.
-	pub fn block_disconnected<B: Deref, F: Deref, L: Deref>(&mut self, header: &BlockHeader, height: u32, broadcaster: B, fee_estimator: F, logger: L)
-		where B::Target: BroadcasterInterface,
-		      F::Target: FeeEstimator,
-		      L::Target: Logger,
-	{
-		let block_hash = header.block_hash();
-		log_trace!(logger, "Block {} at height {} disconnected", block_hash, height);
+	/// Tracks the resolution of our shared claims by the transactions confirmed at `height` and,
+	/// depending on whether our ChainMonitor aggregates claims, moves any non-time-critical new
+	/// claims to shared_claims and hands those which became time-critical back to our
+	/// OnchainTxHandler, or hands all shared claims back to it.
+	fn update_shared_claims(&mut self, height: u32, txn_matched: &[&Transaction], claimable_outpoints: &mut Vec<ClaimRequest>) {
+		for tx in txn_matched.iter() {
+			for input in tx.input.iter() {
+				for claim in self.shared_claims.iter_mut() {
+					if claim.request.outpoint == input.previous_output && claim.spending_tx.is_none() {
+						claim.spending_tx = Some((tx.txid(), height));
+					}
+				}
+			}
+		}
+		let last_block_height = self.last_block_height;
+		self.shared_claims.retain(|claim| match claim.spending_tx {
+			Some((_, spending_height)) => spending_height + ANTI_REORG_DELAY - 1 > last_block_height,
+			None => true,
+		});
 
-		if let Some(_) = self.onchain_events_waiting_threshold_conf.remove(&(height + ANTI_REORG_DELAY - 1)) {
-			//We may discard:
-			//- htlc update there as failure-trigger tx (revoked commitment tx, non-revoked commitment tx, HTLC-timeout tx) has been disconnected
-			//- maturing spendable output has transaction paying us has been disconnected
+		if self.defer_shared_claims {
+			// Claims deferred earlier become time-critical as blocks go by, at which point we claim
+			// them ourselves so that the shared claim transaction can't hold them up.
+			let (expiring_claims, shared_claims): (Vec<SharedClaim>, Vec<SharedClaim>) = self.shared_claims.drain(..).partition(|claim| {
+				claim.spending_tx.is_none() && claim.request.absolute_timelock <= last_block_height + CLTV_SHARED_CLAIM_BUFFER
+			});
+			self.shared_claims = shared_claims;
+			let mut own_claims: Vec<ClaimRequest> = expiring_claims.into_iter().map(|claim| claim.request).collect();
+			for request in claimable_outpoints.drain(..) {
+				// Same criteria as OnchainTxHandler::update_claims_view uses to aggregate claims
+				if !request.aggregable || request.absolute_timelock <= last_block_height + CLTV_SHARED_CLAIM_BUFFER {
+					own_claims.push(request);
+				} else if !self.shared_claims.iter().any(|claim| claim.request.outpoint == request.outpoint) {
+					self.shared_claims.push(SharedClaim { request, height, spending_tx: None });
+				}
+			}
+			*claimable_outpoints = own_claims;
+		} else {
+			// Keep spent claims around until their spend is buried in case it gets reorged out.
+			let (unspent_claims, spent_claims) = self.shared_claims.drain(..).partition(|claim| claim.spending_tx.is_none());
+			self.shared_claims = spent_claims;
+			for claim in unspent_claims.into_iter() {
+				claimable_outpoints.push(claim.request);
+			}
 		}
+	}
 
-		self.onchain_tx_handler.block_disconnected(height, broadcaster, fee_estimator, logger);
+	fn shared_claims_disconnected(&mut self, height: u32) {
+		self.shared_claims.retain(|claim| claim.height < height);
+		for claim in self.shared_claims.iter_mut() {
+			if claim.spending_tx.map(|(_, spending_height)| spending_height >= height).unwrap_or(false) {
+				claim.spending_tx = None;
+			}
+		}
+	}
 
-		self.last_block_hash = block_hash;
+	/// Sets whether non-time-critical claims are deferred to our ChainMonitor for aggregation with
+	/// other channels' claims instead of being broadcast by our OnchainTxHandler.
+	pub(crate) fn set_defer_shared_claims(&mut self, defer: bool) {
+		self.defer_shared_claims = defer;
+	}
+
+	/// Gets the shared claims which haven't been spent yet.
+	pub(crate) fn get_unspent_shared_claims(&self) -> Vec<SharedClaimInput> {
+		self.shared_claims.iter().filter(|claim| claim.spending_tx.is_none()).map(|claim| {
+			let (amount, input_descriptor) = match claim.request.witness_data {
+				InputMaterial::Revoked { ref input_descriptor, ref amount, .. } => (*amount, *input_descriptor),
+				InputMaterial::CounterpartyHTLC { ref preimage, ref htlc, .. } =>
+					(htlc.amount_msat / 1000, if preimage.is_some() { InputDescriptors::OfferedHTLC } else { InputDescriptors::ReceivedHTLC }),
+				_ => unreachable!(), // Only aggregable claims are shared
+			};
+			SharedClaimInput { outpoint: claim.request.outpoint, amount, input_descriptor, absolute_timelock: claim.request.absolute_timelock }
+		}).collect()
+	}
+
+	pub(crate) fn get_destination_script(&self) -> &Script {
+		&self.destination_script
+	}
+
+	/// Signs the input at index `input_idx` of a shared claim transaction, which must spend one of
+	/// our unspent shared claims. Returns false if the claim is unknown or the signer refused.
+	pub(crate) fn sign_shared_claim_input<L: Deref>(&self, claim_tx: &mut Transaction, input_idx: usize, feerate: u64, logger: &L) -> bool where L::Target: Logger {
+		let outpoint = claim_tx.input[input_idx].previous_output;
+		match self.shared_claims.iter().find(|claim| claim.request.outpoint == outpoint && claim.spending_tx.is_none()) {
+			Some(claim) => self.onchain_tx_handler.sign_claim_input(claim_tx, input_idx, &outpoint, &claim.request.witness_data, feerate, logger).is_some(),
+			None => false,
+		}
 	}
 
 	/// Filters a block's `txdata` for transactions spending watched outputs or for any child
@@ -2005,23 +2754,20 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 					}
 				} else {
 					log_info!(logger, "Failing HTLC with payment_hash {} timeout by a spend tx, waiting for confirmation (at height{})", log_bytes!(payment_hash.0), height + ANTI_REORG_DELAY - 1);
-					match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-						hash_map::Entry::Occupied(mut entry) => {
-							let e = entry.get_mut();
-							e.retain(|ref event| {
-								match **event {
-									OnchainEvent::HTLCUpdate { ref htlc_update } => {
-										return htlc_update.0 != source
-									},
-									_ => true
-								}
-							});
-							e.push(OnchainEvent::HTLCUpdate { htlc_update: (source, payment_hash)});
-						}
-						hash_map::Entry::Vacant(entry) => {
-							entry.insert(vec![OnchainEvent::HTLCUpdate { htlc_update: (source, payment_hash)}]);
+					self.onchain_events_waiting_threshold_conf.retain(|ref entry| {
+						if entry.height != height { return true; }
+						match entry.event {
+							OnchainEvent::HTLCUpdate { ref htlc_update } => {
+								htlc_update.0 != source
+							},
+							_ => true
 						}
-					}
+					});
+					self.onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+						txid: tx.txid(),
+						height,
+						event: OnchainEvent::HTLCUpdate { htlc_update: (source, payment_hash)},
+					});
 				}
 			}
 		}
@@ -2079,15 +2825,11 @@ impl<ChanSigner: ChannelKeys> ChannelMonitor<ChanSigner> {
 		}
 		if let Some(spendable_output) = spendable_output {
 			log_trace!(logger, "Maturing {} until {}", log_spendable!(spendable_output), height + ANTI_REORG_DELAY - 1);
-			match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-				hash_map::Entry::Occupied(mut entry) => {
-					let e = entry.get_mut();
-					e.push(OnchainEvent::MaturingOutput { descriptor: spendable_output });
-				}
-				hash_map::Entry::Vacant(entry) => {
-					entry.insert(vec![OnchainEvent::MaturingOutput { descriptor: spendable_output }]);
-				}
-			}
+			self.onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+				txid: tx.txid(),
+				height,
+				event: OnchainEvent::MaturingOutput { descriptor: spendable_output },
+			});
 		}
 	}
 }
@@ -2105,7 +2847,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 			}
 		}
 
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -2138,6 +2880,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 		let funding_info = (outpoint, Readable::read(reader)?);
 		let current_counterparty_commitment_txid = Readable::read(reader)?;
 		let prev_counterparty_commitment_txid = Readable::read(reader)?;
+		let current_counterparty_commitment_tx = if ver >= 2 { Readable::read(reader)? } else { None };
 
 		let counterparty_tx_cache = Readable::read(reader)?;
 		let funding_redeemscript = Readable::read(reader)?;
@@ -2287,32 +3030,33 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 
 		let last_block_hash: BlockHash = Readable::read(reader)?;
 
+		// Before version 2 we didn't know the height of our last block, it is set again by the
+		// next block connected.
+		let last_block_height: u32 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+
 		let waiting_threshold_conf_len: u64 = Readable::read(reader)?;
-		let mut onchain_events_waiting_threshold_conf = HashMap::with_capacity(cmp::min(waiting_threshold_conf_len as usize, MAX_ALLOC_SIZE / 128));
+		let mut onchain_events_waiting_threshold_conf = Vec::with_capacity(cmp::min(waiting_threshold_conf_len as usize, MAX_ALLOC_SIZE / 128));
 		for _ in 0..waiting_threshold_conf_len {
-			let height_target = Readable::read(reader)?;
-			let events_len: u64 = Readable::read(reader)?;
-			let mut events = Vec::with_capacity(cmp::min(events_len as usize, MAX_ALLOC_SIZE / 128));
-			for _ in 0..events_len {
-				let ev = match <u8 as Readable>::read(reader)? {
-					0 => {
-						let htlc_source = Readable::read(reader)?;
-						let hash = Readable::read(reader)?;
-						OnchainEvent::HTLCUpdate {
-							htlc_update: (htlc_source, hash)
-						}
-					},
-					1 => {
-						let descriptor = Readable::read(reader)?;
-						OnchainEvent::MaturingOutput {
-							descriptor
-						}
-					},
-					_ => return Err(DecodeError::InvalidValue),
-				};
-				events.push(ev);
+			if ver >= 2 {
+				let txid = Readable::read(reader)?;
+				let height = Readable::read(reader)?;
+				let event = Readable::read(reader)?;
+				onchain_events_waiting_threshold_conf.push(OnchainEventEntry { txid, height, event });
+			} else {
+				// Events used to be grouped by the height at which they mature, without the
+				// transaction which triggered them, so they can only be reorged out by
+				// block_disconnected.
+				let height_target: u32 = Readable::read(reader)?;
+				let events_len: u64 = Readable::read(reader)?;
+				for _ in 0..events_len {
+					let event = Readable::read(reader)?;
+					onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+						txid: Default::default(),
+						height: (height_target + 1).saturating_sub(ANTI_REORG_DELAY),
+						event
+					});
+				}
 			}
-			onchain_events_waiting_threshold_conf.insert(height_target, events);
 		}
 
 		let outputs_to_watch_len: u64 = Readable::read(reader)?;
@@ -2328,12 +3072,53 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 				return Err(DecodeError::InvalidValue);
 			}
 		}
-		let onchain_tx_handler = Readable::read(reader)?;
+		let onchain_tx_handler = ReadableArgs::read(reader, ver)?;
 
 		let lockdown_from_offchain = Readable::read(reader)?;
 		let holder_tx_signed = Readable::read(reader)?;
 
-		Ok((last_block_hash.clone(), ChannelMonitor {
+		let replication_flag: u8 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+		let replication = match replication_flag {
+			0 => None,
+			1 => {
+				let latest_known_update_id = Readable::read(reader)?;
+				let applied_updates_len: u64 = Readable::read(reader)?;
+				let mut applied_updates = Vec::with_capacity(cmp::min(applied_updates_len as usize, MAX_ALLOC_SIZE / mem::size_of::<ChannelMonitorUpdate>()));
+				for _ in 0..applied_updates_len {
+					applied_updates.push(Readable::read(reader)?);
+				}
+				Some(ReplicationState { latest_known_update_id, applied_updates })
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		};
+
+		let defer_shared_claims = if ver >= 2 { Readable::read(reader)? } else { false };
+		let shared_claims_len: u64 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+		let mut shared_claims = Vec::with_capacity(cmp::min(shared_claims_len as usize, MAX_ALLOC_SIZE / 128));
+		for _ in 0..shared_claims_len {
+			let request = ClaimRequest {
+				absolute_timelock: Readable::read(reader)?,
+				aggregable: Readable::read(reader)?,
+				outpoint: Readable::read(reader)?,
+				witness_data: Readable::read(reader)?,
+			};
+			let height = Readable::read(reader)?;
+			let spending_tx = match <u8 as Readable>::read(reader)? {
+				0 => None,
+				1 => Some((Readable::read(reader)?, Readable::read(reader)?)),
+				_ => return Err(DecodeError::InvalidValue),
+			};
+			shared_claims.push(SharedClaim { request, height, spending_tx });
+		}
+
+		let funding_spend_flag: u8 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+		let funding_spend_confirmed = match funding_spend_flag {
+			0 => None,
+			1 => Some((Readable::read(reader)?, Readable::read(reader)?)),
+			_ => return Err(DecodeError::InvalidValue),
+		};
+
+		let mut monitor = ChannelMonitor {
 			latest_update_id,
 			commitment_transaction_number_obscure_factor,
 
@@ -2345,6 +3130,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 			keys,
 			funding_info,
 			current_counterparty_commitment_txid,
+			current_counterparty_commitment_tx,
 			prev_counterparty_commitment_txid,
 
 			counterparty_tx_cache,
@@ -2375,10 +3161,22 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for (BlockHash, ChannelMonitor
 
 			lockdown_from_offchain,
 			holder_tx_signed,
+			replication,
+
+			defer_shared_claims,
+			shared_claims,
+
+			funding_spend_confirmed,
 
 			last_block_hash,
+			last_block_height,
 			secp_ctx: Secp256k1::new(),
-		}))
+		};
+		if ver < 2 {
+			monitor.recover_legacy_funding_spend();
+		}
+
+		Ok((last_block_hash.clone(), monitor))
 	}
 }
 
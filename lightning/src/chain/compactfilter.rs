@@ -0,0 +1,301 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for driving a [`ChainMonitor`] from [BIP 157]/[BIP 158] compact block filters.
+//!
+//! [`FilterMatcher`] is a [`chain::Filter`] implementation which collects the scripts registered by
+//! a `ChainMonitor` and tests each block's basic filter against them. Only blocks whose filter
+//! matches are fetched from a [`BlockSource`] and connected in full; for all others the monitors
+//! are simply informed of the new best block.
+//!
+//! Since BIP 158 basic filters commit to both the output scripts and the spent previous output
+//! scripts of a block, watching the `script_pubkey` given on registration is sufficient to detect
+//! both confirmation of a registered transaction and any spend of a registered output.
+//!
+//! [`ChainMonitor`]: ../chainmonitor/struct.ChainMonitor.html
+//! [`FilterMatcher`]: struct.FilterMatcher.html
+//! [`chain::Filter`]: ../trait.Filter.html
+//! [`BlockSource`]: trait.BlockSource.html
+//! [BIP 157]: https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki
+//! [BIP 158]: https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::script::Script;
+use bitcoin::hash_types::{BlockHash, Txid};
+use bitcoin::util::bip158::BlockFilter;
+
+use chain;
+use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
+use chain::chainmonitor::ChainMonitor;
+use chain::keysinterface::ChannelKeys;
+use chain::transaction::OutPoint;
+use util::logger::Logger;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::ops::Deref;
+
+/// The `BlockSource` trait defines behavior for retrieving full blocks whose compact filter
+/// matched the watched scripts.
+pub trait BlockSource {
+	/// Returns the block with the given hash, if available.
+	fn get_block(&self, block_hash: &BlockHash) -> Option<Block>;
+}
+
+/// An error when processing a block via [`FilterMatcher::connect_block`].
+///
+/// [`FilterMatcher::connect_block`]: struct.FilterMatcher.html#method.connect_block
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterError {
+	/// The compact filter could not be decoded.
+	InvalidFilter,
+
+	/// The block matched the filter but could not be retrieved from the [`BlockSource`], or the
+	/// retrieved block does not correspond to the given header.
+	///
+	/// [`BlockSource`]: trait.BlockSource.html
+	BlockUnavailable,
+}
+
+/// A [`chain::Filter`] which matches compact block filters against registered scripts.
+///
+/// Should be given as the chain source of a [`ChainMonitor`], which will then register the funding
+/// transactions and outputs to watch for each channel. Blocks should be given in order to
+/// [`connect_block`] along with their basic filter.
+///
+/// [`chain::Filter`]: ../trait.Filter.html
+/// [`ChainMonitor`]: ../chainmonitor/struct.ChainMonitor.html
+/// [`connect_block`]: #method.connect_block
+pub struct FilterMatcher {
+	watched_scripts: Mutex<HashSet<Script>>,
+}
+
+impl FilterMatcher {
+	/// Creates a new `FilterMatcher` which initially watches no scripts.
+	pub fn new() -> Self {
+		Self {
+			watched_scripts: Mutex::new(HashSet::new()),
+		}
+	}
+
+	/// Returns whether `filter`, the basic filter of the block with hash `block_hash`, matches any
+	/// of the registered scripts. Never matches if no scripts have been registered.
+	pub fn matches(&self, block_hash: &BlockHash, filter: &BlockFilter) -> Result<bool, FilterError> {
+		let watched_scripts = self.watched_scripts.lock().unwrap();
+		if watched_scripts.is_empty() {
+			return Ok(false);
+		}
+		let mut query = watched_scripts.iter().map(|script| script.as_bytes());
+		filter.match_any(block_hash, &mut query).map_err(|_| FilterError::InvalidFilter)
+	}
+
+	/// Processes the block with the given `header` and `height` for `chain_monitor` using its
+	/// basic `filter`.
+	///
+	/// If the filter matches any registered script, the full block is fetched from `block_source`
+	/// and passed to [`ChainMonitor::block_connected`]. Otherwise, only
+	/// [`ChainMonitor::best_block_updated`] is called such that time-based events still progress.
+	///
+	/// Returns the fetched block, if any, so that it may also be given to other chain listeners
+	/// such as a [`ChannelManager`]. Blocks must be processed in order since scripts registered
+	/// while connecting a block are taken into account for subsequent blocks.
+	///
+	/// [`ChainMonitor::block_connected`]: ../chainmonitor/struct.ChainMonitor.html#method.block_connected
+	/// [`ChainMonitor::best_block_updated`]: ../chainmonitor/struct.ChainMonitor.html#method.best_block_updated
+	/// [`ChannelManager`]: ../../ln/channelmanager/struct.ChannelManager.html
+	pub fn connect_block<ChanSigner: ChannelKeys, C: Deref, T: Deref, F: Deref, L: Deref, B: Deref>(
+		&self, header: &BlockHeader, height: u32, filter: &BlockFilter, block_source: &B,
+		chain_monitor: &ChainMonitor<ChanSigner, C, T, F, L>
+	) -> Result<Option<Block>, FilterError>
+		where C::Target: chain::Filter,
+		      T::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+		      B::Target: BlockSource,
+	{
+		let block_hash = header.block_hash();
+		if !self.matches(&block_hash, filter)? {
+			chain_monitor.best_block_updated(header, height);
+			return Ok(None);
+		}
+
+		let block = match block_source.get_block(&block_hash) {
+			Some(block) => block,
+			None => return Err(FilterError::BlockUnavailable),
+		};
+		if block.block_hash() != block_hash || !block.check_merkle_root() {
+			return Err(FilterError::BlockUnavailable);
+		}
+
+		let txdata: Vec<_> = block.txdata.iter().enumerate().collect();
+		chain_monitor.block_connected(header, &txdata, height);
+		Ok(Some(block))
+	}
+}
+
+impl chain::Filter for FilterMatcher {
+	fn register_tx(&self, _txid: &Txid, script_pubkey: &Script) {
+		self.watched_scripts.lock().unwrap().insert(script_pubkey.clone());
+	}
+
+	fn register_output(&self, _outpoint: &OutPoint, script_pubkey: &Script) {
+		self.watched_scripts.lock().unwrap().insert(script_pubkey.clone());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::block::{Block, BlockHeader};
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{OutPoint as BitcoinOutPoint, Transaction, TxIn, TxOut};
+	use bitcoin::hash_types::BlockHash;
+	use bitcoin::util::bip158::{self, BlockFilter};
+	use chain::Filter;
+	use chain::chainmonitor::ChainMonitor;
+	use chain::compactfilter::{BlockSource, FilterError, FilterMatcher};
+	use chain::transaction::OutPoint;
+	use util::enforcing_trait_impls::EnforcingChannelKeys;
+	use util::test_utils::{TestBroadcaster, TestFeeEstimator, TestLogger};
+	use std::collections::HashMap;
+	use std::sync::{Arc, Mutex};
+
+	struct TestBlockSource {
+		blocks: HashMap<BlockHash, Block>,
+		fetched: Mutex<Vec<BlockHash>>,
+	}
+
+	impl BlockSource for TestBlockSource {
+		fn get_block(&self, block_hash: &BlockHash) -> Option<Block> {
+			self.fetched.lock().unwrap().push(*block_hash);
+			self.blocks.get(block_hash).cloned()
+		}
+	}
+
+	fn script(n: u8) -> Script {
+		Builder::new().push_slice(&[n; 20]).push_opcode(opcodes::all::OP_DROP).push_opcode(opcodes::OP_TRUE).into_script()
+	}
+
+	fn build_block(prev_blockhash: BlockHash, txdata: Vec<Transaction>) -> Block {
+		let mut block = Block {
+			header: BlockHeader { version: 0x20000000, prev_blockhash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 },
+			txdata,
+		};
+		block.header.merkle_root = block.merkle_root();
+		block
+	}
+
+	// Computes the basic filter of each block, resolving spent scripts from earlier blocks as a
+	// full node would.
+	fn build_filters(blocks: &[Block]) -> Vec<BlockFilter> {
+		let mut utxos = HashMap::new();
+		let mut filters = Vec::new();
+		for block in blocks {
+			filters.push(BlockFilter::new_script_filter(block, |outpoint| {
+				utxos.get(outpoint).cloned().ok_or(bip158::Error::UtxoMissing(*outpoint))
+			}).unwrap());
+			for tx in block.txdata.iter() {
+				for (idx, output) in tx.output.iter().enumerate() {
+					utxos.insert(BitcoinOutPoint { txid: tx.txid(), vout: idx as u32 }, output.script_pubkey.clone());
+				}
+			}
+		}
+		filters
+	}
+
+	fn coinbase(n: u8, script_pubkey: Script) -> Transaction {
+		Transaction { version: 1, lock_time: 0, input: vec![TxIn {
+			previous_output: BitcoinOutPoint::null(),
+			script_sig: Builder::new().push_int(n as i64).into_script(),
+			sequence: 0xffffffff,
+			witness: Vec::new(),
+		}], output: vec![TxOut { value: 50_0000_0000, script_pubkey }] }
+	}
+
+	fn spend(prev_tx: &Transaction, script_pubkey: Script) -> Transaction {
+		Transaction { version: 2, lock_time: 0, input: vec![TxIn {
+			previous_output: BitcoinOutPoint { txid: prev_tx.txid(), vout: 0 },
+			script_sig: Script::new(),
+			sequence: 0xffffffff,
+			witness: Vec::new(),
+		}], output: vec![TxOut { value: 49_0000_0000, script_pubkey }] }
+	}
+
+	#[test]
+	fn test_filter_matcher_fetches_matching_blocks() {
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let fee_estimator = TestFeeEstimator { sat_per_kw: 253 };
+		let logger = TestLogger::new();
+		let matcher = FilterMatcher::new();
+		let chain_monitor: ChainMonitor<EnforcingChannelKeys, _, _, _, _> = ChainMonitor::new(Some(&matcher), &broadcaster, &logger, &fee_estimator);
+
+		// Block 1 pays to a script we'll watch, block 2 doesn't touch it and block 3 spends it
+		// without any of its own outputs being watched.
+		let watched_tx = coinbase(1, script(1));
+		let block_1 = build_block(Default::default(), vec![watched_tx.clone()]);
+		let block_2 = build_block(block_1.block_hash(), vec![coinbase(2, script(2))]);
+		let block_3 = build_block(block_2.block_hash(), vec![coinbase(3, script(3)), spend(&watched_tx, script(4))]);
+		let blocks = vec![block_1, block_2, block_3];
+		let filters = build_filters(&blocks);
+
+		let block_source = Arc::new(TestBlockSource {
+			blocks: blocks.iter().map(|block| (block.block_hash(), block.clone())).collect(),
+			fetched: Mutex::new(Vec::new()),
+		});
+
+		// Nothing is watched yet, so even a block paying to the script isn't fetched.
+		assert!(!matcher.matches(&blocks[0].block_hash(), &filters[0]).unwrap());
+
+		matcher.register_tx(&watched_tx.txid(), &script(1));
+		assert!(matcher.matches(&blocks[0].block_hash(), &filters[0]).unwrap());
+		assert!(!matcher.matches(&blocks[1].block_hash(), &filters[1]).unwrap());
+		assert!(matcher.matches(&blocks[2].block_hash(), &filters[2]).unwrap());
+
+		for (height, (block, filter)) in blocks.iter().zip(filters.iter()).enumerate() {
+			let fetched = matcher.connect_block(&block.header, height as u32 + 1, filter, &block_source, &chain_monitor).unwrap();
+			assert_eq!(fetched.is_some(), height != 1);
+		}
+		assert_eq!(*block_source.fetched.lock().unwrap(), vec![blocks[0].block_hash(), blocks[2].block_hash()]);
+	}
+
+	#[test]
+	fn test_filter_matcher_registered_output_spend() {
+		let matcher = FilterMatcher::new();
+
+		let prev_tx = coinbase(1, script(1));
+		let block_1 = build_block(Default::default(), vec![prev_tx.clone()]);
+		let block_2 = build_block(block_1.block_hash(), vec![coinbase(2, script(2)), spend(&prev_tx, script(3))]);
+		let filters = build_filters(&[block_1.clone(), block_2.clone()]);
+
+		// Only the spent output is registered, which the filter includes via its previous script.
+		matcher.register_output(&OutPoint { txid: prev_tx.txid(), index: 0 }, &script(1));
+		assert!(matcher.matches(&block_2.block_hash(), &filters[1]).unwrap());
+
+		// A filter for another block hash is keyed differently and shouldn't match.
+		let unrelated = build_block(block_1.block_hash(), vec![coinbase(3, script(5))]);
+		let unrelated_filter = build_filters(&[block_1.clone(), unrelated.clone()]).pop().unwrap();
+		assert!(!matcher.matches(&unrelated.block_hash(), &unrelated_filter).unwrap());
+	}
+
+	#[test]
+	fn test_filter_matcher_block_unavailable() {
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let fee_estimator = TestFeeEstimator { sat_per_kw: 253 };
+		let logger = TestLogger::new();
+		let matcher = FilterMatcher::new();
+		let chain_monitor: ChainMonitor<EnforcingChannelKeys, _, _, _, _> = ChainMonitor::new(Some(&matcher), &broadcaster, &logger, &fee_estimator);
+
+		let block = build_block(Default::default(), vec![coinbase(1, script(1))]);
+		let filter = build_filters(&[block.clone()][..]).pop().unwrap();
+		matcher.register_tx(&block.txdata[0].txid(), &script(1));
+
+		let block_source = Arc::new(TestBlockSource { blocks: HashMap::new(), fetched: Mutex::new(Vec::new()) });
+		assert_eq!(matcher.connect_block(&block.header, 1, &filter, &block_source, &chain_monitor), Err(FilterError::BlockUnavailable));
+	}
+}
@@ -11,7 +11,7 @@
 //! spendable on-chain outputs which the user owns and is responsible for using just as any other
 //! on-chain output which is theirs.
 
-use bitcoin::blockdata::transaction::{Transaction, TxOut, SigHashType};
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, SigHashType};
 use bitcoin::blockdata::script::{Script, Builder};
 use bitcoin::blockdata::opcodes;
 use bitcoin::network::constants::Network;
@@ -22,7 +22,7 @@ use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::hashes::sha256::HashEngine as Sha256State;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
-use bitcoin::hash_types::WPubkeyHash;
+use bitcoin::hash_types::{PubkeyHash, WPubkeyHash};
 
 use bitcoin::secp256k1::key::{SecretKey, PublicKey};
 use bitcoin::secp256k1::{Secp256k1, Signature, Signing};
@@ -36,10 +36,14 @@ use ln::chan_utils;
 use ln::chan_utils::{HTLCOutputInCommitment, make_funding_redeemscript, ChannelPublicKeys, HolderCommitmentTransaction, PreCalculatedTxCreationKeys};
 use ln::msgs::UnsignedChannelAnnouncement;
 
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::io::Error;
 use ln::msgs::DecodeError;
 
+/// Outputs below this value are considered non-standard and won't be relayed by the network.
+pub(crate) const DUST_LIMIT_SATOSHIS: u64 = 546;
+
 /// When on-chain outputs are created by rust-lightning (which our counterparty is not able to
 /// claim at any point in the future) an event is generated which you must track and be able to
 /// spend on-chain. The information needed to do this is provided in this enum, including the
@@ -52,6 +56,10 @@ pub enum SpendableOutputDescriptor {
 	/// script_pubkey as it appears in the output.
 	/// These may include outputs from a transaction punishing our counterparty or claiming an HTLC
 	/// on-chain using the payment preimage or after it has timed out.
+	///
+	/// Note that the script_pubkey may be a segwit v1 (e.g. P2TR) program if one was returned by
+	/// KeysInterface::get_destination_script, in which case the wallet owning it has to sign the
+	/// spending input itself, KeysManager only signing for its own P2WPKH scripts.
 	StaticOutput {
 		/// The outpoint which is spendable
 		outpoint: OutPoint,
@@ -122,6 +130,16 @@ pub enum SpendableOutputDescriptor {
 	}
 }
 
+impl SpendableOutputDescriptor {
+	pub(crate) fn outpoint(&self) -> &OutPoint {
+		match self {
+			&SpendableOutputDescriptor::StaticOutput { ref outpoint, .. } => outpoint,
+			&SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, .. } => outpoint,
+			&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref outpoint, .. } => outpoint,
+		}
+	}
+}
+
 impl Writeable for SpendableOutputDescriptor {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		match self {
@@ -182,7 +200,8 @@ impl Readable for SpendableOutputDescriptor {
 /// Signing services could be implemented on a hardware wallet. In this case,
 /// the current ChannelKeys would be a front-end on top of a communication
 /// channel connected to your secure device and lightning key material wouldn't
-/// reside on a hot server. Nevertheless, a this deployment would still need
+/// reside on a hot server. [`RemoteChannelKeys`] provides such a front-end, talking to a
+/// [`SignerServer`] over any transport. Nevertheless, a this deployment would still need
 /// to trust the ChannelManager to avoid loss of funds as this latest component
 /// could ask to sign commitment transaction with HTLCs paying to attacker pubkeys.
 ///
@@ -199,6 +218,9 @@ impl Readable for SpendableOutputDescriptor {
 /// Readable/Writable to serialize out a unique reference to this set of keys so
 /// that you can serialize the full ChannelManager object.
 ///
+/// [`RemoteChannelKeys`]: ../remotesigner/struct.RemoteChannelKeys.html
+/// [`SignerServer`]: ../remotesigner/struct.SignerServer.html
+///
 // (TODO: We shouldn't require that, and should have an API to get them at deser time, due mostly
 // to the possibility of reentrancy issues by calling the user's code during our deserialization
 // routine).
@@ -208,7 +230,10 @@ pub trait ChannelKeys : Send+Clone {
 	/// Gets the per-commitment point for a specific commitment number
 	///
 	/// Note that the commitment number starts at (1 << 48) - 1 and counts backwards.
-	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> PublicKey;
+	///
+	/// An external signer implementation may error here if it can't be reached, in which case
+	/// the channel is closed or, where that isn't required, the call is retried later.
+	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> Result<PublicKey, ()>;
 	/// Gets the commitment secret for a specific commitment number as part of the revocation process
 	///
 	/// An external signer implementation should error here if the commitment was already signed
@@ -217,8 +242,7 @@ pub trait ChannelKeys : Send+Clone {
 	/// May be called more than once for the same index.
 	///
 	/// Note that the commitment number starts at (1 << 48) - 1 and counts backwards.
-	/// TODO: return a Result so we can signal a validation error
-	fn release_commitment_secret(&self, idx: u64) -> [u8; 32];
+	fn release_commitment_secret(&self, idx: u64) -> Result<[u8; 32], ()>;
 	/// Gets the holder's channel public keys and basepoints
 	fn pubkeys(&self) -> &ChannelPublicKeys;
 	/// Gets arbitrary identifiers describing the set of keys which are provided back to you in
@@ -257,7 +281,9 @@ pub trait ChannelKeys : Send+Clone {
 	/// holder_commitment_tx values. While this will never be called with a revoked
 	/// holder_commitment_tx, it is possible that it is called with the second-latest
 	/// holder_commitment_tx (only if we haven't yet revoked it) if some watchtower/secondary
-	/// ChannelMonitor decided to broadcast before it had been updated to the latest.
+	/// ChannelMonitor decided to broadcast before it had been updated to the latest. Secondary
+	/// monitors in replication mode (see ChannelMonitor::enable_replication) will not do so once
+	/// they know another replica has seen a newer update.
 	///
 	/// Either an Err should be returned, or a Vec with one entry for each HTLC which exists in
 	/// holder_commitment_tx. For those HTLCs which have transaction_output_index set to None
@@ -324,8 +350,8 @@ pub trait ChannelKeys : Send+Clone {
 	///
 	/// We bind holder_selected_contest_delay late here for API convenience.
 	///
-	/// Will be called before any signatures are applied.
-	fn on_accept(&mut self, channel_points: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16);
+	/// Will be called before any signatures are applied. If this fails, the channel is not opened.
+	fn on_accept(&mut self, channel_points: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) -> Result<(), ()>;
 }
 
 /// A trait to describe an object which can get user secrets and key material.
@@ -336,12 +362,20 @@ pub trait KeysInterface: Send + Sync {
 	/// Get node secret key (aka node_id or network_key)
 	fn get_node_secret(&self) -> SecretKey;
 	/// Get destination redeemScript to encumber static protocol exit points.
+	///
+	/// This may be any standard script, including a segwit v1 (P2TR) program, as outputs paying
+	/// to it are handed over as SpendableOutputDescriptor::StaticOutput for the wallet to spend.
 	fn get_destination_script(&self) -> Script;
 	/// Get shutdown_pubkey to use as PublicKey at channel closure
+	///
+	/// Our shutdown scriptpubkey is always the P2WPKH of this key. Closing to a segwit v1 (P2TR)
+	/// script of our own is not supported yet, only accepting one from our counterparty.
 	fn get_shutdown_pubkey(&self) -> PublicKey;
 	/// Get a new set of ChannelKeys for per-channel secrets. These MUST be unique even if you
 	/// restarted with some stale data!
-	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> Self::ChanKeySigner;
+	///
+	/// If this fails, the channel is not opened.
+	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> Result<Self::ChanKeySigner, ()>;
 	/// Gets a unique, cryptographically-secure, random 32 byte value. This is used for encrypting
 	/// onion packets and for temporary channel IDs. There is no requirement that these be
 	/// persisted anywhere, though they must be unique across restarts.
@@ -438,6 +472,9 @@ impl InMemoryChannelKeys {
 		}
 	}
 
+	/// The value of the channel these keys are for.
+	pub fn channel_value_satoshis(&self) -> u64 { self.channel_value_satoshis }
+
 	/// Counterparty pubkeys.
 	/// Will panic if on_accept wasn't called.
 	pub fn counterparty_pubkeys(&self) -> &ChannelPublicKeys { &self.accepted_channel_data.as_ref().unwrap().counterparty_channel_pubkeys }
@@ -457,13 +494,13 @@ impl InMemoryChannelKeys {
 }
 
 impl ChannelKeys for InMemoryChannelKeys {
-	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> PublicKey {
+	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> Result<PublicKey, ()> {
 		let commitment_secret = SecretKey::from_slice(&chan_utils::build_commitment_secret(&self.commitment_seed, idx)).unwrap();
-		PublicKey::from_secret_key(secp_ctx, &commitment_secret)
+		Ok(PublicKey::from_secret_key(secp_ctx, &commitment_secret))
 	}
 
-	fn release_commitment_secret(&self, idx: u64) -> [u8; 32] {
-		chan_utils::build_commitment_secret(&self.commitment_seed, idx)
+	fn release_commitment_secret(&self, idx: u64) -> Result<[u8; 32], ()> {
+		Ok(chan_utils::build_commitment_secret(&self.commitment_seed, idx))
 	}
 
 	fn pubkeys(&self) -> &ChannelPublicKeys { &self.holder_channel_pubkeys }
@@ -588,13 +625,14 @@ impl ChannelKeys for InMemoryChannelKeys {
 		Ok(secp_ctx.sign(&msghash, &self.funding_key))
 	}
 
-	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) {
+	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) -> Result<(), ()> {
 		assert!(self.accepted_channel_data.is_none(), "Already accepted");
 		self.accepted_channel_data = Some(AcceptedChannelData {
 			counterparty_channel_pubkeys: channel_pubkeys.clone(),
 			counterparty_selected_contest_delay,
 			holder_selected_contest_delay,
 		});
+		Ok(())
 	}
 }
 
@@ -651,6 +689,20 @@ impl Readable for InMemoryChannelKeys {
 	}
 }
 
+/// Persists the index the next channel's keys will be derived at by a KeysManager created with
+/// `new_deterministic`.
+pub trait ChannelIndexPersister: Send + Sync {
+	/// Durably stores next_channel_index, which must be passed to `KeysManager::new_deterministic`
+	/// on restart. It is called for inbound and outbound channels alike, and the keys at the index
+	/// before it are only handed out once it succeeds, so they can never be reused after a restart.
+	///
+	/// If this fails, the channel is not opened.
+	fn persist_next_channel_index(&self, next_channel_index: u32) -> Result<(), ()>;
+}
+
+/// Channel indices are used as hardened BIP 32 child numbers, so must be below 2^31.
+const CHANNEL_INDEX_LIMIT: u32 = 1 << 31;
+
 /// Simple KeysInterface implementor that takes a 32-byte seed for use as a BIP 32 extended key
 /// and derives keys from that.
 ///
@@ -658,15 +710,24 @@ impl Readable for InMemoryChannelKeys {
 /// ChannelMonitor closes may use seed/1'
 /// Cooperative closes may use seed/2'
 /// The two close keys may be needed to claim on-chain funds!
+///
+/// Channel keys are derived from seed/3'/index' mixed with the starting time, unless the
+/// `KeysManager` was created with `new_deterministic`, in which case they depend only on the seed
+/// and the index.
 pub struct KeysManager {
 	secp_ctx: Secp256k1<secp256k1::SignOnly>,
 	node_secret: SecretKey,
 	destination_script: Script,
+	destination_key: SecretKey,
 	shutdown_pubkey: PublicKey,
+	shutdown_key: SecretKey,
 	channel_master_key: ExtendedPrivKey,
 	channel_child_index: AtomicUsize,
 	rand_bytes_master_key: ExtendedPrivKey,
 	rand_bytes_child_index: AtomicUsize,
+	/// The next channel index and where to persist it, if channel keys are derived from the seed
+	/// and the index only.
+	deterministic_channel_index: Option<(Mutex<u32>, Box<ChannelIndexPersister>)>,
 
 	seed: [u8; 32],
 	starting_time_secs: u64,
@@ -698,17 +759,18 @@ impl KeysManager {
 		match ExtendedPrivKey::new_master(network.clone(), seed) {
 			Ok(master_key) => {
 				let node_secret = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(0).unwrap()).expect("Your RNG is busted").private_key.key;
-				let destination_script = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(1).unwrap()) {
+				let (destination_script, destination_key) = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(1).unwrap()) {
 					Ok(destination_key) => {
 						let wpubkey_hash = WPubkeyHash::hash(&ExtendedPubKey::from_private(&secp_ctx, &destination_key).public_key.to_bytes());
-						Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
-						              .push_slice(&wpubkey_hash.into_inner())
-						              .into_script()
+						(Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+						               .push_slice(&wpubkey_hash.into_inner())
+						               .into_script(),
+						 destination_key.private_key.key)
 					},
 					Err(_) => panic!("Your RNG is busted"),
 				};
-				let shutdown_pubkey = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(2).unwrap()) {
-					Ok(shutdown_key) => ExtendedPubKey::from_private(&secp_ctx, &shutdown_key).public_key.key,
+				let (shutdown_pubkey, shutdown_key) = match master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(2).unwrap()) {
+					Ok(shutdown_key) => (ExtendedPubKey::from_private(&secp_ctx, &shutdown_key).public_key.key, shutdown_key.private_key.key),
 					Err(_) => panic!("Your RNG is busted"),
 				};
 				let channel_master_key = master_key.ckd_priv(&secp_ctx, ChildNumber::from_hardened_idx(3).unwrap()).expect("Your RNG is busted");
@@ -718,11 +780,14 @@ impl KeysManager {
 					secp_ctx,
 					node_secret,
 					destination_script,
+					destination_key,
 					shutdown_pubkey,
+					shutdown_key,
 					channel_master_key,
 					channel_child_index: AtomicUsize::new(0),
 					rand_bytes_master_key,
 					rand_bytes_child_index: AtomicUsize::new(0),
+					deterministic_channel_index: None,
 
 					seed: *seed,
 					starting_time_secs,
@@ -732,6 +797,95 @@ impl KeysManager {
 			Err(_) => panic!("Your rng is busted"),
 		}
 	}
+	/// Constructs a KeysManager whose channel keys are derived from the seed and a channel index
+	/// only, so that they can be recovered from the seed with `recover_channel_keys`.
+	///
+	/// Each time channel keys are handed out, the index past them is stored with index_persister,
+	/// and the last index it stored must be passed in as next_channel_index on restart so that
+	/// channel keys are never reused. starting_time is still used to generate random bytes and must
+	/// be unique as described in `new`.
+	pub fn new_deterministic(seed: &[u8; 32], network: Network, starting_time_secs: u64, starting_time_nanos: u32, next_channel_index: u32, index_persister: Box<ChannelIndexPersister>) -> Self {
+		let mut keys_manager = Self::new(seed, network, starting_time_secs, starting_time_nanos);
+		keys_manager.deterministic_channel_index = Some((Mutex::new(next_channel_index), index_persister));
+		keys_manager
+	}
+
+	/// Gets the index the next channel's keys will be derived at.
+	pub fn get_next_channel_index(&self) -> u32 {
+		match self.deterministic_channel_index {
+			Some((ref next_channel_index, _)) => *next_channel_index.lock().unwrap(),
+			None => self.channel_child_index.load(Ordering::Acquire) as u32,
+		}
+	}
+
+	/// Re-derives the keys of the channel opened at the given channel index by a `KeysManager`
+	/// created with `new_deterministic`, from nothing but the seed.
+	///
+	/// As the channel value can't be recovered, it is set to 0, which doesn't prevent deriving the
+	/// keys needed to spend our outputs on-chain. Fails if the index is 2^31 or above, as no
+	/// channel keys are ever derived at such an index.
+	pub fn recover_channel_keys_at_index(&self, channel_index: u32) -> Result<InMemoryChannelKeys, ()> {
+		if channel_index >= CHANNEL_INDEX_LIMIT {
+			return Err(());
+		}
+		Ok(self.derive_channel_keys(0, (channel_index as u64) << 32, 0))
+	}
+
+	/// Re-derives the keys of the channels with the given ids (see `OutPoint::to_channel_id`)
+	/// opened by a `KeysManager` created with `new_deterministic`, e.g. after losing all channel
+	/// data but the seed.
+	///
+	/// Channel keys are derived before the funding transaction exists, so neither the funding
+	/// outpoint nor the channel id can select them, and a funding output only commits to the hash
+	/// of both parties' funding keys. Instead, the transactions in `spending_txn` which spend a
+	/// channel's funding output, such as our counterparty's commitment transaction, reveal its
+	/// funding redeemscript, and the keys at each channel index below `max_channel_index` are
+	/// scanned for the funding key in it. See `recover_channel_keys_at_index`.
+	///
+	/// Returns, in order, each channel id along with the index and keys of the channel, if found.
+	/// Fails if `max_channel_index` is above 2^31.
+	pub fn recover_channel_keys(&self, channel_ids: &[[u8; 32]], spending_txn: &[Transaction], max_channel_index: u32) -> Result<Vec<([u8; 32], Option<(u32, InMemoryChannelKeys)>)>, ()> {
+		if max_channel_index > CHANNEL_INDEX_LIMIT {
+			return Err(());
+		}
+		let mut funding_redeemscripts = Vec::new();
+		for tx in spending_txn.iter() {
+			for input in tx.input.iter() {
+				let channel_id = OutPoint { txid: input.previous_output.txid, index: input.previous_output.vout as u16 }.to_channel_id();
+				if !channel_ids.contains(&channel_id) {
+					continue;
+				}
+				if let Some(redeemscript) = input.witness.last() {
+					// 2 <pubkey> <pubkey> 2 OP_CHECKMULTISIG
+					if redeemscript.len() == 71 {
+						funding_redeemscripts.push((channel_id, redeemscript));
+					}
+				}
+			}
+		}
+
+		let mut recovered: Vec<([u8; 32], Option<(u32, InMemoryChannelKeys)>)> = channel_ids.iter().map(|channel_id| (*channel_id, None)).collect();
+		for channel_index in 0..max_channel_index {
+			if funding_redeemscripts.is_empty() {
+				break;
+			}
+			let keys = self.recover_channel_keys_at_index(channel_index)?;
+			let funding_pubkey = keys.pubkeys().funding_pubkey.serialize();
+			let matched_channel_id = funding_redeemscripts.iter()
+				.find(|&&(_, redeemscript)| redeemscript[2..35] == funding_pubkey[..] || redeemscript[36..69] == funding_pubkey[..])
+				.map(|&(channel_id, _)| channel_id);
+			if let Some(channel_id) = matched_channel_id {
+				funding_redeemscripts.retain(|&(id, _)| id != channel_id);
+				for entry in recovered.iter_mut() {
+					if entry.0 == channel_id && entry.1.is_none() {
+						entry.1 = Some((channel_index, keys.clone()));
+					}
+				}
+			}
+		}
+		Ok(recovered)
+	}
+
 	fn derive_unique_start(&self) -> Sha256State {
 		let mut unique_start = Sha256::engine();
 		unique_start.input(&byte_utils::be64_to_array(self.starting_time_secs));
@@ -792,6 +946,121 @@ impl KeysManager {
 			(params_1, params_2),
 		)
 	}
+
+	/// Creates a transaction spending all of the given outputs to `destination_script`, paying a
+	/// fee at `feerate_sat_per_1000_weight`, with each input signed.
+	///
+	/// `StaticOutput`s may only be spent if they pay to our destination script or to our shutdown
+	/// pubkey, as is the case for all such outputs generated by rust-lightning when using this
+	/// `KeysManager`. `destination_script` may be any standard script, e.g. a segwit v1 (P2TR)
+	/// program of an external wallet. Note that the transaction can't be broadcast before the
+	/// `to_self_delay` of any `DynamicOutputP2WSH` has elapsed.
+	///
+	/// Signing for segwit v1 (P2TR) outputs is not supported yet, so `StaticOutput`s paying one,
+	/// e.g. to the destination script of another `KeysInterface`, have to be swept by the wallet
+	/// owning them.
+	///
+	/// Returns an Err if there are no descriptors, if an output can't be signed for or if the
+	/// outputs aren't worth enough to pay the fee and leave a non-dust output.
+	pub fn spend_spendable_outputs(&self, descriptors: &[&SpendableOutputDescriptor], destination_script: Script, feerate_sat_per_1000_weight: u32) -> Result<Transaction, ()> {
+		if descriptors.is_empty() {
+			return Err(());
+		}
+
+		// The key to sign each input with, along with the script code committed to in the signature
+		// and the witness after the signature.
+		let mut input_signing_data = Vec::with_capacity(descriptors.len());
+		let mut spend_tx = Transaction { version: 2, lock_time: 0, input: Vec::with_capacity(descriptors.len()), output: Vec::with_capacity(1) };
+		let mut input_value = 0;
+		// Segwit marker and flag
+		let mut witness_weight = 2;
+		for descriptor in descriptors.iter() {
+			let (outpoint, output, sequence, key, script_code, witness_tail) = match *descriptor {
+				&SpendableOutputDescriptor::StaticOutput { ref outpoint, ref output } => {
+					// Our own scripts are always P2WPKH. Segwit v1 (P2TR) outputs would need schnorr
+					// signatures and BIP 341 sighashes, which our dependencies don't provide yet.
+					let key = if output.script_pubkey == self.destination_script {
+						self.destination_key
+					} else if output.script_pubkey == Self::p2wpkh_script(&self.shutdown_pubkey) {
+						self.shutdown_key
+					} else {
+						return Err(());
+					};
+					let pubkey = PublicKey::from_secret_key(&self.secp_ctx, &key);
+					(outpoint, output, 0xfffffffd, key, Self::p2pkh_script(&pubkey), vec![pubkey.serialize().to_vec()])
+				},
+				&SpendableOutputDescriptor::DynamicOutputP2WSH { ref outpoint, ref per_commitment_point, ref to_self_delay, ref output, ref key_derivation_params, ref revocation_pubkey } => {
+					// The channel value isn't used for key derivation
+					let keys = self.derive_channel_keys(0, key_derivation_params.0, key_derivation_params.1);
+					let key = chan_utils::derive_private_key(&self.secp_ctx, per_commitment_point, &keys.delayed_payment_base_key).map_err(|_| ())?;
+					let witness_script = chan_utils::get_revokeable_redeemscript(revocation_pubkey, *to_self_delay, &PublicKey::from_secret_key(&self.secp_ctx, &key));
+					if output.script_pubkey != witness_script.to_v0_p2wsh() {
+						return Err(());
+					}
+					// Due to BIP146 (MINIMALIF) the non-revocation branch is selected by an empty element
+					(outpoint, output, *to_self_delay as u32, key, witness_script.clone(), vec![vec![], witness_script.into_bytes()])
+				},
+				&SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref outpoint, ref output, ref key_derivation_params } => {
+					let keys = self.derive_channel_keys(0, key_derivation_params.0, key_derivation_params.1);
+					let pubkey = keys.pubkeys().payment_point;
+					if output.script_pubkey != Self::p2wpkh_script(&pubkey) {
+						return Err(());
+					}
+					(outpoint, output, 0xfffffffd, keys.payment_key, Self::p2pkh_script(&pubkey), vec![pubkey.serialize().to_vec()])
+				},
+			};
+			spend_tx.input.push(TxIn {
+				previous_output: outpoint.into_bitcoin_outpoint(),
+				script_sig: Script::new(),
+				sequence,
+				witness: Vec::new(),
+			});
+			input_value += output.value;
+			// Number of witness elements, then the (max 73-byte) signature and each other element,
+			// each prefixed with its length.
+			witness_weight += 1 + 1 + 73 + witness_tail.iter().map(|elem| 1 + elem.len()).sum::<usize>();
+			input_signing_data.push((key, script_code, output.value, witness_tail));
+		}
+		spend_tx.output.push(TxOut { script_pubkey: destination_script, value: 0 });
+
+		let fee = (spend_tx.get_weight() + witness_weight) as u64 * feerate_sat_per_1000_weight as u64 / 1000;
+		if input_value < fee + DUST_LIMIT_SATOSHIS {
+			return Err(());
+		}
+		spend_tx.output[0].value = input_value - fee;
+
+		let mut witnesses = Vec::with_capacity(input_signing_data.len());
+		{
+			let mut sighash_cache = bip143::SigHashCache::new(&spend_tx);
+			for (idx, &(ref key, ref script_code, value, ref witness_tail)) in input_signing_data.iter().enumerate() {
+				let sighash = hash_to_message!(&sighash_cache.signature_hash(idx, script_code, value, SigHashType::All)[..]);
+				let mut sig = self.secp_ctx.sign(&sighash, key).serialize_der().to_vec();
+				sig.push(SigHashType::All as u8);
+				let mut witness = vec![sig];
+				witness.extend(witness_tail.iter().cloned());
+				witnesses.push(witness);
+			}
+		}
+		for (input, witness) in spend_tx.input.iter_mut().zip(witnesses.drain(..)) {
+			input.witness = witness;
+		}
+		Ok(spend_tx)
+	}
+
+	fn p2wpkh_script(pubkey: &PublicKey) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0)
+		              .push_slice(&WPubkeyHash::hash(&pubkey.serialize()).into_inner())
+		              .into_script()
+	}
+
+	fn p2pkh_script(pubkey: &PublicKey) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_DUP)
+		              .push_opcode(opcodes::all::OP_HASH160)
+		              .push_slice(&PubkeyHash::hash(&pubkey.serialize()).into_inner())
+		              .push_opcode(opcodes::all::OP_EQUALVERIFY)
+		              .push_opcode(opcodes::all::OP_CHECKSIG)
+		              .into_script()
+	}
 }
 
 impl KeysInterface for KeysManager {
@@ -801,6 +1070,10 @@ impl KeysInterface for KeysManager {
 		self.node_secret.clone()
 	}
 
+	// TODO: Return P2TR destination and shutdown scripts, describe the outputs paying to them with
+	// new SpendableOutputDescriptor variants, and sign for those in spend_spendable_outputs, once
+	// our bitcoin and secp256k1 dependencies support schnorr signatures and BIP 341 sighashes.
+	// Until then, only accepting our counterparty's segwit v1 shutdown scripts is supported.
 	fn get_destination_script(&self) -> Script {
 		self.destination_script.clone()
 	}
@@ -809,10 +1082,23 @@ impl KeysInterface for KeysManager {
 		self.shutdown_pubkey.clone()
 	}
 
-	fn get_channel_keys(&self, _inbound: bool, channel_value_satoshis: u64) -> Self::ChanKeySigner {
+	fn get_channel_keys(&self, _inbound: bool, channel_value_satoshis: u64) -> Result<Self::ChanKeySigner, ()> {
+		if let Some((ref next_channel_index, ref index_persister)) = self.deterministic_channel_index {
+			let mut next_channel_index = next_channel_index.lock().unwrap();
+			let channel_index = *next_channel_index;
+			if channel_index >= CHANNEL_INDEX_LIMIT {
+				return Err(());
+			}
+			index_persister.persist_next_channel_index(channel_index + 1)?;
+			*next_channel_index = channel_index + 1;
+			return Ok(self.derive_channel_keys(channel_value_satoshis, (channel_index as u64) << 32, 0));
+		}
 		let child_ix = self.channel_child_index.fetch_add(1, Ordering::AcqRel);
+		if child_ix >= CHANNEL_INDEX_LIMIT as usize {
+			return Err(());
+		}
 		let ix_and_nanos: u64 = (child_ix as u64) << 32 | (self.starting_time_nanos as u64);
-		self.derive_channel_keys(channel_value_satoshis, ix_and_nanos, self.starting_time_secs)
+		Ok(self.derive_channel_keys(channel_value_satoshis, ix_and_nanos, self.starting_time_secs))
 	}
 
 	fn get_secure_random_bytes(&self) -> [u8; 32] {
@@ -826,3 +1112,129 @@ impl KeysInterface for KeysManager {
 		Sha256::from_engine(sha).into_inner()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use chain::keysinterface::{ChannelIndexPersister, ChannelKeys, KeysInterface, KeysManager, SpendableOutputDescriptor};
+	use chain::transaction::OutPoint;
+	use ln::chan_utils::make_funding_redeemscript;
+
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, OutPoint as BitcoinOutPoint};
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::network::constants::Network;
+	use bitcoin::hash_types::Txid;
+	use bitcoin::hashes::Hash;
+	use bitcoin::secp256k1::key::{SecretKey, PublicKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	use std::sync::{Arc, Mutex};
+
+	struct TestChannelIndexPersister {
+		persisted_indices: Arc<Mutex<Vec<u32>>>,
+	}
+	impl ChannelIndexPersister for TestChannelIndexPersister {
+		fn persist_next_channel_index(&self, next_channel_index: u32) -> Result<(), ()> {
+			let mut persisted_indices = self.persisted_indices.lock().unwrap();
+			// Only accept up to two more indices, to test failing to persist
+			if persisted_indices.len() >= 2 {
+				return Err(());
+			}
+			persisted_indices.push(next_channel_index);
+			Ok(())
+		}
+	}
+
+	fn get_deterministic_keys_manager(seed: &[u8; 32], starting_time: u64, next_channel_index: u32) -> (KeysManager, Arc<Mutex<Vec<u32>>>) {
+		let persisted_indices = Arc::new(Mutex::new(Vec::new()));
+		let index_persister = Box::new(TestChannelIndexPersister { persisted_indices: Arc::clone(&persisted_indices) });
+		(KeysManager::new_deterministic(seed, Network::Testnet, starting_time, starting_time as u32, next_channel_index, index_persister), persisted_indices)
+	}
+
+	#[test]
+	fn test_deterministic_channel_keys_recovery() {
+		let seed = [42; 32];
+		let (keys_manager, persisted_indices) = get_deterministic_keys_manager(&seed, 42, 0);
+		let first_keys = keys_manager.get_channel_keys(true, 1_000_000).unwrap();
+		let second_keys = keys_manager.get_channel_keys(false, 2_000_000).unwrap();
+		assert_eq!(keys_manager.get_next_channel_index(), 2);
+		assert_eq!(*persisted_indices.lock().unwrap(), vec![1, 2]);
+		// Keys aren't handed out unless the next index was persisted
+		assert!(keys_manager.get_channel_keys(false, 3_000_000).is_err());
+		assert_eq!(keys_manager.get_next_channel_index(), 2);
+
+		// A restarted manager with a different start time picks up at the persisted index, and the
+		// channel keys don't depend on the start time.
+		let (restarted_keys_manager, _) = get_deterministic_keys_manager(&seed, 43, 1);
+		assert_eq!(restarted_keys_manager.get_channel_keys(false, 2_000_000).unwrap().pubkeys().funding_pubkey, second_keys.pubkeys().funding_pubkey);
+		assert_ne!(first_keys.pubkeys().funding_pubkey, second_keys.pubkeys().funding_pubkey);
+
+		// The key space ends at 2^31
+		let (exhausted_keys_manager, _) = get_deterministic_keys_manager(&seed, 44, (1 << 31) - 1);
+		assert!(exhausted_keys_manager.get_channel_keys(false, 1_000_000).is_ok());
+		assert!(exhausted_keys_manager.get_channel_keys(false, 1_000_000).is_err());
+
+		// Both channels' funding outputs are spent by our counterparty's commitment transactions,
+		// revealing their funding redeemscripts.
+		let secp_ctx = Secp256k1::new();
+		let counterparty_funding_pubkey = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let mut spending_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: Vec::new() };
+		let mut channel_ids = Vec::new();
+		for (i, keys) in [&first_keys, &second_keys].iter().enumerate() {
+			let funding_outpoint = OutPoint { txid: Txid::from_slice(&[i as u8 + 1; 32]).unwrap(), index: 0 };
+			channel_ids.push(funding_outpoint.to_channel_id());
+			let redeemscript = make_funding_redeemscript(&keys.pubkeys().funding_pubkey, &counterparty_funding_pubkey);
+			spending_tx.input.push(TxIn {
+				previous_output: BitcoinOutPoint { txid: funding_outpoint.txid, vout: 0 },
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: vec![Vec::new(), vec![0; 72], vec![0; 72], redeemscript.into_bytes()],
+			});
+		}
+		let unknown_channel_id = [42; 32];
+
+		// Only the seed is needed to recover, not the index or start time used when opening.
+		let recovery_keys_manager = KeysManager::new(&seed, Network::Testnet, 45, 45);
+		assert_eq!(recovery_keys_manager.recover_channel_keys_at_index(0).unwrap().pubkeys().funding_pubkey, first_keys.pubkeys().funding_pubkey);
+		assert_eq!(recovery_keys_manager.recover_channel_keys_at_index(1).unwrap().pubkeys().funding_pubkey, second_keys.pubkeys().funding_pubkey);
+		assert!(recovery_keys_manager.recover_channel_keys_at_index(1 << 31).is_err());
+		assert!(recovery_keys_manager.recover_channel_keys(&channel_ids, &[spending_tx.clone()], (1 << 31) + 1).is_err());
+
+		let recovered = recovery_keys_manager.recover_channel_keys(&[channel_ids[1], unknown_channel_id, channel_ids[0]], &[spending_tx.clone()], 3).unwrap();
+		assert_eq!(recovered.iter().map(|&(channel_id, ref keys)| (channel_id, keys.as_ref().map(|&(index, _)| index))).collect::<Vec<_>>(),
+			vec![(channel_ids[1], Some(1)), (unknown_channel_id, None), (channel_ids[0], Some(0))]);
+		assert_eq!((recovered[0].1).as_ref().unwrap().1.pubkeys().funding_pubkey, second_keys.pubkeys().funding_pubkey);
+		assert_eq!((recovered[2].1).as_ref().unwrap().1.pubkeys().funding_pubkey, first_keys.pubkeys().funding_pubkey);
+
+		// Channels opened at or past max_channel_index aren't found
+		let recovered = recovery_keys_manager.recover_channel_keys(&channel_ids, &[spending_tx], 1).unwrap();
+		assert_eq!(recovered.iter().map(|&(channel_id, ref keys)| (channel_id, keys.as_ref().map(|&(index, _)| index))).collect::<Vec<_>>(),
+			vec![(channel_ids[0], Some(0)), (channel_ids[1], None)]);
+	}
+
+	#[test]
+	fn test_spend_static_output_to_p2tr_destination() {
+		let keys_manager = KeysManager::new(&[42; 32], Network::Testnet, 42, 42);
+		let prev_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![TxOut {
+			script_pubkey: keys_manager.get_destination_script(),
+			value: 100_000,
+		}]};
+		let descriptor = SpendableOutputDescriptor::StaticOutput {
+			outpoint: OutPoint { txid: prev_tx.txid(), index: 0 },
+			output: prev_tx.output[0].clone(),
+		};
+
+		let p2tr_script = Builder::new().push_int(1).push_slice(&[42; 32]).into_script();
+		let sweep_tx = keys_manager.spend_spendable_outputs(&[&descriptor], p2tr_script.clone(), 253).unwrap();
+		assert_eq!(sweep_tx.output.len(), 1);
+		assert_eq!(sweep_tx.output[0].script_pubkey, p2tr_script);
+		assert!(sweep_tx.output[0].value < 100_000);
+		sweep_tx.verify(|outpoint| if *outpoint == (BitcoinOutPoint { txid: prev_tx.txid(), vout: 0 }) { Some(prev_tx.output[0].clone()) } else { None }).unwrap();
+
+		// Sweeping the P2TR output is left to the wallet owning it
+		let p2tr_descriptor = SpendableOutputDescriptor::StaticOutput {
+			outpoint: OutPoint { txid: sweep_tx.txid(), index: 0 },
+			output: sweep_tx.output[0].clone(),
+		};
+		assert!(keys_manager.spend_spendable_outputs(&[&p2tr_descriptor], keys_manager.get_destination_script(), 253).is_err());
+	}
+}
@@ -19,9 +19,14 @@ use chain::transaction::OutPoint;
 
 pub mod chaininterface;
 pub mod chainmonitor;
+pub mod compactfilter;
+pub mod watchtower;
+pub mod sweeper;
 pub mod channelmonitor;
 pub mod transaction;
 pub mod keysinterface;
+pub mod remotesigner;
+pub mod validatingsigner;
 
 /// The `Access` trait defines behavior for accessing chain data and state, such as blocks and
 /// UTXOs.
@@ -102,7 +107,8 @@ pub trait Watch: Send + Sync {
 /// This is useful in order to have a [`Watch`] implementation convey to a chain source which
 /// transactions to be notified of. Notification may take the form of pre-filtering blocks or, in
 /// the case of [BIP 157]/[BIP 158], only fetching a block if the compact filter matches. If
-/// receiving full blocks from a chain source, any further filtering is unnecessary.
+/// receiving full blocks from a chain source, any further filtering is unnecessary. See
+/// [`FilterMatcher`] for an implementation which matches BIP 158 filters.
 ///
 /// After an output has been registered, subsequent block retrievals from the chain source must not
 /// exclude any transactions matching the new criteria nor any in-block descendants of such
@@ -114,6 +120,7 @@ pub trait Watch: Send + Sync {
 /// invocation that has called the `Filter` must return [`TemporaryFailure`].
 ///
 /// [`Watch`]: trait.Watch.html
+/// [`FilterMatcher`]: compactfilter/struct.FilterMatcher.html
 /// [`TemporaryFailure`]: channelmonitor/enum.ChannelMonitorUpdateErr.html#variant.TemporaryFailure
 /// [BIP 157]: https://github.com/bitcoin/bips/blob/master/bip-0157.mediawiki
 /// [BIP 158]: https://github.com/bitcoin/bips/blob/master/bip-0158.mediawiki
@@ -0,0 +1,1015 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A protocol for holding channel keys in a separate process or device.
+//!
+//! Every [`KeysInterface`] and [`ChannelKeys`] call is mapped to a [`SignerRequest`], which is
+//! answered with a single [`SignerResponse`]. Both are serialized as a type byte followed by their
+//! fields and written back-to-back on any `Read + Write` transport, such as a socket or a serial
+//! connection, with responses returned in request order.
+//!
+//! [`RemoteKeysInterface`] and [`RemoteChannelKeys`] implement the traits on the node side by
+//! forwarding each call over the transport, while a [`SignerServer`] answers requests on the
+//! signer side using an [`InMemoryChannelKeys`] per channel.
+//!
+//! If the transport fails or the signer doesn't know the channel, calls return an error.
+//! Depending on the call, the channel is then not opened, closed, or the call is retried later.
+//!
+//! [`KeysInterface`]: ../keysinterface/trait.KeysInterface.html
+//! [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+//! [`SignerRequest`]: enum.SignerRequest.html
+//! [`SignerResponse`]: enum.SignerResponse.html
+//! [`RemoteKeysInterface`]: struct.RemoteKeysInterface.html
+//! [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+//! [`SignerServer`]: struct.SignerServer.html
+//! [`InMemoryChannelKeys`]: ../keysinterface/struct.InMemoryChannelKeys.html
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
+
+use bitcoin::secp256k1::key::{SecretKey, PublicKey};
+use bitcoin::secp256k1::{Secp256k1, Signature};
+use bitcoin::secp256k1;
+
+use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys, KeysInterface};
+use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+use ln::msgs::{DecodeError, UnsignedChannelAnnouncement};
+use util::byte_utils;
+use util::ser::{Readable, Writeable, Writer};
+
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Error, Read, Write};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A call to a [`KeysInterface`] or, for requests carrying a `channel_keys_id`, to the
+/// [`ChannelKeys`] of the channel whose `key_derivation_params` match it.
+///
+/// [`KeysInterface`]: ../keysinterface/trait.KeysInterface.html
+/// [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+#[derive(Clone, PartialEq)]
+pub enum SignerRequest {
+	/// KeysInterface::get_node_secret
+	GetNodeSecret,
+	/// KeysInterface::get_destination_script
+	GetDestinationScript,
+	/// KeysInterface::get_shutdown_pubkey
+	GetShutdownPubkey,
+	/// KeysInterface::get_channel_keys, answered with SignerResponse::ChannelKeys
+	GetChannelKeys {
+		/// Whether the channel is inbound
+		inbound: bool,
+		/// The value of the channel
+		channel_value_satoshis: u64,
+	},
+	/// KeysInterface::get_secure_random_bytes
+	GetSecureRandomBytes,
+	/// ChannelKeys::get_per_commitment_point
+	GetPerCommitmentPoint {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The commitment number
+		idx: u64,
+	},
+	/// ChannelKeys::release_commitment_secret
+	ReleaseCommitmentSecret {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The commitment number
+		idx: u64,
+	},
+	/// ChannelKeys::sign_counterparty_commitment
+	SignCounterpartyCommitment {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The feerate of the commitment transaction
+		feerate_per_kw: u32,
+		/// The counterparty commitment transaction
+		commitment_tx: Transaction,
+		/// The keys used to build the commitment transaction
+		keys: TxCreationKeys,
+		/// The HTLCs included in the commitment transaction
+		htlcs: Vec<HTLCOutputInCommitment>,
+	},
+	/// ChannelKeys::sign_holder_commitment
+	SignHolderCommitment {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The holder commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// ChannelKeys::unsafe_sign_holder_commitment
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	UnsafeSignHolderCommitment {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The holder commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// ChannelKeys::sign_holder_commitment_htlc_transactions
+	SignHolderCommitmentHTLCTransactions {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The holder commitment transaction
+		holder_commitment_tx: HolderCommitmentTransaction,
+	},
+	/// ChannelKeys::sign_justice_transaction
+	SignJusticeTransaction {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The justice transaction
+		justice_tx: Transaction,
+		/// The index of the input to sign
+		input: u32,
+		/// The value of the output spent by the input
+		amount: u64,
+		/// The revealed per-commitment secret of the revoked commitment transaction
+		per_commitment_key: SecretKey,
+		/// The HTLC spent by the input, if it doesn't spend the to_local output
+		htlc: Option<HTLCOutputInCommitment>,
+	},
+	/// ChannelKeys::sign_counterparty_htlc_transaction
+	SignCounterpartyHTLCTransaction {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The transaction claiming the HTLC output
+		htlc_tx: Transaction,
+		/// The index of the input to sign
+		input: u32,
+		/// The value of the output spent by the input
+		amount: u64,
+		/// The per-commitment point of the counterparty commitment transaction
+		per_commitment_point: PublicKey,
+		/// The HTLC spent by the input
+		htlc: HTLCOutputInCommitment,
+	},
+	/// ChannelKeys::sign_closing_transaction
+	SignClosingTransaction {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The closing transaction
+		closing_tx: Transaction,
+	},
+	/// ChannelKeys::sign_channel_announcement
+	SignChannelAnnouncement {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The channel announcement
+		msg: UnsignedChannelAnnouncement,
+	},
+	/// ChannelKeys::on_accept, answered with SignerResponse::Accepted
+	OnAccept {
+		/// The key_derivation_params of the channel
+		channel_keys_id: (u64, u64),
+		/// The counterparty's channel public keys
+		channel_pubkeys: ChannelPublicKeys,
+		/// The contest delay selected by the counterparty
+		counterparty_selected_contest_delay: u16,
+		/// The contest delay selected by us
+		holder_selected_contest_delay: u16,
+	},
+}
+
+/// The answer to a [`SignerRequest`].
+///
+/// [`SignerRequest`]: enum.SignerRequest.html
+#[derive(Clone, PartialEq)]
+pub enum SignerResponse {
+	/// The node secret
+	NodeSecret(SecretKey),
+	/// The destination script
+	DestinationScript(Script),
+	/// The shutdown pubkey or a per-commitment point
+	PublicKey(PublicKey),
+	/// A new channel's keys
+	ChannelKeys {
+		/// The key_derivation_params identifying the channel in later requests
+		channel_keys_id: (u64, u64),
+		/// The holder public keys of the channel
+		pubkeys: ChannelPublicKeys,
+	},
+	/// Random bytes or a commitment secret
+	Secret([u8; 32]),
+	/// A single signature
+	Signature(Signature),
+	/// The signatures of a counterparty commitment transaction and of its HTLC transactions
+	CounterpartyCommitmentSignatures(Signature, Vec<Signature>),
+	/// The signatures of the HTLC transactions of a holder commitment transaction
+	HTLCSignatures(Vec<Option<Signature>>),
+	/// The counterparty's channel parameters were stored
+	Accepted,
+	/// The signer refused the request or doesn't know the channel
+	Error,
+}
+
+fn write_vec<W: Writer, T: Writeable>(vec: &[T], writer: &mut W) -> Result<(), Error> {
+	(vec.len() as u64).write(writer)?;
+	for elem in vec.iter() {
+		elem.write(writer)?;
+	}
+	Ok(())
+}
+
+fn read_vec<R: Read, T: Readable>(reader: &mut R) -> Result<Vec<T>, DecodeError> {
+	let len: u64 = Readable::read(reader)?;
+	let mut vec = Vec::with_capacity(cmp::min(len as usize, 512));
+	for _ in 0..len {
+		vec.push(Readable::read(reader)?);
+	}
+	Ok(vec)
+}
+
+impl Writeable for SignerRequest {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		match self {
+			&SignerRequest::GetNodeSecret => 0u8.write(writer)?,
+			&SignerRequest::GetDestinationScript => 1u8.write(writer)?,
+			&SignerRequest::GetShutdownPubkey => 2u8.write(writer)?,
+			&SignerRequest::GetChannelKeys { ref inbound, ref channel_value_satoshis } => {
+				3u8.write(writer)?;
+				inbound.write(writer)?;
+				channel_value_satoshis.write(writer)?;
+			},
+			&SignerRequest::GetSecureRandomBytes => 4u8.write(writer)?,
+			&SignerRequest::GetPerCommitmentPoint { ref channel_keys_id, ref idx } => {
+				5u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				idx.write(writer)?;
+			},
+			&SignerRequest::ReleaseCommitmentSecret { ref channel_keys_id, ref idx } => {
+				6u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				idx.write(writer)?;
+			},
+			&SignerRequest::SignCounterpartyCommitment { ref channel_keys_id, ref feerate_per_kw, ref commitment_tx, ref keys, ref htlcs } => {
+				7u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				feerate_per_kw.write(writer)?;
+				commitment_tx.write(writer)?;
+				keys.write(writer)?;
+				write_vec(htlcs, writer)?;
+			},
+			&SignerRequest::SignHolderCommitment { ref channel_keys_id, ref holder_commitment_tx } => {
+				8u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+			&SignerRequest::UnsafeSignHolderCommitment { ref channel_keys_id, ref holder_commitment_tx } => {
+				9u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			&SignerRequest::SignHolderCommitmentHTLCTransactions { ref channel_keys_id, ref holder_commitment_tx } => {
+				10u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				holder_commitment_tx.write(writer)?;
+			},
+			&SignerRequest::SignJusticeTransaction { ref channel_keys_id, ref justice_tx, ref input, ref amount, ref per_commitment_key, ref htlc } => {
+				11u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				justice_tx.write(writer)?;
+				input.write(writer)?;
+				amount.write(writer)?;
+				per_commitment_key.write(writer)?;
+				htlc.write(writer)?;
+			},
+			&SignerRequest::SignCounterpartyHTLCTransaction { ref channel_keys_id, ref htlc_tx, ref input, ref amount, ref per_commitment_point, ref htlc } => {
+				12u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				htlc_tx.write(writer)?;
+				input.write(writer)?;
+				amount.write(writer)?;
+				per_commitment_point.write(writer)?;
+				htlc.write(writer)?;
+			},
+			&SignerRequest::SignClosingTransaction { ref channel_keys_id, ref closing_tx } => {
+				13u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				closing_tx.write(writer)?;
+			},
+			&SignerRequest::SignChannelAnnouncement { ref channel_keys_id, ref msg } => {
+				14u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				msg.write(writer)?;
+			},
+			&SignerRequest::OnAccept { ref channel_keys_id, ref channel_pubkeys, ref counterparty_selected_contest_delay, ref holder_selected_contest_delay } => {
+				15u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				channel_pubkeys.write(writer)?;
+				counterparty_selected_contest_delay.write(writer)?;
+				holder_selected_contest_delay.write(writer)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SignerRequest {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => Ok(SignerRequest::GetNodeSecret),
+			1u8 => Ok(SignerRequest::GetDestinationScript),
+			2u8 => Ok(SignerRequest::GetShutdownPubkey),
+			3u8 => Ok(SignerRequest::GetChannelKeys {
+				inbound: Readable::read(reader)?,
+				channel_value_satoshis: Readable::read(reader)?,
+			}),
+			4u8 => Ok(SignerRequest::GetSecureRandomBytes),
+			5u8 => Ok(SignerRequest::GetPerCommitmentPoint {
+				channel_keys_id: Readable::read(reader)?,
+				idx: Readable::read(reader)?,
+			}),
+			6u8 => Ok(SignerRequest::ReleaseCommitmentSecret {
+				channel_keys_id: Readable::read(reader)?,
+				idx: Readable::read(reader)?,
+			}),
+			7u8 => Ok(SignerRequest::SignCounterpartyCommitment {
+				channel_keys_id: Readable::read(reader)?,
+				feerate_per_kw: Readable::read(reader)?,
+				commitment_tx: Readable::read(reader)?,
+				keys: Readable::read(reader)?,
+				htlcs: read_vec(reader)?,
+			}),
+			8u8 => Ok(SignerRequest::SignHolderCommitment {
+				channel_keys_id: Readable::read(reader)?,
+				holder_commitment_tx: Readable::read(reader)?,
+			}),
+			#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+			9u8 => Ok(SignerRequest::UnsafeSignHolderCommitment {
+				channel_keys_id: Readable::read(reader)?,
+				holder_commitment_tx: Readable::read(reader)?,
+			}),
+			10u8 => Ok(SignerRequest::SignHolderCommitmentHTLCTransactions {
+				channel_keys_id: Readable::read(reader)?,
+				holder_commitment_tx: Readable::read(reader)?,
+			}),
+			11u8 => Ok(SignerRequest::SignJusticeTransaction {
+				channel_keys_id: Readable::read(reader)?,
+				justice_tx: Readable::read(reader)?,
+				input: Readable::read(reader)?,
+				amount: Readable::read(reader)?,
+				per_commitment_key: Readable::read(reader)?,
+				htlc: Readable::read(reader)?,
+			}),
+			12u8 => Ok(SignerRequest::SignCounterpartyHTLCTransaction {
+				channel_keys_id: Readable::read(reader)?,
+				htlc_tx: Readable::read(reader)?,
+				input: Readable::read(reader)?,
+				amount: Readable::read(reader)?,
+				per_commitment_point: Readable::read(reader)?,
+				htlc: Readable::read(reader)?,
+			}),
+			13u8 => Ok(SignerRequest::SignClosingTransaction {
+				channel_keys_id: Readable::read(reader)?,
+				closing_tx: Readable::read(reader)?,
+			}),
+			14u8 => Ok(SignerRequest::SignChannelAnnouncement {
+				channel_keys_id: Readable::read(reader)?,
+				msg: Readable::read(reader)?,
+			}),
+			15u8 => Ok(SignerRequest::OnAccept {
+				channel_keys_id: Readable::read(reader)?,
+				channel_pubkeys: Readable::read(reader)?,
+				counterparty_selected_contest_delay: Readable::read(reader)?,
+				holder_selected_contest_delay: Readable::read(reader)?,
+			}),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for SignerResponse {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		match self {
+			&SignerResponse::NodeSecret(ref node_secret) => {
+				0u8.write(writer)?;
+				node_secret.write(writer)?;
+			},
+			&SignerResponse::DestinationScript(ref script) => {
+				1u8.write(writer)?;
+				script.write(writer)?;
+			},
+			&SignerResponse::PublicKey(ref pubkey) => {
+				2u8.write(writer)?;
+				pubkey.write(writer)?;
+			},
+			&SignerResponse::ChannelKeys { ref channel_keys_id, ref pubkeys } => {
+				3u8.write(writer)?;
+				channel_keys_id.write(writer)?;
+				pubkeys.write(writer)?;
+			},
+			&SignerResponse::Secret(ref secret) => {
+				4u8.write(writer)?;
+				secret.write(writer)?;
+			},
+			&SignerResponse::Signature(ref sig) => {
+				5u8.write(writer)?;
+				sig.write(writer)?;
+			},
+			&SignerResponse::CounterpartyCommitmentSignatures(ref commitment_sig, ref htlc_sigs) => {
+				6u8.write(writer)?;
+				commitment_sig.write(writer)?;
+				htlc_sigs.write(writer)?;
+			},
+			&SignerResponse::HTLCSignatures(ref htlc_sigs) => {
+				7u8.write(writer)?;
+				write_vec(htlc_sigs, writer)?;
+			},
+			&SignerResponse::Accepted => 8u8.write(writer)?,
+			&SignerResponse::Error => 9u8.write(writer)?,
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SignerResponse {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => Ok(SignerResponse::NodeSecret(Readable::read(reader)?)),
+			1u8 => Ok(SignerResponse::DestinationScript(Readable::read(reader)?)),
+			2u8 => Ok(SignerResponse::PublicKey(Readable::read(reader)?)),
+			3u8 => Ok(SignerResponse::ChannelKeys {
+				channel_keys_id: Readable::read(reader)?,
+				pubkeys: Readable::read(reader)?,
+			}),
+			4u8 => Ok(SignerResponse::Secret(Readable::read(reader)?)),
+			5u8 => Ok(SignerResponse::Signature(Readable::read(reader)?)),
+			6u8 => Ok(SignerResponse::CounterpartyCommitmentSignatures(Readable::read(reader)?, Readable::read(reader)?)),
+			7u8 => Ok(SignerResponse::HTLCSignatures(read_vec(reader)?)),
+			8u8 => Ok(SignerResponse::Accepted),
+			9u8 => Ok(SignerResponse::Error),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+/// Sends a request over the transport and waits for its response.
+fn call<S: Read + Write>(transport: &Mutex<S>, request: &SignerRequest) -> Result<SignerResponse, DecodeError> {
+	let mut transport = transport.lock().unwrap();
+	transport.write_all(&request.encode()).map_err(DecodeError::Io)?;
+	transport.flush().map_err(DecodeError::Io)?;
+	Readable::read(&mut *transport)
+}
+
+/// A transport to a [`SignerServer`] which [`RemoteChannelKeys`] read back from disk can find
+/// again by the signer id given to [`RemoteKeysInterface::new`].
+///
+/// As this library keeps no global state, implementations are expected to keep a registry of
+/// their open transports themselves.
+///
+/// [`SignerServer`]: struct.SignerServer.html
+/// [`RemoteChannelKeys`]: struct.RemoteChannelKeys.html
+/// [`RemoteKeysInterface::new`]: struct.RemoteKeysInterface.html#method.new
+pub trait SignerTransport: Read + Write + Send + Sized {
+	/// Gets the transport to the signer with the given id, or None if it isn't connected.
+	fn get_transport(signer_id: u64) -> Option<Arc<Mutex<Self>>>;
+}
+
+/// A [`KeysInterface`] forwarding all calls to a [`SignerServer`] over a transport.
+///
+/// The node secret, destination script and shutdown pubkey are fetched once on creation, while
+/// random bytes are derived from a seed fetched at the same time, so that only
+/// `get_channel_keys` has to reach the signer afterwards.
+///
+/// [`KeysInterface`]: ../keysinterface/trait.KeysInterface.html
+/// [`SignerServer`]: struct.SignerServer.html
+pub struct RemoteKeysInterface<S: Read + Write + Send> {
+	signer_id: u64,
+	transport: Arc<Mutex<S>>,
+	node_secret: SecretKey,
+	destination_script: Script,
+	shutdown_pubkey: PublicKey,
+	rand_bytes_seed: [u8; 32],
+	rand_bytes_index: AtomicUsize,
+}
+
+impl<S: Read + Write + Send> RemoteKeysInterface<S> {
+	/// Creates a new `RemoteKeysInterface` talking to a signer over `transport`, failing if the
+	/// signer doesn't answer.
+	///
+	/// `signer_id` is stored with each `RemoteChannelKeys` handed out, so that it may find the
+	/// transport again through `SignerTransport::get_transport` when deserialized. It must thus
+	/// be the same across restarts.
+	pub fn new(signer_id: u64, transport: Arc<Mutex<S>>) -> Result<Self, DecodeError> {
+		let node_secret = match call(&transport, &SignerRequest::GetNodeSecret)? {
+			SignerResponse::NodeSecret(node_secret) => node_secret,
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		let destination_script = match call(&transport, &SignerRequest::GetDestinationScript)? {
+			SignerResponse::DestinationScript(script) => script,
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		let shutdown_pubkey = match call(&transport, &SignerRequest::GetShutdownPubkey)? {
+			SignerResponse::PublicKey(pubkey) => pubkey,
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		let rand_bytes_seed = match call(&transport, &SignerRequest::GetSecureRandomBytes)? {
+			SignerResponse::Secret(bytes) => bytes,
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		Ok(Self {
+			signer_id,
+			transport,
+			node_secret,
+			destination_script,
+			shutdown_pubkey,
+			rand_bytes_seed,
+			rand_bytes_index: AtomicUsize::new(0),
+		})
+	}
+}
+
+impl<S: Read + Write + Send> KeysInterface for RemoteKeysInterface<S> {
+	type ChanKeySigner = RemoteChannelKeys<S>;
+
+	fn get_node_secret(&self) -> SecretKey {
+		self.node_secret.clone()
+	}
+
+	fn get_destination_script(&self) -> Script {
+		self.destination_script.clone()
+	}
+
+	fn get_shutdown_pubkey(&self) -> PublicKey {
+		self.shutdown_pubkey.clone()
+	}
+
+	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> Result<RemoteChannelKeys<S>, ()> {
+		match call(&self.transport, &SignerRequest::GetChannelKeys { inbound, channel_value_satoshis }) {
+			Ok(SignerResponse::ChannelKeys { channel_keys_id, pubkeys }) => Ok(RemoteChannelKeys {
+				signer_id: self.signer_id,
+				transport: self.transport.clone(),
+				channel_keys_id,
+				pubkeys,
+			}),
+			_ => Err(()),
+		}
+	}
+
+	fn get_secure_random_bytes(&self) -> [u8; 32] {
+		let mut sha = Sha256::engine();
+		sha.input(&self.rand_bytes_seed);
+		sha.input(&byte_utils::be64_to_array(self.rand_bytes_index.fetch_add(1, Ordering::AcqRel) as u64));
+		Sha256::from_engine(sha).into_inner()
+	}
+}
+
+/// A [`ChannelKeys`] forwarding all signing calls to a [`SignerServer`] over a transport. Only the
+/// channel's public keys are held locally, and any call which can't be answered by the signer
+/// returns an error.
+///
+/// When serialized, only the signer id and the channel's key ids and public keys are written.
+/// Reading them back requires the transport to implement [`SignerTransport`].
+///
+/// [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+/// [`SignerServer`]: struct.SignerServer.html
+/// [`SignerTransport`]: trait.SignerTransport.html
+pub struct RemoteChannelKeys<S: Read + Write + Send> {
+	signer_id: u64,
+	transport: Arc<Mutex<S>>,
+	channel_keys_id: (u64, u64),
+	pubkeys: ChannelPublicKeys,
+}
+
+impl<S: Read + Write + Send> Clone for RemoteChannelKeys<S> {
+	fn clone(&self) -> Self {
+		Self {
+			signer_id: self.signer_id,
+			transport: self.transport.clone(),
+			channel_keys_id: self.channel_keys_id,
+			pubkeys: self.pubkeys.clone(),
+		}
+	}
+}
+
+impl<S: Read + Write + Send> RemoteChannelKeys<S> {
+	fn call_for_signature(&self, request: &SignerRequest) -> Result<Signature, ()> {
+		match call(&self.transport, request) {
+			Ok(SignerResponse::Signature(sig)) => Ok(sig),
+			_ => Err(()),
+		}
+	}
+}
+
+impl<S: Read + Write + Send> ChannelKeys for RemoteChannelKeys<S> {
+	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, _secp_ctx: &Secp256k1<T>) -> Result<PublicKey, ()> {
+		match call(&self.transport, &SignerRequest::GetPerCommitmentPoint { channel_keys_id: self.channel_keys_id, idx }) {
+			Ok(SignerResponse::PublicKey(per_commitment_point)) => Ok(per_commitment_point),
+			_ => Err(()),
+		}
+	}
+
+	fn release_commitment_secret(&self, idx: u64) -> Result<[u8; 32], ()> {
+		match call(&self.transport, &SignerRequest::ReleaseCommitmentSecret { channel_keys_id: self.channel_keys_id, idx }) {
+			Ok(SignerResponse::Secret(secret)) => Ok(secret),
+			_ => Err(()),
+		}
+	}
+
+	fn pubkeys(&self) -> &ChannelPublicKeys { &self.pubkeys }
+	fn key_derivation_params(&self) -> (u64, u64) { self.channel_keys_id }
+
+	fn sign_counterparty_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, feerate_per_kw: u32, commitment_tx: &Transaction, keys: &PreCalculatedTxCreationKeys, htlcs: &[&HTLCOutputInCommitment], _secp_ctx: &Secp256k1<T>) -> Result<(Signature, Vec<Signature>), ()> {
+		let request = SignerRequest::SignCounterpartyCommitment {
+			channel_keys_id: self.channel_keys_id,
+			feerate_per_kw,
+			commitment_tx: commitment_tx.clone(),
+			keys: keys.trust_key_derivation().clone(),
+			htlcs: htlcs.iter().map(|&htlc| htlc.clone()).collect(),
+		};
+		match call(&self.transport, &request) {
+			Ok(SignerResponse::CounterpartyCommitmentSignatures(commitment_sig, htlc_sigs)) => Ok((commitment_sig, htlc_sigs)),
+			_ => Err(()),
+		}
+	}
+
+	fn sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::SignHolderCommitment { channel_keys_id: self.channel_keys_id, holder_commitment_tx: holder_commitment_tx.clone() })
+	}
+
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn unsafe_sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::UnsafeSignHolderCommitment { channel_keys_id: self.channel_keys_id, holder_commitment_tx: holder_commitment_tx.clone() })
+	}
+
+	fn sign_holder_commitment_htlc_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, _secp_ctx: &Secp256k1<T>) -> Result<Vec<Option<Signature>>, ()> {
+		match call(&self.transport, &SignerRequest::SignHolderCommitmentHTLCTransactions { channel_keys_id: self.channel_keys_id, holder_commitment_tx: holder_commitment_tx.clone() }) {
+			Ok(SignerResponse::HTLCSignatures(htlc_sigs)) => Ok(htlc_sigs),
+			_ => Err(()),
+		}
+	}
+
+	fn sign_justice_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_tx: &Transaction, input: usize, amount: u64, per_commitment_key: &SecretKey, htlc: &Option<HTLCOutputInCommitment>, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::SignJusticeTransaction {
+			channel_keys_id: self.channel_keys_id,
+			justice_tx: justice_tx.clone(),
+			input: input as u32,
+			amount,
+			per_commitment_key: *per_commitment_key,
+			htlc: htlc.clone(),
+		})
+	}
+
+	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::SignCounterpartyHTLCTransaction {
+			channel_keys_id: self.channel_keys_id,
+			htlc_tx: htlc_tx.clone(),
+			input: input as u32,
+			amount,
+			per_commitment_point: *per_commitment_point,
+			htlc: htlc.clone(),
+		})
+	}
+
+	fn sign_closing_transaction<T: secp256k1::Signing>(&self, closing_tx: &Transaction, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::SignClosingTransaction { channel_keys_id: self.channel_keys_id, closing_tx: closing_tx.clone() })
+	}
+
+	fn sign_channel_announcement<T: secp256k1::Signing>(&self, msg: &UnsignedChannelAnnouncement, _secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.call_for_signature(&SignerRequest::SignChannelAnnouncement { channel_keys_id: self.channel_keys_id, msg: msg.clone() })
+	}
+
+	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) -> Result<(), ()> {
+		let request = SignerRequest::OnAccept {
+			channel_keys_id: self.channel_keys_id,
+			channel_pubkeys: channel_pubkeys.clone(),
+			counterparty_selected_contest_delay,
+			holder_selected_contest_delay,
+		};
+		match call(&self.transport, &request) {
+			Ok(SignerResponse::Accepted) => Ok(()),
+			_ => Err(()),
+		}
+	}
+}
+
+impl<S: Read + Write + Send> Writeable for RemoteChannelKeys<S> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		self.signer_id.write(writer)?;
+		self.channel_keys_id.write(writer)?;
+		self.pubkeys.write(writer)?;
+		Ok(())
+	}
+}
+
+impl<S: SignerTransport> Readable for RemoteChannelKeys<S> {
+	fn read<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let signer_id = Readable::read(reader)?;
+		let channel_keys_id = Readable::read(reader)?;
+		let pubkeys = Readable::read(reader)?;
+		let transport = match S::get_transport(signer_id) {
+			Some(transport) => transport,
+			None => return Err(DecodeError::InvalidValue),
+		};
+		Ok(Self {
+			signer_id,
+			transport,
+			channel_keys_id,
+			pubkeys,
+		})
+	}
+}
+
+/// Answers [`SignerRequest`]s on the signer side, using an [`InMemoryChannelKeys`] per channel.
+///
+/// Channel keys are created through the given `KeysInterface` when a `GetChannelKeys` request is
+/// received. They must be persisted by the signer and restored with `register_channel_keys` on
+/// restart.
+///
+/// [`SignerRequest`]: enum.SignerRequest.html
+/// [`InMemoryChannelKeys`]: ../keysinterface/struct.InMemoryChannelKeys.html
+pub struct SignerServer<K: Deref> where K::Target: KeysInterface<ChanKeySigner = InMemoryChannelKeys> {
+	keys_interface: K,
+	channels: HashMap<(u64, u64), InMemoryChannelKeys>,
+	secp_ctx: Secp256k1<secp256k1::All>,
+}
+
+impl<K: Deref> SignerServer<K> where K::Target: KeysInterface<ChanKeySigner = InMemoryChannelKeys> {
+	/// Creates a new `SignerServer` with no channels.
+	pub fn new(keys_interface: K) -> Self {
+		Self {
+			keys_interface,
+			channels: HashMap::new(),
+			secp_ctx: Secp256k1::new(),
+		}
+	}
+
+	/// Adds the keys of a channel created before a restart.
+	pub fn register_channel_keys(&mut self, channel_keys: InMemoryChannelKeys) {
+		self.channels.insert(channel_keys.key_derivation_params(), channel_keys);
+	}
+
+	/// Gets the keys of the channel identified by `channel_keys_id`, e.g. to persist them.
+	pub fn get_channel_keys(&self, channel_keys_id: &(u64, u64)) -> Option<&InMemoryChannelKeys> {
+		self.channels.get(channel_keys_id)
+	}
+
+	/// Reads a single request from `transport` and writes its response back.
+	pub fn process_request<S: Read + Write>(&mut self, transport: &mut S) -> Result<(), DecodeError> {
+		let request = Readable::read(transport)?;
+		let response = self.handle_request(request);
+		transport.write_all(&response.encode()).map_err(DecodeError::Io)?;
+		transport.flush().map_err(DecodeError::Io)
+	}
+
+	/// Answers a request, returning SignerResponse::Error if it refers to an unknown channel or
+	/// the channel's keys refuse to sign.
+	pub fn handle_request(&mut self, request: SignerRequest) -> SignerResponse {
+		let secp_ctx = &self.secp_ctx;
+		macro_rules! signature_response {
+			($res: expr) => {
+				match $res {
+					Ok(sig) => SignerResponse::Signature(sig),
+					Err(()) => SignerResponse::Error,
+				}
+			}
+		}
+		macro_rules! get_channel {
+			($channel_keys_id: expr) => {
+				match self.channels.get(&$channel_keys_id) {
+					Some(channel_keys) => channel_keys,
+					None => return SignerResponse::Error,
+				}
+			}
+		}
+		match request {
+			SignerRequest::GetNodeSecret => SignerResponse::NodeSecret(self.keys_interface.get_node_secret()),
+			SignerRequest::GetDestinationScript => SignerResponse::DestinationScript(self.keys_interface.get_destination_script()),
+			SignerRequest::GetShutdownPubkey => SignerResponse::PublicKey(self.keys_interface.get_shutdown_pubkey()),
+			SignerRequest::GetChannelKeys { inbound, channel_value_satoshis } => {
+				let channel_keys = match self.keys_interface.get_channel_keys(inbound, channel_value_satoshis) {
+					Ok(channel_keys) => channel_keys,
+					Err(()) => return SignerResponse::Error,
+				};
+				let channel_keys_id = channel_keys.key_derivation_params();
+				let pubkeys = channel_keys.pubkeys().clone();
+				self.channels.insert(channel_keys_id, channel_keys);
+				SignerResponse::ChannelKeys { channel_keys_id, pubkeys }
+			},
+			SignerRequest::GetSecureRandomBytes => SignerResponse::Secret(self.keys_interface.get_secure_random_bytes()),
+			SignerRequest::GetPerCommitmentPoint { channel_keys_id, idx } => {
+				match get_channel!(channel_keys_id).get_per_commitment_point(idx, secp_ctx) {
+					Ok(per_commitment_point) => SignerResponse::PublicKey(per_commitment_point),
+					Err(()) => SignerResponse::Error,
+				}
+			},
+			SignerRequest::ReleaseCommitmentSecret { channel_keys_id, idx } => {
+				match get_channel!(channel_keys_id).release_commitment_secret(idx) {
+					Ok(secret) => SignerResponse::Secret(secret),
+					Err(()) => SignerResponse::Error,
+				}
+			},
+			SignerRequest::SignCounterpartyCommitment { channel_keys_id, feerate_per_kw, commitment_tx, keys, htlcs } => {
+				let htlc_refs = htlcs.iter().collect::<Vec<_>>();
+				match get_channel!(channel_keys_id).sign_counterparty_commitment(feerate_per_kw, &commitment_tx, &PreCalculatedTxCreationKeys::new(keys), &htlc_refs, secp_ctx) {
+					Ok((commitment_sig, htlc_sigs)) => SignerResponse::CounterpartyCommitmentSignatures(commitment_sig, htlc_sigs),
+					Err(()) => SignerResponse::Error,
+				}
+			},
+			SignerRequest::SignHolderCommitment { channel_keys_id, holder_commitment_tx } =>
+				signature_response!(get_channel!(channel_keys_id).sign_holder_commitment(&holder_commitment_tx, secp_ctx)),
+			#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+			SignerRequest::UnsafeSignHolderCommitment { channel_keys_id, holder_commitment_tx } =>
+				signature_response!(get_channel!(channel_keys_id).unsafe_sign_holder_commitment(&holder_commitment_tx, secp_ctx)),
+			SignerRequest::SignHolderCommitmentHTLCTransactions { channel_keys_id, holder_commitment_tx } => {
+				match get_channel!(channel_keys_id).sign_holder_commitment_htlc_transactions(&holder_commitment_tx, secp_ctx) {
+					Ok(htlc_sigs) => SignerResponse::HTLCSignatures(htlc_sigs),
+					Err(()) => SignerResponse::Error,
+				}
+			},
+			SignerRequest::SignJusticeTransaction { channel_keys_id, justice_tx, input, amount, per_commitment_key, htlc } =>
+				signature_response!(get_channel!(channel_keys_id).sign_justice_transaction(&justice_tx, input as usize, amount, &per_commitment_key, &htlc, secp_ctx)),
+			SignerRequest::SignCounterpartyHTLCTransaction { channel_keys_id, htlc_tx, input, amount, per_commitment_point, htlc } =>
+				signature_response!(get_channel!(channel_keys_id).sign_counterparty_htlc_transaction(&htlc_tx, input as usize, amount, &per_commitment_point, &htlc, secp_ctx)),
+			SignerRequest::SignClosingTransaction { channel_keys_id, closing_tx } =>
+				signature_response!(get_channel!(channel_keys_id).sign_closing_transaction(&closing_tx, secp_ctx)),
+			SignerRequest::SignChannelAnnouncement { channel_keys_id, msg } =>
+				signature_response!(get_channel!(channel_keys_id).sign_channel_announcement(&msg, secp_ctx)),
+			SignerRequest::OnAccept { channel_keys_id, channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay } => {
+				match self.channels.get_mut(&channel_keys_id) {
+					Some(channel_keys) => match channel_keys.on_accept(&channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay) {
+						Ok(()) => SignerResponse::Accepted,
+						Err(()) => SignerResponse::Error,
+					},
+					None => SignerResponse::Error,
+				}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::Secp256k1;
+	use bitcoin::secp256k1::key::SecretKey;
+	use chain::keysinterface::{ChannelKeys, KeysInterface, KeysManager};
+	use chain::remotesigner::{RemoteChannelKeys, RemoteKeysInterface, SignerServer, SignerTransport};
+	use ln::chan_utils::HolderCommitmentTransaction;
+	use util::ser::{Readable, Writeable};
+	use std::cell::RefCell;
+	use std::collections::HashMap;
+	use std::io::{Cursor, Read, Write};
+	use std::sync::{Arc, Mutex};
+	use std::sync::mpsc::{channel, Receiver, Sender};
+	use std::thread;
+
+	/// One end of an in-memory pipe. Written bytes are sent to the other end on flush.
+	struct PipeEnd {
+		incoming: Receiver<Vec<u8>>,
+		read_buf: Cursor<Vec<u8>>,
+		outgoing: Sender<Vec<u8>>,
+		write_buf: Vec<u8>,
+	}
+
+	fn pipe() -> (PipeEnd, PipeEnd) {
+		let (a_sender, b_receiver) = channel();
+		let (b_sender, a_receiver) = channel();
+		(PipeEnd { incoming: a_receiver, read_buf: Cursor::new(Vec::new()), outgoing: a_sender, write_buf: Vec::new() },
+		 PipeEnd { incoming: b_receiver, read_buf: Cursor::new(Vec::new()), outgoing: b_sender, write_buf: Vec::new() })
+	}
+
+	impl Read for PipeEnd {
+		fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+			if self.read_buf.position() as usize == self.read_buf.get_ref().len() {
+				match self.incoming.recv() {
+					Ok(bytes) => self.read_buf = Cursor::new(bytes),
+					// The other end hung up
+					Err(_) => return Ok(0),
+				}
+			}
+			self.read_buf.read(buf)
+		}
+	}
+
+	impl Write for PipeEnd {
+		fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+			self.write_buf.extend_from_slice(buf);
+			Ok(buf.len())
+		}
+		fn flush(&mut self) -> ::std::io::Result<()> {
+			let bytes = ::std::mem::replace(&mut self.write_buf, Vec::new());
+			self.outgoing.send(bytes).map_err(|_| ::std::io::Error::new(::std::io::ErrorKind::BrokenPipe, "hung up"))
+		}
+	}
+
+	thread_local! {
+		static TRANSPORTS: RefCell<HashMap<u64, Arc<Mutex<PipeEnd>>>> = RefCell::new(HashMap::new());
+	}
+
+	impl SignerTransport for PipeEnd {
+		fn get_transport(signer_id: u64) -> Option<Arc<Mutex<Self>>> {
+			TRANSPORTS.with(|transports| transports.borrow().get(&signer_id).cloned())
+		}
+	}
+
+	#[test]
+	fn test_remote_signer_matches_local_keys() {
+		let (client_end, mut server_end) = pipe();
+		let server_thread = thread::spawn(move || {
+			let mut server = SignerServer::new(Arc::new(KeysManager::new(&[1; 32], Network::Testnet, 42, 42)));
+			while server.process_request(&mut server_end).is_ok() {}
+		});
+
+		let secp_ctx = Secp256k1::new();
+		let local_keys = KeysManager::new(&[1; 32], Network::Testnet, 42, 42);
+		let transport = Arc::new(Mutex::new(client_end));
+		TRANSPORTS.with(|transports| transports.borrow_mut().insert(42, transport.clone()));
+		let remote_keys = RemoteKeysInterface::new(42, transport.clone()).unwrap();
+		assert_eq!(remote_keys.get_node_secret(), local_keys.get_node_secret());
+		assert_eq!(remote_keys.get_destination_script(), local_keys.get_destination_script());
+		assert_eq!(remote_keys.get_shutdown_pubkey(), local_keys.get_shutdown_pubkey());
+
+		let mut remote_chan_keys = remote_keys.get_channel_keys(false, 100_000).unwrap();
+		let mut local_chan_keys = local_keys.get_channel_keys(false, 100_000).unwrap();
+		assert_eq!(remote_chan_keys.key_derivation_params(), local_chan_keys.key_derivation_params());
+		assert_eq!(remote_chan_keys.pubkeys().funding_pubkey, local_chan_keys.pubkeys().funding_pubkey);
+		assert_eq!(remote_chan_keys.pubkeys().revocation_basepoint, local_chan_keys.pubkeys().revocation_basepoint);
+		assert_eq!(remote_chan_keys.get_per_commitment_point(42, &secp_ctx), local_chan_keys.get_per_commitment_point(42, &secp_ctx));
+		assert_eq!(remote_chan_keys.release_commitment_secret(42), local_chan_keys.release_commitment_secret(42));
+
+		let counterparty_keys = KeysManager::new(&[2; 32], Network::Testnet, 42, 42).get_channel_keys(true, 100_000).unwrap();
+		remote_chan_keys.on_accept(counterparty_keys.pubkeys(), 144, 144).unwrap();
+		local_chan_keys.on_accept(counterparty_keys.pubkeys(), 144, 144).unwrap();
+
+		// Signatures are deterministic, so both signers must produce the same ones.
+		let tx = Transaction { version: 2, lock_time: 0, input: vec![TxIn::default()], output: vec![TxOut { script_pubkey: local_keys.get_destination_script(), value: 99_000 }] };
+		assert_eq!(remote_chan_keys.sign_closing_transaction(&tx, &secp_ctx).unwrap(), local_chan_keys.sign_closing_transaction(&tx, &secp_ctx).unwrap());
+		let per_commitment_key = SecretKey::from_slice(&[3; 32]).unwrap();
+		assert_eq!(remote_chan_keys.sign_justice_transaction(&tx, 0, 100_000, &per_commitment_key, &None, &secp_ctx).unwrap(),
+			local_chan_keys.sign_justice_transaction(&tx, 0, 100_000, &per_commitment_key, &None, &secp_ctx).unwrap());
+		let holder_commitment_tx = HolderCommitmentTransaction::dummy();
+		assert_eq!(remote_chan_keys.sign_holder_commitment(&holder_commitment_tx, &secp_ctx).unwrap(),
+			local_chan_keys.sign_holder_commitment(&holder_commitment_tx, &secp_ctx).unwrap());
+		assert_eq!(remote_chan_keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx, &secp_ctx).unwrap(),
+			local_chan_keys.sign_holder_commitment_htlc_transactions(&holder_commitment_tx, &secp_ctx).unwrap());
+
+		// Channel keys read back from disk find the signer's transport and keep talking to it...
+		let reloaded_chan_keys: RemoteChannelKeys<PipeEnd> = Readable::read(&mut Cursor::new(&remote_chan_keys.encode())).unwrap();
+		assert_eq!(reloaded_chan_keys.sign_closing_transaction(&tx, &secp_ctx).unwrap(), local_chan_keys.sign_closing_transaction(&tx, &secp_ctx).unwrap());
+
+		// ...but requests for channels unknown to the signer fail, without panicking.
+		let mut unknown_chan_keys = remote_chan_keys.encode();
+		unknown_chan_keys[8] ^= 1;
+		let mut unknown_chan_keys: RemoteChannelKeys<PipeEnd> = Readable::read(&mut Cursor::new(&unknown_chan_keys)).unwrap();
+		assert!(unknown_chan_keys.sign_closing_transaction(&tx, &secp_ctx).is_err());
+		assert!(unknown_chan_keys.get_per_commitment_point(42, &secp_ctx).is_err());
+		assert!(unknown_chan_keys.release_commitment_secret(42).is_err());
+		assert!(unknown_chan_keys.on_accept(counterparty_keys.pubkeys(), 144, 144).is_err());
+
+		// Keys of a signer whose transport isn't registered can't be read.
+		let mut unknown_signer_keys = remote_chan_keys.encode();
+		unknown_signer_keys[7] ^= 1;
+		assert!(<RemoteChannelKeys<PipeEnd> as Readable>::read(&mut Cursor::new(&unknown_signer_keys)).is_err());
+
+		// Hanging up stops the server.
+		TRANSPORTS.with(|transports| transports.borrow_mut().clear());
+		drop((remote_keys, remote_chan_keys, reloaded_chan_keys, unknown_chan_keys, transport));
+		server_thread.join().unwrap();
+	}
+	#[test]
+	fn test_remote_signer_hung_up() {
+		// A signer which hangs up after answering the requests made on creation and the one for a
+		// single channel's keys.
+		let (client_end, mut server_end) = pipe();
+		let server_thread = thread::spawn(move || {
+			let mut server = SignerServer::new(Arc::new(KeysManager::new(&[1; 32], Network::Testnet, 42, 42)));
+			for _ in 0..5 {
+				server.process_request(&mut server_end).unwrap();
+			}
+		});
+
+		let secp_ctx = Secp256k1::new();
+		let remote_keys = RemoteKeysInterface::new(42, Arc::new(Mutex::new(client_end))).unwrap();
+		let mut remote_chan_keys = remote_keys.get_channel_keys(false, 100_000).unwrap();
+		server_thread.join().unwrap();
+
+		// What was fetched on creation is still available...
+		assert_eq!(remote_keys.get_node_secret(), KeysManager::new(&[1; 32], Network::Testnet, 42, 42).get_node_secret());
+		assert_ne!(remote_keys.get_secure_random_bytes(), remote_keys.get_secure_random_bytes());
+
+		// ...while everything else fails instead of panicking.
+		assert!(remote_keys.get_channel_keys(false, 100_000).is_err());
+		assert!(remote_chan_keys.get_per_commitment_point(42, &secp_ctx).is_err());
+		assert!(remote_chan_keys.release_commitment_secret(42).is_err());
+		let counterparty_keys = KeysManager::new(&[2; 32], Network::Testnet, 42, 42).get_channel_keys(true, 100_000).unwrap();
+		assert!(remote_chan_keys.on_accept(counterparty_keys.pubkeys(), 144, 144).is_err());
+
+		// Without a signer, no RemoteKeysInterface can be created at all.
+		let (client_end, _) = pipe();
+		assert!(RemoteKeysInterface::new(43, Arc::new(Mutex::new(client_end))).is_err());
+	}
+}
@@ -0,0 +1,367 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for sweeping the outputs given to users in [`Event::SpendableOutputs`] into their
+//! on-chain wallet.
+//!
+//! [`KeysManager::spend_spendable_outputs`] builds and signs a single transaction spending a batch
+//! of [`SpendableOutputDescriptor`]s. An [`OutputSweeper`] additionally keeps track of the sweep
+//! transactions it broadcasts, bumping their feerate with replace-by-fee until they are buried
+//! [`ANTI_REORG_DELAY`] blocks deep. It should be persisted whenever its state changes, i.e.
+//! after outputs are tracked and after each block is connected or disconnected.
+//!
+//! [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+//! [`KeysManager::spend_spendable_outputs`]: ../keysinterface/struct.KeysManager.html#method.spend_spendable_outputs
+//! [`SpendableOutputDescriptor`]: ../keysinterface/enum.SpendableOutputDescriptor.html
+//! [`OutputSweeper`]: struct.OutputSweeper.html
+//! [`ANTI_REORG_DELAY`]: ../channelmonitor/constant.ANTI_REORG_DELAY.html
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::Transaction;
+
+use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use chain::channelmonitor::ANTI_REORG_DELAY;
+use chain::keysinterface::{KeysManager, SpendableOutputDescriptor};
+use chain::transaction::TransactionData;
+use ln::msgs::DecodeError;
+use util::logger::Logger;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::io::Error;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+/// The minimum feerate increase of a replacement transaction, as required by the default
+/// incremental relay fee of 1 sat/vbyte.
+const MIN_FEERATE_BUMP_SAT_PER_1000_WEIGHT: u32 = 253;
+
+/// A broadcast transaction sweeping a batch of outputs.
+#[derive(Clone, PartialEq)]
+struct PendingSweep {
+	descriptors: Vec<SpendableOutputDescriptor>,
+	/// The latest version of the sweep transaction we broadcast.
+	sweep_tx: Transaction,
+	feerate_sat_per_1000_weight: u32,
+	/// The height of the first block after which the sweep transaction is bumped if it hasn't
+	/// confirmed. Outputs with a relative timelock can't confirm before it expires, so we hold off
+	/// on bumping until then.
+	bump_height: u32,
+	/// The height at which a transaction spending our outputs confirmed, if any.
+	confirmation_height: Option<u32>,
+}
+
+impl PendingSweep {
+	fn spends_outputs_of(&self, tx: &Transaction) -> bool {
+		tx.input.iter().any(|input| self.descriptors.iter().any(|descriptor| descriptor.outpoint().into_bitcoin_outpoint() == input.previous_output))
+	}
+}
+
+impl Writeable for PendingSweep {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		(self.descriptors.len() as u64).write(writer)?;
+		for descriptor in self.descriptors.iter() {
+			descriptor.write(writer)?;
+		}
+		self.sweep_tx.write(writer)?;
+		self.feerate_sat_per_1000_weight.write(writer)?;
+		self.bump_height.write(writer)?;
+		self.confirmation_height.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for PendingSweep {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let descriptors_count: u64 = Readable::read(reader)?;
+		let mut descriptors = Vec::with_capacity(cmp::min(descriptors_count as usize, 64));
+		for _ in 0..descriptors_count {
+			descriptors.push(Readable::read(reader)?);
+		}
+		Ok(Self {
+			descriptors,
+			sweep_tx: Readable::read(reader)?,
+			feerate_sat_per_1000_weight: Readable::read(reader)?,
+			bump_height: Readable::read(reader)?,
+			confirmation_height: Readable::read(reader)?,
+		})
+	}
+}
+
+/// Sweeps [`SpendableOutputDescriptor`]s to a destination script, fee-bumping the sweep
+/// transactions with replace-by-fee until they are buried [`ANTI_REORG_DELAY`] blocks deep.
+///
+/// Blocks must be connected and disconnected in the same way as with a [`ChainMonitor`].
+///
+/// [`SpendableOutputDescriptor`]: ../keysinterface/enum.SpendableOutputDescriptor.html
+/// [`ANTI_REORG_DELAY`]: ../channelmonitor/constant.ANTI_REORG_DELAY.html
+/// [`ChainMonitor`]: ../chainmonitor/struct.ChainMonitor.html
+pub struct OutputSweeper<K: Deref<Target = KeysManager>, B: Deref, F: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	pending_sweeps: Mutex<Vec<PendingSweep>>,
+	destination_script: Script,
+	keys_manager: K,
+	broadcaster: B,
+	fee_estimator: F,
+	logger: L,
+}
+
+impl<K: Deref<Target = KeysManager>, B: Deref, F: Deref, L: Deref> OutputSweeper<K, B, F, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	/// Creates a new `OutputSweeper` sweeping outputs to `destination_script`.
+	pub fn new(destination_script: Script, keys_manager: K, broadcaster: B, fee_estimator: F, logger: L) -> Self {
+		Self {
+			pending_sweeps: Mutex::new(Vec::new()),
+			destination_script,
+			keys_manager,
+			broadcaster,
+			fee_estimator,
+			logger,
+		}
+	}
+
+	/// Broadcasts a transaction sweeping the given outputs, which are then tracked until the sweep
+	/// is buried. `height` is the height of the current best block.
+	///
+	/// Returns an Err if the outputs can't be spent by the `KeysManager` or aren't worth enough to
+	/// pay the fee, in which case nothing is tracked.
+	pub fn track_spendable_outputs(&self, descriptors: Vec<SpendableOutputDescriptor>, height: u32) -> Result<(), ()> {
+		let feerate_sat_per_1000_weight = self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal);
+		let sweep_tx = {
+			let descriptor_refs = descriptors.iter().collect::<Vec<_>>();
+			self.keys_manager.spend_spendable_outputs(&descriptor_refs, self.destination_script.clone(), feerate_sat_per_1000_weight)?
+		};
+		let max_to_self_delay = descriptors.iter().map(|descriptor| match descriptor {
+			&SpendableOutputDescriptor::DynamicOutputP2WSH { to_self_delay, .. } => to_self_delay as u32,
+			_ => 0,
+		}).max().unwrap_or(0);
+
+		log_info!(self.logger, "Broadcasting sweep transaction {} for {} outputs", sweep_tx.txid(), descriptors.len());
+		self.broadcaster.broadcast_transaction(&sweep_tx);
+		self.pending_sweeps.lock().unwrap().push(PendingSweep {
+			descriptors,
+			sweep_tx,
+			feerate_sat_per_1000_weight,
+			bump_height: height + max_to_self_delay + 1,
+			confirmation_height: None,
+		});
+		Ok(())
+	}
+
+	/// Gets the latest version of each sweep transaction which isn't yet buried.
+	pub fn get_pending_sweeps(&self) -> Vec<Transaction> {
+		self.pending_sweeps.lock().unwrap().iter().map(|sweep| sweep.sweep_tx.clone()).collect()
+	}
+
+	/// Processes a connected block, marking sweeps whose outputs were spent as confirmed, bumping
+	/// the feerate of unconfirmed sweeps and forgetting sweeps which are now buried.
+	pub fn block_connected(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let mut pending_sweeps = self.pending_sweeps.lock().unwrap();
+		for sweep in pending_sweeps.iter_mut() {
+			if sweep.confirmation_height.is_some() {
+				continue;
+			}
+			if let Some(&(_, tx)) = txdata.iter().find(|&&(_, tx)| sweep.spends_outputs_of(tx)) {
+				log_trace!(self.logger, "Transaction {} spending swept outputs confirmed at height {}", tx.txid(), height);
+				sweep.confirmation_height = Some(height);
+			}
+		}
+
+		pending_sweeps.retain(|sweep| match sweep.confirmation_height {
+			Some(confirmation_height) if confirmation_height + ANTI_REORG_DELAY - 1 <= height => {
+				log_info!(self.logger, "Sweep transaction {} is buried, no longer tracking it", sweep.sweep_tx.txid());
+				false
+			},
+			_ => true,
+		});
+
+		for sweep in pending_sweeps.iter_mut() {
+			if sweep.confirmation_height.is_some() {
+				continue;
+			}
+			if height >= sweep.bump_height {
+				let feerate_sat_per_1000_weight = cmp::max(
+					self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority),
+					sweep.feerate_sat_per_1000_weight + cmp::max(sweep.feerate_sat_per_1000_weight / 4, MIN_FEERATE_BUMP_SAT_PER_1000_WEIGHT));
+				let descriptor_refs = sweep.descriptors.iter().collect::<Vec<_>>();
+				match self.keys_manager.spend_spendable_outputs(&descriptor_refs, self.destination_script.clone(), feerate_sat_per_1000_weight) {
+					Ok(sweep_tx) => {
+						log_info!(self.logger, "Bumping sweep transaction {} to {} with feerate {} sat/kW", sweep.sweep_tx.txid(), sweep_tx.txid(), feerate_sat_per_1000_weight);
+						sweep.sweep_tx = sweep_tx;
+						sweep.feerate_sat_per_1000_weight = feerate_sat_per_1000_weight;
+					},
+					Err(()) => {
+						log_error!(self.logger, "Swept outputs can't pay for a feerate of {} sat/kW, rebroadcasting sweep transaction {}", feerate_sat_per_1000_weight, sweep.sweep_tx.txid());
+					},
+				}
+				sweep.bump_height = height + 1;
+			}
+			self.broadcaster.broadcast_transaction(&sweep.sweep_tx);
+		}
+	}
+
+	/// Processes a disconnected block, marking sweeps confirmed at or above `disconnected_height`
+	/// as unconfirmed again. They are rebroadcast when the next block is connected.
+	pub fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		for sweep in self.pending_sweeps.lock().unwrap().iter_mut() {
+			if sweep.confirmation_height.map(|height| height >= disconnected_height).unwrap_or(false) {
+				log_trace!(self.logger, "Sweep transaction {} was unconfirmed by a reorg", sweep.sweep_tx.txid());
+				sweep.confirmation_height = None;
+			}
+		}
+	}
+}
+
+const SERIALIZATION_VERSION: u8 = 1;
+const MIN_SERIALIZATION_VERSION: u8 = 1;
+
+impl<K: Deref<Target = KeysManager>, B: Deref, F: Deref, L: Deref> Writeable for OutputSweeper<K, B, F, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		write_ver_prefix!(writer, SERIALIZATION_VERSION, MIN_SERIALIZATION_VERSION);
+
+		self.destination_script.write(writer)?;
+		let pending_sweeps = self.pending_sweeps.lock().unwrap();
+		(pending_sweeps.len() as u64).write(writer)?;
+		for sweep in pending_sweeps.iter() {
+			sweep.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// Arguments for the creation of an `OutputSweeper` that are not deserialized.
+pub struct OutputSweeperReadArgs<K: Deref<Target = KeysManager>, B: Deref, F: Deref, L: Deref>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	/// The keys manager which signs the sweep transactions. It must be derived from the same seed
+	/// as the one used to sweep the outputs before serialization.
+	pub keys_manager: K,
+	/// The broadcaster through which sweep transactions are (re)broadcast.
+	pub broadcaster: B,
+	/// The fee estimator used to pick the feerate of replacement sweep transactions.
+	pub fee_estimator: F,
+	/// The logger for use in the `OutputSweeper`.
+	pub logger: L,
+}
+
+impl<K: Deref<Target = KeysManager>, B: Deref, F: Deref, L: Deref> ReadableArgs<OutputSweeperReadArgs<K, B, F, L>> for OutputSweeper<K, B, F, L>
+	where B::Target: BroadcasterInterface,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	fn read<R: ::std::io::Read>(reader: &mut R, args: OutputSweeperReadArgs<K, B, F, L>) -> Result<Self, DecodeError> {
+		let _ver = read_ver_prefix!(reader, SERIALIZATION_VERSION);
+
+		let destination_script = Readable::read(reader)?;
+		let pending_sweeps_count: u64 = Readable::read(reader)?;
+		let mut pending_sweeps = Vec::with_capacity(cmp::min(pending_sweeps_count as usize, 64));
+		for _ in 0..pending_sweeps_count {
+			pending_sweeps.push(Readable::read(reader)?);
+		}
+		Ok(Self {
+			pending_sweeps: Mutex::new(pending_sweeps),
+			destination_script,
+			keys_manager: args.keys_manager,
+			broadcaster: args.broadcaster,
+			fee_estimator: args.fee_estimator,
+			logger: args.logger,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::Builder;
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+	use bitcoin::network::constants::Network;
+	use chain::channelmonitor::ANTI_REORG_DELAY;
+	use chain::keysinterface::{KeysInterface, KeysManager, SpendableOutputDescriptor};
+	use chain::sweeper::{OutputSweeper, OutputSweeperReadArgs};
+	use chain::transaction::OutPoint;
+	use util::ser::{ReadableArgs, Writeable};
+	use util::test_utils::{TestBroadcaster, TestFeeEstimator, TestLogger};
+	use std::io::Cursor;
+	use std::sync::Mutex;
+
+	fn header(n: u32) -> BlockHeader {
+		BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: n, bits: 42, nonce: 42 }
+	}
+
+	#[test]
+	fn test_sweep_survives_reload_and_reorg() {
+		let keys_manager = KeysManager::new(&[42; 32], Network::Testnet, 42, 42);
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let fee_estimator = TestFeeEstimator { sat_per_kw: 253 };
+		let logger = TestLogger::new();
+
+		// Spend an output paying to the KeysManager's destination script, as with a cooperative
+		// closing transaction.
+		let funding_tx = Transaction { version: 2, lock_time: 0, input: vec![TxIn::default()], output: vec![TxOut { script_pubkey: keys_manager.get_destination_script(), value: 10_000 }] };
+		let descriptor = SpendableOutputDescriptor::StaticOutput { outpoint: OutPoint { txid: funding_tx.txid(), index: 0 }, output: funding_tx.output[0].clone() };
+		let destination_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&[42; 20]).into_script();
+
+		let sweeper = OutputSweeper::new(destination_script.clone(), &keys_manager, &broadcaster, &fee_estimator, &logger);
+		sweeper.track_spendable_outputs(vec![descriptor.clone()], 100).unwrap();
+		let sweep_tx = broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+		sweep_tx.verify(|outpoint| if outpoint.txid == funding_tx.txid() { funding_tx.output.get(outpoint.vout as usize).cloned() } else { None }).unwrap();
+		assert_eq!(sweep_tx.output[0].script_pubkey, destination_script);
+
+		// Outputs which aren't ours can't be swept.
+		let foreign_descriptor = SpendableOutputDescriptor::StaticOutput { outpoint: OutPoint { txid: funding_tx.txid(), index: 1 }, output: TxOut { script_pubkey: destination_script.clone(), value: 10_000 } };
+		assert!(sweeper.track_spendable_outputs(vec![foreign_descriptor], 100).is_err());
+		assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+		// Confirm the sweep, then reload the sweeper and reorg the sweep out.
+		sweeper.block_connected(&header(101), &[(0, &sweep_tx)], 101);
+		assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+		let mut w = Vec::new();
+		sweeper.write(&mut w).unwrap();
+		let sweeper: OutputSweeper<_, _, _, _> = ReadableArgs::read(&mut Cursor::new(&w), OutputSweeperReadArgs {
+			keys_manager: &keys_manager, broadcaster: &broadcaster, fee_estimator: &fee_estimator, logger: &logger,
+		}).unwrap();
+		assert_eq!(sweeper.get_pending_sweeps(), vec![sweep_tx.clone()]);
+
+		// Data requiring a newer version of the format is rejected.
+		w[1] = 2;
+		assert!(OutputSweeper::read(&mut Cursor::new(&w), OutputSweeperReadArgs {
+			keys_manager: &keys_manager, broadcaster: &broadcaster, fee_estimator: &fee_estimator, logger: &logger,
+		}).is_err());
+
+		sweeper.block_disconnected(&header(101), 101);
+		sweeper.block_connected(&header(102), &[], 101);
+		let bumped_sweep_tx = broadcaster.txn_broadcasted.lock().unwrap().pop().unwrap();
+		assert_eq!(bumped_sweep_tx.input[0].previous_output, sweep_tx.input[0].previous_output);
+		assert!(bumped_sweep_tx.output[0].value < sweep_tx.output[0].value);
+
+		// Reconfirm the bumped sweep and bury it.
+		sweeper.block_connected(&header(103), &[(0, &bumped_sweep_tx)], 102);
+		for height in 103..102 + ANTI_REORG_DELAY - 1 {
+			sweeper.block_connected(&header(height + 1), &[], height);
+			assert_eq!(sweeper.get_pending_sweeps().len(), 1);
+		}
+		sweeper.block_connected(&header(200), &[], 102 + ANTI_REORG_DELAY - 1);
+		assert!(sweeper.get_pending_sweeps().is_empty());
+		assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+	}
+}
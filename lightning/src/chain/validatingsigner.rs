@@ -0,0 +1,600 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A [`ChannelKeys`] wrapper which checks everything it is asked to sign against a
+//! [`SigningPolicy`] before signing.
+//!
+//! A signer which blindly signs whatever it is handed protects the keys but not the funds: a
+//! compromised node could still ask it to sign a revoked state or a closing transaction paying an
+//! attacker. [`ValidatingChannelKeys`] independently re-derives what it can from the channel's
+//! keys and refuses, returning `Err(())` and logging the reason, to sign anything which violates
+//! its policy. It is intended to run wherever the keys live, e.g. behind a
+//! [`SignerServer`].
+//!
+//! [`ChannelKeys`]: ../keysinterface/trait.ChannelKeys.html
+//! [`SigningPolicy`]: struct.SigningPolicy.html
+//! [`ValidatingChannelKeys`]: struct.ValidatingChannelKeys.html
+//! [`SignerServer`]: ../remotesigner/struct.SignerServer.html
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, SigHashType};
+use bitcoin::util::bip143;
+
+use bitcoin::secp256k1::key::{SecretKey, PublicKey};
+use bitcoin::secp256k1::{Secp256k1, Signature};
+use bitcoin::secp256k1;
+
+use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys};
+use ln::chan_utils;
+use ln::chan_utils::{ChannelPublicKeys, HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+use ln::msgs::{DecodeError, UnsignedChannelAnnouncement};
+use util::logger::Logger;
+use util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::cmp;
+use std::io::Error;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+
+/// The weight of the witness spending the funding output in a closing transaction, including the
+/// segwit marker and flag: two 73-byte signatures and the 71-byte funding redeemscript.
+const CLOSING_TX_WITNESS_WEIGHT: u64 = 2 + 1 + 1 + 2 * (1 + 73) + 1 + 71;
+
+/// The rules a [`ValidatingChannelKeys`] enforces before signing.
+///
+/// [`ValidatingChannelKeys`]: struct.ValidatingChannelKeys.html
+#[derive(Clone, PartialEq)]
+pub struct SigningPolicy {
+	/// The scripts our funds may be sent to by closing, justice and HTLC-claiming transactions,
+	/// e.g. the `KeysInterface`'s destination script and shutdown script.
+	pub allowed_destination_scripts: Vec<Script>,
+	/// The maximum feerate of any transaction we sign, and of the HTLC transactions of
+	/// counterparty commitment transactions.
+	pub max_feerate_per_kw: u32,
+	/// No output of a transaction we sign may be worth less than this.
+	pub dust_limit_satoshis: u64,
+}
+
+impl SigningPolicy {
+	fn is_allowed_destination(&self, script: &Script) -> bool {
+		self.allowed_destination_scripts.iter().any(|allowed| allowed == script)
+	}
+}
+
+impl Writeable for SigningPolicy {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		(self.allowed_destination_scripts.len() as u64).write(writer)?;
+		for script in self.allowed_destination_scripts.iter() {
+			script.write(writer)?;
+		}
+		self.max_feerate_per_kw.write(writer)?;
+		self.dust_limit_satoshis.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for SigningPolicy {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let scripts_count: u64 = Readable::read(reader)?;
+		let mut allowed_destination_scripts = Vec::with_capacity(cmp::min(scripts_count as usize, 16));
+		for _ in 0..scripts_count {
+			allowed_destination_scripts.push(Readable::read(reader)?);
+		}
+		Ok(Self {
+			allowed_destination_scripts,
+			max_feerate_per_kw: Readable::read(reader)?,
+			dust_limit_satoshis: Readable::read(reader)?,
+		})
+	}
+}
+
+/// What we've signed and released so far, shared between clones.
+struct ValidationState {
+	/// The (unobscured) number of the latest counterparty commitment transaction signed.
+	last_counterparty_commitment_number: u64,
+	/// The lowest holder commitment number whose secret was released. All holder commitment
+	/// transactions with this number or above are revoked.
+	lowest_released_holder_commitment_number: u64,
+}
+
+/// A `ChannelKeys` wrapping an [`InMemoryChannelKeys`] which checks every transaction against a
+/// [`SigningPolicy`] and refuses to sign:
+///  * holder commitment transactions whose per-commitment secret was already released,
+///  * commitment transactions built with keys other than those derived from the channel's
+///    basepoints, with HTLC outputs which don't match the HTLCs given or with dust outputs,
+///  * counterparty commitment transactions for a state older than the latest signed,
+///  * HTLC transactions of holder commitment transactions without valid counterparty signatures,
+///  * closing, justice and HTLC-claiming transactions paying our funds to scripts which aren't
+///    whitelisted,
+///  * any transaction paying above the policy's feerate.
+///
+/// Note that clones share which commitments were signed and released, so the copy given to a
+/// `ChannelMonitor` must be a clone of the copy used by the `Channel` for revoked holder
+/// commitment transactions to be refused. Copies deserialized separately only know what was
+/// persisted with them, so a signer should keep a single copy per channel.
+///
+/// [`InMemoryChannelKeys`]: ../keysinterface/struct.InMemoryChannelKeys.html
+/// [`SigningPolicy`]: struct.SigningPolicy.html
+pub struct ValidatingChannelKeys<L: Deref + Clone + Send> where L::Target: Logger {
+	inner: InMemoryChannelKeys,
+	inbound: bool,
+	policy: SigningPolicy,
+	state: Arc<Mutex<ValidationState>>,
+	logger: L,
+}
+
+impl<L: Deref + Clone + Send> Clone for ValidatingChannelKeys<L> where L::Target: Logger {
+	fn clone(&self) -> Self {
+		Self {
+			inner: self.inner.clone(),
+			inbound: self.inbound,
+			policy: self.policy.clone(),
+			state: self.state.clone(),
+			logger: self.logger.clone(),
+		}
+	}
+}
+
+macro_rules! policy_violation {
+	($self: expr, $($arg: tt)*) => {
+		{
+			log_error!($self.logger, $($arg)*);
+			return Err(());
+		}
+	}
+}
+
+impl<L: Deref + Clone + Send> ValidatingChannelKeys<L> where L::Target: Logger {
+	/// Wraps the given keys of an inbound or outbound channel, as passed to
+	/// `KeysInterface::get_channel_keys`, enforcing `policy` on everything they sign.
+	pub fn new(inner: InMemoryChannelKeys, inbound: bool, policy: SigningPolicy, logger: L) -> Self {
+		Self {
+			inner,
+			inbound,
+			policy,
+			state: Arc::new(Mutex::new(ValidationState {
+				last_counterparty_commitment_number: 0,
+				lowest_released_holder_commitment_number: INITIAL_COMMITMENT_NUMBER + 1,
+			})),
+			logger,
+		}
+	}
+
+	/// Gets the wrapped keys.
+	pub fn inner(&self) -> &InMemoryChannelKeys { &self.inner }
+
+	/// Gets the factor commitment numbers of this channel are obscured with, derived from both
+	/// payment basepoints in the order given by which side opened the channel.
+	fn commitment_number_obscure_factor(&self) -> u64 {
+		let holder_payment_point = &self.inner.pubkeys().payment_point;
+		let counterparty_payment_point = &self.inner.counterparty_pubkeys().payment_point;
+		if self.inbound {
+			chan_utils::get_commitment_transaction_number_obscure_factor(counterparty_payment_point, holder_payment_point)
+		} else {
+			chan_utils::get_commitment_transaction_number_obscure_factor(holder_payment_point, counterparty_payment_point)
+		}
+	}
+
+	/// Gets the commitment number of a holder commitment transaction by decoding its obscured
+	/// commitment number and checking it against its per-commitment point.
+	fn holder_commitment_number<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Option<u64> {
+		let tx = &holder_commitment_tx.unsigned_tx;
+		let obscured_commitment_number = (tx.lock_time & 0xffffff) as u64 | ((tx.input[0].sequence as u64 & 0xffffff) << 3*8);
+		let commitment_number = INITIAL_COMMITMENT_NUMBER - (obscured_commitment_number ^ self.commitment_number_obscure_factor());
+		if self.inner.get_per_commitment_point(commitment_number, secp_ctx) == Ok(holder_commitment_tx.keys.per_commitment_point) {
+			Some(commitment_number)
+		} else { None }
+	}
+
+	fn check_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<(), ()> {
+		if holder_commitment_tx.unsigned_tx.input.len() != 1 {
+			policy_violation!(self, "Refusing to sign holder commitment transaction with {} inputs", holder_commitment_tx.unsigned_tx.input.len());
+		}
+		if holder_commitment_tx.feerate_per_kw > self.policy.max_feerate_per_kw {
+			policy_violation!(self, "Refusing to sign holder commitment transaction with feerate {} above {}", holder_commitment_tx.feerate_per_kw, self.policy.max_feerate_per_kw);
+		}
+		let commitment_number = match self.holder_commitment_number(holder_commitment_tx, secp_ctx) {
+			Some(commitment_number) => commitment_number,
+			None => policy_violation!(self, "Refusing to sign holder commitment transaction {} with an unknown per-commitment point", holder_commitment_tx.txid()),
+		};
+		if commitment_number >= self.state.lock().unwrap().lowest_released_holder_commitment_number {
+			policy_violation!(self, "Refusing to sign revoked holder commitment transaction {} with commitment number {}", holder_commitment_tx.txid(), commitment_number);
+		}
+
+		let keys = &holder_commitment_tx.keys;
+		let holder_points = self.inner.pubkeys();
+		let counterparty_points = self.inner.counterparty_pubkeys();
+		match TxCreationKeys::derive_new(secp_ctx, &keys.per_commitment_point, &holder_points.delayed_payment_basepoint, &holder_points.htlc_basepoint, &counterparty_points.revocation_basepoint, &counterparty_points.htlc_basepoint) {
+			Ok(ref expected_keys) if expected_keys == keys => {},
+			_ => policy_violation!(self, "Refusing to sign holder commitment transaction {} with keys not derived from the channel basepoints", holder_commitment_tx.txid()),
+		}
+		self.check_commitment_outputs(&holder_commitment_tx.unsigned_tx, keys, holder_commitment_tx.per_htlc.iter().map(|&(ref htlc, _)| htlc), "holder")
+	}
+
+	/// Checks that each HTLC output of a commitment transaction matches one of `htlcs`, that
+	/// there is at most a to_self and a to_remote output besides them and that no output is dust.
+	fn check_commitment_outputs<'a, I: Iterator<Item = &'a HTLCOutputInCommitment>>(&self, commitment_tx: &Transaction, keys: &TxCreationKeys, htlcs: I, tx_type: &str) -> Result<(), ()> {
+		let mut htlc_outputs = 0;
+		for htlc in htlcs {
+			if let Some(output_index) = htlc.transaction_output_index {
+				htlc_outputs += 1;
+				let expected_script = chan_utils::get_htlc_redeemscript(htlc, keys).to_v0_p2wsh();
+				match commitment_tx.output.get(output_index as usize) {
+					Some(output) if output.script_pubkey == expected_script && output.value == htlc.amount_msat / 1000 => {},
+					_ => policy_violation!(self, "Refusing to sign {} commitment transaction {} whose output {} doesn't match HTLC {}", tx_type, commitment_tx.txid(), output_index, log_bytes!(htlc.payment_hash.0)),
+				}
+			}
+		}
+		if commitment_tx.output.len() > htlc_outputs + 2 {
+			policy_violation!(self, "Refusing to sign {} commitment transaction {} with {} unexpected outputs", tx_type, commitment_tx.txid(), commitment_tx.output.len() - htlc_outputs - 2);
+		}
+		if let Some(output) = commitment_tx.output.iter().find(|output| output.value < self.policy.dust_limit_satoshis) {
+			policy_violation!(self, "Refusing to sign {} commitment transaction {} with dust output of {} sat", tx_type, commitment_tx.txid(), output.value);
+		}
+		Ok(())
+	}
+
+	fn check_destinations(&self, tx: &Transaction, tx_type: &str) -> Result<(), ()> {
+		for output in tx.output.iter() {
+			if !self.policy.is_allowed_destination(&output.script_pubkey) {
+				policy_violation!(self, "Refusing to sign {} transaction {} paying to non-whitelisted script {}", tx_type, tx.txid(), output.script_pubkey);
+			}
+			if output.value < self.policy.dust_limit_satoshis {
+				policy_violation!(self, "Refusing to sign {} transaction {} with dust output of {} sat", tx_type, tx.txid(), output.value);
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<L: Deref + Clone + Send> ChannelKeys for ValidatingChannelKeys<L> where L::Target: Logger {
+	fn get_per_commitment_point<T: secp256k1::Signing + secp256k1::Verification>(&self, idx: u64, secp_ctx: &Secp256k1<T>) -> Result<PublicKey, ()> {
+		self.inner.get_per_commitment_point(idx, secp_ctx)
+	}
+
+	fn release_commitment_secret(&self, idx: u64) -> Result<[u8; 32], ()> {
+		let mut state = self.state.lock().unwrap();
+		state.lowest_released_holder_commitment_number = cmp::min(state.lowest_released_holder_commitment_number, idx);
+		self.inner.release_commitment_secret(idx)
+	}
+
+	fn pubkeys(&self) -> &ChannelPublicKeys { self.inner.pubkeys() }
+	fn key_derivation_params(&self) -> (u64, u64) { self.inner.key_derivation_params() }
+
+	fn sign_counterparty_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, feerate_per_kw: u32, commitment_tx: &Transaction, pre_keys: &PreCalculatedTxCreationKeys, htlcs: &[&HTLCOutputInCommitment], secp_ctx: &Secp256k1<T>) -> Result<(Signature, Vec<Signature>), ()> {
+		if commitment_tx.input.len() != 1 {
+			policy_violation!(self, "Refusing to sign counterparty commitment transaction with {} inputs", commitment_tx.input.len());
+		}
+		if feerate_per_kw > self.policy.max_feerate_per_kw {
+			policy_violation!(self, "Refusing to sign counterparty commitment transaction with feerate {} above {}", feerate_per_kw, self.policy.max_feerate_per_kw);
+		}
+
+		let keys = pre_keys.trust_key_derivation();
+		let counterparty_points = self.inner.counterparty_pubkeys();
+		match TxCreationKeys::derive_new(secp_ctx, &keys.per_commitment_point, &counterparty_points.delayed_payment_basepoint, &counterparty_points.htlc_basepoint, &self.inner.pubkeys().revocation_basepoint, &self.inner.pubkeys().htlc_basepoint) {
+			Ok(ref expected_keys) if expected_keys == keys => {},
+			_ => policy_violation!(self, "Refusing to sign counterparty commitment transaction {} with keys not derived from the channel basepoints", commitment_tx.txid()),
+		}
+
+		self.check_commitment_outputs(commitment_tx, keys, htlcs.iter().map(|htlc| *htlc), "counterparty")?;
+
+		{
+			let obscured_commitment_number = (commitment_tx.lock_time & 0xffffff) as u64 | ((commitment_tx.input[0].sequence as u64 & 0xffffff) << 3*8);
+			let commitment_number = obscured_commitment_number ^ self.commitment_number_obscure_factor();
+			let mut state = self.state.lock().unwrap();
+			let last = state.last_counterparty_commitment_number;
+			if commitment_number != last && commitment_number != last + 1 {
+				policy_violation!(self, "Refusing to sign counterparty commitment transaction {} with commitment number {} as we already signed {}", commitment_tx.txid(), commitment_number, last);
+			}
+			state.last_counterparty_commitment_number = commitment_number;
+		}
+
+		self.inner.sign_counterparty_commitment(feerate_per_kw, commitment_tx, pre_keys, htlcs, secp_ctx)
+	}
+
+	fn sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_holder_commitment(holder_commitment_tx, secp_ctx)?;
+		self.inner.sign_holder_commitment(holder_commitment_tx, secp_ctx)
+	}
+
+	#[cfg(any(test,feature = "unsafe_revoked_tx_signing"))]
+	fn unsafe_sign_holder_commitment<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.inner.unsafe_sign_holder_commitment(holder_commitment_tx, secp_ctx)
+	}
+
+	fn sign_holder_commitment_htlc_transactions<T: secp256k1::Signing + secp256k1::Verification>(&self, holder_commitment_tx: &HolderCommitmentTransaction, secp_ctx: &Secp256k1<T>) -> Result<Vec<Option<Signature>>, ()> {
+		self.check_holder_commitment(holder_commitment_tx, secp_ctx)?;
+
+		let commitment_txid = holder_commitment_tx.txid();
+		let holder_csv = self.inner.counterparty_selected_contest_delay();
+		let keys = &holder_commitment_tx.keys;
+		for &(ref htlc, ref counterparty_sig) in holder_commitment_tx.per_htlc.iter() {
+			if htlc.transaction_output_index.is_some() {
+				let htlc_tx = chan_utils::build_htlc_transaction(&commitment_txid, holder_commitment_tx.feerate_per_kw, holder_csv, htlc, &keys.broadcaster_delayed_payment_key, &keys.revocation_key);
+				let htlc_redeemscript = chan_utils::get_htlc_redeemscript(htlc, keys);
+				let sighash = hash_to_message!(&bip143::SigHashCache::new(&htlc_tx).signature_hash(0, &htlc_redeemscript, htlc.amount_msat / 1000, SigHashType::All)[..]);
+				match counterparty_sig {
+					&Some(ref sig) if secp_ctx.verify(&sighash, sig, &keys.countersignatory_htlc_key).is_ok() => {},
+					_ => policy_violation!(self, "Refusing to sign HTLC transaction {} without a valid counterparty signature", htlc_tx.txid()),
+				}
+			}
+		}
+
+		self.inner.sign_holder_commitment_htlc_transactions(holder_commitment_tx, secp_ctx)
+	}
+
+	fn sign_justice_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, justice_tx: &Transaction, input: usize, amount: u64, per_commitment_key: &SecretKey, htlc: &Option<HTLCOutputInCommitment>, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destinations(justice_tx, "justice")?;
+		self.inner.sign_justice_transaction(justice_tx, input, amount, per_commitment_key, htlc, secp_ctx)
+	}
+
+	fn sign_counterparty_htlc_transaction<T: secp256k1::Signing + secp256k1::Verification>(&self, htlc_tx: &Transaction, input: usize, amount: u64, per_commitment_point: &PublicKey, htlc: &HTLCOutputInCommitment, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.check_destinations(htlc_tx, "HTLC-claiming")?;
+		self.inner.sign_counterparty_htlc_transaction(htlc_tx, input, amount, per_commitment_point, htlc, secp_ctx)
+	}
+
+	fn sign_closing_transaction<T: secp256k1::Signing>(&self, closing_tx: &Transaction, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		if closing_tx.input.len() != 1 || closing_tx.output.len() > 2 {
+			policy_violation!(self, "Refusing to sign closing transaction {} with {} inputs and {} outputs", closing_tx.txid(), closing_tx.input.len(), closing_tx.output.len());
+		}
+		// One output may pay the counterparty, but all others must pay us.
+		if closing_tx.output.iter().filter(|output| !self.policy.is_allowed_destination(&output.script_pubkey)).count() > 1 {
+			policy_violation!(self, "Refusing to sign closing transaction {} without an output to a whitelisted script", closing_tx.txid());
+		}
+		if let Some(output) = closing_tx.output.iter().find(|output| output.value < self.policy.dust_limit_satoshis) {
+			policy_violation!(self, "Refusing to sign closing transaction {} with dust output of {} sat", closing_tx.txid(), output.value);
+		}
+		let output_value: u64 = closing_tx.output.iter().map(|output| output.value).sum();
+		let fee = self.inner.channel_value_satoshis().saturating_sub(output_value);
+		let max_fee = self.policy.max_feerate_per_kw as u64 * (closing_tx.get_weight() as u64 + CLOSING_TX_WITNESS_WEIGHT) / 1000;
+		if fee > max_fee {
+			policy_violation!(self, "Refusing to sign closing transaction {} paying a fee of {} sat, above {}", closing_tx.txid(), fee, max_fee);
+		}
+		self.inner.sign_closing_transaction(closing_tx, secp_ctx)
+	}
+
+	fn sign_channel_announcement<T: secp256k1::Signing>(&self, msg: &UnsignedChannelAnnouncement, secp_ctx: &Secp256k1<T>) -> Result<Signature, ()> {
+		self.inner.sign_channel_announcement(msg, secp_ctx)
+	}
+
+	fn on_accept(&mut self, channel_pubkeys: &ChannelPublicKeys, counterparty_selected_contest_delay: u16, holder_selected_contest_delay: u16) -> Result<(), ()> {
+		self.inner.on_accept(channel_pubkeys, counterparty_selected_contest_delay, holder_selected_contest_delay)
+	}
+}
+
+impl<L: Deref + Clone + Send> Writeable for ValidatingChannelKeys<L> where L::Target: Logger {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		self.inner.write(writer)?;
+		self.inbound.write(writer)?;
+		self.policy.write(writer)?;
+		let state = self.state.lock().unwrap();
+		state.last_counterparty_commitment_number.write(writer)?;
+		state.lowest_released_holder_commitment_number.write(writer)?;
+		Ok(())
+	}
+}
+
+impl<L: Deref + Clone + Send> ReadableArgs<L> for ValidatingChannelKeys<L> where L::Target: Logger {
+	fn read<R: ::std::io::Read>(reader: &mut R, logger: L) -> Result<Self, DecodeError> {
+		let inner = Readable::read(reader)?;
+		let inbound = Readable::read(reader)?;
+		let policy = Readable::read(reader)?;
+		let last_counterparty_commitment_number = Readable::read(reader)?;
+		let lowest_released_holder_commitment_number = Readable::read(reader)?;
+		Ok(Self {
+			inner,
+			inbound,
+			policy,
+			state: Arc::new(Mutex::new(ValidationState {
+				last_counterparty_commitment_number,
+				lowest_released_holder_commitment_number,
+			})),
+			logger,
+		})
+	}
+}
+
+/// Reads the keys along with what they signed and released with a default logger, as needed to
+/// use them as the `ChanSigner` of a `ChannelManager` or `ChannelMonitor` read from disk.
+impl<L: Deref + Clone + Send + Default> Readable for ValidatingChannelKeys<L> where L::Target: Logger {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		ReadableArgs::read(reader, L::default())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::opcodes;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::{Message, Secp256k1};
+	use bitcoin::secp256k1::key::SecretKey;
+	use chain::keysinterface::{ChannelKeys, KeysManager, InMemoryChannelKeys};
+	use chain::validatingsigner::{INITIAL_COMMITMENT_NUMBER, SigningPolicy, ValidatingChannelKeys};
+	use ln::chan_utils;
+	use ln::chan_utils::{HolderCommitmentTransaction, HTLCOutputInCommitment, PreCalculatedTxCreationKeys, TxCreationKeys};
+	use ln::channelmanager::PaymentHash;
+	use util::ser::{Readable, Writeable};
+	use util::test_utils::TestLogger;
+	use std::io::Cursor;
+	use std::sync::Arc;
+
+	fn script(n: u8) -> Script {
+		Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&[n; 20]).into_script()
+	}
+
+	fn channel_keys() -> (ValidatingChannelKeys<Arc<TestLogger>>, InMemoryChannelKeys, Arc<TestLogger>) {
+		let mut holder_keys = KeysManager::new(&[1; 32], Network::Testnet, 42, 42).derive_channel_keys(100_000, 0, 0);
+		let mut counterparty_keys = KeysManager::new(&[2; 32], Network::Testnet, 42, 42).derive_channel_keys(100_000, 0, 0);
+		let holder_pubkeys = holder_keys.pubkeys().clone();
+		holder_keys.on_accept(counterparty_keys.pubkeys(), 144, 144).unwrap();
+		counterparty_keys.on_accept(&holder_pubkeys, 144, 144).unwrap();
+		let policy = SigningPolicy { allowed_destination_scripts: vec![script(1)], max_feerate_per_kw: 10_000, dust_limit_satoshis: 546 };
+		let logger = Arc::new(TestLogger::new());
+		(ValidatingChannelKeys::new(holder_keys, false, policy, logger.clone()), counterparty_keys, logger)
+	}
+
+	/// Builds a commitment transaction with the given obscured commitment number
+	fn commitment_tx(obscured_commitment_number: u64, output: Vec<TxOut>) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: ((0x20 as u32) << 8*3) | ((obscured_commitment_number & 0xffffffu64) as u32),
+			input: vec![TxIn { sequence: ((0x80 as u32) << 8*3) | ((obscured_commitment_number >> 3*8) as u32), ..TxIn::default() }],
+			output,
+		}
+	}
+
+	fn holder_commitment_tx(keys: &ValidatingChannelKeys<Arc<TestLogger>>, counterparty_keys: &InMemoryChannelKeys, encoded_idx: u64, point_idx: u64) -> HolderCommitmentTransaction {
+		holder_commitment_tx_with_htlcs(keys, counterparty_keys, encoded_idx, point_idx, vec![TxOut { script_pubkey: script(2), value: 99_000 }], Vec::new(), |_| {})
+	}
+
+	/// Builds a holder commitment transaction with the given outputs and HTLCs, whose keys may be
+	/// modified by `tweak_keys` after the HTLC outputs' scripts were derived from them
+	fn holder_commitment_tx_with_htlcs<F: Fn(&mut TxCreationKeys)>(keys: &ValidatingChannelKeys<Arc<TestLogger>>, counterparty_keys: &InMemoryChannelKeys, encoded_idx: u64, point_idx: u64, mut output: Vec<TxOut>, htlcs: Vec<HTLCOutputInCommitment>, tweak_keys: F) -> HolderCommitmentTransaction {
+		let secp_ctx = Secp256k1::new();
+		let holder_pubkeys = keys.pubkeys();
+		let counterparty_pubkeys = counterparty_keys.pubkeys();
+		let obscure_factor = chan_utils::get_commitment_transaction_number_obscure_factor(&holder_pubkeys.payment_point, &counterparty_pubkeys.payment_point);
+		let per_commitment_point = keys.get_per_commitment_point(point_idx, &secp_ctx).unwrap();
+		let mut tx_keys = TxCreationKeys::derive_new(&secp_ctx, &per_commitment_point, &holder_pubkeys.delayed_payment_basepoint, &holder_pubkeys.htlc_basepoint, &counterparty_pubkeys.revocation_basepoint, &counterparty_pubkeys.htlc_basepoint).unwrap();
+		for htlc in htlcs.iter() {
+			output.insert(htlc.transaction_output_index.unwrap() as usize, TxOut { script_pubkey: chan_utils::get_htlc_redeemscript(htlc, &tx_keys).to_v0_p2wsh(), value: htlc.amount_msat / 1000 });
+		}
+		tweak_keys(&mut tx_keys);
+		let dummy_sig = secp_ctx.sign(&Message::from_slice(&[42; 32]).unwrap(), &SecretKey::from_slice(&[42; 32]).unwrap());
+		let tx = commitment_tx((INITIAL_COMMITMENT_NUMBER - encoded_idx) ^ obscure_factor, output);
+		let per_htlc = htlcs.into_iter().map(|htlc| (htlc, Some(dummy_sig))).collect();
+		HolderCommitmentTransaction::new_missing_holder_sig(tx, dummy_sig, &holder_pubkeys.funding_pubkey, &counterparty_pubkeys.funding_pubkey, tx_keys, 253, per_htlc)
+	}
+
+	#[test]
+	fn test_refuses_revoked_holder_commitment() {
+		let secp_ctx = Secp256k1::new();
+		let (keys, counterparty_keys, logger) = channel_keys();
+
+		let current = holder_commitment_tx(&keys, &counterparty_keys, INITIAL_COMMITMENT_NUMBER, INITIAL_COMMITMENT_NUMBER);
+		assert!(keys.sign_holder_commitment(&current, &secp_ctx).is_ok());
+
+		// A transaction whose per-commitment point doesn't match its commitment number is refused
+		let mismatched = holder_commitment_tx(&keys, &counterparty_keys, INITIAL_COMMITMENT_NUMBER, INITIAL_COMMITMENT_NUMBER - 1);
+		assert!(keys.sign_holder_commitment(&mismatched, &secp_ctx).is_err());
+
+		// Once its secret is released, by the keys or a clone of them, the state is refused
+		let next = holder_commitment_tx(&keys, &counterparty_keys, INITIAL_COMMITMENT_NUMBER - 1, INITIAL_COMMITMENT_NUMBER - 1);
+		keys.clone().release_commitment_secret(INITIAL_COMMITMENT_NUMBER).unwrap();
+		assert!(keys.sign_holder_commitment(&current, &secp_ctx).is_err());
+		assert!(keys.sign_holder_commitment_htlc_transactions(&current, &secp_ctx).is_err());
+		logger.assert_log_contains("lightning::chain::validatingsigner".to_string(), "Refusing to sign revoked holder commitment transaction".to_string(), 2);
+		assert!(keys.sign_holder_commitment(&next, &secp_ctx).is_ok());
+
+		// The release survives serialization
+		let reloaded: ValidatingChannelKeys<Arc<TestLogger>> = Readable::read(&mut Cursor::new(&keys.encode())).unwrap();
+		assert!(reloaded.sign_holder_commitment(&current, &secp_ctx).is_err());
+		assert!(reloaded.sign_holder_commitment(&next, &secp_ctx).is_ok());
+	}
+
+	#[test]
+	fn test_refuses_invalid_holder_commitment() {
+		let secp_ctx = Secp256k1::new();
+		let (keys, counterparty_keys, logger) = channel_keys();
+		let htlc = HTLCOutputInCommitment { offered: true, amount_msat: 10_000_000, cltv_expiry: 500, payment_hash: PaymentHash([42; 32]), transaction_output_index: Some(0) };
+		let build = |output: Vec<TxOut>, htlcs: Vec<HTLCOutputInCommitment>| holder_commitment_tx_with_htlcs(&keys, &counterparty_keys, INITIAL_COMMITMENT_NUMBER, INITIAL_COMMITMENT_NUMBER, output, htlcs, |_| {});
+
+		let valid = build(vec![TxOut { script_pubkey: script(2), value: 89_000 }], vec![htlc.clone()]);
+		assert!(keys.sign_holder_commitment(&valid, &secp_ctx).is_ok());
+
+		// Outputs beyond to_self, to_remote and the HTLC outputs, an HTLC output not matching its
+		// HTLC or a dust output are refused
+		let unexpected_output = build(vec![TxOut { script_pubkey: script(2), value: 69_000 }, TxOut { script_pubkey: script(3), value: 10_000 }, TxOut { script_pubkey: script(4), value: 10_000 }], vec![htlc.clone()]);
+		assert!(keys.sign_holder_commitment(&unexpected_output, &secp_ctx).is_err());
+		logger.assert_log_contains("lightning::chain::validatingsigner".to_string(), "Refusing to sign holder commitment transaction".to_string(), 1);
+		let mut mismatched_htlc = build(vec![TxOut { script_pubkey: script(2), value: 89_000 }], vec![htlc.clone()]);
+		mismatched_htlc.per_htlc[0].0.amount_msat += 1000;
+		assert!(keys.sign_holder_commitment(&mismatched_htlc, &secp_ctx).is_err());
+		assert!(keys.sign_holder_commitment_htlc_transactions(&mismatched_htlc, &secp_ctx).is_err());
+		let dust = build(vec![TxOut { script_pubkey: script(2), value: 98_500 }, TxOut { script_pubkey: script(3), value: 500 }], Vec::new());
+		assert!(keys.sign_holder_commitment(&dust, &secp_ctx).is_err());
+
+		// As are keys we didn't derive, even with the right per-commitment point
+		let bad_keys = holder_commitment_tx_with_htlcs(&keys, &counterparty_keys, INITIAL_COMMITMENT_NUMBER, INITIAL_COMMITMENT_NUMBER,
+			vec![TxOut { script_pubkey: script(2), value: 89_000 }], vec![htlc.clone()], |tx_keys| tx_keys.revocation_key = tx_keys.broadcaster_delayed_payment_key);
+		assert!(keys.sign_holder_commitment(&bad_keys, &secp_ctx).is_err());
+		logger.assert_log_contains("lightning::chain::validatingsigner".to_string(), "with keys not derived from the channel basepoints".to_string(), 1);
+	}
+
+	#[test]
+	fn test_refuses_invalid_counterparty_commitment() {
+		let secp_ctx = Secp256k1::new();
+		let (keys, counterparty_keys, logger) = channel_keys();
+		let per_commitment_point = counterparty_keys.get_per_commitment_point(INITIAL_COMMITMENT_NUMBER, &secp_ctx).unwrap();
+		let counterparty_pubkeys = counterparty_keys.pubkeys();
+		let tx_keys = TxCreationKeys::derive_new(&secp_ctx, &per_commitment_point, &counterparty_pubkeys.delayed_payment_basepoint, &counterparty_pubkeys.htlc_basepoint, &keys.pubkeys().revocation_basepoint, &keys.pubkeys().htlc_basepoint).unwrap();
+		let htlc = HTLCOutputInCommitment { offered: true, amount_msat: 10_000_000, cltv_expiry: 500, payment_hash: PaymentHash([42; 32]), transaction_output_index: Some(0) };
+		let htlc_output = TxOut { script_pubkey: chan_utils::get_htlc_redeemscript(&htlc, &tx_keys).to_v0_p2wsh(), value: 10_000 };
+		let pre_keys = PreCalculatedTxCreationKeys::new(tx_keys.clone());
+		// We opened the channel, so our payment basepoint comes first
+		let obscure_factor = chan_utils::get_commitment_transaction_number_obscure_factor(&keys.pubkeys().payment_point, &counterparty_pubkeys.payment_point);
+
+		// A first state obscured with the wrong factor, or which skips states, is refused
+		let reversed_obscure_factor = chan_utils::get_commitment_transaction_number_obscure_factor(&counterparty_pubkeys.payment_point, &keys.pubkeys().payment_point);
+		let reversed = commitment_tx(reversed_obscure_factor, vec![htlc_output.clone(), TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(253, &reversed, &pre_keys, &[&htlc], &secp_ctx).is_err());
+		let skipping = commitment_tx(obscure_factor ^ 5, vec![htlc_output.clone(), TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(253, &skipping, &pre_keys, &[&htlc], &secp_ctx).is_err());
+
+		let first = commitment_tx(obscure_factor, vec![htlc_output.clone(), TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(253, &first, &pre_keys, &[&htlc], &secp_ctx).is_ok());
+		let second = commitment_tx(obscure_factor ^ 1, vec![htlc_output.clone(), TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(253, &second, &pre_keys, &[&htlc], &secp_ctx).is_ok());
+
+		// Going back to the first state is refused
+		assert!(keys.sign_counterparty_commitment(253, &first, &pre_keys, &[&htlc], &secp_ctx).is_err());
+		logger.assert_log_contains("lightning::chain::validatingsigner".to_string(), "as we already signed 1".to_string(), 1);
+
+		// As is a state whose HTLC output doesn't match the HTLC, whose feerate is too high or with
+		// keys we didn't derive
+		let mut bad_htlc_output = htlc_output.clone();
+		bad_htlc_output.value -= 1;
+		let third = commitment_tx(obscure_factor ^ 2, vec![bad_htlc_output, TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(253, &third, &pre_keys, &[&htlc], &secp_ctx).is_err());
+		let third = commitment_tx(obscure_factor ^ 2, vec![htlc_output.clone(), TxOut { script_pubkey: script(2), value: 80_000 }]);
+		assert!(keys.sign_counterparty_commitment(20_000, &third, &pre_keys, &[&htlc], &secp_ctx).is_err());
+		let mut bad_keys = tx_keys.clone();
+		bad_keys.revocation_key = bad_keys.broadcaster_delayed_payment_key;
+		assert!(keys.sign_counterparty_commitment(253, &third, &PreCalculatedTxCreationKeys::new(bad_keys), &[&htlc], &secp_ctx).is_err());
+		assert!(keys.sign_counterparty_commitment(253, &third, &pre_keys, &[&htlc], &secp_ctx).is_ok());
+	}
+
+	#[test]
+	fn test_refuses_closing_to_unknown_scripts() {
+		let secp_ctx = Secp256k1::new();
+		let (keys, _, _) = channel_keys();
+		let closing_tx = |outputs: Vec<(u8, u64)>| Transaction {
+			version: 2, lock_time: 0, input: vec![TxIn::default()],
+			output: outputs.iter().map(|&(n, value)| TxOut { script_pubkey: script(n), value }).collect(),
+		};
+
+		assert!(keys.sign_closing_transaction(&closing_tx(vec![(1, 60_000), (2, 39_000)]), &secp_ctx).is_ok());
+		// Our balance may be too small for an output to us
+		assert!(keys.sign_closing_transaction(&closing_tx(vec![(2, 99_000)]), &secp_ctx).is_ok());
+		// But our funds can't go elsewhere
+		assert!(keys.sign_closing_transaction(&closing_tx(vec![(3, 60_000), (2, 39_000)]), &secp_ctx).is_err());
+		// Nor be burnt in fees or in dust outputs
+		assert!(keys.sign_closing_transaction(&closing_tx(vec![(1, 40_000), (2, 39_000)]), &secp_ctx).is_err());
+		assert!(keys.sign_closing_transaction(&closing_tx(vec![(1, 500), (2, 98_500)]), &secp_ctx).is_err());
+
+		let justice_tx = closing_tx(vec![(3, 99_000)]);
+		assert!(keys.sign_justice_transaction(&justice_tx, 0, 100_000, &SecretKey::from_slice(&[3; 32]).unwrap(), &None, &secp_ctx).is_err());
+		let justice_tx = closing_tx(vec![(1, 99_000)]);
+		assert!(keys.sign_justice_transaction(&justice_tx, 0, 100_000, &SecretKey::from_slice(&[3; 32]).unwrap(), &None, &secp_ctx).is_ok());
+	}
+}
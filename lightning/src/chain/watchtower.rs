@@ -0,0 +1,811 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Utilities for outsourcing the punishment of revoked counterparty commitment transactions to
+//! untrusted watchtowers.
+//!
+//! A [`ChannelMonitor`] only protects a channel while our node is online to watch the chain. A
+//! [`WatchtowerClient`] follows the [`ChannelMonitorUpdate`]s of each channel and, whenever a
+//! counterparty commitment transaction is revoked, pre-signs a justice transaction claiming all of
+//! its revocable outputs. The justice transaction is encrypted with a key derived from the txid of
+//! the revoked commitment transaction and handed to a [`Tower`] along with a [`CommitmentHint`].
+//!
+//! The tower learns nothing about the channel until the revoked commitment transaction appears
+//! on-chain: only then can it match the hint against the txid, derive the decryption key and
+//! broadcast the justice transaction. [`WatchtowerServer`] implements this tower side on top of a
+//! [`BlobStore`], processing blocks in the same way as a [`ChainMonitor`].
+//!
+//! [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+//! [`ChannelMonitorUpdate`]: ../channelmonitor/struct.ChannelMonitorUpdate.html
+//! [`WatchtowerClient`]: struct.WatchtowerClient.html
+//! [`Tower`]: trait.Tower.html
+//! [`CommitmentHint`]: struct.CommitmentHint.html
+//! [`WatchtowerServer`]: struct.WatchtowerServer.html
+//! [`BlobStore`]: trait.BlobStore.html
+//! [`ChainMonitor`]: ../chainmonitor/struct.ChainMonitor.html
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut, SigHashType};
+use bitcoin::blockdata::transaction::OutPoint as BitcoinOutPoint;
+use bitcoin::consensus::encode;
+use bitcoin::hash_types::Txid;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+
+use bitcoin::secp256k1::{Secp256k1, PublicKey, SecretKey};
+use bitcoin::secp256k1;
+
+use ln::chan_utils;
+use ln::chan_utils::{HTLCOutputInCommitment, TxCreationKeys};
+use ln::msgs::DecodeError;
+use ln::onchaintx::{OnchainTxHandler, InputDescriptors};
+use chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateStep, ANTI_REORG_DELAY};
+use chain::keysinterface::{ChannelKeys, DUST_LIMIT_SATOSHIS};
+use chain::transaction::{OutPoint, TransactionData};
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
+use util::logger::Logger;
+use util::ser::{Readable, Writer, Writeable};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::ops::Deref;
+
+/// The first 16 bytes of the txid of a revoked commitment transaction, allowing a tower to find
+/// the [`JusticeBlob`] for a transaction seen on-chain without learning the decryption key.
+///
+/// [`JusticeBlob`]: struct.JusticeBlob.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CommitmentHint(pub [u8; 16]);
+
+impl CommitmentHint {
+	/// Computes the hint for the commitment transaction with the given txid.
+	pub fn from_txid(commitment_txid: &Txid) -> Self {
+		let mut hint = [0; 16];
+		hint.copy_from_slice(&commitment_txid[0..16]);
+		CommitmentHint(hint)
+	}
+}
+
+/// A justice transaction encrypted with a key derived from the txid of the revoked commitment
+/// transaction it spends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JusticeBlob {
+	/// The hint of the revoked commitment transaction spent by the justice transaction.
+	pub hint: CommitmentHint,
+	/// The encrypted justice transaction, followed by its 16-byte authentication tag.
+	pub encrypted_justice_tx: Vec<u8>,
+}
+
+impl JusticeBlob {
+	fn derive_key(commitment_txid: &Txid) -> Sha256 {
+		Sha256::hash(&commitment_txid[..])
+	}
+
+	/// Encrypts `justice_tx`, which spends the commitment transaction with the given txid.
+	pub fn encrypt(commitment_txid: &Txid, justice_tx: &Transaction) -> Self {
+		let plaintext = encode::serialize(justice_tx);
+		let mut encrypted_justice_tx = vec![0; plaintext.len() + 16];
+		{
+			let (ciphertext, tag) = encrypted_justice_tx.split_at_mut(plaintext.len());
+			// Each key encrypts a single justice transaction, so a zero nonce is fine.
+			let mut chacha = ChaCha20Poly1305RFC::new(&Self::derive_key(commitment_txid)[..], &[0; 12], &[]);
+			chacha.encrypt(&plaintext, ciphertext, tag);
+		}
+		Self { hint: CommitmentHint::from_txid(commitment_txid), encrypted_justice_tx }
+	}
+
+	/// Decrypts the justice transaction given the txid of the commitment transaction it spends.
+	/// Fails if the txid doesn't correspond to this blob or if the blob is malformed.
+	pub fn decrypt(&self, commitment_txid: &Txid) -> Result<Transaction, ()> {
+		if self.hint != CommitmentHint::from_txid(commitment_txid) || self.encrypted_justice_tx.len() < 16 {
+			return Err(());
+		}
+		let (ciphertext, tag) = self.encrypted_justice_tx.split_at(self.encrypted_justice_tx.len() - 16);
+		let mut plaintext = vec![0; ciphertext.len()];
+		let mut chacha = ChaCha20Poly1305RFC::new(&Self::derive_key(commitment_txid)[..], &[0; 12], &[]);
+		if !chacha.decrypt(ciphertext, &mut plaintext, tag) {
+			return Err(());
+		}
+		encode::deserialize(&plaintext).map_err(|_| ())
+	}
+}
+
+impl Writeable for JusticeBlob {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		self.hint.0.write(w)?;
+		self.encrypted_justice_tx.write(w)?;
+		Ok(())
+	}
+}
+
+impl Readable for JusticeBlob {
+	fn read<R: ::std::io::Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let hint = CommitmentHint(Readable::read(r)?);
+		let encrypted_justice_tx = Readable::read(r)?;
+		Ok(Self { hint, encrypted_justice_tx })
+	}
+}
+
+/// The `Tower` trait defines behavior for delivering [`JusticeBlob`]s to a watchtower.
+///
+/// Delivery may be asynchronous. Once the tower has durably stored a blob, the acknowledgement
+/// should be reported back via [`WatchtowerClient::blob_acknowledged`]. Until then, the blob is
+/// kept by the client and may be re-sent with [`WatchtowerClient::resend_unacknowledged_blobs`].
+///
+/// [`JusticeBlob`]: struct.JusticeBlob.html
+/// [`WatchtowerClient::blob_acknowledged`]: struct.WatchtowerClient.html#method.blob_acknowledged
+/// [`WatchtowerClient::resend_unacknowledged_blobs`]: struct.WatchtowerClient.html#method.resend_unacknowledged_blobs
+pub trait Tower: Send + Sync {
+	/// Sends `blob` to the tower.
+	fn send_blob(&self, blob: &JusticeBlob);
+}
+
+/// A counterparty commitment transaction which has not yet been revoked.
+struct UnrevokedCommitment {
+	tx: Transaction,
+	htlcs: Vec<HTLCOutputInCommitment>,
+}
+
+/// Per-channel data needed to build justice transactions.
+struct TowerChannel<ChanSigner: ChannelKeys> {
+	keys: ChanSigner,
+	counterparty_delayed_payment_base_key: PublicKey,
+	counterparty_htlc_base_key: PublicKey,
+	on_counterparty_tx_csv: u16,
+	unrevoked_commitments: HashMap<u64, UnrevokedCommitment>,
+}
+
+/// Builds and delivers pre-signed justice transactions for revoked counterparty commitment
+/// transactions to a [`Tower`].
+///
+/// Each channel must first be registered with [`track_channel`], after which every
+/// [`ChannelMonitorUpdate`] applied to its [`ChannelMonitor`] must also be given to
+/// [`process_monitor_update`], typically from within a custom [`chain::Watch`] implementation.
+///
+/// Justice transactions pay to the given destination script at the high priority feerate at the
+/// time of revocation. Unacknowledged blobs are not persisted, thus the client should be kept in
+/// sync with the tower before shutting down.
+///
+/// [`Tower`]: trait.Tower.html
+/// [`track_channel`]: #method.track_channel
+/// [`process_monitor_update`]: #method.process_monitor_update
+/// [`ChannelMonitorUpdate`]: ../channelmonitor/struct.ChannelMonitorUpdate.html
+/// [`ChannelMonitor`]: ../channelmonitor/struct.ChannelMonitor.html
+/// [`chain::Watch`]: ../trait.Watch.html
+pub struct WatchtowerClient<ChanSigner: ChannelKeys, T: Deref, F: Deref, L: Deref>
+	where T::Target: Tower,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	channels: Mutex<HashMap<OutPoint, TowerChannel<ChanSigner>>>,
+	unacknowledged_blobs: Mutex<HashMap<CommitmentHint, JusticeBlob>>,
+	destination_script: Script,
+	tower: T,
+	fee_estimator: F,
+	logger: L,
+	secp_ctx: Secp256k1<secp256k1::All>,
+}
+
+impl<ChanSigner: ChannelKeys, T: Deref, F: Deref, L: Deref> WatchtowerClient<ChanSigner, T, F, L>
+	where T::Target: Tower,
+	      F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	/// Creates a new `WatchtowerClient` whose justice transactions pay to `destination_script`.
+	pub fn new(destination_script: Script, tower: T, fee_estimator: F, logger: L) -> Self {
+		Self {
+			channels: Mutex::new(HashMap::new()),
+			unacknowledged_blobs: Mutex::new(HashMap::new()),
+			destination_script,
+			tower,
+			fee_estimator,
+			logger,
+			secp_ctx: Secp256k1::new(),
+		}
+	}
+
+	/// Starts tracking the channel watched by `monitor`, including its current counterparty
+	/// commitment transaction. Should be called when a new channel is given to
+	/// [`chain::Watch::watch_channel`] as well as for each monitor loaded on startup.
+	///
+	/// [`chain::Watch::watch_channel`]: ../trait.Watch.html#tymethod.watch_channel
+	pub fn track_channel(&self, monitor: &ChannelMonitor<ChanSigner>) {
+		let (keys, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, on_counterparty_tx_csv) = monitor.get_justice_parameters();
+		let mut unrevoked_commitments = HashMap::new();
+		if let Some((tx, commitment_number, htlcs)) = monitor.get_current_counterparty_commitment_tx() {
+			unrevoked_commitments.insert(commitment_number, UnrevokedCommitment { tx, htlcs });
+		}
+		let funding_txo = monitor.get_funding_txo().0;
+		log_trace!(self.logger, "Watchtower client tracking channel {}", log_bytes!(funding_txo.to_channel_id()[..]));
+		self.channels.lock().unwrap().insert(funding_txo, TowerChannel {
+			keys,
+			counterparty_delayed_payment_base_key,
+			counterparty_htlc_base_key,
+			on_counterparty_tx_csv,
+			unrevoked_commitments,
+		});
+	}
+
+	/// Stops tracking the channel with the given funding outpoint, e.g. once it has been closed.
+	pub fn untrack_channel(&self, funding_txo: &OutPoint) {
+		self.channels.lock().unwrap().remove(funding_txo);
+	}
+
+	/// Processes an update for the channel with the given funding outpoint, recording new
+	/// counterparty commitment transactions and sending a [`JusticeBlob`] to the tower for each
+	/// one revoked.
+	///
+	/// Updates for untracked channels are ignored.
+	///
+	/// [`JusticeBlob`]: struct.JusticeBlob.html
+	pub fn process_monitor_update(&self, funding_txo: &OutPoint, update: &ChannelMonitorUpdate) {
+		let mut new_blobs = Vec::new();
+		{
+			let mut channels = self.channels.lock().unwrap();
+			let channel = match channels.get_mut(funding_txo) {
+				Some(channel) => channel,
+				None => return,
+			};
+			for step in update.updates.iter() {
+				match step {
+					&ChannelMonitorUpdateStep::LatestCounterpartyCommitmentTXInfo { ref unsigned_commitment_tx, ref htlc_outputs, ref commitment_number, .. } => {
+						let htlcs = htlc_outputs.iter().filter(|&&(ref htlc, _)| htlc.transaction_output_index.is_some()).map(|&(ref htlc, _)| htlc.clone()).collect();
+						channel.unrevoked_commitments.insert(*commitment_number, UnrevokedCommitment { tx: unsigned_commitment_tx.clone(), htlcs });
+					},
+					&ChannelMonitorUpdateStep::CommitmentSecret { ref idx, ref secret } => {
+						if let Some(commitment) = channel.unrevoked_commitments.remove(idx) {
+							let commitment_txid = commitment.tx.txid();
+							match self.build_justice_tx(channel, &commitment, secret) {
+								Some(justice_tx) => {
+									log_trace!(self.logger, "Built justice transaction {} for revoked counterparty commitment transaction {}", justice_tx.txid(), commitment_txid);
+									new_blobs.push(JusticeBlob::encrypt(&commitment_txid, &justice_tx));
+								},
+								None => {
+									log_trace!(self.logger, "No justice transaction to build for revoked counterparty commitment transaction {}", commitment_txid);
+								},
+							}
+						}
+					},
+					_ => {},
+				}
+			}
+		}
+
+		for blob in new_blobs.drain(..) {
+			self.unacknowledged_blobs.lock().unwrap().insert(blob.hint, blob.clone());
+			self.tower.send_blob(&blob);
+		}
+	}
+
+	/// Indicates that the tower has durably stored the blob with the given hint.
+	pub fn blob_acknowledged(&self, hint: &CommitmentHint) {
+		self.unacknowledged_blobs.lock().unwrap().remove(hint);
+	}
+
+	/// Returns the blobs which have not yet been acknowledged by the tower.
+	pub fn get_unacknowledged_blobs(&self) -> Vec<JusticeBlob> {
+		self.unacknowledged_blobs.lock().unwrap().values().cloned().collect()
+	}
+
+	/// Sends all blobs which have not yet been acknowledged to the tower again, e.g. after
+	/// reconnecting to it.
+	pub fn resend_unacknowledged_blobs(&self) {
+		for blob in self.get_unacknowledged_blobs() {
+			self.tower.send_blob(&blob);
+		}
+	}
+
+	/// Builds a justice transaction claiming the to_local and HTLC outputs of a revoked
+	/// counterparty commitment transaction, returning None if there is nothing worth claiming.
+	fn build_justice_tx(&self, channel: &TowerChannel<ChanSigner>, commitment: &UnrevokedCommitment, secret: &[u8; 32]) -> Option<Transaction> {
+		let per_commitment_key = match SecretKey::from_slice(secret) {
+			Ok(key) => key,
+			Err(_) => return None,
+		};
+		let per_commitment_point = PublicKey::from_secret_key(&self.secp_ctx, &per_commitment_key);
+		let chan_keys = match TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, &channel.counterparty_delayed_payment_base_key, &channel.counterparty_htlc_base_key, &channel.keys.pubkeys().revocation_basepoint, &channel.keys.pubkeys().htlc_basepoint) {
+			Ok(keys) => keys,
+			Err(_) => return None,
+		};
+
+		let commitment_txid = commitment.tx.txid();
+		let revokeable_redeemscript = chan_utils::get_revokeable_redeemscript(&chan_keys.revocation_key, channel.on_counterparty_tx_csv, &chan_keys.broadcaster_delayed_payment_key);
+		let revokeable_p2wsh = revokeable_redeemscript.to_v0_p2wsh();
+
+		// Each claimed output as (vout, amount, htlc, witness script)
+		let mut claims = Vec::new();
+		for (idx, outp) in commitment.tx.output.iter().enumerate() {
+			if outp.script_pubkey == revokeable_p2wsh {
+				claims.push((idx as u32, outp.value, None, revokeable_redeemscript.clone()));
+			}
+		}
+		for htlc in commitment.htlcs.iter() {
+			if let Some(transaction_output_index) = htlc.transaction_output_index {
+				match commitment.tx.output.get(transaction_output_index as usize) {
+					Some(outp) if outp.value == htlc.amount_msat / 1000 => {
+						let witness_script = chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key);
+						claims.push((transaction_output_index, outp.value, Some(htlc.clone()), witness_script));
+					},
+					_ => return None,
+				}
+			}
+		}
+		if claims.is_empty() {
+			return None;
+		}
+
+		let mut justice_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: Vec::with_capacity(claims.len()),
+			output: vec![TxOut { script_pubkey: self.destination_script.clone(), value: 0 }],
+		};
+		let mut input_descriptors = Vec::with_capacity(claims.len());
+		let mut amount = 0;
+		for &(vout, value, ref htlc, _) in claims.iter() {
+			justice_tx.input.push(TxIn {
+				previous_output: BitcoinOutPoint { txid: commitment_txid, vout },
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: Vec::new(),
+			});
+			input_descriptors.push(match htlc {
+				&Some(ref htlc) if htlc.offered => InputDescriptors::RevokedOfferedHTLC,
+				&Some(_) => InputDescriptors::RevokedReceivedHTLC,
+				&None => InputDescriptors::RevokedOutput,
+			});
+			amount += value;
+		}
+
+		let predicted_weight = (justice_tx.get_weight() + OnchainTxHandler::<ChanSigner>::get_witnesses_weight(&input_descriptors[..])) as u64;
+		let fee = self.fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority) as u64 * predicted_weight / 1000;
+		if amount < fee + DUST_LIMIT_SATOSHIS {
+			return None;
+		}
+		justice_tx.output[0].value = amount - fee;
+
+		for (i, &(_, value, ref htlc, ref witness_script)) in claims.iter().enumerate() {
+			let sig = match channel.keys.sign_justice_transaction(&justice_tx, i, value, &per_commitment_key, htlc, &self.secp_ctx) {
+				Ok(sig) => sig,
+				Err(_) => return None,
+			};
+			justice_tx.input[i].witness.push(sig.serialize_der().to_vec());
+			justice_tx.input[i].witness[0].push(SigHashType::All as u8);
+			if htlc.is_some() {
+				justice_tx.input[i].witness.push(chan_keys.revocation_key.clone().serialize().to_vec());
+			} else {
+				justice_tx.input[i].witness.push(vec!(1));
+			}
+			justice_tx.input[i].witness.push(witness_script.clone().into_bytes());
+		}
+		Some(justice_tx)
+	}
+}
+
+/// The largest [`JusticeBlob`] a [`WatchtowerServer`] accepts. A justice transaction can't be
+/// larger than the maximum standard transaction weight of 400,000 and still be relayed, and is
+/// followed by a 16-byte authentication tag.
+///
+/// [`JusticeBlob`]: struct.JusticeBlob.html
+/// [`WatchtowerServer`]: struct.WatchtowerServer.html
+pub const MAX_JUSTICE_BLOB_LEN: usize = 400_000 + 16;
+
+/// The `BlobStore` trait defines behavior for durably storing [`JusticeBlob`]s on the tower side.
+///
+/// [`JusticeBlob`]: struct.JusticeBlob.html
+pub trait BlobStore: Send + Sync {
+	/// Stores `blob`. Once this returns `Ok`, the blob may be acknowledged to the client. Several
+	/// blobs may be stored for the same hint.
+	///
+	/// Stores should be bounded in size, failing to store any blob once full.
+	fn store_blob(&self, blob: JusticeBlob) -> Result<(), ()>;
+
+	/// Returns all blobs stored for `hint`.
+	fn get_blobs(&self, hint: &CommitmentHint) -> Vec<JusticeBlob>;
+
+	/// Removes all blobs stored for `hint`.
+	fn remove_blobs(&self, hint: &CommitmentHint);
+}
+
+/// A [`BlobStore`] keeping up to a given number of blobs in memory, mostly useful for testing.
+///
+/// [`BlobStore`]: trait.BlobStore.html
+pub struct MemoryBlobStore {
+	blobs: Mutex<HashMap<CommitmentHint, Vec<JusticeBlob>>>,
+	max_blobs: usize,
+}
+
+impl MemoryBlobStore {
+	/// Creates a new, empty `MemoryBlobStore` holding at most `max_blobs` blobs.
+	pub fn new(max_blobs: usize) -> Self {
+		Self { blobs: Mutex::new(HashMap::new()), max_blobs }
+	}
+
+	/// Returns the number of blobs stored.
+	pub fn len(&self) -> usize {
+		self.blobs.lock().unwrap().values().map(|hint_blobs| hint_blobs.len()).sum()
+	}
+
+	/// Returns whether no blobs are stored.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl BlobStore for MemoryBlobStore {
+	fn store_blob(&self, blob: JusticeBlob) -> Result<(), ()> {
+		let stored_blobs = self.len();
+		let mut blobs = self.blobs.lock().unwrap();
+		if blobs.get(&blob.hint).map(|hint_blobs| hint_blobs.contains(&blob)).unwrap_or(false) {
+			return Ok(());
+		}
+		if stored_blobs >= self.max_blobs {
+			return Err(());
+		}
+		blobs.entry(blob.hint).or_insert(Vec::new()).push(blob);
+		Ok(())
+	}
+
+	fn get_blobs(&self, hint: &CommitmentHint) -> Vec<JusticeBlob> {
+		self.blobs.lock().unwrap().get(hint).cloned().unwrap_or(Vec::new())
+	}
+
+	fn remove_blobs(&self, hint: &CommitmentHint) {
+		self.blobs.lock().unwrap().remove(hint);
+	}
+}
+
+/// A justice transaction broadcast by a [`WatchtowerServer`].
+///
+/// [`WatchtowerServer`]: struct.WatchtowerServer.html
+struct BroadcastJusticeTx {
+	tx: Transaction,
+	/// The txid and height of the first confirmed transaction spending an output claimed by tx,
+	/// which is either tx itself or a conflicting transaction preventing it from confirming.
+	resolution: Option<(Txid, u32)>,
+}
+
+/// A revoked commitment transaction which a [`WatchtowerServer`] saw confirm.
+///
+/// [`WatchtowerServer`]: struct.WatchtowerServer.html
+struct ConfirmedCommitment {
+	hint: CommitmentHint,
+	height: u32,
+	justice_txn: Vec<BroadcastJusticeTx>,
+}
+
+/// The tower side of the protocol: stores [`JusticeBlob`]s received from clients and broadcasts
+/// the justice transaction they contain once the corresponding revoked commitment transaction is
+/// seen on-chain.
+///
+/// Chain data is given through the same methods as to a [`ChainMonitor`]. Blobs are kept until the
+/// outputs claimed by their justice transactions have been spent, by the justice transactions or
+/// otherwise, for [`ANTI_REORG_DELAY`] blocks, such that justice transactions are broadcast again if
+/// the commitment transaction or their spends are reorganized out of the chain.
+///
+/// [`JusticeBlob`]: struct.JusticeBlob.html
+/// [`ChainMonitor`]: ../chainmonitor/struct.ChainMonitor.html
+/// [`ANTI_REORG_DELAY`]: ../channelmonitor/constant.ANTI_REORG_DELAY.html
+pub struct WatchtowerServer<S: Deref, B: Deref, L: Deref>
+	where S::Target: BlobStore,
+	      B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	store: S,
+	broadcaster: B,
+	logger: L,
+	confirmed_commitments: Mutex<HashMap<Txid, ConfirmedCommitment>>,
+}
+
+impl<S: Deref, B: Deref, L: Deref> WatchtowerServer<S, B, L>
+	where S::Target: BlobStore,
+	      B::Target: BroadcasterInterface,
+	      L::Target: Logger,
+{
+	/// Creates a new `WatchtowerServer` storing blobs in `store`.
+	pub fn new(store: S, broadcaster: B, logger: L) -> Self {
+		Self { store, broadcaster, logger, confirmed_commitments: Mutex::new(HashMap::new()) }
+	}
+
+	/// Stores a blob received from a client, returning whether it may be acknowledged. Blobs
+	/// larger than [`MAX_JUSTICE_BLOB_LEN`] are rejected, as are any the store has no room for.
+	///
+	/// [`MAX_JUSTICE_BLOB_LEN`]: constant.MAX_JUSTICE_BLOB_LEN.html
+	pub fn receive_blob(&self, blob: JusticeBlob) -> bool {
+		if blob.encrypted_justice_tx.len() > MAX_JUSTICE_BLOB_LEN {
+			log_trace!(self.logger, "Rejecting blob of {} bytes", blob.encrypted_justice_tx.len());
+			return false;
+		}
+		self.store.store_blob(blob).is_ok()
+	}
+
+	/// Scans the transactions of a connected block for revoked commitment transactions, broadcasting
+	/// the justice transaction of any matching blob, and forgets the blobs whose justice
+	/// transactions were resolved at least [`ANTI_REORG_DELAY`] blocks ago. Returns the broadcast
+	/// justice transactions.
+	///
+	/// [`ANTI_REORG_DELAY`]: ../channelmonitor/constant.ANTI_REORG_DELAY.html
+	pub fn block_connected(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) -> Vec<Transaction> {
+		let justice_txn = self.transactions_confirmed(header, txdata, height);
+		self.best_block_updated(header, height);
+		justice_txn
+	}
+
+	/// Indicates a block was disconnected, forgetting any confirmation at or above its height.
+	/// Justice transactions whose resolution was disconnected are broadcast again, while those
+	/// whose commitment transaction was disconnected are broadcast again once it confirms again.
+	pub fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		self.unconfirm(|_, height| height >= disconnected_height);
+	}
+
+	/// Scans the given confirmed transactions for revoked commitment transactions, broadcasting
+	/// the justice transaction of any matching blob. Returns the broadcast justice transactions.
+	///
+	/// For use by towers which learn of individual confirmed transactions rather than full
+	/// blocks, as with [`ChainMonitor::transactions_confirmed`]. As the tower can't know which
+	/// commitment transactions to look for, all transactions of each block must be given, and
+	/// [`best_block_updated`] called whenever the chain tip changes.
+	///
+	/// [`ChainMonitor::transactions_confirmed`]: ../chainmonitor/struct.ChainMonitor.html#method.transactions_confirmed
+	/// [`best_block_updated`]: #method.best_block_updated
+	pub fn transactions_confirmed(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) -> Vec<Transaction> {
+		let mut justice_txn = Vec::new();
+		let mut confirmed_commitments = self.confirmed_commitments.lock().unwrap();
+		for &(_, tx) in txdata.iter() {
+			let txid = tx.txid();
+			for commitment in confirmed_commitments.values_mut() {
+				for justice_tx in commitment.justice_txn.iter_mut() {
+					if justice_tx.resolution.is_none() && tx.input.iter().any(|input| justice_tx.tx.input.iter().any(|claim| claim.previous_output == input.previous_output)) {
+						justice_tx.resolution = Some((txid, height));
+					}
+				}
+			}
+
+			if confirmed_commitments.contains_key(&txid) {
+				continue;
+			}
+			let hint = CommitmentHint::from_txid(&txid);
+			let mut commitment_justice_txn = Vec::new();
+			for blob in self.store.get_blobs(&hint) {
+				let justice_tx = match blob.decrypt(&txid) {
+					Ok(justice_tx) => justice_tx,
+					Err(()) => {
+						log_trace!(self.logger, "Failed to decrypt blob matching hint of transaction {}", txid);
+						continue;
+					},
+				};
+				if !Self::is_valid_justice_tx(tx, &justice_tx) {
+					log_error!(self.logger, "Blob for transaction {} contains an invalid justice transaction {}", txid, justice_tx.txid());
+					continue;
+				}
+				log_info!(self.logger, "Broadcasting justice transaction {} for revoked commitment transaction {} confirmed at height {}", justice_tx.txid(), txid, height);
+				self.broadcaster.broadcast_transaction(&justice_tx);
+				justice_txn.push(justice_tx.clone());
+				commitment_justice_txn.push(BroadcastJusticeTx { tx: justice_tx, resolution: None });
+			}
+			if !commitment_justice_txn.is_empty() {
+				confirmed_commitments.insert(txid, ConfirmedCommitment { hint, height, justice_txn: commitment_justice_txn });
+			}
+		}
+		justice_txn
+	}
+
+	/// Indicates a transaction was reorganized out of the chain, as with
+	/// [`ChainMonitor::transaction_unconfirmed`]. Transactions to check for reorgs are given by
+	/// [`get_relevant_txids`].
+	///
+	/// [`ChainMonitor::transaction_unconfirmed`]: ../chainmonitor/struct.ChainMonitor.html#method.transaction_unconfirmed
+	/// [`get_relevant_txids`]: #method.get_relevant_txids
+	pub fn transaction_unconfirmed(&self, txid: &Txid) {
+		self.unconfirm(|confirmed_txid, _| confirmed_txid == txid);
+	}
+
+	/// Indicates a new best chain tip, forgetting the blobs whose justice transactions were
+	/// resolved at least [`ANTI_REORG_DELAY`] blocks ago.
+	///
+	/// [`ANTI_REORG_DELAY`]: ../channelmonitor/constant.ANTI_REORG_DELAY.html
+	pub fn best_block_updated(&self, _header: &BlockHeader, height: u32) {
+		let mut confirmed_commitments = self.confirmed_commitments.lock().unwrap();
+		let store = &self.store;
+		let logger = &self.logger;
+		confirmed_commitments.retain(|txid, commitment| {
+			let resolved = commitment.justice_txn.iter().all(|justice_tx| match justice_tx.resolution {
+				Some((_, resolution_height)) => resolution_height + ANTI_REORG_DELAY - 1 <= height,
+				None => false,
+			});
+			if resolved {
+				log_trace!(logger, "Forgetting blobs for revoked commitment transaction {} as its outputs were claimed", txid);
+				store.remove_blobs(&commitment.hint);
+			}
+			!resolved
+		});
+	}
+
+	/// Returns the txids of the revoked commitment transactions and their resolving spends which
+	/// should be monitored for reorganization out of the chain.
+	pub fn get_relevant_txids(&self) -> Vec<Txid> {
+		let mut txids = Vec::new();
+		for (txid, commitment) in self.confirmed_commitments.lock().unwrap().iter() {
+			txids.push(*txid);
+			for justice_tx in commitment.justice_txn.iter() {
+				if let Some((resolution_txid, _)) = justice_tx.resolution {
+					txids.push(resolution_txid);
+				}
+			}
+		}
+		txids.sort_unstable();
+		txids.dedup();
+		txids
+	}
+
+	/// Forgets the confirmation of every commitment transaction and resolution for which
+	/// is_unconfirmed returns true given its txid and height.
+	fn unconfirm<F: Fn(&Txid, u32) -> bool>(&self, is_unconfirmed: F) {
+		let mut confirmed_commitments = self.confirmed_commitments.lock().unwrap();
+		confirmed_commitments.retain(|txid, commitment| !is_unconfirmed(txid, commitment.height));
+		for commitment in confirmed_commitments.values_mut() {
+			for justice_tx in commitment.justice_txn.iter_mut() {
+				let unconfirmed = match justice_tx.resolution {
+					Some((ref txid, height)) => is_unconfirmed(txid, height),
+					None => false,
+				};
+				if unconfirmed {
+					justice_tx.resolution = None;
+					log_info!(self.logger, "Broadcasting justice transaction {} again as its resolution was unconfirmed", justice_tx.tx.txid());
+					self.broadcaster.broadcast_transaction(&justice_tx.tx);
+				}
+			}
+		}
+	}
+
+	/// Checks that `justice_tx` only spends distinct, existing outputs of `commitment_tx` and pays a
+	/// fee. Script validity can't be checked without the full consensus rules and is left to the
+	/// network.
+	fn is_valid_justice_tx(commitment_tx: &Transaction, justice_tx: &Transaction) -> bool {
+		if justice_tx.input.is_empty() || justice_tx.output.is_empty() {
+			return false;
+		}
+		let commitment_txid = commitment_tx.txid();
+		let mut spent_outputs = HashSet::new();
+		let mut amount_in = 0;
+		for input in justice_tx.input.iter() {
+			if input.previous_output.txid != commitment_txid || !spent_outputs.insert(input.previous_output.vout) {
+				return false;
+			}
+			match commitment_tx.output.get(input.previous_output.vout as usize) {
+				Some(outp) => amount_in += outp.value,
+				None => return false,
+			}
+		}
+		let amount_out = justice_tx.output.iter().fold(0u64, |total, outp| total.saturating_add(outp.value));
+		amount_out < amount_in
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::blockdata::transaction::Transaction;
+	use bitcoin::hash_types::Txid;
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::blockdata::block::BlockHeader;
+	use bitcoin::blockdata::script::Script;
+	use bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+	use chain::channelmonitor::ANTI_REORG_DELAY;
+	use chain::watchtower::{BlobStore, CommitmentHint, JusticeBlob, MemoryBlobStore, WatchtowerServer, MAX_JUSTICE_BLOB_LEN};
+	use util::ser::{Readable, Writeable};
+	use util::test_utils::{TestBroadcaster, TestLogger};
+	use std::sync::Mutex;
+
+	#[test]
+	fn test_justice_blob_roundtrip() {
+		let commitment_txid = Txid::from_hash(Sha256dHash::hash(&[1; 32]));
+		let other_txid = Txid::from_hash(Sha256dHash::hash(&[2; 32]));
+		let justice_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: Vec::new() };
+
+		let blob = JusticeBlob::encrypt(&commitment_txid, &justice_tx);
+		assert_eq!(blob.hint, CommitmentHint::from_txid(&commitment_txid));
+		assert_eq!(blob.decrypt(&commitment_txid), Ok(justice_tx));
+		assert!(blob.decrypt(&other_txid).is_err());
+
+		// A blob whose hint matches but whose content was tampered with doesn't decrypt.
+		let mut tampered = blob.clone();
+		tampered.encrypted_justice_tx[0] ^= 1;
+		assert!(tampered.decrypt(&commitment_txid).is_err());
+
+		let encoded = blob.encode();
+		assert_eq!(JusticeBlob::read(&mut ::std::io::Cursor::new(&encoded)).unwrap(), blob);
+	}
+
+	#[test]
+	fn test_watchtower_server_rejects_invalid_justice_tx() {
+		let store = MemoryBlobStore::new(10);
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let logger = TestLogger::new();
+		let server = WatchtowerServer::new(&store, &broadcaster, &logger);
+
+		let commitment_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![TxOut { value: 10_000, script_pubkey: Script::new() }] };
+		let commitment_txid = commitment_tx.txid();
+		let spend = |vout: u32, value: u64| Transaction {
+			version: 2, lock_time: 0,
+			input: vec![TxIn { previous_output: OutPoint { txid: commitment_txid, vout }, script_sig: Script::new(), sequence: 0xfffffffd, witness: Vec::new() }],
+			output: vec![TxOut { value, script_pubkey: Script::new() }],
+		};
+
+		// Spending a nonexistent output, paying no fee or a blob for another transaction sharing the
+		// hint are all ignored.
+		let mut foreign_blob = JusticeBlob::encrypt(&Txid::from_hash(Sha256dHash::hash(&[1; 32])), &spend(0, 9_000));
+		foreign_blob.hint = CommitmentHint::from_txid(&commitment_txid);
+		for blob in vec![JusticeBlob::encrypt(&commitment_txid, &spend(1, 9_000)), JusticeBlob::encrypt(&commitment_txid, &spend(0, 10_000)), foreign_blob] {
+			assert!(server.receive_blob(blob));
+		}
+		assert_eq!(store.get_blobs(&CommitmentHint::from_txid(&commitment_txid)).len(), 3);
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		assert!(server.block_connected(&header, &[(0, &commitment_tx)], 1).is_empty());
+		assert!(broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+		// A valid blob is still found among the invalid ones, and storing it twice is a no-op.
+		let valid_blob = JusticeBlob::encrypt(&commitment_txid, &spend(0, 9_000));
+		assert!(server.receive_blob(valid_blob.clone()));
+		assert!(server.receive_blob(valid_blob));
+		assert_eq!(server.block_connected(&header, &[(0, &commitment_tx)], 1), vec![spend(0, 9_000)]);
+		assert_eq!(*broadcaster.txn_broadcasted.lock().unwrap(), vec![spend(0, 9_000)]);
+	}
+
+	#[test]
+	fn test_watchtower_server_prunes_resolved_blobs() {
+		let store = MemoryBlobStore::new(2);
+		let broadcaster = TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+		let logger = TestLogger::new();
+		let server = WatchtowerServer::new(&store, &broadcaster, &logger);
+
+		let commitment_tx = Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![TxOut { value: 10_000, script_pubkey: Script::new() }] };
+		let commitment_txid = commitment_tx.txid();
+		let justice_tx = Transaction {
+			version: 2, lock_time: 0,
+			input: vec![TxIn { previous_output: OutPoint { txid: commitment_txid, vout: 0 }, script_sig: Script::new(), sequence: 0xfffffffd, witness: Vec::new() }],
+			output: vec![TxOut { value: 9_000, script_pubkey: Script::new() }],
+		};
+
+		// Oversized blobs and blobs beyond the store's bound are rejected.
+		let mut oversized_blob = JusticeBlob::encrypt(&commitment_txid, &justice_tx);
+		oversized_blob.encrypted_justice_tx = vec![0; MAX_JUSTICE_BLOB_LEN + 1];
+		assert!(!server.receive_blob(oversized_blob));
+		assert!(server.receive_blob(JusticeBlob::encrypt(&commitment_txid, &justice_tx)));
+		let other_txid = Txid::from_hash(Sha256dHash::hash(&[1; 32]));
+		assert!(server.receive_blob(JusticeBlob::encrypt(&other_txid, &justice_tx)));
+		assert!(!server.receive_blob(JusticeBlob::encrypt(&Txid::from_hash(Sha256dHash::hash(&[2; 32])), &justice_tx)));
+		assert_eq!(store.len(), 2);
+
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		assert_eq!(server.block_connected(&header, &[(0, &commitment_tx)], 1), vec![justice_tx.clone()]);
+		assert_eq!(server.get_relevant_txids(), vec![commitment_txid]);
+
+		// Once the justice transaction confirms, its blob is kept until it has ANTI_REORG_DELAY
+		// confirmations, and a reorg of the justice transaction has it broadcast again.
+		assert!(server.block_connected(&header, &[(0, &justice_tx)], 2).is_empty());
+		let mut relevant_txids = vec![commitment_txid, justice_tx.txid()];
+		relevant_txids.sort_unstable();
+		assert_eq!(server.get_relevant_txids(), relevant_txids);
+		server.block_disconnected(&header, 2);
+		assert_eq!(*broadcaster.txn_broadcasted.lock().unwrap(), vec![justice_tx.clone(), justice_tx.clone()]);
+		assert!(server.block_connected(&header, &[(0, &justice_tx)], 2).is_empty());
+		for height in 3..ANTI_REORG_DELAY + 1 {
+			server.best_block_updated(&header, height);
+			assert_eq!(store.get_blobs(&CommitmentHint::from_txid(&commitment_txid)).len(), 1);
+		}
+		server.best_block_updated(&header, ANTI_REORG_DELAY + 1);
+		assert!(store.get_blobs(&CommitmentHint::from_txid(&commitment_txid)).is_empty());
+		assert!(server.get_relevant_txids().is_empty());
+		assert_eq!(store.len(), 1);
+	}
+}
@@ -62,6 +62,22 @@ impl HTLCType {
 // Various functions for key derivation and transaction creation for use within channels. Primarily
 // used in Channel and ChannelMonitor.
 
+/// Gets the factor which commitment transaction numbers are XOR'd with before being encoded in
+/// the lock_time and sequence of commitment transactions, as described in BOLT 3.
+pub(crate) fn get_commitment_transaction_number_obscure_factor(opener_payment_basepoint: &PublicKey, acceptor_payment_basepoint: &PublicKey) -> u64 {
+	let mut sha = Sha256::engine();
+	sha.input(&opener_payment_basepoint.serialize());
+	sha.input(&acceptor_payment_basepoint.serialize());
+	let res = Sha256::from_engine(sha).into_inner();
+
+	((res[26] as u64) << 5*8) |
+	((res[27] as u64) << 4*8) |
+	((res[28] as u64) << 3*8) |
+	((res[29] as u64) << 2*8) |
+	((res[30] as u64) << 1*8) |
+	((res[31] as u64) << 0*8)
+}
+
 /// Build the commitment secret from the seed and the commitment number
 pub fn build_commitment_secret(commitment_seed: &[u8; 32], idx: u64) -> [u8; 32] {
 	let mut res: [u8; 32] = commitment_seed.clone();
@@ -222,7 +222,7 @@ fn do_test_monitor_temporary_update_fail(disconnect_count: usize) {
 				let events_3 = nodes[0].node.get_and_clear_pending_events();
 				assert_eq!(events_3.len(), 1);
 				match events_3[0] {
-					Event::PaymentSent { ref payment_preimage } => {
+					Event::PaymentSent { ref payment_preimage, .. } => {
 						assert_eq!(*payment_preimage, payment_preimage_1);
 					},
 					_ => panic!("Unexpected event"),
@@ -314,7 +314,7 @@ fn do_test_monitor_temporary_update_fail(disconnect_count: usize) {
 			let events_3 = nodes[0].node.get_and_clear_pending_events();
 			assert_eq!(events_3.len(), 1);
 			match events_3[0] {
-				Event::PaymentSent { ref payment_preimage } => {
+				Event::PaymentSent { ref payment_preimage, .. } => {
 					assert_eq!(*payment_preimage, payment_preimage_1);
 				},
 				_ => panic!("Unexpected event"),
@@ -1310,7 +1310,7 @@ fn claim_while_disconnected_monitor_update_fail() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(*payment_preimage, payment_preimage_1);
 		},
 		_ => panic!("Unexpected event"),
@@ -1700,7 +1700,7 @@ fn monitor_update_claim_fail_no_response() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(*payment_preimage, payment_preimage_1);
 		},
 		_ => panic!("Unexpected event"),
@@ -14,7 +14,7 @@ use bitcoin::blockdata::opcodes;
 use bitcoin::util::bip143;
 use bitcoin::consensus::encode;
 
-use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::hashes::Hash;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hash_types::{Txid, BlockHash, WPubkeyHash};
 
@@ -162,6 +162,7 @@ struct OutboundHTLCOutput {
 	payment_hash: PaymentHash,
 	state: OutboundHTLCState,
 	source: HTLCSource,
+	blinding_point: Option<PublicKey>,
 }
 
 /// See AwaitingRemoteRevoke ChannelState for more info
@@ -173,6 +174,7 @@ enum HTLCUpdateAwaitingACK {
 		payment_hash: PaymentHash,
 		source: HTLCSource,
 		onion_routing_packet: msgs::OnionPacket,
+		blinding_point: Option<PublicKey>,
 	},
 	ClaimHTLC {
 		payment_preimage: PaymentPreimage,
@@ -182,6 +184,11 @@ enum HTLCUpdateAwaitingACK {
 		htlc_id: u64,
 		err_packet: msgs::OnionErrorPacket,
 	},
+	FailMalformedHTLC {
+		htlc_id: u64,
+		failure_code: u16,
+		sha256_of_onion: [u8; 32],
+	},
 }
 
 /// There are a few "states" and then a number of flags which can be applied:
@@ -353,7 +360,9 @@ pub(super) struct Channel<ChanSigner: ChannelKeys> {
 	/// Used to deduplicate block_connected callbacks, also used to verify consistency during
 	/// ChannelManager deserialization (hence pub(super))
 	pub(super) last_block_connected: BlockHash,
-	funding_tx_confirmations: u64,
+	/// The height at which the funding transaction was confirmed, or 0 if it is not (or no longer)
+	/// confirmed.
+	funding_tx_confirmation_height: u32,
 
 	counterparty_dust_limit_satoshis: u64,
 	#[cfg(test)]
@@ -394,10 +403,6 @@ pub(super) struct Channel<ChanSigner: ChannelKeys> {
 }
 
 pub const OUR_MAX_HTLCS: u16 = 50; //TODO
-/// Confirmation count threshold at which we close a channel. Ideally we'd keep the channel around
-/// on ice until the funding transaction gets more confirmations, but the LN protocol doesn't
-/// really allow for this, so instead we're stuck closing it out at that point.
-const UNCONF_THRESHOLD: u32 = 6;
 const SPENDING_INPUT_FOR_A_OUTPUT_WEIGHT: u64 = 79; // prevout: 36, nSequence: 4, script len: 1, witness lengths: (3+1)/4, sig: 73/4, if-selector: 1, redeemScript: (6 ops + 2*33 pubkeys + 1*2 delay)/4
 const B_OUTPUT_PLUS_SPENDING_INPUT_WEIGHT: u64 = 104; // prevout: 40, nSequence: 4, script len: 1, witness lengths: 3/4, sig: 73/4, pubkey: 33/4, output: 31 (TODO: Wrong? Useless?)
 
@@ -414,6 +419,14 @@ pub const COMMITMENT_TX_WEIGHT_PER_HTLC: u64 = 172;
 /// it's 2^24.
 pub const MAX_FUNDING_SATOSHIS: u64 = 1 << 24;
 
+/// Checks that a counterparty's shutdown scriptpubkey is of one of the standard forms BOLT 2
+/// allows, which includes segwit v1+ programs (e.g. P2TR) if they negotiated
+/// `option_shutdown_anysegwit`.
+fn is_valid_shutdown_script(their_features: &InitFeatures, script: &Script) -> bool {
+	script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wpkh() || script.is_v0_p2wsh() ||
+		(their_features.supports_shutdown_anysegwit() && script.is_witness_program() && script.as_bytes()[0] != opcodes::all::OP_PUSHBYTES_0.into_u8())
+}
+
 /// Used to return a simple Error back to ChannelManager. Will get converted to a
 /// msgs::ErrorAction::SendErrorMessage or msgs::ErrorAction::IgnoreError as appropriate with our
 /// channel_id in ChannelManager.
@@ -467,7 +480,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	      F::Target: FeeEstimator,
 	{
 		let holder_selected_contest_delay = config.own_channel_config.our_to_self_delay;
-		let chan_keys = keys_provider.get_channel_keys(false, channel_value_satoshis);
+		let chan_keys = keys_provider.get_channel_keys(false, channel_value_satoshis)
+			.map_err(|_| APIError::ChannelUnavailable { err: "Failed to get channel keys from our signer".to_owned() })?;
 
 		if channel_value_satoshis >= MAX_FUNDING_SATOSHIS {
 			return Err(APIError::APIMisuseError{err: format!("funding_value must be smaller than {}, it was {}", MAX_FUNDING_SATOSHIS, channel_value_satoshis)});
@@ -534,7 +548,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			funding_tx_confirmed_in: None,
 			short_channel_id: None,
 			last_block_connected: Default::default(),
-			funding_tx_confirmations: 0,
+			funding_tx_confirmation_height: 0,
 
 			feerate_per_kw: feerate,
 			counterparty_dust_limit_satoshis: 0,
@@ -582,7 +596,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		where K::Target: KeysInterface<ChanKeySigner = ChanSigner>,
           F::Target: FeeEstimator
 	{
-		let mut chan_keys = keys_provider.get_channel_keys(true, msg.funding_satoshis);
+		let mut chan_keys = keys_provider.get_channel_keys(true, msg.funding_satoshis)
+			.map_err(|_| ChannelError::Close("Failed to get channel keys from our signer".to_owned()))?;
 		let counterparty_pubkeys = ChannelPublicKeys {
 			funding_pubkey: msg.funding_pubkey,
 			revocation_basepoint: msg.revocation_basepoint,
@@ -590,7 +605,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			delayed_payment_basepoint: msg.delayed_payment_basepoint,
 			htlc_basepoint: msg.htlc_basepoint
 		};
-		chan_keys.on_accept(&counterparty_pubkeys, msg.to_self_delay, config.own_channel_config.our_to_self_delay);
+		chan_keys.on_accept(&counterparty_pubkeys, msg.to_self_delay, config.own_channel_config.our_to_self_delay)
+			.map_err(|_| ChannelError::Close("Our signer failed to accept the channel".to_owned()))?;
 		let mut local_config = (*config).channel_options.clone();
 
 		if config.own_channel_config.our_to_self_delay < BREAKDOWN_TIMEOUT {
@@ -697,7 +713,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			match &msg.shutdown_scriptpubkey {
 				&OptionalField::Present(ref script) => {
 					// Peer is signaling upfront_shutdown and has provided a non-accepted scriptpubkey format. We enforce it while receiving shutdown msg
-					if script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wsh() || script.is_v0_p2wpkh() {
+					if is_valid_shutdown_script(&their_features, script) {
 						Some(script.clone())
 					// Peer is signaling upfront_shutdown and has opt-out with a 0-length script. We don't enforce anything
 					} else if script.len() == 0 {
@@ -761,7 +777,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			funding_tx_confirmed_in: None,
 			short_channel_id: None,
 			last_block_connected: Default::default(),
-			funding_tx_confirmations: 0,
+			funding_tx_confirmation_height: 0,
 
 			feerate_per_kw: msg.feerate_per_kw,
 			channel_value_satoshis: msg.funding_satoshis,
@@ -795,24 +811,12 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	// Utilities to build transactions:
 
 	fn get_commitment_transaction_number_obscure_factor(&self) -> u64 {
-		let mut sha = Sha256::engine();
-
-		let counterparty_payment_point = &self.counterparty_pubkeys.as_ref().unwrap().payment_point.serialize();
+		let counterparty_payment_point = &self.counterparty_pubkeys.as_ref().unwrap().payment_point;
 		if self.channel_outbound {
-			sha.input(&self.holder_keys.pubkeys().payment_point.serialize());
-			sha.input(counterparty_payment_point);
+			chan_utils::get_commitment_transaction_number_obscure_factor(&self.holder_keys.pubkeys().payment_point, counterparty_payment_point)
 		} else {
-			sha.input(counterparty_payment_point);
-			sha.input(&self.holder_keys.pubkeys().payment_point.serialize());
+			chan_utils::get_commitment_transaction_number_obscure_factor(counterparty_payment_point, &self.holder_keys.pubkeys().payment_point)
 		}
-		let res = Sha256::from_engine(sha).into_inner();
-
-		((res[26] as u64) << 5*8) |
-		((res[27] as u64) << 4*8) |
-		((res[28] as u64) << 3*8) |
-		((res[29] as u64) << 2*8) |
-		((res[30] as u64) << 1*8) |
-		((res[31] as u64) << 0*8)
 	}
 
 	/// Transaction nomenclature is somewhat confusing here as there are many different cases - a
@@ -1145,7 +1149,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// The result is a transaction which we can revoke broadcastership of (ie a "local" transaction)
 	/// TODO Some magic rust shit to compile-time check this?
 	fn build_holder_transaction_keys(&self, commitment_number: u64) -> Result<TxCreationKeys, ChannelError> {
-		let per_commitment_point = self.holder_keys.get_per_commitment_point(commitment_number, &self.secp_ctx);
+		let per_commitment_point = self.holder_keys.get_per_commitment_point(commitment_number, &self.secp_ctx)
+			.map_err(|_| ChannelError::Close("Failed to get our per-commitment point".to_owned()))?;
 		let delayed_payment_base = &self.holder_keys.pubkeys().delayed_payment_basepoint;
 		let htlc_basepoint = &self.holder_keys.pubkeys().htlc_basepoint;
 		let counterparty_pubkeys = self.counterparty_pubkeys.as_ref().unwrap();
@@ -1253,7 +1258,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 							return Ok((None, None));
 						}
 					},
-					&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } => {
+					&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } | &HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, .. } => {
 						if htlc_id_arg == htlc_id {
 							log_warn!(logger, "Have preimage and want to fulfill HTLC with pending failure against channel {}", log_bytes!(self.channel_id()));
 							// TODO: We may actually be able to switch to a fulfill here, though its
@@ -1309,13 +1314,9 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		}
 	}
 
-	/// Per HTLC, only one get_update_fail_htlc or get_update_fulfill_htlc call may be made.
-	/// In such cases we debug_assert!(false) and return a ChannelError::Ignore. Thus, will always
-	/// return Ok(_) if debug assertions are turned on or preconditions are met.
-	///
-	/// Note that it is still possible to hit these assertions in case we find a preimage on-chain
-	/// but then have a reorg which settles on an HTLC-failure on chain.
-	pub fn get_update_fail_htlc(&mut self, htlc_id_arg: u64, err_packet: msgs::OnionErrorPacket) -> Result<Option<msgs::UpdateFailHTLC>, ChannelError> {
+	/// Finds the inbound HTLC with the given id which we're about to fail, returning its index in
+	/// pending_inbound_htlcs, or None if it was already failed or fulfilled.
+	fn get_inbound_htlc_to_fail(&self, htlc_id_arg: u64) -> Result<Option<usize>, ChannelError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32)) != (ChannelState::ChannelFunded as u32) {
 			panic!("Was asked to fail an HTLC when channel was not in an operational state");
 		}
@@ -1346,25 +1347,41 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
 		}
 
+		for pending_update in self.holding_cell_htlc_updates.iter() {
+			match pending_update {
+				&HTLCUpdateAwaitingACK::ClaimHTLC { htlc_id, .. } => {
+					if htlc_id_arg == htlc_id {
+						debug_assert!(false, "Tried to fail an HTLC that was already fulfilled");
+						return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
+					}
+				},
+				&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } | &HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, .. } => {
+					if htlc_id_arg == htlc_id {
+						debug_assert!(false, "Tried to fail an HTLC that was already failed");
+						return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
+					}
+				},
+				_ => {}
+			}
+		}
+
+		Ok(Some(pending_idx))
+	}
+
+	/// Per HTLC, only one get_update_fail_htlc or get_update_fulfill_htlc call may be made.
+	/// In such cases we debug_assert!(false) and return a ChannelError::Ignore. Thus, will always
+	/// return Ok(_) if debug assertions are turned on or preconditions are met.
+	///
+	/// Note that it is still possible to hit these assertions in case we find a preimage on-chain
+	/// but then have a reorg which settles on an HTLC-failure on chain.
+	pub fn get_update_fail_htlc(&mut self, htlc_id_arg: u64, err_packet: msgs::OnionErrorPacket) -> Result<Option<msgs::UpdateFailHTLC>, ChannelError> {
+		let pending_idx = match self.get_inbound_htlc_to_fail(htlc_id_arg)? {
+			Some(idx) => idx,
+			None => return Ok(None),
+		};
+
 		// Now update local state:
 		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32)) != 0 {
-			for pending_update in self.holding_cell_htlc_updates.iter() {
-				match pending_update {
-					&HTLCUpdateAwaitingACK::ClaimHTLC { htlc_id, .. } => {
-						if htlc_id_arg == htlc_id {
-							debug_assert!(false, "Tried to fail an HTLC that was already fulfilled");
-							return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
-						}
-					},
-					&HTLCUpdateAwaitingACK::FailHTLC { htlc_id, .. } => {
-						if htlc_id_arg == htlc_id {
-							debug_assert!(false, "Tried to fail an HTLC that was already failed");
-							return Err(ChannelError::Ignore("Unable to find a pending HTLC which matched the given HTLC ID".to_owned()));
-						}
-					},
-					_ => {}
-				}
-			}
 			self.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::FailHTLC {
 				htlc_id: htlc_id_arg,
 				err_packet,
@@ -1384,6 +1401,37 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		}))
 	}
 
+	/// Fails an inbound HTLC with an update_fail_malformed_htlc rather than an onion error, as we
+	/// must when failing back an HTLC we received within a blinded route.
+	pub fn get_update_fail_malformed_htlc(&mut self, htlc_id_arg: u64, failure_code: u16, sha256_of_onion: [u8; 32]) -> Result<Option<msgs::UpdateFailMalformedHTLC>, ChannelError> {
+		let pending_idx = match self.get_inbound_htlc_to_fail(htlc_id_arg)? {
+			Some(idx) => idx,
+			None => return Ok(None),
+		};
+
+		// Now update local state:
+		if (self.channel_state & (ChannelState::AwaitingRemoteRevoke as u32 | ChannelState::PeerDisconnected as u32 | ChannelState::MonitorUpdateFailed as u32)) != 0 {
+			self.holding_cell_htlc_updates.push(HTLCUpdateAwaitingACK::FailMalformedHTLC {
+				htlc_id: htlc_id_arg,
+				failure_code,
+				sha256_of_onion,
+			});
+			return Ok(None);
+		}
+
+		{
+			let htlc = &mut self.pending_inbound_htlcs[pending_idx];
+			htlc.state = InboundHTLCState::LocalRemoved(InboundHTLCRemovalReason::FailMalformed((sha256_of_onion, failure_code)));
+		}
+
+		Ok(Some(msgs::UpdateFailMalformedHTLC {
+			channel_id: self.channel_id(),
+			htlc_id: htlc_id_arg,
+			sha256_of_onion,
+			failure_code,
+		}))
+	}
+
 	// Message handlers:
 
 	pub fn accept_channel(&mut self, msg: &msgs::AcceptChannel, config: &UserConfig, their_features: InitFeatures) -> Result<(), ChannelError> {
@@ -1452,7 +1500,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			match &msg.shutdown_scriptpubkey {
 				&OptionalField::Present(ref script) => {
 					// Peer is signaling upfront_shutdown and has provided a non-accepted scriptpubkey format. We enforce it while receiving shutdown msg
-					if script.is_p2pkh() || script.is_p2sh() || script.is_v0_p2wsh() || script.is_v0_p2wpkh() {
+					if is_valid_shutdown_script(&their_features, script) {
 						Some(script.clone())
 					// Peer is signaling upfront_shutdown and has opt-out with a 0-length script. We don't enforce anything
 					} else if script.len() == 0 {
@@ -1485,7 +1533,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			htlc_basepoint: msg.htlc_basepoint
 		};
 
-		self.holder_keys.on_accept(&counterparty_pubkeys, msg.to_self_delay, self.holder_selected_contest_delay);
+		self.holder_keys.on_accept(&counterparty_pubkeys, msg.to_self_delay, self.holder_selected_contest_delay)
+			.map_err(|_| ChannelError::Close("Our signer failed to accept the channel".to_owned()))?;
 		self.counterparty_pubkeys = Some(counterparty_pubkeys);
 
 		self.counterparty_cur_commitment_point = Some(msg.first_per_commitment_point);
@@ -2047,8 +2096,10 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			}
 		}
 
-		let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number - 1, &self.secp_ctx);
-		let per_commitment_secret = self.holder_keys.release_commitment_secret(self.cur_holder_commitment_transaction_number + 1);
+		let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number - 1, &self.secp_ctx)
+			.map_err(|_| (None, ChannelError::Close("Failed to get our per-commitment point".to_owned())))?;
+		let per_commitment_secret = self.holder_keys.release_commitment_secret(self.cur_holder_commitment_transaction_number + 1)
+			.map_err(|_| (None, ChannelError::Close("Failed to release our commitment secret".to_owned())))?;
 
 		// Update state now that we've passed all the can-fail calls...
 		let mut need_commitment = false;
@@ -2157,6 +2208,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			let mut update_add_htlcs = Vec::with_capacity(htlc_updates.len());
 			let mut update_fulfill_htlcs = Vec::with_capacity(htlc_updates.len());
 			let mut update_fail_htlcs = Vec::with_capacity(htlc_updates.len());
+			let mut update_fail_malformed_htlcs = Vec::new();
 			let mut htlcs_to_fail = Vec::new();
 			for htlc_update in htlc_updates.drain(..) {
 				// Note that this *can* fail, though it should be due to rather-rare conditions on
@@ -2165,8 +2217,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				// handling this case better and maybe fulfilling some of the HTLCs while attempting
 				// to rebalance channels.
 				match &htlc_update {
-					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, ..} => {
-						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone()) {
+					&HTLCUpdateAwaitingACK::AddHTLC {amount_msat, cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, blinding_point} => {
+						match self.send_htlc(amount_msat, *payment_hash, cltv_expiry, source.clone(), onion_routing_packet.clone(), blinding_point) {
 							Ok(update_add_msg_option) => update_add_htlcs.push(update_add_msg_option.unwrap()),
 							Err(e) => {
 								match e {
@@ -2214,9 +2266,20 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 							}
 						}
 					},
+					&HTLCUpdateAwaitingACK::FailMalformedHTLC { htlc_id, failure_code, sha256_of_onion } => {
+						match self.get_update_fail_malformed_htlc(htlc_id, failure_code, sha256_of_onion) {
+							Ok(update_fail_msg_option) => update_fail_malformed_htlcs.push(update_fail_msg_option.unwrap()),
+							Err(e) => {
+								if let ChannelError::Ignore(_) = e {}
+								else {
+									panic!("Got a non-IgnoreError action trying to fail holding cell HTLC");
+								}
+							}
+						}
+					},
 				}
 			}
-			if update_add_htlcs.is_empty() && update_fulfill_htlcs.is_empty() && update_fail_htlcs.is_empty() && self.holding_cell_update_fee.is_none() {
+			if update_add_htlcs.is_empty() && update_fulfill_htlcs.is_empty() && update_fail_htlcs.is_empty() && update_fail_malformed_htlcs.is_empty() && self.holding_cell_update_fee.is_none() {
 				return Ok((None, htlcs_to_fail));
 			}
 			let update_fee = if let Some(feerate) = self.holding_cell_update_fee {
@@ -2239,7 +2302,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				update_add_htlcs,
 				update_fulfill_htlcs,
 				update_fail_htlcs,
-				update_fail_malformed_htlcs: Vec::new(),
+				update_fail_malformed_htlcs,
 				update_fee,
 				commitment_signed,
 			}, monitor_update)), htlcs_to_fail))
@@ -2577,7 +2640,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 					outbound_drops.push((source.clone(), payment_hash.clone()));
 					false
 				},
-				&HTLCUpdateAwaitingACK::ClaimHTLC {..} | &HTLCUpdateAwaitingACK::FailHTLC {..} => true,
+				&HTLCUpdateAwaitingACK::ClaimHTLC {..} | &HTLCUpdateAwaitingACK::FailHTLC {..} | &HTLCUpdateAwaitingACK::FailMalformedHTLC {..} => true,
 			}
 		});
 		self.channel_state |= ChannelState::PeerDisconnected as u32;
@@ -2605,8 +2668,22 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// Indicates that the latest ChannelMonitor update has been committed by the client
 	/// successfully and we should restore normal operation. Returns messages which should be sent
 	/// to the remote side.
-	pub fn monitor_updating_restored<L: Deref>(&mut self, logger: &L) -> (Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>, RAACommitmentOrder, Vec<(PendingHTLCInfo, u64)>, Vec<(HTLCSource, PaymentHash, HTLCFailReason)>, bool, Option<msgs::FundingLocked>) where L::Target: Logger {
+	///
+	/// If our signer fails to provide the per-commitment data we need, a ChannelError::Ignore is
+	/// returned and the channel is left paused, so that this may be called again later.
+	pub fn monitor_updating_restored<L: Deref>(&mut self, logger: &L) -> Result<(Option<msgs::RevokeAndACK>, Option<msgs::CommitmentUpdate>, RAACommitmentOrder, Vec<(PendingHTLCInfo, u64)>, Vec<(HTLCSource, PaymentHash, HTLCFailReason)>, bool, Option<msgs::FundingLocked>), ChannelError> where L::Target: Logger {
 		assert_eq!(self.channel_state & ChannelState::MonitorUpdateFailed as u32, ChannelState::MonitorUpdateFailed as u32);
+
+		// Fetch everything we need from our signer before changing any state.
+		let funding_locked_point = if self.monitor_pending_funding_locked {
+			Some(self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)
+				.map_err(|_| ChannelError::Ignore("Failed to get our per-commitment point".to_owned()))?)
+		} else { None };
+		let raa = if self.monitor_pending_revoke_and_ack && self.channel_state & (ChannelState::PeerDisconnected as u32) == 0 {
+			Some(self.get_last_revoke_and_ack()
+				.map_err(|_| ChannelError::Ignore("Failed to get our revoke_and_ack from our signer".to_owned()))?)
+		} else { None };
+
 		self.channel_state &= !(ChannelState::MonitorUpdateFailed as u32);
 
 		let needs_broadcast_safe = self.channel_state & (ChannelState::FundingSent as u32) != 0 && self.channel_outbound;
@@ -2617,10 +2694,9 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		// monitor_pending_funding_locked when we're an inbound channel which failed to persist the
 		// monitor on funding_created, and we even got the funding transaction confirmed before the
 		// monitor was persisted.
-		let funding_locked = if self.monitor_pending_funding_locked {
+		let funding_locked = if let Some(next_per_commitment_point) = funding_locked_point {
 			assert!(!self.channel_outbound, "Funding transaction broadcast without FundingBroadcastSafe!");
 			self.monitor_pending_funding_locked = false;
-			let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
 			Some(msgs::FundingLocked {
 				channel_id: self.channel_id(),
 				next_per_commitment_point,
@@ -2635,12 +2711,9 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		if self.channel_state & (ChannelState::PeerDisconnected as u32) != 0 {
 			self.monitor_pending_revoke_and_ack = false;
 			self.monitor_pending_commitment_signed = false;
-			return (None, None, RAACommitmentOrder::RevokeAndACKFirst, forwards, failures, needs_broadcast_safe, funding_locked);
+			return Ok((None, None, RAACommitmentOrder::RevokeAndACKFirst, forwards, failures, needs_broadcast_safe, funding_locked));
 		}
 
-		let raa = if self.monitor_pending_revoke_and_ack {
-			Some(self.get_last_revoke_and_ack())
-		} else { None };
 		let commitment_update = if self.monitor_pending_commitment_signed {
 			Some(self.get_last_commitment_update(logger))
 		} else { None };
@@ -2653,7 +2726,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			if commitment_update.is_some() { "a" } else { "no" },
 			if raa.is_some() { "an" } else { "no" },
 			match order { RAACommitmentOrder::CommitmentFirst => "commitment", RAACommitmentOrder::RevokeAndACKFirst => "RAA"});
-		(raa, commitment_update, order, forwards, failures, needs_broadcast_safe, funding_locked)
+		Ok((raa, commitment_update, order, forwards, failures, needs_broadcast_safe, funding_locked))
 	}
 
 	pub fn update_fee<F: Deref>(&mut self, fee_estimator: &F, msg: &msgs::UpdateFee) -> Result<(), ChannelError>
@@ -2671,14 +2744,14 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		Ok(())
 	}
 
-	fn get_last_revoke_and_ack(&self) -> msgs::RevokeAndACK {
-		let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
-		let per_commitment_secret = self.holder_keys.release_commitment_secret(self.cur_holder_commitment_transaction_number + 2);
-		msgs::RevokeAndACK {
+	fn get_last_revoke_and_ack(&self) -> Result<msgs::RevokeAndACK, ()> {
+		let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)?;
+		let per_commitment_secret = self.holder_keys.release_commitment_secret(self.cur_holder_commitment_transaction_number + 2)?;
+		Ok(msgs::RevokeAndACK {
 			channel_id: self.channel_id,
 			per_commitment_secret,
 			next_per_commitment_point,
-		}
+		})
 	}
 
 	fn get_last_commitment_update<L: Deref>(&self, logger: &L) -> msgs::CommitmentUpdate where L::Target: Logger {
@@ -2696,6 +2769,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 					payment_hash: htlc.payment_hash,
 					cltv_expiry: htlc.cltv_expiry,
 					onion_routing_packet: (**onion_packet).clone(),
+					blinding_point: htlc.blinding_point,
 				});
 			}
 		}
@@ -2756,7 +2830,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		if msg.next_remote_commitment_number > 0 {
 			match msg.data_loss_protect {
 				OptionalField::Present(ref data_loss) => {
-					let expected_point = self.holder_keys.get_per_commitment_point(INITIAL_COMMITMENT_NUMBER - msg.next_remote_commitment_number + 1, &self.secp_ctx);
+					let expected_point = self.holder_keys.get_per_commitment_point(INITIAL_COMMITMENT_NUMBER - msg.next_remote_commitment_number + 1, &self.secp_ctx)
+						.map_err(|_| ChannelError::Close("Failed to get our per-commitment point".to_owned()))?;
 					let given_secret = SecretKey::from_slice(&data_loss.your_last_per_commitment_secret)
 						.map_err(|_| ChannelError::Close("Peer sent a garbage channel_reestablish with unparseable secret key".to_owned()))?;
 					if expected_point != PublicKey::from_secret_key(&self.secp_ctx, &given_secret) {
@@ -2795,7 +2870,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			}
 
 			// We have OurFundingLocked set!
-			let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
+			let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)
+				.map_err(|_| ChannelError::Close("Failed to get our per-commitment point".to_owned()))?;
 			return Ok((Some(msgs::FundingLocked {
 				channel_id: self.channel_id(),
 				next_per_commitment_point,
@@ -2811,7 +2887,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				self.monitor_pending_revoke_and_ack = true;
 				None
 			} else {
-				Some(self.get_last_revoke_and_ack())
+				Some(self.get_last_revoke_and_ack()
+					.map_err(|_| ChannelError::Close("Failed to get our revoke_and_ack from our signer".to_owned()))?)
 			}
 		} else {
 			return Err(ChannelError::Close("Peer attempted to reestablish channel with a very old local commitment transaction".to_owned()));
@@ -2825,7 +2902,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 
 		let resend_funding_locked = if msg.next_local_commitment_number == 1 && INITIAL_COMMITMENT_NUMBER - self.cur_holder_commitment_transaction_number == 1 {
 			// We should never have to worry about MonitorUpdateFailed resending FundingLocked
-			let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
+			let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)
+				.map_err(|_| ChannelError::Close("Failed to get our per-commitment point".to_owned()))?;
 			Some(msgs::FundingLocked {
 				channel_id: self.channel_id(),
 				next_per_commitment_point,
@@ -2920,7 +2998,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		})
 	}
 
-	pub fn shutdown<F: Deref>(&mut self, fee_estimator: &F, msg: &msgs::Shutdown) -> Result<(Option<msgs::Shutdown>, Option<msgs::ClosingSigned>, Vec<(HTLCSource, PaymentHash)>), ChannelError>
+	pub fn shutdown<F: Deref>(&mut self, fee_estimator: &F, their_features: &InitFeatures, msg: &msgs::Shutdown) -> Result<(Option<msgs::Shutdown>, Option<msgs::ClosingSigned>, Vec<(HTLCSource, PaymentHash)>), ChannelError>
 		where F::Target: FeeEstimator
 	{
 		if self.channel_state & (ChannelState::PeerDisconnected as u32) == ChannelState::PeerDisconnected as u32 {
@@ -2940,13 +3018,15 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		assert_eq!(self.channel_state & ChannelState::ShutdownComplete as u32, 0);
 
 		// BOLT 2 says we must only send a scriptpubkey of certain standard forms, which are up to
-		// 34 bytes in length, so don't let the remote peer feed us some super fee-heavy script.
-		if self.channel_outbound && msg.scriptpubkey.len() > 34 {
+		// 34 bytes in length (42 for future segwit versions), so don't let the remote peer feed us
+		// some super fee-heavy script.
+		let max_script_len = if their_features.supports_shutdown_anysegwit() { 42 } else { 34 };
+		if self.channel_outbound && msg.scriptpubkey.len() > max_script_len {
 			return Err(ChannelError::Close(format!("Got counterparty shutdown_scriptpubkey ({}) of absurd length from remote peer", msg.scriptpubkey.to_bytes().to_hex())));
 		}
 
 		//Check counterparty_shutdown_scriptpubkey form as BOLT says we must
-		if !msg.scriptpubkey.is_p2pkh() && !msg.scriptpubkey.is_p2sh() && !msg.scriptpubkey.is_v0_p2wpkh() && !msg.scriptpubkey.is_v0_p2wsh() {
+		if !is_valid_shutdown_script(their_features, &msg.scriptpubkey) {
 			return Err(ChannelError::Close(format!("Got a nonstandard scriptpubkey ({}) from remote peer", msg.scriptpubkey.to_bytes().to_hex())));
 		}
 
@@ -3167,7 +3247,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			// Upper bound by capacity. We make it a bit less than full capacity to prevent attempts
 			// to use full capacity. This is an effort to reduce routing failures, because in many cases
 			// channel might have been used to route very small values (either by honest users or as DoS).
-			self.channel_value_satoshis * 9 / 10,
+			self.channel_value_satoshis * 1000 * 9 / 10,
 
 			Channel::<ChanSigner>::get_holder_max_htlc_value_in_flight_msat(self.channel_value_satoshis)
 		);
@@ -3203,6 +3283,12 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		self.cur_counterparty_commitment_transaction_number + 2
 	}
 
+	/// Gets the parameters our channel keys were derived with, see
+	/// ChannelKeys::key_derivation_params.
+	pub fn get_key_derivation_params(&self) -> (u64, u64) {
+		self.holder_keys.key_derivation_params()
+	}
+
 	#[cfg(test)]
 	pub fn get_keys(&self) -> &ChanSigner {
 		&self.holder_keys
@@ -3332,6 +3418,94 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 		self.network_sync == UpdateStatus::DisabledMarked
 	}
 
+	/// Returns the number of confirmations of the funding transaction as of the block at `height`,
+	/// or 0 if it is not confirmed at that height.
+	fn get_funding_tx_confirmations(&self, height: u32) -> u32 {
+		if self.funding_tx_confirmation_height == 0 || height < self.funding_tx_confirmation_height {
+			0
+		} else {
+			height - self.funding_tx_confirmation_height + 1
+		}
+	}
+
+	/// Returns the height at which the funding transaction was confirmed, if it is confirmed.
+	pub fn get_funding_tx_confirmation_height(&self) -> Option<u32> {
+		if self.funding_tx_confirmation_height == 0 { None } else { Some(self.funding_tx_confirmation_height) }
+	}
+
+	/// Checks whether the funding transaction has reached minimum_depth as of `height` and, if we
+	/// haven't done so yet, moves the channel forward and returns the funding_locked to send.
+	fn check_get_funding_locked(&mut self, height: u32) -> Option<msgs::FundingLocked> {
+		if self.get_funding_tx_confirmations(height) < cmp::max(self.minimum_depth, 1) {
+			return None;
+		}
+
+		let non_shutdown_state = self.channel_state & (!MULTI_STATE_FLAGS);
+		// Get the point to send from our signer before moving the channel forward, so that if it
+		// fails we simply try again on the next block.
+		let next_per_commitment_point = if (non_shutdown_state == ChannelState::FundingSent as u32 ||
+				non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::TheirFundingLocked as u32)) &&
+				self.channel_state & (ChannelState::MonitorUpdateFailed as u32) == 0 {
+			match self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx) {
+				Ok(point) => Some(point),
+				Err(_) => return None,
+			}
+		} else { None };
+
+		let need_commitment_update = if non_shutdown_state == ChannelState::FundingSent as u32 {
+			self.channel_state |= ChannelState::OurFundingLocked as u32;
+			true
+		} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::TheirFundingLocked as u32) {
+			self.channel_state = ChannelState::ChannelFunded as u32 | (self.channel_state & MULTI_STATE_FLAGS);
+			self.update_time_counter += 1;
+			true
+		} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::OurFundingLocked as u32) {
+			// We got a reorg but not enough to trigger a force close, just return.
+			false
+		} else if self.channel_state < ChannelState::ChannelFunded as u32 {
+			panic!("Started confirming a channel in a state pre-FundingSent?: {}", self.channel_state);
+		} else {
+			// We got a reorg but not enough to trigger a force close, just return.
+			false
+		};
+
+		//TODO: Note that this must be a duplicate of the previous commitment point they sent us,
+		//as otherwise we will have a commitment transaction that they can't revoke (well, kinda,
+		//they can by sending two revoke_and_acks back-to-back, but not really). This appears to be
+		//a protocol oversight, but I assume I'm just missing something.
+		if need_commitment_update {
+			self.funding_tx_confirmed_in = Some(self.last_block_connected);
+			if let Some(next_per_commitment_point) = next_per_commitment_point {
+				return Some(msgs::FundingLocked {
+					channel_id: self.channel_id,
+					next_per_commitment_point,
+				});
+			} else {
+				self.monitor_pending_funding_locked = true;
+			}
+		}
+		None
+	}
+
+	/// Checks that, if we've already sent funding_locked, the funding transaction hasn't since
+	/// dipped below minimum_depth / 2 confirmations as of `height`. If it has, the channel must be
+	/// closed and we hope we can get the latest state on chain (because presumably the funding
+	/// transaction is at least still in the mempool of most nodes).
+	fn check_funding_unconfirmed(&self, height: u32) -> Result<(), msgs::ErrorMessage> {
+		let non_shutdown_state = self.channel_state & (!MULTI_STATE_FLAGS);
+		if non_shutdown_state >= ChannelState::ChannelFunded as u32 ||
+				(non_shutdown_state & ChannelState::OurFundingLocked as u32) == ChannelState::OurFundingLocked as u32 {
+			let funding_tx_confirmations = self.get_funding_tx_confirmations(height);
+			if funding_tx_confirmations < self.minimum_depth / 2 {
+				return Err(msgs::ErrorMessage {
+					channel_id: self.channel_id(),
+					data: format!("Funding transaction was un-confirmed. Locked at {} confs, now have {} confs.", self.minimum_depth, funding_tx_confirmations),
+				});
+			}
+		}
+		Ok(())
+	}
+
 	/// When we receive a new block, we (a) check whether the block contains the funding
 	/// transaction (which would start us counting blocks until we send the funding_signed), and
 	/// (b) check the height of the block against outbound holding cell HTLCs in case we need to
@@ -3347,24 +3521,21 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// May return some HTLCs (and their payment_hash) which have timed out and should be failed
 	/// back.
 	pub fn block_connected(&mut self, header: &BlockHeader, txdata: &TransactionData, height: u32) -> Result<(Option<msgs::FundingLocked>, Vec<(HTLCSource, PaymentHash)>), msgs::ErrorMessage> {
-		let mut timed_out_htlcs = Vec::new();
-		self.holding_cell_htlc_updates.retain(|htlc_update| {
-			match htlc_update {
-				&HTLCUpdateAwaitingACK::AddHTLC { ref payment_hash, ref source, ref cltv_expiry, .. } => {
-					if *cltv_expiry <= height + HTLC_FAIL_BACK_BUFFER {
-						timed_out_htlcs.push((source.clone(), payment_hash.clone()));
-						false
-					} else { true }
-				},
-				_ => true
-			}
-		});
+		let funding_locked = self.transactions_confirmed(txdata, height)?;
+		let timed_out_htlcs = self.best_block_changed(header, height);
+		// Note that we don't check for un-confirmation of the funding transaction here, as blocks
+		// being disconnected are signalled through block_disconnected.
+		Ok((funding_locked.or_else(|| self.check_get_funding_locked(height)), timed_out_htlcs))
+	}
+
+	/// Checks whether any of the given transactions, confirmed in the block at the given height,
+	/// is our funding transaction. If so, we record its confirmation height
+	/// and short channel id, and may return a funding_locked to send if it is already buried deep
+	/// enough (e.g. with a minimum_depth of 1).
+	///
+	/// If we return Err, the funding transaction was bogus and the channel has been closed.
+	pub fn transactions_confirmed(&mut self, txdata: &TransactionData, height: u32) -> Result<Option<msgs::FundingLocked>, msgs::ErrorMessage> {
 		let non_shutdown_state = self.channel_state & (!MULTI_STATE_FLAGS);
-		if header.block_hash() != self.last_block_connected {
-			if self.funding_tx_confirmations > 0 {
-				self.funding_tx_confirmations += 1;
-			}
-		}
 		if non_shutdown_state & !(ChannelState::TheirFundingLocked as u32) == ChannelState::FundingSent as u32 {
 			for &(index_in_block, tx) in txdata.iter() {
 				if tx.txid() == self.funding_txo.unwrap().txid {
@@ -3401,82 +3572,90 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 							panic!("Block was bogus - either height 16 million or had > 16 million transactions");
 						}
 						assert!(txo_idx <= 0xffff); // txo_idx is a (u16 as usize), so this is just listed here for completeness
-						self.funding_tx_confirmations = 1;
+						self.funding_tx_confirmation_height = height;
 						self.short_channel_id = Some(((height as u64)         << (5*8)) |
 						                             ((index_in_block as u64) << (2*8)) |
 						                             ((txo_idx as u64)        << (0*8)));
 					}
 				}
 			}
-		}
-		if header.block_hash() != self.last_block_connected {
-			self.last_block_connected = header.block_hash();
-			self.update_time_counter = cmp::max(self.update_time_counter, header.time);
-			if self.funding_tx_confirmations > 0 {
-				if self.funding_tx_confirmations == self.minimum_depth as u64 {
-					let need_commitment_update = if non_shutdown_state == ChannelState::FundingSent as u32 {
-						self.channel_state |= ChannelState::OurFundingLocked as u32;
-						true
-					} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::TheirFundingLocked as u32) {
-						self.channel_state = ChannelState::ChannelFunded as u32 | (self.channel_state & MULTI_STATE_FLAGS);
-						self.update_time_counter += 1;
-						true
-					} else if non_shutdown_state == (ChannelState::FundingSent as u32 | ChannelState::OurFundingLocked as u32) {
-						// We got a reorg but not enough to trigger a force close, just update
-						// funding_tx_confirmed_in and return.
-						false
-					} else if self.channel_state < ChannelState::ChannelFunded as u32 {
-						panic!("Started confirming a channel in a state pre-FundingSent?: {}", self.channel_state);
-					} else {
-						// We got a reorg but not enough to trigger a force close, just update
-						// funding_tx_confirmed_in and return.
-						false
-					};
-					self.funding_tx_confirmed_in = Some(self.last_block_connected);
-
-					//TODO: Note that this must be a duplicate of the previous commitment point they sent us,
-					//as otherwise we will have a commitment transaction that they can't revoke (well, kinda,
-					//they can by sending two revoke_and_acks back-to-back, but not really). This appears to be
-					//a protocol oversight, but I assume I'm just missing something.
-					if need_commitment_update {
-						if self.channel_state & (ChannelState::MonitorUpdateFailed as u32) == 0 {
-							let next_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
-							return Ok((Some(msgs::FundingLocked {
-								channel_id: self.channel_id,
-								next_per_commitment_point,
-							}), timed_out_htlcs));
-						} else {
-							self.monitor_pending_funding_locked = true;
-							return Ok((None, timed_out_htlcs));
-						}
-					}
-				}
+			if self.funding_tx_confirmation_height == height {
+				return Ok(self.check_get_funding_locked(height));
 			}
 		}
+		Ok(None)
+	}
+
+	/// When the best block changes, we check the height against outbound holding cell HTLCs in
+	/// case we need to give up on them prematurely and time them out, and check whether the
+	/// funding transaction has reached (or, after a reorg, fallen below) the required depth.
+	///
+	/// If we return Err, the funding transaction was un-confirmed and the channel must be
+	/// force-closed.
+	///
+	/// May return some HTLCs (and their payment_hash) which have timed out and should be failed
+	/// back.
+	pub fn update_best_block(&mut self, header: &BlockHeader, height: u32) -> Result<(Option<msgs::FundingLocked>, Vec<(HTLCSource, PaymentHash)>), msgs::ErrorMessage> {
+		let timed_out_htlcs = self.best_block_changed(header, height);
+
+		if height < self.funding_tx_confirmation_height {
+			// The block containing our funding transaction was disconnected.
+			self.funding_tx_confirmation_height = 0;
+		}
+		if let Some(funding_locked) = self.check_get_funding_locked(height) {
+			return Ok((Some(funding_locked), timed_out_htlcs));
+		}
+		self.check_funding_unconfirmed(height)?;
 		Ok((None, timed_out_htlcs))
 	}
 
-	/// Called by channelmanager based on chain blocks being disconnected.
+	/// Times out holding cell HTLCs too close to expiry as of the new best block at `height` and
+	/// records the block, returning the HTLCs to fail back.
+	fn best_block_changed(&mut self, header: &BlockHeader, height: u32) -> Vec<(HTLCSource, PaymentHash)> {
+		let mut timed_out_htlcs = Vec::new();
+		self.holding_cell_htlc_updates.retain(|htlc_update| {
+			match htlc_update {
+				&HTLCUpdateAwaitingACK::AddHTLC { ref payment_hash, ref source, ref cltv_expiry, .. } => {
+					if *cltv_expiry <= height + HTLC_FAIL_BACK_BUFFER {
+						timed_out_htlcs.push((source.clone(), payment_hash.clone()));
+						false
+					} else { true }
+				},
+				_ => true
+			}
+		});
+
+		self.last_block_connected = header.block_hash();
+		self.update_time_counter = cmp::max(self.update_time_counter, header.time);
+		timed_out_htlcs
+	}
+
+	/// Called by channelmanager when the funding transaction is reported as reorged out of the
+	/// chain by a client which is notified of transactions rather than blocks.
+	///
+	/// If we return Err, we had already considered the funding transaction locked in and the
+	/// channel must be force-closed.
+	pub fn funding_transaction_unconfirmed(&mut self) -> Result<(), msgs::ErrorMessage> {
+		self.funding_tx_confirmation_height = 0;
+		self.check_funding_unconfirmed(0)
+	}
+
+	/// Called by channelmanager based on chain blocks being disconnected, with `new_height` being
+	/// the height of the new best block.
 	/// Returns true if we need to close the channel now due to funding transaction
 	/// unconfirmation/reorg.
-	pub fn block_disconnected(&mut self, header: &BlockHeader) -> bool {
-		if self.funding_tx_confirmations > 0 {
-			self.funding_tx_confirmations -= 1;
-			if self.funding_tx_confirmations == UNCONF_THRESHOLD as u64 {
-				return true;
-			}
-		}
+	pub fn block_disconnected(&mut self, header: &BlockHeader, new_height: u32) -> bool {
 		self.last_block_connected = header.block_hash();
-		if Some(self.last_block_connected) == self.funding_tx_confirmed_in {
-			self.funding_tx_confirmations = self.minimum_depth as u64 - 1;
+		if new_height < self.funding_tx_confirmation_height {
+			self.funding_tx_confirmation_height = 0;
 		}
-		false
+		self.check_funding_unconfirmed(new_height).is_err()
 	}
 
 	// Methods to get unprompted messages to send to the remote end (or where we already returned
 	// something in the handler for the message that prompted this message):
 
-	pub fn get_open_channel(&self, chain_hash: BlockHash) -> msgs::OpenChannel {
+	pub fn get_open_channel(&self, chain_hash: BlockHash) -> Result<msgs::OpenChannel, APIError> {
 		if !self.channel_outbound {
 			panic!("Tried to open a channel for an inbound channel?");
 		}
@@ -3488,10 +3667,11 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			panic!("Tried to send an open_channel for a channel that has already advanced");
 		}
 
-		let first_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
+		let first_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)
+			.map_err(|_| APIError::ChannelUnavailable { err: "Failed to get our first per-commitment point".to_owned() })?;
 		let keys = self.holder_keys.pubkeys();
 
-		msgs::OpenChannel {
+		Ok(msgs::OpenChannel {
 			chain_hash,
 			temporary_channel_id: self.channel_id,
 			funding_satoshis: self.channel_value_satoshis,
@@ -3511,10 +3691,10 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			first_per_commitment_point,
 			channel_flags: if self.config.announced_channel {1} else {0},
 			shutdown_scriptpubkey: OptionalField::Present(if self.config.commit_upfront_shutdown_pubkey { self.get_closing_scriptpubkey() } else { Builder::new().into_script() })
-		}
+		})
 	}
 
-	pub fn get_accept_channel(&self) -> msgs::AcceptChannel {
+	pub fn get_accept_channel(&self) -> Result<msgs::AcceptChannel, ChannelError> {
 		if self.channel_outbound {
 			panic!("Tried to send accept_channel for an outbound channel?");
 		}
@@ -3525,10 +3705,11 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			panic!("Tried to send an accept_channel for a channel that has already advanced");
 		}
 
-		let first_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx);
+		let first_per_commitment_point = self.holder_keys.get_per_commitment_point(self.cur_holder_commitment_transaction_number, &self.secp_ctx)
+			.map_err(|_| ChannelError::Close("Failed to get our first per-commitment point".to_owned()))?;
 		let keys = self.holder_keys.pubkeys();
 
-		msgs::AcceptChannel {
+		Ok(msgs::AcceptChannel {
 			temporary_channel_id: self.channel_id,
 			dust_limit_satoshis: self.holder_dust_limit_satoshis,
 			max_htlc_value_in_flight_msat: Channel::<ChanSigner>::get_holder_max_htlc_value_in_flight_msat(self.channel_value_satoshis),
@@ -3544,7 +3725,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			htlc_basepoint: keys.htlc_basepoint,
 			first_per_commitment_point,
 			shutdown_scriptpubkey: OptionalField::Present(if self.config.commit_upfront_shutdown_pubkey { self.get_closing_scriptpubkey() } else { Builder::new().into_script() })
-		}
+		})
 	}
 
 	/// If an Err is returned, it is a ChannelError::Close (for get_outbound_funding_created)
@@ -3700,7 +3881,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// HTLCs on the wire or we wouldn't be able to determine what they actually ACK'ed.
 	/// You MUST call send_commitment prior to any other calls on this Channel
 	/// If an Err is returned, it's a ChannelError::Ignore!
-	pub fn send_htlc(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> {
+	pub fn send_htlc(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, blinding_point: Option<PublicKey>) -> Result<Option<msgs::UpdateAddHTLC>, ChannelError> {
 		if (self.channel_state & (ChannelState::ChannelFunded as u32 | BOTH_SIDES_SHUTDOWN_MASK)) != (ChannelState::ChannelFunded as u32) {
 			return Err(ChannelError::Ignore("Cannot send HTLC until channel is fully established and we haven't started shutting down".to_owned()));
 		}
@@ -3777,6 +3958,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 				cltv_expiry,
 				source,
 				onion_routing_packet,
+				blinding_point,
 			});
 			return Ok(None);
 		}
@@ -3788,6 +3970,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			cltv_expiry,
 			state: OutboundHTLCState::LocalAnnounced(Box::new(onion_routing_packet.clone())),
 			source,
+			blinding_point,
 		});
 
 		let res = msgs::UpdateAddHTLC {
@@ -3797,6 +3980,7 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point,
 		};
 		self.next_holder_htlc_id += 1;
 
@@ -3936,8 +4120,8 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	/// to send to the remote peer in one go.
 	/// Shorthand for calling send_htlc() followed by send_commitment(), see docs on those for
 	/// more info.
-	pub fn send_htlc_and_commit<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, logger: &L) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, ChannelError> where L::Target: Logger {
-		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet)? {
+	pub fn send_htlc_and_commit<L: Deref>(&mut self, amount_msat: u64, payment_hash: PaymentHash, cltv_expiry: u32, source: HTLCSource, onion_routing_packet: msgs::OnionPacket, blinding_point: Option<PublicKey>, logger: &L) -> Result<Option<(msgs::UpdateAddHTLC, msgs::CommitmentSigned, ChannelMonitorUpdate)>, ChannelError> where L::Target: Logger {
+		match self.send_htlc(amount_msat, payment_hash, cltv_expiry, source, onion_routing_packet, blinding_point)? {
 			Some(update_add_htlc) => {
 				let (commitment_signed, monitor_update) = self.send_commitment_no_status_check(logger)?;
 				Ok(Some((update_add_htlc, commitment_signed, monitor_update)))
@@ -4033,8 +4217,10 @@ impl<ChanSigner: ChannelKeys> Channel<ChanSigner> {
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+// Version 2 records the height at which the funding transaction was confirmed instead of its
+// number of confirmations and the blinding points of outbound HTLCs.
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for InboundHTLCRemovalReason {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
@@ -4136,6 +4322,7 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 			htlc.cltv_expiry.write(writer)?;
 			htlc.payment_hash.write(writer)?;
 			htlc.source.write(writer)?;
+			htlc.blinding_point.write(writer)?;
 			match &htlc.state {
 				&OutboundHTLCState::LocalAnnounced(ref onion_packet) => {
 					0u8.write(writer)?;
@@ -4162,13 +4349,14 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 		(self.holding_cell_htlc_updates.len() as u64).write(writer)?;
 		for update in self.holding_cell_htlc_updates.iter() {
 			match update {
-				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet } => {
+				&HTLCUpdateAwaitingACK::AddHTLC { ref amount_msat, ref cltv_expiry, ref payment_hash, ref source, ref onion_routing_packet, ref blinding_point } => {
 					0u8.write(writer)?;
 					amount_msat.write(writer)?;
 					cltv_expiry.write(writer)?;
 					payment_hash.write(writer)?;
 					source.write(writer)?;
 					onion_routing_packet.write(writer)?;
+					blinding_point.write(writer)?;
 				},
 				&HTLCUpdateAwaitingACK::ClaimHTLC { ref payment_preimage, ref htlc_id } => {
 					1u8.write(writer)?;
@@ -4179,6 +4367,12 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 					2u8.write(writer)?;
 					htlc_id.write(writer)?;
 					err_packet.write(writer)?;
+				},
+				&HTLCUpdateAwaitingACK::FailMalformedHTLC { ref htlc_id, ref failure_code, ref sha256_of_onion } => {
+					3u8.write(writer)?;
+					htlc_id.write(writer)?;
+					failure_code.write(writer)?;
+					sha256_of_onion.write(writer)?;
 				}
 			}
 		}
@@ -4228,7 +4422,7 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 		self.short_channel_id.write(writer)?;
 
 		self.last_block_connected.write(writer)?;
-		self.funding_tx_confirmations.write(writer)?;
+		self.funding_tx_confirmation_height.write(writer)?;
 
 		self.counterparty_dust_limit_satoshis.write(writer)?;
 		self.holder_dust_limit_satoshis.write(writer)?;
@@ -4256,7 +4450,7 @@ impl<ChanSigner: ChannelKeys + Writeable> Writeable for Channel<ChanSigner> {
 
 impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 	fn read<R : ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -4307,6 +4501,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 				cltv_expiry: Readable::read(reader)?,
 				payment_hash: Readable::read(reader)?,
 				source: Readable::read(reader)?,
+				blinding_point: if ver >= 2 { Readable::read(reader)? } else { None },
 				state: match <u8 as Readable>::read(reader)? {
 					0 => OutboundHTLCState::LocalAnnounced(Box::new(Readable::read(reader)?)),
 					1 => OutboundHTLCState::Committed,
@@ -4328,6 +4523,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 					payment_hash: Readable::read(reader)?,
 					source: Readable::read(reader)?,
 					onion_routing_packet: Readable::read(reader)?,
+					blinding_point: if ver >= 2 { Readable::read(reader)? } else { None },
 				},
 				1 => HTLCUpdateAwaitingACK::ClaimHTLC {
 					payment_preimage: Readable::read(reader)?,
@@ -4337,6 +4533,11 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 					htlc_id: Readable::read(reader)?,
 					err_packet: Readable::read(reader)?,
 				},
+				3 => HTLCUpdateAwaitingACK::FailMalformedHTLC {
+					htlc_id: Readable::read(reader)?,
+					failure_code: Readable::read(reader)?,
+					sha256_of_onion: Readable::read(reader)?,
+				},
 				_ => return Err(DecodeError::InvalidValue),
 			});
 		}
@@ -4379,10 +4580,19 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 
 		let funding_txo = Readable::read(reader)?;
 		let funding_tx_confirmed_in = Readable::read(reader)?;
-		let short_channel_id = Readable::read(reader)?;
+		let short_channel_id: Option<u64> = Readable::read(reader)?;
 
 		let last_block_connected = Readable::read(reader)?;
-		let funding_tx_confirmations = Readable::read(reader)?;
+		let funding_tx_confirmation_height = if ver >= 2 {
+			Readable::read(reader)?
+		} else {
+			// The funding transaction's height is in the short channel id, set once it confirmed.
+			let funding_tx_confirmations: u64 = Readable::read(reader)?;
+			match short_channel_id {
+				Some(scid) if funding_tx_confirmations > 0 => (scid >> 5*8) as u32,
+				_ => 0,
+			}
+		};
 
 		let counterparty_dust_limit_satoshis = Readable::read(reader)?;
 		let holder_dust_limit_satoshis = Readable::read(reader)?;
@@ -4454,7 +4664,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for Channel<ChanSigner> {
 			funding_tx_confirmed_in,
 			short_channel_id,
 			last_block_connected,
-			funding_tx_confirmations,
+			funding_tx_confirmation_height,
 
 			counterparty_dust_limit_satoshis,
 			holder_dust_limit_satoshis,
@@ -4549,8 +4759,8 @@ mod tests {
 			PublicKey::from_secret_key(&secp_ctx, &channel_close_key)
 		}
 
-		fn get_channel_keys(&self, _inbound: bool, _channel_value_satoshis: u64) -> InMemoryChannelKeys {
-			self.chan_keys.clone()
+		fn get_channel_keys(&self, _inbound: bool, _channel_value_satoshis: u64) -> Result<InMemoryChannelKeys, ()> {
+			Ok(self.chan_keys.clone())
 		}
 		fn get_secure_random_bytes(&self) -> [u8; 32] { [0; 32] }
 	}
@@ -4577,7 +4787,7 @@ mod tests {
 		// Now change the fee so we can check that the fee in the open_channel message is the
 		// same as the old fee.
 		fee_est.fee_est = 500;
-		let open_channel_msg = node_a_chan.get_open_channel(genesis_block(network).header.block_hash());
+		let open_channel_msg = node_a_chan.get_open_channel(genesis_block(network).header.block_hash()).unwrap();
 		assert_eq!(open_channel_msg.feerate_per_kw, original_fee);
 	}
 
@@ -4598,12 +4808,12 @@ mod tests {
 		let mut node_a_chan = Channel::<EnforcingChannelKeys>::new_outbound(&&feeest, &&keys_provider, node_b_node_id, 10000000, 100000, 42, &config).unwrap();
 
 		// Create Node B's channel by receiving Node A's open_channel message
-		let open_channel_msg = node_a_chan.get_open_channel(genesis_block(network).header.block_hash());
+		let open_channel_msg = node_a_chan.get_open_channel(genesis_block(network).header.block_hash()).unwrap();
 		let node_b_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[7; 32]).unwrap());
 		let mut node_b_chan = Channel::<EnforcingChannelKeys>::new_from_req(&&feeest, &&keys_provider, node_b_node_id, InitFeatures::known(), &open_channel_msg, 7, &config).unwrap();
 
 		// Node B --> Node A: accept channel
-		let accept_channel_msg = node_b_chan.get_accept_channel();
+		let accept_channel_msg = node_b_chan.get_accept_channel().unwrap();
 		node_a_chan.accept_channel(&accept_channel_msg, &config, InitFeatures::known()).unwrap();
 
 		// Node A --> Node B: funding created
@@ -4687,7 +4897,7 @@ mod tests {
 			delayed_payment_basepoint: public_from_secret_hex(&secp_ctx, "1552dfba4f6cf29a62a0af13c8d6981d36d0ef8d61ba10fb0fe90da7634d7e13"),
 			htlc_basepoint: public_from_secret_hex(&secp_ctx, "4444444444444444444444444444444444444444444444444444444444444444")
 		};
-		chan_keys.on_accept(&counterparty_pubkeys, chan.counterparty_selected_contest_delay, chan.holder_selected_contest_delay);
+		chan_keys.on_accept(&counterparty_pubkeys, chan.counterparty_selected_contest_delay, chan.holder_selected_contest_delay).unwrap();
 
 		assert_eq!(counterparty_pubkeys.payment_point.serialize()[..],
 		           hex::decode("032c0b7cf95324a07d05398b240174dc0c2be444d96b159aa6c7f7b1e668680991").unwrap()[..]);
@@ -4821,6 +5031,7 @@ mod tests {
 				payment_hash: PaymentHash([0; 32]),
 				state: OutboundHTLCState::Committed,
 				source: HTLCSource::dummy(),
+				blinding_point: None,
 			};
 			out.payment_hash.0 = Sha256::hash(&hex::decode("0202020202020202020202020202020202020202020202020202020202020202").unwrap()).into_inner();
 			out
@@ -4833,6 +5044,7 @@ mod tests {
 				payment_hash: PaymentHash([0; 32]),
 				state: OutboundHTLCState::Committed,
 				source: HTLCSource::dummy(),
+				blinding_point: None,
 			};
 			out.payment_hash.0 = Sha256::hash(&hex::decode("0303030303030303030303030303030303030303030303030303030303030303").unwrap()).into_inner();
 			out
@@ -0,0 +1,372 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Static channel backups and the recovery flow which uses them to get our funds back after all
+//! [`ChannelMonitor`]s have been lost.
+//!
+//! A [`StaticChannelBackup`], exported with [`ChannelManager::get_static_channel_backup`], only
+//! changes when channels are opened or closed and holds just enough information to find our
+//! counterparties and re-derive our channel keys. It is *not* a replacement for persisting
+//! [`ChannelMonitor`]s: it cannot be used to continue operating the channels, only to ask our
+//! counterparties to close them and claim our balance from their commitment transactions.
+//!
+//! To recover, build a [`ChannelBackupRecovery`] from the backup and a [`KeysManager`] using the
+//! same seed as the one which opened the channels, and use it as the channel message handler of a
+//! [`PeerManager`] in place of a [`ChannelManager`]. Connect to the peers returned by
+//! [`ChannelBackupRecovery::get_peers_to_connect`]: on connection, we send each of them a
+//! `channel_reestablish` claiming a stale state, using `option_data_loss_protect` semantics, which
+//! leads them to broadcast their latest commitment transaction, followed by an `error` to ensure
+//! they do so even if they don't consider the state stale. Once their commitment transaction
+//! confirms, as reported by [`ChannelBackupRecovery::block_connected`], our `to_remote` output is
+//! handed to the user in an [`Event::SpendableOutputs`] as a
+//! [`SpendableOutputDescriptor::StaticOutputCounterpartyPayment`].
+//!
+//! Any HTLCs which were pending at the time of the close are lost.
+//!
+//! [`ChannelMonitor`]: ../../chain/channelmonitor/struct.ChannelMonitor.html
+//! [`StaticChannelBackup`]: struct.StaticChannelBackup.html
+//! [`ChannelManager::get_static_channel_backup`]: ../channelmanager/struct.ChannelManager.html#method.get_static_channel_backup
+//! [`ChannelBackupRecovery`]: struct.ChannelBackupRecovery.html
+//! [`KeysManager`]: ../../chain/keysinterface/struct.KeysManager.html
+//! [`PeerManager`]: ../peers/handler/struct.PeerManager.html
+//! [`ChannelManager`]: ../channelmanager/struct.ChannelManager.html
+//! [`ChannelBackupRecovery::get_peers_to_connect`]: struct.ChannelBackupRecovery.html#method.get_peers_to_connect
+//! [`ChannelBackupRecovery::block_connected`]: struct.ChannelBackupRecovery.html#method.block_connected
+//! [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+//! [`SpendableOutputDescriptor::StaticOutputCounterpartyPayment`]: ../../chain/keysinterface/enum.SpendableOutputDescriptor.html#variant.StaticOutputCounterpartyPayment
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::{Script, Builder};
+use bitcoin::blockdata::opcodes;
+use bitcoin::hash_types::WPubkeyHash;
+use bitcoin::hashes::Hash;
+
+use bitcoin::secp256k1::key::PublicKey;
+use bitcoin::secp256k1::Secp256k1;
+
+use chain::channelmonitor::ANTI_REORG_DELAY;
+use chain::keysinterface::{ChannelKeys, KeysManager, SpendableOutputDescriptor};
+use chain::transaction::{OutPoint, TransactionData};
+use ln::features::InitFeatures;
+use ln::msgs;
+use ln::msgs::{ChannelMessageHandler, DataLossProtect, DecodeError, ErrorAction, NetAddress, OptionalField};
+use util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
+use util::logger::Logger;
+use util::ser::{Readable, Writeable, Writer};
+
+use std::cmp;
+use std::io::Error;
+use std::ops::Deref;
+use std::sync::Mutex;
+
+/// The commitment number we claim to be at when asking a counterparty to close a channel.
+/// Commitment 0 is the first commitment transaction, which is always stale once a payment has
+/// been made over the channel.
+const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
+
+const MAX_ALLOC_SIZE: u64 = 64*1024;
+
+/// The information required to recover our funds in a single channel.
+#[derive(Clone, PartialEq)]
+pub struct ChannelBackup {
+	/// The channel's ID, which is derived from its funding outpoint.
+	pub channel_id: [u8; 32],
+	/// The node_id of our counterparty.
+	pub counterparty_node_id: PublicKey,
+	/// The addresses our counterparty could be reached at when the backup was taken.
+	pub counterparty_addresses: Vec<NetAddress>,
+	/// The funding transaction output of the channel.
+	pub funding_txo: OutPoint,
+	/// The value, in satoshis, of the funding output.
+	pub channel_value_satoshis: u64,
+	/// The parameters to pass to [`KeysManager::derive_channel_keys`] to re-derive our keys for the
+	/// channel.
+	///
+	/// [`KeysManager::derive_channel_keys`]: ../../chain/keysinterface/struct.KeysManager.html#method.derive_channel_keys
+	pub key_derivation_params: (u64, u64),
+}
+
+impl Writeable for ChannelBackup {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		self.channel_id.write(writer)?;
+		self.counterparty_node_id.write(writer)?;
+		(self.counterparty_addresses.len() as u64).write(writer)?;
+		for address in self.counterparty_addresses.iter() {
+			address.write(writer)?;
+		}
+		self.funding_txo.write(writer)?;
+		self.channel_value_satoshis.write(writer)?;
+		self.key_derivation_params.0.write(writer)?;
+		self.key_derivation_params.1.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for ChannelBackup {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let channel_id = Readable::read(reader)?;
+		let counterparty_node_id = Readable::read(reader)?;
+		let addresses_count: u64 = Readable::read(reader)?;
+		let mut counterparty_addresses = Vec::with_capacity(cmp::min(addresses_count, MAX_ALLOC_SIZE / 40) as usize);
+		for _ in 0..addresses_count {
+			match Readable::read(reader)? {
+				Ok(address) => counterparty_addresses.push(address),
+				Err(_) => return Err(DecodeError::InvalidValue),
+			}
+		}
+		let funding_txo = Readable::read(reader)?;
+		let channel_value_satoshis = Readable::read(reader)?;
+		let key_derivation_params = (Readable::read(reader)?, Readable::read(reader)?);
+		Ok(ChannelBackup {
+			channel_id,
+			counterparty_node_id,
+			counterparty_addresses,
+			funding_txo,
+			channel_value_satoshis,
+			key_derivation_params,
+		})
+	}
+}
+
+/// A backup of all of a ChannelManager's funded channels, see the [module-level
+/// documentation](index.html) for how it is used.
+#[derive(Clone, PartialEq)]
+pub struct StaticChannelBackup {
+	/// The backed up channels.
+	pub channels: Vec<ChannelBackup>,
+}
+
+impl Writeable for StaticChannelBackup {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		(self.channels.len() as u64).write(writer)?;
+		for channel in self.channels.iter() {
+			channel.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for StaticChannelBackup {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		let channels_count: u64 = Readable::read(reader)?;
+		let mut channels = Vec::with_capacity(cmp::min(channels_count, MAX_ALLOC_SIZE / 128) as usize);
+		for _ in 0..channels_count {
+			channels.push(Readable::read(reader)?);
+		}
+		Ok(StaticChannelBackup { channels })
+	}
+}
+
+struct RecoveringChannel {
+	backup: ChannelBackup,
+	/// The script of our to_remote output in our counterparty's commitment transactions.
+	payment_script: Script,
+	/// The height at which a transaction spending the funding output confirmed, if any.
+	closing_height: Option<u32>,
+}
+
+struct RecoveryState {
+	channels: Vec<RecoveringChannel>,
+	/// Outputs found in confirmed closing transactions, with the height they confirmed at, which
+	/// are handed to the user once they are ANTI_REORG_DELAY blocks deep.
+	maturing_outputs: Vec<(u32, SpendableOutputDescriptor)>,
+}
+
+/// Recovers our funds from the channels in a [`StaticChannelBackup`] by asking our counterparties
+/// to force-close them, see the [module-level documentation](index.html) for the full flow.
+///
+/// The recovery state is not persisted. If restarted before all channels are closed and swept, a
+/// new `ChannelBackupRecovery` should be created from the same backup and fed all blocks since the
+/// funding transactions confirmed.
+///
+/// [`StaticChannelBackup`]: struct.StaticChannelBackup.html
+pub struct ChannelBackupRecovery<K: Deref<Target = KeysManager>, L: Deref> where L::Target: Logger {
+	keys_manager: K,
+	state: Mutex<RecoveryState>,
+	pending_msg_events: Mutex<Vec<MessageSendEvent>>,
+	pending_events: Mutex<Vec<Event>>,
+	logger: L,
+}
+
+impl<K: Deref<Target = KeysManager>, L: Deref> ChannelBackupRecovery<K, L> where L::Target: Logger {
+	/// Constructs a new ChannelBackupRecovery for the channels in `backup`. `keys_manager` must use
+	/// the same seed as the KeysManager which opened the channels.
+	pub fn new(backup: StaticChannelBackup, keys_manager: K, logger: L) -> Self {
+		let channels = backup.channels.into_iter().map(|backup| {
+			let keys = keys_manager.derive_channel_keys(backup.channel_value_satoshis, backup.key_derivation_params.0, backup.key_derivation_params.1);
+			let payment_hash160 = WPubkeyHash::hash(&keys.pubkeys().payment_point.serialize());
+			let payment_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&payment_hash160[..]).into_script();
+			RecoveringChannel { backup, payment_script, closing_height: None }
+		}).collect();
+		ChannelBackupRecovery {
+			keys_manager,
+			state: Mutex::new(RecoveryState { channels, maturing_outputs: Vec::new() }),
+			pending_msg_events: Mutex::new(Vec::new()),
+			pending_events: Mutex::new(Vec::new()),
+			logger,
+		}
+	}
+
+	/// Gets the counterparties, and their backed-up addresses, which still have to be connected to
+	/// in order to have them close our channels.
+	pub fn get_peers_to_connect(&self) -> Vec<(PublicKey, Vec<NetAddress>)> {
+		let state = self.state.lock().unwrap();
+		let mut peers: Vec<(PublicKey, Vec<NetAddress>)> = Vec::new();
+		for channel in state.channels.iter().filter(|channel| channel.closing_height.is_none()) {
+			if !peers.iter().any(|&(ref node_id, _)| *node_id == channel.backup.counterparty_node_id) {
+				peers.push((channel.backup.counterparty_node_id, channel.backup.counterparty_addresses.clone()));
+			}
+		}
+		peers
+	}
+
+	/// Gets the ids of the channels which haven't been closed on-chain yet.
+	pub fn get_unclosed_channels(&self) -> Vec<[u8; 32]> {
+		self.state.lock().unwrap().channels.iter()
+			.filter(|channel| channel.closing_height.is_none())
+			.map(|channel| channel.backup.channel_id)
+			.collect()
+	}
+
+	/// Scans a newly connected block for our counterparties' commitment transactions, generating
+	/// an [`Event::SpendableOutputs`] for our output in each of them once they are
+	/// [`ANTI_REORG_DELAY`] blocks deep.
+	///
+	/// [`Event::SpendableOutputs`]: ../../util/events/enum.Event.html#variant.SpendableOutputs
+	/// [`ANTI_REORG_DELAY`]: ../../chain/channelmonitor/constant.ANTI_REORG_DELAY.html
+	pub fn block_connected(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let mut state = self.state.lock().unwrap();
+		let state = &mut *state;
+		for &(_, tx) in txdata.iter() {
+			for channel in state.channels.iter_mut().filter(|channel| channel.closing_height.is_none()) {
+				let funding_txo = channel.backup.funding_txo;
+				if !tx.input.iter().any(|input| input.previous_output == funding_txo.into_bitcoin_outpoint()) { continue; }
+				log_info!(self.logger, "Channel {} was closed on-chain by transaction {}", log_bytes!(channel.backup.channel_id), tx.txid());
+				channel.closing_height = Some(height);
+				for (idx, output) in tx.output.iter().enumerate() {
+					if output.script_pubkey == channel.payment_script {
+						state.maturing_outputs.push((height, SpendableOutputDescriptor::StaticOutputCounterpartyPayment {
+							outpoint: OutPoint { txid: tx.txid(), index: idx as u16 },
+							output: output.clone(),
+							key_derivation_params: channel.backup.key_derivation_params,
+						}));
+					}
+				}
+			}
+		}
+
+		let mut outputs = Vec::new();
+		state.maturing_outputs.retain(|&(confirmation_height, ref descriptor)| {
+			if confirmation_height + ANTI_REORG_DELAY - 1 <= height {
+				outputs.push(descriptor.clone());
+				false
+			} else { true }
+		});
+		if !outputs.is_empty() {
+			self.pending_events.lock().unwrap().push(Event::SpendableOutputs { outputs });
+		}
+	}
+
+	/// Forgets about any closing transactions confirmed in a block which was disconnected.
+	pub fn block_disconnected(&self, _header: &BlockHeader, disconnected_height: u32) {
+		let mut state = self.state.lock().unwrap();
+		for channel in state.channels.iter_mut() {
+			if channel.closing_height == Some(disconnected_height) {
+				channel.closing_height = None;
+			}
+		}
+		state.maturing_outputs.retain(|&(confirmation_height, _)| confirmation_height != disconnected_height);
+	}
+
+	fn get_stale_channel_reestablish(&self, backup: &ChannelBackup) -> msgs::ChannelReestablish {
+		let keys = self.keys_manager.derive_channel_keys(backup.channel_value_satoshis, backup.key_derivation_params.0, backup.key_derivation_params.1);
+		let secp_ctx = Secp256k1::new();
+		msgs::ChannelReestablish {
+			channel_id: backup.channel_id,
+			// Claim we've only ever received the first commitment_signed and never revoked it.
+			next_local_commitment_number: 1,
+			next_remote_commitment_number: 0,
+			data_loss_protect: OptionalField::Present(DataLossProtect {
+				your_last_per_commitment_secret: [0; 32],
+				my_current_per_commitment_point: keys.get_per_commitment_point(INITIAL_COMMITMENT_NUMBER, &secp_ctx).unwrap(),
+			}),
+		}
+	}
+}
+
+impl<K: Deref<Target = KeysManager> + Sync + Send, L: Deref + Sync + Send> ChannelMessageHandler for ChannelBackupRecovery<K, L> where L::Target: Logger {
+	fn handle_open_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &msgs::OpenChannel) {}
+	fn handle_accept_channel(&self, _their_node_id: &PublicKey, _their_features: InitFeatures, _msg: &msgs::AcceptChannel) {}
+	fn handle_funding_created(&self, _their_node_id: &PublicKey, _msg: &msgs::FundingCreated) {}
+	fn handle_funding_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::FundingSigned) {}
+	fn handle_funding_locked(&self, _their_node_id: &PublicKey, _msg: &msgs::FundingLocked) {}
+	fn handle_shutdown(&self, _their_node_id: &PublicKey, _msg: &msgs::Shutdown) {}
+	fn handle_closing_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::ClosingSigned) {}
+	fn handle_update_add_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateAddHTLC) {}
+	fn handle_update_fulfill_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFulfillHTLC) {}
+	fn handle_update_fail_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFailHTLC) {}
+	fn handle_update_fail_malformed_htlc(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFailMalformedHTLC) {}
+	fn handle_commitment_signed(&self, _their_node_id: &PublicKey, _msg: &msgs::CommitmentSigned) {}
+	fn handle_revoke_and_ack(&self, _their_node_id: &PublicKey, _msg: &msgs::RevokeAndACK) {}
+	fn handle_update_fee(&self, _their_node_id: &PublicKey, _msg: &msgs::UpdateFee) {}
+	fn handle_announcement_signatures(&self, _their_node_id: &PublicKey, _msg: &msgs::AnnouncementSignatures) {}
+	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
+
+	fn peer_connected(&self, their_node_id: &PublicKey, _msg: &msgs::Init) {
+		let state = self.state.lock().unwrap();
+		let mut pending_msg_events = self.pending_msg_events.lock().unwrap();
+		for channel in state.channels.iter().filter(|channel| channel.closing_height.is_none() && channel.backup.counterparty_node_id == *their_node_id) {
+			log_info!(self.logger, "Asking {} to force-close channel {} after losing its state", log_pubkey!(their_node_id), log_bytes!(channel.backup.channel_id));
+			pending_msg_events.push(MessageSendEvent::SendChannelReestablish {
+				node_id: *their_node_id,
+				msg: self.get_stale_channel_reestablish(&channel.backup),
+			});
+			pending_msg_events.push(MessageSendEvent::HandleError {
+				node_id: *their_node_id,
+				action: ErrorAction::SendErrorMessage {
+					msg: msgs::ErrorMessage {
+						channel_id: channel.backup.channel_id,
+						data: "Channel state was lost, please broadcast your latest commitment transaction".to_owned(),
+					},
+				},
+			});
+		}
+	}
+
+	fn provided_init_features(&self) -> InitFeatures {
+		// We can't forward anything while recovering
+		InitFeatures::known().clear_trampoline_routing()
+	}
+
+	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &msgs::ChannelReestablish) {
+		log_trace!(self.logger, "Received channel_reestablish for channel {} from {} while recovering", log_bytes!(msg.channel_id), log_pubkey!(their_node_id));
+	}
+
+	fn handle_error(&self, their_node_id: &PublicKey, msg: &msgs::ErrorMessage) {
+		log_info!(self.logger, "Received error for channel {} from {} while recovering: {}", log_bytes!(msg.channel_id), log_pubkey!(their_node_id), msg.data);
+	}
+}
+
+impl<K: Deref<Target = KeysManager>, L: Deref> MessageSendEventsProvider for ChannelBackupRecovery<K, L> where L::Target: Logger {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut pending_msg_events = self.pending_msg_events.lock().unwrap();
+		let mut ret = Vec::new();
+		::std::mem::swap(&mut ret, &mut *pending_msg_events);
+		ret
+	}
+}
+
+impl<K: Deref<Target = KeysManager>, L: Deref> EventsProvider for ChannelBackupRecovery<K, L> where L::Target: Logger {
+	fn get_and_clear_pending_events(&self) -> Vec<Event> {
+		let mut pending_events = self.pending_events.lock().unwrap();
+		let mut ret = Vec::new();
+		::std::mem::swap(&mut ret, &mut *pending_events);
+		ret
+	}
+}
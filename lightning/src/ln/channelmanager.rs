@@ -27,7 +27,7 @@ use bitcoin::hashes::hmac::{Hmac, HmacEngine};
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::cmp::fixed_time_eq;
-use bitcoin::hash_types::BlockHash;
+use bitcoin::hash_types::{BlockHash, Txid};
 
 use bitcoin::secp256k1::key::{SecretKey,PublicKey};
 use bitcoin::secp256k1::Secp256k1;
@@ -40,8 +40,10 @@ use chain::chaininterface::{BroadcasterInterface, FeeEstimator};
 use chain::channelmonitor::{ChannelMonitor, ChannelMonitorUpdate, ChannelMonitorUpdateErr, HTLC_FAIL_BACK_BUFFER, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY, MonitorEvent};
 use chain::transaction::{OutPoint, TransactionData};
 use ln::channel::{Channel, ChannelError};
-use ln::features::{InitFeatures, NodeFeatures};
-use routing::router::{Route, RouteHop};
+use ln::channelbackup::{ChannelBackup, StaticChannelBackup};
+use ln::features::InitFeatures;
+use routing::network_graph::NetworkGraph;
+use routing::router::{get_route, BlindedRoute, Route, RouteHint, RouteHop, TrampolineRoute};
 use ln::msgs;
 use ln::msgs::NetAddress;
 use ln::onion_utils;
@@ -87,11 +89,18 @@ enum PendingHTLCRouting {
 	Forward {
 		onion_packet: msgs::OnionPacket,
 		short_channel_id: u64, // This should be NonZero<u64> eventually when we bump MSRV
+		blinding_point: Option<PublicKey>, // Set if the next hop is within a blinded route
 	},
 	Receive {
 		payment_data: Option<msgs::FinalOnionHopData>,
 		incoming_cltv_expiry: u32, // Used to track when we should expire pending HTLCs that go unclaimed
 	},
+	TrampolineForward {
+		trampoline_packet: msgs::TrampolineOnionPacket,
+		outgoing_node_id: PublicKey,
+		incoming_amt_msat: u64, // Used to determine the fee budget we have to route with
+		incoming_cltv_expiry: u32,
+	},
 }
 
 #[derive(Clone)] // See Channel::revoke_and_ack for why, tl;dr: Rust bug
@@ -101,6 +110,9 @@ pub(super) struct PendingHTLCInfo {
 	payment_hash: PaymentHash,
 	pub(super) amt_to_forward: u64,
 	pub(super) outgoing_cltv_value: u32,
+	/// Set if we received the HTLC as part of a blinded route, in which case we must not reveal
+	/// why it failed to the sender.
+	blinded_failure: Option<BlindedFailure>,
 }
 
 #[derive(Clone)] // See Channel::revoke_and_ack for why, tl;dr: Rust bug
@@ -126,6 +138,23 @@ pub(super) enum HTLCForwardInfo {
 		htlc_id: u64,
 		err_packet: msgs::OnionErrorPacket,
 	},
+	FailMalformedHTLC {
+		htlc_id: u64,
+		failure_code: u16,
+		sha256_of_onion: [u8; 32],
+	},
+}
+
+/// How we fail back an HTLC we received as part of a blinded route, so that the sender only ever
+/// learns that the blinded route failed as a whole.
+#[derive(Clone, Copy, PartialEq)]
+enum BlindedFailure {
+	/// We are the introduction node, so we relay invalid_onion_blinding in an onion error.
+	FromIntroductionNode,
+	/// The update_add_htlc carried a blinding_point, so we are within the blinded route and fail
+	/// back with an update_fail_malformed_htlc, leaving it to the introduction node to build the
+	/// onion error.
+	FromBlindedNode,
 }
 
 /// Tracks the inbound corresponding to an outbound HTLC
@@ -134,6 +163,7 @@ pub(crate) struct HTLCPreviousHopData {
 	short_channel_id: u64,
 	htlc_id: u64,
 	incoming_packet_shared_secret: [u8; 32],
+	blinded_failure: Option<BlindedFailure>,
 }
 
 struct ClaimableHTLC {
@@ -147,6 +177,19 @@ struct ClaimableHTLC {
 	cltv_expiry: u32,
 }
 
+/// An HTLC we received as a trampoline node, waiting on process_pending_trampoline_forwards to
+/// find a route to the next trampoline hop for it.
+struct TrampolineHTLC {
+	prev_hop: HTLCPreviousHopData,
+	payment_hash: PaymentHash,
+	value: u64,
+	cltv_expiry: u32,
+	outgoing_node_id: PublicKey,
+	trampoline_packet: msgs::TrampolineOnionPacket,
+	amt_to_forward: u64,
+	outgoing_cltv_value: u32,
+}
+
 /// Tracks the inbound corresponding to an outbound HTLC
 #[derive(Clone, PartialEq)]
 pub(crate) enum HTLCSource {
@@ -158,6 +201,14 @@ pub(crate) enum HTLCSource {
 		/// doing a double-pass on route when we get a failure back
 		first_hop_htlc_msat: u64,
 	},
+	/// An HTLC we sent on as a trampoline node, along a path we found ourselves. Failures are
+	/// decoded as for an OutboundRoute before being passed back to the previous hop.
+	TrampolineRoute {
+		previous_hop: HTLCPreviousHopData,
+		path: Vec<RouteHop>,
+		session_priv: SecretKey,
+		first_hop_htlc_msat: u64,
+	},
 }
 #[cfg(test)]
 impl HTLCSource {
@@ -316,6 +367,9 @@ pub(super) struct ChannelHolder<ChanSigner: ChannelKeys> {
 	/// guarantees are made about the channels given here actually existing anymore by the time you
 	/// go to read them!
 	claimable_htlcs: HashMap<(PaymentHash, Option<PaymentSecret>), Vec<ClaimableHTLC>>,
+	/// HTLCs we received as a trampoline node which are waiting for a route onwards, see
+	/// ChannelManager::process_pending_trampoline_forwards.
+	trampoline_htlcs: Vec<TrampolineHTLC>,
 	/// Messages to send to peers - pushed to in the same lock that they are generated in (except
 	/// for broadcast messages, where ordering isn't as strict).
 	pub(super) pending_msg_events: Vec<MessageSendEvent>,
@@ -738,6 +792,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				short_to_id: HashMap::new(),
 				forward_htlcs: HashMap::new(),
 				claimable_htlcs: HashMap::new(),
+				trampoline_htlcs: Vec::new(),
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: keys_manager.get_node_secret(),
@@ -774,7 +829,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 		let config = if override_config.is_some() { override_config.as_ref().unwrap() } else { &self.default_configuration };
 		let channel = Channel::new_outbound(&self.fee_estimator, &self.keys_manager, their_network_key, channel_value_satoshis, push_msat, user_id, config)?;
-		let res = channel.get_open_channel(self.genesis_hash.clone());
+		let res = channel.get_open_channel(self.genesis_hash.clone())?;
 
 		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 		let mut channel_state = self.channel_state.lock().unwrap();
@@ -842,6 +897,36 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		self.list_channels_with_filter(|&(_, ref channel)| channel.is_live())
 	}
 
+	/// Gets a static backup of all channels with a funding transaction, allowing our balance in
+	/// them to be recovered with a [`ChannelBackupRecovery`] from the seed and the backup alone if
+	/// all channel state is lost.
+	///
+	/// The backup only changes as channels are opened and closed, thus it should be re-exported
+	/// and stored (ideally remotely) after each channel is funded or closed. Counterparty addresses
+	/// aren't known to the ChannelManager and are looked up in `peer_addresses` (e.g. filled from
+	/// [`NetworkGraph::get_addresses`]), being left empty for missing peers.
+	///
+	/// [`ChannelBackupRecovery`]: ../channelbackup/struct.ChannelBackupRecovery.html
+	/// [`NetworkGraph::get_addresses`]: ../../routing/network_graph/struct.NetworkGraph.html#method.get_addresses
+	pub fn get_static_channel_backup(&self, peer_addresses: &HashMap<PublicKey, Vec<NetAddress>>) -> StaticChannelBackup {
+		let channel_state = self.channel_state.lock().unwrap();
+		let mut channels = Vec::with_capacity(channel_state.by_id.len());
+		for (channel_id, channel) in channel_state.by_id.iter() {
+			if let Some(funding_txo) = channel.get_funding_txo() {
+				let counterparty_node_id = channel.get_counterparty_node_id();
+				channels.push(ChannelBackup {
+					channel_id: *channel_id,
+					counterparty_node_id,
+					counterparty_addresses: peer_addresses.get(&counterparty_node_id).cloned().unwrap_or(Vec::new()),
+					funding_txo,
+					channel_value_satoshis: channel.get_value_satoshis(),
+					key_derivation_params: channel.get_key_derivation_params(),
+				});
+			}
+		}
+		StaticChannelBackup { channels }
+	}
+
 	/// Begins the process of closing a channel. After this call (plus some timeout), no new HTLCs
 	/// will be accepted on the given channel, and after additional timeout/the closing of all
 	/// pending HTLCs, the channel will be closed on chain.
@@ -949,7 +1034,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						channel_id: msg.channel_id,
 						htlc_id: msg.htlc_id,
 						sha256_of_onion: Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(),
-						failure_code: $err_code,
+						// Within a blinded route, all failures are reported as invalid_onion_blinding
+						failure_code: if msg.blinding_point.is_some() { 0x8000 | 0x4000 | 24 } else { $err_code },
 					})), self.channel_state.lock().unwrap());
 				}
 			}
@@ -959,9 +1045,19 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			return_malformed_err!("invalid ephemeral pubkey", 0x8000 | 0x4000 | 6);
 		}
 
+		// If we're within a blinded route (after its introduction node), the sender built our onion
+		// layer for our blinded node id, so we need to decode it with the matching secret.
+		let onion_node_secret = match msg.blinding_point {
+			Some(ref blinding_point) => match onion_utils::blinded_node_secret(&self.our_network_key, blinding_point) {
+				Ok(secret) => secret,
+				Err(_) => return_malformed_err!("Unable to derive our blinded node secret", 0x8000 | 0x4000 | 24),
+			},
+			None => self.our_network_key.clone(),
+		};
+
 		let shared_secret = {
 			let mut arr = [0; 32];
-			arr.copy_from_slice(&SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &self.our_network_key)[..]);
+			arr.copy_from_slice(&SharedSecret::new(&msg.onion_routing_packet.public_key.unwrap(), &onion_node_secret)[..]);
 			arr
 		};
 		let (rho, mu) = onion_utils::gen_rho_mu_from_shared_secret(&shared_secret);
@@ -984,6 +1080,9 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 
 		let mut channel_state = None;
+		// Set once we find we are within a blinded route, either as its introduction node or because
+		// the update_add_htlc came with a blinding_point
+		let mut blinded_failure = if msg.blinding_point.is_some() { Some(BlindedFailure::FromBlindedNode) } else { None };
 		macro_rules! return_err {
 			($msg: expr, $err_code: expr, $data: expr) => {
 				{
@@ -991,11 +1090,49 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					if channel_state.is_none() {
 						channel_state = Some(self.channel_state.lock().unwrap());
 					}
-					return (PendingHTLCStatus::Fail(HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
-						channel_id: msg.channel_id,
-						htlc_id: msg.htlc_id,
-						reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
-					})), channel_state.unwrap());
+					// Within a blinded route we hide the real failure, so that the sender cannot use
+					// it to probe the route. Hops after the introduction node fail back as malformed,
+					// leaving it to the introduction node to report invalid_onion_blinding.
+					let sha256_of_onion = Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner();
+					let fail_msg = match blinded_failure {
+						Some(BlindedFailure::FromBlindedNode) => HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							sha256_of_onion,
+							failure_code: 0x8000 | 0x4000 | 24,
+						}),
+						Some(BlindedFailure::FromIntroductionNode) => HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							reason: onion_utils::build_first_hop_failure_packet(&shared_secret, 0x8000 | 0x4000 | 24, &sha256_of_onion),
+						}),
+						None => HTLCFailureMsg::Relay(msgs::UpdateFailHTLC {
+							channel_id: msg.channel_id,
+							htlc_id: msg.htlc_id,
+							reason: onion_utils::build_first_hop_failure_packet(&shared_secret, $err_code, $data),
+						}),
+					};
+					return (PendingHTLCStatus::Fail(fail_msg), channel_state.unwrap());
+				}
+			}
+		}
+		macro_rules! decrypt_blinded_hop_data {
+			($encrypted_data: expr, $payload_blinding_point: expr) => {
+				{
+					// The introduction node gets the blinding point in its onion payload, every
+					// later hop in the update_add_htlc.
+					let blinding_point = match (msg.blinding_point, $payload_blinding_point) {
+						(Some(blinding_point), None) => blinding_point,
+						(None, Some(blinding_point)) => {
+							blinded_failure = Some(BlindedFailure::FromIntroductionNode);
+							blinding_point
+						},
+						_ => return_err!("Got blinded route data without exactly one blinding point", 0x4000 | 22, &[0;0]),
+					};
+					match onion_utils::decrypt_blinded_hop_data(&self.secp_ctx, &self.our_network_key, &blinding_point, &$encrypted_data[..]) {
+						Ok(res) => res,
+						Err(()) => return_err!("Unable to decrypt our blinded route data", 0x4000 | 22, &[0;0]),
+					}
 				}
 			}
 		}
@@ -1024,6 +1161,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			}
 		};
 
+		if msg.blinding_point.is_some() {
+			if let msgs::OnionHopDataFormat::Blinded { .. } = next_hop_data.format {} else {
+				return_err!("Got a non-blinded payload within a blinded route", 0x4000 | 22, &[0;0]);
+			}
+		}
+
 		let pending_forward_info = if next_hop_hmac == [0; 32] {
 				#[cfg(test)]
 				{
@@ -1059,10 +1202,73 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					return_err!("Upstream node set CLTV to the wrong value", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
 				}
 
-				let payment_data = match next_hop_data.format {
-					msgs::OnionHopDataFormat::Legacy { .. } => None,
+				let (routing, amt_to_forward, outgoing_cltv_value) = match next_hop_data.format {
+					msgs::OnionHopDataFormat::Legacy { .. } => (PendingHTLCRouting::Receive {
+						payment_data: None,
+						incoming_cltv_expiry: msg.cltv_expiry,
+					}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value),
 					msgs::OnionHopDataFormat::NonFinalNode { .. } => return_err!("Got non final data with an HMAC of 0", 0x4000 | 22, &[0;0]),
-					msgs::OnionHopDataFormat::FinalNode { payment_data } => payment_data,
+					msgs::OnionHopDataFormat::TrampolineForward { .. } => return_err!("Got trampoline forward data outside of a trampoline onion", 0x4000 | 22, &[0;0]),
+					msgs::OnionHopDataFormat::FinalNode { payment_data } => (PendingHTLCRouting::Receive {
+						payment_data,
+						incoming_cltv_expiry: msg.cltv_expiry,
+					}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value),
+					msgs::OnionHopDataFormat::TrampolineEntry { trampoline_packet, .. } => {
+						let (trampoline_hop_data, next_trampoline_packet) = match onion_utils::decode_trampoline_onion(&self.secp_ctx, &self.our_network_key, &trampoline_packet, &msg.payment_hash) {
+							Ok(res) => res,
+							Err(error_code) => return_err!("Unable to decode our trampoline hop data", error_code, &[0;0]),
+						};
+						match (trampoline_hop_data.format, next_trampoline_packet) {
+							(msgs::OnionHopDataFormat::FinalNode { payment_data }, None) => {
+								// The payment is to us, and the trampoline onion tells us what the
+								// sender intended us to receive.
+								if trampoline_hop_data.amt_to_forward > next_hop_data.amt_to_forward {
+									return_err!("Upstream trampoline node sent less than we were supposed to receive in payment", 19, &byte_utils::be64_to_array(msg.amount_msat));
+								}
+								if trampoline_hop_data.outgoing_cltv_value > next_hop_data.outgoing_cltv_value {
+									return_err!("Upstream trampoline node set CLTV to the wrong value", 18, &byte_utils::be32_to_array(msg.cltv_expiry));
+								}
+								(PendingHTLCRouting::Receive {
+									payment_data,
+									incoming_cltv_expiry: msg.cltv_expiry,
+								}, trampoline_hop_data.amt_to_forward, trampoline_hop_data.outgoing_cltv_value)
+							},
+							(msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id }, Some(trampoline_packet)) => {
+								// We are a trampoline node, which process_pending_trampoline_forwards
+								// will find a route onwards for, provided we were left enough fee
+								// and CLTV delta to do so.
+								if !self.default_configuration.accept_trampoline_forwards {
+									return_err!("We don't act as a trampoline node", 0x4000 | 22, &[0;0]);
+								}
+								if trampoline_hop_data.amt_to_forward > msg.amount_msat { // trampoline_fee_insufficient
+									return_err!("Prior hop did not leave us a fee to route the trampoline payment with", 0x2000 | 51, &[0;0]);
+								}
+								if (msg.cltv_expiry as u64) < trampoline_hop_data.outgoing_cltv_value as u64 + CLTV_EXPIRY_DELTA as u64 { // trampoline_expiry_too_soon
+									return_err!("Prior hop did not leave us a CLTV delta to route the trampoline payment with", 0x2000 | 52, &[0;0]);
+								}
+								(PendingHTLCRouting::TrampolineForward {
+									trampoline_packet,
+									outgoing_node_id,
+									incoming_amt_msat: msg.amount_msat,
+									incoming_cltv_expiry: msg.cltv_expiry,
+								}, trampoline_hop_data.amt_to_forward, trampoline_hop_data.outgoing_cltv_value)
+							},
+							_ => unreachable!(), // decode_trampoline_onion only returns the above
+						}
+					},
+					msgs::OnionHopDataFormat::Blinded { encrypted_data, blinding_point, payment_data } => {
+						let (hop_data, _) = decrypt_blinded_hop_data!(encrypted_data, blinding_point);
+						// We set the path_id of blinded routes to us to the payment_secret we expect
+						// over them, so that they can't be used to probe for other payments.
+						match (hop_data.short_channel_id, hop_data.path_id, &payment_data) {
+							(None, Some(path_id), &Some(ref payment_data)) if payment_data.payment_secret.0 == path_id => {},
+							_ => return_err!("Got blinded route data which isn't for a payment to us", 0x4000 | 22, &[0;0]),
+						}
+						(PendingHTLCRouting::Receive {
+							payment_data,
+							incoming_cltv_expiry: msg.cltv_expiry,
+						}, next_hop_data.amt_to_forward, next_hop_data.outgoing_cltv_value)
+					},
 				};
 
 				// Note that we could obviously respond immediately with an update_fulfill_htlc
@@ -1071,14 +1277,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				// delay) once they've send us a commitment_signed!
 
 				PendingHTLCStatus::Forward(PendingHTLCInfo {
-					routing: PendingHTLCRouting::Receive {
-						payment_data,
-						incoming_cltv_expiry: msg.cltv_expiry,
-					},
+					routing,
 					payment_hash: msg.payment_hash.clone(),
 					incoming_shared_secret: shared_secret,
-					amt_to_forward: next_hop_data.amt_to_forward,
-					outgoing_cltv_value: next_hop_data.outgoing_cltv_value,
+					amt_to_forward,
+					outgoing_cltv_value,
+					blinded_failure,
 				})
 			} else {
 				let mut new_packet_data = [0; 20*65];
@@ -1116,23 +1320,36 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					hmac: next_hop_hmac.clone(),
 				};
 
-				let short_channel_id = match next_hop_data.format {
-					msgs::OnionHopDataFormat::Legacy { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id } => short_channel_id,
-					msgs::OnionHopDataFormat::FinalNode { .. } => {
+				let (short_channel_id, blinding_point) = match next_hop_data.format {
+					msgs::OnionHopDataFormat::Legacy { short_channel_id } => (short_channel_id, None),
+					msgs::OnionHopDataFormat::NonFinalNode { short_channel_id } => (short_channel_id, None),
+					msgs::OnionHopDataFormat::FinalNode { .. } |
+					msgs::OnionHopDataFormat::TrampolineEntry { .. } => {
 						return_err!("Final Node OnionHopData provided for us as an intermediary node", 0x4000 | 22, &[0;0]);
 					},
+					msgs::OnionHopDataFormat::TrampolineForward { .. } => {
+						return_err!("Got trampoline forward data outside of a trampoline onion", 0x4000 | 22, &[0;0]);
+					},
+					msgs::OnionHopDataFormat::Blinded { encrypted_data, blinding_point, payment_data } => {
+						let (hop_data, next_blinding_point) = decrypt_blinded_hop_data!(encrypted_data, blinding_point);
+						match (hop_data.short_channel_id, hop_data.path_id, payment_data) {
+							(Some(short_channel_id), None, None) => (short_channel_id, Some(next_blinding_point)),
+							_ => return_err!("Got blinded route data which doesn't tell us where to forward", 0x4000 | 22, &[0;0]),
+						}
+					},
 				};
 
 				PendingHTLCStatus::Forward(PendingHTLCInfo {
 					routing: PendingHTLCRouting::Forward {
 						onion_packet: outgoing_packet,
 						short_channel_id,
+						blinding_point,
 					},
 					payment_hash: msg.payment_hash.clone(),
 					incoming_shared_secret: shared_secret,
 					amt_to_forward: next_hop_data.amt_to_forward,
 					outgoing_cltv_value: next_hop_data.outgoing_cltv_value,
+					blinded_failure,
 				})
 			};
 
@@ -1244,13 +1461,32 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	// Only public for testing, this should otherwise never be called direcly
 	pub(crate) fn send_payment_along_path(&self, path: &Vec<RouteHop>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, total_value: u64, cur_height: u32) -> Result<(), APIError> {
+		self.send_htlc_along_path(path, payment_hash, payment_secret, total_value, cur_height, None, None, None)
+	}
+
+	/// Sends an HTLC along the given path. If a trampoline_packet is given, it is handed to the
+	/// last hop in the path, and if a previous_hop is given, the HTLC is one we're forwarding as a
+	/// trampoline node rather than a payment of our own. If a blinded_route is given, the path
+	/// must end in it, as returned by get_blinded_route.
+	fn send_htlc_along_path(&self, path: &Vec<RouteHop>, payment_hash: &PaymentHash, payment_secret: &Option<PaymentSecret>, total_value: u64, cur_height: u32, trampoline_packet: Option<msgs::TrampolineOnionPacket>, previous_hop: Option<HTLCPreviousHopData>, blinded_route: Option<&BlindedRoute>) -> Result<(), APIError> {
 		log_trace!(self.logger, "Attempting to send payment for path with next hop {}", path.first().unwrap().short_channel_id);
 		let prng_seed = self.keys_manager.get_secure_random_bytes();
 		let session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
 
 		let onion_keys = onion_utils::construct_onion_keys(&self.secp_ctx, &path, &session_priv)
 			.map_err(|_| APIError::RouteError{err: "Pubkey along hop was maliciously selected"})?;
-		let (onion_payloads, htlc_msat, htlc_cltv) = onion_utils::build_onion_payloads(path, total_value, payment_secret, cur_height)?;
+		let (mut onion_payloads, htlc_msat, htlc_cltv) = match blinded_route {
+			Some(blinded_route) => onion_utils::build_blinded_onion_payloads(path, blinded_route, total_value, payment_secret, cur_height)?,
+			None => onion_utils::build_onion_payloads(path, total_value, payment_secret, cur_height)?,
+		};
+		if let Some(trampoline_packet) = trampoline_packet {
+			let last_payload = onion_payloads.last_mut().unwrap();
+			let payment_data = match last_payload.format {
+				msgs::OnionHopDataFormat::FinalNode { ref mut payment_data } => payment_data.take(),
+				_ => return Err(APIError::RouteError{err: "Next trampoline node does not support variable-length onions"}),
+			};
+			last_payload.format = msgs::OnionHopDataFormat::TrampolineEntry { payment_data, trampoline_packet };
+		}
 		if onion_utils::route_size_insane(&onion_payloads) {
 			return Err(APIError::RouteError{err: "Route size too large considering onion data"});
 		}
@@ -1274,11 +1510,21 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					if !chan.get().is_live() {
 						return Err(APIError::ChannelUnavailable{err: "Peer for first hop currently disconnected/pending monitor update!".to_owned()});
 					}
-					break_chan_entry!(self, chan.get_mut().send_htlc_and_commit(htlc_msat, payment_hash.clone(), htlc_cltv, HTLCSource::OutboundRoute {
-						path: path.clone(),
-						session_priv: session_priv.clone(),
-						first_hop_htlc_msat: htlc_msat,
-					}, onion_packet, &self.logger), channel_state, chan)
+					let htlc_source = if let Some(previous_hop) = previous_hop {
+						HTLCSource::TrampolineRoute {
+							previous_hop,
+							path: path.clone(),
+							session_priv: session_priv.clone(),
+							first_hop_htlc_msat: htlc_msat,
+						}
+					} else {
+						HTLCSource::OutboundRoute {
+							path: path.clone(),
+							session_priv: session_priv.clone(),
+							first_hop_htlc_msat: htlc_msat,
+						}
+					};
+					break_chan_entry!(self, chan.get_mut().send_htlc_and_commit(htlc_msat, payment_hash.clone(), htlc_cltv, htlc_source, onion_packet, None, &self.logger), channel_state, chan)
 				} {
 					Some((update_add, commitment_signed, monitor_update)) => {
 						if let Err(e) = self.chain_monitor.update_channel(chan.get().get_funding_txo().unwrap(), monitor_update) {
@@ -1414,6 +1660,133 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 	}
 
+	/// Sends a payment along a given TrampolineRoute, over one of our channels to a trampoline
+	/// node which will find the rest of the route itself. This allows paying without a full view
+	/// of the network graph, see routing::router::get_trampoline_route.
+	///
+	/// The payment is carried to the trampoline node(s) in a nested onion, which the
+	/// payment_secret (if any) is placed in for the destination. The trampoline node learns only
+	/// the next trampoline hop, but the last trampoline node learns the destination.
+	///
+	/// Errors and payment_hash handling are as for send_payment. As a TrampolineRoute has only a
+	/// single path, PaymentSendFailure::PartialFailure is only returned on monitor update failure.
+	pub fn send_trampoline_payment(&self, route: &TrampolineRoute, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>) -> Result<(), PaymentSendFailure> {
+		if route.trampoline_hops.len() < 1 || route.trampoline_hops.len() > 5 {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Trampoline route didn't go anywhere/had bogus size"}));
+		}
+		let our_node_id = self.get_our_node_id();
+		if route.first_hop.pubkey == our_node_id || route.trampoline_hops[..route.trampoline_hops.len() - 1].iter().any(|hop| hop.pubkey == our_node_id) {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Trampoline route went through us"}));
+		}
+
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+		let total_value = route.trampoline_hops.last().unwrap().fee_msat;
+		let prng_seed = self.keys_manager.get_secure_random_bytes();
+		let session_priv = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+
+		let onion_keys = onion_utils::construct_trampoline_onion_keys(&self.secp_ctx, route, &session_priv)
+			.map_err(|_| PaymentSendFailure::ParameterError(APIError::RouteError{err: "Pubkey along hop was maliciously selected"}))?;
+		let (onion_payloads, forwarded_msat, forwarded_cltv) = onion_utils::build_trampoline_onion_payloads(route, total_value, payment_secret, cur_height)
+			.map_err(|e| PaymentSendFailure::ParameterError(e))?;
+		if forwarded_msat > route.first_hop.fee_msat || forwarded_cltv > cur_height + route.first_hop.cltv_expiry_delta {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "First hop does not carry enough value or CLTV delta for the trampoline hops"}));
+		}
+		if onion_utils::trampoline_route_size_insane(&onion_payloads) {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Trampoline route size too large considering onion data"}));
+		}
+		let trampoline_packet = onion_utils::construct_trampoline_onion_packet(onion_payloads, onion_keys, prng_seed, &payment_hash);
+
+		// The payment_secret is for the destination alone, so we don't give one to the first
+		// trampoline node in the outer onion.
+		let path = vec![route.first_hop.clone()];
+		match self.send_htlc_along_path(&path, &payment_hash, &None, route.first_hop.fee_msat, cur_height, Some(trampoline_packet), None, None) {
+			Ok(()) => Ok(()),
+			Err(APIError::MonitorUpdateFailed) => Err(PaymentSendFailure::PartialFailure(vec![Err(APIError::MonitorUpdateFailed)])),
+			Err(e) => Err(PaymentSendFailure::AllFailedRetrySafe(vec![e])),
+		}
+	}
+
+	/// Creates a BlindedRoute to us over the given hops, the first of which leads from the
+	/// introduction node and the last of which leads to us, which may be handed to a sender in
+	/// place of our node id. Senders learn only the introduction node and the number of hops after
+	/// it, while each hop learns only its neighbours in the route.
+	///
+	/// Payments over the returned route are only accepted if they carry the given payment_secret,
+	/// which should thus be the one given to the sender for the payment. If hops is empty, we are
+	/// the introduction node ourselves.
+	pub fn create_blinded_route(&self, hops: &[RouteHint], payment_secret: &PaymentSecret) -> Result<BlindedRoute, APIError> {
+		if hops.len() > 10 {
+			return Err(APIError::RouteError{err: "Blinded route had bogus size"});
+		}
+		let blinding_secret = SecretKey::from_slice(&self.keys_manager.get_secure_random_bytes()[..]).expect("RNG is busted");
+		onion_utils::construct_blinded_route(&self.secp_ctx, &blinding_secret, hops, &self.get_our_node_id(), payment_secret.0)
+			.map_err(|_| APIError::RouteError{err: "Pubkey along hop was maliciously selected"})
+	}
+
+	/// Sends a payment over a route which ends in the given BlindedRoute, as returned by
+	/// routing::router::get_blinded_route. The recipient's node id and the channels after the
+	/// introduction node are never learned, as each hop in the blinded route is handed only the
+	/// data the recipient encrypted to it.
+	///
+	/// Errors and payment_hash handling are as for send_payment, though only a single path is
+	/// supported. Failures within the blinded route are reported only as invalid_onion_blinding,
+	/// without indicating which hop failed.
+	pub fn send_payment_to_blinded_route(&self, route: &Route, blinded_route: &BlindedRoute, payment_hash: PaymentHash, payment_secret: &Option<PaymentSecret>) -> Result<(), PaymentSendFailure> {
+		if route.paths.len() != 1 {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Payments to blinded routes must be sent over a single path"}));
+		}
+		let path = &route.paths[0];
+		if path.len() < 1 || path.len() > 20 {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Path didn't go anywhere/had bogus size"}));
+		}
+		let our_node_id = self.get_our_node_id();
+		if path.iter().any(|hop| hop.pubkey == our_node_id) {
+			return Err(PaymentSendFailure::ParameterError(APIError::RouteError{err: "Path to a blinded route went through us"}));
+		}
+
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+		let total_value = path.last().unwrap().fee_msat;
+		match self.send_htlc_along_path(path, &payment_hash, payment_secret, total_value, cur_height, None, None, Some(blinded_route)) {
+			Ok(()) => Ok(()),
+			Err(APIError::MonitorUpdateFailed) => Err(PaymentSendFailure::PartialFailure(vec![Err(APIError::MonitorUpdateFailed)])),
+			Err(e) => Err(PaymentSendFailure::AllFailedRetrySafe(vec![e])),
+		}
+	}
+
+	/// Sends a probe along each path in the given route, returning the PaymentHash used.
+	///
+	/// A probe is an HTLC with a random payment hash which the destination cannot know the
+	/// preimage for. It is thus always failed back, and the destination replying with
+	/// incorrect_or_unknown_payment_details tells us the probe made it through every channel in
+	/// the path. The result is surfaced as an Event::ProbeSuccessful or Event::ProbeFailed
+	/// (which may be fed into a routing::scorer::Score) rather than as a PaymentFailed.
+	///
+	/// Probes lock up liquidity along the path until they are failed back, so should not be sent
+	/// in excess. Errors are the same as for send_payment.
+	pub fn send_probe(&self, route: &Route) -> Result<PaymentHash, PaymentSendFailure> {
+		let mut payment_hash = [0; 32];
+		payment_hash[..16].copy_from_slice(&self.keys_manager.get_secure_random_bytes()[..16]);
+		let tag = self.probe_tag(&payment_hash[..16]);
+		payment_hash[16..].copy_from_slice(&tag[..16]);
+		let payment_hash = PaymentHash(payment_hash);
+		self.send_payment(route, payment_hash, &None)?;
+		Ok(payment_hash)
+	}
+
+	/// Probe payment hashes are made of 16 random bytes followed by the first 16 bytes of this
+	/// HMAC of them, keyed by our node secret. This lets us recognise our probes as they fail
+	/// without having to track (and persist) them.
+	fn probe_tag(&self, nonce: &[u8]) -> [u8; 32] {
+		let mut hmac = HmacEngine::<Sha256>::new(&self.our_network_key[..]);
+		hmac.input(b"LDK probe");
+		hmac.input(nonce);
+		Hmac::from_engine(hmac).into_inner()
+	}
+
+	fn is_probe_payment_hash(&self, payment_hash: &PaymentHash) -> bool {
+		fixed_time_eq(&self.probe_tag(&payment_hash.0[..16])[..16], &payment_hash.0[16..])
+	}
+
 	/// Call this upon creation of a funding transaction for the given channel.
 	///
 	/// Note that ALL inputs in the transaction pointed to by funding_txo MUST spend SegWit outputs
@@ -1493,6 +1866,16 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	// smaller than 500:
 	const STATIC_ASSERT: u32 = Self::HALF_MESSAGE_IS_ADDRS - 500;
 
+	/// The features we advertise, both to our peers and in our node_announcement. We only claim
+	/// to support trampoline routing if the user asked us to act as a trampoline node.
+	pub(crate) fn our_init_features(&self) -> InitFeatures {
+		if self.default_configuration.accept_trampoline_forwards {
+			InitFeatures::known()
+		} else {
+			InitFeatures::known().clear_trampoline_routing()
+		}
+	}
+
 	/// Generates a signed node_announcement from the given arguments and creates a
 	/// BroadcastNodeAnnouncement event. Note that such messages will be ignored unless peers have
 	/// seen a channel_announcement from us (ie unless we have public channels open).
@@ -1514,7 +1897,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		}
 
 		let announcement = msgs::UnsignedNodeAnnouncement {
-			features: NodeFeatures::known(),
+			features: self.our_init_features().to_context(),
 			timestamp: self.last_node_announcement_serial.fetch_add(1, Ordering::AcqRel) as u32,
 			node_id: self.get_our_node_id(),
 			rgb, alias, addresses,
@@ -1536,6 +1919,9 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	///
 	/// Should only really ever be called in response to a PendingHTLCsForwardable event.
 	/// Will likely generate further events.
+	///
+	/// HTLCs we receive as a trampoline node are held until process_pending_trampoline_forwards
+	/// is called.
 	pub fn process_pending_htlc_forwards(&self) {
 		let _consistency_lock = self.total_consistency_lock.read().unwrap();
 
@@ -1559,12 +1945,13 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 											short_channel_id: prev_short_channel_id,
 											htlc_id: prev_htlc_id,
 											incoming_packet_shared_secret: forward_info.incoming_shared_secret,
+											blinded_failure: forward_info.blinded_failure,
 										});
 										failed_forwards.push((htlc_source, forward_info.payment_hash,
 											HTLCFailReason::Reason { failure_code: 0x4000 | 10, data: Vec::new() }
 										));
 									},
-									HTLCForwardInfo::FailHTLC { .. } => {
+									HTLCForwardInfo::FailHTLC { .. } | HTLCForwardInfo::FailMalformedHTLC { .. } => {
 										// Channel went away before we could fail it. This implies
 										// the channel is now on chain and our counterparty is
 										// trying to broadcast the HTLC-Timeout, but that's their
@@ -1578,19 +1965,21 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					if let hash_map::Entry::Occupied(mut chan) = channel_state.by_id.entry(forward_chan_id) {
 						let mut add_htlc_msgs = Vec::new();
 						let mut fail_htlc_msgs = Vec::new();
+						let mut fail_malformed_htlc_msgs = Vec::new();
 						for forward_info in pending_forwards.drain(..) {
 							match forward_info {
 								HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
 										routing: PendingHTLCRouting::Forward {
-											onion_packet, ..
-										}, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value }, } => {
+											onion_packet, blinding_point, ..
+										}, incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value, blinded_failure }, } => {
 									log_trace!(self.logger, "Adding HTLC from short id {} with payment_hash {} to channel with short id {} after delay", log_bytes!(payment_hash.0), prev_short_channel_id, short_chan_id);
 									let htlc_source = HTLCSource::PreviousHopData(HTLCPreviousHopData {
 										short_channel_id: prev_short_channel_id,
 										htlc_id: prev_htlc_id,
 										incoming_packet_shared_secret: incoming_shared_secret,
+										blinded_failure,
 									});
-									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet) {
+									match chan.get_mut().send_htlc(amt_to_forward, payment_hash, outgoing_cltv_value, htlc_source.clone(), onion_packet, blinding_point) {
 										Err(e) => {
 											if let ChannelError::Ignore(msg) = e {
 												log_trace!(self.logger, "Failed to forward HTLC with payment_hash {}: {}", log_bytes!(payment_hash.0), msg);
@@ -1649,10 +2038,29 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 										}
 									}
 								},
+								HTLCForwardInfo::FailMalformedHTLC { htlc_id, failure_code, sha256_of_onion } => {
+									log_trace!(self.logger, "Failing HTLC back to channel with short id {} after delay", short_chan_id);
+									match chan.get_mut().get_update_fail_malformed_htlc(htlc_id, failure_code, sha256_of_onion) {
+										Err(e) => {
+											if let ChannelError::Ignore(msg) = e {
+												log_trace!(self.logger, "Failed to fail backwards to short_id {}: {}", short_chan_id, msg);
+											} else {
+												panic!("Stated return value requirements in get_update_fail_malformed_htlc() were not met");
+											}
+											continue;
+										},
+										Ok(Some(msg)) => { fail_malformed_htlc_msgs.push(msg); },
+										Ok(None) => {
+											// Nothing to do here...as with FailHTLC above, the
+											// Channel will send the update_fail_malformed_htlc
+											// once it gets their revoke_and_ack.
+										}
+									}
+								},
 							}
 						}
 
-						if !add_htlc_msgs.is_empty() || !fail_htlc_msgs.is_empty() {
+						if !add_htlc_msgs.is_empty() || !fail_htlc_msgs.is_empty() || !fail_malformed_htlc_msgs.is_empty() {
 							let (commitment_msg, monitor_update) = match chan.get_mut().send_commitment(&self.logger) {
 								Ok(res) => res,
 								Err(e) => {
@@ -1687,7 +2095,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 									update_add_htlcs: add_htlc_msgs,
 									update_fulfill_htlcs: Vec::new(),
 									update_fail_htlcs: fail_htlc_msgs,
-									update_fail_malformed_htlcs: Vec::new(),
+									update_fail_malformed_htlcs: fail_malformed_htlc_msgs,
 									update_fee: None,
 									commitment_signed: commitment_msg,
 								},
@@ -1701,11 +2109,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						match forward_info {
 							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
 									routing: PendingHTLCRouting::Receive { payment_data, incoming_cltv_expiry },
-									incoming_shared_secret, payment_hash, amt_to_forward, .. }, } => {
+									incoming_shared_secret, payment_hash, amt_to_forward, blinded_failure, .. }, } => {
 								let prev_hop = HTLCPreviousHopData {
 									short_channel_id: prev_short_channel_id,
 									htlc_id: prev_htlc_id,
 									incoming_packet_shared_secret: incoming_shared_secret,
+									blinded_failure,
 								};
 
 								let mut total_value = 0;
@@ -1740,6 +2149,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 													short_channel_id: htlc.prev_hop.short_channel_id,
 													htlc_id: htlc.prev_hop.htlc_id,
 													incoming_packet_shared_secret: htlc.prev_hop.incoming_packet_shared_secret,
+													blinded_failure: htlc.prev_hop.blinded_failure,
 												}), payment_hash,
 												HTLCFailReason::Reason { failure_code: 0x4000 | 15, data: htlc_msat_height_data }
 											));
@@ -1759,10 +2169,29 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 									});
 								}
 							},
+							HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info: PendingHTLCInfo {
+									routing: PendingHTLCRouting::TrampolineForward { trampoline_packet, outgoing_node_id, incoming_amt_msat, incoming_cltv_expiry },
+									incoming_shared_secret, payment_hash, amt_to_forward, outgoing_cltv_value, blinded_failure }, } => {
+								channel_state.trampoline_htlcs.push(TrampolineHTLC {
+									prev_hop: HTLCPreviousHopData {
+										short_channel_id: prev_short_channel_id,
+										htlc_id: prev_htlc_id,
+										incoming_packet_shared_secret: incoming_shared_secret,
+										blinded_failure,
+									},
+									payment_hash,
+									value: incoming_amt_msat,
+									cltv_expiry: incoming_cltv_expiry,
+									outgoing_node_id,
+									trampoline_packet,
+									amt_to_forward,
+									outgoing_cltv_value,
+								});
+							},
 							HTLCForwardInfo::AddHTLC { .. } => {
-								panic!("short_channel_id == 0 should imply any pending_forward entries are of type Receive");
+								panic!("short_channel_id == 0 should imply any pending_forward entries are of type Receive or TrampolineForward");
 							},
-							HTLCForwardInfo::FailHTLC { .. } => {
+							HTLCForwardInfo::FailHTLC { .. } | HTLCForwardInfo::FailMalformedHTLC { .. } => {
 								panic!("Got pending fail of our own HTLC");
 							}
 						}
@@ -1784,6 +2213,77 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		events.append(&mut new_events);
 	}
 
+	/// Finds routes for, and forwards, the HTLCs we received as a trampoline node. As we do not
+	/// hold the network graph ourselves, it must be provided, eg from a NetGraphMsgHandler.
+	///
+	/// Should be called after each call to process_pending_htlc_forwards if you wish to act as a
+	/// trampoline node. The fee and CLTV delta left to us by the sender must cover both the route
+	/// we find and our own forwarding fee and CLTV delta, otherwise the HTLC is failed back with
+	/// trampoline_fee_insufficient or trampoline_expiry_too_soon.
+	pub fn process_pending_trampoline_forwards(&self, network_graph: &NetworkGraph) {
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+
+		let mut trampoline_htlcs = mem::replace(&mut self.channel_state.lock().unwrap().trampoline_htlcs, Vec::new());
+		if trampoline_htlcs.is_empty() { return; }
+
+		let our_node_id = self.get_our_node_id();
+		let first_hops = self.list_usable_channels();
+		let first_hop_refs: Vec<_> = first_hops.iter().collect();
+		let cur_height = self.latest_block_height.load(Ordering::Acquire) as u32 + 1;
+
+		let mut failed_forwards = Vec::new();
+		for htlc in trampoline_htlcs.drain(..) {
+			macro_rules! fail_forward {
+				($msg: expr, $err_code: expr) => {
+					{
+						log_info!(self.logger, "Failed to forward trampoline HTLC with payment_hash {}: {}", log_bytes!(htlc.payment_hash.0), $msg);
+						failed_forwards.push((HTLCSource::PreviousHopData(htlc.prev_hop), htlc.payment_hash,
+							HTLCFailReason::Reason { failure_code: $err_code, data: Vec::new() }));
+						continue;
+					}
+				}
+			}
+
+			// Give ourselves the same headroom as we require of HTLCs forwarded over our channels.
+			if htlc.outgoing_cltv_value <= cur_height + HTLC_FAIL_BACK_BUFFER {
+				fail_forward!("Outgoing CLTV value is too soon", 0x2000 | 52);
+			}
+			let route = match get_route(&our_node_id, network_graph, &htlc.outgoing_node_id, Some(&first_hop_refs[..]), &[],
+					htlc.amt_to_forward, htlc.outgoing_cltv_value - cur_height, &*self.logger) {
+				Ok(route) => route,
+				Err(e) => fail_forward!(e.err, 0x2000 | 2),
+			};
+			let path = &route.paths[0];
+			let route_msat = path.iter().fold(0, |total, hop| total + hop.fee_msat);
+			let route_cltv = path.iter().fold(cur_height, |total, hop| total + hop.cltv_expiry_delta);
+
+			let our_fee = {
+				let channel_state = self.channel_state.lock().unwrap();
+				match channel_state.short_to_id.get(&path[0].short_channel_id).and_then(|id| channel_state.by_id.get(id)) {
+					Some(chan) => htlc.amt_to_forward.checked_mul(chan.get_fee_proportional_millionths() as u64)
+						.and_then(|prop_fee| (prop_fee / 1000000).checked_add(chan.get_holder_fee_base_msat(&self.fee_estimator) as u64)),
+					None => fail_forward!("Channel for the first hop of our route went away", 0x2000 | 2),
+				}
+			};
+			if our_fee.and_then(|fee| fee.checked_add(route_msat)).map(|total| total > htlc.value).unwrap_or(true) {
+				fail_forward!("Not enough fee left to route the trampoline payment", 0x2000 | 51);
+			}
+			if (htlc.cltv_expiry as u64) < route_cltv as u64 + CLTV_EXPIRY_DELTA as u64 {
+				fail_forward!("Not enough CLTV delta left to route the trampoline payment", 0x2000 | 52);
+			}
+
+			match self.send_htlc_along_path(path, &htlc.payment_hash, &None, htlc.amt_to_forward, cur_height, Some(htlc.trampoline_packet.clone()), Some(htlc.prev_hop.clone()), None) {
+				// On monitor update failure the HTLC is sent once the monitor is restored.
+				Ok(()) | Err(APIError::MonitorUpdateFailed) => {},
+				Err(e) => fail_forward!(format!("{:?}", e), 0x2000 | 2),
+			}
+		}
+
+		for (htlc_source, payment_hash, failure_reason) in failed_forwards.drain(..) {
+			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), htlc_source, &payment_hash, failure_reason);
+		}
+	}
+
 	/// If a peer is disconnected we mark any channels with that peer as 'disabled'.
 	/// After some time, if channels are still disabled we need to broadcast a ChannelUpdate
 	/// to inform the network about the uselessness of these channels.
@@ -1840,7 +2340,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	fn fail_holding_cell_htlcs(&self, mut htlcs_to_fail: Vec<(HTLCSource, PaymentHash)>, channel_id: [u8; 32]) {
 		for (htlc_src, payment_hash) in htlcs_to_fail.drain(..) {
 			match htlc_src {
-				HTLCSource::PreviousHopData(HTLCPreviousHopData { .. }) => {
+				HTLCSource::PreviousHopData(HTLCPreviousHopData { .. }) |
+				HTLCSource::TrampolineRoute { .. } => {
 					let (failure_code, onion_failure_data) =
 						match self.channel_state.lock().unwrap().by_id.entry(channel_id) {
 							hash_map::Entry::Occupied(chan_entry) => {
@@ -1856,11 +2357,23 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					self.fail_htlc_backwards_internal(channel_state,
 						htlc_src, &payment_hash, HTLCFailReason::Reason { failure_code, data: onion_failure_data});
 				},
-				HTLCSource::OutboundRoute { .. } => {
+				HTLCSource::OutboundRoute { ref path, .. } => {
+					if self.is_probe_payment_hash(&payment_hash) {
+						self.pending_events.lock().unwrap().push(
+							events::Event::ProbeFailed {
+								payment_hash,
+								path: path.clone(),
+								short_channel_id: Some(path[0].short_channel_id),
+							}
+						);
+						continue;
+					}
 					self.pending_events.lock().unwrap().push(
 						events::Event::PaymentFailed {
 							payment_hash,
 							rejected_by_dest: false,
+							path: path.clone(),
+							network_update: None,
 #[cfg(test)]
 							error_code: None,
 #[cfg(test)]
@@ -1892,21 +2405,40 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 #[cfg(test)]
 						let (channel_update, payment_retryable, onion_error_code, onion_error_data) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
 #[cfg(not(test))]
-						let (channel_update, payment_retryable, _, _) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
+						let (channel_update, payment_retryable, onion_error_code, _) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &source, err.data.clone());
+						let failed_scid = match channel_update {
+							Some(msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg }) => Some(msg.contents.short_channel_id),
+							Some(msgs::HTLCFailChannelUpdate::ChannelClosed { short_channel_id, .. }) => Some(short_channel_id),
+							_ => None,
+						};
 						// TODO: If we decided to blame ourselves (or one of our channels) in
 						// process_onion_failure we should close that channel as it implies our
 						// next-hop is needlessly blaming us!
-						if let Some(update) = channel_update {
+						if let Some(ref update) = channel_update {
 							self.channel_state.lock().unwrap().pending_msg_events.push(
 								events::MessageSendEvent::PaymentFailureNetworkUpdate {
-									update,
+									update: update.clone(),
 								}
 							);
 						}
+						if self.is_probe_payment_hash(payment_hash) {
+							// Only the destination can fail a probe with
+							// incorrect_or_unknown_payment_details without it being retryable,
+							// which means the probe made it all the way.
+							let event = if onion_error_code == Some(0x4000 | 15) && !payment_retryable {
+								events::Event::ProbeSuccessful { payment_hash: *payment_hash, path: path.clone() }
+							} else {
+								events::Event::ProbeFailed { payment_hash: *payment_hash, path: path.clone(), short_channel_id: failed_scid }
+							};
+							self.pending_events.lock().unwrap().push(event);
+							return;
+						}
 						self.pending_events.lock().unwrap().push(
 							events::Event::PaymentFailed {
 								payment_hash: payment_hash.clone(),
 								rejected_by_dest: !payment_retryable,
+								path: path.clone(),
+								network_update: channel_update,
 #[cfg(test)]
 								error_code: onion_error_code,
 #[cfg(test)]
@@ -1927,10 +2459,22 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						// ChannelDetails.
 						// TODO: For non-temporary failures, we really should be closing the
 						// channel here as we apparently can't relay through them anyway.
+						if self.is_probe_payment_hash(payment_hash) {
+							self.pending_events.lock().unwrap().push(
+								events::Event::ProbeFailed {
+									payment_hash: *payment_hash,
+									path: path.clone(),
+									short_channel_id: Some(path[0].short_channel_id),
+								}
+							);
+							return;
+						}
 						self.pending_events.lock().unwrap().push(
 							events::Event::PaymentFailed {
 								payment_hash: payment_hash.clone(),
 								rejected_by_dest: path.len() == 1,
+								path: path.clone(),
+								network_update: None,
 #[cfg(test)]
 								error_code: Some(*failure_code),
 #[cfg(test)]
@@ -1940,17 +2484,60 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					}
 				}
 			},
-			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret }) => {
-				let err_packet = match onion_error {
-					HTLCFailReason::Reason { failure_code, data } => {
-						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards from us with code {}", log_bytes!(payment_hash.0), failure_code);
-						let packet = onion_utils::build_failure_packet(&incoming_packet_shared_secret, failure_code, &data[..]).encode();
-						onion_utils::encrypt_failure_packet(&incoming_packet_shared_secret, &packet)
-					},
+			HTLCSource::TrampolineRoute { previous_hop, path, session_priv, first_hop_htlc_msat } => {
+				log_trace!(self.logger, "Failing trampoline HTLC with payment_hash {} backwards", log_bytes!(payment_hash.0));
+				mem::drop(channel_state_lock);
+				let passed_through_failure = match onion_error {
 					HTLCFailReason::LightningError { err } => {
-						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards with pre-built LightningError", log_bytes!(payment_hash.0));
-						onion_utils::encrypt_failure_packet(&incoming_packet_shared_secret, &err.data)
-					}
+						let outbound_source = HTLCSource::OutboundRoute { path, session_priv, first_hop_htlc_msat };
+						let (channel_update, payment_retryable, onion_error_code, onion_error_data) = onion_utils::process_onion_failure(&self.secp_ctx, &self.logger, &outbound_source, err.data);
+						if let Some(update) = channel_update {
+							self.channel_state.lock().unwrap().pending_msg_events.push(
+								events::MessageSendEvent::PaymentFailureNetworkUpdate {
+									update,
+								}
+							);
+						}
+						// The next trampoline node (or the destination) rejecting the payment is
+						// passed back as-is, as is a next trampoline node asking for a larger
+						// fee or CLTV budget. Anything else is our own failure to route.
+						match (onion_error_code, onion_error_data) {
+							(Some(code), Some(data)) if !payment_retryable || code == 0x2000 | 51 || code == 0x2000 | 52 => Some((code, data)),
+							_ => None,
+						}
+					},
+					HTLCFailReason::Reason { .. } => None,
+				};
+				let (failure_code, data) = passed_through_failure.unwrap_or((0x2000 | 2, Vec::new()));
+				self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), HTLCSource::PreviousHopData(previous_hop), payment_hash, HTLCFailReason::Reason { failure_code, data });
+			},
+			HTLCSource::PreviousHopData(HTLCPreviousHopData { short_channel_id, htlc_id, incoming_packet_shared_secret, blinded_failure }) => {
+				// Failures within a blinded route would let the sender probe its hops, so we only
+				// ever tell them that the blinded route failed as a whole.
+				let failure = match blinded_failure {
+					Some(BlindedFailure::FromBlindedNode) => {
+						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards within a blinded route", log_bytes!(payment_hash.0));
+						HTLCForwardInfo::FailMalformedHTLC { htlc_id, failure_code: 0x8000 | 0x4000 | 24, sha256_of_onion: [0; 32] }
+					},
+					Some(BlindedFailure::FromIntroductionNode) => {
+						log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards from the introduction node of a blinded route", log_bytes!(payment_hash.0));
+						let packet = onion_utils::build_failure_packet(&incoming_packet_shared_secret, 0x8000 | 0x4000 | 24, &[0; 32]).encode();
+						HTLCForwardInfo::FailHTLC { htlc_id, err_packet: onion_utils::encrypt_failure_packet(&incoming_packet_shared_secret, &packet) }
+					},
+					None => {
+						let err_packet = match onion_error {
+							HTLCFailReason::Reason { failure_code, data } => {
+								log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards from us with code {}", log_bytes!(payment_hash.0), failure_code);
+								let packet = onion_utils::build_failure_packet(&incoming_packet_shared_secret, failure_code, &data[..]).encode();
+								onion_utils::encrypt_failure_packet(&incoming_packet_shared_secret, &packet)
+							},
+							HTLCFailReason::LightningError { err } => {
+								log_trace!(self.logger, "Failing HTLC with payment_hash {} backwards with pre-built LightningError", log_bytes!(payment_hash.0));
+								onion_utils::encrypt_failure_packet(&incoming_packet_shared_secret, &err.data)
+							}
+						};
+						HTLCForwardInfo::FailHTLC { htlc_id, err_packet }
+					},
 				};
 
 				let mut forward_event = None;
@@ -1959,10 +2546,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				}
 				match channel_state_lock.forward_htlcs.entry(short_channel_id) {
 					hash_map::Entry::Occupied(mut entry) => {
-						entry.get_mut().push(HTLCForwardInfo::FailHTLC { htlc_id, err_packet });
+						entry.get_mut().push(failure);
 					},
 					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec!(HTLCForwardInfo::FailHTLC { htlc_id, err_packet }));
+						entry.insert(vec!(failure));
 					}
 				}
 				mem::drop(channel_state_lock);
@@ -2127,14 +2714,15 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 	fn claim_funds_internal(&self, mut channel_state_lock: MutexGuard<ChannelHolder<ChanSigner>>, source: HTLCSource, payment_preimage: PaymentPreimage) {
 		match source {
-			HTLCSource::OutboundRoute { .. } => {
+			HTLCSource::OutboundRoute { path, .. } => {
 				mem::drop(channel_state_lock);
 				let mut pending_events = self.pending_events.lock().unwrap();
 				pending_events.push(events::Event::PaymentSent {
-					payment_preimage
+					payment_preimage,
+					path,
 				});
 			},
-			HTLCSource::PreviousHopData(hop_data) => {
+			HTLCSource::PreviousHopData(hop_data) | HTLCSource::TrampolineRoute { previous_hop: hop_data, .. } => {
 				if let Err((counterparty_node_id, err)) = match self.claim_funds_from_hop(&mut channel_state_lock, hop_data, payment_preimage) {
 					Ok(()) => Ok(()),
 					Err(None) => {
@@ -2199,7 +2787,13 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				return;
 			}
 
-			let (raa, commitment_update, order, pending_forwards, mut pending_failures, needs_broadcast_safe, funding_locked) = channel.monitor_updating_restored(&self.logger);
+			let (raa, commitment_update, order, pending_forwards, mut pending_failures, needs_broadcast_safe, funding_locked) = match channel.monitor_updating_restored(&self.logger) {
+				Ok(res) => res,
+				Err(e) => {
+					log_error!(self.logger, "Failed to restore channel {} after monitor update: {:?}", log_bytes!(channel.channel_id()), e);
+					return;
+				},
+			};
 			if !pending_forwards.is_empty() {
 				htlc_forwards.push((channel.get_short_channel_id().expect("We can't have pending forwards before funding confirmation"), pending_forwards));
 			}
@@ -2271,6 +2865,8 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 
 		let channel = Channel::new_from_req(&self.fee_estimator, &self.keys_manager, counterparty_node_id.clone(), their_features, msg, 0, &self.default_configuration)
 			.map_err(|e| MsgHandleErrInternal::from_chan_no_close(e, msg.temporary_channel_id))?;
+		let accept_msg = channel.get_accept_channel()
+			.map_err(|e| MsgHandleErrInternal::from_chan_no_close(e, msg.temporary_channel_id))?;
 		let mut channel_state_lock = self.channel_state.lock().unwrap();
 		let channel_state = &mut *channel_state_lock;
 		match channel_state.by_id.entry(channel.channel_id()) {
@@ -2278,7 +2874,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			hash_map::Entry::Vacant(entry) => {
 				channel_state.pending_msg_events.push(events::MessageSendEvent::SendAcceptChannel {
 					node_id: counterparty_node_id.clone(),
-					msg: channel.get_accept_channel(),
+					msg: accept_msg,
 				});
 				entry.insert(channel);
 			}
@@ -2423,6 +3019,10 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 	}
 
 	fn internal_shutdown(&self, counterparty_node_id: &PublicKey, msg: &msgs::Shutdown) -> Result<(), MsgHandleErrInternal> {
+		let their_features = match self.per_peer_state.read().unwrap().get(counterparty_node_id) {
+			Some(peer_state) => peer_state.lock().unwrap().latest_features.clone(),
+			None => InitFeatures::empty(),
+		};
 		let (mut dropped_htlcs, chan_option) = {
 			let mut channel_state_lock = self.channel_state.lock().unwrap();
 			let channel_state = &mut *channel_state_lock;
@@ -2432,7 +3032,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					if chan_entry.get().get_counterparty_node_id() != *counterparty_node_id {
 						return Err(MsgHandleErrInternal::send_err_msg_no_close("Got a message for a channel from the wrong node!".to_owned(), msg.channel_id));
 					}
-					let (shutdown, closing_signed, dropped_htlcs) = try_chan_entry!(self, chan_entry.get_mut().shutdown(&self.fee_estimator, &msg), channel_state, chan_entry);
+					let (shutdown, closing_signed, dropped_htlcs) = try_chan_entry!(self, chan_entry.get_mut().shutdown(&self.fee_estimator, &their_features, &msg), channel_state, chan_entry);
 					if let Some(msg) = shutdown {
 						channel_state.pending_msg_events.push(events::MessageSendEvent::SendShutdown {
 							node_id: counterparty_node_id.clone(),
@@ -2542,8 +3142,18 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					// but if we've sent a shutdown and they haven't acknowledged it yet, we just
 					// want to reject the new HTLC and fail it backwards instead of forwarding.
 					match pending_forward_info {
-						PendingHTLCStatus::Forward(PendingHTLCInfo { ref incoming_shared_secret, .. }) => {
-							let reason = if let Ok(upd) = self.get_channel_update(chan) {
+						PendingHTLCStatus::Forward(PendingHTLCInfo { blinded_failure: Some(BlindedFailure::FromBlindedNode), .. }) => {
+							PendingHTLCStatus::Fail(HTLCFailureMsg::Malformed(msgs::UpdateFailMalformedHTLC {
+								channel_id: msg.channel_id,
+								htlc_id: msg.htlc_id,
+								sha256_of_onion: Sha256::hash(&msg.onion_routing_packet.hop_data).into_inner(),
+								failure_code: 0x8000 | 0x4000 | 24,
+							}))
+						},
+						PendingHTLCStatus::Forward(PendingHTLCInfo { ref incoming_shared_secret, ref blinded_failure, .. }) => {
+							let reason = if blinded_failure.is_some() {
+								onion_utils::build_first_hop_failure_packet(incoming_shared_secret, 0x8000 | 0x4000 | 24, &[0; 32])
+							} else if let Ok(upd) = self.get_channel_update(chan) {
 								onion_utils::build_first_hop_failure_packet(incoming_shared_secret, error_code, &{
 									let mut res = Vec::with_capacity(8 + 128);
 									// TODO: underspecified, follow https://github.com/lightningnetwork/lightning-rfc/issues/791
@@ -2697,6 +3307,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 					match channel_state.forward_htlcs.entry(match forward_info.routing {
 							PendingHTLCRouting::Forward { short_channel_id, .. } => short_channel_id,
 							PendingHTLCRouting::Receive { .. } => 0,
+							PendingHTLCRouting::TrampolineForward { .. } => 0,
 					}) {
 						hash_map::Entry::Occupied(mut entry) => {
 							entry.get_mut().push(HTLCForwardInfo::AddHTLC { prev_short_channel_id, prev_htlc_id, forward_info });
@@ -3064,6 +3675,112 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		let header_hash = header.block_hash();
 		log_trace!(self.logger, "Block {} at height {} connected", header_hash, height);
 		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		self.do_chain_event(Some(txdata), Some(height), |channel| {
+			let res = channel.block_connected(header, txdata, height);
+			// Only a bogus funding transaction shuts the channel down immediately, any other error
+			// means it was un-confirmed and must be force-closed.
+			res.map_err(|e| if channel.is_shutdown() { Some(e) } else { None })
+		});
+		self.update_best_block(header, height);
+	}
+
+	/// Updates channel state based on a disconnected block.
+	///
+	/// If necessary, the channel may be force-closed without letting the counterparty participate
+	/// in the shutdown.
+	pub fn block_disconnected(&self, header: &BlockHeader) {
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		let new_height = self.latest_block_height.load(Ordering::Acquire) as u32 - 1;
+		self.do_chain_event(None, None, |channel| {
+			if channel.block_disconnected(header, new_height) {
+				Err(None)
+			} else {
+				Ok((None, Vec::new()))
+			}
+		});
+		self.latest_block_height.fetch_sub(1, Ordering::AcqRel);
+		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header.block_hash();
+	}
+
+	/// Updates channel state based on transactions confirmed in the block with the given header and
+	/// height, for clients which are notified of individual transactions rather than full blocks
+	/// (e.g. ones backed by an Electrum or Esplora server).
+	///
+	/// Only transactions relevant to our channels need be provided, i.e. funding transactions and
+	/// transactions spending them, as registered with the [`chain::Filter`] by the [`chain::Watch`]
+	/// implementation. Transactions may be provided for blocks which are not the best block, and
+	/// [`best_block_updated`] must additionally be called whenever the chain tip changes.
+	///
+	/// [`chain::Filter`]: ../../chain/trait.Filter.html
+	/// [`chain::Watch`]: ../../chain/trait.Watch.html
+	/// [`best_block_updated`]: #method.best_block_updated
+	pub fn transactions_confirmed(&self, header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		log_trace!(self.logger, "{} transactions included in block {} at height {} provided", txdata.len(), header.block_hash(), height);
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		self.do_chain_event(Some(txdata), None, |channel| {
+			channel.transactions_confirmed(txdata, height).map(|funding_locked| (funding_locked, Vec::new())).map_err(|e| Some(e))
+		});
+	}
+
+	/// Updates channel state based on a transaction having been reorganized out of the chain, for
+	/// clients which are notified of individual transactions rather than full blocks.
+	///
+	/// If the transaction is the funding transaction of a channel which we had already considered
+	/// locked in, the channel is force-closed without letting the counterparty participate in the
+	/// shutdown. See [`get_relevant_txids`] for the transactions which must be checked.
+	///
+	/// [`get_relevant_txids`]: #method.get_relevant_txids
+	pub fn transaction_unconfirmed(&self, txid: &Txid) {
+		log_trace!(self.logger, "Transaction {} unconfirmed", txid);
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		self.do_chain_event(None, None, |channel| {
+			if let Some(funding_txo) = channel.get_funding_txo() {
+				if funding_txo.txid == *txid {
+					return channel.funding_transaction_unconfirmed().map(|_| (None, Vec::new())).map_err(|_| None);
+				}
+			}
+			Ok((None, Vec::new()))
+		});
+	}
+
+	/// Updates channel state based on a new best chain tip, for clients which are notified of
+	/// individual transactions rather than full blocks. This may time out HTLCs, send
+	/// funding_locked messages once funding transactions are buried deep enough or, if the new tip
+	/// is lower than the previous one, force-close channels whose funding transactions were
+	/// reorganized out.
+	pub fn best_block_updated(&self, header: &BlockHeader, height: u32) {
+		log_trace!(self.logger, "New best block {} at height {}", header.block_hash(), height);
+		let _consistency_lock = self.total_consistency_lock.read().unwrap();
+		self.do_chain_event(None, Some(height), |channel| channel.update_best_block(header, height).map_err(|_| None));
+		self.update_best_block(header, height);
+	}
+
+	/// Returns the txids of confirmed funding transactions of our channels, which should be checked
+	/// for reorganization out of the chain and passed to [`transaction_unconfirmed`] if so.
+	///
+	/// [`transaction_unconfirmed`]: #method.transaction_unconfirmed
+	pub fn get_relevant_txids(&self) -> Vec<Txid> {
+		let channel_state = self.channel_state.lock().unwrap();
+		let mut res = Vec::with_capacity(channel_state.short_to_id.len());
+		for chan in channel_state.by_id.values() {
+			if let (Some(funding_txo), Some(_)) = (chan.get_funding_txo(), chan.get_funding_tx_confirmation_height()) {
+				res.push(funding_txo.txid);
+			}
+		}
+		res
+	}
+
+	/// Applies a chain event to each channel via `f`, which returns either any funding_locked to
+	/// send and HTLCs to fail backwards, or an error if the channel must be closed. An error with an
+	/// `ErrorMessage` indicates the channel was already shut down and the counterparty should be
+	/// told, otherwise the channel is force-closed.
+	///
+	/// If `txdata` is provided, channels whose funding outputs are spent by it are force-closed.
+	/// If `height` is provided, claimable HTLCs which are too close to expiry are failed backwards.
+	fn do_chain_event<FN>(&self, txdata: Option<&TransactionData>, height: Option<u32>, f: FN)
+	where
+		FN: Fn(&mut Channel<ChanSigner>) -> Result<(Option<msgs::FundingLocked>, Vec<(HTLCSource, PaymentHash)>), Option<msgs::ErrorMessage>>
+	{
 		let mut failed_channels = Vec::new();
 		let mut timed_out_htlcs = Vec::new();
 		{
@@ -3072,7 +3789,7 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			let short_to_id = &mut channel_state.short_to_id;
 			let pending_msg_events = &mut channel_state.pending_msg_events;
 			channel_state.by_id.retain(|_, channel| {
-				let res = channel.block_connected(header, txdata, height);
+				let res = f(channel);
 				if let Ok((chan_res, mut timed_out_pending_htlcs)) = res {
 					for (source, payment_hash) in timed_out_pending_htlcs.drain(..) {
 						let chan_update = self.get_channel_update(&channel).map(|u| u.encode_with_len()).unwrap(); // Cannot add/recv HTLCs before we have a short_id so unwrap is safe
@@ -3097,14 +3814,26 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 						}
 						short_to_id.insert(channel.get_short_channel_id().unwrap(), channel.channel_id());
 					}
-				} else if let Err(e) = res {
+				} else if let Err(Some(e)) = res {
 					pending_msg_events.push(events::MessageSendEvent::HandleError {
 						node_id: channel.get_counterparty_node_id(),
 						action: msgs::ErrorAction::SendErrorMessage { msg: e },
 					});
 					return false;
+				} else {
+					// The funding transaction was reorged out after we'd considered it locked in.
+					if let Some(short_id) = channel.get_short_channel_id() {
+						short_to_id.remove(&short_id);
+					}
+					failed_channels.push(channel.force_shutdown(true));
+					if let Ok(update) = self.get_channel_update(&channel) {
+						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
+							msg: update
+						});
+					}
+					return false;
 				}
-				if let Some(funding_txo) = channel.get_funding_txo() {
+				if let (Some(funding_txo), Some(txdata)) = (channel.get_funding_txo(), txdata) {
 					for &(_, tx) in txdata.iter() {
 						for inp in tx.input.iter() {
 							if inp.previous_output == funding_txo.into_bitcoin_outpoint() {
@@ -3129,24 +3858,37 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 				true
 			});
 
-			channel_state.claimable_htlcs.retain(|&(ref payment_hash, _), htlcs| {
-				htlcs.retain(|htlc| {
-					// If height is approaching the number of blocks we think it takes us to get
-					// our commitment transaction confirmed before the HTLC expires, plus the
-					// number of blocks we generally consider it to take to do a commitment update,
-					// just give up on it and fail the HTLC.
+			if let Some(height) = height {
+				channel_state.claimable_htlcs.retain(|&(ref payment_hash, _), htlcs| {
+					htlcs.retain(|htlc| {
+						// If height is approaching the number of blocks we think it takes us to get
+						// our commitment transaction confirmed before the HTLC expires, plus the
+						// number of blocks we generally consider it to take to do a commitment update,
+						// just give up on it and fail the HTLC.
+						if height >= htlc.cltv_expiry - HTLC_FAIL_BACK_BUFFER {
+							let mut htlc_msat_height_data = byte_utils::be64_to_array(htlc.value).to_vec();
+							htlc_msat_height_data.extend_from_slice(&byte_utils::be32_to_array(height));
+							timed_out_htlcs.push((HTLCSource::PreviousHopData(htlc.prev_hop.clone()), payment_hash.clone(), HTLCFailReason::Reason {
+								failure_code: 0x4000 | 15,
+								data: htlc_msat_height_data
+							}));
+							false
+						} else { true }
+					});
+					!htlcs.is_empty() // Only retain this entry if htlcs has at least one entry.
+				});
+				channel_state.trampoline_htlcs.retain(|htlc| {
+					// As for claimable HTLCs, give up on trampoline HTLCs we haven't found a route
+					// for before we'd have to go on-chain to fail them.
 					if height >= htlc.cltv_expiry - HTLC_FAIL_BACK_BUFFER {
-						let mut htlc_msat_height_data = byte_utils::be64_to_array(htlc.value).to_vec();
-						htlc_msat_height_data.extend_from_slice(&byte_utils::be32_to_array(height));
-						timed_out_htlcs.push((HTLCSource::PreviousHopData(htlc.prev_hop.clone()), payment_hash.clone(), HTLCFailReason::Reason {
-							failure_code: 0x4000 | 15,
-							data: htlc_msat_height_data
+						timed_out_htlcs.push((HTLCSource::PreviousHopData(htlc.prev_hop.clone()), htlc.payment_hash, HTLCFailReason::Reason {
+							failure_code: 0x2000 | 2,
+							data: Vec::new(),
 						}));
 						false
 					} else { true }
 				});
-				!htlcs.is_empty() // Only retain this entry if htlcs has at least one entry.
-			});
+			}
 		}
 		for failure in failed_channels.drain(..) {
 			self.finish_force_close_channel(failure);
@@ -3155,8 +3897,12 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 		for (source, payment_hash, reason) in timed_out_htlcs.drain(..) {
 			self.fail_htlc_backwards_internal(self.channel_state.lock().unwrap(), source, &payment_hash, reason);
 		}
+	}
+
+	/// Records the new best block after its events have been applied to each channel.
+	fn update_best_block(&self, header: &BlockHeader, height: u32) {
 		self.latest_block_height.store(height as usize, Ordering::Release);
-		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header_hash;
+		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header.block_hash();
 		loop {
 			// Update last_node_announcement_serial to be the max of its current value and the
 			// block timestamp. This should keep us close to the current time without relying on
@@ -3170,42 +3916,6 @@ impl<ChanSigner: ChannelKeys, M: Deref, T: Deref, K: Deref, F: Deref, L: Deref>
 			}
 		}
 	}
-
-	/// Updates channel state based on a disconnected block.
-	///
-	/// If necessary, the channel may be force-closed without letting the counterparty participate
-	/// in the shutdown.
-	pub fn block_disconnected(&self, header: &BlockHeader) {
-		let _consistency_lock = self.total_consistency_lock.read().unwrap();
-		let mut failed_channels = Vec::new();
-		{
-			let mut channel_lock = self.channel_state.lock().unwrap();
-			let channel_state = &mut *channel_lock;
-			let short_to_id = &mut channel_state.short_to_id;
-			let pending_msg_events = &mut channel_state.pending_msg_events;
-			channel_state.by_id.retain(|_,  v| {
-				if v.block_disconnected(header) {
-					if let Some(short_id) = v.get_short_channel_id() {
-						short_to_id.remove(&short_id);
-					}
-					failed_channels.push(v.force_shutdown(true));
-					if let Ok(update) = self.get_channel_update(&v) {
-						pending_msg_events.push(events::MessageSendEvent::BroadcastChannelUpdate {
-							msg: update
-						});
-					}
-					false
-				} else {
-					true
-				}
-			});
-		}
-		for failure in failed_channels.drain(..) {
-			self.finish_force_close_channel(failure);
-		}
-		self.latest_block_height.fetch_sub(1, Ordering::AcqRel);
-		*self.last_block_hash.try_lock().expect("block_(dis)connected must not be called in parallel") = header.block_hash();
-	}
 }
 
 impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K: Deref + Sync + Send, F: Deref + Sync + Send, L: Deref + Sync + Send>
@@ -3385,6 +4095,10 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 		}
 	}
 
+	fn provided_init_features(&self) -> InitFeatures {
+		self.our_init_features()
+	}
+
 	fn peer_connected(&self, counterparty_node_id: &PublicKey, init_msg: &msgs::Init) {
 		log_debug!(self.logger, "Generating channel_reestablish events for {}", log_pubkey!(counterparty_node_id));
 
@@ -3442,49 +4156,82 @@ impl<ChanSigner: ChannelKeys, M: Deref + Sync + Send, T: Deref + Sync + Send, K:
 	}
 }
 
-const SERIALIZATION_VERSION: u8 = 1;
-const MIN_SERIALIZATION_VERSION: u8 = 1;
+// Version 2 records the HTLCs we're forwarding as a trampoline node and how to fail back the
+// HTLCs we can claim if they were received over a blinded route.
+const SERIALIZATION_VERSION: u8 = 2;
+const MIN_SERIALIZATION_VERSION: u8 = 2;
 
 impl Writeable for PendingHTLCInfo {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		// HTLCs which aren't part of a blinded route keep the layout from before route blinding,
+		// others are written with their own routing types followed by the blinded_failure.
+		let blinded = match &self.routing {
+			&PendingHTLCRouting::Forward { ref blinding_point, .. } => blinding_point.is_some() || self.blinded_failure.is_some(),
+			&PendingHTLCRouting::Receive { .. } => self.blinded_failure.is_some(),
+			&PendingHTLCRouting::TrampolineForward { .. } => true,
+		};
 		match &self.routing {
-			&PendingHTLCRouting::Forward { ref onion_packet, ref short_channel_id } => {
-				0u8.write(writer)?;
+			&PendingHTLCRouting::Forward { ref onion_packet, ref short_channel_id, ref blinding_point } => {
+				if blinded { 3u8.write(writer)?; } else { 0u8.write(writer)?; }
 				onion_packet.write(writer)?;
 				short_channel_id.write(writer)?;
+				if blinded { blinding_point.write(writer)?; }
 			},
 			&PendingHTLCRouting::Receive { ref payment_data, ref incoming_cltv_expiry } => {
-				1u8.write(writer)?;
+				if blinded { 4u8.write(writer)?; } else { 1u8.write(writer)?; }
 				payment_data.write(writer)?;
 				incoming_cltv_expiry.write(writer)?;
 			},
+			&PendingHTLCRouting::TrampolineForward { ref trampoline_packet, ref outgoing_node_id, ref incoming_amt_msat, ref incoming_cltv_expiry } => {
+				2u8.write(writer)?;
+				trampoline_packet.write(writer)?;
+				outgoing_node_id.write(writer)?;
+				incoming_amt_msat.write(writer)?;
+				incoming_cltv_expiry.write(writer)?;
+			},
 		}
 		self.incoming_shared_secret.write(writer)?;
 		self.payment_hash.write(writer)?;
 		self.amt_to_forward.write(writer)?;
 		self.outgoing_cltv_value.write(writer)?;
+		if blinded { self.blinded_failure.write(writer)?; }
 		Ok(())
 	}
 }
 
 impl Readable for PendingHTLCInfo {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<PendingHTLCInfo, DecodeError> {
-		Ok(PendingHTLCInfo {
-			routing: match Readable::read(reader)? {
-				0u8 => PendingHTLCRouting::Forward {
-					onion_packet: Readable::read(reader)?,
-					short_channel_id: Readable::read(reader)?,
-				},
-				1u8 => PendingHTLCRouting::Receive {
-					payment_data: Readable::read(reader)?,
-					incoming_cltv_expiry: Readable::read(reader)?,
-				},
-				_ => return Err(DecodeError::InvalidValue),
+		let routing_type: u8 = Readable::read(reader)?;
+		let routing = match routing_type {
+			0u8 => PendingHTLCRouting::Forward {
+				onion_packet: Readable::read(reader)?,
+				short_channel_id: Readable::read(reader)?,
+				blinding_point: None,
+			},
+			1u8|4u8 => PendingHTLCRouting::Receive {
+				payment_data: Readable::read(reader)?,
+				incoming_cltv_expiry: Readable::read(reader)?,
 			},
+			2u8 => PendingHTLCRouting::TrampolineForward {
+				trampoline_packet: Readable::read(reader)?,
+				outgoing_node_id: Readable::read(reader)?,
+				incoming_amt_msat: Readable::read(reader)?,
+				incoming_cltv_expiry: Readable::read(reader)?,
+			},
+			3u8 => PendingHTLCRouting::Forward {
+				onion_packet: Readable::read(reader)?,
+				short_channel_id: Readable::read(reader)?,
+				blinding_point: Readable::read(reader)?,
+			},
+			_ => return Err(DecodeError::InvalidValue),
+		};
+		Ok(PendingHTLCInfo {
+			routing,
 			incoming_shared_secret: Readable::read(reader)?,
 			payment_hash: Readable::read(reader)?,
 			amt_to_forward: Readable::read(reader)?,
 			outgoing_cltv_value: Readable::read(reader)?,
+			blinded_failure: if routing_type >= 2 { Readable::read(reader)? } else { None },
 		})
 	}
 }
@@ -3541,12 +4288,51 @@ impl Readable for PendingHTLCStatus {
 	}
 }
 
+impl Writeable for BlindedFailure {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match self {
+			&BlindedFailure::FromIntroductionNode => 0u8.write(writer),
+			&BlindedFailure::FromBlindedNode => 1u8.write(writer),
+		}
+	}
+}
+
+impl Readable for BlindedFailure {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<BlindedFailure, DecodeError> {
+		match <u8 as Readable>::read(reader)? {
+			0 => Ok(BlindedFailure::FromIntroductionNode),
+			1 => Ok(BlindedFailure::FromBlindedNode),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
 impl_writeable!(HTLCPreviousHopData, 0, {
 	short_channel_id,
 	htlc_id,
-	incoming_packet_shared_secret
+	incoming_packet_shared_secret,
+	blinded_failure
 });
 
+impl HTLCPreviousHopData {
+	/// Writes the fields predating route blinding, for HTLCs received outside of blinded routes
+	/// which keep the layout they were written with before.
+	fn write_without_blinded_failure<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.short_channel_id.write(writer)?;
+		self.htlc_id.write(writer)?;
+		self.incoming_packet_shared_secret.write(writer)
+	}
+
+	fn read_without_blinded_failure<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		Ok(HTLCPreviousHopData {
+			short_channel_id: Readable::read(reader)?,
+			htlc_id: Readable::read(reader)?,
+			incoming_packet_shared_secret: Readable::read(reader)?,
+			blinded_failure: None,
+		})
+	}
+}
+
 impl_writeable!(ClaimableHTLC, 0, {
 	prev_hop,
 	value,
@@ -3554,19 +4340,42 @@ impl_writeable!(ClaimableHTLC, 0, {
 	cltv_expiry
 });
 
+impl_writeable!(TrampolineHTLC, 0, {
+	prev_hop,
+	payment_hash,
+	value,
+	cltv_expiry,
+	outgoing_node_id,
+	trampoline_packet,
+	amt_to_forward,
+	outgoing_cltv_value
+});
+
 impl Writeable for HTLCSource {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
 		match self {
 			&HTLCSource::PreviousHopData(ref hop_data) => {
-				0u8.write(writer)?;
-				hop_data.write(writer)?;
+				if hop_data.blinded_failure.is_some() {
+					3u8.write(writer)?;
+					hop_data.write(writer)?;
+				} else {
+					0u8.write(writer)?;
+					hop_data.write_without_blinded_failure(writer)?;
+				}
 			},
 			&HTLCSource::OutboundRoute { ref path, ref session_priv, ref first_hop_htlc_msat } => {
 				1u8.write(writer)?;
 				path.write(writer)?;
 				session_priv.write(writer)?;
 				first_hop_htlc_msat.write(writer)?;
-			}
+			},
+			&HTLCSource::TrampolineRoute { ref previous_hop, ref path, ref session_priv, ref first_hop_htlc_msat } => {
+				2u8.write(writer)?;
+				previous_hop.write(writer)?;
+				path.write(writer)?;
+				session_priv.write(writer)?;
+				first_hop_htlc_msat.write(writer)?;
+			},
 		}
 		Ok(())
 	}
@@ -3575,12 +4384,19 @@ impl Writeable for HTLCSource {
 impl Readable for HTLCSource {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<HTLCSource, DecodeError> {
 		match <u8 as Readable>::read(reader)? {
-			0 => Ok(HTLCSource::PreviousHopData(Readable::read(reader)?)),
+			0 => Ok(HTLCSource::PreviousHopData(HTLCPreviousHopData::read_without_blinded_failure(reader)?)),
 			1 => Ok(HTLCSource::OutboundRoute {
 				path: Readable::read(reader)?,
 				session_priv: Readable::read(reader)?,
 				first_hop_htlc_msat: Readable::read(reader)?,
 			}),
+			2 => Ok(HTLCSource::TrampolineRoute {
+				previous_hop: Readable::read(reader)?,
+				path: Readable::read(reader)?,
+				session_priv: Readable::read(reader)?,
+				first_hop_htlc_msat: Readable::read(reader)?,
+			}),
+			3 => Ok(HTLCSource::PreviousHopData(Readable::read(reader)?)),
 			_ => Err(DecodeError::InvalidValue),
 		}
 	}
@@ -3630,6 +4446,12 @@ impl Writeable for HTLCForwardInfo {
 				htlc_id.write(writer)?;
 				err_packet.write(writer)?;
 			},
+			&HTLCForwardInfo::FailMalformedHTLC { ref htlc_id, ref failure_code, ref sha256_of_onion } => {
+				2u8.write(writer)?;
+				htlc_id.write(writer)?;
+				failure_code.write(writer)?;
+				sha256_of_onion.write(writer)?;
+			},
 		}
 		Ok(())
 	}
@@ -3647,6 +4469,11 @@ impl Readable for HTLCForwardInfo {
 				htlc_id: Readable::read(reader)?,
 				err_packet: Readable::read(reader)?,
 			}),
+			2 => Ok(HTLCForwardInfo::FailMalformedHTLC {
+				htlc_id: Readable::read(reader)?,
+				failure_code: Readable::read(reader)?,
+				sha256_of_onion: Readable::read(reader)?,
+			}),
 			_ => Err(DecodeError::InvalidValue),
 		}
 	}
@@ -3701,6 +4528,11 @@ impl<ChanSigner: ChannelKeys + Writeable, M: Deref, T: Deref, K: Deref, F: Deref
 			}
 		}
 
+		(channel_state.trampoline_htlcs.len() as u64).write(writer)?;
+		for htlc in channel_state.trampoline_htlcs.iter() {
+			htlc.write(writer)?;
+		}
+
 		let per_peer_state = self.per_peer_state.write().unwrap();
 		(per_peer_state.len() as u64).write(writer)?;
 		for (peer_pubkey, peer_state_mutex) in per_peer_state.iter() {
@@ -3828,7 +4660,7 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
         L::Target: Logger,
 {
 	fn read<R: ::std::io::Read>(reader: &mut R, mut args: ChannelManagerReadArgs<'a, ChanSigner, M, T, K, F, L>) -> Result<Self, DecodeError> {
-		let _ver: u8 = Readable::read(reader)?;
+		let ver: u8 = Readable::read(reader)?;
 		let min_ver: u8 = Readable::read(reader)?;
 		if min_ver > SERIALIZATION_VERSION {
 			return Err(DecodeError::UnknownVersion);
@@ -3904,11 +4736,26 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 			let previous_hops_len: u64 = Readable::read(reader)?;
 			let mut previous_hops = Vec::with_capacity(cmp::min(previous_hops_len as usize, MAX_ALLOC_SIZE/mem::size_of::<ClaimableHTLC>()));
 			for _ in 0..previous_hops_len {
-				previous_hops.push(Readable::read(reader)?);
+				if ver >= 2 {
+					previous_hops.push(Readable::read(reader)?);
+				} else {
+					previous_hops.push(ClaimableHTLC {
+						prev_hop: HTLCPreviousHopData::read_without_blinded_failure(reader)?,
+						value: Readable::read(reader)?,
+						payment_data: Readable::read(reader)?,
+						cltv_expiry: Readable::read(reader)?,
+					});
+				}
 			}
 			claimable_htlcs.insert(payment_hash, previous_hops);
 		}
 
+		let trampoline_htlcs_count: u64 = if ver >= 2 { Readable::read(reader)? } else { 0 };
+		let mut trampoline_htlcs = Vec::with_capacity(cmp::min(trampoline_htlcs_count as usize, MAX_ALLOC_SIZE/mem::size_of::<TrampolineHTLC>()));
+		for _ in 0..trampoline_htlcs_count {
+			trampoline_htlcs.push(Readable::read(reader)?);
+		}
+
 		let peer_count: u64 = Readable::read(reader)?;
 		let mut per_peer_state = HashMap::with_capacity(cmp::min(peer_count as usize, MAX_ALLOC_SIZE/mem::size_of::<(PublicKey, Mutex<PeerState>)>()));
 		for _ in 0..peer_count {
@@ -3945,6 +4792,7 @@ impl<'a, ChanSigner: ChannelKeys + Readable, M: Deref, T: Deref, K: Deref, F: De
 				short_to_id,
 				forward_htlcs,
 				claimable_htlcs,
+				trampoline_htlcs,
 				pending_msg_events: Vec::new(),
 			}),
 			our_network_key: args.keys_manager.get_node_secret(),
@@ -28,6 +28,7 @@ use util::config::UserConfig;
 use util::ser::{ReadableArgs, Writeable, Readable};
 
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::transaction::{Transaction, TxOut};
 use bitcoin::network::constants::Network;
 
@@ -90,6 +91,32 @@ pub fn disconnect_block<'a, 'b, 'c, 'd>(node: &'a Node<'b, 'c, 'd>, header: &Blo
 	node.node.block_disconnected(header);
 }
 
+/// Provides the transactions of `block` via the transaction-level chain interface, as a client
+/// backed by an Electrum or Esplora server would, and sets it as the best block.
+pub fn confirm_transactions_out_of_band<'a, 'b, 'c, 'd>(node: &'a Node<'b, 'c, 'd>, block: &Block, height: u32) {
+	let txdata: Vec<_> = block.txdata.iter().enumerate().collect();
+	node.chain_monitor.chain_monitor.transactions_confirmed(&block.header, &txdata, height);
+	node.node.transactions_confirmed(&block.header, &txdata, height);
+	node.chain_monitor.chain_monitor.best_block_updated(&block.header, height);
+	node.node.best_block_updated(&block.header, height);
+}
+
+/// Reports every relevant transaction confirmed above `new_height` as unconfirmed via the
+/// transaction-level chain interface, and sets `header` at `new_height` as the best block.
+pub fn unconfirm_transactions_out_of_band<'a, 'b, 'c, 'd>(node: &'a Node<'b, 'c, 'd>, reorged_txn: &[Transaction], header: &BlockHeader, new_height: u32) {
+	let mut relevant_txids = node.chain_monitor.chain_monitor.get_relevant_txids();
+	relevant_txids.append(&mut node.node.get_relevant_txids());
+	for tx in reorged_txn {
+		let txid = tx.txid();
+		if relevant_txids.contains(&txid) {
+			node.chain_monitor.chain_monitor.transaction_unconfirmed(&txid);
+			node.node.transaction_unconfirmed(&txid);
+		}
+	}
+	node.chain_monitor.chain_monitor.best_block_updated(header, new_height);
+	node.node.best_block_updated(header, new_height);
+}
+
 pub struct TestChanMonCfg {
 	pub tx_broadcaster: test_utils::TestBroadcaster,
 	pub fee_estimator: test_utils::TestFeeEstimator,
@@ -136,7 +163,7 @@ impl<'a, 'b, 'c> Drop for Node<'a, 'b, 'c> {
 				let network_graph_deser = <NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap();
 				assert!(network_graph_deser == *self.net_graph_msg_handler.network_graph.read().unwrap());
 				let net_graph_msg_handler = NetGraphMsgHandler::from_net_graph(
-					Some(self.chain_source), self.logger, network_graph_deser
+					genesis_block(Network::Testnet).header.block_hash(), Some(self.chain_source), self.logger, network_graph_deser
 				);
 				let mut chan_progress = 0;
 				loop {
@@ -799,8 +826,9 @@ macro_rules! expect_payment_sent {
 		let events = $node.node.get_and_clear_pending_events();
 		assert_eq!(events.len(), 1);
 		match events[0] {
-			Event::PaymentSent { ref payment_preimage } => {
+			Event::PaymentSent { ref payment_preimage, ref path } => {
 				assert_eq!($expected_payment_preimage, *payment_preimage);
+				assert!(!path.is_empty());
 			},
 			_ => panic!("Unexpected event"),
 		}
@@ -812,8 +840,9 @@ macro_rules! expect_payment_failed {
 		let events = $node.node.get_and_clear_pending_events();
 		assert_eq!(events.len(), 1);
 		match events[0] {
-			Event::PaymentFailed { ref payment_hash, rejected_by_dest, ref error_code, ref error_data } => {
+			Event::PaymentFailed { ref payment_hash, rejected_by_dest, ref path, ref error_code, ref error_data, .. } => {
 				assert_eq!(*payment_hash, $expected_payment_hash);
+				assert!(!path.is_empty());
 				assert_eq!(rejected_by_dest, $rejected_by_dest);
 				assert!(error_code.is_some());
 				assert!(error_data.is_some());
@@ -1026,6 +1055,24 @@ pub fn send_payment<'a, 'b, 'c>(origin: &Node<'a, 'b, 'c>, expected_route: &[&No
 }
 
 pub fn fail_payment_along_route<'a, 'b, 'c>(origin_node: &Node<'a, 'b, 'c>, expected_route: &[&Node<'a, 'b, 'c>], skip_last: bool, our_payment_hash: PaymentHash)  {
+	pass_failed_payment_back(origin_node, expected_route, skip_last, our_payment_hash);
+
+	if !skip_last {
+		let events = origin_node.node.get_and_clear_pending_events();
+		assert_eq!(events.len(), 1);
+		match events[0] {
+			Event::PaymentFailed { payment_hash, rejected_by_dest, .. } => {
+				assert_eq!(payment_hash, our_payment_hash);
+				assert!(rejected_by_dest);
+			},
+			_ => panic!("Unexpected event"),
+		}
+	}
+}
+
+/// Fails the HTLC(s) for our_payment_hash back from the last node in expected_route to the origin
+/// node, without checking the events generated at the origin.
+pub fn pass_failed_payment_back<'a, 'b, 'c>(origin_node: &Node<'a, 'b, 'c>, expected_route: &[&Node<'a, 'b, 'c>], skip_last: bool, our_payment_hash: PaymentHash)  {
 	assert!(expected_route.last().unwrap().node.fail_htlc_backwards(&our_payment_hash, &None));
 	expect_pending_htlcs_forwardable!(expected_route.last().unwrap());
 	check_added_monitors!(expected_route.last().unwrap(), 1);
@@ -1081,16 +1128,6 @@ pub fn fail_payment_along_route<'a, 'b, 'c>(origin_node: &Node<'a, 'b, 'c>, expe
 
 	if !skip_last {
 		update_fail_dance!(origin_node, expected_route.first().unwrap(), true);
-
-		let events = origin_node.node.get_and_clear_pending_events();
-		assert_eq!(events.len(), 1);
-		match events[0] {
-			Event::PaymentFailed { payment_hash, rejected_by_dest, .. } => {
-				assert_eq!(payment_hash, our_payment_hash);
-				assert!(rejected_by_dest);
-			},
-			_ => panic!("Unexpected event"),
-		}
 	}
 }
 
@@ -1144,7 +1181,7 @@ pub fn create_network<'a, 'b: 'a, 'c: 'b>(node_count: usize, cfgs: &'b Vec<NodeC
 	let payment_count = Rc::new(RefCell::new(0));
 
 	for i in 0..node_count {
-		let net_graph_msg_handler = NetGraphMsgHandler::new(None, cfgs[i].logger);
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, cfgs[i].logger);
 		nodes.push(Node{ chain_source: cfgs[i].chain_source,
 		                 tx_broadcaster: cfgs[i].tx_broadcaster, chain_monitor: &cfgs[i].chain_monitor,
 		                 keys_manager: &cfgs[i].keys_manager, node: &chan_mgrs[i], net_graph_msg_handler,
@@ -12,18 +12,23 @@
 //! claim outputs on-chain.
 
 use chain::Watch;
+use chain::chaininterface::{ConfirmationTarget, FeeBumpPolicy};
 use chain::channelmonitor;
 use chain::channelmonitor::{ChannelMonitor, CLTV_CLAIM_BUFFER, LATENCY_GRACE_PERIOD_BLOCKS, ANTI_REORG_DELAY};
 use chain::transaction::OutPoint;
+use chain::watchtower::{CommitmentHint, MemoryBlobStore, WatchtowerClient, WatchtowerServer};
+use chain::sweeper::OutputSweeper;
 use chain::keysinterface::{ChannelKeys, KeysInterface, SpendableOutputDescriptor};
 use ln::channel::{COMMITMENT_TX_BASE_WEIGHT, COMMITMENT_TX_WEIGHT_PER_HTLC};
 use ln::channelmanager::{ChannelManager, ChannelManagerReadArgs, RAACommitmentOrder, PaymentPreimage, PaymentHash, PaymentSecret, PaymentSendFailure, BREAKDOWN_TIMEOUT};
 use ln::channel::{Channel, ChannelError};
+use ln::channelbackup::{ChannelBackupRecovery, StaticChannelBackup};
 use ln::{chan_utils, onion_utils};
-use routing::router::{Route, RouteHop, get_route};
+use routing::network_graph::RoutingFees;
+use routing::router::{Route, RouteHint, RouteHop, get_blinded_route, get_route, get_trampoline_route};
 use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 use ln::msgs;
-use ln::msgs::{ChannelMessageHandler,RoutingMessageHandler,HTLCFailChannelUpdate, ErrorAction};
+use ln::msgs::{ChannelMessageHandler,RoutingMessageHandler,HTLCFailChannelUpdate, ErrorAction, OptionalField};
 use util::enforcing_trait_impls::EnforcingChannelKeys;
 use util::{byte_utils, test_utils};
 use util::events::{Event, EventsProvider, MessageSendEvent, MessageSendEventsProvider};
@@ -907,7 +912,7 @@ fn updates_shutdown_wait() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(our_payment_preimage, *payment_preimage);
 		},
 		_ => panic!("Unexpected event"),
@@ -1080,7 +1085,7 @@ fn do_test_shutdown_rebroadcast(recv_count: u8) {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(our_payment_preimage, *payment_preimage);
 		},
 		_ => panic!("Unexpected event"),
@@ -1603,6 +1608,7 @@ fn test_fee_spike_violation_fails_htlc() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1623,7 +1629,7 @@ fn test_fee_spike_violation_fails_htlc() {
 		let chan_keys = local_chan.get_keys();
 		let pubkeys = chan_keys.pubkeys();
 		(pubkeys.revocation_basepoint, pubkeys.htlc_basepoint, pubkeys.payment_point,
-		 chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER), chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 2))
+		 chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER).unwrap(), chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 2).unwrap())
 	};
 	let (remote_delayed_payment_basepoint, remote_htlc_basepoint, remote_payment_point, remote_secret1) = {
 		let chan_lock = nodes[1].node.channel_state.lock().unwrap();
@@ -1631,7 +1637,7 @@ fn test_fee_spike_violation_fails_htlc() {
 		let chan_keys = remote_chan.get_keys();
 		let pubkeys = chan_keys.pubkeys();
 		(pubkeys.delayed_payment_basepoint, pubkeys.htlc_basepoint, pubkeys.payment_point,
-		 chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 1))
+		 chan_keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 1).unwrap())
 	};
 
 	// Assemble the set of keys we can use for signatures for our commitment_signed message.
@@ -1811,6 +1817,7 @@ fn test_chan_reserve_violation_inbound_htlc_outbound_channel() {
 		payment_hash: payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &msg);
@@ -1883,6 +1890,7 @@ fn test_chan_reserve_violation_inbound_htlc_inbound_chan() {
 		payment_hash: our_payment_hash_1,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet,
+		blinding_point: None,
 	};
 
 	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &msg);
@@ -1960,7 +1968,9 @@ fn test_channel_reserve_holding_cell_htlcs() {
 
 	// attempt to send amt_msat > their_max_htlc_value_in_flight_msat
 	{
-		let (route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_0 + 1);
+		// The router won't exceed the announced htlc_maximum_msat, so bump the amount by hand
+		let (mut route, our_payment_hash, _) = get_route_and_payment_hash!(recv_value_0);
+		route.paths[0].last_mut().unwrap().fee_msat += 1;
 		assert!(route.paths[0].iter().rev().skip(1).all(|h| h.fee_msat == feemsat));
 		unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
 			assert!(regex::Regex::new(r"Cannot send value that would put us over the max HTLC value in flight our peer will accept \(\d+\)").unwrap().is_match(err)));
@@ -2498,6 +2508,172 @@ fn channel_monitor_network_test() {
 	assert_eq!(nodes[4].node.list_channels().len(), 0);
 }
 
+#[test]
+fn test_watchtower_client_justice_blobs() {
+	// Test that a watchtower client following node 1's monitor updates hands the tower a decryptable
+	// and valid justice transaction for each of node 0's revoked commitment transactions.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let tower = test_utils::TestTower::new();
+	let destination_script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script().to_v0_p2wsh();
+	let client = WatchtowerClient::new(destination_script.clone(), &tower, &chanmon_cfgs[1].fee_estimator, &chanmon_cfgs[1].logger);
+	{
+		let monitors = nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let (_, monitor) = monitors.iter().find(|&(funding_txo, _)| funding_txo.to_channel_id() == chan.2).unwrap();
+		client.track_channel(monitor);
+	}
+	let first_update = nodes[1].chain_monitor.applied_updates.lock().unwrap().len();
+
+	// Revoke both the initial commitment transaction and one with a pending HTLC
+	let revoked_initial_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+	let revoked_htlc_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+
+	for &(ref funding_txo, ref update) in nodes[1].chain_monitor.applied_updates.lock().unwrap()[first_update..].iter() {
+		client.process_monitor_update(funding_txo, update);
+	}
+
+	assert_eq!(tower.blobs.lock().unwrap().len(), 2);
+	assert_eq!(client.get_unacknowledged_blobs().len(), 2);
+	for &(ref revoked_tx, expected_inputs) in [(&revoked_initial_txn[0], 1), (&revoked_htlc_txn[0], 2)].iter() {
+		let hint = CommitmentHint::from_txid(&revoked_tx.txid());
+		let blob = tower.blobs.lock().unwrap().iter().find(|blob| blob.hint == hint).unwrap().clone();
+		let justice_tx = blob.decrypt(&revoked_tx.txid()).unwrap();
+		assert_eq!(justice_tx.input.len(), expected_inputs);
+		assert_eq!(justice_tx.output[0].script_pubkey, destination_script);
+		check_spends!(justice_tx, revoked_tx);
+	}
+
+	// Only unacknowledged blobs are sent again
+	client.blob_acknowledged(&CommitmentHint::from_txid(&revoked_initial_txn[0].txid()));
+	assert_eq!(client.get_unacknowledged_blobs().len(), 1);
+	client.resend_unacknowledged_blobs();
+	let blobs = tower.blobs.lock().unwrap();
+	assert_eq!(blobs.len(), 3);
+	assert_eq!(blobs[2].hint, CommitmentHint::from_txid(&revoked_htlc_txn[0].txid()));
+}
+
+#[test]
+fn test_watchtower_server_broadcasts_justice_tx() {
+	// Test that a watchtower server given node 1's justice blobs broadcasts the right justice
+	// transaction once node 0's revoked commitment transaction confirms.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let tower = test_utils::TestTower::new();
+	let destination_script = Builder::new().push_opcode(opcodes::OP_TRUE).into_script().to_v0_p2wsh();
+	let client = WatchtowerClient::new(destination_script, &tower, &chanmon_cfgs[1].fee_estimator, &chanmon_cfgs[1].logger);
+	{
+		let monitors = nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let (_, monitor) = monitors.iter().find(|&(funding_txo, _)| funding_txo.to_channel_id() == chan.2).unwrap();
+		client.track_channel(monitor);
+	}
+	let first_update = nodes[1].chain_monitor.applied_updates.lock().unwrap().len();
+
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+	let revoked_local_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+	for &(ref funding_txo, ref update) in nodes[1].chain_monitor.applied_updates.lock().unwrap()[first_update..].iter() {
+		client.process_monitor_update(funding_txo, update);
+	}
+
+	let store = MemoryBlobStore::new(10);
+	let tower_broadcaster = test_utils::TestBroadcaster { txn_broadcasted: Mutex::new(Vec::new()) };
+	let server = WatchtowerServer::new(&store, &tower_broadcaster, &chanmon_cfgs[1].logger);
+	for blob in tower.blobs.lock().unwrap().drain(..) {
+		assert!(server.receive_blob(blob.clone()));
+		client.blob_acknowledged(&blob.hint);
+	}
+	assert!(client.get_unacknowledged_blobs().is_empty());
+
+	// A block without any revoked commitment transaction doesn't trigger anything.
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	assert!(server.block_connected(&header, &[(0, &chan.3)], 1).is_empty());
+
+	let justice_txn = server.block_connected(&header, &[(0, &revoked_local_txn[0])], 2);
+	assert_eq!(justice_txn.len(), 1);
+	assert_eq!(justice_txn[0].input.len(), 2); // to_local and the HTLC output
+	check_spends!(justice_txn[0], revoked_local_txn[0]);
+	assert_eq!(*tower_broadcaster.txn_broadcasted.lock().unwrap(), justice_txn);
+}
+
+#[test]
+fn test_monitor_replicas_refuse_stale_holder_commitment() {
+	// Test that of two replicas of node 0's monitor, the one which fell behind refuses to release
+	// its (revoked) holder commitment transaction until it reconciles with the other.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let mut monitor_serialized = test_utils::TestVecWriter(Vec::new());
+	nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().iter().next().unwrap().1.write_for_disk(&mut monitor_serialized).unwrap();
+	let mut replicas = Vec::new();
+	for _ in 0..2 {
+		let (_, mut replica) = <(BlockHash, ChannelMonitor<EnforcingChannelKeys>)>::read(&mut &monitor_serialized.0[..]).unwrap();
+		replica.enable_replication();
+		replicas.push(replica);
+	}
+	let first_update = nodes[0].chain_monitor.applied_updates.lock().unwrap().len();
+
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3_000_000).0;
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+	let latest_holder_txn = get_local_commitment_txn!(nodes[0], chan.2);
+
+	// Only the second replica is kept up-to-date.
+	let updates: Vec<_> = nodes[0].chain_monitor.applied_updates.lock().unwrap()[first_update..].iter().map(|&(_, ref update)| update.clone()).collect();
+	for update in updates.iter() {
+		replicas[1].update_monitor(update.clone(), &nodes[0].tx_broadcaster, &nodes[0].logger).unwrap();
+	}
+	let latest_update_id = replicas[1].get_latest_update_id();
+	assert!(!replicas[1].is_stale_replica());
+
+	// Until it learns of the newer update, the first replica would happily sign a revoked state.
+	assert!(!replicas[0].is_stale_replica());
+	replicas[0].note_latest_update_id(latest_update_id);
+	assert!(replicas[0].is_stale_replica());
+	assert!(replicas[0].get_latest_holder_commitment_txn(&nodes[0].logger).is_empty());
+
+	// Updates which leave a gap are refused.
+	let stale_update_id = replicas[0].get_latest_update_id();
+	let missing_first = replicas[1].get_updates_after(stale_update_id + 1);
+	assert!(replicas[0].reconcile(&missing_first, &nodes[0].tx_broadcaster, &nodes[0].logger).is_err());
+	assert_eq!(replicas[0].get_latest_update_id(), stale_update_id);
+
+	let missing = replicas[1].get_updates_after(stale_update_id);
+	assert_eq!(missing.len(), updates.len());
+	replicas[0].reconcile(&missing, &nodes[0].tx_broadcaster, &nodes[0].logger).unwrap();
+	assert_eq!(replicas[0].get_latest_update_id(), latest_update_id);
+	assert!(!replicas[0].is_stale_replica());
+	let holder_txn = replicas[0].get_latest_holder_commitment_txn(&nodes[0].logger);
+	assert_eq!(holder_txn[0].txid(), latest_holder_txn[0].txid());
+
+	// Replication state survives serialization, and pruning drops exchanged updates.
+	let mut replica_serialized = test_utils::TestVecWriter(Vec::new());
+	replicas[0].write_for_disk(&mut replica_serialized).unwrap();
+	let (_, mut replica_read) = <(BlockHash, ChannelMonitor<EnforcingChannelKeys>)>::read(&mut &replica_serialized.0[..]).unwrap();
+	assert!(replica_read == replicas[0]);
+	replica_read.prune_updates(latest_update_id);
+	assert!(replica_read.get_updates_after(stale_update_id).is_empty());
+
+	// An update which fails to apply isn't handed out to other replicas.
+	let bad_update = channelmonitor::ChannelMonitorUpdate {
+		update_id: latest_update_id + 1,
+		updates: vec![channelmonitor::ChannelMonitorUpdateStep::CommitmentSecret { idx: 0, secret: [42; 32] }],
+	};
+	assert!(replica_read.update_monitor(bad_update, &nodes[0].tx_broadcaster, &nodes[0].logger).is_err());
+	assert!(replica_read.get_updates_after(latest_update_id).is_empty());
+}
+
 #[test]
 fn test_justice_tx() {
 	// Test justice txn built on revoked HTLC-Success tx, against both sides
@@ -2944,7 +3120,7 @@ fn test_htlc_on_chain_success() {
 	let mut first_claimed = false;
 	for event in events {
 		match event {
-			Event::PaymentSent { payment_preimage } => {
+			Event::PaymentSent { payment_preimage, .. } => {
 				if payment_preimage == our_payment_preimage {
 					assert!(!first_claimed);
 					first_claimed = true;
@@ -3415,6 +3591,7 @@ fn fail_backward_pending_htlc_upon_channel_failure() {
 			payment_hash,
 			cltv_expiry,
 			onion_routing_packet,
+			blinding_point: None,
 		};
 		nodes[0].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &update_add_htlc);
 	}
@@ -3606,7 +3783,7 @@ fn test_simple_peer_disconnect() {
 		let events = nodes[0].node.get_and_clear_pending_events();
 		assert_eq!(events.len(), 2);
 		match events[0] {
-			Event::PaymentSent { payment_preimage } => {
+			Event::PaymentSent { payment_preimage, .. } => {
 				assert_eq!(payment_preimage, payment_preimage_3);
 			},
 			_ => panic!("Unexpected event"),
@@ -3752,7 +3929,7 @@ fn do_test_drop_messages_peer_disconnect(messages_delivered: u8) {
 		let events_4 = nodes[0].node.get_and_clear_pending_events();
 		assert_eq!(events_4.len(), 1);
 		match events_4[0] {
-			Event::PaymentSent { ref payment_preimage } => {
+			Event::PaymentSent { ref payment_preimage, .. } => {
 				assert_eq!(payment_preimage_1, *payment_preimage);
 			},
 			_ => panic!("Unexpected event"),
@@ -3793,7 +3970,7 @@ fn do_test_drop_messages_peer_disconnect(messages_delivered: u8) {
 			let events_4 = nodes[0].node.get_and_clear_pending_events();
 			assert_eq!(events_4.len(), 1);
 			match events_4[0] {
-				Event::PaymentSent { ref payment_preimage } => {
+				Event::PaymentSent { ref payment_preimage, .. } => {
 					assert_eq!(payment_preimage_1, *payment_preimage);
 				},
 				_ => panic!("Unexpected event"),
@@ -3972,7 +4149,7 @@ fn test_drop_messages_peer_disconnect_dual_htlc() {
 			let events_3 = nodes[0].node.get_and_clear_pending_events();
 			assert_eq!(events_3.len(), 1);
 			match events_3[0] {
-				Event::PaymentSent { ref payment_preimage } => {
+				Event::PaymentSent { ref payment_preimage, .. } => {
 					assert_eq!(*payment_preimage, payment_preimage_1);
 				},
 				_ => panic!("Unexpected event"),
@@ -4262,7 +4439,7 @@ fn test_invalid_channel_announcement() {
 				bitcoin_key_1: if were_node_one { as_bitcoin_key } else { bs_bitcoin_key },
 				bitcoin_key_2: if were_node_one { bs_bitcoin_key } else { as_bitcoin_key },
 				excess_data: Vec::new(),
-			};
+			}
 		}
 	}
 
@@ -4829,6 +5006,245 @@ fn test_claim_sizeable_push_msat() {
 	check_spends!(spend_txn[0], node_txn[0]);
 }
 
+#[test]
+fn test_claimable_balances_on_holder_close() {
+	// Step through a force-close by broadcasting our commitment transaction with a pending
+	// outbound HTLC and check the balances reported by our ChannelMonitor at each stage.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 0, InitFeatures::known(), InitFeatures::known());
+	let commitment_fee = |num_htlcs: u64| 253 * (COMMITMENT_TX_BASE_WEIGHT + num_htlcs * COMMITMENT_TX_WEIGHT_PER_HTLC) / 1000;
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 1_000_000 - commitment_fee(0) }]);
+	assert_eq!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 0 }]);
+
+	let payment_hash = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3_000_000).1;
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 1_000_000 - 3_000 - commitment_fee(1) }]);
+
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 2); // commitment tx + HTLC-timeout tx
+		check_spends!(node_txn[0], chan.3);
+		check_spends!(node_txn[1], node_txn[0]);
+		let commitment_tx = node_txn[0].clone();
+		node_txn.clear();
+		commitment_tx
+	};
+	// Until our commitment transaction confirms, our balance is still claimable on close.
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 1_000_000 - 3_000 - commitment_fee(1) }]);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	let htlc_timeout_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let htlc_timeout_tx = node_txn.iter().find(|tx| tx.input[0].previous_output.txid == commitment_tx.txid()).unwrap().clone();
+		node_txn.clear();
+		htlc_timeout_tx
+	};
+	let htlc_cltv_expiry = htlc_timeout_tx.lock_time;
+	let mut balances = nodes[0].chain_monitor.chain_monitor.get_claimable_balances();
+	balances.sort_unstable_by_key(|balance| format!("{:?}", balance));
+	assert_eq!(balances, vec![
+		channelmonitor::Balance::ClaimableAwaitingCsv { claimable_amount_satoshis: 1_000_000 - 3_000 - commitment_fee(1), claimable_height: 1 + BREAKDOWN_TIMEOUT as u32 },
+		channelmonitor::Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_amount_satoshis: 3_000, claimable_height: htlc_cltv_expiry },
+	]);
+
+	// Once our to_self output is buried it is handed over and no longer reported.
+	let header_hash = connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	check_spendable_outputs!(nodes[0], 1, node_cfgs[0].keys_manager, 1_000_000);
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::MaybeClaimableHTLCAwaitingTimeout { claimable_amount_satoshis: 3_000, claimable_height: htlc_cltv_expiry }]);
+
+	// Confirm our HTLC-timeout transaction, whose output is locked behind the same CSV delay.
+	let header_hash = connect_blocks(&nodes[0], htlc_cltv_expiry - ANTI_REORG_DELAY - 1, ANTI_REORG_DELAY, true, header_hash);
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![htlc_timeout_tx.clone()] }, htlc_cltv_expiry);
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances(),
+		vec![channelmonitor::Balance::ClaimableAwaitingCsv { claimable_amount_satoshis: htlc_timeout_tx.output[0].value, claimable_height: htlc_cltv_expiry + BREAKDOWN_TIMEOUT as u32 }]);
+
+	connect_blocks(&nodes[0], ANTI_REORG_DELAY - 1, htlc_cltv_expiry, true, header.block_hash());
+	assert!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances().is_empty());
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentFailed { payment_hash: ref failed_payment_hash, .. } => assert_eq!(*failed_payment_hash, payment_hash),
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_claimable_balances_on_revoked_commitment() {
+	// Check the balances reported by our ChannelMonitor while claiming the outputs of a revoked
+	// counterparty commitment transaction, until our justice transaction is buried.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 59_000_000, InitFeatures::known(), InitFeatures::known());
+	let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3_000_000).0;
+	let revoked_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage, 3_000_000);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![revoked_txn[0].clone()] }, 1);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_broadcast!(nodes[1], false);
+	let to_remote_value = revoked_txn[0].output.iter().find(|outp| outp.script_pubkey.is_v0_p2wpkh()).unwrap().value;
+	let to_local_value = revoked_txn[0].output.iter().find(|outp| outp.script_pubkey.is_v0_p2wsh() && outp.value != 3_000).unwrap().value;
+	let mut balances = nodes[1].chain_monitor.chain_monitor.get_claimable_balances();
+	balances.sort_unstable_by_key(|balance| format!("{:?}", balance));
+	assert_eq!(balances, vec![
+		channelmonitor::Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis: to_remote_value, confirmation_height: ANTI_REORG_DELAY },
+		channelmonitor::Balance::ContentiousClaimable { claimable_amount_satoshis: 3_000, timeout_height: TEST_FINAL_CLTV + CHAN_CONFIRM_DEPTH },
+		channelmonitor::Balance::ContentiousClaimable { claimable_amount_satoshis: to_local_value, timeout_height: 1 + BREAKDOWN_TIMEOUT as u32 },
+	]);
+
+	let justice_tx = {
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		check_spends!(node_txn[0], revoked_txn[0]);
+		let justice_tx = node_txn[0].clone();
+		node_txn.clear();
+		justice_tx
+	};
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![justice_tx.clone()] }, 2);
+	let mut balances = nodes[1].chain_monitor.chain_monitor.get_claimable_balances();
+	balances.sort_unstable_by_key(|balance| format!("{:?}", balance));
+	assert_eq!(balances, vec![
+		channelmonitor::Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis: to_remote_value, confirmation_height: ANTI_REORG_DELAY },
+		channelmonitor::Balance::ClaimableAwaitingConfirmations { claimable_amount_satoshis: justice_tx.output[0].value, confirmation_height: ANTI_REORG_DELAY + 1 },
+	]);
+
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 2, true, header.block_hash());
+	assert!(nodes[1].chain_monitor.chain_monitor.get_claimable_balances().is_empty());
+	assert_eq!(nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events().len(), 2);
+}
+
+#[test]
+fn test_legacy_monitor_funding_spend_recovery() {
+	// Monitors written before version 2 didn't track the funding spend. Check it is recovered for
+	// a confirmed counterparty commitment transaction, but not for our own, which is the
+	// documented limitation.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 100_000_000, InitFeatures::known(), InitFeatures::known());
+	route_payment(&nodes[0], &vec!(&nodes[1])[..], 3_000_000);
+
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(commitment_tx, chan.3);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+	connect_blocks(&nodes[1], 2, 1, true, header.block_hash());
+
+	{
+		let mut monitors = nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let monitor = monitors.get_mut(&OutPoint { txid: chan.3.txid(), index: 0 }).unwrap();
+		assert_eq!(monitor.funding_spend_confirmed, Some((commitment_tx.txid(), 1)));
+		monitor.funding_spend_confirmed = None;
+		monitor.recover_legacy_funding_spend();
+		assert_eq!(monitor.funding_spend_confirmed, Some((commitment_tx.txid(), 1)));
+	}
+	{
+		let mut monitors = nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let monitor = monitors.get_mut(&OutPoint { txid: chan.3.txid(), index: 0 }).unwrap();
+		assert_eq!(monitor.funding_spend_confirmed, Some((commitment_tx.txid(), 1)));
+		monitor.funding_spend_confirmed = None;
+		monitor.recover_legacy_funding_spend();
+		assert_eq!(monitor.funding_spend_confirmed, None);
+		match monitor.get_claimable_balances()[0] {
+			channelmonitor::Balance::ClaimableOnChannelClose { .. } => {},
+			_ => panic!("Unexpected balance"),
+		}
+		assert!(!monitor.is_fully_resolved());
+	}
+}
+
+#[test]
+fn test_archive_fully_resolved_monitors() {
+	// Force-close a channel and check its monitor is only archived once our commitment
+	// transaction is buried and our to_self output was handed over, after which it no longer
+	// processes blocks.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	let persister = test_utils::TestPersister::new();
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1_000_000, 0, InitFeatures::known(), InitFeatures::known());
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister).is_empty());
+
+	nodes[0].node.force_close_channel(&chan.2);
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	let commitment_tx = {
+		let mut node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], chan.3);
+		node_txn.remove(0)
+	};
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister).is_empty());
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[0], &Block { header, txdata: vec![commitment_tx.clone()] }, 1);
+	nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	let header_hash = connect_blocks(&nodes[0], ANTI_REORG_DELAY - 2, 1, true, header.block_hash());
+	assert!(!nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().values().next().unwrap().is_fully_resolved());
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister).is_empty());
+
+	// Once our to_self output is buried, the monitor is only resolved after handing it over.
+	let header_hash = connect_blocks(&nodes[0], 1, ANTI_REORG_DELAY - 1, true, header_hash);
+	assert!(!nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().values().next().unwrap().is_fully_resolved());
+	check_spendable_outputs!(nodes[0], 1, node_cfgs[0].keys_manager, 1_000_000);
+	assert!(nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().values().next().unwrap().is_fully_resolved());
+
+	// A monitor the persister fails to archive is kept until it succeeds.
+	*persister.archive_ret.lock().unwrap() = Err(());
+	assert!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister).is_empty());
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().len(), 1);
+	*persister.archive_ret.lock().unwrap() = Ok(());
+
+	let funding_txo = OutPoint { txid: chan.3.txid(), index: 0 };
+	assert_eq!(nodes[0].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister), vec![funding_txo]);
+	assert_eq!(*persister.archived_channels.lock().unwrap(), vec![funding_txo]);
+	assert!(nodes[0].chain_monitor.chain_monitor.monitors.lock().unwrap().is_empty());
+	assert!(nodes[0].chain_monitor.chain_monitor.get_claimable_balances().is_empty());
+
+	// The counterparty's monitor hasn't seen the commitment transaction and is kept.
+	assert!(nodes[1].chain_monitor.chain_monitor.archive_fully_resolved_channel_monitors(&persister).is_empty());
+	assert_eq!(nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap().len(), 1);
+
+	// Further blocks are no longer given to the archived monitor.
+	connect_blocks(&nodes[0], 1, ANTI_REORG_DELAY, true, header_hash);
+	assert!(nodes[0].chain_monitor.chain_monitor.get_and_clear_pending_events().is_empty());
+	assert!(nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
+
+	// Archived monitors aren't reloaded on restart, thus drop what they registered with our chain
+	// source for the reload check run when nodes are dropped.
+	nodes[0].chain_source.watched_txn.lock().unwrap().clear();
+	nodes[0].chain_source.watched_outputs.lock().unwrap().clear();
+}
+
 #[test]
 fn test_claim_on_remote_sizeable_push_msat() {
 	// Same test as previous, just test on remote commitment tx, as per_commitment_point registration changes following you're funder/fundee and
@@ -4893,107 +5309,448 @@ fn test_claim_on_remote_revoked_sizeable_push_msat() {
 }
 
 #[test]
-fn test_static_spendable_outputs_preimage_tx() {
+fn test_sweeper_batches_and_bumps_spendable_outputs() {
+	// Sweep the to_remote output of a revoked commitment transaction and the output of the
+	// justice transaction claiming it in a single transaction, then check the OutputSweeper
+	// RBF-bumps the sweep until it confirms and forgets it once it is buried.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
 	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
-	// Create some initial channels
-	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
-
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 59000000, InitFeatures::known(), InitFeatures::known());
 	let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+	let revoked_local_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage, 3_000_000);
 
-	let commitment_tx = get_local_commitment_txn!(nodes[0], chan_1.2);
-	assert_eq!(commitment_tx[0].input.len(), 1);
-	assert_eq!(commitment_tx[0].input[0].previous_output.txid, chan_1.3.txid());
-
-	// Settle A's commitment tx on B's chain
 	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-	assert!(nodes[1].node.claim_funds(payment_preimage, &None, 3_000_000));
-	check_added_monitors!(nodes[1], 1);
-	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx[0].clone()] }, 1);
+	connect_block(&nodes[1], &Block { header, txdata: vec![revoked_local_txn[0].clone()] }, 0);
+	check_closed_broadcast!(nodes[1], false);
 	check_added_monitors!(nodes[1], 1);
-	let events = nodes[1].node.get_and_clear_pending_msg_events();
-	match events[0] {
-		MessageSendEvent::UpdateHTLCs { .. } => {},
-		_ => panic!("Unexpected event"),
-	}
-	match events[1] {
-		MessageSendEvent::BroadcastChannelUpdate { .. } => {},
-		_ => panic!("Unexepected event"),
+
+	let justice_tx = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap()[0].clone();
+	check_spends!(justice_tx, revoked_local_txn[0]);
+	let header_1 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![justice_tx.clone()] }, 1);
+	let mut header_hash = connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	let mut height = ANTI_REORG_DELAY;
+
+	let mut descriptors = Vec::new();
+	for event in nodes[1].chain_monitor.chain_monitor.get_and_clear_pending_events() {
+		match event {
+			Event::SpendableOutputs { outputs } => descriptors.extend(outputs),
+			_ => panic!("Unexpected event"),
+		}
 	}
+	assert_eq!(descriptors.len(), 2);
 
-	// Check B's monitor was able to send back output descriptor event for preimage tx on A's commitment tx
-	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap(); // ChannelManager : 2 (local commitment tx + HTLC-Success), ChannelMonitor: preimage tx
-	assert_eq!(node_txn.len(), 3);
-	check_spends!(node_txn[0], commitment_tx[0]);
-	assert_eq!(node_txn[0].input[0].witness.last().unwrap().len(), OFFERED_HTLC_SCRIPT_WEIGHT);
-	check_spends!(node_txn[1], chan_1.3);
-	check_spends!(node_txn[2], node_txn[1]);
+	let destination_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&[42; 20]).into_script();
+	let sweeper = OutputSweeper::new(destination_script.clone(), node_cfgs[1].keys_manager.keys_manager(), nodes[1].tx_broadcaster, node_cfgs[1].fee_estimator, nodes[1].logger);
+	nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	sweeper.track_spendable_outputs(descriptors, height).unwrap();
 
-	let header_1 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![node_txn[0].clone()] }, 1);
-	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	let sweep_tx = {
+		let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		assert_eq!(node_txn[0].input.len(), 2);
+		assert_eq!(node_txn[0].output.len(), 1);
+		assert_eq!(node_txn[0].output[0].script_pubkey, destination_script);
+		check_spends!(node_txn[0], revoked_local_txn[0], justice_tx);
+		node_txn[0].clone()
+	};
+	nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
 
-	let spend_txn = check_spendable_outputs!(nodes[1], 1, node_cfgs[1].keys_manager, 100000);
-	assert_eq!(spend_txn.len(), 1);
-	check_spends!(spend_txn[0], node_txn[0]);
+	// The sweep didn't confirm in the next block, so it's replaced with one paying a higher fee.
+	height += 1;
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	sweeper.block_connected(&header, &[], height);
+	header_hash = header.block_hash();
+	let bumped_sweep_tx = {
+		let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], revoked_local_txn[0], justice_tx);
+		assert_eq!(node_txn[0].input.iter().map(|input| input.previous_output).collect::<Vec<_>>(), sweep_tx.input.iter().map(|input| input.previous_output).collect::<Vec<_>>());
+		assert!(node_txn[0].output[0].value < sweep_tx.output[0].value);
+		node_txn[0].clone()
+	};
+	nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().clear();
+	assert_eq!(sweeper.get_pending_sweeps(), vec![bumped_sweep_tx.clone()]);
+
+	// Once the bumped sweep confirms, it's no longer rebroadcast and is forgotten once buried.
+	height += 1;
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	sweeper.block_connected(&header, &[(0, &bumped_sweep_tx)], height);
+	header_hash = header.block_hash();
+	for _ in 0..ANTI_REORG_DELAY - 1 {
+		assert_eq!(sweeper.get_pending_sweeps().len(), 1);
+		height += 1;
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		sweeper.block_connected(&header, &[], height);
+		header_hash = header.block_hash();
+	}
+	assert!(sweeper.get_pending_sweeps().is_empty());
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().is_empty());
 }
 
 #[test]
-fn test_static_spendable_outputs_timeout_tx() {
+fn test_static_channel_backup_recovery() {
+	// Node 1 loses all its channel state and recovers its balance from a static channel backup by
+	// having node 0 broadcast its commitment transaction and sweeping its to_remote output.
 	let chanmon_cfgs = create_chanmon_cfgs(2);
 	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
 	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
 	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
-	// Create some initial channels
-	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 100000, 0, InitFeatures::known(), InitFeatures::known());
+	let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+	claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage, 3_000_000);
 
-	// Rebalance the network a bit by relaying one payment through all the channels ...
-	send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000, 8_000_000);
+	let mut peer_addresses = HashMap::new();
+	peer_addresses.insert(nodes[0].node.get_our_node_id(), vec![msgs::NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 }]);
+	let backup = nodes[1].node.get_static_channel_backup(&peer_addresses);
+	assert_eq!(backup.channels.len(), 1);
+	assert_eq!(backup.channels[0].channel_id, chan.2);
+	let backup: StaticChannelBackup = Readable::read(&mut ::std::io::Cursor::new(backup.encode())).unwrap();
 
-	let (_, our_payment_hash) = route_payment(&nodes[1], &vec!(&nodes[0])[..], 3_000_000);
+	let recovery = ChannelBackupRecovery::new(backup, node_cfgs[1].keys_manager.keys_manager(), nodes[1].logger);
+	let peers = recovery.get_peers_to_connect();
+	assert_eq!(peers.len(), 1);
+	assert_eq!(peers[0].0, nodes[0].node.get_our_node_id());
+	assert_eq!(peers[0].1.len(), 1);
 
-	let commitment_tx = get_local_commitment_txn!(nodes[0], chan_1.2);
-	assert_eq!(commitment_tx[0].input.len(), 1);
-	assert_eq!(commitment_tx[0].input[0].previous_output.txid, chan_1.3.txid());
+	nodes[0].node.peer_disconnected(&nodes[1].node.get_our_node_id(), false);
+	nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty() });
+	get_chan_reestablish_msgs!(nodes[0], nodes[1]);
 
-	// Settle A's commitment tx on B' chain
-	let header = BlockHeader { version: 0x2000_0000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42};
-	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx[0].clone()] }, 0);
-	check_added_monitors!(nodes[1], 1);
-	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	// On connection, the recovering node claims a stale state, which node 0 force-closes on.
+	recovery.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::Init { features: InitFeatures::empty() });
+	let events = recovery.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 2);
 	match events[0] {
-		MessageSendEvent::BroadcastChannelUpdate { .. } => {},
+		MessageSendEvent::SendChannelReestablish { ref node_id, ref msg } => {
+			assert_eq!(*node_id, nodes[0].node.get_our_node_id());
+			nodes[0].node.handle_channel_reestablish(&nodes[1].node.get_our_node_id(), msg);
+		},
 		_ => panic!("Unexpected event"),
 	}
+	match events[1] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg }, .. } => assert_eq!(msg.channel_id, chan.2),
+		_ => panic!("Unexpected event"),
+	}
+	check_closed_broadcast!(nodes[0], true);
+	check_added_monitors!(nodes[0], 1);
 
-	// Check B's monitor was able to send back output descriptor event for timeout tx on A's commitment tx
-	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
-	assert_eq!(node_txn.len(), 3); // ChannelManager : 2 (local commitent tx + HTLC-timeout), ChannelMonitor: timeout tx
-	check_spends!(node_txn[0],  commitment_tx[0].clone());
-	assert_eq!(node_txn[0].input[0].witness.last().unwrap().len(), ACCEPTED_HTLC_SCRIPT_WEIGHT);
-	check_spends!(node_txn[1], chan_1.3.clone());
-	check_spends!(node_txn[2], node_txn[1]);
+	let commitment_tx = {
+		let node_txn = nodes[0].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], chan.3);
+		node_txn[0].clone()
+	};
 
-	let header_1 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
-	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![node_txn[0].clone()] }, 1);
-	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
-	expect_payment_failed!(nodes[1], our_payment_hash, true);
+	// Once node 0's commitment transaction is buried, our output in it is handed to the user.
+	let mut header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	recovery.block_connected(&header, &[(0, &commitment_tx)], 1);
+	assert!(recovery.get_unclosed_channels().is_empty());
+	assert!(recovery.get_peers_to_connect().is_empty());
+	for height in 2..ANTI_REORG_DELAY + 1 {
+		assert!(recovery.get_and_clear_pending_events().is_empty());
+		header = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		recovery.block_connected(&header, &[], height);
+	}
+	let events = recovery.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	let descriptor = match events[0] {
+		Event::SpendableOutputs { ref outputs } => {
+			assert_eq!(outputs.len(), 1);
+			match outputs[0] {
+				SpendableOutputDescriptor::StaticOutputCounterpartyPayment { ref output, .. } => assert_eq!(output.value, 3000),
+				_ => panic!("Unexpected descriptor"),
+			}
+			outputs[0].clone()
+		},
+		_ => panic!("Unexpected event"),
+	};
 
-	let spend_txn = check_spendable_outputs!(nodes[1], 1, node_cfgs[1].keys_manager, 100000);
-	assert_eq!(spend_txn.len(), 2); // SpendableOutput: remote_commitment_tx.to_remote, timeout_tx.output
-	check_spends!(spend_txn[1], node_txn[0]);
+	let destination_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&[42; 20]).into_script();
+	let sweep_tx = node_cfgs[1].keys_manager.keys_manager().spend_spendable_outputs(&[&descriptor], destination_script, 253).unwrap();
+	check_spends!(sweep_tx, commitment_tx);
 }
 
 #[test]
-fn test_static_spendable_outputs_justice_tx_revoked_commitment_tx() {
-	let chanmon_cfgs = create_chanmon_cfgs(2);
-	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
-	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
-	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+fn test_claim_aggregation_across_channels() {
+	// Revoked commitment transactions from two channels confirm in the same block. With claim
+	// aggregation enabled, all their outputs are claimed in a single justice transaction, which is
+	// bumped with RBF once its timer expires and forgotten once buried.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	nodes[1].chain_monitor.chain_monitor.enable_claim_aggregation();
+
+	let mut revoked_txn = Vec::new();
+	for &counterparty in [0, 2].iter() {
+		let chan = create_announced_chan_between_nodes(&nodes, counterparty, 1, InitFeatures::known(), InitFeatures::known());
+		let payment_preimage = route_payment(&nodes[counterparty], &[&nodes[1]], 3000000).0;
+		revoked_txn.push(get_local_commitment_txn!(nodes[counterparty], chan.2)[0].clone());
+		claim_payment(&nodes[counterparty], &[&nodes[1]], payment_preimage, 3_000_000);
+	}
+	// Each revoked commitment transaction only has a to_local and an HTLC output, both ours to claim.
+	assert_eq!(revoked_txn[0].output.len(), 2);
+	assert_eq!(revoked_txn[1].output.len(), 2);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: revoked_txn.clone() }, 1);
+	assert_eq!(nodes[1].node.get_and_clear_pending_msg_events().len(), 2);
+	check_added_monitors!(nodes[1], 2);
+
+	let justice_tx = {
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let mut justice_txn: Vec<Transaction> = node_txn.iter().filter(|tx| tx.input.iter().any(|input| input.previous_output.txid == revoked_txn[0].txid() || input.previous_output.txid == revoked_txn[1].txid())).cloned().collect();
+		assert_eq!(justice_txn.len(), 1);
+		assert_eq!(justice_txn[0].input.len(), 4);
+		check_spends!(justice_txn[0], revoked_txn[0], revoked_txn[1]);
+		node_txn.clear();
+		justice_txn.pop().unwrap()
+	};
+
+	// Without confirmation, the justice transaction is replaced by one paying a higher fee once
+	// its timer expires.
+	let mut header_hash = header.block_hash();
+	let mut height = 1;
+	let bumped_justice_tx = loop {
+		height += 1;
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		connect_block(&nodes[1], &Block { header, txdata: vec![] }, height);
+		header_hash = header.block_hash();
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		if let Some(tx) = node_txn.iter().find(|tx| tx.input.len() == 4).cloned() {
+			node_txn.clear();
+			break tx;
+		}
+		node_txn.clear();
+		assert!(height < 20);
+	};
+	check_spends!(bumped_justice_tx, revoked_txn[0], revoked_txn[1]);
+	assert!(bumped_justice_tx.output[0].value < justice_tx.output[0].value);
+
+	// Once the bumped transaction is buried, the claims are forgotten and nothing is rebroadcast.
+	height += 1;
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![bumped_justice_tx.clone()] }, height);
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY + 20, height, true, header.block_hash());
+	assert!(nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap().iter().all(|tx| tx.input.iter().all(|input| input.previous_output.txid != revoked_txn[0].txid() && input.previous_output.txid != revoked_txn[1].txid())));
+	for monitor in nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap().values() {
+		assert!(monitor.get_unspent_shared_claims().is_empty());
+	}
+}
+
+#[test]
+fn test_claim_aggregation_per_destination_script() {
+	// Revoked commitment transactions from two channels paying to different destination scripts
+	// confirm in the same block. Their outputs are claimed in one justice transaction per script.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	nodes[1].chain_monitor.chain_monitor.enable_claim_aggregation();
+
+	let mut revoked_txn = Vec::new();
+	let mut destination_scripts = Vec::new();
+	for &counterparty in [0, 2].iter() {
+		let destination_script = Builder::new().push_opcode(opcodes::all::OP_PUSHBYTES_0).push_slice(&[counterparty as u8; 20]).into_script();
+		*nodes[1].keys_manager.override_destination_script.lock().unwrap() = Some(destination_script.clone());
+		destination_scripts.push(destination_script);
+		let chan = create_announced_chan_between_nodes(&nodes, counterparty, 1, InitFeatures::known(), InitFeatures::known());
+		let payment_preimage = route_payment(&nodes[counterparty], &[&nodes[1]], 3000000).0;
+		revoked_txn.push(get_local_commitment_txn!(nodes[counterparty], chan.2)[0].clone());
+		claim_payment(&nodes[counterparty], &[&nodes[1]], payment_preimage, 3_000_000);
+	}
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: revoked_txn.clone() }, 1);
+	assert_eq!(nodes[1].node.get_and_clear_pending_msg_events().len(), 2);
+	check_added_monitors!(nodes[1], 2);
+
+	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+	for (revoked_tx, destination_script) in revoked_txn.iter().zip(destination_scripts.iter()) {
+		let justice_txn: Vec<&Transaction> = node_txn.iter().filter(|tx| tx.input.iter().any(|input| input.previous_output.txid == revoked_tx.txid())).collect();
+		assert_eq!(justice_txn.len(), 1);
+		assert_eq!(justice_txn[0].input.len(), 2);
+		check_spends!(justice_txn[0], revoked_tx);
+		assert_eq!(justice_txn[0].output.len(), 1);
+		assert_eq!(justice_txn[0].output[0].script_pubkey, *destination_script);
+	}
+}
+
+#[test]
+fn test_claim_aggregation_expiring_claim() {
+	// A revoked commitment transaction confirms with claim aggregation enabled and the shared
+	// claim transaction never confirms. Once the revoked HTLC output comes within
+	// CLTV_SHARED_CLAIM_BUFFER of its expiry, the monitor claims it itself and the shared claim
+	// transaction is rebuilt without it.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+	nodes[1].chain_monitor.chain_monitor.enable_claim_aggregation();
+
+	let chan = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let payment_preimage = route_payment(&nodes[0], &[&nodes[1]], 3000000).0;
+	let revoked_tx = get_local_commitment_txn!(nodes[0], chan.2)[0].clone();
+	claim_payment(&nodes[0], &[&nodes[1]], payment_preimage, 3_000_000);
+	assert_eq!(revoked_tx.output.len(), 2);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![revoked_tx.clone()] }, 1);
+	check_closed_broadcast!(nodes[1], false);
+	check_added_monitors!(nodes[1], 1);
+	{
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		let justice_txn: Vec<&Transaction> = node_txn.iter().filter(|tx| tx.input.iter().any(|input| input.previous_output.txid == revoked_tx.txid())).collect();
+		assert_eq!(justice_txn.len(), 1);
+		assert_eq!(justice_txn[0].input.len(), 2);
+		node_txn.clear();
+	}
+
+	// The revoked HTLC output expires well before the revoked to_local output may be spent by
+	// our counterparty.
+	let htlc_claim = {
+		let monitors = nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap();
+		let mut shared_claims = monitors.get(&OutPoint { txid: chan.3.txid(), index: 0 }).unwrap().get_unspent_shared_claims();
+		assert_eq!(shared_claims.len(), 2);
+		shared_claims.sort_unstable_by_key(|claim| claim.absolute_timelock);
+		shared_claims.remove(0)
+	};
+	let expiring_height = htlc_claim.absolute_timelock - channelmonitor::CLTV_SHARED_CLAIM_BUFFER;
+	assert!(expiring_height > 1);
+
+	let mut header_hash = header.block_hash();
+	for height in 2..expiring_height + 1 {
+		let header = BlockHeader { version: 0x20000000, prev_blockhash: header_hash, merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+		connect_block(&nodes[1], &Block { header, txdata: vec![] }, height);
+		header_hash = header.block_hash();
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		if height < expiring_height {
+			// Only bumps of the shared claim transaction are broadcast until then.
+			assert!(node_txn.iter().all(|tx| tx.input.len() == 2));
+		} else {
+			let htlc_txn: Vec<&Transaction> = node_txn.iter().filter(|tx| tx.input.len() == 1 && tx.input[0].previous_output == htlc_claim.outpoint).collect();
+			assert_eq!(htlc_txn.len(), 1);
+			check_spends!(htlc_txn[0], revoked_tx);
+			let shared_txn: Vec<&Transaction> = node_txn.iter().filter(|tx| tx.input.len() == 1 && tx.input[0].previous_output != htlc_claim.outpoint).collect();
+			assert_eq!(shared_txn.len(), 1);
+			check_spends!(shared_txn[0], revoked_tx);
+		}
+		node_txn.clear();
+	}
+	let monitors = nodes[1].chain_monitor.chain_monitor.monitors.lock().unwrap();
+	let shared_claims = monitors.get(&OutPoint { txid: chan.3.txid(), index: 0 }).unwrap().get_unspent_shared_claims();
+	assert_eq!(shared_claims.len(), 1);
+	assert!(shared_claims[0].outpoint != htlc_claim.outpoint);
+}
+
+#[test]
+fn test_static_spendable_outputs_preimage_tx() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	// Create some initial channels
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+
+	let commitment_tx = get_local_commitment_txn!(nodes[0], chan_1.2);
+	assert_eq!(commitment_tx[0].input.len(), 1);
+	assert_eq!(commitment_tx[0].input[0].previous_output.txid, chan_1.3.txid());
+
+	// Settle A's commitment tx on B's chain
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	assert!(nodes[1].node.claim_funds(payment_preimage, &None, 3_000_000));
+	check_added_monitors!(nodes[1], 1);
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx[0].clone()] }, 1);
+	check_added_monitors!(nodes[1], 1);
+	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	match events[0] {
+		MessageSendEvent::UpdateHTLCs { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+	match events[1] {
+		MessageSendEvent::BroadcastChannelUpdate { .. } => {},
+		_ => panic!("Unexepected event"),
+	}
+
+	// Check B's monitor was able to send back output descriptor event for preimage tx on A's commitment tx
+	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap(); // ChannelManager : 2 (local commitment tx + HTLC-Success), ChannelMonitor: preimage tx
+	assert_eq!(node_txn.len(), 3);
+	check_spends!(node_txn[0], commitment_tx[0]);
+	assert_eq!(node_txn[0].input[0].witness.last().unwrap().len(), OFFERED_HTLC_SCRIPT_WEIGHT);
+	check_spends!(node_txn[1], chan_1.3);
+	check_spends!(node_txn[2], node_txn[1]);
+
+	let header_1 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![node_txn[0].clone()] }, 1);
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+
+	let spend_txn = check_spendable_outputs!(nodes[1], 1, node_cfgs[1].keys_manager, 100000);
+	assert_eq!(spend_txn.len(), 1);
+	check_spends!(spend_txn[0], node_txn[0]);
+}
+
+#[test]
+fn test_static_spendable_outputs_timeout_tx() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	// Create some initial channels
+	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+
+	// Rebalance the network a bit by relaying one payment through all the channels ...
+	send_payment(&nodes[0], &vec!(&nodes[1])[..], 8000000, 8_000_000);
+
+	let (_, our_payment_hash) = route_payment(&nodes[1], &vec!(&nodes[0])[..], 3_000_000);
+
+	let commitment_tx = get_local_commitment_txn!(nodes[0], chan_1.2);
+	assert_eq!(commitment_tx[0].input.len(), 1);
+	assert_eq!(commitment_tx[0].input[0].previous_output.txid, chan_1.3.txid());
+
+	// Settle A's commitment tx on B' chain
+	let header = BlockHeader { version: 0x2000_0000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42};
+	connect_block(&nodes[1], &Block { header, txdata: vec![commitment_tx[0].clone()] }, 0);
+	check_added_monitors!(nodes[1], 1);
+	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	match events[0] {
+		MessageSendEvent::BroadcastChannelUpdate { .. } => {},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Check B's monitor was able to send back output descriptor event for timeout tx on A's commitment tx
+	let node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+	assert_eq!(node_txn.len(), 3); // ChannelManager : 2 (local commitent tx + HTLC-timeout), ChannelMonitor: timeout tx
+	check_spends!(node_txn[0],  commitment_tx[0].clone());
+	assert_eq!(node_txn[0].input[0].witness.last().unwrap().len(), ACCEPTED_HTLC_SCRIPT_WEIGHT);
+	check_spends!(node_txn[1], chan_1.3.clone());
+	check_spends!(node_txn[2], node_txn[1]);
+
+	let header_1 = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header: header_1, txdata: vec![node_txn[0].clone()] }, 1);
+	connect_blocks(&nodes[1], ANTI_REORG_DELAY - 1, 1, true, header.block_hash());
+	expect_payment_failed!(nodes[1], our_payment_hash, true);
+
+	let spend_txn = check_spendable_outputs!(nodes[1], 1, node_cfgs[1].keys_manager, 100000);
+	assert_eq!(spend_txn.len(), 2); // SpendableOutput: remote_commitment_tx.to_remote, timeout_tx.output
+	check_spends!(spend_txn[1], node_txn[0]);
+}
+
+#[test]
+fn test_static_spendable_outputs_justice_tx_revoked_commitment_tx() {
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
 
 	// Create some initial channels
 	let chan_1 = create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
@@ -5378,7 +6135,7 @@ fn test_duplicate_payment_hash_one_failure_one_success() {
 
 	let events = nodes[0].node.get_and_clear_pending_events();
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(*payment_preimage, our_payment_preimage);
 		}
 		_ => panic!("Unexpected event"),
@@ -5848,7 +6605,7 @@ fn do_htlc_claim_local_commitment_only(use_dust: bool) {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { payment_preimage } => {
+		Event::PaymentSent { payment_preimage, .. } => {
 			assert_eq!(payment_preimage, our_payment_preimage);
 		},
 		_ => panic!("Unexpected event"),
@@ -6114,7 +6871,7 @@ fn test_fail_holding_cell_htlc_upon_free() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match &events[0] {
-		&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref error_code, ref error_data } => {
+		&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref error_code, ref error_data, .. } => {
 			assert_eq!(our_payment_hash.clone(), *payment_hash);
 			assert_eq!(*rejected_by_dest, false);
 			assert_eq!(*error_code, None);
@@ -6194,7 +6951,7 @@ fn test_free_and_fail_holding_cell_htlcs() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match &events[0] {
-		&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref error_code, ref error_data } => {
+		&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref error_code, ref error_data, .. } => {
 			assert_eq!(payment_hash_2.clone(), *payment_hash);
 			assert_eq!(*rejected_by_dest, false);
 			assert_eq!(*error_code, None);
@@ -6238,7 +6995,7 @@ fn test_free_and_fail_holding_cell_htlcs() {
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
 	match events[0] {
-		Event::PaymentSent { ref payment_preimage } => {
+		Event::PaymentSent { ref payment_preimage, .. } => {
 			assert_eq!(*payment_preimage, payment_preimage_1);
 		}
 		_ => panic!("Unexpected event"),
@@ -6470,7 +7227,7 @@ fn test_update_add_htlc_bolt2_sender_cltv_expiry_too_high() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
-	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 100000000, 500000001, &logger).unwrap();
+	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], 1000000, 500000001, &logger).unwrap();
 	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::RouteError { ref err },
 		assert_eq!(err, &"Channel CLTV overflowed?"));
 }
@@ -6538,7 +7295,9 @@ fn test_update_add_htlc_bolt2_sender_exceed_max_htlc_value_in_flight() {
 	let (_, our_payment_hash) = get_payment_preimage_hash!(nodes[0]);
 	let net_graph_msg_handler = &nodes[0].net_graph_msg_handler;
 	let logger = test_utils::TestLogger::new();
-	let route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_in_flight+1, TEST_FINAL_CLTV, &logger).unwrap();
+	// The router won't exceed the announced htlc_maximum_msat, so bump the amount by hand
+	let mut route = get_route(&nodes[0].node.get_our_node_id(), &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[1].node.get_our_node_id(), None, &[], max_in_flight, TEST_FINAL_CLTV, &logger).unwrap();
+	route.paths[0][0].fee_msat = max_in_flight + 1;
 	unwrap_send_err!(nodes[0].node.send_payment(&route, our_payment_hash, &None), true, APIError::ChannelUnavailable { ref err },
 		assert!(regex::Regex::new(r"Cannot send value that would put us over the max HTLC value in flight our peer will accept \(\d+\)").unwrap().is_match(err)));
 
@@ -6644,6 +7403,7 @@ fn test_update_add_htlc_bolt2_receiver_check_max_htlc_limit() {
 		payment_hash: our_payment_hash,
 		cltv_expiry: htlc_cltv,
 		onion_routing_packet: onion_packet.clone(),
+		blinding_point: None,
 	};
 
 	for i in 0..super::channel::OUR_MAX_HTLCS {
@@ -7393,6 +8153,58 @@ fn test_upfront_shutdown_script() {
 	}
 }
 
+#[test]
+fn test_shutdown_anysegwit_script() {
+	// BOLT 2 : option_shutdown_anysegwit, segwit v1+ shutdown scripts (e.g. P2TR) are only
+	// accepted from peers which negotiated it, both upfront and at shutdown.
+	let mut config = UserConfig::default();
+	config.channel_options.announced_channel = true;
+	config.peer_channel_config_limits.force_announced_channel_preference = false;
+	config.channel_options.commit_upfront_shutdown_pubkey = false;
+	let user_cfgs = [Some(config.clone()), None, Some(config)];
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &user_cfgs);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	let p2tr_script = Builder::new().push_int(1).push_slice(&[42; 32]).into_script();
+
+	nodes[1].node.peer_connected(&nodes[0].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	nodes[1].node.peer_connected(&nodes[2].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known().clear_shutdown_anysegwit() });
+
+	// An upfront segwit v1 script is only accepted if our peer supports option_shutdown_anysegwit
+	nodes[0].node.create_channel(nodes[1].node.get_our_node_id(), 100000, 10001, 42, None).unwrap();
+	let mut open_channel = get_event_msg!(nodes[0], MessageSendEvent::SendOpenChannel, nodes[1].node.get_our_node_id());
+	open_channel.shutdown_scriptpubkey = OptionalField::Present(p2tr_script.clone());
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known().clear_shutdown_anysegwit(), &open_channel);
+	let events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		MessageSendEvent::HandleError { action: ErrorAction::SendErrorMessage { ref msg }, .. } => {
+			assert!(regex::Regex::new(r"Peer is signaling upfront_shutdown but has provided a non-accepted scriptpubkey format").unwrap().is_match(msg.data.as_str()));
+		},
+		_ => panic!("Unexpected event"),
+	}
+	nodes[1].node.handle_open_channel(&nodes[0].node.get_our_node_id(), InitFeatures::known(), &open_channel);
+	get_event_msg!(nodes[1], MessageSendEvent::SendAcceptChannel, nodes[0].node.get_our_node_id());
+
+	// At shutdown, a segwit v1 script is accepted from a peer supporting option_shutdown_anysegwit
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 1000000, InitFeatures::known(), InitFeatures::known());
+	nodes[0].node.close_channel(&OutPoint { txid: chan.3.txid(), index: 0 }.to_channel_id()).unwrap();
+	let mut node_0_shutdown = get_event_msg!(nodes[0], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id());
+	node_0_shutdown.scriptpubkey = p2tr_script.clone();
+	nodes[1].node.handle_shutdown(&nodes[0].node.get_our_node_id(), &node_0_shutdown);
+	get_event_msg!(nodes[1], MessageSendEvent::SendShutdown, nodes[0].node.get_our_node_id());
+
+	// ...but rejected from one which doesn't
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 2, 1, 1000000, 1000000, InitFeatures::known(), InitFeatures::known());
+	nodes[2].node.close_channel(&OutPoint { txid: chan.3.txid(), index: 0 }.to_channel_id()).unwrap();
+	let mut node_2_shutdown = get_event_msg!(nodes[2], MessageSendEvent::SendShutdown, nodes[1].node.get_our_node_id());
+	node_2_shutdown.scriptpubkey = p2tr_script;
+	nodes[1].node.handle_shutdown(&nodes[2].node.get_our_node_id(), &node_2_shutdown);
+	assert!(regex::Regex::new(r"Got a nonstandard scriptpubkey \([A-Fa-f0-9]+\) from remote peer").unwrap().is_match(check_closed_broadcast!(nodes[1], true).unwrap().data.as_str()));
+	check_added_monitors!(nodes[1], 1);
+}
+
 #[test]
 fn test_user_configurable_csv_delay() {
 	// We test our channel constructors yield errors when we pass them absurd csv delay
@@ -7779,6 +8591,75 @@ fn test_bump_penalty_txn_on_revoked_commitment() {
 	nodes[1].node.get_and_clear_pending_msg_events();
 }
 
+struct LinearFeeBumpPolicy {}
+impl FeeBumpPolicy for LinearFeeBumpPolicy {
+	fn initial_confirmation_target(&self, _blocks_to_expiry: u32) -> ConfirmationTarget { ConfirmationTarget::Normal }
+	fn bumped_feerate(&self, previous_feerate: u64, _blocks_to_expiry: u32) -> u64 { previous_feerate + 5000 }
+	fn max_fee(&self, claimed_value: u64, _blocks_to_expiry: u32) -> u64 { claimed_value / 100 }
+	fn blocks_before_bump(&self, _blocks_to_expiry: u32) -> u32 { 2 }
+}
+
+#[test]
+fn test_fee_bump_policy() {
+	// Check that justice transactions are bumped as dictated by a custom FeeBumpPolicy, both in
+	// timing and feerate, until the maximum fee it allows is reached.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let chan = create_announced_chan_between_nodes_with_value(&nodes, 0, 1, 1000000, 59000000, InitFeatures::known(), InitFeatures::known());
+	nodes[1].chain_monitor.chain_monitor.set_fee_bump_policy(Arc::new(LinearFeeBumpPolicy {}));
+
+	let payment_preimage = route_payment(&nodes[0], &vec!(&nodes[1])[..], 3000000).0;
+	let revoked_txn = get_local_commitment_txn!(nodes[0], chan.2);
+	assert_eq!(revoked_txn[0].output.len(), 3);
+	let penalty_sum: u64 = revoked_txn[0].output.iter().filter(|outp| outp.script_pubkey.is_v0_p2wsh()).map(|outp| outp.value).sum();
+	claim_payment(&nodes[0], &vec!(&nodes[1])[..], payment_preimage, 3_000_000);
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	connect_block(&nodes[1], &Block { header, txdata: vec![revoked_txn[0].clone()] }, 1);
+	check_added_monitors!(nodes[1], 1);
+	check_closed_broadcast!(nodes[1], false);
+
+	let mut feerates = Vec::new();
+	{
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		assert_eq!(node_txn.len(), 2); // justice tx + local commitment tx
+		assert_eq!(node_txn[0].input.len(), 2);
+		check_spends!(node_txn[0], revoked_txn[0]);
+		let fee = penalty_sum - node_txn[0].output[0].value;
+		feerates.push(fee * 1000 / node_txn[0].get_weight() as u64);
+		node_txn.clear();
+	}
+
+	// Step blocks one by one: a bumped justice tx is broadcast every 2 blocks until the next bump
+	// would pay more than a hundredth of the claimed value.
+	let mut header_hash = header.block_hash();
+	for height in 2..12 {
+		header_hash = connect_blocks(&nodes[1], 1, height - 1, true, header_hash);
+		let mut node_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
+		if height % 2 == 0 || feerates.len() == 3 {
+			assert!(node_txn.is_empty());
+			continue;
+		}
+		assert_eq!(node_txn.len(), 1);
+		check_spends!(node_txn[0], revoked_txn[0]);
+		let fee = penalty_sum - node_txn[0].output[0].value;
+		assert!(fee <= penalty_sum / 100);
+		feerates.push(fee * 1000 / node_txn[0].get_weight() as u64);
+		node_txn.clear();
+	}
+	assert_eq!(feerates.len(), 3);
+	assert!(feerates[0] >= 253 && feerates[0] < 253 + 5);
+	for i in 1..feerates.len() {
+		assert!(feerates[i] >= feerates[i - 1] + 5000 - 5 && feerates[i] <= feerates[i - 1] + 5000 + 50);
+	}
+
+	nodes[1].node.get_and_clear_pending_events();
+	nodes[1].node.get_and_clear_pending_msg_events();
+}
+
 #[test]
 fn test_bump_penalty_txn_on_revoked_htlcs() {
 	// In case of penalty txn with too low feerates for getting into mempools, RBF-bump them to sure
@@ -8168,8 +9049,8 @@ fn test_counterparty_raa_skip_no_crash() {
 	let keys = &guard.by_id.get_mut(&channel_id).unwrap().holder_keys;
 	const INITIAL_COMMITMENT_NUMBER: u64 = (1 << 48) - 1;
 	let next_per_commitment_point = PublicKey::from_secret_key(&Secp256k1::new(),
-		&SecretKey::from_slice(&keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 2)).unwrap());
-	let per_commitment_secret = keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER);
+		&SecretKey::from_slice(&keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER - 2).unwrap()).unwrap());
+	let per_commitment_secret = keys.release_commitment_secret(INITIAL_COMMITMENT_NUMBER).unwrap();
 
 	nodes[1].node.handle_revoke_and_ack(&nodes[0].node.get_our_node_id(),
 		&msgs::RevokeAndACK { channel_id, per_commitment_secret, next_per_commitment_point });
@@ -8497,3 +9378,410 @@ fn test_concurrent_monitor_claim() {
 		check_spends!(htlc_txn[1], bob_state_y);
 	}
 }
+
+#[test]
+fn test_probe_successful() {
+	// A probe is failed back by its destination as it can't know the preimage, which we should
+	// report as a ProbeSuccessful rather than a PaymentFailed.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 1_000_000, TEST_FINAL_CLTV, &logger).unwrap();
+	let payment_hash = nodes[0].node.send_probe(&route).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	pass_along_route(&nodes[0], &[&[&nodes[1], &nodes[2]]], 1_000_000, payment_hash, None);
+	pass_failed_payment_back(&nodes[0], &[&nodes[1], &nodes[2]], false, payment_hash);
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::ProbeSuccessful { payment_hash: ref probe_hash, ref path } => {
+			assert_eq!(*probe_hash, payment_hash);
+			assert_eq!(*path, route.paths[0]);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	// Regular payments which are rejected by their destination are still reported as failed
+	let (_, payment_hash) = route_payment(&nodes[0], &[&nodes[1], &nodes[2]], 1_000_000);
+	fail_payment(&nodes[0], &[&nodes[1], &nodes[2]], payment_hash);
+}
+
+#[test]
+fn test_probe_failed() {
+	// A probe which can't be forwarded by an intermediate node is reported as a ProbeFailed,
+	// identifying the channel which lacked liquidity.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_2 = create_announced_chan_between_nodes_with_value(&nodes, 1, 2, 100000, 95000000, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let route = get_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2].node.get_our_node_id(), None, &Vec::new(), 5_000_000, TEST_FINAL_CLTV, &logger).unwrap();
+	let payment_hash = nodes[0].node.send_probe(&route).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	// Once to try to forward the probe, and once to fail it backwards
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false, true);
+
+	// The network graph is still informed of the failure
+	let msg_events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(msg_events.len(), 1);
+	match msg_events[0] {
+		MessageSendEvent::PaymentFailureNetworkUpdate { update: msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg }} => {
+			assert_eq!(msg.contents.short_channel_id, chan_2.0.contents.short_channel_id);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	let events = nodes[0].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::ProbeFailed { payment_hash: ref probe_hash, ref path, short_channel_id } => {
+			assert_eq!(*probe_hash, payment_hash);
+			assert_eq!(*path, route.paths[0]);
+			assert_eq!(short_channel_id, Some(chan_2.0.contents.short_channel_id));
+		},
+		_ => panic!("Unexpected event"),
+	}
+}
+
+#[test]
+fn test_trampoline_payment() {
+	// A payment routed via a trampoline node, which finds the rest of the route itself, is
+	// received and claimed as usual.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut trampoline_config = UserConfig::default();
+	trampoline_config.channel_options.announced_channel = true;
+	trampoline_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	trampoline_config.accept_trampoline_forwards = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(trampoline_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	assert!(nodes[1].node.our_init_features().supports_trampoline_routing());
+	// We need to know our peers' features to route through them, and the trampoline node needs
+	// to know the destination can receive a trampoline onion.
+	nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	nodes[1].node.peer_connected(&nodes[2].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let payment_secret = Some(PaymentSecret([0x42; 32]));
+	let trampoline_fees = RoutingFees { base_msat: 1000, proportional_millionths: 0 };
+	let route = {
+		let first_hops = nodes[0].node.list_usable_channels();
+		get_trampoline_route(&nodes[0].node.get_our_node_id(), &first_hops.iter().collect::<Vec<_>>(), &nodes[1].node.get_our_node_id(),
+			&trampoline_fees, 144, &nodes[2].node.get_our_node_id(), 1_000_000, TEST_FINAL_CLTV).unwrap()
+	};
+	assert_eq!(route.first_hop.fee_msat, 1_001_000);
+	nodes[0].node.send_trampoline_payment(&route, payment_hash, &payment_secret).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	// The HTLC is only forwarded once the trampoline node has found a route for it
+	assert!(nodes[1].node.get_and_clear_pending_msg_events().is_empty());
+	nodes[1].node.process_pending_trampoline_forwards(&nodes[1].net_graph_msg_handler.network_graph.read().unwrap());
+	check_added_monitors!(nodes[1], 1);
+
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	assert_eq!(payment_event.node_id, nodes[2].node.get_our_node_id());
+	assert_eq!(payment_event.msgs[0].amount_msat, 1_000_000);
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[2]);
+
+	let events = nodes[2].node.get_and_clear_pending_events();
+	assert_eq!(events.len(), 1);
+	match events[0] {
+		Event::PaymentReceived { payment_hash: ref received_hash, payment_secret: ref received_secret, amt } => {
+			assert_eq!(*received_hash, payment_hash);
+			assert_eq!(*received_secret, payment_secret);
+			assert_eq!(amt, 1_000_000);
+		},
+		_ => panic!("Unexpected event"),
+	}
+
+	claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2]]], false, payment_preimage, payment_secret, 1_000_000);
+}
+
+#[test]
+fn test_trampoline_fee_insufficient() {
+	// If the sender doesn't leave the trampoline node enough fee to cover its own forwarding fee,
+	// the payment is failed back with trampoline_fee_insufficient.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let mut trampoline_config = UserConfig::default();
+	trampoline_config.channel_options.announced_channel = true;
+	trampoline_config.peer_channel_config_limits.force_announced_channel_preference = false;
+	trampoline_config.accept_trampoline_forwards = true;
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, Some(trampoline_config), None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	nodes[1].node.peer_connected(&nodes[2].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let trampoline_fees = RoutingFees { base_msat: 0, proportional_millionths: 0 };
+	let route = {
+		let first_hops = nodes[0].node.list_usable_channels();
+		get_trampoline_route(&nodes[0].node.get_our_node_id(), &first_hops.iter().collect::<Vec<_>>(), &nodes[1].node.get_our_node_id(),
+			&trampoline_fees, 144, &nodes[2].node.get_our_node_id(), 1_000_000, TEST_FINAL_CLTV).unwrap()
+	};
+	nodes[0].node.send_trampoline_payment(&route, payment_hash, &None).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	nodes[1].node.process_pending_trampoline_forwards(&nodes[1].net_graph_msg_handler.network_graph.read().unwrap());
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false, true);
+	nodes[0].node.get_and_clear_pending_msg_events();
+	expect_payment_failed!(nodes[0], payment_hash, false, 0x2000 | 51, &[0; 0]);
+}
+
+#[test]
+fn test_trampoline_forwards_disabled() {
+	// Unless configured to act as a trampoline node, we don't advertise trampoline_routing and
+	// fail HTLCs asking us to act as one immediately, without trying to find a route onwards.
+	let chanmon_cfgs = create_chanmon_cfgs(3);
+	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
+	let nodes = create_network(3, &node_cfgs, &node_chanmgrs);
+	assert!(!nodes[1].node.our_init_features().supports_trampoline_routing());
+
+	// The sender believes nodes[1] supports trampoline routing anyway
+	nodes[0].node.peer_connected(&nodes[1].node.get_our_node_id(), &msgs::Init { features: InitFeatures::known() });
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let trampoline_fees = RoutingFees { base_msat: 1000, proportional_millionths: 0 };
+	let route = {
+		let first_hops = nodes[0].node.list_usable_channels();
+		get_trampoline_route(&nodes[0].node.get_our_node_id(), &first_hops.iter().collect::<Vec<_>>(), &nodes[1].node.get_our_node_id(),
+			&trampoline_fees, 144, &nodes[2].node.get_our_node_id(), 1_000_000, TEST_FINAL_CLTV).unwrap()
+	};
+	nodes[0].node.send_trampoline_payment(&route, payment_hash, &None).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false, true);
+
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false, true);
+	nodes[0].node.get_and_clear_pending_msg_events();
+	expect_payment_failed!(nodes[0], payment_hash, true, 0x4000 | 22, &[0; 0]);
+}
+
+fn blinded_route_hints<'a, 'b, 'c>(nodes: &Vec<Node<'a, 'b, 'c>>, updates: &[(usize, &msgs::ChannelUpdate)]) -> Vec<RouteHint> {
+	updates.iter().map(|&(node_idx, update)| RouteHint {
+		src_node_id: nodes[node_idx].node.get_our_node_id(),
+		short_channel_id: update.contents.short_channel_id,
+		fees: RoutingFees {
+			base_msat: update.contents.fee_base_msat,
+			proportional_millionths: update.contents.fee_proportional_millionths,
+		},
+		cltv_expiry_delta: update.contents.cltv_expiry_delta,
+		htlc_minimum_msat: update.contents.htlc_minimum_msat,
+		htlc_maximum_msat: None,
+	}).collect()
+}
+
+#[test]
+fn test_blinded_route_payment() {
+	// A payment to a recipient hidden behind a blinded route is received and claimed as usual,
+	// without the sender learning the recipient's node id or the channels it was paid over.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_1_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_2_3 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	let (payment_preimage, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let payment_secret = PaymentSecret([0x42; 32]);
+	let hops = blinded_route_hints(&nodes, &[(1, &chan_1_2.0), (2, &chan_2_3.0)]);
+	let blinded_route = nodes[3].node.create_blinded_route(&hops, &payment_secret).unwrap();
+	assert_eq!(blinded_route.introduction_node_id, nodes[1].node.get_our_node_id());
+
+	let route = get_blinded_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &blinded_route, None, 1_000_000, TEST_FINAL_CLTV, &logger).unwrap();
+	assert_eq!(route.paths[0].len(), 3);
+	assert_eq!(route.paths[0][0].pubkey, nodes[1].node.get_our_node_id());
+	for hop in route.paths[0].iter() {
+		assert_ne!(hop.pubkey, nodes[2].node.get_our_node_id());
+		assert_ne!(hop.pubkey, nodes[3].node.get_our_node_id());
+	}
+
+	nodes[0].node.send_payment_to_blinded_route(&route, &blinded_route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+	pass_along_route(&nodes[0], &[&[&nodes[1], &nodes[2], &nodes[3]]], 1_000_000, payment_hash, Some(payment_secret));
+	claim_payment_along_route_with_secret(&nodes[0], &[&[&nodes[1], &nodes[2], &nodes[3]]], false, payment_preimage, Some(payment_secret), 1_000_000);
+
+}
+
+#[test]
+fn test_blinded_route_failure() {
+	// A failure within a blinded route is reported to the sender by the introduction node as
+	// invalid_onion_blinding, without revealing which hop failed or why.
+	let chanmon_cfgs = create_chanmon_cfgs(4);
+	let node_cfgs = create_node_cfgs(4, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(4, &node_cfgs, &[None, None, None, None]);
+	let nodes = create_network(4, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_1_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_2_3 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	// The recipient tells nodes[2] to forward over a channel it doesn't have
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let payment_secret = PaymentSecret([0x42; 32]);
+	let mut hops = blinded_route_hints(&nodes, &[(1, &chan_1_2.0), (2, &chan_2_3.0)]);
+	hops[1].short_channel_id = 0xdeadbeef;
+	let blinded_route = nodes[3].node.create_blinded_route(&hops, &payment_secret).unwrap();
+	let route = get_blinded_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &blinded_route, None, 1_000_000, TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_payment_to_blinded_route(&route, &blinded_route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	assert!(payment_event.msgs[0].blinding_point.is_none());
+	nodes[1].node.handle_update_add_htlc(&nodes[0].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[1], nodes[0], payment_event.commitment_msg, false);
+	expect_pending_htlcs_forwardable!(nodes[1]);
+	check_added_monitors!(nodes[1], 1);
+
+	// The introduction node hands the next hop the blinding point it needs to unblind itself
+	let mut events = nodes[1].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let payment_event = SendEvent::from_event(events.pop().unwrap());
+	assert_eq!(payment_event.node_id, nodes[2].node.get_our_node_id());
+	assert!(payment_event.msgs[0].blinding_point.is_some());
+	nodes[2].node.handle_update_add_htlc(&nodes[1].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[2], nodes[1], payment_event.commitment_msg, false, true);
+
+	// Within the blinded route, the failure is malformed rather than unknown_next_peer...
+	let fail_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	assert!(fail_updates.update_fail_htlcs.is_empty());
+	assert_eq!(fail_updates.update_fail_malformed_htlcs.len(), 1);
+	assert_eq!(fail_updates.update_fail_malformed_htlcs[0].failure_code, 0x8000 | 0x4000 | 24);
+	nodes[1].node.handle_update_fail_malformed_htlc(&nodes[2].node.get_our_node_id(), &fail_updates.update_fail_malformed_htlcs[0]);
+	commitment_signed_dance!(nodes[1], nodes[2], fail_updates.commitment_signed, true);
+
+	// ...which the introduction node turns into an invalid_onion_blinding error for the sender,
+	// who cannot tell which channel to blame.
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false, true);
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+	expect_payment_failed!(nodes[0], payment_hash, false, 0x8000 | 0x4000 | 24, &[0; 32]);
+}
+
+#[test]
+fn test_blinded_route_failure_past_first_blinded_hop() {
+	// A failure two hops inside a blinded route is passed back as malformed by every hop after the
+	// introduction node, rather than as an onion error the sender could attribute to a hop.
+	let chanmon_cfgs = create_chanmon_cfgs(5);
+	let node_cfgs = create_node_cfgs(5, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(5, &node_cfgs, &[None, None, None, None, None]);
+	let nodes = create_network(5, &node_cfgs, &node_chanmgrs);
+	create_announced_chan_between_nodes(&nodes, 0, 1, InitFeatures::known(), InitFeatures::known());
+	let chan_1_2 = create_announced_chan_between_nodes(&nodes, 1, 2, InitFeatures::known(), InitFeatures::known());
+	let chan_2_3 = create_announced_chan_between_nodes(&nodes, 2, 3, InitFeatures::known(), InitFeatures::known());
+	let chan_3_4 = create_announced_chan_between_nodes(&nodes, 3, 4, InitFeatures::known(), InitFeatures::known());
+	let logger = test_utils::TestLogger::new();
+
+	// The recipient tells nodes[3] to forward over a channel it doesn't have
+	let (_, payment_hash) = get_payment_preimage_hash!(nodes[0]);
+	let payment_secret = PaymentSecret([0x42; 32]);
+	let mut hops = blinded_route_hints(&nodes, &[(1, &chan_1_2.0), (2, &chan_2_3.0), (3, &chan_3_4.0)]);
+	hops[2].short_channel_id = 0xdeadbeef;
+	let blinded_route = nodes[4].node.create_blinded_route(&hops, &payment_secret).unwrap();
+	let route = get_blinded_route(&nodes[0].node.get_our_node_id(), &nodes[0].net_graph_msg_handler.network_graph.read().unwrap(), &blinded_route, None, 1_000_000, TEST_FINAL_CLTV, &logger).unwrap();
+	nodes[0].node.send_payment_to_blinded_route(&route, &blinded_route, payment_hash, &Some(payment_secret)).unwrap();
+	check_added_monitors!(nodes[0], 1);
+
+	let mut events = nodes[0].node.get_and_clear_pending_msg_events();
+	assert_eq!(events.len(), 1);
+	let mut payment_event = SendEvent::from_event(events.pop().unwrap());
+	for hop in 1..3 {
+		nodes[hop].node.handle_update_add_htlc(&nodes[hop - 1].node.get_our_node_id(), &payment_event.msgs[0]);
+		commitment_signed_dance!(nodes[hop], nodes[hop - 1], payment_event.commitment_msg, false);
+		expect_pending_htlcs_forwardable!(nodes[hop]);
+		check_added_monitors!(nodes[hop], 1);
+
+		let mut events = nodes[hop].node.get_and_clear_pending_msg_events();
+		assert_eq!(events.len(), 1);
+		payment_event = SendEvent::from_event(events.pop().unwrap());
+		assert_eq!(payment_event.node_id, nodes[hop + 1].node.get_our_node_id());
+		assert!(payment_event.msgs[0].blinding_point.is_some());
+	}
+	nodes[3].node.handle_update_add_htlc(&nodes[2].node.get_our_node_id(), &payment_event.msgs[0]);
+	commitment_signed_dance!(nodes[3], nodes[2], payment_event.commitment_msg, false, true);
+
+	// Both nodes[3] and nodes[2] are within the blinded route, so fail back as malformed...
+	let fail_updates = get_htlc_update_msgs!(nodes[3], nodes[2].node.get_our_node_id());
+	assert!(fail_updates.update_fail_htlcs.is_empty());
+	assert_eq!(fail_updates.update_fail_malformed_htlcs.len(), 1);
+	nodes[2].node.handle_update_fail_malformed_htlc(&nodes[3].node.get_our_node_id(), &fail_updates.update_fail_malformed_htlcs[0]);
+	commitment_signed_dance!(nodes[2], nodes[3], fail_updates.commitment_signed, true);
+
+	let fail_updates = get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
+	assert!(fail_updates.update_fail_htlcs.is_empty());
+	assert_eq!(fail_updates.update_fail_malformed_htlcs.len(), 1);
+	assert_eq!(fail_updates.update_fail_malformed_htlcs[0].failure_code, 0x8000 | 0x4000 | 24);
+	nodes[1].node.handle_update_fail_malformed_htlc(&nodes[2].node.get_our_node_id(), &fail_updates.update_fail_malformed_htlcs[0]);
+	commitment_signed_dance!(nodes[1], nodes[2], fail_updates.commitment_signed, true);
+
+	// ...and only the introduction node reports invalid_onion_blinding to the sender.
+	let fail_updates = get_htlc_update_msgs!(nodes[1], nodes[0].node.get_our_node_id());
+	assert_eq!(fail_updates.update_fail_htlcs.len(), 1);
+	nodes[0].node.handle_update_fail_htlc(&nodes[1].node.get_our_node_id(), &fail_updates.update_fail_htlcs[0]);
+	commitment_signed_dance!(nodes[0], nodes[1], fail_updates.commitment_signed, false, true);
+	assert!(nodes[0].node.get_and_clear_pending_msg_events().is_empty());
+	expect_payment_failed!(nodes[0], payment_hash, false, 0x8000 | 0x4000 | 24, &[0; 32]);
+}
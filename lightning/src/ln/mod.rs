@@ -19,6 +19,7 @@
 //! call into your NetGraphMsgHandler.
 
 pub mod channelmanager;
+pub mod channelbackup;
 pub mod msgs;
 pub mod peers;
 pub mod chan_utils;
@@ -36,7 +36,9 @@ use std::{cmp, fmt};
 use std::io::Read;
 
 use util::events;
-use util::ser::{Readable, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedVarInt};
+use util::ser::{Readable, Writeable, Writer, FixedLengthReader, HighZeroBytesDroppedVarInt, BigSize};
+use util::byte_utils::{be32_to_array, be64_to_array, slice_to_be32, slice_to_be64};
+use util::zlib;
 
 use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret};
 
@@ -243,6 +245,9 @@ pub struct UpdateAddHTLC {
 	/// The expiry height of the HTLC
 	pub cltv_expiry: u32,
 	pub(crate) onion_routing_packet: OnionPacket,
+	/// The point used to unblind our hop of a blinded route, set when we are a hop within a
+	/// blinded route other than its introduction node
+	pub blinding_point: Option<PublicKey>,
 }
 
 /// An update_fulfill_htlc message to be sent or received from a peer
@@ -582,15 +587,18 @@ pub struct QueryChannelRange {
 	pub first_blocknum: u32,
 	/// The number of blocks to include in the query results
 	pub number_of_blocks: u32,
+	/// A bitfield of additional information requested for each channel in the replies, see
+	/// QUERY_OPTION_TIMESTAMPS and QUERY_OPTION_CHECKSUMS. None if no query_option TLV was
+	/// included.
+	pub query_option: Option<u64>,
 }
 
 /// A reply_channel_range message is a reply to a query_channel_range
 /// message. Multiple reply_channel_range messages can be sent in reply
 /// to a single query_channel_range message. The query recipient makes a
 /// best effort to respond based on their local network view which may
-/// not be a perfect view of the network. The short_channel_ids in the
-/// reply are encoded. We only support encoding_type=0 uncompressed
-/// serialization and do not support encoding_type=1 zlib serialization.
+/// not be a perfect view of the network. The short_channel_ids and
+/// timestamps in the reply are encoded with encoding_type.
 #[derive(Clone, Debug)]
 pub struct ReplyChannelRange {
 	/// The genesis hash of the blockchain being queried
@@ -604,6 +612,35 @@ pub struct ReplyChannelRange {
 	pub full_information: bool,
 	/// The short_channel_ids in the channel range
 	pub short_channel_ids: Vec<u64>,
+	/// The encoding used for short_channel_ids and timestamps
+	pub encoding_type: EncodingType,
+	/// The timestamps of the latest channel_update in each direction of each channel, if
+	/// requested with QUERY_OPTION_TIMESTAMPS
+	pub timestamps: Option<Vec<ChannelUpdateTimestamps>>,
+	/// The checksums of the latest channel_update in each direction of each channel, if
+	/// requested with QUERY_OPTION_CHECKSUMS
+	pub checksums: Option<Vec<ChannelUpdateChecksums>>,
+}
+
+/// The timestamps of the latest channel_update sent by each side of a channel, as included in a
+/// reply_channel_range. A timestamp of 0 indicates no channel_update is known for that side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelUpdateTimestamps {
+	/// The timestamp of the channel_update from node_id_1
+	pub timestamp_node_id_1: u32,
+	/// The timestamp of the channel_update from node_id_2
+	pub timestamp_node_id_2: u32,
+}
+
+/// The checksums of the latest channel_update sent by each side of a channel, as included in a
+/// reply_channel_range. Each checksum is the CRC32C of the channel_update with its signature and
+/// timestamp removed, or 0 if no channel_update is known for that side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelUpdateChecksums {
+	/// The checksum of the channel_update from node_id_1
+	pub checksum_node_id_1: u32,
+	/// The checksum of the channel_update from node_id_2
+	pub checksum_node_id_2: u32,
 }
 
 /// A query_short_channel_ids message is used to query a peer for
@@ -611,15 +648,20 @@ pub struct ReplyChannelRange {
 /// The query recipient will reply with the latest, if available,
 /// channel_announcement, channel_update and node_announcement messages
 /// it maintains for the requested short_channel_ids followed by a
-/// reply_short_channel_ids_end message. The short_channel_ids sent in
-/// this query are encoded. We only support encoding_type=0 uncompressed
-/// serialization and do not support encoding_type=1 zlib serialization.
+/// reply_short_channel_ids_end message. The short_channel_ids and
+/// query_flags sent in this query are encoded with encoding_type.
 #[derive(Clone, Debug)]
 pub struct QueryShortChannelIds {
 	/// The genesis hash of the blockchain being queried
 	pub chain_hash: BlockHash,
 	/// The short_channel_ids that are being queried
 	pub short_channel_ids: Vec<u64>,
+	/// The encoding used for short_channel_ids and query_flags
+	pub encoding_type: EncodingType,
+	/// A bitfield for each short_channel_id indicating which of the messages related to it are
+	/// requested, see the QUERY_FLAG_* constants. None if everything is requested for every
+	/// channel.
+	pub query_flags: Option<Vec<u64>>,
 }
 
 /// A reply_short_channel_ids_end message is sent as a reply to a
@@ -649,10 +691,29 @@ pub struct GossipTimestampFilter {
 }
 
 /// Encoding type for data compression of collections in gossip queries.
-/// We do not support encoding_type=1 zlib serialization defined in BOLT #7.
-enum EncodingType {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncodingType {
+	/// The collection is serialized as-is
 	Uncompressed = 0x00,
-}
+	/// The collection is compressed with zlib
+	Zlib = 0x01,
+}
+
+/// query_option bit requesting the timestamps of each channel's channel_updates
+pub const QUERY_OPTION_TIMESTAMPS: u64 = 1 << 0;
+/// query_option bit requesting the checksums of each channel's channel_updates
+pub const QUERY_OPTION_CHECKSUMS: u64 = 1 << 1;
+
+/// query_flags bit requesting a channel's channel_announcement
+pub const QUERY_FLAG_CHANNEL_ANNOUNCEMENT: u64 = 1 << 0;
+/// query_flags bit requesting a channel's channel_update from node_id_1
+pub const QUERY_FLAG_CHANNEL_UPDATE_1: u64 = 1 << 1;
+/// query_flags bit requesting a channel's channel_update from node_id_2
+pub const QUERY_FLAG_CHANNEL_UPDATE_2: u64 = 1 << 2;
+/// query_flags bit requesting the node_announcement of a channel's node_id_1
+pub const QUERY_FLAG_NODE_ANNOUNCEMENT_1: u64 = 1 << 3;
+/// query_flags bit requesting the node_announcement of a channel's node_id_2
+pub const QUERY_FLAG_NODE_ANNOUNCEMENT_2: u64 = 1 << 4;
 
 /// Used to put an error message in a LightningError
 #[derive(Clone)]
@@ -701,7 +762,7 @@ pub struct CommitmentUpdate {
 /// The information we received from a peer along the route of a payment we originated. This is
 /// returned by ChannelMessageHandler::handle_update_fail_htlc to be passed into
 /// RoutingMessageHandler::handle_htlc_fail_channel_update to update our network map.
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum HTLCFailChannelUpdate {
 	/// We received an error which included a full ChannelUpdate message.
 	ChannelUpdateMessage {
@@ -793,6 +854,9 @@ pub trait ChannelMessageHandler : events::MessageSendEventsProvider + Send + Syn
 
 	/// Handle a peer reconnecting, possibly generating channel_reestablish message(s).
 	fn peer_connected(&self, their_node_id: &PublicKey, msg: &Init);
+	/// Gets the features we advertise to peers in our init message, which may depend on our
+	/// configuration.
+	fn provided_init_features(&self) -> InitFeatures;
 	/// Handle an incoming channel_reestablish message from the given peer.
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish);
 
@@ -825,9 +889,34 @@ pub trait RoutingMessageHandler : Send + Sync {
 	fn get_next_node_announcements(&self, starting_point: Option<&PublicKey>, batch_amount: u8) -> Vec<NodeAnnouncement>;
 	/// Returns whether a full sync should be requested from a peer.
 	fn should_request_full_sync(&self, node_id: &PublicKey) -> bool;
+
+	// Gossip queries:
+	/// Called when a connection is established with a peer which supports the gossip_queries
+	/// feature. Returns the gossip_timestamp_filter which should be sent to the peer to start
+	/// receiving its gossip and, if full_sync is set (as returned by should_request_full_sync
+	/// for this peer), a query_channel_range to learn of the channels we are missing.
+	fn sync_routing_table(&self, their_node_id: &PublicKey, full_sync: bool) -> (Option<GossipTimestampFilter>, Option<QueryChannelRange>);
+	/// Handle an incoming query_channel_range message from the given peer, returning the
+	/// reply_channel_range messages which should be sent back, in order.
+	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, LightningError>;
+	/// Handle an incoming reply_channel_range message from the given peer, returning a
+	/// query_short_channel_ids message to send if we should now query for channels we are missing.
+	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: &ReplyChannelRange) -> Result<Option<QueryShortChannelIds>, LightningError>;
+	/// Handle an incoming query_short_channel_ids message from the given peer, returning the
+	/// channel announcements and updates, followed by the node announcements, and finally the
+	/// reply_short_channel_ids_end message which should be sent back. Channel announcements may be
+	/// omitted if the peer's query_flags did not request them.
+	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(Vec<(Option<ChannelAnnouncement>, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>, ReplyShortChannelIdsEnd), LightningError>;
+	/// Handle an incoming reply_short_channel_ids_end message from the given peer, returning the
+	/// next query_short_channel_ids message to send if we still have channels to query for.
+	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: &ReplyShortChannelIdsEnd) -> Result<Option<QueryShortChannelIds>, LightningError>;
+	/// Handle an incoming gossip_timestamp_filter message from the given peer, returning an error
+	/// if the filter should be ignored, eg because it is for a chain we don't know.
+	fn handle_gossip_timestamp_filter(&self, their_node_id: &PublicKey, msg: &GossipTimestampFilter) -> Result<(), LightningError>;
 }
 
 mod fuzzy_internal_msgs {
+	use bitcoin::secp256k1::key::PublicKey;
 	use ln::channelmanager::PaymentSecret;
 
 	// These types aren't intended to be pub, but are exposed for direct fuzzing (as we deserialize
@@ -850,6 +939,32 @@ mod fuzzy_internal_msgs {
 		FinalNode {
 			payment_data: Option<FinalOnionHopData>,
 		},
+		/// The final hop of the outer onion, carrying a nested trampoline onion which tells the
+		/// receiving node where the payment goes next.
+		TrampolineEntry {
+			payment_data: Option<FinalOnionHopData>,
+			trampoline_packet: super::TrampolineOnionPacket,
+		},
+		/// A hop within a trampoline onion, asking the receiving trampoline node to find a route to
+		/// the given node itself.
+		TrampolineForward {
+			outgoing_node_id: PublicKey,
+		},
+		/// A hop within a blinded route, where the payment goes next (or that it is for us)
+		/// is encrypted for the receiving node by the route's creator. The introduction node of
+		/// the route is given the blinding point here, later hops get it in update_add_htlc.
+		Blinded {
+			encrypted_data: Vec<u8>,
+			blinding_point: Option<PublicKey>,
+			payment_data: Option<FinalOnionHopData>,
+		},
+	}
+
+	/// The data the creator of a blinded route encrypts for each of its hops. Forwarding hops are
+	/// given the channel to forward over, the final hop the path_id the creator picked.
+	pub(crate) struct BlindedHopData {
+		pub(crate) short_channel_id: Option<u64>,
+		pub(crate) path_id: Option<[u8; 32]>,
 	}
 
 	pub struct OnionHopData {
@@ -894,6 +1009,33 @@ impl PartialEq for OnionPacket {
 	}
 }
 
+/// The length of the hop data in a trampoline onion, which is nested in the final hop payload of
+/// the outer onion and thus much smaller than the 1300 bytes of a full [`OnionPacket`].
+pub(crate) const TRAMPOLINE_ONION_DATA_LEN: usize = 400;
+
+/// An onion packet nested within the final hop payload of a regular onion, routing a payment
+/// through one or more trampoline nodes which compute the routes between each other themselves.
+#[derive(Clone)]
+pub struct TrampolineOnionPacket {
+	pub(crate) version: u8,
+	/// As with [`OnionPacket`], we hold a Result so that a bogus ephemeral key can be reported as
+	/// an onion error rather than a decode failure.
+	pub(crate) public_key: Result<PublicKey, secp256k1::Error>,
+	pub(crate) hop_data: [u8; TRAMPOLINE_ONION_DATA_LEN],
+	pub(crate) hmac: [u8; 32],
+}
+
+impl PartialEq for TrampolineOnionPacket {
+	fn eq(&self, other: &TrampolineOnionPacket) -> bool {
+		for (i, j) in self.hop_data.iter().zip(other.hop_data.iter()) {
+			if i != j { return false; }
+		}
+		self.version == other.version &&
+			self.public_key == other.public_key &&
+			self.hmac == other.hmac
+	}
+}
+
 #[derive(Clone, PartialEq)]
 pub(crate) struct OnionErrorPacket {
 	// This really should be a constant size slice, but the spec lets these things be up to 128KB?
@@ -1200,14 +1342,78 @@ impl Readable for OnionPacket {
 	}
 }
 
-impl_writeable!(UpdateAddHTLC, 32+8+8+32+4+1366, {
-	channel_id,
-	htlc_id,
-	amount_msat,
-	payment_hash,
-	cltv_expiry,
-	onion_routing_packet
-});
+impl Writeable for TrampolineOnionPacket {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(1 + 33 + TRAMPOLINE_ONION_DATA_LEN + 32);
+		self.version.write(w)?;
+		match self.public_key {
+			Ok(pubkey) => pubkey.write(w)?,
+			Err(_) => [0u8;33].write(w)?,
+		}
+		w.write_all(&self.hop_data)?;
+		self.hmac.write(w)?;
+		Ok(())
+	}
+}
+
+impl Readable for TrampolineOnionPacket {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		Ok(TrampolineOnionPacket {
+			version: Readable::read(r)?,
+			public_key: {
+				let mut buf = [0u8;33];
+				r.read_exact(&mut buf)?;
+				PublicKey::from_slice(&buf)
+			},
+			hop_data: Readable::read(r)?,
+			hmac: Readable::read(r)?,
+		})
+	}
+}
+
+impl Writeable for UpdateAddHTLC {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.size_hint(32+8+8+32+4+1366 + if self.blinding_point.is_some() { 1+1+33 } else { 0 });
+		self.channel_id.write(w)?;
+		self.htlc_id.write(w)?;
+		self.amount_msat.write(w)?;
+		self.payment_hash.write(w)?;
+		self.cltv_expiry.write(w)?;
+		self.onion_routing_packet.write(w)?;
+		if let Some(ref blinding_point) = self.blinding_point {
+			encode_tlv!(w, {
+				(0, blinding_point)
+			});
+		}
+		Ok(())
+	}
+}
+
+impl Readable for UpdateAddHTLC {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let channel_id = Readable::read(r)?;
+		let htlc_id = Readable::read(r)?;
+		let amount_msat = Readable::read(r)?;
+		let payment_hash = Readable::read(r)?;
+		let cltv_expiry = Readable::read(r)?;
+		let onion_routing_packet = Readable::read(r)?;
+
+		let mut blinding_point: Option<PublicKey> = None;
+		decode_tlv!(&mut r, {}, {
+			(0, blinding_point)
+		});
+
+		Ok(UpdateAddHTLC {
+			channel_id,
+			htlc_id,
+			amount_msat,
+			payment_hash,
+			cltv_expiry,
+			onion_routing_packet,
+			blinding_point,
+		})
+	}
+}
 
 impl Writeable for FinalOnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
@@ -1225,6 +1431,37 @@ impl Readable for FinalOnionHopData {
 	}
 }
 
+impl Writeable for BlindedHopData {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		match (self.short_channel_id, self.path_id) {
+			(Some(short_channel_id), None) => encode_tlv!(w, {
+				(2, short_channel_id)
+			}),
+			(None, Some(ref path_id)) => encode_tlv!(w, {
+				(6, path_id)
+			}),
+			(Some(short_channel_id), Some(ref path_id)) => encode_tlv!(w, {
+				(2, short_channel_id),
+				(6, path_id)
+			}),
+			(None, None) => {},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BlindedHopData {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let mut short_channel_id: Option<u64> = None;
+		let mut path_id: Option<[u8; 32]> = None;
+		decode_tlv!(&mut r, {}, {
+			(2, short_channel_id),
+			(6, path_id)
+		});
+		Ok(BlindedHopData { short_channel_id, path_id })
+	}
+}
+
 impl Writeable for OnionHopData {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(33);
@@ -1261,19 +1498,73 @@ impl Writeable for OnionHopData {
 					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value))
 				});
 			},
+			OnionHopDataFormat::TrampolineEntry { payment_data: Some(ref final_data), ref trampoline_packet } => {
+				if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(8, final_data),
+					(66100, trampoline_packet)
+				});
+			},
+			OnionHopDataFormat::TrampolineEntry { payment_data: None, ref trampoline_packet } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(66100, trampoline_packet)
+				});
+			},
+			OnionHopDataFormat::TrampolineForward { ref outgoing_node_id } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(66098, outgoing_node_id)
+				});
+			},
+			OnionHopDataFormat::Blinded { ref encrypted_data, blinding_point: Some(ref blinding_point), payment_data: Some(ref final_data) } => {
+				if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(8, final_data),
+					(10, TlvBytes(encrypted_data.clone())),
+					(12, blinding_point)
+				});
+			},
+			OnionHopDataFormat::Blinded { ref encrypted_data, blinding_point: Some(ref blinding_point), payment_data: None } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(10, TlvBytes(encrypted_data.clone())),
+					(12, blinding_point)
+				});
+			},
+			OnionHopDataFormat::Blinded { ref encrypted_data, blinding_point: None, payment_data: Some(ref final_data) } => {
+				if final_data.total_msat > MAX_VALUE_MSAT { panic!("We should never be sending infinite/overflow onion payments"); }
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(8, final_data),
+					(10, TlvBytes(encrypted_data.clone()))
+				});
+			},
+			OnionHopDataFormat::Blinded { ref encrypted_data, blinding_point: None, payment_data: None } => {
+				encode_varint_length_prefixed_tlv!(w, {
+					(2, HighZeroBytesDroppedVarInt(self.amt_to_forward)),
+					(4, HighZeroBytesDroppedVarInt(self.outgoing_cltv_value)),
+					(10, TlvBytes(encrypted_data.clone()))
+				});
+			},
 		}
 		Ok(())
 	}
 }
 
 impl Readable for OnionHopData {
-	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
-		use bitcoin::consensus::encode::{Decodable, Error, VarInt};
-		let v: VarInt = Decodable::consensus_decode(&mut r)
-			.map_err(|e| match e {
-				Error::Io(ioe) => DecodeError::from(ioe),
-				_ => DecodeError::InvalidValue
-			})?;
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		// The length prefix is a BigSize, which only matches a bitcoin VarInt below 0xfd, so it
+		// must be read as such now that trampoline payloads may exceed that.
+		let v: BigSize = Readable::read(r)?;
 		const LEGACY_ONION_HOP_FLAG: u64 = 0;
 		let (format, amt, cltv_value) = if v.0 != LEGACY_ONION_HOP_FLAG {
 			let mut rd = FixedLengthReader::new(r, v.0);
@@ -1281,27 +1572,63 @@ impl Readable for OnionHopData {
 			let mut cltv_value = HighZeroBytesDroppedVarInt(0u32);
 			let mut short_id: Option<u64> = None;
 			let mut payment_data: Option<FinalOnionHopData> = None;
+			let mut outgoing_node_id: Option<PublicKey> = None;
+			let mut trampoline_packet: Option<TrampolineOnionPacket> = None;
+			let mut encrypted_data: Option<TlvBytes> = None;
+			let mut blinding_point: Option<PublicKey> = None;
 			decode_tlv!(&mut rd, {
 				(2, amt),
 				(4, cltv_value)
 			}, {
 				(6, short_id),
-				(8, payment_data)
+				(8, payment_data),
+				(10, encrypted_data),
+				(12, blinding_point),
+				(66098, outgoing_node_id),
+				(66100, trampoline_packet)
 			});
 			rd.eat_remaining().map_err(|_| DecodeError::ShortRead)?;
+			if encrypted_data.is_none() && blinding_point.is_some() {
+				return Err(DecodeError::InvalidValue);
+			}
 			let format = if let Some(short_channel_id) = short_id {
-				if payment_data.is_some() { return Err(DecodeError::InvalidValue); }
+				if payment_data.is_some() || outgoing_node_id.is_some() || trampoline_packet.is_some() || encrypted_data.is_some() {
+					return Err(DecodeError::InvalidValue);
+				}
 				OnionHopDataFormat::NonFinalNode {
 					short_channel_id,
 				}
+			} else if let Some(outgoing_node_id) = outgoing_node_id {
+				if payment_data.is_some() || trampoline_packet.is_some() || encrypted_data.is_some() {
+					return Err(DecodeError::InvalidValue);
+				}
+				OnionHopDataFormat::TrampolineForward {
+					outgoing_node_id,
+				}
 			} else {
 				if let &Some(ref data) = &payment_data {
 					if data.total_msat > MAX_VALUE_MSAT {
 						return Err(DecodeError::InvalidValue);
 					}
 				}
-				OnionHopDataFormat::FinalNode {
-					payment_data
+				if let Some(TlvBytes(encrypted_data)) = encrypted_data {
+					if trampoline_packet.is_some() {
+						return Err(DecodeError::InvalidValue);
+					}
+					OnionHopDataFormat::Blinded {
+						encrypted_data,
+						blinding_point,
+						payment_data,
+					}
+				} else if let Some(trampoline_packet) = trampoline_packet {
+					OnionHopDataFormat::TrampolineEntry {
+						payment_data,
+						trampoline_packet,
+					}
+				} else {
+					OnionHopDataFormat::FinalNode {
+						payment_data
+					}
 				}
 			};
 			(format, amt.0, cltv_value.0)
@@ -1472,6 +1799,53 @@ impl_writeable_len_match!(ChannelUpdate, {
 	contents
 });
 
+impl Writeable for HTLCFailChannelUpdate {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		match self {
+			&HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg } => {
+				0u8.write(w)?;
+				// A ChannelUpdate reads any excess data up to the end of the stream, so needs a
+				// length prefix to be followed by anything else.
+				w.write_all(&msg.encode_with_len()[..])?;
+			},
+			&HTLCFailChannelUpdate::ChannelClosed { ref short_channel_id, ref is_permanent } => {
+				1u8.write(w)?;
+				short_channel_id.write(w)?;
+				is_permanent.write(w)?;
+			},
+			&HTLCFailChannelUpdate::NodeFailure { ref node_id, ref is_permanent } => {
+				2u8.write(w)?;
+				node_id.write(w)?;
+				is_permanent.write(w)?;
+			},
+		}
+		Ok(())
+	}
+}
+
+impl Readable for HTLCFailChannelUpdate {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		match <u8 as Readable>::read(r)? {
+			0 => {
+				let len: u16 = Readable::read(r)?;
+				let mut rd = FixedLengthReader::new(r, len as u64);
+				let msg = Readable::read(&mut rd)?;
+				rd.eat_remaining()?;
+				Ok(HTLCFailChannelUpdate::ChannelUpdateMessage { msg })
+			},
+			1 => Ok(HTLCFailChannelUpdate::ChannelClosed {
+				short_channel_id: Readable::read(r)?,
+				is_permanent: Readable::read(r)?,
+			}),
+			2 => Ok(HTLCFailChannelUpdate::NodeFailure {
+				node_id: Readable::read(r)?,
+				is_permanent: Readable::read(r)?,
+			}),
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
 impl Writeable for ErrorMessage {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
 		w.size_hint(32 + 2 + self.data.len());
@@ -1601,53 +1975,138 @@ impl_writeable_len_match!(NodeAnnouncement, {
 	contents
 });
 
-impl Readable for QueryShortChannelIds {
-	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
-		let chain_hash: BlockHash = Readable::read(r)?;
+/// The largest size we will decode an encoded collection in a gossip query to. Even when compressed
+/// with zlib, honest peers' collections are far smaller, but this keeps a peer from making us
+/// allocate arbitrary amounts of memory with a tiny, highly-compressible message.
+const MAX_DECODED_COLLECTION_LEN: usize = 1024 * 1024;
 
-		// We expect the encoding_len to always includes the 1-byte
-		// encoding_type and that short_channel_ids are 8-bytes each
-		let encoding_len: u16 = Readable::read(r)?;
-		if encoding_len == 0 || (encoding_len - 1) % 8 != 0 {
-			return Err(DecodeError::InvalidValue);
+impl EncodingType {
+	/// Encodes a collection, prefixed with the encoding_type byte.
+	fn encode(&self, data: &[u8]) -> Vec<u8> {
+		let mut res = Vec::with_capacity(1 + data.len());
+		res.push(*self as u8);
+		match *self {
+			EncodingType::Uncompressed => res.extend_from_slice(data),
+			EncodingType::Zlib => res.extend_from_slice(&zlib::compress(data)),
 		}
+		res
+	}
 
-		// Must be encoding_type=0 uncompressed serialization. We do not
-		// support encoding_type=1 zlib serialization.
-		let encoding_type: u8 = Readable::read(r)?;
-		if encoding_type != EncodingType::Uncompressed as u8 {
-			return Err(DecodeError::InvalidValue);
+	/// Decodes a collection prefixed with its encoding_type byte, returning the encoding type along
+	/// with the decoded bytes.
+	fn decode(encoded: &[u8]) -> Result<(EncodingType, Vec<u8>), DecodeError> {
+		match encoded.first() {
+			Some(&0x00) => Ok((EncodingType::Uncompressed, encoded[1..].to_vec())),
+			Some(&0x01) => {
+				let decoded = zlib::decompress(&encoded[1..], MAX_DECODED_COLLECTION_LEN).map_err(|_| DecodeError::InvalidValue)?;
+				Ok((EncodingType::Zlib, decoded))
+			},
+			_ => Err(DecodeError::InvalidValue),
 		}
+	}
+}
 
-		// Read short_channel_ids (8-bytes each), for the u16 encoding_len
-		// less the 1-byte encoding_type
-		let short_channel_id_count: u16 = (encoding_len - 1)/8;
-		let mut short_channel_ids = Vec::with_capacity(short_channel_id_count as usize);
-		for _ in 0..short_channel_id_count {
-			short_channel_ids.push(Readable::read(r)?);
-		}
+/// A TLV value which spans the rest of its TLV record and is written without a length prefix.
+struct TlvBytes(Vec<u8>);
+
+impl Writeable for TlvBytes {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
+		w.write_all(&self.0)
+	}
+}
+
+impl Readable for TlvBytes {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let mut bytes = Vec::new();
+		r.read_to_end(&mut bytes)?;
+		Ok(TlvBytes(bytes))
+	}
+}
+
+fn read_encoded_short_channel_ids<R: Read>(r: &mut R) -> Result<(EncodingType, Vec<u64>), DecodeError> {
+	// The encoding_len always includes the 1-byte encoding_type
+	let encoding_len: u16 = Readable::read(r)?;
+	let mut encoded = vec![0; encoding_len as usize];
+	r.read_exact(&mut encoded)?;
+	let (encoding_type, decoded) = EncodingType::decode(&encoded)?;
+
+	// short_channel_ids are 8-bytes each
+	if decoded.len() % 8 != 0 {
+		return Err(DecodeError::InvalidValue);
+	}
+	Ok((encoding_type, decoded.chunks(8).map(slice_to_be64).collect()))
+}
+
+fn write_encoded_short_channel_ids<W: Writer>(w: &mut W, encoding_type: EncodingType, short_channel_ids: &[u64]) -> Result<(), ::std::io::Error> {
+	let mut data = Vec::with_capacity(short_channel_ids.len() * 8);
+	for scid in short_channel_ids.iter() {
+		data.extend_from_slice(&be64_to_array(*scid));
+	}
+	let encoded = encoding_type.encode(&data);
+	if encoded.len() > ::std::u16::MAX as usize {
+		return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "too many short_channel_ids to encode"));
+	}
+	(encoded.len() as u16).write(w)?;
+	w.write_all(&encoded)
+}
+
+impl Readable for QueryShortChannelIds {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
+		let chain_hash: BlockHash = Readable::read(r)?;
+		let (encoding_type, short_channel_ids) = read_encoded_short_channel_ids(r)?;
+
+		let mut encoded_query_flags: Option<TlvBytes> = None;
+		decode_tlv!(&mut r, {}, {
+			(1, encoded_query_flags)
+		});
+		let query_flags = match encoded_query_flags {
+			Some(TlvBytes(encoded)) => {
+				let (_, decoded) = EncodingType::decode(&encoded)?;
+				let mut flags_reader = ::std::io::Cursor::new(&decoded[..]);
+				let mut query_flags = Vec::with_capacity(short_channel_ids.len());
+				while (flags_reader.position() as usize) < decoded.len() {
+					let flags: BigSize = Readable::read(&mut flags_reader)?;
+					query_flags.push(flags.0);
+				}
+				// There must be exactly one set of flags per short_channel_id
+				if query_flags.len() != short_channel_ids.len() {
+					return Err(DecodeError::InvalidValue);
+				}
+				Some(query_flags)
+			},
+			None => None,
+		};
 
 		Ok(QueryShortChannelIds {
 			chain_hash,
 			short_channel_ids,
+			encoding_type,
+			query_flags,
 		})
 	}
 }
 
 impl Writeable for QueryShortChannelIds {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
-		// Calculated from 1-byte encoding_type plus 8-bytes per short_channel_id
-		let encoding_len: u16 = 1 + self.short_channel_ids.len() as u16 * 8;
-
-		w.size_hint(32 + 2 + encoding_len as usize);
+		let encoded_query_flags = match self.query_flags {
+			Some(ref query_flags) => {
+				let mut data = Vec::with_capacity(query_flags.len());
+				for flags in query_flags.iter() {
+					BigSize(*flags).write(&mut data)?;
+				}
+				Some(TlvBytes(self.encoding_type.encode(&data)))
+			},
+			None => None,
+		};
+		// Each TLV record adds at most a 1-byte type and 5-byte length to its value
+		w.size_hint(32 + 2 + 1 + self.short_channel_ids.len() * 8 + encoded_query_flags.as_ref().map(|flags| 6 + flags.0.len()).unwrap_or(0));
 		self.chain_hash.write(w)?;
-		encoding_len.write(w)?;
+		write_encoded_short_channel_ids(w, self.encoding_type, &self.short_channel_ids)?;
 
-		// We only support type=0 uncompressed serialization
-		(EncodingType::Uncompressed as u8).write(w)?;
-
-		for scid in self.short_channel_ids.iter() {
-			scid.write(w)?;
+		if let Some(encoded_query_flags) = encoded_query_flags {
+			encode_tlv!(w, {
+				(1, encoded_query_flags)
+			});
 		}
 
 		Ok(())
@@ -1675,80 +2134,133 @@ impl Writeable for ReplyShortChannelIdsEnd {
 }
 
 impl Readable for QueryChannelRange {
-	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
 		let chain_hash: BlockHash = Readable::read(r)?;
 		let first_blocknum: u32 = Readable::read(r)?;
 		let number_of_blocks: u32 = Readable::read(r)?;
+
+		let mut query_option: Option<BigSize> = None;
+		decode_tlv!(&mut r, {}, {
+			(1, query_option)
+		});
+
 		Ok(QueryChannelRange {
 			chain_hash,
 			first_blocknum,
-			number_of_blocks
+			number_of_blocks,
+			query_option: query_option.map(|option| option.0),
 		})
 	}
 }
 
 impl Writeable for QueryChannelRange {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
-		w.size_hint(32 + 4 + 4);
+		// The query_option TLV adds at most a 1-byte type, 1-byte length and 9-byte BigSize
+		w.size_hint(32 + 4 + 4 + if self.query_option.is_some() { 11 } else { 0 });
 		self.chain_hash.write(w)?;
 		self.first_blocknum.write(w)?;
 		self.number_of_blocks.write(w)?;
+		if let Some(query_option) = self.query_option {
+			encode_tlv!(w, {
+				(1, BigSize(query_option))
+			});
+		}
 		Ok(())
 	}
 }
 
 impl Readable for ReplyChannelRange {
-	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+	fn read<R: Read>(mut r: &mut R) -> Result<Self, DecodeError> {
 		let chain_hash: BlockHash = Readable::read(r)?;
 		let first_blocknum: u32 = Readable::read(r)?;
 		let number_of_blocks: u32 = Readable::read(r)?;
 		let full_information: bool = Readable::read(r)?;
-
-		// We expect the encoding_len to always includes the 1-byte
-		// encoding_type and that short_channel_ids are 8-bytes each
-		let encoding_len: u16 = Readable::read(r)?;
-		if encoding_len == 0 || (encoding_len - 1) % 8 != 0 {
-			return Err(DecodeError::InvalidValue);
-		}
-
-		// Must be encoding_type=0 uncompressed serialization. We do not
-		// support encoding_type=1 zlib serialization.
-		let encoding_type: u8 = Readable::read(r)?;
-		if encoding_type != EncodingType::Uncompressed as u8 {
-			return Err(DecodeError::InvalidValue);
-		}
-
-		// Read short_channel_ids (8-bytes each), for the u16 encoding_len
-		// less the 1-byte encoding_type
-		let short_channel_id_count: u16 = (encoding_len - 1)/8;
-		let mut short_channel_ids = Vec::with_capacity(short_channel_id_count as usize);
-		for _ in 0..short_channel_id_count {
-			short_channel_ids.push(Readable::read(r)?);
-		}
+		let (encoding_type, short_channel_ids) = read_encoded_short_channel_ids(r)?;
+
+		let mut encoded_timestamps: Option<TlvBytes> = None;
+		let mut raw_checksums: Option<TlvBytes> = None;
+		decode_tlv!(&mut r, {}, {
+			(1, encoded_timestamps),
+			(3, raw_checksums)
+		});
+
+		// Both timestamps and checksums are a pair of u32s per short_channel_id
+		let timestamps = match encoded_timestamps {
+			Some(TlvBytes(encoded)) => {
+				let (_, decoded) = EncodingType::decode(&encoded)?;
+				if decoded.len() != short_channel_ids.len() * 8 {
+					return Err(DecodeError::InvalidValue);
+				}
+				Some(decoded.chunks(8).map(|pair| ChannelUpdateTimestamps {
+					timestamp_node_id_1: slice_to_be32(&pair[0..4]),
+					timestamp_node_id_2: slice_to_be32(&pair[4..8]),
+				}).collect())
+			},
+			None => None,
+		};
+		let checksums = match raw_checksums {
+			Some(TlvBytes(raw)) => {
+				if raw.len() != short_channel_ids.len() * 8 {
+					return Err(DecodeError::InvalidValue);
+				}
+				Some(raw.chunks(8).map(|pair| ChannelUpdateChecksums {
+					checksum_node_id_1: slice_to_be32(&pair[0..4]),
+					checksum_node_id_2: slice_to_be32(&pair[4..8]),
+				}).collect())
+			},
+			None => None,
+		};
 
 		Ok(ReplyChannelRange {
 			chain_hash,
 			first_blocknum,
 			number_of_blocks,
 			full_information,
-			short_channel_ids
+			short_channel_ids,
+			encoding_type,
+			timestamps,
+			checksums,
 		})
 	}
 }
 
 impl Writeable for ReplyChannelRange {
 	fn write<W: Writer>(&self, w: &mut W) -> Result<(), ::std::io::Error> {
-		let encoding_len: u16 = 1 + self.short_channel_ids.len() as u16 * 8;
-		w.size_hint(32 + 4 + 4 + 1 + 2 + encoding_len as usize);
+		let encoded_timestamps = self.timestamps.as_ref().map(|timestamps| {
+			let mut data = Vec::with_capacity(timestamps.len() * 8);
+			for pair in timestamps.iter() {
+				data.extend_from_slice(&be32_to_array(pair.timestamp_node_id_1));
+				data.extend_from_slice(&be32_to_array(pair.timestamp_node_id_2));
+			}
+			TlvBytes(self.encoding_type.encode(&data))
+		});
+		let raw_checksums = self.checksums.as_ref().map(|checksums| {
+			let mut data = Vec::with_capacity(checksums.len() * 8);
+			for pair in checksums.iter() {
+				data.extend_from_slice(&be32_to_array(pair.checksum_node_id_1));
+				data.extend_from_slice(&be32_to_array(pair.checksum_node_id_2));
+			}
+			TlvBytes(data)
+		});
+		// Each TLV record adds at most a 1-byte type and 5-byte length to its value
+		w.size_hint(32 + 4 + 4 + 1 + 2 + 1 + self.short_channel_ids.len() * 8 +
+			encoded_timestamps.as_ref().map(|timestamps| 6 + timestamps.0.len()).unwrap_or(0) +
+			raw_checksums.as_ref().map(|checksums| 6 + checksums.0.len()).unwrap_or(0));
 		self.chain_hash.write(w)?;
 		self.first_blocknum.write(w)?;
 		self.number_of_blocks.write(w)?;
 		self.full_information.write(w)?;
+		write_encoded_short_channel_ids(w, self.encoding_type, &self.short_channel_ids)?;
 
-		encoding_len.write(w)?;
-		(EncodingType::Uncompressed as u8).write(w)?;
-		for scid in self.short_channel_ids.iter() {
-			scid.write(w)?;
+		if let Some(encoded_timestamps) = encoded_timestamps {
+			encode_tlv!(w, {
+				(1, encoded_timestamps)
+			});
+		}
+		if let Some(raw_checksums) = raw_checksums {
+			encode_tlv!(w, {
+				(3, raw_checksums)
+			});
 		}
 
 		Ok(())
@@ -2071,6 +2583,14 @@ mod tests {
 			target_value.append(&mut hex::decode("000000003b9aca00").unwrap());
 		}
 		assert_eq!(encoded_value, target_value);
+
+		// Wrapped in an HTLCFailChannelUpdate, the message may be followed by other data
+		let update = msgs::HTLCFailChannelUpdate::ChannelUpdateMessage { msg: channel_update };
+		let mut encoded_update = update.encode();
+		encoded_update.push(42);
+		let mut reader = Cursor::new(&encoded_update);
+		assert_eq!(<msgs::HTLCFailChannelUpdate as Readable>::read(&mut reader).unwrap(), update);
+		assert_eq!(<u8 as Readable>::read(&mut reader).unwrap(), 42);
 	}
 
 	#[test]
@@ -2286,13 +2806,44 @@ mod tests {
 			amount_msat: 3608586615801332854,
 			payment_hash: PaymentHash([1; 32]),
 			cltv_expiry: 821716,
-			onion_routing_packet
+			onion_routing_packet,
+			blinding_point: None,
 		};
 		let encoded_value = update_add_htlc.encode();
 		let target_value = hex::decode("020202020202020202020202020202020202020202020202020202020202020200083a840000034d32144668701144760101010101010101010101010101010101010101010101010101010101010101000c89d4ff031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010101010202020202020202020202020202020202020202020202020202020202020202").unwrap();
 		assert_eq!(encoded_value, target_value);
 	}
 
+	#[test]
+	fn encoding_update_add_htlc_with_blinding_point() {
+		let secp_ctx = Secp256k1::new();
+		let (_, pubkey_1) = get_keys_from!("0101010101010101010101010101010101010101010101010101010101010101", secp_ctx);
+		let onion_routing_packet = msgs::OnionPacket {
+			version: 255,
+			public_key: Ok(pubkey_1),
+			hop_data: [1; 20*65],
+			hmac: [2; 32]
+		};
+		let mut update_add_htlc = msgs::UpdateAddHTLC {
+			channel_id: [2; 32],
+			htlc_id: 2316138423780173,
+			amount_msat: 3608586615801332854,
+			payment_hash: PaymentHash([1; 32]),
+			cltv_expiry: 821716,
+			onion_routing_packet,
+			blinding_point: None,
+		};
+		let encoded_without_blinding = update_add_htlc.encode();
+		update_add_htlc.blinding_point = Some(pubkey_1);
+		let encoded_value = update_add_htlc.encode();
+		// The blinding point is appended as a TLV of type 0
+		let mut target_value = encoded_without_blinding;
+		target_value.extend_from_slice(&hex::decode("0021031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f").unwrap());
+		assert_eq!(encoded_value, target_value);
+		let decoded: msgs::UpdateAddHTLC = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		assert!(decoded == update_add_htlc);
+	}
+
 	#[test]
 	fn encoding_update_fulfill_htlc() {
 		let update_fulfill_htlc = msgs::UpdateFulfillHTLC {
@@ -2515,12 +3066,117 @@ mod tests {
 		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
 	}
 
+	#[test]
+	fn encoding_trampoline_onion_hop_data() {
+		let secp_ctx = Secp256k1::new();
+		let outgoing_node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+		let mut msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::TrampolineForward {
+				outgoing_node_id,
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		msg = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		if let OnionHopDataFormat::TrampolineForward { outgoing_node_id: decoded_node_id } = msg.format {
+			assert_eq!(decoded_node_id, outgoing_node_id);
+		} else { panic!(); }
+		assert_eq!(msg.amt_to_forward, 0x0badf00d01020304);
+		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
+
+		let trampoline_packet = msgs::TrampolineOnionPacket {
+			version: 0,
+			public_key: Ok(outgoing_node_id),
+			hop_data: [0x42; msgs::TRAMPOLINE_ONION_DATA_LEN],
+			hmac: [0x43; 32],
+		};
+		msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::TrampolineEntry {
+				payment_data: Some(FinalOnionHopData {
+					payment_secret: PaymentSecret([0x44; 32]),
+					total_msat: 0x1badca1f
+				}),
+				trampoline_packet: trampoline_packet.clone(),
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		msg = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		if let OnionHopDataFormat::TrampolineEntry {
+			payment_data: Some(FinalOnionHopData {
+				payment_secret,
+				total_msat: 0x1badca1f
+			}),
+			trampoline_packet: decoded_packet,
+		} = msg.format {
+			assert_eq!(payment_secret, PaymentSecret([0x44; 32]));
+			assert!(decoded_packet == trampoline_packet);
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn encoding_blinded_onion_hop_data() {
+		let secp_ctx = Secp256k1::new();
+		let blinding_point = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[0x42; 32]).unwrap());
+		let mut msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::Blinded {
+				encrypted_data: vec![0x43; 300],
+				blinding_point: Some(blinding_point),
+				payment_data: None,
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		msg = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		if let OnionHopDataFormat::Blinded { encrypted_data, blinding_point: Some(decoded_point), payment_data: None } = msg.format {
+			assert_eq!(encrypted_data, vec![0x43; 300]);
+			assert_eq!(decoded_point, blinding_point);
+		} else { panic!(); }
+		assert_eq!(msg.amt_to_forward, 0x0badf00d01020304);
+		assert_eq!(msg.outgoing_cltv_value, 0xffffffff);
+
+		msg = msgs::OnionHopData {
+			format: OnionHopDataFormat::Blinded {
+				encrypted_data: vec![0x43; 32],
+				blinding_point: None,
+				payment_data: Some(FinalOnionHopData {
+					payment_secret: PaymentSecret([0x44; 32]),
+					total_msat: 0x1badca1f
+				}),
+			},
+			amt_to_forward: 0x0badf00d01020304,
+			outgoing_cltv_value: 0xffffffff,
+		};
+		let encoded_value = msg.encode();
+		msg = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		if let OnionHopDataFormat::Blinded {
+			encrypted_data,
+			blinding_point: None,
+			payment_data: Some(FinalOnionHopData {
+				payment_secret,
+				total_msat: 0x1badca1f
+			}),
+		} = msg.format {
+			assert_eq!(encrypted_data, vec![0x43; 32]);
+			assert_eq!(payment_secret, PaymentSecret([0x44; 32]));
+		} else { panic!(); }
+
+		// A blinding point without any encrypted data is bogus
+		let mut target_value = hex::decode("3302080badf00d010203040404ffffffff0c21").unwrap();
+		target_value.extend_from_slice(&blinding_point.serialize()[..]);
+		assert!(<msgs::OnionHopData as Readable>::read(&mut Cursor::new(&target_value[..])).is_err());
+	}
+
 	#[test]
 	fn encoding_query_channel_range() {
 		let mut query_channel_range = msgs::QueryChannelRange {
 			chain_hash: BlockHash::from_hex("06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f").unwrap(),
 			first_blocknum: 100000,
 			number_of_blocks: 1500,
+			query_option: None,
 		};
 		let encoded_value = query_channel_range.encode();
 		let target_value = hex::decode("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206000186a0000005dc").unwrap();
@@ -2529,6 +3185,26 @@ mod tests {
 		query_channel_range = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
 		assert_eq!(query_channel_range.first_blocknum, 100000);
 		assert_eq!(query_channel_range.number_of_blocks, 1500);
+		assert_eq!(query_channel_range.query_option, None);
+
+		// With a query_option TLV
+		query_channel_range.query_option = Some(msgs::QUERY_OPTION_TIMESTAMPS | msgs::QUERY_OPTION_CHECKSUMS);
+		let encoded_value = query_channel_range.encode();
+		let target_value = hex::decode("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206000186a0000005dc010103").unwrap();
+		assert_eq!(encoded_value, target_value);
+
+		query_channel_range = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		assert_eq!(query_channel_range.query_option, Some(3));
+
+		// Unknown odd TLVs are ignored, unknown even ones are rejected
+		let mut target_value = target_value.clone();
+		target_value.append(&mut hex::decode("03020000").unwrap());
+		query_channel_range = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		assert_eq!(query_channel_range.query_option, Some(3));
+		let last = target_value.len() - 4;
+		target_value[last] = 4;
+		let result: Result<msgs::QueryChannelRange, msgs::DecodeError> = Readable::read(&mut Cursor::new(&target_value[..]));
+		if let Err(msgs::DecodeError::UnknownRequiredFeature) = result {} else { panic!(); }
 	}
 
 	#[test]
@@ -2546,6 +3222,9 @@ mod tests {
 			number_of_blocks: 1500,
 			full_information: true,
 			short_channel_ids: vec![0x000000000000008e, 0x0000000000003c69, 0x000000000045a6c4],
+			encoding_type: msgs::EncodingType::Uncompressed,
+			timestamps: None,
+			checksums: None,
 		};
 
 		if encoding_type == 0 {
@@ -2561,13 +3240,71 @@ mod tests {
 			assert_eq!(reply_channel_range.short_channel_ids[0], 0x000000000000008e);
 			assert_eq!(reply_channel_range.short_channel_ids[1], 0x0000000000003c69);
 			assert_eq!(reply_channel_range.short_channel_ids[2], 0x000000000045a6c4);
+			assert_eq!(reply_channel_range.encoding_type, msgs::EncodingType::Uncompressed);
 		} else {
 			target_value.append(&mut hex::decode("001601789c636000833e08659309a65878be010010a9023a").unwrap());
-			let result: Result<msgs::ReplyChannelRange, msgs::DecodeError> = Readable::read(&mut Cursor::new(&target_value[..]));
-			assert!(result.is_err(), "Expected decode failure with unsupported zlib encoding");
+			reply_channel_range = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+			assert_eq!(reply_channel_range.chain_hash, expected_chain_hash);
+			assert_eq!(reply_channel_range.full_information, true);
+			assert_eq!(reply_channel_range.short_channel_ids, vec![0x000000000000008e, 0x0000000000003c69, 0x0000000000040cf6]);
+			assert_eq!(reply_channel_range.encoding_type, msgs::EncodingType::Zlib);
+
+			// Our own zlib encoding may differ, but must decode to the same thing
+			let encoded_value = reply_channel_range.encode();
+			let decoded: msgs::ReplyChannelRange = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+			assert_eq!(decoded.short_channel_ids, reply_channel_range.short_channel_ids);
+			assert_eq!(decoded.encoding_type, msgs::EncodingType::Zlib);
 		}
 	}
 
+	#[test]
+	fn encoding_reply_channel_range_with_timestamps_and_checksums() {
+		let mut reply_channel_range = msgs::ReplyChannelRange {
+			chain_hash: BlockHash::from_hex("06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f").unwrap(),
+			first_blocknum: 756230,
+			number_of_blocks: 1500,
+			full_information: true,
+			short_channel_ids: vec![0x000000000000008e, 0x0000000000003c69, 0x000000000045a6c4],
+			encoding_type: msgs::EncodingType::Uncompressed,
+			timestamps: Some(vec![
+				msgs::ChannelUpdateTimestamps { timestamp_node_id_1: 1, timestamp_node_id_2: 2 },
+				msgs::ChannelUpdateTimestamps { timestamp_node_id_1: 3, timestamp_node_id_2: 4 },
+				msgs::ChannelUpdateTimestamps { timestamp_node_id_1: 5, timestamp_node_id_2: 6 },
+			]),
+			checksums: Some(vec![
+				msgs::ChannelUpdateChecksums { checksum_node_id_1: 7, checksum_node_id_2: 8 },
+				msgs::ChannelUpdateChecksums { checksum_node_id_1: 9, checksum_node_id_2: 10 },
+				msgs::ChannelUpdateChecksums { checksum_node_id_1: 11, checksum_node_id_2: 12 },
+			]),
+		};
+		let encoded_value = reply_channel_range.encode();
+		let target_value = hex::decode("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206000b8a06000005dc01001900000000000000008e0000000000003c69000000000045a6c401190000000001000000020000000300000004000000050000000603180000000700000008000000090000000a0000000b0000000c").unwrap();
+		assert_eq!(encoded_value, target_value);
+
+		let decoded: msgs::ReplyChannelRange = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		assert_eq!(decoded.timestamps, reply_channel_range.timestamps);
+		assert_eq!(decoded.checksums, reply_channel_range.checksums);
+
+		// With zlib, both the short_channel_ids and timestamps are compressed
+		reply_channel_range.encoding_type = msgs::EncodingType::Zlib;
+		let encoded_value = reply_channel_range.encode();
+		let decoded: msgs::ReplyChannelRange = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		assert_eq!(decoded.encoding_type, msgs::EncodingType::Zlib);
+		assert_eq!(decoded.short_channel_ids, reply_channel_range.short_channel_ids);
+		assert_eq!(decoded.timestamps, reply_channel_range.timestamps);
+		assert_eq!(decoded.checksums, reply_channel_range.checksums);
+
+		// The number of timestamps and checksums must match the number of short_channel_ids
+		let mut bad_reply = reply_channel_range.clone();
+		bad_reply.timestamps.as_mut().unwrap().pop();
+		let result: Result<msgs::ReplyChannelRange, msgs::DecodeError> = Readable::read(&mut Cursor::new(&bad_reply.encode()[..]));
+		if let Err(msgs::DecodeError::InvalidValue) = result {} else { panic!(); }
+		let mut bad_reply = reply_channel_range.clone();
+		bad_reply.checksums.as_mut().unwrap().pop();
+		let result: Result<msgs::ReplyChannelRange, msgs::DecodeError> = Readable::read(&mut Cursor::new(&bad_reply.encode()[..]));
+		if let Err(msgs::DecodeError::InvalidValue) = result {} else { panic!(); }
+	}
+
 	#[test]
 	fn encoding_query_short_channel_ids() {
 		do_encoding_query_short_channel_ids(0);
@@ -2580,6 +3317,8 @@ mod tests {
 		let mut query_short_channel_ids = msgs::QueryShortChannelIds {
 			chain_hash: expected_chain_hash,
 			short_channel_ids: vec![0x0000000000008e, 0x0000000000003c69, 0x000000000045a6c4],
+			encoding_type: msgs::EncodingType::Uncompressed,
+			query_flags: None,
 		};
 
 		if encoding_type == 0 {
@@ -2592,13 +3331,70 @@ mod tests {
 			assert_eq!(query_short_channel_ids.short_channel_ids[0], 0x000000000000008e);
 			assert_eq!(query_short_channel_ids.short_channel_ids[1], 0x0000000000003c69);
 			assert_eq!(query_short_channel_ids.short_channel_ids[2], 0x000000000045a6c4);
+			assert_eq!(query_short_channel_ids.encoding_type, msgs::EncodingType::Uncompressed);
+			assert_eq!(query_short_channel_ids.query_flags, None);
 		} else {
 			target_value.append(&mut hex::decode("001601789c636000833e08659309a65878be010010a9023a").unwrap());
-			let result: Result<msgs::QueryShortChannelIds, msgs::DecodeError> = Readable::read(&mut Cursor::new(&target_value[..]));
-			assert!(result.is_err(), "Expected decode failure with unsupported zlib encoding");
+			query_short_channel_ids = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+			assert_eq!(query_short_channel_ids.chain_hash, expected_chain_hash);
+			assert_eq!(query_short_channel_ids.short_channel_ids, vec![0x000000000000008e, 0x0000000000003c69, 0x0000000000040cf6]);
+			assert_eq!(query_short_channel_ids.encoding_type, msgs::EncodingType::Zlib);
+
+			let encoded_value = query_short_channel_ids.encode();
+			let decoded: msgs::QueryShortChannelIds = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+			assert_eq!(decoded.short_channel_ids, query_short_channel_ids.short_channel_ids);
+			assert_eq!(decoded.encoding_type, msgs::EncodingType::Zlib);
 		}
 	}
 
+	#[test]
+	fn encoding_query_short_channel_ids_with_query_flags() {
+		let mut query_short_channel_ids = msgs::QueryShortChannelIds {
+			chain_hash: BlockHash::from_hex("06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f").unwrap(),
+			short_channel_ids: vec![0x0000000000008e, 0x0000000000003c69, 0x000000000045a6c4],
+			encoding_type: msgs::EncodingType::Uncompressed,
+			query_flags: Some(vec![msgs::QUERY_FLAG_CHANNEL_ANNOUNCEMENT, msgs::QUERY_FLAG_CHANNEL_UPDATE_1, 0x1f]),
+		};
+		let encoded_value = query_short_channel_ids.encode();
+		let target_value = hex::decode("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206001900000000000000008e0000000000003c69000000000045a6c401040001021f").unwrap();
+		assert_eq!(encoded_value, target_value);
+
+		let decoded: msgs::QueryShortChannelIds = Readable::read(&mut Cursor::new(&target_value[..])).unwrap();
+		assert_eq!(decoded.query_flags, query_short_channel_ids.query_flags);
+
+		query_short_channel_ids.encoding_type = msgs::EncodingType::Zlib;
+		let encoded_value = query_short_channel_ids.encode();
+		let decoded: msgs::QueryShortChannelIds = Readable::read(&mut Cursor::new(&encoded_value[..])).unwrap();
+		assert_eq!(decoded.short_channel_ids, query_short_channel_ids.short_channel_ids);
+		assert_eq!(decoded.query_flags, query_short_channel_ids.query_flags);
+
+		// There must be exactly one set of query_flags per short_channel_id
+		let mut bad_query = query_short_channel_ids.clone();
+		bad_query.query_flags.as_mut().unwrap().pop();
+		let result: Result<msgs::QueryShortChannelIds, msgs::DecodeError> = Readable::read(&mut Cursor::new(&bad_query.encode()[..]));
+		if let Err(msgs::DecodeError::InvalidValue) = result {} else { panic!(); }
+
+		// Unknown encoding types are rejected
+		let mut bad_value = target_value.clone();
+		bad_value[32 + 2] = 2;
+		let result: Result<msgs::QueryShortChannelIds, msgs::DecodeError> = Readable::read(&mut Cursor::new(&bad_value[..]));
+		if let Err(msgs::DecodeError::InvalidValue) = result {} else { panic!(); }
+	}
+
+	#[test]
+	fn encoding_too_many_short_channel_ids() {
+		// The encoded short_channel_ids are prefixed with a u16 length, so we refuse to write more
+		// than fit rather than truncating the length.
+		let query_short_channel_ids = msgs::QueryShortChannelIds {
+			chain_hash: BlockHash::from_hex("06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f").unwrap(),
+			short_channel_ids: (0..8192).collect(),
+			encoding_type: msgs::EncodingType::Uncompressed,
+			query_flags: None,
+		};
+		let mut buf = Vec::new();
+		assert!(query_short_channel_ids.write(&mut buf).is_err());
+	}
+
 	#[test]
 	fn encoding_reply_short_channel_ids_end() {
 		let expected_chain_hash = BlockHash::from_hex("06226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f").unwrap();
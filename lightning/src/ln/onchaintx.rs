@@ -25,16 +25,17 @@ use ln::msgs::DecodeError;
 use ln::channelmanager::PaymentPreimage;
 use ln::chan_utils;
 use ln::chan_utils::{TxCreationKeys, HolderCommitmentTransaction};
-use chain::chaininterface::{FeeEstimator, BroadcasterInterface, ConfirmationTarget, MIN_RELAY_FEE_SAT_PER_1000_WEIGHT};
+use chain::chaininterface::{FeeEstimator, FeeBumpPolicy, DefaultFeeBumpPolicy, BroadcasterInterface, ConfirmationTarget, MIN_RELAY_FEE_SAT_PER_1000_WEIGHT};
 use chain::channelmonitor::{ANTI_REORG_DELAY, CLTV_SHARED_CLAIM_BUFFER, InputMaterial, ClaimRequest};
-use chain::keysinterface::ChannelKeys;
+use chain::keysinterface::{ChannelKeys, InMemoryChannelKeys};
 use util::logger::Logger;
-use util::ser::{Readable, Writer, Writeable};
+use util::ser::{Readable, ReadableArgs, Writer, Writeable};
 use util::byte_utils;
 
-use std::collections::{HashMap, hash_map};
+use std::collections::HashMap;
 use std::cmp;
 use std::ops::Deref;
+use std::sync::Arc;
 
 const MAX_ALLOC_SIZE: usize = 64*1024;
 
@@ -56,6 +57,64 @@ enum OnchainEvent {
 	}
 }
 
+impl Writeable for OnchainEvent {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		match *self {
+			OnchainEvent::Claim { ref claim_request } => {
+				writer.write_all(&[0; 1])?;
+				claim_request.write(writer)?;
+			},
+			OnchainEvent::ContentiousOutpoint { ref outpoint, ref input_material } => {
+				writer.write_all(&[1; 1])?;
+				outpoint.write(writer)?;
+				input_material.write(writer)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Readable for OnchainEvent {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+		match <u8 as Readable>::read(reader)? {
+			0 => {
+				let claim_request = Readable::read(reader)?;
+				Ok(OnchainEvent::Claim {
+					claim_request
+				})
+			},
+			1 => {
+				let outpoint = Readable::read(reader)?;
+				let input_material = Readable::read(reader)?;
+				Ok(OnchainEvent::ContentiousOutpoint {
+					outpoint,
+					input_material
+				})
+			}
+			_ => Err(DecodeError::InvalidValue),
+		}
+	}
+}
+
+/// An onchain event along with the transaction which triggered it and the height at which that
+/// transaction was confirmed.
+#[derive(Clone, PartialEq)]
+struct OnchainEventEntry {
+	txid: Txid,
+	height: u32,
+	event: OnchainEvent,
+}
+
+impl OnchainEventEntry {
+	fn confirmation_threshold(&self) -> u32 {
+		self.height + ANTI_REORG_DELAY - 1
+	}
+
+	fn has_reached_confirmation_threshold(&self, height: u32) -> bool {
+		self.confirmation_threshold() <= height
+	}
+}
+
 /// Higher-level cache structure needed to re-generate bumped claim txn if needed
 #[derive(Clone, PartialEq)]
 pub struct ClaimTxBumpMaterial {
@@ -157,41 +216,6 @@ impl Readable for InputDescriptors {
 	}
 }
 
-macro_rules! subtract_high_prio_fee {
-	($logger: ident, $fee_estimator: expr, $value: expr, $predicted_weight: expr, $used_feerate: expr) => {
-		{
-			$used_feerate = $fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority).into();
-			let mut fee = $used_feerate as u64 * $predicted_weight / 1000;
-			if $value <= fee {
-				$used_feerate = $fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Normal).into();
-				fee = $used_feerate as u64 * $predicted_weight / 1000;
-				if $value <= fee.into() {
-					$used_feerate = $fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::Background).into();
-					fee = $used_feerate as u64 * $predicted_weight / 1000;
-					if $value <= fee {
-						log_error!($logger, "Failed to generate an on-chain punishment tx as even low priority fee ({} sat) was more than the entire claim balance ({} sat)",
-							fee, $value);
-						false
-					} else {
-						log_warn!($logger, "Used low priority fee for on-chain punishment tx as high priority fee was more than the entire claim balance ({} sat)",
-							$value);
-						$value -= fee;
-						true
-					}
-				} else {
-					log_warn!($logger, "Used medium priority fee for on-chain punishment tx as high priority fee was more than the entire claim balance ({} sat)",
-						$value);
-					$value -= fee;
-					true
-				}
-			} else {
-				$value -= fee;
-				true
-			}
-		}
-	}
-}
-
 impl Readable for Option<Vec<Option<(usize, Signature)>>> {
 	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
 		match Readable::read(reader)? {
@@ -237,6 +261,81 @@ impl Writeable for Option<Vec<Option<(usize, Signature)>>> {
 }
 
 
+/// In LN, output claimed are time-sensitive, which means we have to spend them before reaching some timelock expiration. At in-channel
+/// output detection, we generate a first version of a claim tx and associate to it a height timer. A height timer is an absolute block
+/// height than once reached we should generate a new bumped "version" of the claim tx to be sure than we safely claim outputs before
+/// than our counterparty can do it too. If timelock expires soon, the fee-bump policy should scale down the height timer in consequence
+/// to increase frequency of the bump and so increase our bets of success.
+fn get_height_timer(fee_bump_policy: &FeeBumpPolicy, current_height: u32, timelock_expiration: u32) -> u32 {
+	current_height + cmp::max(fee_bump_policy.blocks_before_bump(timelock_expiration.saturating_sub(current_height)), 1)
+}
+
+/// Computes the fee of a new version of a claim transaction of `predicted_weight` spending `amount`
+/// satoshis, given the feerate of its previous version (0 if it is the first one). Returns the fee
+/// along with the resulting feerate, or None if the fee-bump policy doesn't let us pay enough.
+///
+/// The fee may only exceed `amount` if the policy is willing to burn all of it in fees, in which
+/// case the claim transaction should pay it all.
+fn compute_claim_fee<F: Deref, L: Deref>(fee_bump_policy: &FeeBumpPolicy, fee_estimator: &F, amount: u64, predicted_weight: u64, previous_feerate: u64, blocks_to_expiry: u32, logger: &L) -> Option<(u64, u64)>
+	where F::Target: FeeEstimator,
+	      L::Target: Logger,
+{
+	let max_fee = cmp::min(fee_bump_policy.max_fee(amount, blocks_to_expiry), amount);
+	let target = fee_bump_policy.initial_confirmation_target(blocks_to_expiry);
+	// If old feerate inferior to actual one given back by Fee Estimator (or if this is the first
+	// version of the claim), use it to compute new fee, falling back to lower confirmation targets
+	// if the claimed amount can't afford it...
+	let (new_fee, new_feerate) = if previous_feerate < fee_estimator.get_est_sat_per_1000_weight(target) as u64 {
+		let targets: &[ConfirmationTarget] = match target {
+			ConfirmationTarget::HighPriority => &[ConfirmationTarget::HighPriority, ConfirmationTarget::Normal, ConfirmationTarget::Background],
+			ConfirmationTarget::Normal => &[ConfirmationTarget::Normal, ConfirmationTarget::Background],
+			ConfirmationTarget::Background => &[ConfirmationTarget::Background],
+		};
+		let mut fee_and_feerate = None;
+		for (i, target) in targets.iter().enumerate() {
+			let feerate = fee_estimator.get_est_sat_per_1000_weight(*target) as u64;
+			let fee = feerate * predicted_weight / 1000;
+			if fee < amount && fee <= max_fee {
+				if i != 0 {
+					log_warn!(logger, "Used lower priority fee for on-chain claim tx as higher priority fee was more than allowed for the claim balance ({} sat)", amount);
+				}
+				fee_and_feerate = Some((fee, feerate));
+				break;
+			}
+		}
+		match fee_and_feerate {
+			Some(fee_and_feerate) => fee_and_feerate,
+			None => {
+				log_error!(logger, "Failed to generate an on-chain claim tx as even low priority fee was more than allowed for the claim balance ({} sat)", amount);
+				return None;
+			}
+		}
+	// ...else just bump the previous feerate as the policy tells us to
+	} else {
+		let fee = fee_bump_policy.bumped_feerate(previous_feerate, blocks_to_expiry) * predicted_weight / 1000;
+		if fee >= amount || fee > max_fee {
+			log_trace!(logger, "Can't bump new claiming tx, amount {} is too small for fee {}", amount, fee);
+			return None;
+		}
+		(fee, fee * 1000 / predicted_weight)
+	};
+	if previous_feerate == 0 {
+		return Some((new_fee, new_feerate));
+	}
+
+	let previous_fee = previous_feerate * predicted_weight / 1000;
+	let min_relay_fee = MIN_RELAY_FEE_SAT_PER_1000_WEIGHT * predicted_weight / 1000;
+	// BIP 125 Opt-in Full Replace-by-Fee Signaling
+	// 	* 3. The replacement transaction pays an absolute fee of at least the sum paid by the original transactions.
+	//	* 4. The replacement transaction must also pay for its own bandwidth at or above the rate set by the node's minimum relay fee setting.
+	let new_fee = cmp::max(new_fee, previous_fee + min_relay_fee);
+	if new_fee > max_fee && max_fee < amount {
+		log_trace!(logger, "Can't bump new claiming tx, fee {} required to replace the previous one is more than allowed for the claim balance ({} sat)", new_fee, amount);
+		return None;
+	}
+	Some((new_fee, new_fee * 1000 / predicted_weight))
+}
+
 /// OnchainTxHandler receives claiming requests, aggregates them if it's sound, broadcast and
 /// do RBF bumping if possible.
 pub struct OnchainTxHandler<ChanSigner: ChannelKeys> {
@@ -280,7 +379,10 @@ pub struct OnchainTxHandler<ChanSigner: ChannelKeys> {
 	#[cfg(not(test))]
 	claimable_outpoints: HashMap<BitcoinOutPoint, (Txid, u32)>,
 
-	onchain_events_waiting_threshold_conf: HashMap<u32, Vec<OnchainEvent>>,
+	onchain_events_waiting_threshold_conf: Vec<OnchainEventEntry>,
+
+	// Not persisted, set by our ChannelMonitor's user after deserialization.
+	fee_bump_policy: Arc<FeeBumpPolicy>,
 
 	secp_ctx: Secp256k1<secp256k1::All>,
 }
@@ -311,29 +413,18 @@ impl<ChanSigner: ChannelKeys + Writeable> OnchainTxHandler<ChanSigner> {
 		}
 
 		writer.write_all(&byte_utils::be64_to_array(self.onchain_events_waiting_threshold_conf.len() as u64))?;
-		for (ref target, ref events) in self.onchain_events_waiting_threshold_conf.iter() {
-			writer.write_all(&byte_utils::be32_to_array(**target))?;
-			writer.write_all(&byte_utils::be64_to_array(events.len() as u64))?;
-			for ev in events.iter() {
-				match *ev {
-					OnchainEvent::Claim { ref claim_request } => {
-						writer.write_all(&[0; 1])?;
-						claim_request.write(writer)?;
-					},
-					OnchainEvent::ContentiousOutpoint { ref outpoint, ref input_material } => {
-						writer.write_all(&[1; 1])?;
-						outpoint.write(writer)?;
-						input_material.write(writer)?;
-					}
-				}
-			}
+		for ref entry in self.onchain_events_waiting_threshold_conf.iter() {
+			entry.txid.write(writer)?;
+			writer.write_all(&byte_utils::be32_to_array(entry.height))?;
+			entry.event.write(writer)?;
 		}
 		Ok(())
 	}
 }
 
-impl<ChanSigner: ChannelKeys + Readable> Readable for OnchainTxHandler<ChanSigner> {
-	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<Self, DecodeError> {
+/// Read with the serialization version of the ChannelMonitor we're part of.
+impl<ChanSigner: ChannelKeys + Readable> ReadableArgs<u8> for OnchainTxHandler<ChanSigner> {
+	fn read<R: ::std::io::Read>(reader: &mut R, ver: u8) -> Result<Self, DecodeError> {
 		let destination_script = Readable::read(reader)?;
 
 		let holder_commitment = Readable::read(reader)?;
@@ -360,32 +451,27 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for OnchainTxHandler<ChanSigne
 			claimable_outpoints.insert(outpoint, (ancestor_claim_txid, height));
 		}
 		let waiting_threshold_conf_len: u64 = Readable::read(reader)?;
-		let mut onchain_events_waiting_threshold_conf = HashMap::with_capacity(cmp::min(waiting_threshold_conf_len as usize, MAX_ALLOC_SIZE / 128));
+		let mut onchain_events_waiting_threshold_conf = Vec::with_capacity(cmp::min(waiting_threshold_conf_len as usize, MAX_ALLOC_SIZE / 128));
 		for _ in 0..waiting_threshold_conf_len {
-			let height_target = Readable::read(reader)?;
-			let events_len: u64 = Readable::read(reader)?;
-			let mut events = Vec::with_capacity(cmp::min(events_len as usize, MAX_ALLOC_SIZE / 128));
-			for _ in 0..events_len {
-				let ev = match <u8 as Readable>::read(reader)? {
-					0 => {
-						let claim_request = Readable::read(reader)?;
-						OnchainEvent::Claim {
-							claim_request
-						}
-					},
-					1 => {
-						let outpoint = Readable::read(reader)?;
-						let input_material = Readable::read(reader)?;
-						OnchainEvent::ContentiousOutpoint {
-							outpoint,
-							input_material
-						}
-					}
-					_ => return Err(DecodeError::InvalidValue),
-				};
-				events.push(ev);
+			if ver >= 2 {
+				let txid = Readable::read(reader)?;
+				let height = Readable::read(reader)?;
+				let event = Readable::read(reader)?;
+				onchain_events_waiting_threshold_conf.push(OnchainEventEntry { txid, height, event });
+			} else {
+				// As in ChannelMonitor, events used to be grouped by the height at which they mature,
+				// without the transaction which triggered them.
+				let height_target: u32 = Readable::read(reader)?;
+				let events_len: u64 = Readable::read(reader)?;
+				for _ in 0..events_len {
+					let event = Readable::read(reader)?;
+					onchain_events_waiting_threshold_conf.push(OnchainEventEntry {
+						txid: Default::default(),
+						height: (height_target + 1).saturating_sub(ANTI_REORG_DELAY),
+						event
+					});
+				}
 			}
-			onchain_events_waiting_threshold_conf.insert(height_target, events);
 		}
 
 		Ok(OnchainTxHandler {
@@ -399,6 +485,7 @@ impl<ChanSigner: ChannelKeys + Readable> Readable for OnchainTxHandler<ChanSigne
 			claimable_outpoints,
 			pending_claim_requests,
 			onchain_events_waiting_threshold_conf,
+			fee_bump_policy: Arc::new(DefaultFeeBumpPolicy {}),
 			secp_ctx: Secp256k1::new(),
 		})
 	}
@@ -419,12 +506,18 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 			key_storage,
 			pending_claim_requests: HashMap::new(),
 			claimable_outpoints: HashMap::new(),
-			onchain_events_waiting_threshold_conf: HashMap::new(),
+			onchain_events_waiting_threshold_conf: Vec::new(),
+			fee_bump_policy: Arc::new(DefaultFeeBumpPolicy {}),
 
 			secp_ctx: Secp256k1::new(),
 		}
 	}
 
+	/// Sets the policy used to set and bump the fee of our claim transactions.
+	pub(crate) fn set_fee_bump_policy(&mut self, fee_bump_policy: Arc<FeeBumpPolicy>) {
+		self.fee_bump_policy = fee_bump_policy;
+	}
+
 	pub(crate) fn get_witnesses_weight(inputs: &[InputDescriptors]) -> usize {
 		let mut tx_weight = 2; // count segwit flags
 		for inp in inputs {
@@ -455,20 +548,6 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		tx_weight
 	}
 
-	/// In LN, output claimed are time-sensitive, which means we have to spend them before reaching some timelock expiration. At in-channel
-	/// output detection, we generate a first version of a claim tx and associate to it a height timer. A height timer is an absolute block
-	/// height than once reached we should generate a new bumped "version" of the claim tx to be sure than we safely claim outputs before
-	/// than our counterparty can do it too. If timelock expires soon, height timer is going to be scale down in consequence to increase
-	/// frequency of the bump and so increase our bets of success.
-	fn get_height_timer(current_height: u32, timelock_expiration: u32) -> u32 {
-		if timelock_expiration <= current_height + 3 {
-			return current_height + 1
-		} else if timelock_expiration - current_height <= 15 {
-			return current_height + 3
-		}
-		current_height + 15
-	}
-
 	/// Lightning security model (i.e being able to redeem/timeout HTLC or penalize coutnerparty onchain) lays on the assumption of claim transactions getting confirmed before timelock expiration
 	/// (CSV or CLTV following cases). In case of high-fee spikes, claim tx may stuck in the mempool, so you need to bump its feerate quickly using Replace-By-Fee or Child-Pay-For-Parent.
 	fn generate_claim_tx<F: Deref, L: Deref>(&mut self, height: u32, cached_claim_datas: &ClaimTxBumpMaterial, fee_estimator: F, logger: L) -> Option<(Option<u32>, u32, Transaction)>
@@ -496,48 +575,9 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 			}],
 		};
 
-		macro_rules! RBF_bump {
-			($amount: expr, $old_feerate: expr, $fee_estimator: expr, $predicted_weight: expr) => {
-				{
-					let mut used_feerate: u32;
-					// If old feerate inferior to actual one given back by Fee Estimator, use it to compute new fee...
-					let new_fee = if $old_feerate < $fee_estimator.get_est_sat_per_1000_weight(ConfirmationTarget::HighPriority) {
-						let mut value = $amount;
-						if subtract_high_prio_fee!(logger, $fee_estimator, value, $predicted_weight, used_feerate) {
-							// Overflow check is done in subtract_high_prio_fee
-							($amount - value)
-						} else {
-							log_trace!(logger, "Can't new-estimation bump new claiming tx, amount {} is too small", $amount);
-							return None;
-						}
-					// ...else just increase the previous feerate by 25% (because that's a nice number)
-					} else {
-						let fee = $old_feerate as u64 * ($predicted_weight as u64) / 750;
-						if $amount <= fee {
-							log_trace!(logger, "Can't 25% bump new claiming tx, amount {} is too small", $amount);
-							return None;
-						}
-						fee
-					};
-
-					let previous_fee = $old_feerate as u64 * ($predicted_weight as u64) / 1000;
-					let min_relay_fee = MIN_RELAY_FEE_SAT_PER_1000_WEIGHT * ($predicted_weight as u64) / 1000;
-					// BIP 125 Opt-in Full Replace-by-Fee Signaling
-					// 	* 3. The replacement transaction pays an absolute fee of at least the sum paid by the original transactions.
-					//	* 4. The replacement transaction must also pay for its own bandwidth at or above the rate set by the node's minimum relay fee setting.
-					let new_fee = if new_fee < previous_fee + min_relay_fee {
-						new_fee + previous_fee + min_relay_fee - new_fee
-					} else {
-						new_fee
-					};
-					Some((new_fee, new_fee * 1000 / ($predicted_weight as u64)))
-				}
-			}
-		}
-
 		// Compute new height timer to decide when we need to regenerate a new bumped version of the claim tx (if we
 		// didn't receive confirmation of it before, or not enough reorg-safe depth on top of it).
-		let new_timer = Some(Self::get_height_timer(height, cached_claim_datas.soonest_timelock));
+		let new_timer = Some(get_height_timer(&*self.fee_bump_policy, height, cached_claim_datas.soonest_timelock));
 		let mut inputs_witnesses_weight = 0;
 		let mut amt = 0;
 		let mut dynamic_fee = true;
@@ -561,72 +601,15 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		}
 		if dynamic_fee {
 			let predicted_weight = (bumped_tx.get_weight() + inputs_witnesses_weight) as u64;
-			let mut new_feerate;
+			let blocks_to_expiry = cached_claim_datas.soonest_timelock.saturating_sub(height);
 			// If old feerate is 0, first iteration of this claim, use normal fee calculation
-			if cached_claim_datas.feerate_previous != 0 {
-				if let Some((new_fee, feerate)) = RBF_bump!(amt, cached_claim_datas.feerate_previous, fee_estimator, predicted_weight) {
-					// If new computed fee is superior at the whole claimable amount burn all in fees
-					if new_fee as u64 > amt {
-						bumped_tx.output[0].value = 0;
-					} else {
-						bumped_tx.output[0].value = amt - new_fee as u64;
-					}
-					new_feerate = feerate;
-				} else { return None; }
-			} else {
-				if subtract_high_prio_fee!(logger, fee_estimator, amt, predicted_weight, new_feerate) {
-					bumped_tx.output[0].value = amt;
-				} else { return None; }
-			}
+			let (new_fee, new_feerate) = compute_claim_fee(&*self.fee_bump_policy, &fee_estimator, amt, predicted_weight, cached_claim_datas.feerate_previous as u64, blocks_to_expiry, &logger)?;
+			// If new computed fee is superior at the whole claimable amount burn all in fees
+			bumped_tx.output[0].value = amt.saturating_sub(new_fee);
 			assert!(new_feerate != 0);
 
 			for (i, (outp, per_outp_material)) in cached_claim_datas.per_input_material.iter().enumerate() {
-				match per_outp_material {
-					&InputMaterial::Revoked { ref per_commitment_point, ref counterparty_delayed_payment_base_key, ref counterparty_htlc_base_key, ref per_commitment_key, ref input_descriptor, ref amount, ref htlc, ref on_counterparty_tx_csv } => {
-						if let Ok(chan_keys) = TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, &self.key_storage.pubkeys().revocation_basepoint, &self.key_storage.pubkeys().htlc_basepoint) {
-
-							let witness_script = if let Some(ref htlc) = *htlc {
-								chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key)
-							} else {
-								chan_utils::get_revokeable_redeemscript(&chan_keys.revocation_key, *on_counterparty_tx_csv, &chan_keys.broadcaster_delayed_payment_key)
-							};
-
-							if let Ok(sig) = self.key_storage.sign_justice_transaction(&bumped_tx, i, *amount, &per_commitment_key, htlc, &self.secp_ctx) {
-								bumped_tx.input[i].witness.push(sig.serialize_der().to_vec());
-								bumped_tx.input[i].witness[0].push(SigHashType::All as u8);
-								if htlc.is_some() {
-									bumped_tx.input[i].witness.push(chan_keys.revocation_key.clone().serialize().to_vec());
-								} else {
-									bumped_tx.input[i].witness.push(vec!(1));
-								}
-								bumped_tx.input[i].witness.push(witness_script.clone().into_bytes());
-							} else { return None; }
-							//TODO: panic ?
-
-							log_trace!(logger, "Going to broadcast Penalty Transaction {} claiming revoked {} output {} from {} with new feerate {}...", bumped_tx.txid(), if *input_descriptor == InputDescriptors::RevokedOutput { "to_holder" } else if *input_descriptor == InputDescriptors::RevokedOfferedHTLC { "offered" } else if *input_descriptor == InputDescriptors::RevokedReceivedHTLC { "received" } else { "" }, outp.vout, outp.txid, new_feerate);
-						}
-					},
-					&InputMaterial::CounterpartyHTLC { ref per_commitment_point, ref counterparty_delayed_payment_base_key, ref counterparty_htlc_base_key, ref preimage, ref htlc } => {
-						if let Ok(chan_keys) = TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, &self.key_storage.pubkeys().revocation_basepoint, &self.key_storage.pubkeys().htlc_basepoint) {
-							let witness_script = chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key);
-
-							if !preimage.is_some() { bumped_tx.lock_time = htlc.cltv_expiry }; // Right now we don't aggregate time-locked transaction, if we do we should set lock_time before to avoid breaking hash computation
-							if let Ok(sig) = self.key_storage.sign_counterparty_htlc_transaction(&bumped_tx, i, &htlc.amount_msat / 1000, &per_commitment_point, htlc, &self.secp_ctx) {
-								bumped_tx.input[i].witness.push(sig.serialize_der().to_vec());
-								bumped_tx.input[i].witness[0].push(SigHashType::All as u8);
-								if let &Some(preimage) = preimage {
-									bumped_tx.input[i].witness.push(preimage.0.to_vec());
-								} else {
-									// Due to BIP146 (MINIMALIF) this must be a zero-length element to relay.
-									bumped_tx.input[i].witness.push(vec![]);
-								}
-								bumped_tx.input[i].witness.push(witness_script.clone().into_bytes());
-							}
-							log_trace!(logger, "Going to broadcast Claim Transaction {} claiming counterparty {} htlc output {} from {} with new feerate {}...", bumped_tx.txid(), if preimage.is_some() { "offered" } else { "received" }, outp.vout, outp.txid, new_feerate);
-						}
-					},
-					_ => unreachable!()
-				}
+				self.sign_claim_input(&mut bumped_tx, i, outp, per_outp_material, new_feerate, &logger)?;
 			}
 			log_trace!(logger, "...with timer {}", new_timer.unwrap());
 			assert!(predicted_weight >= bumped_tx.get_weight() as u64);
@@ -657,12 +640,70 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		None
 	}
 
-	pub(crate) fn block_connected<B: Deref, F: Deref, L: Deref>(&mut self, txn_matched: &[&Transaction], claimable_outpoints: Vec<ClaimRequest>, height: u32, broadcaster: B, fee_estimator: F, logger: L)
+	/// Signs the input at index `i` of a dynamic-fee claim transaction, which must otherwise be
+	/// complete, spending `outp` with the given material. Returns None if the signer refused to sign.
+	pub(crate) fn sign_claim_input<L: Deref>(&self, bumped_tx: &mut Transaction, i: usize, outp: &BitcoinOutPoint, per_outp_material: &InputMaterial, new_feerate: u64, logger: &L) -> Option<()>
+		where L::Target: Logger,
+	{
+		match per_outp_material {
+			&InputMaterial::Revoked { ref per_commitment_point, ref counterparty_delayed_payment_base_key, ref counterparty_htlc_base_key, ref per_commitment_key, ref input_descriptor, ref amount, ref htlc, ref on_counterparty_tx_csv } => {
+				if let Ok(chan_keys) = TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, &self.key_storage.pubkeys().revocation_basepoint, &self.key_storage.pubkeys().htlc_basepoint) {
+
+					let witness_script = if let Some(ref htlc) = *htlc {
+						chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key)
+					} else {
+						chan_utils::get_revokeable_redeemscript(&chan_keys.revocation_key, *on_counterparty_tx_csv, &chan_keys.broadcaster_delayed_payment_key)
+					};
+
+					if let Ok(sig) = self.key_storage.sign_justice_transaction(&bumped_tx, i, *amount, &per_commitment_key, htlc, &self.secp_ctx) {
+						bumped_tx.input[i].witness.push(sig.serialize_der().to_vec());
+						bumped_tx.input[i].witness[0].push(SigHashType::All as u8);
+						if htlc.is_some() {
+							bumped_tx.input[i].witness.push(chan_keys.revocation_key.clone().serialize().to_vec());
+						} else {
+							bumped_tx.input[i].witness.push(vec!(1));
+						}
+						bumped_tx.input[i].witness.push(witness_script.clone().into_bytes());
+					} else { return None; }
+					//TODO: panic ?
+
+					log_trace!(logger, "Going to broadcast Penalty Transaction {} claiming revoked {} output {} from {} with new feerate {}...", bumped_tx.txid(), if *input_descriptor == InputDescriptors::RevokedOutput { "to_holder" } else if *input_descriptor == InputDescriptors::RevokedOfferedHTLC { "offered" } else if *input_descriptor == InputDescriptors::RevokedReceivedHTLC { "received" } else { "" }, outp.vout, outp.txid, new_feerate);
+				}
+			},
+			&InputMaterial::CounterpartyHTLC { ref per_commitment_point, ref counterparty_delayed_payment_base_key, ref counterparty_htlc_base_key, ref preimage, ref htlc } => {
+				if let Ok(chan_keys) = TxCreationKeys::derive_new(&self.secp_ctx, &per_commitment_point, counterparty_delayed_payment_base_key, counterparty_htlc_base_key, &self.key_storage.pubkeys().revocation_basepoint, &self.key_storage.pubkeys().htlc_basepoint) {
+					let witness_script = chan_utils::get_htlc_redeemscript_with_explicit_keys(&htlc, &chan_keys.broadcaster_htlc_key, &chan_keys.countersignatory_htlc_key, &chan_keys.revocation_key);
+
+					if !preimage.is_some() { bumped_tx.lock_time = htlc.cltv_expiry }; // Right now we don't aggregate time-locked transaction, if we do we should set lock_time before to avoid breaking hash computation
+					if let Ok(sig) = self.key_storage.sign_counterparty_htlc_transaction(&bumped_tx, i, &htlc.amount_msat / 1000, &per_commitment_point, htlc, &self.secp_ctx) {
+						bumped_tx.input[i].witness.push(sig.serialize_der().to_vec());
+						bumped_tx.input[i].witness[0].push(SigHashType::All as u8);
+						if let &Some(preimage) = preimage {
+							bumped_tx.input[i].witness.push(preimage.0.to_vec());
+						} else {
+							// Due to BIP146 (MINIMALIF) this must be a zero-length element to relay.
+							bumped_tx.input[i].witness.push(vec![]);
+						}
+						bumped_tx.input[i].witness.push(witness_script.clone().into_bytes());
+					}
+					log_trace!(logger, "Going to broadcast Claim Transaction {} claiming counterparty {} htlc output {} from {} with new feerate {}...", bumped_tx.txid(), if preimage.is_some() { "offered" } else { "received" }, outp.vout, outp.txid, new_feerate);
+				}
+			},
+			_ => unreachable!()
+		}
+		Some(())
+	}
+
+	/// Upon channelmonitor.block_confirmed(..) (either on a full block connection or on a set of
+	/// transactions confirmed out-of-band of blocks), registers new claim requests, checks txn
+	/// confirmed at `height` against pending claims and bumps any claims whose timer expired by
+	/// `cur_height`, the height of the current best block.
+	pub(crate) fn update_claims_view<B: Deref, F: Deref, L: Deref>(&mut self, txn_matched: &[&Transaction], claimable_outpoints: Vec<ClaimRequest>, height: u32, cur_height: u32, broadcaster: B, fee_estimator: F, logger: L)
 		where B::Target: BroadcasterInterface,
 		      F::Target: FeeEstimator,
-					L::Target: Logger,
+		      L::Target: Logger,
 	{
-		log_trace!(logger, "Block at height {} connected with {} claim requests", height, claimable_outpoints.len());
+		log_trace!(logger, "Updating claims view at height {} with {} matched transactions at height {} and {} claim requests", cur_height, txn_matched.len(), height, claimable_outpoints.len());
 		let mut new_claims = Vec::new();
 		let mut aggregated_claim = HashMap::new();
 		let mut aggregated_soonest = ::std::u32::MAX;
@@ -672,8 +713,8 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		for req in claimable_outpoints {
 			// Don't claim a outpoint twice that would be bad for privacy and may uselessly lock a CPFP input for a while
 			if let Some(_) = self.claimable_outpoints.get(&req.outpoint) { log_trace!(logger, "Bouncing off outpoint {}:{}, already registered its claiming request", req.outpoint.txid, req.outpoint.vout); } else {
-				log_trace!(logger, "Test if outpoint can be aggregated with expiration {} against {}", req.absolute_timelock, height + CLTV_SHARED_CLAIM_BUFFER);
-				if req.absolute_timelock <= height + CLTV_SHARED_CLAIM_BUFFER || !req.aggregable { // Don't aggregate if outpoint absolute timelock is soon or marked as non-aggregable
+				log_trace!(logger, "Test if outpoint can be aggregated with expiration {} against {}", req.absolute_timelock, cur_height + CLTV_SHARED_CLAIM_BUFFER);
+				if req.absolute_timelock <= cur_height + CLTV_SHARED_CLAIM_BUFFER || !req.aggregable { // Don't aggregate if outpoint absolute timelock is soon or marked as non-aggregable
 					let mut single_input = HashMap::new();
 					single_input.insert(req.outpoint, req.witness_data);
 					new_claims.push((req.absolute_timelock, single_input));
@@ -691,7 +732,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		// height timer expiration (i.e in how many blocks we're going to take action).
 		for (soonest_timelock, claim) in new_claims.drain(..) {
 			let mut claim_material = ClaimTxBumpMaterial { height_timer: None, feerate_previous: 0, soonest_timelock, per_input_material: claim };
-			if let Some((new_timer, new_feerate, tx)) = self.generate_claim_tx(height, &claim_material, &*fee_estimator, &*logger) {
+			if let Some((new_timer, new_feerate, tx)) = self.generate_claim_tx(cur_height, &claim_material, &*fee_estimator, &*logger) {
 				claim_material.height_timer = new_timer;
 				claim_material.feerate_previous = new_feerate;
 				let txid = tx.txid();
@@ -729,16 +770,13 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 
 						macro_rules! clean_claim_request_after_safety_delay {
 							() => {
-								let new_event = OnchainEvent::Claim { claim_request: first_claim_txid_height.0.clone() };
-								match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-									hash_map::Entry::Occupied(mut entry) => {
-										if !entry.get().contains(&new_event) {
-											entry.get_mut().push(new_event);
-										}
-									},
-									hash_map::Entry::Vacant(entry) => {
-										entry.insert(vec![new_event]);
-									}
+								let entry = OnchainEventEntry {
+									txid: tx.txid(),
+									height,
+									event: OnchainEvent::Claim { claim_request: first_claim_txid_height.0.clone() }
+								};
+								if !self.onchain_events_waiting_threshold_conf.contains(&entry) {
+									self.onchain_events_waiting_threshold_conf.push(entry);
 								}
 							}
 						}
@@ -772,36 +810,34 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 				}
 			}
 			for (outpoint, input_material) in claimed_outputs_material.drain(..) {
-				let new_event = OnchainEvent::ContentiousOutpoint { outpoint, input_material };
-				match self.onchain_events_waiting_threshold_conf.entry(height + ANTI_REORG_DELAY - 1) {
-					hash_map::Entry::Occupied(mut entry) => {
-						if !entry.get().contains(&new_event) {
-							entry.get_mut().push(new_event);
-						}
-					},
-					hash_map::Entry::Vacant(entry) => {
-						entry.insert(vec![new_event]);
-					}
+				let entry = OnchainEventEntry {
+					txid: tx.txid(),
+					height,
+					event: OnchainEvent::ContentiousOutpoint { outpoint, input_material },
+				};
+				if !self.onchain_events_waiting_threshold_conf.contains(&entry) {
+					self.onchain_events_waiting_threshold_conf.push(entry);
 				}
 			}
 		}
 
 		// After security delay, either our claim tx got enough confs or outpoint is definetely out of reach
-		if let Some(events) = self.onchain_events_waiting_threshold_conf.remove(&height) {
-			for ev in events {
-				match ev {
-					OnchainEvent::Claim { claim_request } => {
-						// We may remove a whole set of claim outpoints here, as these one may have
-						// been aggregated in a single tx and claimed so atomically
-						if let Some(bump_material) = self.pending_claim_requests.remove(&claim_request) {
-							for outpoint in bump_material.per_input_material.keys() {
-								self.claimable_outpoints.remove(&outpoint);
-							}
+		let (onchain_events_reaching_threshold_conf, onchain_events_waiting_threshold_conf): (Vec<_>, Vec<_>) =
+			self.onchain_events_waiting_threshold_conf.drain(..).partition(|entry| entry.has_reached_confirmation_threshold(cur_height));
+		self.onchain_events_waiting_threshold_conf = onchain_events_waiting_threshold_conf;
+		for entry in onchain_events_reaching_threshold_conf {
+			match entry.event {
+				OnchainEvent::Claim { claim_request } => {
+					// We may remove a whole set of claim outpoints here, as these one may have
+					// been aggregated in a single tx and claimed so atomically
+					if let Some(bump_material) = self.pending_claim_requests.remove(&claim_request) {
+						for outpoint in bump_material.per_input_material.keys() {
+							self.claimable_outpoints.remove(&outpoint);
 						}
-					},
-					OnchainEvent::ContentiousOutpoint { outpoint, .. } => {
-						self.claimable_outpoints.remove(&outpoint);
 					}
+				},
+				OnchainEvent::ContentiousOutpoint { outpoint, .. } => {
+					self.claimable_outpoints.remove(&outpoint);
 				}
 			}
 		}
@@ -809,7 +845,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		// Check if any pending claim request must be rescheduled
 		for (first_claim_txid, ref claim_data) in self.pending_claim_requests.iter() {
 			if let Some(h) = claim_data.height_timer {
-				if h == height {
+				if h <= cur_height {
 					bump_candidates.insert(*first_claim_txid, (*claim_data).clone());
 				}
 			}
@@ -818,7 +854,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		// Build, bump and rebroadcast tx accordingly
 		log_trace!(logger, "Bumping {} candidates", bump_candidates.len());
 		for (first_claim_txid, claim_material) in bump_candidates.iter() {
-			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(height, &claim_material, &*fee_estimator, &*logger) {
+			if let Some((new_timer, new_feerate, bump_tx)) = self.generate_claim_tx(cur_height, &claim_material, &*fee_estimator, &*logger) {
 				log_trace!(logger, "Broadcast onchain {}", log_tx!(bump_tx));
 				broadcaster.broadcast_transaction(&bump_tx);
 				if let Some(claim_material) = self.pending_claim_requests.get_mut(first_claim_txid) {
@@ -829,17 +865,41 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		}
 	}
 
+	/// Drops any onchain events pending on the given transaction and resurrects any claims it
+	/// resolved, as if the block it was confirmed in was disconnected.
+	pub(crate) fn transaction_unconfirmed<B: Deref, F: Deref, L: Deref>(&mut self, txid: &Txid, broadcaster: B, fee_estimator: F, logger: L)
+		where B::Target: BroadcasterInterface,
+		      F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		let mut height = None;
+		for entry in self.onchain_events_waiting_threshold_conf.iter() {
+			if entry.txid == *txid {
+				height = Some(entry.height);
+				break;
+			}
+		}
+
+		if let Some(height) = height {
+			self.block_disconnected(height, broadcaster, fee_estimator, logger);
+		}
+	}
+
+	/// Disconnects all blocks at `height` and above, dropping any onchain events confirmed in
+	/// them and resurrecting contentious outpoints into their claim requests.
 	pub(crate) fn block_disconnected<B: Deref, F: Deref, L: Deref>(&mut self, height: u32, broadcaster: B, fee_estimator: F, logger: L)
 		where B::Target: BroadcasterInterface,
 		      F::Target: FeeEstimator,
-					L::Target: Logger,
+		      L::Target: Logger,
 	{
 		let mut bump_candidates = HashMap::new();
-		if let Some(events) = self.onchain_events_waiting_threshold_conf.remove(&(height + ANTI_REORG_DELAY - 1)) {
-			//- our claim tx on a commitment tx output
-			//- resurect outpoint back in its claimable set and regenerate tx
-			for ev in events {
-				match ev {
+		let onchain_events_waiting_threshold_conf =
+			self.onchain_events_waiting_threshold_conf.drain(..).collect::<Vec<_>>();
+		for entry in onchain_events_waiting_threshold_conf {
+			if entry.height >= height {
+				//- our claim tx on a commitment tx output
+				//- resurect outpoint back in its claimable set and regenerate tx
+				match entry.event {
 					OnchainEvent::ContentiousOutpoint { outpoint, input_material } => {
 						if let Some(ancestor_claimable_txid) = self.claimable_outpoints.get(&outpoint) {
 							if let Some(claim_material) = self.pending_claim_requests.get_mut(&ancestor_claimable_txid.0) {
@@ -852,6 +912,8 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 					},
 					_ => {},
 				}
+			} else {
+				self.onchain_events_waiting_threshold_conf.push(entry);
 			}
 		}
 		for (_, claim_material) in bump_candidates.iter_mut() {
@@ -868,7 +930,7 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		// right now if one of the outpoint get disconnected, just erase whole pending claim request.
 		let mut remove_request = Vec::new();
 		self.claimable_outpoints.retain(|_, ref v|
-			if v.1 == height {
+			if v.1 >= height {
 			remove_request.push(v.0.clone());
 			false
 			} else { true });
@@ -877,6 +939,53 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		}
 	}
 
+	/// Returns the set of txids confirmed but not yet past ANTI_REORG_DELAY which resolve our
+	/// claims.
+	pub(crate) fn get_relevant_txids(&self) -> Vec<Txid> {
+		let mut txids: Vec<Txid> = self.onchain_events_waiting_threshold_conf
+			.iter()
+			.map(|entry| entry.txid)
+			.collect();
+		txids.sort_unstable();
+		txids.dedup();
+		txids
+	}
+
+	/// Gets the outpoints we're still trying to claim, along with the material to claim them and
+	/// the soonest timelock of the claim request they're part of. Claims whose transaction already
+	/// confirmed are skipped, the outputs paying us being tracked by our ChannelMonitor until they
+	/// reach ANTI_REORG_DELAY confirmations.
+	pub(crate) fn get_unresolved_claims(&self) -> Vec<(&BitcoinOutPoint, &InputMaterial, u32)> {
+		let mut claims = Vec::new();
+		for (first_claim_txid, claim_material) in self.pending_claim_requests.iter() {
+			let claim_confirmed = self.onchain_events_waiting_threshold_conf.iter().any(|entry| match entry.event {
+				OnchainEvent::Claim { ref claim_request } => claim_request == first_claim_txid,
+				_ => false,
+			});
+			if claim_confirmed { continue; }
+			for (outpoint, input_material) in claim_material.per_input_material.iter() {
+				claims.push((outpoint, input_material, claim_material.soonest_timelock));
+			}
+		}
+		claims
+	}
+
+	/// Returns true if we're still trying to claim outputs, or waiting on claims to reach
+	/// ANTI_REORG_DELAY confirmations.
+	/// Returns the height at which the earliest claim on an output of the given transaction was
+	/// registered, i.e. the height at which the transaction confirmed.
+	pub(crate) fn get_claim_height(&self, txid: &Txid) -> Option<u32> {
+		self.claimable_outpoints.iter().filter(|&(outpoint, _)| outpoint.txid == *txid).map(|(_, &(_, height))| height).min()
+	}
+
+	pub(crate) fn has_pending_claims(&self) -> bool {
+		!self.pending_claim_requests.is_empty() || !self.onchain_events_waiting_threshold_conf.is_empty()
+	}
+
+	pub(crate) fn get_unsigned_holder_commitment_tx(&self) -> Option<&Transaction> {
+		self.holder_commitment.as_ref().map(|holder_commitment| &holder_commitment.unsigned_tx)
+	}
+
 	pub(crate) fn provide_latest_holder_tx(&mut self, tx: HolderCommitmentTransaction) {
 		self.prev_holder_commitment = self.holder_commitment.take();
 		self.holder_commitment = Some(tx);
@@ -984,3 +1093,87 @@ impl<ChanSigner: ChannelKeys> OnchainTxHandler<ChanSigner> {
 		ret
 	}
 }
+
+/// An unresolved claim which a ChannelMonitor deferred to its ChainMonitor for aggregation with
+/// other channels' claims, see ClaimAggregator.
+#[derive(Clone)]
+pub(crate) struct SharedClaimInput {
+	pub(crate) outpoint: BitcoinOutPoint,
+	pub(crate) amount: u64,
+	pub(crate) input_descriptor: InputDescriptors,
+	pub(crate) absolute_timelock: u32,
+}
+
+/// Builds a single claim transaction out of the non-time-critical claims of several channels,
+/// bumping it with RBF whenever its height timer expires or its set of inputs changes (i.e. new
+/// claims were registered or some inputs were spent by our counterparties).
+///
+/// Each input is signed by the ChannelMonitor it was deferred by, so this only deals with the
+/// transaction structure and its fee.
+pub(crate) struct ClaimAggregator {
+	/// The inputs of the last transaction built, in order.
+	inputs: Vec<BitcoinOutPoint>,
+	feerate_previous: u64,
+	height_timer: u32,
+}
+
+impl ClaimAggregator {
+	pub(crate) fn new() -> Self {
+		ClaimAggregator {
+			inputs: Vec::new(),
+			feerate_previous: 0,
+			height_timer: 0,
+		}
+	}
+
+	/// Given all unresolved shared claims at `height`, returns a new unsigned claim transaction
+	/// paying to `destination_script` along with its feerate if one should be (re-)broadcast.
+	pub(crate) fn update_claims<F: Deref, L: Deref>(&mut self, mut claims: Vec<SharedClaimInput>, destination_script: &Script, height: u32, fee_bump_policy: &FeeBumpPolicy, fee_estimator: F, logger: L) -> Option<(Transaction, u64)>
+		where F::Target: FeeEstimator,
+		      L::Target: Logger,
+	{
+		if claims.is_empty() {
+			self.inputs.clear();
+			self.feerate_previous = 0;
+			return None;
+		}
+		claims.sort_unstable_by_key(|claim| claim.outpoint);
+		let inputs: Vec<BitcoinOutPoint> = claims.iter().map(|claim| claim.outpoint).collect();
+		if inputs == self.inputs && self.height_timer > height {
+			return None;
+		}
+
+		let mut claim_tx = Transaction {
+			version: 2,
+			lock_time: 0,
+			input: inputs.iter().map(|outpoint| TxIn {
+				previous_output: *outpoint,
+				script_sig: Script::new(),
+				sequence: 0xfffffffd,
+				witness: Vec::new(),
+			}).collect(),
+			output: vec![TxOut {
+				script_pubkey: destination_script.clone(),
+				value: 0,
+			}],
+		};
+		let input_descriptors: Vec<InputDescriptors> = claims.iter().map(|claim| claim.input_descriptor).collect();
+		let predicted_weight = (claim_tx.get_weight() + OnchainTxHandler::<InMemoryChannelKeys>::get_witnesses_weight(&input_descriptors)) as u64;
+		let amount: u64 = claims.iter().map(|claim| claim.amount).sum();
+
+		let soonest_timelock = claims.iter().map(|claim| claim.absolute_timelock).min().unwrap();
+		let blocks_to_expiry = soonest_timelock.saturating_sub(height);
+		let (fee, feerate) = compute_claim_fee(fee_bump_policy, &fee_estimator, amount, predicted_weight, self.feerate_previous, blocks_to_expiry, &logger)?;
+		if fee >= amount {
+			log_trace!(logger, "Can't build shared claim transaction spending {} outpoints, amount {} is too small for fee {}", inputs.len(), amount, fee);
+			return None;
+		}
+		claim_tx.output[0].value = amount - fee;
+
+		self.height_timer = get_height_timer(fee_bump_policy, height, soonest_timelock);
+		self.feerate_previous = feerate;
+		self.inputs = inputs;
+		log_trace!(logger, "Built shared claim transaction {} spending {} outpoints with feerate {} and timer {}", claim_tx.txid(), claim_tx.input.len(), feerate, self.height_timer);
+		Some((claim_tx, feerate))
+	}
+}
@@ -171,18 +171,21 @@ fn run_onion_failure_test_with_fail_intercept<F1,F2,F3>(_name: &str, test_case:
 
 	let events = nodes[0].node.get_and_clear_pending_events();
 	assert_eq!(events.len(), 1);
-	if let &Event::PaymentFailed { payment_hash:_, ref rejected_by_dest, ref error_code, error_data: _ } = &events[0] {
+	let network_update = if let &Event::PaymentFailed { payment_hash:_, ref rejected_by_dest, ref network_update, ref error_code, error_data: _, .. } = &events[0] {
 		assert_eq!(*rejected_by_dest, !expected_retryable);
 		assert_eq!(*error_code, expected_error_code);
+		network_update.clone()
 	} else {
 		panic!("Uexpected event");
-	}
+	};
 
 	let events = nodes[0].node.get_and_clear_pending_msg_events();
 	if expected_channel_update.is_some() {
 		assert_eq!(events.len(), 1);
 		match events[0] {
 			MessageSendEvent::PaymentFailureNetworkUpdate { ref update } => {
+				// The PaymentFailed event carries the same update
+				assert_eq!(network_update.as_ref(), Some(update));
 				match update {
 					&HTLCFailChannelUpdate::ChannelUpdateMessage { .. } => {
 						if let HTLCFailChannelUpdate::ChannelUpdateMessage { .. } = expected_channel_update.unwrap() {} else {
@@ -211,6 +214,7 @@ fn run_onion_failure_test_with_fail_intercept<F1,F2,F3>(_name: &str, test_case:
 		}
 	} else {
 		assert_eq!(events.len(), 0);
+		assert!(network_update.is_none());
 	}
 }
 
@@ -9,9 +9,11 @@
 
 use ln::channelmanager::{PaymentHash, PaymentSecret, HTLCSource};
 use ln::msgs;
-use routing::router::RouteHop;
+use routing::network_graph::RoutingFees;
+use routing::router::{BlindedHop, BlindedRoute, RouteHint, RouteHop, TrampolineRoute};
 use util::byte_utils;
-use util::chacha20::ChaCha20;
+use util::chacha20::{ChaCha20, ChaChaReader};
+use util::chacha20poly1305rfc::ChaCha20Poly1305RFC;
 use util::errors::{self, APIError};
 use util::ser::{Readable, Writeable, LengthCalculatingWriter};
 use util::logger::Logger;
@@ -26,7 +28,8 @@ use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1::ecdh::SharedSecret;
 use bitcoin::secp256k1;
 
-use std::io::Cursor;
+use std::io::{Cursor, Read};
+use std::iter;
 use std::ops::Deref;
 
 pub(super) struct OnionKeys {
@@ -72,12 +75,12 @@ pub(super) fn gen_ammag_from_shared_secret(shared_secret: &[u8]) -> [u8; 32] {
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
 #[inline]
-pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+fn construct_onion_keys_for_pubkeys_callback<'a, T: secp256k1::Signing, I: Iterator<Item=&'a PublicKey>, FType: FnMut(SharedSecret, [u8; 32], PublicKey, usize)> (secp_ctx: &Secp256k1<T>, pubkeys: I, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
 	let mut blinded_priv = session_priv.clone();
 	let mut blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-	for hop in path.iter() {
-		let shared_secret = SharedSecret::new(&hop.pubkey, &blinded_priv);
+	for (idx, pubkey) in pubkeys.enumerate() {
+		let shared_secret = SharedSecret::new(pubkey, &blinded_priv);
 
 		let mut sha = Sha256::engine();
 		sha.input(&blinded_pub.serialize()[..]);
@@ -89,17 +92,25 @@ pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(
 		blinded_priv.mul_assign(&blinding_factor)?;
 		blinded_pub = PublicKey::from_secret_key(secp_ctx, &blinded_priv);
 
-		callback(shared_secret, blinding_factor, ephemeral_pubkey, hop);
+		callback(shared_secret, blinding_factor, ephemeral_pubkey, idx);
 	}
 
 	Ok(())
 }
 
 // can only fail if an intermediary hop has an invalid public key or session_priv is invalid
-pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
-	let mut res = Vec::with_capacity(path.len());
+#[inline]
+pub(super) fn construct_onion_keys_callback<T: secp256k1::Signing, FType: FnMut(SharedSecret, [u8; 32], PublicKey, &RouteHop)> (secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey, mut callback: FType) -> Result<(), secp256k1::Error> {
+	construct_onion_keys_for_pubkeys_callback(secp_ctx, path.iter().map(|hop| &hop.pubkey), session_priv, |shared_secret, blinding_factor, ephemeral_pubkey, idx| {
+		callback(shared_secret, blinding_factor, ephemeral_pubkey, &path[idx]);
+	})
+}
+
+// can only fail if an intermediary hop has an invalid public key or session_priv is invalid
+fn construct_onion_keys_for_pubkeys<'a, T: secp256k1::Signing, I: Iterator<Item=&'a PublicKey>>(secp_ctx: &Secp256k1<T>, pubkeys: I, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	let mut res = Vec::new();
 
-	construct_onion_keys_callback(secp_ctx, path, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _| {
+	construct_onion_keys_for_pubkeys_callback(secp_ctx, pubkeys, session_priv, |shared_secret, _blinding_factor, ephemeral_pubkey, _| {
 		let (rho, mu) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
 
 		res.push(OnionKeys {
@@ -116,6 +127,17 @@ pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T
 	Ok(res)
 }
 
+// can only fail if an intermediary hop has an invalid public key or session_priv is invalid
+pub(super) fn construct_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, path: &Vec<RouteHop>, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	construct_onion_keys_for_pubkeys(secp_ctx, path.iter().map(|hop| &hop.pubkey), session_priv)
+}
+
+// can only fail if a trampoline hop has an invalid public key or session_priv is invalid
+pub(super) fn construct_trampoline_onion_keys<T: secp256k1::Signing>(secp_ctx: &Secp256k1<T>, route: &TrampolineRoute, session_priv: &SecretKey) -> Result<Vec<OnionKeys>, secp256k1::Error> {
+	let pubkeys = iter::once(&route.first_hop.pubkey).chain(route.trampoline_hops.iter().map(|hop| &hop.pubkey));
+	construct_onion_keys_for_pubkeys(secp_ctx, pubkeys, session_priv)
+}
+
 /// returns the hop data, as well as the first-hop value_msat and CLTV value we should send.
 pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
 	let mut cur_value_msat = 0u64;
@@ -166,13 +188,175 @@ pub(super) fn build_onion_payloads(path: &Vec<RouteHop>, total_msat: u64, paymen
 	Ok((res, cur_value_msat, cur_cltv))
 }
 
+/// returns the trampoline hop data, one payload for route.first_hop's node followed by one for
+/// each of route.trampoline_hops, as well as the value_msat and CLTV value the first trampoline
+/// node should forward.
+pub(super) fn build_trampoline_onion_payloads(route: &TrampolineRoute, total_msat: u64, payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+	let mut cur_value_msat = 0u64;
+	let mut cur_cltv = starting_htlc_offset;
+	let mut res: Vec<msgs::OnionHopData> = Vec::with_capacity(route.trampoline_hops.len() + 1);
+
+	for hop in route.trampoline_hops.iter().rev() {
+		cur_value_msat += hop.fee_msat;
+		if cur_value_msat >= 21000000 * 100000000 * 1000 {
+			return Err(APIError::RouteError{err: "Trampoline fees overflowed?"});
+		}
+		cur_cltv += hop.cltv_expiry_delta;
+		if cur_cltv >= 500000000 {
+			return Err(APIError::RouteError{err: "Trampoline CLTV overflowed?"});
+		}
+		// Each payload gives the value and CLTV the next node should receive, except for the
+		// destination's, which gives the value and CLTV it should itself receive.
+		if res.is_empty() {
+			res.insert(0, msgs::OnionHopData {
+				format: msgs::OnionHopDataFormat::FinalNode {
+					payment_data: if let &Some(ref payment_secret) = payment_secret_option {
+						Some(msgs::FinalOnionHopData {
+							payment_secret: payment_secret.clone(),
+							total_msat,
+						})
+					} else { None },
+				},
+				amt_to_forward: cur_value_msat,
+				outgoing_cltv_value: cur_cltv,
+			});
+		}
+		res.insert(0, msgs::OnionHopData {
+			format: msgs::OnionHopDataFormat::TrampolineForward {
+				outgoing_node_id: hop.pubkey,
+			},
+			amt_to_forward: cur_value_msat,
+			outgoing_cltv_value: cur_cltv,
+		});
+	}
+	Ok((res, cur_value_msat, cur_cltv))
+}
+
+#[inline]
+fn gen_blinded_node_id_factor(shared_secret: &[u8]) -> [u8; 32] {
+	assert_eq!(shared_secret.len(), 32);
+	let mut hmac = HmacEngine::<Sha256>::new(b"blinded_node_id");
+	hmac.input(&shared_secret[..]);
+	Hmac::from_engine(hmac).into_inner()
+}
+
+#[inline]
+fn gen_next_blinding_factor(blinding_point: &PublicKey, shared_secret: &[u8]) -> [u8; 32] {
+	let mut sha = Sha256::engine();
+	sha.input(&blinding_point.serialize()[..]);
+	sha.input(&shared_secret[..]);
+	Sha256::from_engine(sha).into_inner()
+}
+
+/// Builds a blinded route to recipient_node_id over the given hops, the first of which leads
+/// from the introduction node. Each hop is told the channel it is to forward over, and the
+/// recipient is handed path_id, in data only the node itself can decrypt.
+/// Can only fail if a hop has an invalid public key or blinding_secret is invalid.
+pub(super) fn construct_blinded_route<T: secp256k1::Signing + secp256k1::Verification>(secp_ctx: &Secp256k1<T>, blinding_secret: &SecretKey, hops: &[RouteHint], recipient_node_id: &PublicKey, path_id: [u8; 32]) -> Result<BlindedRoute, secp256k1::Error> {
+	let mut blinding_secret = blinding_secret.clone();
+	let mut blinding_point = PublicKey::from_secret_key(secp_ctx, &blinding_secret);
+	let first_blinding_point = blinding_point;
+
+	let node_ids = hops.iter().map(|hop| &hop.src_node_id).chain(iter::once(recipient_node_id));
+	let mut blinded_hops = Vec::with_capacity(hops.len() + 1);
+	for (idx, node_id) in node_ids.enumerate() {
+		let shared_secret = SharedSecret::new(node_id, &blinding_secret);
+
+		let mut blinded_node_id = node_id.clone();
+		blinded_node_id.mul_assign(secp_ctx, &gen_blinded_node_id_factor(&shared_secret[..])[..])?;
+
+		let (hop_data, fees, cltv_expiry_delta) = match hops.get(idx) {
+			Some(hop) => (msgs::BlindedHopData { short_channel_id: Some(hop.short_channel_id), path_id: None }, hop.fees, hop.cltv_expiry_delta),
+			None => (msgs::BlindedHopData { short_channel_id: None, path_id: Some(path_id) }, RoutingFees { base_msat: 0, proportional_millionths: 0 }, 0),
+		};
+		let hop_data = hop_data.encode();
+		let (rho, _) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
+		let mut encrypted_data = vec![0; hop_data.len() + 16];
+		let mut chacha = ChaCha20Poly1305RFC::new(&rho, &[0; 12], &[]);
+		{
+			let (ciphertext, tag) = encrypted_data.split_at_mut(hop_data.len());
+			chacha.encrypt(&hop_data[..], ciphertext, tag);
+		}
+
+		blinded_hops.push(BlindedHop { blinded_node_id, encrypted_data, fees, cltv_expiry_delta });
+
+		blinding_secret.mul_assign(&gen_next_blinding_factor(&blinding_point, &shared_secret[..])[..])?;
+		blinding_point = PublicKey::from_secret_key(secp_ctx, &blinding_secret);
+	}
+
+	Ok(BlindedRoute {
+		introduction_node_id: hops.first().map(|hop| hop.src_node_id).unwrap_or(*recipient_node_id),
+		blinding_point: first_blinding_point,
+		blinded_hops,
+	})
+}
+
+/// Gets the secret for our blinded node id in a blinded route, which we use in place of our
+/// node secret to decode onions sent to us along with the given blinding point.
+pub(super) fn blinded_node_secret(node_secret: &SecretKey, blinding_point: &PublicKey) -> Result<SecretKey, secp256k1::Error> {
+	let shared_secret = SharedSecret::new(blinding_point, node_secret);
+	let mut blinded_node_secret = node_secret.clone();
+	blinded_node_secret.mul_assign(&gen_blinded_node_id_factor(&shared_secret[..])[..])?;
+	Ok(blinded_node_secret)
+}
+
+/// Decrypts the encrypted_data handed to us in a blinded route, returning it as well as the
+/// blinding point to hand to the next hop.
+pub(super) fn decrypt_blinded_hop_data<T: secp256k1::Verification>(secp_ctx: &Secp256k1<T>, node_secret: &SecretKey, blinding_point: &PublicKey, encrypted_data: &[u8]) -> Result<(msgs::BlindedHopData, PublicKey), ()> {
+	if encrypted_data.len() < 16 {
+		return Err(());
+	}
+	let shared_secret = SharedSecret::new(blinding_point, node_secret);
+	let (rho, _) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
+	let data_len = encrypted_data.len() - 16;
+	let mut hop_data = vec![0; data_len];
+	let mut chacha = ChaCha20Poly1305RFC::new(&rho, &[0; 12], &[]);
+	if !chacha.decrypt(&encrypted_data[..data_len], &mut hop_data[..], &encrypted_data[data_len..]) {
+		return Err(());
+	}
+	let hop_data: msgs::BlindedHopData = Readable::read(&mut Cursor::new(&hop_data[..])).map_err(|_| ())?;
+
+	let mut next_blinding_point = blinding_point.clone();
+	next_blinding_point.mul_assign(secp_ctx, &gen_next_blinding_factor(blinding_point, &shared_secret[..])[..]).map_err(|_| ())?;
+	Ok((hop_data, next_blinding_point))
+}
+
+/// returns the hop data for a path which ends in the given blinded route, as returned by
+/// get_blinded_route, as well as the first-hop value_msat and CLTV value we should send.
+pub(super) fn build_blinded_onion_payloads(path: &Vec<RouteHop>, blinded_route: &BlindedRoute, total_msat: u64, payment_secret_option: &Option<PaymentSecret>, starting_htlc_offset: u32) -> Result<(Vec<msgs::OnionHopData>, u64, u32), APIError> {
+	let blinded_hop_count = blinded_route.blinded_hops.len();
+	if blinded_hop_count == 0 || path.len() < blinded_hop_count {
+		return Err(APIError::RouteError{err: "Path does not end in the blinded route"});
+	}
+	let intro_idx = path.len() - blinded_hop_count;
+	if path[intro_idx].pubkey != blinded_route.introduction_node_id ||
+		path[intro_idx + 1..].iter().zip(blinded_route.blinded_hops.iter().skip(1)).any(|(hop, blinded_hop)| hop.pubkey != blinded_hop.blinded_node_id) {
+		return Err(APIError::RouteError{err: "Path does not end in the blinded route"});
+	}
+
+	let (mut res, value_msat, cltv) = build_onion_payloads(path, total_msat, payment_secret_option, starting_htlc_offset)?;
+	for (idx, (payload, blinded_hop)) in res[intro_idx..].iter_mut().zip(blinded_route.blinded_hops.iter()).enumerate() {
+		payload.format = msgs::OnionHopDataFormat::Blinded {
+			encrypted_data: blinded_hop.encrypted_data.clone(),
+			blinding_point: if idx == 0 { Some(blinded_route.blinding_point) } else { None },
+			payment_data: if idx != blinded_hop_count - 1 { None } else if let &Some(ref payment_secret) = payment_secret_option {
+				Some(msgs::FinalOnionHopData {
+					payment_secret: payment_secret.clone(),
+					total_msat,
+				})
+			} else { None },
+		};
+	}
+	Ok((res, value_msat, cltv))
+}
+
 /// Length of the onion data packet. Before TLV-based onions this was 20 65-byte hops, though now
 /// the hops can be of variable length.
 pub(crate) const ONION_DATA_LEN: usize = 20*65;
 
 #[inline]
-fn shift_arr_right(arr: &mut [u8; ONION_DATA_LEN], amt: usize) {
-	for i in (amt..ONION_DATA_LEN).rev() {
+fn shift_arr_right(arr: &mut [u8], amt: usize) {
+	for i in (amt..arr.len()).rev() {
 		arr[i] = arr[i-amt];
 	}
 	for i in 0..amt {
@@ -180,20 +364,28 @@ fn shift_arr_right(arr: &mut [u8; ONION_DATA_LEN], amt: usize) {
 	}
 }
 
-pub(super) fn route_size_insane(payloads: &Vec<msgs::OnionHopData>) -> bool {
+fn payloads_exceed_len(payloads: &Vec<msgs::OnionHopData>, data_len: usize) -> bool {
 	let mut len = 0;
 	for payload in payloads.iter() {
 		let mut payload_len = LengthCalculatingWriter(0);
 		payload.write(&mut payload_len).expect("Failed to calculate length");
 		assert!(payload_len.0 + 32 < ONION_DATA_LEN);
 		len += payload_len.0 + 32;
-		if len > ONION_DATA_LEN {
+		if len > data_len {
 			return true;
 		}
 	}
 	false
 }
 
+pub(super) fn route_size_insane(payloads: &Vec<msgs::OnionHopData>) -> bool {
+	payloads_exceed_len(payloads, ONION_DATA_LEN)
+}
+
+pub(super) fn trampoline_route_size_insane(payloads: &Vec<msgs::OnionHopData>) -> bool {
+	payloads_exceed_len(payloads, msgs::TRAMPOLINE_ONION_DATA_LEN)
+}
+
 /// panics if route_size_insane(paylods)
 pub(super) fn construct_onion_packet(payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, prng_seed: [u8; 32], associated_data: &PaymentHash) -> msgs::OnionPacket {
 	let mut packet_data = [0; ONION_DATA_LEN];
@@ -217,7 +409,39 @@ pub(super) fn construct_onion_packet_bogus_hopdata<HD: Writeable>(payloads: Vec<
 }
 
 /// panics if route_size_insane(paylods)
-fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>, onion_keys: Vec<OnionKeys>, mut packet_data: [u8; ONION_DATA_LEN], associated_data: &PaymentHash) -> msgs::OnionPacket {
+fn construct_onion_packet_with_init_noise<HD: Writeable>(payloads: Vec<HD>, onion_keys: Vec<OnionKeys>, mut packet_data: [u8; ONION_DATA_LEN], associated_data: &PaymentHash) -> msgs::OnionPacket {
+	let hmac = encrypt_onion_payloads(payloads, &onion_keys, &mut packet_data, associated_data);
+
+	msgs::OnionPacket {
+		version: 0,
+		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
+		hop_data: packet_data,
+		hmac,
+	}
+}
+
+/// panics if trampoline_route_size_insane(payloads)
+pub(super) fn construct_trampoline_onion_packet(payloads: Vec<msgs::OnionHopData>, onion_keys: Vec<OnionKeys>, prng_seed: [u8; 32], associated_data: &PaymentHash) -> msgs::TrampolineOnionPacket {
+	let mut packet_data = [0; msgs::TRAMPOLINE_ONION_DATA_LEN];
+
+	let mut chacha = ChaCha20::new(&prng_seed, &[0; 8]);
+	chacha.process(&[0; msgs::TRAMPOLINE_ONION_DATA_LEN], &mut packet_data);
+
+	let hmac = encrypt_onion_payloads(payloads, &onion_keys, &mut packet_data, associated_data);
+
+	msgs::TrampolineOnionPacket {
+		version: 0,
+		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
+		hop_data: packet_data,
+		hmac,
+	}
+}
+
+/// Layers the given payloads into packet_data (which should be pre-filled with noise), returning
+/// the HMAC for the first hop. The same construction is used for both the outer onion and the
+/// smaller trampoline onion, only the length of packet_data differs.
+fn encrypt_onion_payloads<HD: Writeable>(mut payloads: Vec<HD>, onion_keys: &Vec<OnionKeys>, packet_data: &mut [u8], associated_data: &PaymentHash) -> [u8; 32] {
+	let data_len = packet_data.len();
 	let filler = {
 		const ONION_HOP_DATA_LEN: usize = 65; // We may decrease this eventually after TLV is common
 		let mut res = Vec::with_capacity(ONION_HOP_DATA_LEN * (payloads.len() - 1));
@@ -227,7 +451,7 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 			if i == payloads.len() - 1 { break; }
 
 			let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
-			for _ in 0..(ONION_DATA_LEN - pos) { // TODO: Batch this.
+			for _ in 0..(data_len - pos) { // TODO: Batch this.
 				let mut dummy = [0; 1];
 				chacha.process_in_place(&mut dummy); // We don't have a seek function :(
 			}
@@ -235,7 +459,7 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 			let mut payload_len = LengthCalculatingWriter(0);
 			payload.write(&mut payload_len).expect("Failed to calculate length");
 			pos += payload_len.0 + 32;
-			assert!(pos <= ONION_DATA_LEN);
+			assert!(pos <= data_len);
 
 			res.resize(pos, 0u8);
 			chacha.process_in_place(&mut res);
@@ -247,28 +471,98 @@ fn construct_onion_packet_with_init_noise<HD: Writeable>(mut payloads: Vec<HD>,
 	for (i, (payload, keys)) in payloads.iter_mut().zip(onion_keys.iter()).rev().enumerate() {
 		let mut payload_len = LengthCalculatingWriter(0);
 		payload.write(&mut payload_len).expect("Failed to calculate length");
-		shift_arr_right(&mut packet_data, payload_len.0 + 32);
+		shift_arr_right(packet_data, payload_len.0 + 32);
 		packet_data[0..payload_len.0].copy_from_slice(&payload.encode()[..]);
 		packet_data[payload_len.0..(payload_len.0 + 32)].copy_from_slice(&hmac_res);
 
 		let mut chacha = ChaCha20::new(&keys.rho, &[0u8; 8]);
-		chacha.process_in_place(&mut packet_data);
+		chacha.process_in_place(packet_data);
 
 		if i == 0 {
-			packet_data[ONION_DATA_LEN - filler.len()..ONION_DATA_LEN].copy_from_slice(&filler[..]);
+			packet_data[data_len - filler.len()..data_len].copy_from_slice(&filler[..]);
 		}
 
 		let mut hmac = HmacEngine::<Sha256>::new(&keys.mu);
-		hmac.input(&packet_data);
+		hmac.input(packet_data);
 		hmac.input(&associated_data.0[..]);
 		hmac_res = Hmac::from_engine(hmac).into_inner();
 	}
 
-	msgs::OnionPacket {
-		version: 0,
-		public_key: Ok(onion_keys.first().unwrap().ephemeral_pubkey),
-		hop_data: packet_data,
-		hmac: hmac_res,
+	hmac_res
+}
+
+/// Peels our layer off a trampoline onion which was handed to us in the final hop payload of an
+/// outer onion. Returns the payload addressed to us and, if we are to act as a trampoline node,
+/// the packet to hand on to the next trampoline hop.
+/// On failure, returns the failure code to include in the error for the outer onion.
+pub(super) fn decode_trampoline_onion<T: secp256k1::Verification>(secp_ctx: &Secp256k1<T>, node_secret: &SecretKey, packet: &msgs::TrampolineOnionPacket, payment_hash: &PaymentHash) -> Result<(msgs::OnionHopData, Option<msgs::TrampolineOnionPacket>), u16> {
+	// As the trampoline onion is itself carried in an onion payload, any issues with it are
+	// reported as an invalid_onion_payload in the outer onion.
+	const INVALID_ONION_PAYLOAD: u16 = 0x4000 | 22;
+
+	let public_key = match packet.public_key {
+		Ok(public_key) => public_key,
+		Err(_) => return Err(INVALID_ONION_PAYLOAD),
+	};
+	if packet.version != 0 {
+		return Err(INVALID_ONION_PAYLOAD);
+	}
+
+	let shared_secret = SharedSecret::new(&public_key, node_secret);
+	let (rho, mu) = gen_rho_mu_from_shared_secret(&shared_secret[..]);
+
+	let mut hmac = HmacEngine::<Sha256>::new(&mu);
+	hmac.input(&packet.hop_data);
+	hmac.input(&payment_hash.0[..]);
+	if !fixed_time_eq(&Hmac::from_engine(hmac).into_inner(), &packet.hmac) {
+		return Err(INVALID_ONION_PAYLOAD);
+	}
+
+	let mut chacha = ChaCha20::new(&rho, &[0u8; 8]);
+	let mut chacha_stream = ChaChaReader { chacha: &mut chacha, read: Cursor::new(&packet.hop_data[..]) };
+	let hop_data = match msgs::OnionHopData::read(&mut chacha_stream) {
+		Ok(hop_data) => hop_data,
+		Err(_) => return Err(INVALID_ONION_PAYLOAD),
+	};
+	let mut next_hop_hmac = [0; 32];
+	if let Err(_) = chacha_stream.read_exact(&mut next_hop_hmac[..]) {
+		return Err(INVALID_ONION_PAYLOAD);
+	}
+
+	if next_hop_hmac == [0; 32] {
+		match hop_data.format {
+			msgs::OnionHopDataFormat::FinalNode { .. } => Ok((hop_data, None)),
+			_ => Err(INVALID_ONION_PAYLOAD),
+		}
+	} else {
+		match hop_data.format {
+			msgs::OnionHopDataFormat::TrampolineForward { .. } => {},
+			_ => return Err(INVALID_ONION_PAYLOAD),
+		}
+
+		let mut new_packet_data = [0; msgs::TRAMPOLINE_ONION_DATA_LEN];
+		let read_pos = chacha_stream.read(&mut new_packet_data).unwrap();
+		// Once we've emptied the bytes we were given, encrypt 0 bytes until we fill the onion hop
+		// data we'll hand to the next trampoline node.
+		chacha_stream.chacha.process_in_place(&mut new_packet_data[read_pos..]);
+
+		let mut new_pubkey = public_key;
+		let blinding_factor = {
+			let mut sha = Sha256::engine();
+			sha.input(&new_pubkey.serialize()[..]);
+			sha.input(&shared_secret[..]);
+			Sha256::from_engine(sha).into_inner()
+		};
+		let public_key = if let Err(e) = new_pubkey.mul_assign(secp_ctx, &blinding_factor[..]) {
+			Err(e)
+		} else { Ok(new_pubkey) };
+
+		Ok((hop_data, Some(msgs::TrampolineOnionPacket {
+			version: 0,
+			public_key,
+			hop_data: new_packet_data,
+			hmac: next_hop_hmac,
+		})))
 	}
 }
 
@@ -377,7 +671,8 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing, L: Deref>(secp_ctx: &
 							15|16|17|18|19 => true,
 							_ => false,
 						} && is_from_final_node) // PERM bit observed below even this error is from the intermediate nodes
-						|| error_code == 21; // Special case error 21 as the Route object is bogus, TODO: Maybe fail the node if the CLTV was reasonable?
+						|| error_code == 21 // Special case error 21 as the Route object is bogus, TODO: Maybe fail the node if the CLTV was reasonable?
+						|| error_code == 0x8000|PERM|24; // invalid_onion_blinding hides which hop in a blinded route failed, so there's no channel to blame
 
 						let mut fail_channel_update = None;
 
@@ -477,9 +772,10 @@ pub(super) fn process_onion_failure<T: secp256k1::Signing, L: Deref>(secp_ctx: &
 
 #[cfg(test)]
 mod tests {
-	use ln::channelmanager::PaymentHash;
+	use ln::channelmanager::{PaymentHash, PaymentSecret};
 	use ln::features::{ChannelFeatures, NodeFeatures};
-	use routing::router::{Route, RouteHop};
+	use routing::network_graph::RoutingFees;
+	use routing::router::{Route, RouteHint, RouteHop, TrampolineHop, TrampolineRoute};
 	use ln::msgs;
 	use util::ser::{Writeable, Writer};
 
@@ -689,4 +985,158 @@ mod tests {
 		// anyway...
 		assert_eq!(packet.encode(), hex::decode("0002eec7245d6b7d2ccb30380bfbe2a3648cd7a942653f5aa340edcea1f283686619e5f14350c2a76fc232b5e46d421e9615471ab9e0bc887beff8c95fdb878f7b3a71a060daf367132b378b3a3883c0e2c0e026b8900b2b5cdbc784e1a3bb913f88a9c50f7d61ab590531cf08000178a333a347f8b4072ed056f820f77774345e183a342ec4729f3d84accf515e88adddb85ecc08daba68404bae9a8e8d7178977d7094a1ae549f89338c0777551f874159eb42d3a59fb9285ad4e24883f27de23942ec966611e99bee1cee503455be9e8e642cef6cef7b9864130f692283f8a973d47a8f1c1726b6e59969385975c766e35737c8d76388b64f748ee7943ffb0e2ee45c57a1abc40762ae598723d21bd184e2b338f68ebff47219357bd19cd7e01e2337b806ef4d717888e129e59cd3dc31e6201ccb2fd6d7499836f37a993262468bcb3a4dcd03a22818aca49c6b7b9b8e9e870045631d8e039b066ff86e0d1b7291f71cefa7264c70404a8e538b566c17ccc5feab231401e6c08a01bd5edfc1aa8e3e533b96e82d1f91118d508924b923531929aea889fcdf050597c681185f336b1da63b0939aa2b7c50b21b5eb7b6ad66c81fab98a3cdf73f658149e7e9ced4edde5d38c9b8f92e16f6b4ab13d7fca6a0e4ecc9f9de611a90da6e99c39551094c56e3196f282c5dffd9fc4b2fc12f3bca8e6fe47eb45fbdd3be21a8a8d200797eae3c9a0497132f92410d804977408494dff49dd3d8bce248e0b74fd9e6f0f7102c25ddfa02bd9ad9f746abbfa337ef811d5345a9e16b60de1767b209645ba40bd1f9a5f75bc04feca9b27c5554be4fe83fac2cb83aa447a817bb85ae966c68b420063833fada375e2f515965e687a45699632902672c654d1d18d7bcbf55e8fa57f63f2da449f8e1e606e8722df081e5f193fc4179feb99ad22819afdeef211f7c54afdba92aeef0c00b7bc2b65a4813c01f907a8377585708f2d4c940a25328e585714c8ded0a9a4d7a6de1027c1cb7a0198cd3db68b58c0704dfd0cfbe624e9cd18cc0ae5d96697bb476708b9ee0403d211e64e0d5a7683a7a9a140c02f0ff1c6e67a302941b4052bdea8a63e70a3ad62c5b89c698f1fd3c7685cb49705096cad702d02d93bcb1c27a409f4c9bddec001205ca4a2740f19b50900be81c7e847f1a863deea8d35701f1355cad8db57b1d4eb2ab4e29587734785abfb46ddede71928213d7d089dfdeda052827f459f1688cc0935bd47e7bcec27427c8376dcce7e22699567c0d145f8a7db33f6758815f1f15f9f7a9760dec4f34ae095edda4c64e9735bdd029c4e32c2ee31ba47ec5e6bdb97813d52dbd15b4e0b7a2c7f790ae64104d99f38c127f0a093288fa34144adb16b8968d4fa7656fcec99de8503dd46d3b03620a71c7cd085364abd30dccf7fbda25a1cdc102600149c9af1c97aa0372cd2e1909f28ac5c686f432b310e79528c9b8b9e8f314c1e74621ce6308ad2278b81d460892e0d9dd38b7c76d58be6dfd10ae7583ee1e7ef5b3f6f78dc60af0950df1b00cc55b6d178ba2e476bea0eaeef49323b83f05804159e7aef4eed4cc60dd07be76f067dfd0bcfb0b806b69ba921336a20c43c832d0cab8fa3ddeb29e3bf07b0d98a112eb07802756235a49d44a8b82a950d84e95e01971f0e106ccb337f07384e21620e0ad39e16ed9edca123226cf55ac44f449eeb53e38a7f27d101806e4823e4efcc887414240ee6826c4a5cb1c6443ad36ebf905a435c1d9054e54173911b17b5b40f60b3d9fd5f12eac54ca1e20191f5f18544d5fd3d665e9bcef96fb44b76110aa64d9db4c86c9513cbdad546538e8aec521fbe83ceac5e74a15629f1ed0b870a1d0d1e5680b6d6100d1bd3f3b9043bd35b8919c4088f1949b8be89e4701eb870f8ed64fafa446c78df3ea").unwrap());
 	}
+
+	#[test]
+	fn trampoline_onion_round_trip() {
+		// Build a trampoline onion through two trampoline nodes to a destination and check that
+		// each node peels off exactly the payload meant for it.
+		let secp_ctx = Secp256k1::new();
+		let node_secrets: Vec<SecretKey> = (1..4).map(|i| SecretKey::from_slice(&[i as u8; 32]).unwrap()).collect();
+		let node_ids: Vec<PublicKey> = node_secrets.iter().map(|secret| PublicKey::from_secret_key(&secp_ctx, secret)).collect();
+		let route = TrampolineRoute {
+			first_hop: RouteHop {
+				pubkey: node_ids[0],
+				node_features: NodeFeatures::known(),
+				short_channel_id: 42,
+				channel_features: ChannelFeatures::known(),
+				fee_msat: 12_000,
+				cltv_expiry_delta: 200,
+			},
+			trampoline_hops: vec![TrampolineHop {
+				pubkey: node_ids[1],
+				fee_msat: 1_000,
+				cltv_expiry_delta: 100,
+			}, TrampolineHop {
+				pubkey: node_ids[2],
+				fee_msat: 10_000,
+				cltv_expiry_delta: 18,
+			}],
+		};
+		let payment_hash = PaymentHash([0x42; 32]);
+		let payment_secret = Some(PaymentSecret([0x43; 32]));
+
+		let session_priv = SecretKey::from_slice(&[0x44; 32]).unwrap();
+		let onion_keys = super::construct_trampoline_onion_keys(&secp_ctx, &route, &session_priv).unwrap();
+		let (payloads, forwarded_msat, forwarded_cltv) = super::build_trampoline_onion_payloads(&route, 10_000, &payment_secret, 1000).unwrap();
+		assert_eq!(forwarded_msat, 11_000);
+		assert_eq!(forwarded_cltv, 1118);
+		assert!(!super::trampoline_route_size_insane(&payloads));
+		let mut packet = super::construct_trampoline_onion_packet(payloads, onion_keys, [0x45; 32], &payment_hash);
+
+		for (idx, node_secret) in node_secrets.iter().enumerate() {
+			let (hop_data, next_packet) = super::decode_trampoline_onion(&secp_ctx, node_secret, &packet, &payment_hash).unwrap();
+			match idx {
+				0|1 => {
+					if let msgs::OnionHopDataFormat::TrampolineForward { outgoing_node_id } = hop_data.format {
+						assert_eq!(outgoing_node_id, node_ids[idx + 1]);
+					} else { panic!(); }
+					assert_eq!(hop_data.amt_to_forward, if idx == 0 { 11_000 } else { 10_000 });
+					assert_eq!(hop_data.outgoing_cltv_value, if idx == 0 { 1118 } else { 1018 });
+					packet = next_packet.unwrap();
+				},
+				_ => {
+					if let msgs::OnionHopDataFormat::FinalNode { payment_data: Some(payment_data) } = hop_data.format {
+						assert_eq!(payment_data.payment_secret, payment_secret.unwrap());
+						assert_eq!(payment_data.total_msat, 10_000);
+					} else { panic!(); }
+					assert_eq!(hop_data.amt_to_forward, 10_000);
+					assert_eq!(hop_data.outgoing_cltv_value, 1018);
+					assert!(next_packet.is_none());
+				},
+			}
+		}
+
+		// A node the packet wasn't meant for fails the HMAC check
+		assert_eq!(super::decode_trampoline_onion(&secp_ctx, &node_secrets[0], &packet, &payment_hash).err(), Some(0x4000 | 22));
+	}
+
+	#[test]
+	fn blinded_route_round_trip() {
+		// Build a blinded route from an introduction node, through another node, to a recipient
+		// and check that each node can unblind its hop and decrypt the data meant for it.
+		let secp_ctx = Secp256k1::new();
+		let node_secrets: Vec<SecretKey> = (1..4).map(|i| SecretKey::from_slice(&[i as u8; 32]).unwrap()).collect();
+		let node_ids: Vec<PublicKey> = node_secrets.iter().map(|secret| PublicKey::from_secret_key(&secp_ctx, secret)).collect();
+		let hops = vec![RouteHint {
+			src_node_id: node_ids[0],
+			short_channel_id: 42,
+			fees: RoutingFees { base_msat: 1000, proportional_millionths: 0 },
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}, RouteHint {
+			src_node_id: node_ids[1],
+			short_channel_id: 43,
+			fees: RoutingFees { base_msat: 0, proportional_millionths: 100 },
+			cltv_expiry_delta: 20,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
+		}];
+		let blinding_secret = SecretKey::from_slice(&[0x44; 32]).unwrap();
+		let route = super::construct_blinded_route(&secp_ctx, &blinding_secret, &hops, &node_ids[2], [0x42; 32]).unwrap();
+		assert_eq!(route.introduction_node_id, node_ids[0]);
+		assert_eq!(route.blinding_point, PublicKey::from_secret_key(&secp_ctx, &blinding_secret));
+		assert_eq!(route.blinded_hops.len(), 3);
+		assert_eq!(route.blinded_hops[1].fees, hops[1].fees);
+		assert_eq!(route.blinded_hops[2].cltv_expiry_delta, 0);
+
+		// A node which isn't the introduction node cannot decrypt its data
+		assert!(super::decrypt_blinded_hop_data(&secp_ctx, &node_secrets[1], &route.blinding_point, &route.blinded_hops[0].encrypted_data).is_err());
+
+		let mut blinding_point = route.blinding_point;
+		for (idx, node_secret) in node_secrets.iter().enumerate() {
+			let blinded_node_secret = super::blinded_node_secret(node_secret, &blinding_point).unwrap();
+			assert_eq!(PublicKey::from_secret_key(&secp_ctx, &blinded_node_secret), route.blinded_hops[idx].blinded_node_id);
+			assert_ne!(route.blinded_hops[idx].blinded_node_id, node_ids[idx]);
+
+			let (hop_data, next_blinding_point) = super::decrypt_blinded_hop_data(&secp_ctx, node_secret, &blinding_point, &route.blinded_hops[idx].encrypted_data).unwrap();
+			match idx {
+				0|1 => {
+					assert_eq!(hop_data.short_channel_id, Some(42 + idx as u64));
+					assert_eq!(hop_data.path_id, None);
+				},
+				_ => {
+					assert_eq!(hop_data.short_channel_id, None);
+					assert_eq!(hop_data.path_id, Some([0x42; 32]));
+				},
+			}
+			blinding_point = next_blinding_point;
+		}
+
+		// Paying into the route, the introduction node is handed the blinding point and the
+		// recipient the payment_secret, with every hop getting its encrypted data.
+		let mut path = vec![RouteHop {
+			pubkey: node_ids[0],
+			node_features: NodeFeatures::known(),
+			short_channel_id: 41,
+			channel_features: ChannelFeatures::known(),
+			fee_msat: 1000,
+			cltv_expiry_delta: 40,
+		}];
+		for (fee_msat, cltv_expiry_delta, hop) in vec![(1, 20, &route.blinded_hops[1]), (10_000, 18, &route.blinded_hops[2])] {
+			path.push(RouteHop {
+				pubkey: hop.blinded_node_id,
+				node_features: NodeFeatures::empty(),
+				short_channel_id: 0,
+				channel_features: ChannelFeatures::empty(),
+				fee_msat,
+				cltv_expiry_delta,
+			});
+		}
+		let payment_secret = Some(PaymentSecret([0x42; 32]));
+		let (payloads, value_msat, cltv) = super::build_blinded_onion_payloads(&path, &route, 10_000, &payment_secret, 1000).unwrap();
+		assert_eq!(value_msat, 11_001);
+		assert_eq!(cltv, 1078);
+		for (idx, payload) in payloads.iter().enumerate() {
+			if let msgs::OnionHopDataFormat::Blinded { ref encrypted_data, ref blinding_point, ref payment_data } = payload.format {
+				assert_eq!(*encrypted_data, route.blinded_hops[idx].encrypted_data);
+				assert_eq!(blinding_point.is_some(), idx == 0);
+				assert_eq!(payment_data.is_some(), idx == 2);
+			} else { panic!(); }
+		}
+
+		// A path which doesn't end in the blinded route is rejected
+		path[2].pubkey = node_ids[2];
+		assert!(super::build_blinded_onion_payloads(&path, &route, 10_000, &payment_secret, 1000).is_err());
+	}
 }
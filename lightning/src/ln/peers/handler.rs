@@ -19,7 +19,7 @@ use bitcoin::secp256k1::key::{SecretKey,PublicKey};
 
 use ln::features::InitFeatures;
 use ln::msgs;
-use ln::msgs::{ChannelMessageHandler, LightningError, RoutingMessageHandler};
+use ln::msgs::{ChannelMessageHandler, LightningError, RoutingMessageHandler, QueryShortChannelIds, ReplyShortChannelIdsEnd};
 use ln::channelmanager::{SimpleArcChannelManager, SimpleRefChannelManager};
 use util::ser::{Writeable};
 use ln::wire;
@@ -195,11 +195,28 @@ enum InitSyncTracker{
 	NodesSyncing(PublicKey),
 }
 
+// Returns true if gossip with the given timestamp passes the (first_timestamp, timestamp_range)
+// filter a peer set with gossip_timestamp_filter. Peers which negotiated gossip_queries only
+// receive gossip once they ask for it with a filter, while all gossip passes for other peers.
+fn gossip_filter_allows(gossip_filter: &Option<(u32, u32)>, gossip_queries: bool, timestamp: u32) -> bool {
+	match gossip_filter {
+		&None => !gossip_queries,
+		&Some((first_timestamp, timestamp_range)) => {
+			timestamp >= first_timestamp && (timestamp as u64) < first_timestamp as u64 + timestamp_range as u64
+		}
+	}
+}
+
 // Container for all state only valid after an Init message is seen
 struct PostInitState {
 	awaiting_pong: bool,
 	sync_status: InitSyncTracker,
 	their_features: InitFeatures,
+	// The (first_timestamp, timestamp_range) from the last gossip_timestamp_filter the peer sent
+	gossip_filter: Option<(u32, u32)>,
+	// A query_short_channel_ids from the peer which we are still replying to, along with the
+	// index of the next short_channel_id in it to reply with
+	pending_scid_query: Option<(QueryShortChannelIds, usize)>,
 }
 
 impl PostInitState {
@@ -207,7 +224,9 @@ impl PostInitState {
 		Self {
 			awaiting_pong: false,
 			sync_status,
-			their_features
+			their_features,
+			gossip_filter: None,
+			pending_scid_query: None,
 		}
 	}
 }
@@ -217,6 +236,8 @@ struct Peer<TransportImpl: ITransport> {
 	outbound_queue: OutboundQueue,
 	post_init_state: Option<PostInitState>,
 	transport: TransportImpl,
+	// Whether we asked the peer for a full routing table sync in our Init message
+	full_sync_requested: bool,
 }
 
 impl<TransportImpl: ITransport> Peer<TransportImpl> {
@@ -225,7 +246,8 @@ impl<TransportImpl: ITransport> Peer<TransportImpl> {
 			outbound,
 			outbound_queue: OutboundQueue::new(OUTBOUND_QUEUE_SIZE),
 			post_init_state: None,
-			transport
+			transport,
+			full_sync_requested: false,
 		}
 	}
 
@@ -267,6 +289,16 @@ impl<TransportImpl: ITransport> Peer<TransportImpl> {
 			}
 		}
 	}
+
+	/// Returns true if gossip we generated ourselves with the given timestamp falls within the
+	/// gossip_timestamp_filter this peer sent us, or if it has not sent one. Unlike gossip we relay,
+	/// BOLT #7 allows sending our own gossip to peers which have not asked for any.
+	fn should_forward_gossip_with_timestamp(&self, timestamp: u32) -> bool {
+		match &self.post_init_state {
+			None => panic!("should_forward_gossip_with_timestamp() only valid on an initialized peer"),
+			Some(state) => gossip_filter_allows(&state.gossip_filter, false, timestamp),
+		}
+	}
 }
 
 struct PeerHolder<Descriptor: SocketDescriptor, TransportImpl: ITransport> {
@@ -338,7 +370,7 @@ impl<Descriptor: SocketDescriptor, TransportImpl: ITransport> PeerHolder<Descrip
 		self.peers.iter_mut().filter(|(_, peer)| {
 			let has_outbound_sync = match &peer.post_init_state {
 				None => false,
-				Some(post_init_state) => match &post_init_state.sync_status {
+				Some(post_init_state) => post_init_state.pending_scid_query.is_some() || match &post_init_state.sync_status {
 					InitSyncTracker::NoSyncRequested => false,
 					InitSyncTracker::ChannelsSyncing(_) => true,
 					InitSyncTracker::NodesSyncing(_) => true,
@@ -604,57 +636,152 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 	}
 
 	// Fill remaining slots in output queue with sync messages, updating the sync state when
-	// appropriate
+	// appropriate. Only gossip passing the peer's gossip_timestamp_filter, if any, is sent.
 	fn fill_outbound_queue_with_sync(
 		&self,
 		sync_status: &mut InitSyncTracker,
+		gossip_filter: &Option<(u32, u32)>,
+		gossip_queries: bool,
 		transport: &mut TransportImpl,
 		outbound_queue: &mut OutboundQueue) {
 
-		let queue_space = outbound_queue.queue_space();
-		if queue_space > 0 {
+		// A batch may be entirely filtered out, so keep going until something was queued or the
+		// sync is complete.
+		loop {
+			let queue_space = outbound_queue.queue_space();
+			if queue_space == 0 {
+				return;
+			}
+			self.fill_outbound_queue_with_sync_batch(queue_space, sync_status, gossip_filter, gossip_queries, transport, outbound_queue);
 			match sync_status {
-				&mut InitSyncTracker::NoSyncRequested => {},
-				&mut InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
-					let steps = (queue_space / 3) as u8;
-					let all_messages = self.message_handler.route_handler.get_next_channel_announcements(c, steps);
-					for &(ref announce, ref update_a_option, ref update_b_option) in all_messages.iter() {
+				&mut InitSyncTracker::NoSyncRequested => return,
+				_ => if outbound_queue.queue_space() < queue_space { return },
+			}
+		}
+	}
+
+	fn fill_outbound_queue_with_sync_batch(
+		&self,
+		queue_space: usize,
+		sync_status: &mut InitSyncTracker,
+		gossip_filter: &Option<(u32, u32)>,
+		gossip_queries: bool,
+		transport: &mut TransportImpl,
+		outbound_queue: &mut OutboundQueue) {
+
+		match sync_status {
+			&mut InitSyncTracker::NoSyncRequested => {},
+			&mut InitSyncTracker::ChannelsSyncing(c) if c < 0xffff_ffff_ffff_ffff => {
+				let steps = (queue_space / 3) as u8;
+				let all_messages = self.message_handler.route_handler.get_next_channel_announcements(c, steps);
+				for &(ref announce, ref update_a_option, ref update_b_option) in all_messages.iter() {
+					let update_a_allowed = update_a_option.as_ref().map(|update| gossip_filter_allows(gossip_filter, gossip_queries, update.contents.timestamp)).unwrap_or(false);
+					let update_b_allowed = update_b_option.as_ref().map(|update| gossip_filter_allows(gossip_filter, gossip_queries, update.contents.timestamp)).unwrap_or(false);
+					// Channel announcements carry no timestamp, so send them along with any
+					// update which passes the filter.
+					if (gossip_filter.is_none() && !gossip_queries) || update_a_allowed || update_b_allowed {
 						transport.enqueue_message(announce, outbound_queue, &*self.logger);
-						if let &Some(ref update_a) = update_a_option {
+					}
+					if let &Some(ref update_a) = update_a_option {
+						if update_a_allowed {
 							transport.enqueue_message(update_a, outbound_queue, &*self.logger);
 						}
-						if let &Some(ref update_b) = update_b_option {
+					}
+					if let &Some(ref update_b) = update_b_option {
+						if update_b_allowed {
 							transport.enqueue_message(update_b, outbound_queue, &*self.logger);
 						}
-						*sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id + 1);
-					}
-					if all_messages.is_empty() || all_messages.len() != steps as usize {
-						*sync_status = InitSyncTracker::ChannelsSyncing(0xffff_ffff_ffff_ffff);
 					}
-				},
-				&mut InitSyncTracker::ChannelsSyncing(c) if c == 0xffff_ffff_ffff_ffff => {
-					let steps = queue_space as u8;
-					let all_messages = self.message_handler.route_handler.get_next_node_announcements(None, steps);
-					for msg in all_messages.iter() {
+					*sync_status = InitSyncTracker::ChannelsSyncing(announce.contents.short_channel_id + 1);
+				}
+				if all_messages.is_empty() || all_messages.len() != steps as usize {
+					*sync_status = InitSyncTracker::ChannelsSyncing(0xffff_ffff_ffff_ffff);
+				}
+			},
+			&mut InitSyncTracker::ChannelsSyncing(c) if c == 0xffff_ffff_ffff_ffff => {
+				let steps = queue_space as u8;
+				let all_messages = self.message_handler.route_handler.get_next_node_announcements(None, steps);
+				for msg in all_messages.iter() {
+					if gossip_filter_allows(gossip_filter, gossip_queries, msg.contents.timestamp) {
 						transport.enqueue_message(msg, outbound_queue, &*self.logger);
-						*sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
-					}
-					if all_messages.is_empty() || all_messages.len() != steps as usize {
-						*sync_status = InitSyncTracker::NoSyncRequested;
 					}
-				},
-				&mut InitSyncTracker::ChannelsSyncing(_) => unreachable!(),
-				&mut InitSyncTracker::NodesSyncing(key) => {
-					let steps = queue_space as u8;
-					let all_messages = self.message_handler.route_handler.get_next_node_announcements(Some(&key), steps);
-					for msg in all_messages.iter() {
+					*sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+				}
+				if all_messages.is_empty() || all_messages.len() != steps as usize {
+					*sync_status = InitSyncTracker::NoSyncRequested;
+				}
+			},
+			&mut InitSyncTracker::ChannelsSyncing(_) => unreachable!(),
+			&mut InitSyncTracker::NodesSyncing(key) => {
+				let steps = queue_space as u8;
+				let all_messages = self.message_handler.route_handler.get_next_node_announcements(Some(&key), steps);
+				for msg in all_messages.iter() {
+					if gossip_filter_allows(gossip_filter, gossip_queries, msg.contents.timestamp) {
 						transport.enqueue_message(msg, outbound_queue, &*self.logger);
-						*sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
 					}
-					if all_messages.is_empty() || all_messages.len() != steps as usize {
-						*sync_status = InitSyncTracker::NoSyncRequested;
+					*sync_status = InitSyncTracker::NodesSyncing(msg.contents.node_id);
+				}
+				if all_messages.is_empty() || all_messages.len() != steps as usize {
+					*sync_status = InitSyncTracker::NoSyncRequested;
+				}
+			},
+		}
+	}
+
+	// Fill remaining slots in output queue with replies to the peer's query_short_channel_ids,
+	// finishing with reply_short_channel_ids_end once we've replied for every short_channel_id.
+	fn fill_outbound_queue_with_scid_query_replies(
+		&self,
+		pending_scid_query: &mut Option<(QueryShortChannelIds, usize)>,
+		transport: &mut TransportImpl,
+		outbound_queue: &mut OutboundQueue) {
+
+		loop {
+			let queue_space = outbound_queue.queue_space();
+			if queue_space == 0 {
+				return;
+			}
+			let (reply_end, finished) = match pending_scid_query {
+				&mut None => return,
+				&mut Some((ref query, ref mut next_idx)) => {
+					// Each short_channel_id may be answered with up to three channel messages
+					let batch_end = cmp::min(query.short_channel_ids.len(), *next_idx + cmp::max(queue_space / 3, 1));
+					let batch = QueryShortChannelIds {
+						chain_hash: query.chain_hash,
+						short_channel_ids: query.short_channel_ids[*next_idx..batch_end].to_vec(),
+						encoding_type: query.encoding_type,
+						query_flags: query.query_flags.as_ref().map(|query_flags| query_flags[*next_idx..batch_end].to_vec()),
+					};
+					*next_idx = batch_end;
+					match self.message_handler.route_handler.handle_query_short_channel_ids(&transport.get_their_node_id(), &batch) {
+						Ok((channels, nodes, reply_end)) => {
+							for &(ref announce_option, ref update_a_option, ref update_b_option) in channels.iter() {
+								if let &Some(ref announce) = announce_option {
+									transport.enqueue_message(announce, outbound_queue, &*self.logger);
+								}
+								if let &Some(ref update_a) = update_a_option {
+									transport.enqueue_message(update_a, outbound_queue, &*self.logger);
+								}
+								if let &Some(ref update_b) = update_b_option {
+									transport.enqueue_message(update_b, outbound_queue, &*self.logger);
+								}
+							}
+							for node in nodes.iter() {
+								transport.enqueue_message(node, outbound_queue, &*self.logger);
+							}
+							let finished = batch_end == query.short_channel_ids.len() || !reply_end.full_information;
+							(reply_end, finished)
+						},
+						Err(e) => {
+							log_debug!(self.logger, "Failed to reply to query_short_channel_ids: {}", e.err);
+							(ReplyShortChannelIdsEnd { chain_hash: query.chain_hash, full_information: false }, true)
+						},
 					}
 				},
+			};
+			if finished {
+				transport.enqueue_message(&reply_end, outbound_queue, &*self.logger);
+				*pending_scid_query = None;
 			}
 		}
 	}
@@ -667,10 +794,14 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 		outbound_queue: &mut OutboundQueue) {
 
 		while !outbound_queue.is_blocked() {
-			// If connected, fill output queue with sync messages
+			// If connected, fill output queue with sync messages and replies to gossip queries
 			match post_init_state {
 				None => {},
-				&mut Some(ref mut state) => self.fill_outbound_queue_with_sync(&mut state.sync_status, transport, outbound_queue)
+				&mut Some(ref mut state) => {
+					let gossip_queries = state.their_features.supports_gossip_queries();
+					self.fill_outbound_queue_with_sync(&mut state.sync_status, &state.gossip_filter, gossip_queries, transport, outbound_queue);
+					self.fill_outbound_queue_with_scid_query_replies(&mut state.pending_scid_query, transport, outbound_queue);
+				},
 			}
 
 			// No messages to send
@@ -721,15 +852,18 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 		}
 
 		log_info!(
-			self.logger, "Received peer Init message: data_loss_protect: {}, initial_routing_sync: {}, upfront_shutdown_script: {}, static_remote_key: {}, unknown flags: {}",
+			self.logger, "Received peer Init message: data_loss_protect: {}, initial_routing_sync: {}, upfront_shutdown_script: {}, gossip_queries: {}, static_remote_key: {}, unknown flags: {}",
 			if init_message.features.supports_data_loss_protect() { "supported" } else { "not supported"},
 			if init_message.features.initial_routing_sync() { "requested" } else { "not requested" },
 			if init_message.features.supports_upfront_shutdown_script() { "supported" } else { "not supported"},
+			if init_message.features.supports_gossip_queries() { "supported" } else { "not supported"},
 			if init_message.features.supports_static_remote_key() { "supported" } else { "not supported"},
 			if init_message.features.supports_unknown_bits() { "present" } else { "none" }
 		);
 
-		let sync_status = if init_message.features.initial_routing_sync() {
+		// Peers which support gossip_queries ask for our routing table with gossip_timestamp_filter
+		// instead of initial_routing_sync.
+		let sync_status = if init_message.features.initial_routing_sync() && !init_message.features.supports_gossip_queries() {
 			InitSyncTracker::ChannelsSyncing(0)
 		} else {
 			InitSyncTracker::NoSyncRequested
@@ -745,8 +879,9 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 
 	// Add an Init message to the outbound queue
 	fn enqueue_init_message(&self, peer: &mut Peer<TransportImpl>) {
-		let mut features = InitFeatures::known();
-		if !self.message_handler.route_handler.should_request_full_sync(&peer.transport.get_their_node_id()) {
+		let mut features = self.message_handler.chan_handler.provided_init_features();
+		peer.full_sync_requested = self.message_handler.route_handler.should_request_full_sync(&peer.transport.get_their_node_id());
+		if !peer.full_sync_requested {
 			features.clear_initial_routing_sync();
 		}
 
@@ -781,6 +916,16 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 
 				assert!(peer.post_init_state.is_none());
 				peer.post_init_state = Some(new_post_init_state);
+
+				if init_message.features.supports_gossip_queries() {
+					let (filter, query) = self.message_handler.route_handler.sync_routing_table(&their_node_id, peer.full_sync_requested);
+					if let Some(filter) = filter {
+						peer.transport.enqueue_message(&filter, &mut peer.outbound_queue, &*self.logger);
+					}
+					if let Some(query) = query {
+						peer.transport.enqueue_message(&query, &mut peer.outbound_queue, &*self.logger);
+					}
+				}
 			}
 			_ => {
 				log_trace!(self.logger, "Peer {} sent non-Init first message", log_pubkey!(&their_node_id));
@@ -995,6 +1140,57 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 				}
 			},
 
+			// Gossip queries:
+			wire::Message::QueryChannelRange(msg) => {
+				let replies = match self.message_handler.route_handler.handle_query_channel_range(&their_node_id, &msg) {
+					Ok(v) => v,
+					Err(e) => { return Err(e.into()); },
+				};
+				for reply in replies.iter() {
+					peer.transport.enqueue_message(reply, &mut peer.outbound_queue, &*self.logger);
+				}
+			},
+			wire::Message::ReplyChannelRange(msg) => {
+				let query = match self.message_handler.route_handler.handle_reply_channel_range(&their_node_id, &msg) {
+					Ok(v) => v,
+					Err(e) => { return Err(e.into()); },
+				};
+				if let Some(query) = query {
+					peer.transport.enqueue_message(&query, &mut peer.outbound_queue, &*self.logger);
+				}
+			},
+			wire::Message::QueryShortChannelIds(msg) => {
+				// Peers must wait for our reply_short_channel_ids_end before sending another query.
+				// The replies are streamed out as room frees up in the outbound queue.
+				if post_init_state.pending_scid_query.is_some() {
+					log_debug!(self.logger, "Peer {} sent query_short_channel_ids before we finished replying to its last one", log_pubkey!(their_node_id));
+					return Err(PeerHandleError{ no_connection_possible: false }.into());
+				}
+				post_init_state.pending_scid_query = Some((msg, 0));
+			},
+			wire::Message::ReplyShortChannelIdsEnd(msg) => {
+				let query = match self.message_handler.route_handler.handle_reply_short_channel_ids_end(&their_node_id, &msg) {
+					Ok(v) => v,
+					Err(e) => { return Err(e.into()); },
+				};
+				if let Some(query) = query {
+					peer.transport.enqueue_message(&query, &mut peer.outbound_queue, &*self.logger);
+				}
+			},
+			wire::Message::GossipTimestampFilter(msg) => {
+				if let Err(e) = self.message_handler.route_handler.handle_gossip_timestamp_filter(&their_node_id, &msg) {
+					return Err(e.into());
+				}
+				// The first filter a peer sends also asks for the gossip we already have which
+				// passes it, so dump our routing table through the filter.
+				if post_init_state.gossip_filter.is_none() {
+					if let InitSyncTracker::NoSyncRequested = post_init_state.sync_status {
+						post_init_state.sync_status = InitSyncTracker::ChannelsSyncing(0);
+					}
+				}
+				post_init_state.gossip_filter = Some((msg.first_timestamp, msg.timestamp_range));
+			},
+
 			// Unknown messages:
 			wire::Message::Unknown(msg_type) if msg_type.is_even() => {
 				log_debug!(self.logger, "Received unknown even message of type {}, disconnecting peer!", msg_type);
@@ -1182,7 +1378,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 						};
 						if route_handler_wants_broadcast {
 							for (descriptor, peer) in peers.initialized_peers_mut() {
-								if !peer.should_forward_channel_announcement(msg.contents.short_channel_id) {
+								if !peer.should_forward_channel_announcement(msg.contents.short_channel_id) ||
+									!peer.should_forward_gossip_with_timestamp(update_msg.contents.timestamp) {
 									continue
 								}
 
@@ -1204,7 +1401,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 							},
 							Ok(true) => {
 								for (descriptor, peer) in peers.initialized_peers_mut() {
-									if !peer.should_forward_node_announcement(msg.contents.node_id) {
+									if !peer.should_forward_node_announcement(msg.contents.node_id) ||
+										!peer.should_forward_gossip_with_timestamp(msg.contents.timestamp) {
 										continue
 									}
 									peer.transport.enqueue_message(msg, &mut peer.outbound_queue, &*self.logger);
@@ -1222,7 +1420,8 @@ impl<Descriptor: SocketDescriptor, CM: Deref, RM: Deref, L: Deref, TransportImpl
 							},
 							Ok(true) => {
 								for (descriptor, peer) in peers.initialized_peers_mut() {
-									if !peer.should_forward_channel_announcement(msg.contents.short_channel_id) {
+									if !peer.should_forward_channel_announcement(msg.contents.short_channel_id) ||
+										!peer.should_forward_gossip_with_timestamp(msg.contents.timestamp) {
 										continue
 									}
 									peer.transport.enqueue_message(msg, &mut peer.outbound_queue, &*self.logger);
@@ -1637,7 +1836,8 @@ mod unit_tests {
 		let mut descriptor = SocketDescriptorMock::with_fixed_size(0);
 		let mut transport = new_connected_transport!(&test_ctx);
 		let peer_manager = new_peer_manager_for_test!(&test_ctx);
-		transport.borrow_mut().add_incoming_message(Message::Init(Init { features: InitFeatures::known() }));
+		// Peers supporting gossip_queries ignore initial_routing_sync, so use one which does not
+		transport.borrow_mut().add_incoming_message(Message::Init(Init { features: InitFeatures::known().clear_gossip_queries() }));
 
 		new_inbound!(peer_manager, descriptor, &mut transport);
 
@@ -1987,6 +2187,135 @@ mod unit_tests {
 		assert_read_event_errors!(peer_manager, &mut descriptor, true);
 	}
 
+	// Test that a connection with a peer supporting gossip_queries:
+	// * sends the messages returned by sync_routing_table() after Init
+	#[test]
+	fn post_init_gossip_queries_sync_routing_table() {
+		let mut routing_handler = RoutingMessageHandlerTestStub::new();
+		routing_handler.sync_routing_table_return = (
+			Some(GossipTimestampFilter { chain_hash: Default::default(), first_timestamp: 0, timestamp_range: 0 }),
+			Some(QueryChannelRange { chain_hash: Default::default(), first_blocknum: 0, number_of_blocks: 0, query_option: None }));
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, RoutingMessageHandlerTestStub>::with_routing_handler(routing_handler);
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_for_test!(&test_ctx);
+		new_outbound!(peer_manager, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::Init(Init { features: InitFeatures::known() }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		let recording = descriptor.get_recording();
+		assert_eq!(recording.len(), 3);
+		assert_matches_message!(&recording[0].0, Message::Init(_));
+		assert_matches_message!(&recording[1].0, Message::GossipTimestampFilter(_));
+		assert_matches_message!(&recording[2].0, Message::QueryChannelRange(_));
+	}
+
+	// Test that a connection with a peer not supporting gossip_queries:
+	// * does not call sync_routing_table()
+	#[test]
+	fn post_init_no_gossip_queries_no_sync_routing_table() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, RoutingMessageHandlerTestSpy>::with_routing_handler(RoutingMessageHandlerTestSpy::new());
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_for_test!(&test_ctx);
+		new_outbound!(peer_manager, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::Init(Init { features: InitFeatures::known().clear_gossip_queries() }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		assert!(!route_handler_called!(&test_ctx, sync_routing_table));
+	}
+
+	// Test that a post-init connection:
+	// * read_event() calls the correct RoutingMessageHandler callback given a gossip query message
+	macro_rules! generate_handle_gossip_query_test {
+		($expected_cb: ident, $msg: expr) => {
+			#[test]
+			fn $expected_cb() {
+				let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, RoutingMessageHandlerTestSpy>::with_routing_handler(RoutingMessageHandlerTestSpy::new());
+				let mut descriptor = SocketDescriptorMock::new();
+				let transport = new_connected_transport!(&test_ctx);
+				let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+				transport.borrow_mut().add_incoming_message($msg);
+
+				assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+				// Replies to query_short_channel_ids are only generated as we write to the peer
+				peer_manager.process_events();
+				assert!(route_handler_called!(&test_ctx, $expected_cb));
+			}
+		}
+	}
+
+	generate_handle_gossip_query_test!(handle_query_channel_range, Message::QueryChannelRange(QueryChannelRange { chain_hash: Default::default(), first_blocknum: 0, number_of_blocks: 0, query_option: None }));
+	generate_handle_gossip_query_test!(handle_reply_channel_range, Message::ReplyChannelRange(ReplyChannelRange { chain_hash: Default::default(), first_blocknum: 0, number_of_blocks: 0, full_information: true, short_channel_ids: vec![], encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None }));
+	generate_handle_gossip_query_test!(handle_query_short_channel_ids, Message::QueryShortChannelIds(QueryShortChannelIds { chain_hash: Default::default(), short_channel_ids: vec![], encoding_type: EncodingType::Uncompressed, query_flags: None }));
+	generate_handle_gossip_query_test!(handle_reply_short_channel_ids_end, Message::ReplyShortChannelIdsEnd(ReplyShortChannelIdsEnd { chain_hash: Default::default(), full_information: true }));
+	generate_handle_gossip_query_test!(handle_gossip_timestamp_filter, Message::GossipTimestampFilter(GossipTimestampFilter { chain_hash: Default::default(), first_timestamp: 0, timestamp_range: 0 }));
+
+	// Test that a post-init connection:
+	// * read_event() answers a query_short_channel_ids with reply_short_channel_ids_end
+	#[test]
+	fn post_init_query_short_channel_ids_sends_reply_end() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, RoutingMessageHandlerTestStub>::new();
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::QueryShortChannelIds(QueryShortChannelIds { chain_hash: Default::default(), short_channel_ids: vec![], encoding_type: EncodingType::Uncompressed, query_flags: None }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		let recording = descriptor.get_recording();
+		assert_eq!(recording.len(), 1);
+		assert_matches_message!(&recording[0].0, Message::ReplyShortChannelIdsEnd(_));
+	}
+
+	// Test that a post-init connection:
+	// * replies to query_short_channel_ids are only queued as room frees up in the outbound queue
+	// * reply_short_channel_ids_end follows once every short_channel_id has been answered
+	#[test]
+	fn post_init_query_short_channel_ids_streams_replies() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, TestRoutingMessageHandler>::with_routing_handler(TestRoutingMessageHandler::new());
+		let mut descriptor = SocketDescriptorMock::with_fixed_size(0);
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::QueryShortChannelIds(QueryShortChannelIds { chain_hash: Default::default(), short_channel_ids: (0..1000).collect(), encoding_type: EncodingType::Uncompressed, query_flags: None }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		assert_matches!(peer_manager.write_buffer_space_avail(&mut descriptor), Ok(()));
+
+		// Nothing can be written, so we only answer as many channels as fit in the queue
+		let channels_answered = test_ctx.route_handler.chan_anns_sent.load(Ordering::Acquire);
+		assert!(channels_answered > 0);
+		assert!(channels_answered * 3 <= OUTBOUND_QUEUE_SIZE + 2);
+
+		// Another query before we finished replying to the first is a protocol violation
+		transport.borrow_mut().add_incoming_message(Message::QueryShortChannelIds(QueryShortChannelIds { chain_hash: Default::default(), short_channel_ids: vec![0], encoding_type: EncodingType::Uncompressed, query_flags: None }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Err(_));
+	}
+
+	// Test that a post-init connection:
+	// * eventually answers every short_channel_id in a query_short_channel_ids, followed by
+	//   reply_short_channel_ids_end
+	#[test]
+	fn post_init_query_short_channel_ids_sends_all_replies() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, TestRoutingMessageHandler>::with_routing_handler(TestRoutingMessageHandler::new());
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::QueryShortChannelIds(QueryShortChannelIds { chain_hash: Default::default(), short_channel_ids: (0..100).collect(), encoding_type: EncodingType::Uncompressed, query_flags: None }));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		assert_eq!(test_ctx.route_handler.chan_anns_sent.load(Ordering::Acquire), 100);
+		let recording = descriptor.get_recording();
+		assert_eq!(recording.len(), 301);
+		assert_matches_message!(&recording[0].0, Message::ChannelAnnouncement(_));
+		assert_matches_message!(&recording[300].0, Message::ReplyShortChannelIdsEnd(_));
+	}
+
 	// Test that a post-Init connection:
 	// * read_event() ignores Message::Unknown (odd)
 	#[test]
@@ -2825,6 +3154,101 @@ mod unit_tests {
 		assert_matches_message!(&recording[0].0, Message::ChannelUpdate(_));
 	}
 
+	// Test that a post-Init connection:
+	// * process_events() only forwards broadcast gossip passing the peer's gossip_timestamp_filter
+	#[test]
+	fn post_init_broadcast_channel_update_gossip_timestamp_filter() {
+		let channel_handler = TestChannelMessageHandler::new();
+		let test_ctx = TestCtx::<&TestChannelMessageHandler, RoutingMessageHandlerTestStub>::with_channel_handler(&channel_handler);
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::GossipTimestampFilter(GossipTimestampFilter {
+			chain_hash: Default::default(),
+			first_timestamp: 100,
+			timestamp_range: 100,
+		}));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		// Let the (empty) routing table dump started by the filter complete
+		peer_manager.process_events();
+
+		for timestamp in [99, 100, 199, 200].iter() {
+			let mut msg = fake_channel_update_msg!();
+			msg.contents.timestamp = *timestamp;
+			channel_handler.pending_events.lock().unwrap().push(BroadcastChannelUpdate { msg });
+		}
+		peer_manager.process_events();
+
+		let recording = descriptor.get_recording();
+		assert_eq!(recording.len(), 2);
+		assert_matches_message!(&recording[0].0, Message::ChannelUpdate(ChannelUpdate { contents: UnsignedChannelUpdate { timestamp: 100, .. }, .. }));
+		assert_matches_message!(&recording[1].0, Message::ChannelUpdate(ChannelUpdate { contents: UnsignedChannelUpdate { timestamp: 199, .. }, .. }));
+	}
+
+	// Test that a post-Init connection:
+	// * the first gossip_timestamp_filter starts a routing table dump
+	// * gossip not passing the filter is left out of the dump
+	#[test]
+	fn post_init_gossip_timestamp_filter_starts_filtered_sync() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, TestRoutingMessageHandler>::with_routing_handler(TestRoutingMessageHandler::new());
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		// The TestRoutingMessageHandler's channel updates all have a timestamp of 0
+		transport.borrow_mut().add_incoming_message(Message::GossipTimestampFilter(GossipTimestampFilter {
+			chain_hash: Default::default(),
+			first_timestamp: 1,
+			timestamp_range: 0xffff_ffff,
+		}));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		assert_eq!(test_ctx.route_handler.chan_anns_sent.load(Ordering::Acquire), 50);
+		assert!(descriptor.get_recording().is_empty());
+	}
+
+	// Test that a connection with a peer supporting gossip_queries:
+	// * does not dump our routing table if the peer set initial_routing_sync without sending a
+	//   gossip_timestamp_filter
+	#[test]
+	fn post_init_gossip_queries_no_filter_no_sync() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, TestRoutingMessageHandler>::with_routing_handler(TestRoutingMessageHandler::new());
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_for_test!(&test_ctx);
+		transport.borrow_mut().add_incoming_message(Message::Init(Init { features: InitFeatures::known() }));
+		new_outbound!(peer_manager, descriptor, &transport);
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		assert_eq!(test_ctx.route_handler.chan_anns_sent.load(Ordering::Acquire), 0);
+	}
+
+	// Test that a post-Init connection:
+	// * a gossip_timestamp_filter covering everything sends the whole routing table
+	#[test]
+	fn post_init_gossip_timestamp_filter_sends_sync() {
+		let test_ctx = TestCtx::<ChannelMessageHandlerTestSpy, TestRoutingMessageHandler>::with_routing_handler(TestRoutingMessageHandler::new());
+		let mut descriptor = SocketDescriptorMock::new();
+		let transport = new_connected_transport!(&test_ctx);
+		let peer_manager = new_peer_manager_post_init!(&test_ctx, &mut descriptor, &transport);
+
+		transport.borrow_mut().add_incoming_message(Message::GossipTimestampFilter(GossipTimestampFilter {
+			chain_hash: Default::default(),
+			first_timestamp: 0,
+			timestamp_range: 0xffff_ffff,
+		}));
+		assert_matches!(peer_manager.read_event(&mut descriptor, &[]), Ok(_));
+		peer_manager.process_events();
+
+		let recording = descriptor.get_recording();
+		assert!(!recording.is_empty());
+		assert_matches_message!(&recording[0].0, Message::ChannelAnnouncement(_));
+		assert_matches_message!(&recording[1].0, Message::ChannelUpdate(_));
+	}
+
 	// Test that a post-Init connection:
 	// * process_events() calls the correct route handler callback when it receives a
 	//   PaymentFailureNetworkUpdate event
@@ -3254,6 +3678,10 @@ mod tests {
 		peers[1].process_events();
 		peers[0].read_event(&mut fd_a, &fd_b.outbound_data.lock().unwrap().split_off(0)).unwrap();
 		peers[0].process_events();
+		// Each peer only dumps its routing table once it sees the other's gossip_timestamp_filter,
+		// so the peer which sent its filter last receives its sync a round later.
+		peers[1].read_event(&mut fd_b, &fd_a.outbound_data.lock().unwrap().split_off(0)).unwrap();
+		peers[1].process_events();
 
 		// Check that each peer has received the expected number of channel updates and channel
 		// announcements.
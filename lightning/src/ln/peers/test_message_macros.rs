@@ -131,7 +131,8 @@ macro_rules! fake_update_add_htlc_msg {
 				public_key: Ok(fake_public_key!()),
 				hop_data: [0; 1300],
 				hmac: [0; 32]
-			}
+			},
+			blinding_point: None,
 		}
 	}}
 }
@@ -20,7 +20,7 @@ use std::default::Default;
 
 use ln::functional_test_utils::*;
 
-fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
+fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool, tx_interface: bool) {
 	// Our on-chain HTLC-claim learning has a few properties worth testing:
 	//  * If an upstream HTLC is claimed with a preimage (both against our own commitment
 	//    transaction our counterparty's), we claim it backwards immediately.
@@ -36,6 +36,9 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 	//
 	// We then either allow these transactions to confirm (if !claim) or we wait until one block
 	// before they otherwise would and reorg them out, confirming an HTLC-Success tx instead.
+	//
+	// If tx_interface is set, node 1 learns of transactions and reorgs through the
+	// transaction-level chain interface instead of full block (dis)connection.
 	let chanmon_cfgs = create_chanmon_cfgs(3);
 	let node_cfgs = create_node_cfgs(3, &chanmon_cfgs);
 	let node_chanmgrs = create_node_chanmgrs(3, &node_cfgs, &[None, None, None]);
@@ -51,6 +54,18 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 	check_added_monitors!(nodes[2], 1);
 	get_htlc_update_msgs!(nodes[2], nodes[1].node.get_our_node_id());
 
+	let mut node_1_confirmed_txn = Vec::new();
+	macro_rules! connect_node_1_block {
+		($block: expr, $height: expr) => {
+			node_1_confirmed_txn.extend_from_slice(&$block.txdata);
+			if tx_interface {
+				confirm_transactions_out_of_band(&nodes[1], $block, $height);
+			} else {
+				connect_block(&nodes[1], $block, $height);
+			}
+		}
+	}
+
 	let header = BlockHeader { version: 0x2000_0000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
 	let claim_txn = if local_commitment {
 		// Broadcast node 1 commitment txn to broadcast the HTLC-Timeout
@@ -72,7 +87,7 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 		check_spends!(node_2_commitment_txn[0], node_1_commitment_txn[0]);
 
 		// Confirm node 1's commitment txn (and HTLC-Timeout) on node 1
-		connect_block(&nodes[1], &Block { header, txdata: node_1_commitment_txn.clone() }, CHAN_CONFIRM_DEPTH + 1);
+		connect_node_1_block!(&Block { header, txdata: node_1_commitment_txn.clone() }, CHAN_CONFIRM_DEPTH + 1);
 
 		// ...but return node 1's commitment tx in case claim is set and we're preparing to reorg
 		vec![node_1_commitment_txn[0].clone(), node_2_commitment_txn[0].clone()]
@@ -85,7 +100,7 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 		check_spends!(node_2_commitment_txn[1], node_2_commitment_txn[0]);
 
 		// Give node 1 node 2's commitment transaction and get its response (timing the HTLC out)
-		connect_block(&nodes[1], &Block { header, txdata: vec![node_2_commitment_txn[0].clone()] }, CHAN_CONFIRM_DEPTH + 1);
+		connect_node_1_block!(&Block { header, txdata: vec![node_2_commitment_txn[0].clone()] }, CHAN_CONFIRM_DEPTH + 1);
 		let node_1_commitment_txn = nodes[1].tx_broadcaster.txn_broadcasted.lock().unwrap();
 		assert_eq!(node_1_commitment_txn.len(), 3); // ChannelMonitor: 1 offered HTLC-Timeout, ChannelManger: 1 local commitment tx, 1 Offered HTLC-Timeout
 		assert_eq!(node_1_commitment_txn[1].output.len(), 2); // to-local and Offered HTLC (to-remote is dust)
@@ -94,7 +109,7 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 		check_spends!(node_1_commitment_txn[0], node_2_commitment_txn[0]);
 
 		// Confirm node 2's commitment txn (and node 1's HTLC-Timeout) on node 1
-		connect_block(&nodes[1], &Block { header, txdata: vec![node_2_commitment_txn[0].clone(), node_1_commitment_txn[0].clone()] }, CHAN_CONFIRM_DEPTH + 1);
+		connect_node_1_block!(&Block { header, txdata: vec![node_2_commitment_txn[0].clone(), node_1_commitment_txn[0].clone()] }, CHAN_CONFIRM_DEPTH + 1);
 		// ...but return node 2's commitment tx (and claim) in case claim is set and we're preparing to reorg
 		node_2_commitment_txn
 	};
@@ -110,7 +125,7 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 			header: BlockHeader { version: 0x20000000, prev_blockhash: block.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 },
 			txdata: vec![],
 		};
-		connect_block(&nodes[1], &block, i);
+		connect_node_1_block!(&block, i);
 		blocks.push(block.clone());
 	}
 	check_added_monitors!(nodes[1], 0);
@@ -118,15 +133,22 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 
 	if claim {
 		// Now reorg back to CHAN_CONFIRM_DEPTH and confirm node 2's broadcasted transactions:
-		for (height, block) in (CHAN_CONFIRM_DEPTH + 1..CHAN_CONFIRM_DEPTH + ANTI_REORG_DELAY - 1).zip(blocks.iter()).rev() {
-			disconnect_block(&nodes[1], &block.header, height);
+		if tx_interface {
+			let tip = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+			let relevant_txids = nodes[1].chain_monitor.chain_monitor.get_relevant_txids();
+			assert!(node_1_confirmed_txn.iter().any(|tx| relevant_txids.contains(&tx.txid())));
+			unconfirm_transactions_out_of_band(&nodes[1], &node_1_confirmed_txn, &tip, CHAN_CONFIRM_DEPTH);
+		} else {
+			for (height, block) in (CHAN_CONFIRM_DEPTH + 1..CHAN_CONFIRM_DEPTH + ANTI_REORG_DELAY - 1).zip(blocks.iter()).rev() {
+				disconnect_block(&nodes[1], &block.header, height);
+			}
 		}
 
 		block = Block {
 			header: BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 },
 			txdata: claim_txn,
 		};
-		connect_block(&nodes[1], &block, CHAN_CONFIRM_DEPTH + 1);
+		connect_node_1_block!(&block, CHAN_CONFIRM_DEPTH + 1);
 
 		// ChannelManager only polls chain::Watch::release_pending_monitor_events when we
 		// probe it for events, so we probe non-message events here (which should still end up empty):
@@ -137,7 +159,7 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 			header: BlockHeader { version: 0x20000000, prev_blockhash: block.block_hash(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 },
 			txdata: vec![],
 		};
-		connect_block(&nodes[1], &block, CHAN_CONFIRM_DEPTH + ANTI_REORG_DELAY);
+		connect_node_1_block!(&block, CHAN_CONFIRM_DEPTH + ANTI_REORG_DELAY);
 		expect_pending_htlcs_forwardable!(nodes[1]);
 	}
 
@@ -166,17 +188,64 @@ fn do_test_onchain_htlc_reorg(local_commitment: bool, claim: bool) {
 
 #[test]
 fn test_onchain_htlc_claim_reorg_local_commitment() {
-	do_test_onchain_htlc_reorg(true, true);
+	do_test_onchain_htlc_reorg(true, true, false);
 }
 #[test]
 fn test_onchain_htlc_timeout_delay_local_commitment() {
-	do_test_onchain_htlc_reorg(true, false);
+	do_test_onchain_htlc_reorg(true, false, false);
 }
 #[test]
 fn test_onchain_htlc_claim_reorg_remote_commitment() {
-	do_test_onchain_htlc_reorg(false, true);
+	do_test_onchain_htlc_reorg(false, true, false);
 }
 #[test]
 fn test_onchain_htlc_timeout_delay_remote_commitment() {
-	do_test_onchain_htlc_reorg(false, false);
+	do_test_onchain_htlc_reorg(false, false, false);
+}
+#[test]
+fn test_onchain_htlc_claim_reorg_tx_interface() {
+	do_test_onchain_htlc_reorg(true, true, true);
+	do_test_onchain_htlc_reorg(false, true, true);
+}
+#[test]
+fn test_onchain_htlc_timeout_delay_tx_interface() {
+	do_test_onchain_htlc_reorg(true, false, true);
+	do_test_onchain_htlc_reorg(false, false, true);
+}
+
+#[test]
+fn test_funding_confirmed_and_unconfirmed_tx_interface() {
+	// Tests that a channel gets locked in when its funding transaction is provided through the
+	// transaction-level chain interface, and is force-closed once the funding transaction is
+	// reported as unconfirmed.
+	let chanmon_cfgs = create_chanmon_cfgs(2);
+	let node_cfgs = create_node_cfgs(2, &chanmon_cfgs);
+	let node_chanmgrs = create_node_chanmgrs(2, &node_cfgs, &[None, None]);
+	let nodes = create_network(2, &node_cfgs, &node_chanmgrs);
+
+	let funding_tx = create_chan_between_nodes_with_value_init(&nodes[0], &nodes[1], 100000, 10001, InitFeatures::known(), InitFeatures::known());
+
+	let header = BlockHeader { version: 0x20000000, prev_blockhash: Default::default(), merkle_root: Default::default(), time: 42, bits: 42, nonce: 42 };
+	for node in nodes.iter() {
+		confirm_transactions_out_of_band(node, &Block { header, txdata: vec![funding_tx.clone()] }, 1);
+		assert!(node.node.get_and_clear_pending_msg_events().is_empty());
+		assert_eq!(node.node.get_relevant_txids(), vec![funding_tx.txid()]);
+	}
+
+	// Jump straight to the height at which the funding transaction has enough confirmations.
+	let tip = BlockHeader { version: 0x20000000, prev_blockhash: header.block_hash(), merkle_root: Default::default(), time: 43, bits: 42, nonce: 42 };
+	nodes[1].node.best_block_updated(&tip, CHAN_CONFIRM_DEPTH);
+	nodes[0].node.handle_funding_locked(&nodes[1].node.get_our_node_id(), &get_event_msg!(nodes[1], MessageSendEvent::SendFundingLocked, nodes[0].node.get_our_node_id()));
+	nodes[0].node.best_block_updated(&tip, CHAN_CONFIRM_DEPTH);
+	let (funding_locked, _) = create_chan_between_nodes_with_value_confirm_second(&nodes[1], &nodes[0]);
+	nodes[1].node.handle_funding_locked(&nodes[0].node.get_our_node_id(), &funding_locked.0);
+	get_event_msg!(nodes[1], MessageSendEvent::SendAnnouncementSignatures, nodes[0].node.get_our_node_id());
+	assert_eq!(nodes[0].node.list_usable_channels().len(), 1);
+
+	// Once the funding transaction is reorged out, the channel must be force-closed.
+	nodes[0].node.transaction_unconfirmed(&funding_tx.txid());
+	check_closed_broadcast!(nodes[0], false);
+	check_added_monitors!(nodes[0], 1);
+	assert!(nodes[0].node.list_channels().is_empty());
+	assert!(nodes[0].node.get_relevant_txids().is_empty());
 }
@@ -15,32 +15,180 @@ use bitcoin::secp256k1;
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
-use bitcoin::blockdata::script::Builder;
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::script::{Builder, Script};
 use bitcoin::blockdata::transaction::TxOut;
 use bitcoin::blockdata::opcodes;
+use bitcoin::hash_types::BlockHash;
 
 use chain;
 use chain::Access;
+use chain::transaction::TransactionData;
+use ln::chan_utils::make_funding_redeemscript;
 use ln::features::{ChannelFeatures, NodeFeatures};
 use ln::msgs::{DecodeError, ErrorAction, LightningError, RoutingMessageHandler, NetAddress, MAX_VALUE_MSAT};
 use ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, OptionalField};
+use ln::msgs::{GossipTimestampFilter, QueryChannelRange, ReplyChannelRange, QueryShortChannelIds, ReplyShortChannelIdsEnd};
+use ln::msgs::{ChannelUpdateChecksums, ChannelUpdateTimestamps, EncodingType};
 use ln::msgs;
-use util::ser::{Writeable, Readable, Writer};
+use ln::peers::encryption::LN_MAX_MSG_LEN;
+use util::ser::{BigSize, Writeable, Readable, Writer};
 use util::logger::Logger;
 
-use std::{cmp, fmt};
-use std::sync::{RwLock, RwLockReadGuard};
+use std::{cmp, fmt, io};
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::collections::btree_map::Entry as BtreeEntry;
 use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
 use bitcoin::hashes::hex::ToHex;
 
+/// The maximum number of short_channel_ids we place in a single reply_channel_range, keeping the
+/// message comfortably below the 65535 byte limit when no timestamps or checksums are included.
+const MAX_SCIDS_PER_REPLY: usize = 8000;
+
+/// The number of bytes a reply_channel_range takes up besides its per-channel data: the 2-byte
+/// message type, chain_hash, first_blocknum, number_of_blocks, full_information, and the length
+/// and encoding_type of the encoded short_channel_ids.
+const REPLY_CHANNEL_RANGE_OVERHEAD: usize = 2 + 32 + 4 + 4 + 1 + 2 + 1;
+
+/// The number of bytes each of the timestamps and checksums TLVs adds to a reply_channel_range
+/// besides its per-channel data: a 1-byte type and a 3-byte BigSize length (plus the
+/// encoding_type, for timestamps).
+const REPLY_CHANNEL_RANGE_TLV_OVERHEAD: usize = 1 + 3 + 1;
+
+/// The number of short_channel_ids we can fit in a single uncompressed reply_channel_range
+/// without exceeding LN_MAX_MSG_LEN, given which of the timestamps and checksums TLVs the peer
+/// asked for. Each of them adds another 8 bytes per channel.
+fn max_scids_per_reply(include_timestamps: bool, include_checksums: bool) -> usize {
+	let mut overhead = REPLY_CHANNEL_RANGE_OVERHEAD;
+	let mut bytes_per_scid = 8;
+	if include_timestamps {
+		overhead += REPLY_CHANNEL_RANGE_TLV_OVERHEAD;
+		bytes_per_scid += 8;
+	}
+	if include_checksums {
+		overhead += REPLY_CHANNEL_RANGE_TLV_OVERHEAD;
+		bytes_per_scid += 8;
+	}
+	cmp::min(MAX_SCIDS_PER_REPLY, (LN_MAX_MSG_LEN - overhead) / bytes_per_scid)
+}
+
+/// The maximum number of short_channel_ids we ask for in a single query_short_channel_ids. Each
+/// one may be answered with up to three messages, so we keep batches small and only send the next
+/// one once the peer has sent reply_short_channel_ids_end.
+const MAX_SCIDS_PER_QUERY: usize = 500;
+
+/// The age after which a channel whose channel_updates are all at least this old is considered
+/// stale and pruned from the network graph. BOLT #7 allows pruning channels after two weeks
+/// without updates.
+pub const STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS: u64 = 60 * 60 * 24 * 14;
+
+/// The magic bytes at the start of a graph snapshot written by NetworkGraph::write_snapshot.
+const SNAPSHOT_MAGIC: [u8; 3] = *b"LNG";
+
+/// The version of the graph snapshot format written by NetworkGraph::write_snapshot. Snapshots of
+/// any other version are rejected when read.
+pub const SNAPSHOT_VERSION: u8 = 2;
+
+/// The number of channel removals we remember in order to include them in snapshot deltas. Deltas
+/// from before the oldest one we still have are served as full snapshots instead.
+const MAX_REMOVED_CHANNEL_RECORDS: usize = 10_000;
+
+/// The maximum number of channels we queue up to query a single peer for. This is well above the
+/// number of public channels, and keeps a peer from making us track arbitrarily many.
+const MAX_QUEUED_SCIDS: usize = 100_000;
+
+/// All the query_flags bits, requesting every message related to a channel.
+const ALL_QUERY_FLAGS: u64 = msgs::QUERY_FLAG_CHANNEL_ANNOUNCEMENT | msgs::QUERY_FLAG_CHANNEL_UPDATE_1 |
+	msgs::QUERY_FLAG_CHANNEL_UPDATE_2 | msgs::QUERY_FLAG_NODE_ANNOUNCEMENT_1 | msgs::QUERY_FLAG_NODE_ANNOUNCEMENT_2;
+
+/// The block height encoded in the top three bytes of a short_channel_id.
+fn block_from_scid(short_channel_id: &u64) -> u32 {
+	(short_channel_id >> 40) as u32
+}
+
+/// The CRC32C (Castagnoli) checksum of the given data.
+fn crc32c(data: &[u8]) -> u32 {
+	let mut crc = 0xffff_ffffu32;
+	for byte in data.iter() {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+/// The checksum of a channel_update included in reply_channel_range, which is the CRC32C of the
+/// message with its signature and timestamp removed. It lets peers tell whether a newer update
+/// actually changed anything or merely refreshed the timestamp.
+fn channel_update_checksum(update: &ChannelUpdate) -> u32 {
+	let mut data = update.contents.encode();
+	// The timestamp follows the 32-byte chain_hash and 8-byte short_channel_id
+	data.drain(32 + 8..32 + 8 + 4);
+	crc32c(&data)
+}
+
+/// The timestamp and checksum of the last channel_update we can relay for one direction of a
+/// channel, or zeros if we have none.
+fn update_timestamp_and_checksum(direction: &Option<DirectionalChannelInfo>) -> (u32, u32) {
+	match direction.as_ref().and_then(|info| info.last_update_message.as_ref()) {
+		Some(update) => (update.contents.timestamp, channel_update_checksum(update)),
+		None => (0, 0),
+	}
+}
+
+/// State of our outstanding gossip queries to a single peer.
+struct PendingGossipQueries {
+	/// The block after the last one covered by the query_channel_range we sent the peer, if we are
+	/// still waiting on reply_channel_range messages covering the rest of it.
+	awaiting_range_end: Option<u32>,
+	/// Channels the peer told us about in reply_channel_range which we have not yet queried, along
+	/// with the query_flags describing which of their messages we are missing.
+	queued_scids: BTreeMap<u64, u64>,
+	/// Whether we have sent a query_short_channel_ids and are waiting on the matching
+	/// reply_short_channel_ids_end.
+	awaiting_reply_end: bool,
+}
+
+/// Gets the current unix time, or 0 if the clock is set before the epoch.
+fn unix_time_now() -> u64 {
+	match SystemTime::now().duration_since(UNIX_EPOCH) {
+		Ok(duration) => duration.as_secs(),
+		Err(_) => 0,
+	}
+}
+
 /// Represents the network as nodes and channels between them
 #[derive(PartialEq)]
 pub struct NetworkGraph {
 	channels: BTreeMap<u64, ChannelInfo>,
 	nodes: BTreeMap<PublicKey, NodeInfo>,
+	/// Index of channels by their funding_redeemscript, used to notice their funding outputs being
+	/// spent on-chain. Not serialized, as it is rebuilt from the channels on read.
+	channels_by_funding_redeemscript: HashMap<Script, u64>,
+	/// The sequence number of the last change to the graph. Snapshot deltas are keyed on it rather
+	/// than on gossip timestamps, which are set by the channel's nodes and say nothing about when
+	/// we learned of an update.
+	last_change_sequence: u64,
+	/// The sequence numbers at which each channel and each of its directions last changed.
+	channel_change_sequences: BTreeMap<u64, ChannelChangeSequences>,
+	/// The sequence number at which each recently removed channel was removed, oldest first.
+	removed_channels: VecDeque<(u64, u64)>,
+	/// The sequence number of the newest removal we no longer remember. Deltas from before it
+	/// can't list every removal.
+	removed_channels_pruned_through: u64,
+}
+
+/// The sequence numbers at which a channel and each of its directions last changed, see
+/// NetworkGraph::last_change_sequence.
+#[derive(Clone, Copy, Default, PartialEq)]
+struct ChannelChangeSequences {
+	channel: u64,
+	one_to_two: u64,
+	two_to_one: u64,
 }
 
 /// A simple newtype for RwLockReadGuard<'a, NetworkGraph>.
@@ -58,7 +206,9 @@ pub struct NetGraphMsgHandler<C: Deref, L: Deref> where C::Target: chain::Access
 	/// Representation of the payment channel network
 	pub network_graph: RwLock<NetworkGraph>,
 	chain_access: Option<C>,
+	genesis_hash: BlockHash,
 	full_syncs_requested: AtomicUsize,
+	pending_gossip_queries: Mutex<HashMap<PublicKey, PendingGossipQueries>>,
 	logger: L,
 }
 
@@ -68,31 +218,99 @@ impl<C: Deref, L: Deref> NetGraphMsgHandler<C, L> where C::Target: chain::Access
 	/// Chain monitor is used to make sure announced channels exist on-chain,
 	/// channel data is correct, and that the announcement is signed with
 	/// channel owners' keys.
-	pub fn new(chain_access: Option<C>, logger: L) -> Self {
+	/// The genesis hash identifies the chain we answer and issue gossip queries for.
+	pub fn new(genesis_hash: BlockHash, chain_access: Option<C>, logger: L) -> Self {
 		NetGraphMsgHandler {
 			secp_ctx: Secp256k1::verification_only(),
-			network_graph: RwLock::new(NetworkGraph {
-				channels: BTreeMap::new(),
-				nodes: BTreeMap::new(),
-			}),
+			network_graph: RwLock::new(NetworkGraph::new()),
 			full_syncs_requested: AtomicUsize::new(0),
+			pending_gossip_queries: Mutex::new(HashMap::new()),
 			chain_access,
+			genesis_hash,
 			logger,
 		}
 	}
 
 	/// Creates a new tracker of the actual state of the network of channels and nodes,
 	/// assuming an existing Network Graph.
-	pub fn from_net_graph(chain_access: Option<C>, logger: L, network_graph: NetworkGraph) -> Self {
+	pub fn from_net_graph(genesis_hash: BlockHash, chain_access: Option<C>, logger: L, network_graph: NetworkGraph) -> Self {
 		NetGraphMsgHandler {
 			secp_ctx: Secp256k1::verification_only(),
 			network_graph: RwLock::new(network_graph),
 			full_syncs_requested: AtomicUsize::new(0),
+			pending_gossip_queries: Mutex::new(HashMap::new()),
 			chain_access,
+			genesis_hash,
 			logger,
 		}
 	}
 
+	/// Returns the next query_short_channel_ids to send to a peer, if we are not already waiting
+	/// on a reply from it and still have channels queued to ask it about.
+	fn next_scid_query(&self, queries: &mut PendingGossipQueries) -> Option<QueryShortChannelIds> {
+		if queries.awaiting_reply_end || queries.queued_scids.is_empty() {
+			return None;
+		}
+		let batch: Vec<(u64, u64)> = queries.queued_scids.iter().take(MAX_SCIDS_PER_QUERY).map(|(scid, flags)| (*scid, *flags)).collect();
+		for &(scid, _) in batch.iter() {
+			queries.queued_scids.remove(&scid);
+		}
+		queries.awaiting_reply_end = true;
+		// Only include query_flags if we are not asking for everything, as peers which did not send
+		// us timestamps may not understand them.
+		let query_flags = if batch.iter().all(|&(_, flags)| flags == ALL_QUERY_FLAGS) {
+			None
+		} else {
+			Some(batch.iter().map(|&(_, flags)| flags).collect())
+		};
+		Some(QueryShortChannelIds {
+			chain_hash: self.genesis_hash,
+			short_channel_ids: batch.iter().map(|&(scid, _)| scid).collect(),
+			encoding_type: EncodingType::Uncompressed,
+			query_flags,
+		})
+	}
+
+	/// Removes channels from the network graph whose channel_updates are all older than
+	/// STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS, as well as any nodes left without channels.
+	///
+	/// This should be called periodically, eg once an hour, to keep the graph from accumulating
+	/// channels which have been closed without us noticing.
+	pub fn remove_stale_channels(&self) {
+		let removed = self.network_graph.write().unwrap().remove_stale_channels_with_time(unix_time_now());
+		if !removed.is_empty() {
+			log_debug!(self.logger, "Pruned {} stale channels from the network graph", removed.len());
+		}
+	}
+
+	/// Removes channels from the network graph whose funding outputs are spent by transactions in
+	/// the given block, as well as any nodes left without channels.
+	///
+	/// This should be called for each connected block, in order. Channels removed this way are not
+	/// restored if the block is later disconnected, though they will be learned again through
+	/// gossip if they are still open.
+	pub fn block_connected(&self, _header: &BlockHeader, txdata: &TransactionData, height: u32) {
+		let removed = self.network_graph.write().unwrap().remove_spent_channels(txdata);
+		for short_channel_id in removed.iter() {
+			log_trace!(self.logger, "Removed channel {} from the network graph as its funding output was spent at height {}", short_channel_id, height);
+		}
+	}
+
+	/// Writes a compact snapshot of our network graph for clients to bootstrap from. See
+	/// NetworkGraph::write_snapshot for details.
+	pub fn write_snapshot<W: Writer>(&self, since_sequence: u64, writer: &mut W) -> Result<(), io::Error> {
+		self.network_graph.read().unwrap().write_snapshot(&self.genesis_hash, since_sequence, writer)
+	}
+
+	/// Applies a snapshot written by write_snapshot to our network graph, returning the change
+	/// sequence number to request the next delta from. See NetworkGraph::apply_snapshot for
+	/// details.
+	pub fn apply_snapshot<R: io::Read>(&self, reader: &mut R) -> Result<u64, DecodeError> {
+		let since_sequence = self.network_graph.write().unwrap().apply_snapshot(&self.genesis_hash, reader)?;
+		log_debug!(self.logger, "Applied network graph snapshot up to change sequence number {}", since_sequence);
+		Ok(since_sequence)
+	}
+
 	/// Take a read lock on the network_graph and return it in the C-bindings
 	/// newtype helper. This is likely only useful when called via the C
 	/// bindings as you can call `self.network_graph.read().unwrap()` in Rust
@@ -145,8 +363,7 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 						if script_pubkey != expected_script {
 							return Err(LightningError{err: format!("Channel announcement key ({}) didn't match on-chain script ({})", script_pubkey.to_hex(), expected_script.to_hex()), action: ErrorAction::IgnoreError});
 						}
-						//TODO: Check if value is worth storing, use it to inform routing, and compare it
-						//to the new HTLC max field in channel_update
+						//TODO: Check if value is worth storing
 						Some(value)
 					},
 					Err(chain::AccessError::UnknownChain) => {
@@ -243,6 +460,270 @@ impl<C: Deref + Sync + Send, L: Deref + Sync + Send> RoutingMessageHandler for N
 			false
 		}
 	}
+
+	fn sync_routing_table(&self, their_node_id: &PublicKey, full_sync: bool) -> (Option<GossipTimestampFilter>, Option<QueryChannelRange>) {
+		// Any queries left over from a previous connection to this peer will never be answered.
+		let mut pending_gossip_queries = self.pending_gossip_queries.lock().unwrap();
+		pending_gossip_queries.remove(their_node_id);
+
+		// We learn about historical gossip through channel range queries, so only ask the peer to
+		// relay gossip generated from now on.
+		let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+			Ok(duration) => duration.as_secs() as u32,
+			Err(_) => 0,
+		};
+		let filter = GossipTimestampFilter {
+			chain_hash: self.genesis_hash,
+			first_timestamp: now,
+			timestamp_range: 0xffff_ffff,
+		};
+		let query = if full_sync {
+			log_debug!(self.logger, "Querying {} for all channels", log_pubkey!(their_node_id));
+			// Ask for update timestamps and checksums so we only fetch channels which changed.
+			let query = QueryChannelRange {
+				chain_hash: self.genesis_hash,
+				first_blocknum: 0,
+				number_of_blocks: 0xffff_ffff,
+				query_option: Some(msgs::QUERY_OPTION_TIMESTAMPS | msgs::QUERY_OPTION_CHECKSUMS),
+			};
+			pending_gossip_queries.insert(their_node_id.clone(), PendingGossipQueries {
+				awaiting_range_end: Some(query.first_blocknum.saturating_add(query.number_of_blocks)),
+				queued_scids: BTreeMap::new(),
+				awaiting_reply_end: false,
+			});
+			Some(query)
+		} else { None };
+		(Some(filter), query)
+	}
+
+	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			log_debug!(self.logger, "Received query_channel_range from {} for an unknown chain", log_pubkey!(their_node_id));
+			return Ok(vec![ReplyChannelRange {
+				chain_hash: msg.chain_hash,
+				first_blocknum: msg.first_blocknum,
+				number_of_blocks: msg.number_of_blocks,
+				full_information: false,
+				short_channel_ids: Vec::new(),
+				encoding_type: EncodingType::Uncompressed,
+				timestamps: None,
+				checksums: None,
+			}]);
+		}
+
+		let query_option = msg.query_option.unwrap_or(0);
+		let include_timestamps = query_option & msgs::QUERY_OPTION_TIMESTAMPS != 0;
+		let include_checksums = query_option & msgs::QUERY_OPTION_CHECKSUMS != 0;
+		let query_end = msg.first_blocknum.saturating_add(msg.number_of_blocks);
+		let mut short_channel_ids = Vec::new();
+		let mut timestamps = Vec::new();
+		let mut checksums = Vec::new();
+		{
+			let network_graph = self.network_graph.read().unwrap();
+			for (scid, chan) in network_graph.get_channels().range(((msg.first_blocknum as u64) << 40)..) {
+				if block_from_scid(scid) >= query_end { break; }
+				short_channel_ids.push(*scid);
+				if include_timestamps || include_checksums {
+					let (timestamp_node_id_1, checksum_node_id_1) = update_timestamp_and_checksum(&chan.one_to_two);
+					let (timestamp_node_id_2, checksum_node_id_2) = update_timestamp_and_checksum(&chan.two_to_one);
+					timestamps.push(ChannelUpdateTimestamps { timestamp_node_id_1, timestamp_node_id_2 });
+					checksums.push(ChannelUpdateChecksums { checksum_node_id_1, checksum_node_id_2 });
+				}
+			}
+		}
+
+		if short_channel_ids.is_empty() {
+			return Ok(vec![ReplyChannelRange {
+				chain_hash: self.genesis_hash,
+				first_blocknum: msg.first_blocknum,
+				number_of_blocks: msg.number_of_blocks,
+				full_information: true,
+				short_channel_ids,
+				encoding_type: EncodingType::Uncompressed,
+				timestamps: if include_timestamps { Some(Vec::new()) } else { None },
+				checksums: if include_checksums { Some(Vec::new()) } else { None },
+			}]);
+		}
+
+		// Split the channels across replies which together cover the queried range. Successive
+		// replies may share a block if its channels did not fit in a single reply.
+		let scids_per_reply = max_scids_per_reply(include_timestamps, include_checksums);
+		let chunk_count = (short_channel_ids.len() + scids_per_reply - 1) / scids_per_reply;
+		let mut replies = Vec::with_capacity(chunk_count);
+		let mut next_first_blocknum = msg.first_blocknum;
+		for (idx, chunk) in short_channel_ids.chunks(scids_per_reply).enumerate() {
+			let first_blocknum = cmp::min(next_first_blocknum, block_from_scid(&chunk[0]));
+			let end_blocknum = if idx == chunk_count - 1 { query_end } else { block_from_scid(chunk.last().unwrap()) + 1 };
+			let chunk_range = idx * scids_per_reply..idx * scids_per_reply + chunk.len();
+			replies.push(ReplyChannelRange {
+				chain_hash: self.genesis_hash,
+				first_blocknum,
+				number_of_blocks: end_blocknum - first_blocknum,
+				full_information: true,
+				short_channel_ids: chunk.to_vec(),
+				encoding_type: EncodingType::Uncompressed,
+				timestamps: if include_timestamps { Some(timestamps[chunk_range.clone()].to_vec()) } else { None },
+				checksums: if include_checksums { Some(checksums[chunk_range].to_vec()) } else { None },
+			});
+			next_first_blocknum = end_blocknum;
+		}
+		log_trace!(self.logger, "Replying to query_channel_range from {} with {} channels in {} messages", log_pubkey!(their_node_id), short_channel_ids.len(), replies.len());
+		Ok(replies)
+	}
+
+	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: &ReplyChannelRange) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			return Err(LightningError{err: "Received reply_channel_range for an unknown chain".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if !msg.full_information {
+			return Err(LightningError{err: "Received reply_channel_range with no information available".to_owned(), action: ErrorAction::IgnoreError});
+		}
+
+		let mut pending_gossip_queries = self.pending_gossip_queries.lock().unwrap();
+		let (next_query, finished) = {
+			let queries = match pending_gossip_queries.get_mut(their_node_id) {
+				Some(queries) => queries,
+				None => return Err(LightningError{err: "Received unsolicited reply_channel_range".to_owned(), action: ErrorAction::IgnoreError}),
+			};
+			let range_end = match queries.awaiting_range_end {
+				Some(range_end) => range_end,
+				None => return Err(LightningError{err: "Received unsolicited reply_channel_range".to_owned(), action: ErrorAction::IgnoreError}),
+			};
+			let reply_end = msg.first_blocknum.saturating_add(msg.number_of_blocks);
+			if reply_end >= range_end {
+				queries.awaiting_range_end = None;
+			}
+			{
+				let network_graph = self.network_graph.read().unwrap();
+				for (idx, scid) in msg.short_channel_ids.iter().enumerate() {
+					if queries.queued_scids.len() >= MAX_QUEUED_SCIDS && !queries.queued_scids.contains_key(scid) {
+						log_debug!(self.logger, "Too many channels queued to query {} for, ignoring the rest of its reply_channel_range", log_pubkey!(their_node_id));
+						break;
+					}
+					let chan = match network_graph.get_channels().get(scid) {
+						Some(chan) => chan,
+						None => {
+							*queries.queued_scids.entry(*scid).or_insert(0) |= ALL_QUERY_FLAGS;
+							continue;
+						},
+					};
+					// For channels we already know, only fetch the channel_updates which the peer has a
+					// newer version of. If checksums were provided, skip updates which only refreshed
+					// the timestamp without changing anything.
+					let their_timestamps = match msg.timestamps {
+						Some(ref timestamps) => timestamps[idx],
+						None => continue,
+					};
+					let their_checksums = msg.checksums.as_ref().map(|checksums| checksums[idx]);
+					let (our_timestamp_1, our_checksum_1) = update_timestamp_and_checksum(&chan.one_to_two);
+					let (our_timestamp_2, our_checksum_2) = update_timestamp_and_checksum(&chan.two_to_one);
+					let mut flags = 0;
+					if their_timestamps.timestamp_node_id_1 > our_timestamp_1 &&
+							their_checksums.map(|checksums| checksums.checksum_node_id_1 != our_checksum_1).unwrap_or(true) {
+						flags |= msgs::QUERY_FLAG_CHANNEL_UPDATE_1;
+					}
+					if their_timestamps.timestamp_node_id_2 > our_timestamp_2 &&
+							their_checksums.map(|checksums| checksums.checksum_node_id_2 != our_checksum_2).unwrap_or(true) {
+						flags |= msgs::QUERY_FLAG_CHANNEL_UPDATE_2;
+					}
+					if flags != 0 {
+						*queries.queued_scids.entry(*scid).or_insert(0) |= flags;
+					}
+				}
+			}
+			log_trace!(self.logger, "Received reply_channel_range from {}, {} channels queued for query", log_pubkey!(their_node_id), queries.queued_scids.len());
+			let next_query = self.next_scid_query(queries);
+			(next_query, !queries.awaiting_reply_end && queries.awaiting_range_end.is_none())
+		};
+		if finished {
+			pending_gossip_queries.remove(their_node_id);
+		}
+		Ok(next_query)
+	}
+
+	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(Vec<(Option<ChannelAnnouncement>, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>, ReplyShortChannelIdsEnd), LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			log_debug!(self.logger, "Received query_short_channel_ids from {} for an unknown chain", log_pubkey!(their_node_id));
+			return Ok((Vec::new(), Vec::new(), ReplyShortChannelIdsEnd {
+				chain_hash: msg.chain_hash,
+				full_information: false,
+			}));
+		}
+
+		let network_graph = self.network_graph.read().unwrap();
+		let mut channels = Vec::new();
+		let mut nodes = Vec::new();
+		let mut nodes_sent = HashSet::new();
+		for (idx, scid) in msg.short_channel_ids.iter().enumerate() {
+			let flags = match msg.query_flags {
+				Some(ref query_flags) => query_flags[idx],
+				None => ALL_QUERY_FLAGS,
+			};
+			let chan = match network_graph.get_channels().get(scid) {
+				Some(chan) => chan,
+				None => continue,
+			};
+			// Never reveal updates for channels we could not relay the announcement of
+			let chan_announcement = match chan.announcement_message {
+				Some(ref announcement) => announcement,
+				None => continue,
+			};
+			let one_to_two_update = if flags & msgs::QUERY_FLAG_CHANNEL_UPDATE_1 != 0 {
+				chan.one_to_two.as_ref().and_then(|one_to_two| one_to_two.last_update_message.clone())
+			} else { None };
+			let two_to_one_update = if flags & msgs::QUERY_FLAG_CHANNEL_UPDATE_2 != 0 {
+				chan.two_to_one.as_ref().and_then(|two_to_one| two_to_one.last_update_message.clone())
+			} else { None };
+			let chan_announcement = if flags & msgs::QUERY_FLAG_CHANNEL_ANNOUNCEMENT != 0 { Some(chan_announcement.clone()) } else { None };
+			if chan_announcement.is_some() || one_to_two_update.is_some() || two_to_one_update.is_some() {
+				channels.push((chan_announcement, one_to_two_update, two_to_one_update));
+			}
+
+			let requested_nodes = [(chan.node_one, msgs::QUERY_FLAG_NODE_ANNOUNCEMENT_1), (chan.node_two, msgs::QUERY_FLAG_NODE_ANNOUNCEMENT_2)];
+			for &(ref node_id, node_flag) in requested_nodes.iter() {
+				if flags & node_flag == 0 || !nodes_sent.insert(*node_id) { continue; }
+				if let Some(node) = network_graph.get_nodes().get(node_id) {
+					if let Some(ref announcement_info) = node.announcement_info {
+						if let Some(ref announcement) = announcement_info.announcement_message {
+							nodes.push(announcement.clone());
+						}
+					}
+				}
+			}
+		}
+		log_trace!(self.logger, "Replying to query_short_channel_ids from {} with {} channels and {} nodes", log_pubkey!(their_node_id), channels.len(), nodes.len());
+		Ok((channels, nodes, ReplyShortChannelIdsEnd {
+			chain_hash: self.genesis_hash,
+			full_information: true,
+		}))
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: &ReplyShortChannelIdsEnd) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		let mut pending_gossip_queries = self.pending_gossip_queries.lock().unwrap();
+		let (next_query, finished) = match pending_gossip_queries.get_mut(their_node_id) {
+			Some(ref mut queries) if queries.awaiting_reply_end && msg.chain_hash == self.genesis_hash => {
+				queries.awaiting_reply_end = false;
+				if !msg.full_information {
+					log_debug!(self.logger, "Peer {} has no information for our chain, dropping queued gossip queries", log_pubkey!(their_node_id));
+					queries.queued_scids.clear();
+				}
+				let next_query = self.next_scid_query(queries);
+				(next_query, !queries.awaiting_reply_end && queries.awaiting_range_end.is_none())
+			},
+			_ => return Err(LightningError{err: "Received unsolicited reply_short_channel_ids_end".to_owned(), action: ErrorAction::IgnoreError}),
+		};
+		if finished {
+			pending_gossip_queries.remove(their_node_id);
+		}
+		Ok(next_query)
+	}
+
+	fn handle_gossip_timestamp_filter(&self, _their_node_id: &PublicKey, msg: &GossipTimestampFilter) -> Result<(), LightningError> {
+		if msg.chain_hash != self.genesis_hash {
+			return Err(LightningError{err: "Received gossip_timestamp_filter for an unknown chain".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		Ok(())
+	}
 }
 
 #[derive(PartialEq, Debug)]
@@ -302,6 +783,18 @@ pub struct ChannelInfo {
 	pub two_to_one: Option<DirectionalChannelInfo>,
 	/// The channel capacity as seen on-chain, if chain lookup is available.
 	pub capacity_sats: Option<u64>,
+	/// The 2-of-2 multisig script of the channel's funding output, built from the bitcoin keys in
+	/// its announcement. It is revealed in the witness of any transaction spending the funding
+	/// output, letting us notice when the channel closes. Empty if we only know of the channel
+	/// from a snapshot.
+	///
+	/// Serialized along with the NetworkGraph rather than as part of the ChannelInfo itself.
+	pub funding_redeemscript: Script,
+	/// The unix time at which we learned of the channel, used to prune it if it never receives a
+	/// channel_update.
+	///
+	/// Serialized along with the NetworkGraph rather than as part of the ChannelInfo itself.
+	pub announcement_received_time: u64,
 	/// An initial announcement of the channel
 	/// Mostly redundant with the data we store in fields explicitly.
 	/// Everything else is useful only for sending out for initial routing sync.
@@ -317,15 +810,45 @@ impl fmt::Display for ChannelInfo {
 	}
 }
 
-impl_writeable!(ChannelInfo, 0, {
-	features,
-	node_one,
-	one_to_two,
-	node_two,
-	two_to_one,
-	capacity_sats,
-	announcement_message
-});
+impl Writeable for ChannelInfo {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.features.write(writer)?;
+		self.node_one.write(writer)?;
+		self.one_to_two.write(writer)?;
+		self.node_two.write(writer)?;
+		self.two_to_one.write(writer)?;
+		self.capacity_sats.write(writer)?;
+		self.announcement_message.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for ChannelInfo {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<ChannelInfo, DecodeError> {
+		let features = Readable::read(reader)?;
+		let node_one = Readable::read(reader)?;
+		let one_to_two = Readable::read(reader)?;
+		let node_two = Readable::read(reader)?;
+		let two_to_one = Readable::read(reader)?;
+		let capacity_sats = Readable::read(reader)?;
+		let announcement_message: Option<ChannelAnnouncement> = Readable::read(reader)?;
+		let funding_redeemscript = match announcement_message {
+			Some(ref msg) => make_funding_redeemscript(&msg.contents.bitcoin_key_1, &msg.contents.bitcoin_key_2),
+			None => Script::new(),
+		};
+		Ok(ChannelInfo {
+			features,
+			node_one,
+			one_to_two,
+			node_two,
+			two_to_one,
+			capacity_sats,
+			funding_redeemscript,
+			announcement_received_time: unix_time_now(),
+			announcement_message,
+		})
+	}
+}
 
 
 /// Fees for routing via a given channel or a node
@@ -489,6 +1012,28 @@ impl Writeable for NetworkGraph {
 			node_id.write(writer)?;
 			node_info.write(writer)?;
 		}
+		// Trailing fields of each channel, which graphs written before them don't have
+		(self.channels.len() as u64).write(writer)?;
+		for (ref chan_id, ref chan_info) in self.channels.iter() {
+			(*chan_id).write(writer)?;
+			chan_info.funding_redeemscript.write(writer)?;
+			chan_info.announcement_received_time.write(writer)?;
+		}
+		// Change sequence numbers for snapshot deltas, which graphs written before them don't have
+		self.last_change_sequence.write(writer)?;
+		self.removed_channels_pruned_through.write(writer)?;
+		(self.channel_change_sequences.len() as u64).write(writer)?;
+		for (chan_id, sequences) in self.channel_change_sequences.iter() {
+			chan_id.write(writer)?;
+			sequences.channel.write(writer)?;
+			sequences.one_to_two.write(writer)?;
+			sequences.two_to_one.write(writer)?;
+		}
+		(self.removed_channels.len() as u64).write(writer)?;
+		for &(sequence, chan_id) in self.removed_channels.iter() {
+			sequence.write(writer)?;
+			chan_id.write(writer)?;
+		}
 		Ok(())
 	}
 }
@@ -499,7 +1044,7 @@ impl Readable for NetworkGraph {
 		let mut channels = BTreeMap::new();
 		for _ in 0..channels_count {
 			let chan_id: u64 = Readable::read(reader)?;
-			let chan_info = Readable::read(reader)?;
+			let chan_info: ChannelInfo = Readable::read(reader)?;
 			channels.insert(chan_id, chan_info);
 		}
 		let nodes_count: u64 = Readable::read(reader)?;
@@ -509,9 +1054,64 @@ impl Readable for NetworkGraph {
 			let node_info = Readable::read(reader)?;
 			nodes.insert(node_id, node_info);
 		}
+		// Graphs written before the trailing channel fields end here, in which case channels keep
+		// the funding_redeemscript derived from their announcement and are considered received
+		// now.
+		let trailing_channels_count: u64 = match Readable::read(reader) {
+			Ok(count) => count,
+			Err(DecodeError::ShortRead) => 0,
+			Err(e) => return Err(e),
+		};
+		for _ in 0..trailing_channels_count {
+			let chan_id: u64 = Readable::read(reader)?;
+			let chan_info = channels.get_mut(&chan_id).ok_or(DecodeError::InvalidValue)?;
+			chan_info.funding_redeemscript = Readable::read(reader)?;
+			chan_info.announcement_received_time = Readable::read(reader)?;
+		}
+		// Graphs written before change sequence numbers end here, in which case every channel is
+		// considered unchanged since sequence number 0.
+		let mut last_change_sequence = 0;
+		let mut removed_channels_pruned_through = 0;
+		let mut channel_change_sequences = BTreeMap::new();
+		let mut removed_channels = VecDeque::new();
+		match Readable::read(reader) {
+			Ok(sequence) => {
+				last_change_sequence = sequence;
+				removed_channels_pruned_through = Readable::read(reader)?;
+				let sequences_count: u64 = Readable::read(reader)?;
+				for _ in 0..sequences_count {
+					let chan_id: u64 = Readable::read(reader)?;
+					if !channels.contains_key(&chan_id) {
+						return Err(DecodeError::InvalidValue);
+					}
+					channel_change_sequences.insert(chan_id, ChannelChangeSequences {
+						channel: Readable::read(reader)?,
+						one_to_two: Readable::read(reader)?,
+						two_to_one: Readable::read(reader)?,
+					});
+				}
+				let removed_count: u64 = Readable::read(reader)?;
+				for _ in 0..removed_count {
+					let sequence: u64 = Readable::read(reader)?;
+					let chan_id: u64 = Readable::read(reader)?;
+					removed_channels.push_back((sequence, chan_id));
+				}
+			},
+			Err(DecodeError::ShortRead) => {},
+			Err(e) => return Err(e),
+		}
+		let channels_by_funding_redeemscript = channels.iter()
+			.filter(|&(_, chan_info): &(&u64, &ChannelInfo)| !chan_info.funding_redeemscript.is_empty())
+			.map(|(chan_id, chan_info): (&u64, &ChannelInfo)| (chan_info.funding_redeemscript.clone(), *chan_id))
+			.collect();
 		Ok(NetworkGraph {
 			channels,
 			nodes,
+			channels_by_funding_redeemscript,
+			last_change_sequence,
+			channel_change_sequences,
+			removed_channels,
+			removed_channels_pruned_through,
 		})
 	}
 }
@@ -559,6 +1159,11 @@ impl NetworkGraph {
 		Self {
 			channels: BTreeMap::new(),
 			nodes: BTreeMap::new(),
+			channels_by_funding_redeemscript: HashMap::new(),
+			last_change_sequence: 0,
+			channel_change_sequences: BTreeMap::new(),
+			removed_channels: VecDeque::new(),
+			removed_channels_pruned_through: 0,
 		}
 	}
 
@@ -618,11 +1223,31 @@ impl NetworkGraph {
 				node_two: msg.contents.node_id_2.clone(),
 				two_to_one: None,
 				capacity_sats: utxo_value,
+				funding_redeemscript: make_funding_redeemscript(&msg.contents.bitcoin_key_1, &msg.contents.bitcoin_key_2),
+				announcement_received_time: unix_time_now(),
 				announcement_message: if should_relay { Some(msg.clone()) } else { None },
 			};
+		let funding_redeemscript = chan_info.funding_redeemscript.clone();
 
 		match self.channels.entry(msg.contents.short_channel_id) {
 			BtreeEntry::Occupied(mut entry) => {
+				let is_from_snapshot = entry.get().announcement_message.is_none() &&
+					(entry.get().funding_redeemscript.is_empty() || entry.get().funding_redeemscript == funding_redeemscript);
+				if is_from_snapshot && entry.get().node_one == msg.contents.node_id_1 && entry.get().node_two == msg.contents.node_id_2 {
+					// We learned of the channel from a snapshot, which carries neither the
+					// announcement itself nor, possibly, the bitcoin keys. Fill them in, keeping the
+					// updates we have.
+					let existing = entry.get_mut();
+					existing.funding_redeemscript = chan_info.funding_redeemscript;
+					existing.announcement_message = chan_info.announcement_message;
+					if utxo_value.is_some() {
+						existing.capacity_sats = utxo_value;
+					}
+					self.channels_by_funding_redeemscript.insert(funding_redeemscript, msg.contents.short_channel_id);
+					self.last_change_sequence += 1;
+					self.channel_change_sequences.entry(msg.contents.short_channel_id).or_default().channel = self.last_change_sequence;
+					return Ok(should_relay);
+				}
 				//TODO: because asking the blockchain if short_channel_id is valid is only optional
 				//in the blockchain API, we need to handle it smartly here, though it's unclear
 				//exactly how...
@@ -636,6 +1261,9 @@ impl NetworkGraph {
 					//    get reorg'd out.
 					// c) it's unclear how to do so without exposing ourselves to massive DoS risk.
 					Self::remove_channel_in_nodes(&mut self.nodes, &entry.get(), msg.contents.short_channel_id);
+					if self.channels_by_funding_redeemscript.get(&entry.get().funding_redeemscript) == Some(&msg.contents.short_channel_id) {
+						self.channels_by_funding_redeemscript.remove(&entry.get().funding_redeemscript);
+					}
 					*entry.get_mut() = chan_info;
 				} else {
 					return Err(LightningError{err: "Already have knowledge of channel".to_owned(), action: ErrorAction::IgnoreError})
@@ -645,49 +1273,419 @@ impl NetworkGraph {
 				entry.insert(chan_info);
 			}
 		};
+		self.channels_by_funding_redeemscript.insert(funding_redeemscript, msg.contents.short_channel_id);
+		let sequence = self.next_change_sequence();
+		self.channel_change_sequences.insert(msg.contents.short_channel_id, ChannelChangeSequences { channel: sequence, one_to_two: 0, two_to_one: 0 });
 
-		macro_rules! add_channel_to_node {
-			( $node_id: expr ) => {
-				match self.nodes.entry($node_id) {
-					BtreeEntry::Occupied(node_entry) => {
-						node_entry.into_mut().channels.push(msg.contents.short_channel_id);
-					},
-					BtreeEntry::Vacant(node_entry) => {
-						node_entry.insert(NodeInfo {
-							channels: vec!(msg.contents.short_channel_id),
-							lowest_inbound_channel_fees: None,
-							announcement_info: None,
-						});
-					}
-				}
-			};
-		}
-
-		add_channel_to_node!(msg.contents.node_id_1);
-		add_channel_to_node!(msg.contents.node_id_2);
+		Self::add_channel_to_node(&mut self.nodes, msg.contents.node_id_1, msg.contents.short_channel_id);
+		Self::add_channel_to_node(&mut self.nodes, msg.contents.node_id_2, msg.contents.short_channel_id);
 
 		Ok(should_relay)
 	}
 
+	fn add_channel_to_node(nodes: &mut BTreeMap<PublicKey, NodeInfo>, node_id: PublicKey, short_channel_id: u64) {
+		match nodes.entry(node_id) {
+			BtreeEntry::Occupied(node_entry) => {
+				node_entry.into_mut().channels.push(short_channel_id);
+			},
+			BtreeEntry::Vacant(node_entry) => {
+				node_entry.insert(NodeInfo {
+					channels: vec!(short_channel_id),
+					lowest_inbound_channel_fees: None,
+					announcement_info: None,
+				});
+			}
+		}
+	}
+
 	/// Close a channel if a corresponding HTLC fail was sent.
 	/// If permanent, removes a channel from the local storage.
 	/// May cause the removal of nodes too, if this was their last channel.
 	/// If not permanent, makes channels unavailable for routing.
 	pub fn close_channel_from_update(&mut self, short_channel_id: u64, is_permanent: bool) {
 		if is_permanent {
-			if let Some(chan) = self.channels.remove(&short_channel_id) {
-				Self::remove_channel_in_nodes(&mut self.nodes, &chan, short_channel_id);
-			}
+			self.remove_channel(short_channel_id);
 		} else {
 			if let Some(chan) = self.channels.get_mut(&short_channel_id) {
+				self.last_change_sequence += 1;
+				let sequences = self.channel_change_sequences.entry(short_channel_id).or_default();
 				if let Some(one_to_two) = chan.one_to_two.as_mut() {
 					one_to_two.enabled = false;
+					sequences.one_to_two = self.last_change_sequence;
 				}
 				if let Some(two_to_one) = chan.two_to_one.as_mut() {
 					two_to_one.enabled = false;
+					sequences.two_to_one = self.last_change_sequence;
+				}
+			}
+		}
+	}
+
+	/// Removes all channels whose channel_updates are at least STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS
+	/// older than the given unix time, along with any nodes left without channels. Returns the
+	/// short_channel_ids of the removed channels.
+	///
+	/// Channels for which we have not seen any channel_update are removed once we learned of them
+	/// at least STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS ago.
+	pub fn remove_stale_channels_with_time(&mut self, current_time_unix: u64) -> Vec<u64> {
+		let min_time_unix = current_time_unix.saturating_sub(STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS);
+		let stale_channels: Vec<u64> = self.channels.iter().filter(|&(_, chan)| {
+			let is_stale = |direction: &Option<DirectionalChannelInfo>| match direction {
+				&Some(ref info) => (info.last_update as u64) < min_time_unix,
+				&None => true,
+			};
+			if chan.one_to_two.is_none() && chan.two_to_one.is_none() {
+				chan.announcement_received_time < min_time_unix
+			} else {
+				is_stale(&chan.one_to_two) && is_stale(&chan.two_to_one)
+			}
+		}).map(|(short_channel_id, _)| *short_channel_id).collect();
+		for short_channel_id in stale_channels.iter() {
+			self.remove_channel(*short_channel_id);
+		}
+		stale_channels
+	}
+
+	/// Removes all channels whose funding output is spent by one of the given transactions, along
+	/// with any nodes left without channels. Returns the short_channel_ids of the removed
+	/// channels.
+	pub fn remove_spent_channels(&mut self, txdata: &TransactionData) -> Vec<u64> {
+		let mut spent_channels = Vec::new();
+		for &(_, tx) in txdata.iter() {
+			for input in tx.input.iter() {
+				// Any spend of a P2WSH output carries its witness script last
+				let witness_script = match input.witness.last() {
+					Some(witness_script) => Script::from(witness_script.clone()),
+					None => continue,
+				};
+				if let Some(short_channel_id) = self.channels_by_funding_redeemscript.get(&witness_script).cloned() {
+					self.remove_channel(short_channel_id);
+					spent_channels.push(short_channel_id);
+				}
+			}
+		}
+		spent_channels
+	}
+
+	fn remove_channel(&mut self, short_channel_id: u64) {
+		if let Some(chan) = self.channels.remove(&short_channel_id) {
+			if self.channels_by_funding_redeemscript.get(&chan.funding_redeemscript) == Some(&short_channel_id) {
+				self.channels_by_funding_redeemscript.remove(&chan.funding_redeemscript);
+			}
+			Self::remove_channel_in_nodes(&mut self.nodes, &chan, short_channel_id);
+			self.channel_change_sequences.remove(&short_channel_id);
+			let sequence = self.next_change_sequence();
+			self.removed_channels.push_back((sequence, short_channel_id));
+			if self.removed_channels.len() > MAX_REMOVED_CHANNEL_RECORDS {
+				let (pruned_sequence, _) = self.removed_channels.pop_front().unwrap();
+				self.removed_channels_pruned_through = pruned_sequence;
+			}
+		}
+	}
+
+	fn next_change_sequence(&mut self) -> u64 {
+		self.last_change_sequence += 1;
+		self.last_change_sequence
+	}
+
+	/// Writes a compact snapshot of the graph, suitable for a client to bootstrap its own graph
+	/// from using apply_snapshot without downloading and verifying the full gossip.
+	///
+	/// Signatures and node announcements are dropped, node ids are deduplicated into a table
+	/// referenced by index, and short_channel_ids, fees and timestamps are varint-encoded. The
+	/// channels' bitcoin keys are kept so that clients notice their funding outputs being spent.
+	///
+	/// If since_sequence is non-zero, only a delta is written, containing the channels and
+	/// channel_updates we learned of after the graph's change sequence number since_sequence, as
+	/// well as the channels removed since. Clients should pass the value returned by their last
+	/// apply_snapshot to fetch only what changed since. If we no longer remember every channel
+	/// removed since since_sequence, a full snapshot is written instead, and the channels the
+	/// client misses the removal of are eventually pruned as stale.
+	pub fn write_snapshot<W: Writer>(&self, chain_hash: &BlockHash, since_sequence: u64, writer: &mut W) -> Result<(), io::Error> {
+		let since_sequence = if since_sequence < self.removed_channels_pruned_through || since_sequence > self.last_change_sequence {
+			0
+		} else { since_sequence };
+		let no_sequences = ChannelChangeSequences::default();
+		let sequences_of = |short_channel_id: &u64| self.channel_change_sequences.get(short_channel_id).unwrap_or(&no_sequences);
+		let is_included = |direction: &Option<DirectionalChannelInfo>, sequence: u64| {
+			direction.is_some() && (since_sequence == 0 || sequence > since_sequence)
+		};
+		let channels: Vec<(&u64, &ChannelInfo)> = self.channels.iter().filter(|&(short_channel_id, _)| {
+			let sequences = sequences_of(short_channel_id);
+			since_sequence == 0 || sequences.channel > since_sequence ||
+				sequences.one_to_two > since_sequence || sequences.two_to_one > since_sequence
+		}).collect();
+
+		let mut updates = Vec::new();
+		for &(short_channel_id, chan) in channels.iter() {
+			let sequences = sequences_of(short_channel_id);
+			if is_included(&chan.one_to_two, sequences.one_to_two) {
+				updates.push((*short_channel_id, 0u8, chan.one_to_two.as_ref().unwrap()));
+			}
+			if is_included(&chan.two_to_one, sequences.two_to_one) {
+				updates.push((*short_channel_id, 1u8, chan.two_to_one.as_ref().unwrap()));
+			}
+		}
+		let snapshot_timestamp = updates.iter().map(|&(_, _, info)| info.last_update).max().unwrap_or(0);
+
+		// A channel may have been removed and learned of again since, in which case it is listed
+		// among the channels instead.
+		let mut removed_channels: Vec<u64> = if since_sequence == 0 { Vec::new() } else {
+			self.removed_channels.iter()
+				.filter(|&&(sequence, short_channel_id)| sequence > since_sequence && !self.channels.contains_key(&short_channel_id))
+				.map(|&(_, short_channel_id)| short_channel_id).collect()
+		};
+		removed_channels.sort();
+		removed_channels.dedup();
+
+		let mut node_ids: Vec<&PublicKey> = channels.iter().flat_map(|&(_, chan)| vec![&chan.node_one, &chan.node_two]).collect();
+		node_ids.sort();
+		node_ids.dedup();
+
+		writer.write_all(&SNAPSHOT_MAGIC)?;
+		SNAPSHOT_VERSION.write(writer)?;
+		chain_hash.write(writer)?;
+		since_sequence.write(writer)?;
+		self.last_change_sequence.write(writer)?;
+		snapshot_timestamp.write(writer)?;
+
+		BigSize(node_ids.len() as u64).write(writer)?;
+		for node_id in node_ids.iter() {
+			node_id.write(writer)?;
+		}
+
+		// node_ids is sorted, so we can find each node's index with a binary search
+		let node_index = |node_id: &PublicKey| node_ids.binary_search(&node_id).unwrap() as u64;
+		BigSize(channels.len() as u64).write(writer)?;
+		let mut prev_scid = 0;
+		for &(short_channel_id, chan) in channels.iter() {
+			BigSize(short_channel_id - prev_scid).write(writer)?;
+			prev_scid = *short_channel_id;
+			chan.features.write(writer)?;
+			BigSize(node_index(&chan.node_one)).write(writer)?;
+			BigSize(node_index(&chan.node_two)).write(writer)?;
+			// The funding redeemscript is OP_2 <key> <key> OP_2 OP_CHECKMULTISIG, each key being
+			// pushed with a single-byte length prefix.
+			let funding_keys = if chan.funding_redeemscript.len() == 71 {
+				Some((&chan.funding_redeemscript[2..35], &chan.funding_redeemscript[36..69]))
+			} else { None };
+			let mut flags = 0u8;
+			if chan.capacity_sats.is_some() { flags |= 1; }
+			if funding_keys.is_some() { flags |= 1 << 1; }
+			flags.write(writer)?;
+			if let Some(capacity_sats) = chan.capacity_sats {
+				BigSize(capacity_sats).write(writer)?;
+			}
+			if let Some((bitcoin_key_1, bitcoin_key_2)) = funding_keys {
+				writer.write_all(bitcoin_key_1)?;
+				writer.write_all(bitcoin_key_2)?;
+			}
+		}
+
+		BigSize(updates.len() as u64).write(writer)?;
+		let mut prev_scid = 0;
+		for &(short_channel_id, direction, info) in updates.iter() {
+			BigSize(short_channel_id - prev_scid).write(writer)?;
+			prev_scid = short_channel_id;
+			let mut flags = direction;
+			if !info.enabled { flags |= 1 << 1; }
+			if info.htlc_maximum_msat.is_some() { flags |= 1 << 2; }
+			flags.write(writer)?;
+			BigSize((snapshot_timestamp - info.last_update) as u64).write(writer)?;
+			BigSize(info.cltv_expiry_delta as u64).write(writer)?;
+			BigSize(info.htlc_minimum_msat).write(writer)?;
+			BigSize(info.fees.base_msat as u64).write(writer)?;
+			BigSize(info.fees.proportional_millionths as u64).write(writer)?;
+			if let Some(htlc_maximum_msat) = info.htlc_maximum_msat {
+				BigSize(htlc_maximum_msat).write(writer)?;
+			}
+		}
+
+		BigSize(removed_channels.len() as u64).write(writer)?;
+		let mut prev_scid = 0;
+		for short_channel_id in removed_channels.iter() {
+			BigSize(short_channel_id - prev_scid).write(writer)?;
+			prev_scid = *short_channel_id;
+		}
+		Ok(())
+	}
+
+	/// Applies a snapshot written by write_snapshot on top of this graph, returning the change
+	/// sequence number to pass as since_sequence when requesting the next delta.
+	///
+	/// Channels we do not yet know of are added, channels the snapshot lists as removed are
+	/// removed, and channel_updates are applied only if they are newer than the ones we have, so
+	/// applying a snapshot never replaces fresher gossip. As snapshots carry no signatures, they
+	/// must come from a trusted source. Channels added from a snapshot are not relayed to peers
+	/// until we receive their signed announcement, and are pruned like any other channel once
+	/// their updates go stale or their funding output is spent.
+	///
+	/// The whole snapshot is validated before anything is applied, so on error the graph is left
+	/// unchanged.
+	pub fn apply_snapshot<R: io::Read>(&mut self, chain_hash: &BlockHash, reader: &mut R) -> Result<u64, DecodeError> {
+		let mut magic = [0u8; 3];
+		reader.read_exact(&mut magic)?;
+		if magic != SNAPSHOT_MAGIC {
+			return Err(DecodeError::InvalidValue);
+		}
+		let version: u8 = Readable::read(reader)?;
+		if version != SNAPSHOT_VERSION {
+			return Err(DecodeError::UnknownVersion);
+		}
+		let snapshot_chain_hash: BlockHash = Readable::read(reader)?;
+		if snapshot_chain_hash != *chain_hash {
+			return Err(DecodeError::InvalidValue);
+		}
+		let since_sequence: u64 = Readable::read(reader)?;
+		let snapshot_sequence: u64 = Readable::read(reader)?;
+		if since_sequence > snapshot_sequence {
+			return Err(DecodeError::InvalidValue);
+		}
+		let snapshot_timestamp: u32 = Readable::read(reader)?;
+
+		// Counts come from the snapshot, so we let the vectors grow as we read rather than
+		// allocating up-front.
+		let node_count: BigSize = Readable::read(reader)?;
+		let mut node_ids: Vec<PublicKey> = Vec::new();
+		for _ in 0..node_count.0 {
+			node_ids.push(Readable::read(reader)?);
+		}
+		let read_node = |reader: &mut R| -> Result<PublicKey, DecodeError> {
+			let index: BigSize = Readable::read(reader)?;
+			node_ids.get(index.0 as usize).cloned().ok_or(DecodeError::InvalidValue)
+		};
+		macro_rules! read_scid {
+			($prev_scid: expr) => { {
+				let delta: BigSize = Readable::read(reader)?;
+				$prev_scid = $prev_scid.checked_add(delta.0).ok_or(DecodeError::InvalidValue)?;
+				$prev_scid
+			} }
+		}
+
+		let channel_count: BigSize = Readable::read(reader)?;
+		let mut channels = Vec::new();
+		let mut prev_scid = 0u64;
+		for _ in 0..channel_count.0 {
+			let short_channel_id = read_scid!(prev_scid);
+			let features: ChannelFeatures = Readable::read(reader)?;
+			let node_one = read_node(reader)?;
+			let node_two = read_node(reader)?;
+			if node_one == node_two {
+				return Err(DecodeError::InvalidValue);
+			}
+			let flags: u8 = Readable::read(reader)?;
+			let capacity_sats = if flags & 1 == 1 {
+				let capacity_sats: BigSize = Readable::read(reader)?;
+				if capacity_sats.0 > MAX_VALUE_MSAT / 1000 {
+					return Err(DecodeError::InvalidValue);
+				}
+				Some(capacity_sats.0)
+			} else { None };
+			let funding_redeemscript = if flags & (1 << 1) != 0 {
+				let bitcoin_key_1: PublicKey = Readable::read(reader)?;
+				let bitcoin_key_2: PublicKey = Readable::read(reader)?;
+				if bitcoin_key_1 == bitcoin_key_2 {
+					return Err(DecodeError::InvalidValue);
+				}
+				make_funding_redeemscript(&bitcoin_key_1, &bitcoin_key_2)
+			} else { Script::new() };
+			channels.push((short_channel_id, ChannelInfo {
+				features,
+				node_one,
+				one_to_two: None,
+				node_two,
+				two_to_one: None,
+				capacity_sats,
+				funding_redeemscript,
+				announcement_received_time: unix_time_now(),
+				announcement_message: None,
+			}));
+		}
+
+		let read_u32 = |reader: &mut R| -> Result<u32, DecodeError> {
+			let value: BigSize = Readable::read(reader)?;
+			if value.0 > u32::max_value() as u64 {
+				return Err(DecodeError::InvalidValue);
+			}
+			Ok(value.0 as u32)
+		};
+		let update_count: BigSize = Readable::read(reader)?;
+		let mut updates = Vec::new();
+		let mut prev_scid = 0u64;
+		for _ in 0..update_count.0 {
+			let short_channel_id = read_scid!(prev_scid);
+			let flags: u8 = Readable::read(reader)?;
+			let timestamp_offset = read_u32(reader)?;
+			if timestamp_offset > snapshot_timestamp {
+				return Err(DecodeError::InvalidValue);
+			}
+			let cltv_expiry_delta = read_u32(reader)?;
+			if cltv_expiry_delta > u16::max_value() as u32 {
+				return Err(DecodeError::InvalidValue);
+			}
+			let htlc_minimum_msat: BigSize = Readable::read(reader)?;
+			let fee_base_msat = read_u32(reader)?;
+			let fee_proportional_millionths = read_u32(reader)?;
+			let htlc_maximum_msat = if flags & (1 << 2) != 0 {
+				let htlc_maximum_msat: BigSize = Readable::read(reader)?;
+				if htlc_maximum_msat.0 > MAX_VALUE_MSAT {
+					return Err(DecodeError::InvalidValue);
 				}
+				OptionalField::Present(htlc_maximum_msat.0)
+			} else { OptionalField::Absent };
+			updates.push(msgs::UnsignedChannelUpdate {
+				chain_hash: *chain_hash,
+				short_channel_id,
+				timestamp: snapshot_timestamp - timestamp_offset,
+				flags: flags & 0b11,
+				cltv_expiry_delta: cltv_expiry_delta as u16,
+				htlc_minimum_msat: htlc_minimum_msat.0,
+				htlc_maximum_msat,
+				fee_base_msat,
+				fee_proportional_millionths,
+				excess_data: Vec::new(),
+			});
+		}
+
+		let removed_count: BigSize = Readable::read(reader)?;
+		let mut removed_channels = Vec::new();
+		let mut prev_scid = 0u64;
+		for _ in 0..removed_count.0 {
+			removed_channels.push(read_scid!(prev_scid));
+		}
+
+		for short_channel_id in removed_channels.iter() {
+			self.remove_channel(*short_channel_id);
+		}
+		for (short_channel_id, chan_info) in channels.drain(..) {
+			match self.channels.entry(short_channel_id) {
+				BtreeEntry::Vacant(entry) => {
+					let (node_one, node_two) = (chan_info.node_one, chan_info.node_two);
+					if !chan_info.funding_redeemscript.is_empty() {
+						self.channels_by_funding_redeemscript.insert(chan_info.funding_redeemscript.clone(), short_channel_id);
+					}
+					entry.insert(chan_info);
+					Self::add_channel_to_node(&mut self.nodes, node_one, short_channel_id);
+					Self::add_channel_to_node(&mut self.nodes, node_two, short_channel_id);
+				},
+				BtreeEntry::Occupied(mut entry) => {
+					// Fill in the funding keys of a channel an older snapshot didn't have them for
+					let existing = entry.get_mut();
+					if existing.funding_redeemscript.is_empty() && !chan_info.funding_redeemscript.is_empty() &&
+							existing.node_one == chan_info.node_one && existing.node_two == chan_info.node_two {
+						self.channels_by_funding_redeemscript.insert(chan_info.funding_redeemscript.clone(), short_channel_id);
+						existing.funding_redeemscript = chan_info.funding_redeemscript;
+					} else { continue; }
+				},
 			}
+			self.last_change_sequence += 1;
+			self.channel_change_sequences.entry(short_channel_id).or_default().channel = self.last_change_sequence;
 		}
+		for update in updates.iter() {
+			// Updates for unknown channels or older than ones we already have are simply skipped
+			let _ = self.update_channel_intern(update, None, None);
+		}
+		Ok(snapshot_sequence)
 	}
 
 	fn fail_node(&mut self, _node_id: &PublicKey, is_permanent: bool) {
@@ -701,14 +1699,21 @@ impl NetworkGraph {
 	/// For an already known (from announcement) channel, update info about one of the directions of a channel.
 	/// Announcement signatures are checked here only if Secp256k1 object is provided.
 	fn update_channel(&mut self, msg: &msgs::ChannelUpdate, secp_ctx: Option<&Secp256k1<secp256k1::VerifyOnly>>) -> Result<bool, LightningError> {
+		self.update_channel_intern(&msg.contents, Some(msg), secp_ctx.map(|secp_ctx| (&msg.signature, secp_ctx)))
+	}
+
+	/// Updates info about one direction of a known channel. The full message is only stored (for
+	/// relaying to peers) if provided, and its signature is checked if a Secp256k1 object is
+	/// provided along with it.
+	fn update_channel_intern(&mut self, msg: &msgs::UnsignedChannelUpdate, full_msg: Option<&msgs::ChannelUpdate>, sig_info: Option<(&secp256k1::Signature, &Secp256k1<secp256k1::VerifyOnly>)>) -> Result<bool, LightningError> {
 		let dest_node_id;
-		let chan_enabled = msg.contents.flags & (1 << 1) != (1 << 1);
+		let chan_enabled = msg.flags & (1 << 1) != (1 << 1);
 		let chan_was_enabled;
 
-		match self.channels.get_mut(&msg.contents.short_channel_id) {
+		match self.channels.get_mut(&msg.short_channel_id) {
 			None => return Err(LightningError{err: "Couldn't find channel for update".to_owned(), action: ErrorAction::IgnoreError}),
 			Some(channel) => {
-				if let OptionalField::Present(htlc_maximum_msat) = msg.contents.htlc_maximum_msat {
+				if let OptionalField::Present(htlc_maximum_msat) = msg.htlc_maximum_msat {
 					if htlc_maximum_msat > MAX_VALUE_MSAT {
 						return Err(LightningError{err: "htlc_maximum_msat is larger than maximum possible msats".to_owned(), action: ErrorAction::IgnoreError});
 					}
@@ -724,7 +1729,7 @@ impl NetworkGraph {
 				macro_rules! maybe_update_channel_info {
 					( $target: expr, $src_node: expr) => {
 						if let Some(existing_chan_info) = $target.as_ref() {
-							if existing_chan_info.last_update >= msg.contents.timestamp {
+							if existing_chan_info.last_update >= msg.timestamp {
 								return Err(LightningError{err: "Update older than last processed update".to_owned(), action: ErrorAction::IgnoreError});
 							}
 							chan_was_enabled = existing_chan_info.enabled;
@@ -732,21 +1737,21 @@ impl NetworkGraph {
 							chan_was_enabled = false;
 						}
 
-						let last_update_message = if msg.contents.excess_data.is_empty() {
-							Some(msg.clone())
+						let last_update_message = if msg.excess_data.is_empty() {
+							full_msg.cloned()
 						} else {
 							None
 						};
 
 						let updated_channel_dir_info = DirectionalChannelInfo {
 							enabled: chan_enabled,
-							last_update: msg.contents.timestamp,
-							cltv_expiry_delta: msg.contents.cltv_expiry_delta,
-							htlc_minimum_msat: msg.contents.htlc_minimum_msat,
-							htlc_maximum_msat: if let OptionalField::Present(max_value) = msg.contents.htlc_maximum_msat { Some(max_value) } else { None },
+							last_update: msg.timestamp,
+							cltv_expiry_delta: msg.cltv_expiry_delta,
+							htlc_minimum_msat: msg.htlc_minimum_msat,
+							htlc_maximum_msat: if let OptionalField::Present(max_value) = msg.htlc_maximum_msat { Some(max_value) } else { None },
 							fees: RoutingFees {
-								base_msat: msg.contents.fee_base_msat,
-								proportional_millionths: msg.contents.fee_proportional_millionths,
+								base_msat: msg.fee_base_msat,
+								proportional_millionths: msg.fee_proportional_millionths,
 							},
 							last_update_message
 						};
@@ -754,27 +1759,35 @@ impl NetworkGraph {
 					}
 				}
 
-				let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.contents.encode()[..])[..]);
-				if msg.contents.flags & 1 == 1 {
+				if msg.flags & 1 == 1 {
 					dest_node_id = channel.node_one.clone();
-					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_two);
+					if let Some((sig, sig_verifier)) = sig_info {
+						let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.encode()[..])[..]);
+						secp_verify_sig!(sig_verifier, &msg_hash, sig, &channel.node_two);
 					}
 					maybe_update_channel_info!(channel.two_to_one, channel.node_two);
 				} else {
 					dest_node_id = channel.node_two.clone();
-					if let Some(sig_verifier) = secp_ctx {
-						secp_verify_sig!(sig_verifier, &msg_hash, &msg.signature, &channel.node_one);
+					if let Some((sig, sig_verifier)) = sig_info {
+						let msg_hash = hash_to_message!(&Sha256dHash::hash(&msg.encode()[..])[..]);
+						secp_verify_sig!(sig_verifier, &msg_hash, sig, &channel.node_one);
 					}
 					maybe_update_channel_info!(channel.one_to_two, channel.node_one);
 				}
 			}
 		}
+		let sequence = self.next_change_sequence();
+		let sequences = self.channel_change_sequences.entry(msg.short_channel_id).or_default();
+		if msg.flags & 1 == 1 {
+			sequences.two_to_one = sequence;
+		} else {
+			sequences.one_to_two = sequence;
+		}
 
 		if chan_enabled {
 			let node = self.nodes.get_mut(&dest_node_id).unwrap();
-			let mut base_msat = msg.contents.fee_base_msat;
-			let mut proportional_millionths = msg.contents.fee_proportional_millionths;
+			let mut base_msat = msg.fee_base_msat;
+			let mut proportional_millionths = msg.fee_proportional_millionths;
 			if let Some(fees) = node.lowest_inbound_channel_fees {
 				base_msat = cmp::min(base_msat, fees.base_msat);
 				proportional_millionths = cmp::min(proportional_millionths, fees.proportional_millionths);
@@ -808,7 +1821,7 @@ impl NetworkGraph {
 			node.lowest_inbound_channel_fees = lowest_inbound_channel_fees;
 		}
 
-		Ok(msg.contents.excess_data.is_empty())
+		Ok(msg.excess_data.is_empty())
 	}
 
 	fn remove_channel_in_nodes(nodes: &mut BTreeMap<PublicKey, NodeInfo>, chan: &ChannelInfo, short_channel_id: u64) {
@@ -836,10 +1849,15 @@ impl NetworkGraph {
 mod tests {
 	use chain;
 	use ln::features::{ChannelFeatures, NodeFeatures};
-	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph};
-	use ln::msgs::{OptionalField, RoutingMessageHandler, UnsignedNodeAnnouncement, NodeAnnouncement,
+	use routing::network_graph::{NetGraphMsgHandler, NetworkGraph, MAX_SCIDS_PER_REPLY, MAX_SCIDS_PER_QUERY, MAX_QUEUED_SCIDS, ALL_QUERY_FLAGS,
+		STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS, SNAPSHOT_VERSION, crc32c, channel_update_checksum};
+	use ln::chan_utils::make_funding_redeemscript;
+	use ln::msgs::{DecodeError, OptionalField, RoutingMessageHandler, UnsignedNodeAnnouncement, NodeAnnouncement,
 		UnsignedChannelAnnouncement, ChannelAnnouncement, UnsignedChannelUpdate, ChannelUpdate, HTLCFailChannelUpdate,
-		MAX_VALUE_MSAT};
+		QueryChannelRange, ReplyChannelRange, QueryShortChannelIds, ReplyShortChannelIdsEnd, MAX_VALUE_MSAT,
+		GossipTimestampFilter, EncodingType, ChannelUpdateTimestamps, ChannelUpdateChecksums};
+	use ln::msgs;
+	use ln::peers::encryption::LN_MAX_MSG_LEN;
 	use util::test_utils;
 	use util::logger::Logger;
 	use util::ser::{Readable, Writeable};
@@ -848,8 +1866,8 @@ mod tests {
 	use bitcoin::hashes::Hash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
-	use bitcoin::blockdata::script::Builder;
-	use bitcoin::blockdata::transaction::TxOut;
+	use bitcoin::blockdata::script::{Builder, Script};
+	use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
 	use bitcoin::blockdata::opcodes;
 
 	use hex;
@@ -862,7 +1880,7 @@ mod tests {
 	fn create_net_graph_msg_handler() -> (Secp256k1<All>, NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>>) {
 		let secp_ctx = Secp256k1::new();
 		let logger = Arc::new(test_utils::TestLogger::new());
-		let net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		(secp_ctx, net_graph_msg_handler)
 	}
 
@@ -1023,7 +2041,7 @@ mod tests {
 		};
 
 		// Test if the UTXO lookups were not supported
-		let mut net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let mut net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		match net_graph_msg_handler.handle_channel_announcement(&valid_announcement) {
 			Ok(res) => assert!(res),
 			_ => panic!()
@@ -1047,7 +2065,7 @@ mod tests {
 		// Test if an associated transaction were not on-chain (or not confirmed).
 		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
 		*chain_source.utxo_ret.lock().unwrap() = Err(chain::AccessError::UnknownTx);
-		net_graph_msg_handler = NetGraphMsgHandler::new(Some(chain_source.clone()), Arc::clone(&logger));
+		net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(chain_source.clone()), Arc::clone(&logger));
 		unsigned_announcement.short_channel_id += 1;
 
 		msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
@@ -1171,7 +2189,7 @@ mod tests {
 		let secp_ctx = Secp256k1::new();
 		let logger: Arc<Logger> = Arc::new(test_utils::TestLogger::new());
 		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
-		let net_graph_msg_handler = NetGraphMsgHandler::new(Some(chain_source.clone()), Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(chain_source.clone()), Arc::clone(&logger));
 
 		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
 		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
@@ -1766,4 +2784,816 @@ mod tests {
 		network.write(&mut w).unwrap();
 		assert!(<NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap() == *network);
 	}
+
+	// Builds a signed channel announcement between two fixed nodes. The signatures only cover the
+	// given short_channel_id, so copies with other ids must be added to the graph unchecked.
+	fn get_channel_announcement(secp_ctx: &Secp256k1<All>, short_channel_id: u64) -> ChannelAnnouncement {
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let node_2_privkey = &SecretKey::from_slice(&[41; 32]).unwrap();
+		let node_1_btckey = &SecretKey::from_slice(&[40; 32]).unwrap();
+		let node_2_btckey = &SecretKey::from_slice(&[39; 32]).unwrap();
+		let unsigned_announcement = UnsignedChannelAnnouncement {
+			features: ChannelFeatures::known(),
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			node_id_1: PublicKey::from_secret_key(&secp_ctx, node_1_privkey),
+			node_id_2: PublicKey::from_secret_key(&secp_ctx, node_2_privkey),
+			bitcoin_key_1: PublicKey::from_secret_key(&secp_ctx, node_1_btckey),
+			bitcoin_key_2: PublicKey::from_secret_key(&secp_ctx, node_2_btckey),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_announcement.encode()[..])[..]);
+		ChannelAnnouncement {
+			node_signature_1: secp_ctx.sign(&msghash, node_1_privkey),
+			node_signature_2: secp_ctx.sign(&msghash, node_2_privkey),
+			bitcoin_signature_1: secp_ctx.sign(&msghash, node_1_btckey),
+			bitcoin_signature_2: secp_ctx.sign(&msghash, node_2_btckey),
+			contents: unsigned_announcement,
+		}
+	}
+
+	fn add_channels_unchecked(net_graph_msg_handler: &NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>>, template: &ChannelAnnouncement, short_channel_ids: &[u64]) {
+		let mut network_graph = net_graph_msg_handler.network_graph.write().unwrap();
+		for short_channel_id in short_channel_ids.iter() {
+			let mut announcement = template.clone();
+			announcement.contents.short_channel_id = *short_channel_id;
+			network_graph.update_channel_from_announcement(&announcement, None, None).unwrap();
+		}
+	}
+
+	fn scid(block: u64, tx_index: u64) -> u64 {
+		(block << 40) | (tx_index << 16)
+	}
+
+	fn get_channel_update(secp_ctx: &Secp256k1<All>, short_channel_id: u64, timestamp: u32, fee_base_msat: u32) -> ChannelUpdate {
+		// Signed by node_id_1 of the channels built by get_channel_announcement
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+		let unsigned_channel_update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags: 0,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000000,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat,
+			fee_proportional_millionths: 20,
+			excess_data: Vec::new()
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+		ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_channel_update
+		}
+	}
+
+	#[test]
+	fn channel_update_checksums() {
+		assert_eq!(crc32c(b"123456789"), 0xe3069283);
+
+		// Checksums ignore the timestamp (and signature), but not the rest of the update
+		let secp_ctx = Secp256k1::new();
+		let update = get_channel_update(&secp_ctx, scid(1, 0), 100, 10000);
+		assert_eq!(channel_update_checksum(&update), channel_update_checksum(&get_channel_update(&secp_ctx, scid(1, 0), 200, 10000)));
+		assert_ne!(channel_update_checksum(&update), channel_update_checksum(&get_channel_update(&secp_ctx, scid(1, 0), 100, 20000)));
+		let mut data = update.contents.encode();
+		data.drain(40..44);
+		assert_eq!(channel_update_checksum(&update), crc32c(&data));
+	}
+
+	#[test]
+	fn sync_routing_table_queries_full_sync_peers() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		let (filter, query) = net_graph_msg_handler.sync_routing_table(&node_id, true);
+		let filter = filter.unwrap();
+		assert_eq!(filter.chain_hash, chain_hash);
+		assert!(filter.first_timestamp > 0);
+		assert_eq!(filter.timestamp_range, 0xffff_ffff);
+		let query = query.unwrap();
+		assert_eq!(query.chain_hash, chain_hash);
+		assert_eq!(query.first_blocknum, 0);
+		assert_eq!(query.number_of_blocks, 0xffff_ffff);
+		assert_eq!(query.query_option, Some(msgs::QUERY_OPTION_TIMESTAMPS | msgs::QUERY_OPTION_CHECKSUMS));
+
+		let (filter, query) = net_graph_msg_handler.sync_routing_table(&node_id, false);
+		assert!(filter.is_some());
+		assert!(query.is_none());
+	}
+
+	#[test]
+	fn handling_query_channel_range() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		// An empty graph is answered with a single empty reply covering the whole range
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, query_option: None,
+		}).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].first_blocknum, 0);
+		assert_eq!(replies[0].number_of_blocks, 0xffff_ffff);
+		assert!(replies[0].full_information);
+		assert!(replies[0].short_channel_ids.is_empty());
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 0), scid(2, 1), scid(5, 0)]);
+
+		// Only channels confirmed within [2, 5) are returned
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 2, number_of_blocks: 3, query_option: None,
+		}).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].first_blocknum, 2);
+		assert_eq!(replies[0].number_of_blocks, 3);
+		assert_eq!(replies[0].short_channel_ids, vec![scid(2, 0), scid(2, 1)]);
+
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, query_option: None,
+		}).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].short_channel_ids, vec![scid(1, 0), scid(2, 0), scid(2, 1), scid(5, 0)]);
+
+		// Queries for another chain are answered without any information
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash: other_chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, query_option: None,
+		}).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].chain_hash, other_chain_hash);
+		assert!(!replies[0].full_information);
+		assert!(replies[0].short_channel_ids.is_empty());
+	}
+
+	#[test]
+	fn handling_query_channel_range_across_multiple_replies() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		// Put one more channel than fits in a reply in block 100, and one more in block 200.
+		let template = get_channel_announcement(&secp_ctx, 0);
+		let mut short_channel_ids: Vec<u64> = (0..MAX_SCIDS_PER_REPLY as u64 + 1).map(|idx| scid(100, idx)).collect();
+		short_channel_ids.push(scid(200, 0));
+		add_channels_unchecked(&net_graph_msg_handler, &template, &short_channel_ids);
+
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 10, number_of_blocks: 1000, query_option: None,
+		}).unwrap();
+		assert_eq!(replies.len(), 2);
+		assert_eq!(replies[0].first_blocknum, 10);
+		assert_eq!(replies[0].number_of_blocks, 91);
+		assert_eq!(replies[0].short_channel_ids.len(), MAX_SCIDS_PER_REPLY);
+		// The second reply starts at the block the first one had to split
+		assert_eq!(replies[1].first_blocknum, 100);
+		assert_eq!(replies[1].number_of_blocks, 910);
+		assert_eq!(replies[1].short_channel_ids, vec![scid(100, MAX_SCIDS_PER_REPLY as u64), scid(200, 0)]);
+	}
+
+	#[test]
+	fn handling_query_channel_range_with_timestamps_and_checksums_fits_in_messages() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		// Each channel takes up 24 bytes with both timestamps and checksums, so only about 2730
+		// of them fit in a single message.
+		let template = get_channel_announcement(&secp_ctx, 0);
+		let short_channel_ids: Vec<u64> = (0..6000).map(|idx| scid(100 + idx / 1000, idx as u64)).collect();
+		add_channels_unchecked(&net_graph_msg_handler, &template, &short_channel_ids);
+
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			query_option: Some(msgs::QUERY_OPTION_TIMESTAMPS | msgs::QUERY_OPTION_CHECKSUMS),
+		}).unwrap();
+		assert_eq!(replies.len(), 3);
+		let mut replied_scids = Vec::new();
+		for reply in replies.iter() {
+			// The encoded message plus its 2-byte type must fit in a single Lightning message
+			assert!(reply.encode().len() + 2 <= LN_MAX_MSG_LEN);
+			assert_eq!(reply.timestamps.as_ref().unwrap().len(), reply.short_channel_ids.len());
+			assert_eq!(reply.checksums.as_ref().unwrap().len(), reply.short_channel_ids.len());
+			replied_scids.extend_from_slice(&reply.short_channel_ids);
+		}
+		assert_eq!(replied_scids, short_channel_ids);
+
+		// With only timestamps, more channels fit in each reply
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			query_option: Some(msgs::QUERY_OPTION_TIMESTAMPS),
+		}).unwrap();
+		assert_eq!(replies.len(), 2);
+		for reply in replies.iter() {
+			assert!(reply.encode().len() + 2 <= LN_MAX_MSG_LEN);
+		}
+	}
+
+	#[test]
+	fn handling_reply_channel_range_queries_missing_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0)]);
+
+		// An end message we never asked for is rejected
+		assert!(net_graph_msg_handler.handle_reply_short_channel_ids_end(&node_id, &ReplyShortChannelIdsEnd {
+			chain_hash, full_information: true,
+		}).is_err());
+
+		// Replies to a query we never sent are rejected
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: true, short_channel_ids: vec![scid(2, 0)],
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).is_err());
+		assert!(net_graph_msg_handler.sync_routing_table(&node_id, true).1.is_some());
+
+		// Replies for another chain or without information are rejected
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash: genesis_block(Network::Bitcoin).header.block_hash(), first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: true, short_channel_ids: vec![scid(2, 0)],
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).is_err());
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: false, short_channel_ids: vec![scid(2, 0)],
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).is_err());
+
+		// We only query for channels we don't already know, in batches
+		let mut short_channel_ids = vec![scid(1, 0)];
+		short_channel_ids.extend((0..MAX_SCIDS_PER_QUERY as u64 + 1).map(|idx| scid(2, idx)));
+		let query = net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 3,
+			full_information: true, short_channel_ids,
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).unwrap().unwrap();
+		assert_eq!(query.chain_hash, chain_hash);
+		assert_eq!(query.short_channel_ids, (0..MAX_SCIDS_PER_QUERY as u64).map(|idx| scid(2, idx)).collect::<Vec<_>>());
+
+		// Further replies are queued until the outstanding query is answered
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 3, number_of_blocks: 0xffff_fffc,
+			full_information: true, short_channel_ids: vec![scid(3, 0)],
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).unwrap().is_none());
+
+		// Once the replies cover the whole range we queried, any more are rejected
+		assert!(net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: true, short_channel_ids: vec![scid(4, 0)],
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).is_err());
+
+		let query = net_graph_msg_handler.handle_reply_short_channel_ids_end(&node_id, &ReplyShortChannelIdsEnd {
+			chain_hash, full_information: true,
+		}).unwrap().unwrap();
+		assert_eq!(query.short_channel_ids, vec![scid(2, MAX_SCIDS_PER_QUERY as u64), scid(3, 0)]);
+
+		assert!(net_graph_msg_handler.handle_reply_short_channel_ids_end(&node_id, &ReplyShortChannelIdsEnd {
+			chain_hash, full_information: true,
+		}).unwrap().is_none());
+		assert!(net_graph_msg_handler.handle_reply_short_channel_ids_end(&node_id, &ReplyShortChannelIdsEnd {
+			chain_hash, full_information: true,
+		}).is_err());
+	}
+
+	#[test]
+	fn handling_reply_channel_range_caps_queued_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		net_graph_msg_handler.sync_routing_table(&node_id, true);
+		let short_channel_ids: Vec<u64> = (0..MAX_QUEUED_SCIDS as u64 + 100).map(|idx| scid(1, idx)).collect();
+		let query = net_graph_msg_handler.handle_reply_channel_range(&node_id, &ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: true, short_channel_ids,
+			encoding_type: EncodingType::Uncompressed, timestamps: None, checksums: None,
+		}).unwrap().unwrap();
+		assert_eq!(query.short_channel_ids.len(), MAX_SCIDS_PER_QUERY);
+		let pending_gossip_queries = net_graph_msg_handler.pending_gossip_queries.lock().unwrap();
+		assert_eq!(pending_gossip_queries.get(&node_id).unwrap().queued_scids.len(), MAX_QUEUED_SCIDS - MAX_SCIDS_PER_QUERY);
+	}
+
+	#[test]
+	fn handling_query_short_channel_ids() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 0)]);
+
+		let unsigned_channel_update = UnsignedChannelUpdate {
+			chain_hash,
+			short_channel_id: scid(1, 0),
+			timestamp: 100,
+			flags: 0,
+			cltv_expiry_delta: 144,
+			htlc_minimum_msat: 1000000,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: 10000,
+			fee_proportional_millionths: 20,
+			excess_data: Vec::new()
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_channel_update.encode()[..])[..]);
+		let channel_update = ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_channel_update
+		};
+		assert!(net_graph_msg_handler.handle_channel_update(&channel_update).unwrap());
+
+		let unsigned_node_announcement = UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp: 100,
+			node_id: template.contents.node_id_1,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		let node_announcement = NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_node_announcement
+		};
+		assert!(net_graph_msg_handler.handle_node_announcement(&node_announcement).unwrap());
+
+		// Unknown channels are skipped and each node announcement is sent once
+		let (channels, nodes, reply_end) = net_graph_msg_handler.handle_query_short_channel_ids(&node_id, &QueryShortChannelIds {
+			chain_hash, short_channel_ids: vec![scid(1, 0), scid(2, 0), scid(3, 0)],
+			encoding_type: EncodingType::Uncompressed, query_flags: None,
+		}).unwrap();
+		assert_eq!(channels.len(), 2);
+		assert_eq!(channels[0].0.as_ref().unwrap().contents.short_channel_id, scid(1, 0));
+		assert_eq!(channels[0].1, Some(channel_update));
+		assert_eq!(channels[0].2, None);
+		assert_eq!(channels[1].0.as_ref().unwrap().contents.short_channel_id, scid(2, 0));
+		assert_eq!(channels[1].1, None);
+		assert_eq!(nodes, vec![node_announcement]);
+		assert_eq!(reply_end.chain_hash, chain_hash);
+		assert!(reply_end.full_information);
+
+		// Queries for another chain are answered without any information
+		let other_chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		let (channels, nodes, reply_end) = net_graph_msg_handler.handle_query_short_channel_ids(&node_id, &QueryShortChannelIds {
+			chain_hash: other_chain_hash, short_channel_ids: vec![scid(1, 0)],
+			encoding_type: EncodingType::Uncompressed, query_flags: None,
+		}).unwrap();
+		assert!(channels.is_empty());
+		assert!(nodes.is_empty());
+		assert_eq!(reply_end.chain_hash, other_chain_hash);
+		assert!(!reply_end.full_information);
+	}
+
+	#[test]
+	fn handling_query_channel_range_with_timestamps_and_checksums() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 0)]);
+		let channel_update = get_channel_update(&secp_ctx, scid(1, 0), 100, 10000);
+		assert!(net_graph_msg_handler.handle_channel_update(&channel_update).unwrap());
+
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			query_option: Some(msgs::QUERY_OPTION_TIMESTAMPS | msgs::QUERY_OPTION_CHECKSUMS),
+		}).unwrap();
+		assert_eq!(replies.len(), 1);
+		assert_eq!(replies[0].short_channel_ids, vec![scid(1, 0), scid(2, 0)]);
+		assert_eq!(replies[0].timestamps, Some(vec![
+			ChannelUpdateTimestamps { timestamp_node_id_1: 100, timestamp_node_id_2: 0 },
+			ChannelUpdateTimestamps { timestamp_node_id_1: 0, timestamp_node_id_2: 0 },
+		]));
+		assert_eq!(replies[0].checksums, Some(vec![
+			ChannelUpdateChecksums { checksum_node_id_1: channel_update_checksum(&channel_update), checksum_node_id_2: 0 },
+			ChannelUpdateChecksums { checksum_node_id_1: 0, checksum_node_id_2: 0 },
+		]));
+
+		// Only the requested information is included
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			query_option: Some(msgs::QUERY_OPTION_TIMESTAMPS),
+		}).unwrap();
+		assert!(replies[0].timestamps.is_some());
+		assert!(replies[0].checksums.is_none());
+		let replies = net_graph_msg_handler.handle_query_channel_range(&node_id, &QueryChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff, query_option: None,
+		}).unwrap();
+		assert!(replies[0].timestamps.is_none());
+		assert!(replies[0].checksums.is_none());
+	}
+
+	#[test]
+	fn handling_reply_channel_range_queries_updated_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0)]);
+		let channel_update = get_channel_update(&secp_ctx, scid(1, 0), 100, 10000);
+		assert!(net_graph_msg_handler.handle_channel_update(&channel_update).unwrap());
+		let our_checksum = channel_update_checksum(&channel_update);
+
+		let reply = |timestamp_node_id_1, checksum_node_id_1| ReplyChannelRange {
+			chain_hash, first_blocknum: 0, number_of_blocks: 0xffff_ffff,
+			full_information: true, short_channel_ids: vec![scid(1, 0), scid(2, 0)],
+			encoding_type: EncodingType::Uncompressed,
+			timestamps: Some(vec![
+				ChannelUpdateTimestamps { timestamp_node_id_1, timestamp_node_id_2: 50 },
+				ChannelUpdateTimestamps { timestamp_node_id_1: 1, timestamp_node_id_2: 1 },
+			]),
+			checksums: Some(vec![
+				ChannelUpdateChecksums { checksum_node_id_1, checksum_node_id_2: 1 },
+				ChannelUpdateChecksums { checksum_node_id_1: 1, checksum_node_id_2: 1 },
+			]),
+		};
+
+		// We fetch everything for unknown channels, but only the newer updates of known ones
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		net_graph_msg_handler.sync_routing_table(&node_id, true);
+		let query = net_graph_msg_handler.handle_reply_channel_range(&node_id, &reply(100, our_checksum)).unwrap().unwrap();
+		assert_eq!(query.short_channel_ids, vec![scid(1, 0), scid(2, 0)]);
+		assert_eq!(query.query_flags, Some(vec![msgs::QUERY_FLAG_CHANNEL_UPDATE_2, ALL_QUERY_FLAGS]));
+
+		// A newer update is fetched if its checksum differs from ours...
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[3; 32]).unwrap());
+		net_graph_msg_handler.sync_routing_table(&node_id, true);
+		let query = net_graph_msg_handler.handle_reply_channel_range(&node_id, &reply(200, our_checksum + 1)).unwrap().unwrap();
+		assert_eq!(query.query_flags, Some(vec![msgs::QUERY_FLAG_CHANNEL_UPDATE_1 | msgs::QUERY_FLAG_CHANNEL_UPDATE_2, ALL_QUERY_FLAGS]));
+
+		// ...but not if it only refreshed the timestamp
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[4; 32]).unwrap());
+		net_graph_msg_handler.sync_routing_table(&node_id, true);
+		let query = net_graph_msg_handler.handle_reply_channel_range(&node_id, &reply(200, our_checksum)).unwrap().unwrap();
+		assert_eq!(query.query_flags, Some(vec![msgs::QUERY_FLAG_CHANNEL_UPDATE_2, ALL_QUERY_FLAGS]));
+	}
+
+	#[test]
+	fn handling_query_short_channel_ids_with_query_flags() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[2; 32]).unwrap());
+		let chain_hash = genesis_block(Network::Testnet).header.block_hash();
+		let node_1_privkey = &SecretKey::from_slice(&[42; 32]).unwrap();
+
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 0), scid(3, 0)]);
+		let channel_update = get_channel_update(&secp_ctx, scid(1, 0), 100, 10000);
+		assert!(net_graph_msg_handler.handle_channel_update(&channel_update).unwrap());
+
+		let unsigned_node_announcement = UnsignedNodeAnnouncement {
+			features: NodeFeatures::known(),
+			timestamp: 100,
+			node_id: template.contents.node_id_1,
+			rgb: [0; 3],
+			alias: [0; 32],
+			addresses: Vec::new(),
+			excess_address_data: Vec::new(),
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&unsigned_node_announcement.encode()[..])[..]);
+		let node_announcement = NodeAnnouncement {
+			signature: secp_ctx.sign(&msghash, node_1_privkey),
+			contents: unsigned_node_announcement
+		};
+		assert!(net_graph_msg_handler.handle_node_announcement(&node_announcement).unwrap());
+
+		let (channels, nodes, reply_end) = net_graph_msg_handler.handle_query_short_channel_ids(&node_id, &QueryShortChannelIds {
+			chain_hash, short_channel_ids: vec![scid(1, 0), scid(2, 0), scid(3, 0)],
+			encoding_type: EncodingType::Zlib,
+			query_flags: Some(vec![
+				msgs::QUERY_FLAG_CHANNEL_UPDATE_1 | msgs::QUERY_FLAG_CHANNEL_UPDATE_2,
+				msgs::QUERY_FLAG_CHANNEL_ANNOUNCEMENT | msgs::QUERY_FLAG_NODE_ANNOUNCEMENT_1,
+				0,
+			]),
+		}).unwrap();
+		assert_eq!(channels.len(), 2);
+		assert_eq!(channels[0], (None, Some(channel_update), None));
+		assert_eq!(channels[1].0.as_ref().unwrap().contents.short_channel_id, scid(2, 0));
+		assert_eq!(channels[1].1, None);
+		assert_eq!(nodes, vec![node_announcement]);
+		assert!(reply_end.full_information);
+	}
+
+	#[test]
+	fn removing_stale_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let template = get_channel_announcement(&secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 0), scid(3, 0)]);
+
+		// Channel 1 has an old update, channel 2 a recent one and channel 3 none at all
+		let update_time = 1_600_000_000;
+		assert!(net_graph_msg_handler.handle_channel_update(&get_channel_update(&secp_ctx, scid(1, 0), update_time, 10000)).unwrap());
+		assert!(net_graph_msg_handler.handle_channel_update(&get_channel_update(&secp_ctx, scid(2, 0), update_time + 100, 10000)).unwrap());
+
+		let mut network_graph = net_graph_msg_handler.network_graph.write().unwrap();
+		assert!(network_graph.remove_stale_channels_with_time(update_time as u64 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS).is_empty());
+		assert_eq!(network_graph.remove_stale_channels_with_time(update_time as u64 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS + 1), vec![scid(1, 0)]);
+		assert_eq!(network_graph.get_channels().keys().cloned().collect::<Vec<_>>(), vec![scid(2, 0), scid(3, 0)]);
+		assert_eq!(network_graph.get_nodes().len(), 2);
+
+		// Once the last channel with updates goes stale, nodes are only kept for channel 3
+		assert_eq!(network_graph.remove_stale_channels_with_time(update_time as u64 + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS + 101), vec![scid(2, 0)]);
+		assert_eq!(network_graph.get_channels().keys().cloned().collect::<Vec<_>>(), vec![scid(3, 0)]);
+		for (_, node) in network_graph.get_nodes().iter() {
+			assert_eq!(node.channels, vec![scid(3, 0)]);
+		}
+
+		// Channel 3 is removed once we learned of it long enough ago without it being updated
+		let received_time = network_graph.get_channels().get(&scid(3, 0)).unwrap().announcement_received_time;
+		assert!(network_graph.remove_stale_channels_with_time(received_time + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS).is_empty());
+		assert_eq!(network_graph.remove_stale_channels_with_time(received_time + STALE_CHANNEL_UPDATE_AGE_LIMIT_SECS + 1), vec![scid(3, 0)]);
+		assert!(network_graph.get_nodes().is_empty());
+	}
+
+	fn get_funding_spend(funding_redeemscript: &Script) -> Transaction {
+		Transaction {
+			version: 2,
+			lock_time: 0,
+			input: vec![TxIn {
+				previous_output: OutPoint::default(),
+				script_sig: Script::new(),
+				sequence: 0xffffffff,
+				witness: vec![Vec::new(), vec![0; 72], vec![0; 72], funding_redeemscript.to_bytes()],
+			}],
+			output: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn removing_spent_channels() {
+		let (secp_ctx, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let announcement = get_channel_announcement(&secp_ctx, scid(1, 0));
+		assert!(net_graph_msg_handler.handle_channel_announcement(&announcement).unwrap());
+
+		// A second channel between the same nodes, with different funding keys
+		let mut other_announcement = get_channel_announcement(&secp_ctx, scid(2, 0));
+		other_announcement.contents.bitcoin_key_2 = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[38; 32]).unwrap());
+		add_channels_unchecked(&net_graph_msg_handler, &other_announcement, &[scid(2, 0)]);
+
+		let funding_redeemscript = make_funding_redeemscript(&announcement.contents.bitcoin_key_1, &announcement.contents.bitcoin_key_2);
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().get(&scid(1, 0)).unwrap().funding_redeemscript, funding_redeemscript);
+
+		// Transactions not spending a funding output are ignored
+		let header = genesis_block(Network::Testnet).header;
+		let unrelated_tx = get_funding_spend(&Builder::new().push_opcode(opcodes::OP_TRUE).into_script());
+		net_graph_msg_handler.block_connected(&header, &[(0, &unrelated_tx)], 100);
+		assert_eq!(net_graph_msg_handler.network_graph.read().unwrap().get_channels().len(), 2);
+
+		// The funding_redeemscript index survives serialization
+		let mut w = test_utils::TestVecWriter(Vec::new());
+		net_graph_msg_handler.network_graph.read().unwrap().write(&mut w).unwrap();
+		let network_graph = <NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0)).unwrap();
+		assert!(network_graph == *net_graph_msg_handler.network_graph.read().unwrap());
+
+		// Graphs written without change sequence numbers consider every channel unchanged since
+		// sequence number 0
+		let sequences_len = 8 + 8 + 8 + network_graph.channel_change_sequences.len() * 8 * 4 + 8 + network_graph.removed_channels.len() * 8 * 2;
+		let legacy_graph = <NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0[..w.0.len() - sequences_len])).unwrap();
+		assert_ne!(network_graph.last_change_sequence, 0);
+		assert_eq!(legacy_graph.last_change_sequence, 0);
+		assert!(legacy_graph.channel_change_sequences.is_empty());
+		assert!(legacy_graph.get_channels() == network_graph.get_channels());
+
+		// Graphs written without the trailing channel fields derive the funding_redeemscript from
+		// the stored announcement
+		let trailing_len = sequences_len + 8 + network_graph.get_channels().values().map(|chan| 8 + chan.funding_redeemscript.encode().len() + 8).sum::<usize>();
+		let legacy_graph = <NetworkGraph>::read(&mut ::std::io::Cursor::new(&w.0[..w.0.len() - trailing_len])).unwrap();
+		assert_eq!(legacy_graph.get_channels().get(&scid(1, 0)).unwrap().funding_redeemscript, funding_redeemscript);
+		let net_graph_msg_handler: NetGraphMsgHandler<Arc<test_utils::TestChainSource>, _> = NetGraphMsgHandler::from_net_graph(genesis_block(Network::Testnet).header.block_hash(), None, Arc::new(test_utils::TestLogger::new()), network_graph);
+
+		let spend_tx = get_funding_spend(&funding_redeemscript);
+		net_graph_msg_handler.block_connected(&header, &[(0, &unrelated_tx), (1, &spend_tx)], 100);
+		{
+			let network_graph = net_graph_msg_handler.network_graph.read().unwrap();
+			assert_eq!(network_graph.get_channels().keys().cloned().collect::<Vec<_>>(), vec![scid(2, 0)]);
+			assert_eq!(network_graph.get_nodes().len(), 2);
+		}
+
+		// Nodes are removed along with their last channel
+		let other_spend_tx = get_funding_spend(&make_funding_redeemscript(&other_announcement.contents.bitcoin_key_1, &other_announcement.contents.bitcoin_key_2));
+		assert_eq!(net_graph_msg_handler.network_graph.write().unwrap().remove_spent_channels(&[(0, &other_spend_tx)]), vec![scid(2, 0)]);
+		let network_graph = net_graph_msg_handler.network_graph.read().unwrap();
+		assert!(network_graph.get_channels().is_empty());
+		assert!(network_graph.get_nodes().is_empty());
+	}
+
+	fn get_snapshot_test_graph(secp_ctx: &Secp256k1<All>) -> NetGraphMsgHandler<Arc<test_utils::TestChainSource>, Arc<test_utils::TestLogger>> {
+		let (_, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let template = get_channel_announcement(secp_ctx, 0);
+		add_channels_unchecked(&net_graph_msg_handler, &template, &[scid(1, 0), scid(2, 5), scid(700_000, 1)]);
+		{
+			let mut network_graph = net_graph_msg_handler.network_graph.write().unwrap();
+			network_graph.channels.get_mut(&scid(2, 5)).unwrap().capacity_sats = Some(1_000_000);
+			network_graph.update_channel(&get_channel_update(secp_ctx, scid(1, 0), 1_600_000_000, 1000), None).unwrap();
+			let mut update = get_channel_update(secp_ctx, scid(1, 0), 1_600_000_100, 2000);
+			update.contents.flags = 1 | 2;
+			update.contents.htlc_maximum_msat = OptionalField::Present(500_000_000);
+			network_graph.update_channel(&update, None).unwrap();
+			network_graph.update_channel(&get_channel_update(secp_ctx, scid(2, 5), 1_600_000_050, 3000), None).unwrap();
+		}
+		net_graph_msg_handler
+	}
+
+	#[test]
+	fn graph_snapshot_round_trip() {
+		let secp_ctx = Secp256k1::new();
+		let server = get_snapshot_test_graph(&secp_ctx);
+		let mut snapshot = Vec::new();
+		server.write_snapshot(0, &mut snapshot).unwrap();
+		let mut full_serialization = test_utils::TestVecWriter(Vec::new());
+		server.network_graph.read().unwrap().write(&mut full_serialization).unwrap();
+		assert!(snapshot.len() * 4 < full_serialization.0.len());
+
+		let (_, client) = create_net_graph_msg_handler();
+		assert_eq!(client.apply_snapshot(&mut &snapshot[..]).unwrap(), server.network_graph.read().unwrap().last_change_sequence);
+		{
+			let server_graph = server.network_graph.read().unwrap();
+			let client_graph = client.network_graph.read().unwrap();
+			assert_eq!(client_graph.get_channels().len(), 3);
+			for (short_channel_id, server_chan) in server_graph.get_channels().iter() {
+				let client_chan = client_graph.get_channels().get(short_channel_id).unwrap();
+				assert_eq!(client_chan.features, server_chan.features);
+				assert_eq!(client_chan.node_one, server_chan.node_one);
+				assert_eq!(client_chan.node_two, server_chan.node_two);
+				assert_eq!(client_chan.capacity_sats, server_chan.capacity_sats);
+				assert!(client_chan.announcement_message.is_none());
+				assert_eq!(client_chan.funding_redeemscript, server_chan.funding_redeemscript);
+				for (client_dir, server_dir) in [(&client_chan.one_to_two, &server_chan.one_to_two), (&client_chan.two_to_one, &server_chan.two_to_one)].iter() {
+					match (client_dir, server_dir) {
+						(&Some(ref client_dir), &Some(ref server_dir)) => {
+							assert_eq!(client_dir.last_update, server_dir.last_update);
+							assert_eq!(client_dir.enabled, server_dir.enabled);
+							assert_eq!(client_dir.cltv_expiry_delta, server_dir.cltv_expiry_delta);
+							assert_eq!(client_dir.htlc_minimum_msat, server_dir.htlc_minimum_msat);
+							assert_eq!(client_dir.htlc_maximum_msat, server_dir.htlc_maximum_msat);
+							assert_eq!(client_dir.fees, server_dir.fees);
+							assert!(client_dir.last_update_message.is_none());
+						},
+						(&None, &None) => {},
+						_ => panic!(),
+					}
+				}
+			}
+			assert_eq!(client_graph.get_nodes().len(), 2);
+			for (node_id, server_node) in server_graph.get_nodes().iter() {
+				let client_node = client_graph.get_nodes().get(node_id).unwrap();
+				assert_eq!(client_node.channels, server_node.channels);
+				assert_eq!(client_node.lowest_inbound_channel_fees, server_node.lowest_inbound_channel_fees);
+			}
+		}
+
+		// Channels from the snapshot are pruned once their funding output is spent. The test
+		// channels all share the same funding keys, so only the last one is indexed by them.
+		let announcement = get_channel_announcement(&secp_ctx, scid(1, 0));
+		let spend_tx = get_funding_spend(&make_funding_redeemscript(&announcement.contents.bitcoin_key_1, &announcement.contents.bitcoin_key_2));
+		assert_eq!(client.network_graph.write().unwrap().remove_spent_channels(&[(0, &spend_tx)]), vec![scid(700_000, 1)]);
+
+		// Unsigned channels aren't relayed until we see their announcement, which fills in what
+		// the snapshot lacked without dropping its updates.
+		assert!(client.get_next_channel_announcements(0, 10).is_empty());
+		assert!(client.handle_channel_announcement(&announcement).unwrap());
+		let client_graph = client.network_graph.read().unwrap();
+		let chan = client_graph.get_channels().get(&scid(1, 0)).unwrap();
+		assert_eq!(chan.announcement_message, Some(announcement.clone()));
+		assert_eq!(chan.funding_redeemscript, make_funding_redeemscript(&announcement.contents.bitcoin_key_1, &announcement.contents.bitcoin_key_2));
+		assert_eq!(chan.one_to_two.as_ref().unwrap().last_update, 1_600_000_000);
+		assert_eq!(client_graph.get_nodes().values().next().unwrap().channels.len(), 2);
+	}
+
+	#[test]
+	fn graph_snapshot_deltas() {
+		let secp_ctx = Secp256k1::new();
+		let server = get_snapshot_test_graph(&secp_ctx);
+		let mut snapshot = Vec::new();
+		server.write_snapshot(0, &mut snapshot).unwrap();
+		let (_, client) = create_net_graph_msg_handler();
+		let since = client.apply_snapshot(&mut &snapshot[..]).unwrap();
+
+		// Nothing changed, so the delta is empty and leaves the sequence number where it was
+		let mut delta = Vec::new();
+		server.write_snapshot(since, &mut delta).unwrap();
+		assert!(delta.len() < snapshot.len());
+		assert_eq!(client.apply_snapshot(&mut &delta[..]).unwrap(), since);
+
+		// The client hears about a newer update for channel 1 on its own
+		assert!(client.handle_channel_update(&get_channel_update(&secp_ctx, scid(1, 0), 1_600_000_300, 4000)).unwrap());
+
+		// The server sees newer updates for channels 1 and 2, but only channel 2's is fresher than
+		// what the client has.
+		{
+			let mut network_graph = server.network_graph.write().unwrap();
+			network_graph.update_channel(&get_channel_update(&secp_ctx, scid(1, 0), 1_600_000_200, 5000), None).unwrap();
+			network_graph.update_channel(&get_channel_update(&secp_ctx, scid(2, 5), 1_600_000_250, 6000), None).unwrap();
+		}
+		let mut delta = Vec::new();
+		server.write_snapshot(since, &mut delta).unwrap();
+		let since = client.apply_snapshot(&mut &delta[..]).unwrap();
+		assert_eq!(since, server.network_graph.read().unwrap().last_change_sequence);
+
+		{
+			let client_graph = client.network_graph.read().unwrap();
+			let chan_1 = client_graph.get_channels().get(&scid(1, 0)).unwrap();
+			assert_eq!(chan_1.one_to_two.as_ref().unwrap().fees.base_msat, 4000);
+			assert!(chan_1.one_to_two.as_ref().unwrap().last_update_message.is_some());
+			assert_eq!(chan_1.two_to_one.as_ref().unwrap().fees.base_msat, 2000);
+			let chan_2 = client_graph.get_channels().get(&scid(2, 5)).unwrap();
+			assert_eq!(chan_2.one_to_two.as_ref().unwrap().fees.base_msat, 6000);
+			assert_eq!(chan_2.one_to_two.as_ref().unwrap().last_update, 1_600_000_250);
+		}
+
+		// A delta applied to an empty graph only brings in the channels it mentions
+		let (_, other_client) = create_net_graph_msg_handler();
+		other_client.apply_snapshot(&mut &delta[..]).unwrap();
+		assert_eq!(other_client.network_graph.read().unwrap().get_channels().keys().cloned().collect::<Vec<_>>(), vec![scid(1, 0), scid(2, 5)]);
+
+		// Deltas follow the order in which the server learned of updates rather than their
+		// timestamps, so a first update for channel 3 carrying an old timestamp is still included.
+		// Channels the server removed are removed from the client as well.
+		{
+			let mut network_graph = server.network_graph.write().unwrap();
+			network_graph.update_channel(&get_channel_update(&secp_ctx, scid(700_000, 1), 1_500_000_000, 7000), None).unwrap();
+			network_graph.close_channel_from_update(scid(2, 5), true);
+		}
+		let mut delta = Vec::new();
+		server.write_snapshot(since, &mut delta).unwrap();
+		let since = client.apply_snapshot(&mut &delta[..]).unwrap();
+		{
+			let client_graph = client.network_graph.read().unwrap();
+			assert_eq!(client_graph.get_channels().keys().cloned().collect::<Vec<_>>(), vec![scid(1, 0), scid(700_000, 1)]);
+			let chan_3 = client_graph.get_channels().get(&scid(700_000, 1)).unwrap();
+			assert_eq!(chan_3.one_to_two.as_ref().unwrap().fees.base_msat, 7000);
+			assert_eq!(chan_3.one_to_two.as_ref().unwrap().last_update, 1_500_000_000);
+		}
+
+		// Once the server no longer remembers every removal since the client's sequence number, it
+		// serves a full snapshot instead.
+		server.network_graph.write().unwrap().removed_channels_pruned_through = since + 1;
+		let mut delta = Vec::new();
+		server.write_snapshot(since, &mut delta).unwrap();
+		let mut full_snapshot = Vec::new();
+		server.write_snapshot(0, &mut full_snapshot).unwrap();
+		assert_eq!(delta, full_snapshot);
+	}
+
+	#[test]
+	fn handling_gossip_timestamp_filter() {
+		let (_, net_graph_msg_handler) = create_net_graph_msg_handler();
+		let node_id = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[1; 32]).unwrap());
+
+		let mut filter = GossipTimestampFilter {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			first_timestamp: 0,
+			timestamp_range: 0xffff_ffff,
+		};
+		assert!(net_graph_msg_handler.handle_gossip_timestamp_filter(&node_id, &filter).is_ok());
+
+		// A filter for a chain we don't know is ignored
+		filter.chain_hash = genesis_block(Network::Bitcoin).header.block_hash();
+		match net_graph_msg_handler.handle_gossip_timestamp_filter(&node_id, &filter) {
+			Ok(_) => panic!(),
+			Err(e) => assert_eq!(e.err, "Received gossip_timestamp_filter for an unknown chain")
+		};
+	}
+
+	#[test]
+	fn graph_snapshot_rejects_invalid() {
+		let secp_ctx = Secp256k1::new();
+		let server = get_snapshot_test_graph(&secp_ctx);
+		let mut snapshot = Vec::new();
+		server.write_snapshot(0, &mut snapshot).unwrap();
+		let (_, client) = create_net_graph_msg_handler();
+
+		let mut bad_magic = snapshot.clone();
+		bad_magic[0] ^= 1;
+		if let Err(DecodeError::InvalidValue) = client.apply_snapshot(&mut &bad_magic[..]) {} else { panic!(); }
+		let mut bad_version = snapshot.clone();
+		bad_version[3] = SNAPSHOT_VERSION + 1;
+		if let Err(DecodeError::UnknownVersion) = client.apply_snapshot(&mut &bad_version[..]) {} else { panic!(); }
+		let mut other_chain = Vec::new();
+		server.network_graph.read().unwrap().write_snapshot(&genesis_block(Network::Bitcoin).header.block_hash(), 0, &mut other_chain).unwrap();
+		if let Err(DecodeError::InvalidValue) = client.apply_snapshot(&mut &other_chain[..]) {} else { panic!(); }
+
+		// A truncated snapshot is rejected without applying the part we could read
+		if let Err(DecodeError::ShortRead) = client.apply_snapshot(&mut &snapshot[..snapshot.len() - 1]) {} else { panic!(); }
+		assert!(client.network_graph.read().unwrap().get_channels().is_empty());
+		assert!(client.apply_snapshot(&mut &snapshot[..]).is_ok());
+
+		// So is a channel with more capacity than can exist
+		server.network_graph.write().unwrap().channels.get_mut(&scid(2, 5)).unwrap().capacity_sats = Some(MAX_VALUE_MSAT / 1000 + 1);
+		let mut too_large = Vec::new();
+		server.write_snapshot(0, &mut too_large).unwrap();
+		let (_, client) = create_net_graph_msg_handler();
+		if let Err(DecodeError::InvalidValue) = client.apply_snapshot(&mut &too_large[..]) {} else { panic!(); }
+	}
 }
@@ -16,17 +16,18 @@ use bitcoin::secp256k1::key::PublicKey;
 
 use ln::channelmanager::ChannelDetails;
 use ln::features::{ChannelFeatures, NodeFeatures};
-use ln::msgs::{DecodeError, ErrorAction, LightningError, MAX_VALUE_MSAT};
+use ln::msgs::{DecodeError, ErrorAction, HTLCFailChannelUpdate, LightningError, MAX_VALUE_MSAT};
 use routing::network_graph::{NetworkGraph, RoutingFees};
+use routing::scorer::Score;
 use util::ser::{Writeable, Readable};
 use util::logger::Logger;
 
 use std::cmp;
-use std::collections::{HashMap,BinaryHeap};
+use std::collections::{HashMap,HashSet,BinaryHeap};
 use std::ops::Deref;
 
 /// A hop in a route
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RouteHop {
 	/// The node_id of the node at this hop.
 	pub pubkey: PublicKey,
@@ -112,6 +113,99 @@ impl Readable for Route {
 	}
 }
 
+/// A hop in the trampoline part of a TrampolineRoute. Trampoline nodes find the path to the next
+/// trampoline hop themselves, so unlike a RouteHop no channel is given.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrampolineHop {
+	/// The node_id of the node at this hop.
+	pub pubkey: PublicKey,
+	/// The fee taken on this hop, which must also cover the fees of the path the previous
+	/// trampoline node finds to it. For the last hop, this should be the full value of the payment.
+	pub fee_msat: u64,
+	/// The CLTV delta added for this hop, which must also cover the path the previous trampoline
+	/// node finds to it. For the last hop, this should be the full CLTV value expected at the
+	/// destination, in excess of the current block height.
+	pub cltv_expiry_delta: u32,
+}
+
+/// A route which directs a payment over one of our own channels to a trampoline node, which then
+/// finds the rest of the path (possibly via further trampoline nodes) itself. This allows sending
+/// payments without a full view of the network graph.
+#[derive(Clone, PartialEq)]
+pub struct TrampolineRoute {
+	/// The hop from us to the first trampoline node. Its fee_msat and cltv_expiry_delta are the
+	/// total value and CLTV delta handed to the first trampoline node.
+	pub first_hop: RouteHop,
+	/// The trampoline nodes to pass through after first_hop (NOT INCLUDING the node first_hop
+	/// leads to), followed by the destination. Thus, this must always be at least length one.
+	pub trampoline_hops: Vec<TrampolineHop>,
+}
+
+/// A hop in a BlindedRoute. The real node_id of the node at this hop is hidden behind a blinded
+/// one, and the channel to the next hop is only known to the node itself, via encrypted_data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlindedHop {
+	/// The blinded node_id of the node at this hop. For the introduction node, this is a blinded
+	/// id as well, though senders route to the introduction node using its real id.
+	pub blinded_node_id: PublicKey,
+	/// The data the node at this hop needs to forward the payment (or, for the last hop, to
+	/// recognize it), encrypted to the node itself.
+	pub encrypted_data: Vec<u8>,
+	/// The fees this hop charges to forward the payment to the next hop. Zero for the last hop.
+	pub fees: RoutingFees,
+	/// The CLTV delta this hop requires to forward the payment to the next hop. Zero for the last
+	/// hop.
+	pub cltv_expiry_delta: u16,
+}
+
+/// A route to a recipient, provided by the recipient, in which all but the first node (the
+/// introduction node) are hidden from the sender. Senders find a path to the introduction node
+/// themselves and append the blinded hops to it with get_blinded_route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlindedRoute {
+	/// The real node_id of the introduction node.
+	pub introduction_node_id: PublicKey,
+	/// The blinding point the introduction node needs to decrypt its encrypted_data, which it
+	/// receives in its onion payload.
+	pub blinding_point: PublicKey,
+	/// The hops of the route, starting with the introduction node and ending with the recipient.
+	/// Thus, this must always be at least length one.
+	pub blinded_hops: Vec<BlindedHop>,
+}
+
+impl Writeable for BlindedRoute {
+	fn write<W: ::util::ser::Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.introduction_node_id.write(writer)?;
+		self.blinding_point.write(writer)?;
+		(self.blinded_hops.len() as u8).write(writer)?;
+		for hop in self.blinded_hops.iter() {
+			hop.blinded_node_id.write(writer)?;
+			hop.encrypted_data.write(writer)?;
+			hop.fees.write(writer)?;
+			hop.cltv_expiry_delta.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for BlindedRoute {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<BlindedRoute, DecodeError> {
+		let introduction_node_id = Readable::read(reader)?;
+		let blinding_point = Readable::read(reader)?;
+		let hops_count: u8 = Readable::read(reader)?;
+		let mut blinded_hops = Vec::with_capacity(hops_count as usize);
+		for _ in 0..hops_count {
+			blinded_hops.push(BlindedHop {
+				blinded_node_id: Readable::read(reader)?,
+				encrypted_data: Readable::read(reader)?,
+				fees: Readable::read(reader)?,
+				cltv_expiry_delta: Readable::read(reader)?,
+			});
+		}
+		Ok(BlindedRoute { introduction_node_id, blinding_point, blinded_hops })
+	}
+}
+
 /// A channel descriptor which provides a last-hop route to get_route
 pub struct RouteHint {
 	/// The node_id of the non-target end of the route
@@ -124,6 +218,31 @@ pub struct RouteHint {
 	pub cltv_expiry_delta: u16,
 	/// The minimum value, in msat, which must be relayed to the next hop.
 	pub htlc_minimum_msat: u64,
+	/// The maximum value in msat available for routing with a single HTLC, if known.
+	pub htlc_maximum_msat: Option<u64>,
+}
+
+/// Constraints on the routes returned by get_route_with_scorer, which are applied as paths are
+/// explored rather than by filtering candidate routes afterwards.
+#[derive(Clone, Default)]
+pub struct RouteParameters {
+	/// The maximum total fee, in msat, paid to the nodes along the route.
+	pub max_total_fee_msat: Option<u64>,
+	/// The maximum total CLTV delta of the route, including the final_cltv required by the
+	/// recipient.
+	pub max_total_cltv_expiry_delta: Option<u32>,
+	/// The maximum number of hops in the route, including the final hop to the recipient.
+	pub max_path_length: Option<u8>,
+	/// Nodes which the route must not pass through. Routing to an excluded node fails.
+	pub excluded_nodes: HashSet<PublicKey>,
+	/// Channels which the route must not use, including our own channels and those in last_hops.
+	pub excluded_channels: HashSet<u64>,
+	/// Nodes through which we prefer to route. The fees and scorer penalties of channels from
+	/// these nodes are disregarded when comparing paths, though their fees still count towards
+	/// max_total_fee_msat and are paid as usual.
+	pub preferred_nodes: HashSet<PublicKey>,
+	/// Channels which are preferred in the same way as the channels of preferred_nodes.
+	pub preferred_channels: HashSet<u64>,
 }
 
 #[derive(Eq, PartialEq)]
@@ -131,6 +250,16 @@ struct RouteGraphNode {
 	pubkey: PublicKey,
 	lowest_fee_to_peer_through_node: u64,
 	lowest_fee_to_node: u64,
+	/// The sum of the scorer's penalties for the channels from this node to the target. It is
+	/// included in lowest_fee_to_peer_through_node but, unlike fees, is not paid to anyone.
+	path_penalty_msat: u64,
+	/// The fees of preferred channels from this node to the target, which are excluded from
+	/// lowest_fee_to_peer_through_node.
+	preferred_fee_msat: u64,
+	/// The sum of the CLTV deltas of the channels from this node to the target.
+	cltv_expiry_delta_to_node: u32,
+	/// The number of channels from this node to the target.
+	path_length_to_node: u8,
 }
 
 impl cmp::Ord for RouteGraphNode {
@@ -149,9 +278,21 @@ impl cmp::PartialOrd for RouteGraphNode {
 struct DummyDirectionalChannelInfo {
 	cltv_expiry_delta: u32,
 	htlc_minimum_msat: u64,
+	htlc_maximum_msat: Option<u64>,
 	fees: RoutingFees,
 }
 
+/// A Score which doesn't penalize any channel, used by get_route.
+struct NoPenaltyScorer;
+
+impl Score for NoPenaltyScorer {
+	fn channel_penalty_msat(&self, _short_channel_id: u64, _source: &PublicKey, _target: &PublicKey, _amount_msat: u64, _capacity_msat: Option<u64>) -> u64 { 0 }
+	fn payment_path_failed(&mut self, _path: &[RouteHop], _update: &HTLCFailChannelUpdate) {}
+	fn payment_path_successful(&mut self, _path: &[RouteHop]) {}
+	fn probe_failed(&mut self, _path: &[RouteHop], _short_channel_id: u64) {}
+	fn probe_successful(&mut self, _path: &[RouteHop]) {}
+}
+
 
 /// Gets a route from us to the given target node.
 ///
@@ -169,10 +310,29 @@ struct DummyDirectionalChannelInfo {
 /// The fees on channels from us to next-hops are ignored (as they are assumed to all be
 /// equal), however the enabled/disabled bit on such channels as well as the htlc_minimum_msat
 /// *is* checked as they may change based on the receiving node.
+///
+/// Channels whose htlc_maximum_msat or on-chain capacity (if known, see
+/// NetGraphMsgHandler::new's chain_access) is below the amount which would be sent over them are
+/// not used.
+///
+/// Paths are chosen purely by fees. See get_route_with_scorer to also take into account what we
+/// learned from past payments.
 pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, target: &PublicKey, first_hops: Option<&[&ChannelDetails]>,
 	last_hops: &[&RouteHint], final_value_msat: u64, final_cltv: u32, logger: L) -> Result<Route, LightningError> where L::Target: Logger {
-	// TODO: Obviously *only* using total fee cost sucks. We should consider weighting by
-	// uptime/success in using a node in the past.
+	get_route_with_scorer(our_node_id, network, target, first_hops, last_hops, final_value_msat, final_cltv, &RouteParameters::default(), &NoPenaltyScorer, logger)
+}
+
+/// Gets a route from us to the given target node, as get_route does, but choosing the path which
+/// minimizes the sum of fees and the penalties the given scorer assigns to its channels, subject
+/// to the given constraints.
+///
+/// The scorer is not consulted for channels from us, as we know their liquidity exactly, and
+/// its penalties do not affect the fees paid along the returned route.
+///
+/// As we only keep track of the best path from each node, a route satisfying the constraints may
+/// not be found if a cheaper path through the same nodes violates them.
+pub fn get_route_with_scorer<L: Deref, S: Score>(our_node_id: &PublicKey, network: &NetworkGraph, target: &PublicKey, first_hops: Option<&[&ChannelDetails]>,
+	last_hops: &[&RouteHint], final_value_msat: u64, final_cltv: u32, params: &RouteParameters, scorer: &S, logger: L) -> Result<Route, LightningError> where L::Target: Logger {
 	if *target == *our_node_id {
 		return Err(LightningError{err: "Cannot generate a route to ourselves".to_owned(), action: ErrorAction::IgnoreError});
 	}
@@ -181,6 +341,14 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 		return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError});
 	}
 
+	if params.excluded_nodes.contains(target) {
+		return Err(LightningError{err: "Cannot generate a route to an excluded node".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	if final_cltv > params.max_total_cltv_expiry_delta.unwrap_or(u32::max_value()) || params.max_path_length == Some(0) {
+		return Err(LightningError{err: "Cannot generate a route within the given constraints".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
 	// We do a dest-to-source Dijkstra's sorting by each node's distance from the destination
 	// plus the minimum per-HTLC fee to get from it to another node (aka "shitty A*").
 	// TODO: There are a few tweaks we could do, including possibly pre-calculating more stuff
@@ -190,6 +358,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	let dummy_directional_info = DummyDirectionalChannelInfo { // used for first_hops routes
 		cltv_expiry_delta: 0,
 		htlc_minimum_msat: 0,
+		htlc_maximum_msat: None,
 		fees: RoutingFees {
 			base_msat: 0,
 			proportional_millionths: 0,
@@ -203,6 +372,9 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	if let Some(hops) = first_hops {
 		for chan in hops {
 			let short_channel_id = chan.short_channel_id.expect("first_hops should be filled in with usable channels, not pending ones");
+			if params.excluded_channels.contains(&short_channel_id) {
+				continue;
+			}
 			if chan.remote_network_id == *target {
 				return Ok(Route {
 					paths: vec![vec![RouteHop {
@@ -225,13 +397,33 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	macro_rules! add_entry {
 		// Adds entry which goes from $src_node_id to $dest_node_id
 		// over the channel with id $chan_id with fees described in
-		// $directional_info.
-		( $chan_id: expr, $src_node_id: expr, $dest_node_id: expr, $directional_info: expr, $chan_features: expr, $starting_fee_msat: expr ) => {
+		// $directional_info and the given capacity, if known.
+		// The remaining arguments describe the path from $dest_node_id to the target, as in
+		// RouteGraphNode.
+		( $chan_id: expr, $src_node_id: expr, $dest_node_id: expr, $directional_info: expr, $capacity_msat: expr, $chan_features: expr, $starting_fee_msat: expr,
+		  $path_penalty_msat: expr, $preferred_fee_msat: expr, $cltv_expiry_delta_to_target: expr, $path_length_to_target: expr ) => {
+			// Channels from us don't add to the CLTV delta, as we don't charge ourselves one.
+			let cltv_expiry_delta_to_node = if $src_node_id != *our_node_id {
+				($cltv_expiry_delta_to_target as u32).saturating_add($directional_info.cltv_expiry_delta as u32)
+			} else { $cltv_expiry_delta_to_target as u32 };
+			let path_length_to_node = ($path_length_to_target as u8).saturating_add(1);
+			let is_excluded = params.excluded_channels.contains(&$chan_id) || params.excluded_nodes.contains(&$src_node_id);
+			let is_within_constraints = final_cltv.saturating_add(cltv_expiry_delta_to_node) <= params.max_total_cltv_expiry_delta.unwrap_or(u32::max_value()) &&
+				path_length_to_node <= params.max_path_length.unwrap_or(u8::max_value());
+			// The whole amount must fit in a single HTLC, within both the channel's capacity and
+			// the maximum set by its channel_update.
+			let amount_to_transfer_msat = $starting_fee_msat as u64 + final_value_msat;
+			let capacity_msat: Option<u64> = $capacity_msat;
+			let max_htlc_msat = cmp::min($directional_info.htlc_maximum_msat.unwrap_or(u64::max_value()),
+				capacity_msat.unwrap_or(u64::max_value()));
 			//TODO: Explore simply adding fee to hit htlc_minimum_msat
-			if $starting_fee_msat as u64 + final_value_msat >= $directional_info.htlc_minimum_msat {
+			if !is_excluded && is_within_constraints && amount_to_transfer_msat >= $directional_info.htlc_minimum_msat && amount_to_transfer_msat <= max_htlc_msat {
 				let proportional_fee_millions = ($starting_fee_msat + final_value_msat).checked_mul($directional_info.fees.proportional_millionths as u64);
+				// Fees of channels from us are ignored, as we don't pay them
+				let max_fee_to_node = params.max_total_fee_msat.unwrap_or(u64::max_value());
 				if let Some(new_fee) = proportional_fee_millions.and_then(|part| {
 						($directional_info.fees.base_msat as u64).checked_add(part / 1000000) })
+						.filter(|new_fee| $src_node_id == *our_node_id || ($starting_fee_msat as u64).saturating_add(*new_fee) <= max_fee_to_node)
 				{
 					let mut total_fee = $starting_fee_msat as u64;
 					let hm_entry = dist.entry(&$src_node_id);
@@ -267,14 +459,30 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 							total_fee = u64::max_value();
 						}
 					}
+					let mut path_penalty_msat = $path_penalty_msat as u64;
+					let mut preferred_fee_msat = $preferred_fee_msat as u64;
+					if $src_node_id != *our_node_id {
+						if params.preferred_channels.contains(&$chan_id) || params.preferred_nodes.contains(&$src_node_id) {
+							preferred_fee_msat = preferred_fee_msat.saturating_add(new_fee);
+						} else {
+							let channel_penalty_msat = scorer.channel_penalty_msat($chan_id.clone(), &$src_node_id, &$dest_node_id,
+								amount_to_transfer_msat, capacity_msat);
+							path_penalty_msat = path_penalty_msat.saturating_add(channel_penalty_msat);
+						}
+					}
+					let total_cost = total_fee.saturating_sub(preferred_fee_msat).saturating_add(path_penalty_msat);
 					let new_graph_node = RouteGraphNode {
 						pubkey: $src_node_id,
-						lowest_fee_to_peer_through_node: total_fee,
+						lowest_fee_to_peer_through_node: total_cost,
 						lowest_fee_to_node: $starting_fee_msat as u64 + new_fee,
+						path_penalty_msat,
+						preferred_fee_msat,
+						cltv_expiry_delta_to_node,
+						path_length_to_node,
 					};
-					if old_entry.0 > total_fee {
+					if old_entry.0 > total_cost {
 						targets.push(new_graph_node);
-						old_entry.0 = total_fee;
+						old_entry.0 = total_cost;
 						old_entry.3 = RouteHop {
 							pubkey: $dest_node_id.clone(),
 							node_features: NodeFeatures::empty(),
@@ -290,10 +498,11 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	}
 
 	macro_rules! add_entries_to_cheapest_to_target_node {
-		( $node: expr, $node_id: expr, $fee_to_target_msat: expr ) => {
+		( $node: expr, $node_id: expr, $fee_to_target_msat: expr, $path_penalty_msat: expr, $preferred_fee_msat: expr, $cltv_expiry_delta_to_target: expr, $path_length_to_target: expr ) => {
 			if first_hops.is_some() {
 				if let Some(&(ref first_hop, ref features)) = first_hop_targets.get(&$node_id) {
-					add_entry!(first_hop, *our_node_id, $node_id, dummy_directional_info, features.to_context(), $fee_to_target_msat);
+					add_entry!(first_hop, *our_node_id, $node_id, dummy_directional_info, None, features.to_context(), $fee_to_target_msat,
+						$path_penalty_msat, $preferred_fee_msat, $cltv_expiry_delta_to_target, $path_length_to_target);
 				}
 			}
 
@@ -313,7 +522,8 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 							if first_hops.is_none() || chan.node_two != *our_node_id {
 								if let Some(two_to_one) = chan.two_to_one.as_ref() {
 									if two_to_one.enabled {
-										add_entry!(chan_id, chan.node_two, chan.node_one, two_to_one, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, chan.node_two, chan.node_one, two_to_one, chan.capacity_sats.map(|capacity_sats| capacity_sats.saturating_mul(1000)), chan.features, $fee_to_target_msat,
+											$path_penalty_msat, $preferred_fee_msat, $cltv_expiry_delta_to_target, $path_length_to_target);
 									}
 								}
 							}
@@ -321,7 +531,8 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 							if first_hops.is_none() || chan.node_one != *our_node_id {
 								if let Some(one_to_two) = chan.one_to_two.as_ref() {
 									if one_to_two.enabled {
-										add_entry!(chan_id, chan.node_one, chan.node_two, one_to_two, chan.features, $fee_to_target_msat);
+										add_entry!(chan_id, chan.node_one, chan.node_two, one_to_two, chan.capacity_sats.map(|capacity_sats| capacity_sats.saturating_mul(1000)), chan.features, $fee_to_target_msat,
+											$path_penalty_msat, $preferred_fee_msat, $cltv_expiry_delta_to_target, $path_length_to_target);
 									}
 								}
 
@@ -336,7 +547,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	match network.get_nodes().get(target) {
 		None => {},
 		Some(node) => {
-			add_entries_to_cheapest_to_target_node!(node, target, 0);
+			add_entries_to_cheapest_to_target_node!(node, target, 0, 0, 0, 0, 0);
 		},
 	}
 
@@ -349,17 +560,17 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 						// bit lazy here. In the future, we should pull them out via our
 						// ChannelManager, but there's no reason to waste the space until we
 						// need them.
-						add_entry!(first_hop, *our_node_id , hop.src_node_id, dummy_directional_info, features.to_context(), 0);
+						add_entry!(first_hop, *our_node_id , hop.src_node_id, dummy_directional_info, None, features.to_context(), 0, 0, 0, 0, 0);
 					}
 				}
 				// BOLT 11 doesn't allow inclusion of features for the last hop hints, which
 				// really sucks, cause we're gonna need that eventually.
-				add_entry!(hop.short_channel_id, hop.src_node_id, target, hop, ChannelFeatures::empty(), 0);
+				add_entry!(hop.short_channel_id, hop.src_node_id, target, hop, None, ChannelFeatures::empty(), 0, 0, 0, 0, 0);
 			}
 		}
 	}
 
-	while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, .. }) = targets.pop() {
+	while let Some(RouteGraphNode { pubkey, lowest_fee_to_node, path_penalty_msat, preferred_fee_msat, cltv_expiry_delta_to_node, path_length_to_node, .. }) = targets.pop() {
 		if pubkey == *our_node_id {
 			let mut res = vec!(dist.remove(&our_node_id).unwrap().3);
 			loop {
@@ -400,7 +611,7 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 		match network.get_nodes().get(&pubkey) {
 			None => {},
 			Some(node) => {
-				add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node);
+				add_entries_to_cheapest_to_target_node!(node, &pubkey, lowest_fee_to_node, path_penalty_msat, preferred_fee_msat, cltv_expiry_delta_to_node, path_length_to_node);
 			},
 		}
 	}
@@ -408,21 +619,151 @@ pub fn get_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, targ
 	Err(LightningError{err: "Failed to find a path to the given destination".to_owned(), action: ErrorAction::IgnoreError})
 }
 
+/// Gets a route from us to the given target node through the given trampoline node, using only
+/// our own channels. The trampoline node will find a path to the target itself, for which it
+/// charges the given fees and CLTV delta on top of the final value and CLTV.
+///
+/// first_hops should be filled in with the results of ChannelManager::list_usable_channels(),
+/// which must include a live channel to the trampoline node with enough outbound capacity for the
+/// payment and trampoline fees.
+///
+/// Panics if first_hops contains channels without short_channel_ids
+/// (ChannelManager::list_usable_channels will never include such channels).
+pub fn get_trampoline_route(our_node_id: &PublicKey, first_hops: &[&ChannelDetails], trampoline_node_id: &PublicKey,
+	trampoline_fees: &RoutingFees, trampoline_cltv_expiry_delta: u16, target: &PublicKey, final_value_msat: u64, final_cltv: u32) -> Result<TrampolineRoute, LightningError> {
+	if *target == *our_node_id || *trampoline_node_id == *our_node_id {
+		return Err(LightningError{err: "Cannot generate a route to ourselves".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	if *target == *trampoline_node_id {
+		return Err(LightningError{err: "Cannot route via a trampoline node to itself".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	if final_value_msat > MAX_VALUE_MSAT {
+		return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	let trampoline_fee_msat = final_value_msat.checked_mul(trampoline_fees.proportional_millionths as u64)
+		.and_then(|prop_fee| (prop_fee / 1000000).checked_add(trampoline_fees.base_msat as u64));
+	let total_value_msat = match trampoline_fee_msat.and_then(|fee| fee.checked_add(final_value_msat)) {
+		Some(total) if total <= MAX_VALUE_MSAT => total,
+		_ => return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError}),
+	};
+
+	let mut found_trampoline_peer = false;
+	for chan in first_hops {
+		if chan.remote_network_id != *trampoline_node_id {
+			continue;
+		}
+		found_trampoline_peer = true;
+		if !chan.counterparty_features.supports_trampoline_routing() {
+			return Err(LightningError{err: "Trampoline node does not support trampoline routing".to_owned(), action: ErrorAction::IgnoreError});
+		}
+		if !chan.is_live || chan.outbound_capacity_msat < total_value_msat {
+			continue;
+		}
+		let short_channel_id = chan.short_channel_id.expect("first_hops should be filled in with usable channels, not pending ones");
+		return Ok(TrampolineRoute {
+			first_hop: RouteHop {
+				pubkey: chan.remote_network_id,
+				node_features: chan.counterparty_features.to_context(),
+				short_channel_id,
+				channel_features: chan.counterparty_features.to_context(),
+				fee_msat: total_value_msat,
+				cltv_expiry_delta: final_cltv + trampoline_cltv_expiry_delta as u32,
+			},
+			trampoline_hops: vec![TrampolineHop {
+				pubkey: *target,
+				fee_msat: final_value_msat,
+				cltv_expiry_delta: final_cltv,
+			}],
+		});
+	}
+
+	if found_trampoline_peer {
+		Err(LightningError{err: "No channel to the trampoline node has enough capacity for the payment".to_owned(), action: ErrorAction::IgnoreError})
+	} else {
+		Err(LightningError{err: "Cannot route to a trampoline node we have no channel with".to_owned(), action: ErrorAction::IgnoreError})
+	}
+}
+
+/// Gets a route from us through the given blinded route, as provided by the recipient. A path to
+/// the introduction node is found as in get_route, and the blinded hops are appended to it, with
+/// their blinded node_ids as pubkeys and a short_channel_id of 0, as only the nodes themselves
+/// know which channels they forward over.
+///
+/// The returned Route must be paid with ChannelManager::send_payment_to_blinded_route.
+pub fn get_blinded_route<L: Deref>(our_node_id: &PublicKey, network: &NetworkGraph, blinded_route: &BlindedRoute, first_hops: Option<&[&ChannelDetails]>,
+	final_value_msat: u64, final_cltv: u32, logger: L) -> Result<Route, LightningError> where L::Target: Logger {
+	if blinded_route.blinded_hops.is_empty() {
+		return Err(LightningError{err: "Cannot route via a blinded route without any hops".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	if final_value_msat > MAX_VALUE_MSAT {
+		return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError});
+	}
+
+	// Walk the blinded hops backwards from the recipient, calculating the fee and CLTV delta each
+	// hop charges to forward to the next, exactly as the introduction node's RouteHop would be.
+	let hop_count = blinded_route.blinded_hops.len();
+	let mut hop_fees = vec![0; hop_count];
+	let mut hop_cltv_deltas = vec![0; hop_count];
+	hop_fees[hop_count - 1] = final_value_msat;
+	hop_cltv_deltas[hop_count - 1] = final_cltv;
+	let mut value_msat = final_value_msat;
+	let mut cltv = final_cltv;
+	for (idx, hop) in blinded_route.blinded_hops.iter().enumerate().rev().skip(1) {
+		let hop_fee_msat = value_msat.checked_mul(hop.fees.proportional_millionths as u64)
+			.and_then(|prop_fee| (prop_fee / 1000000).checked_add(hop.fees.base_msat as u64));
+		value_msat = match hop_fee_msat.and_then(|fee| fee.checked_add(value_msat)) {
+			Some(total) if total <= MAX_VALUE_MSAT => total,
+			_ => return Err(LightningError{err: "Cannot generate a route of more value than all existing satoshis".to_owned(), action: ErrorAction::IgnoreError}),
+		};
+		hop_fees[idx] = hop_fee_msat.unwrap();
+		hop_cltv_deltas[idx] = hop.cltv_expiry_delta as u32;
+		cltv += hop.cltv_expiry_delta as u32;
+	}
+
+	let mut route = get_route(our_node_id, network, &blinded_route.introduction_node_id, first_hops, &[], value_msat, cltv, logger)?;
+	for path in route.paths.iter_mut() {
+		{
+			let intro_hop = path.last_mut().unwrap();
+			intro_hop.fee_msat = hop_fees[0];
+			intro_hop.cltv_expiry_delta = hop_cltv_deltas[0];
+		}
+		for (idx, hop) in blinded_route.blinded_hops.iter().enumerate().skip(1) {
+			path.push(RouteHop {
+				pubkey: hop.blinded_node_id,
+				node_features: NodeFeatures::empty(),
+				short_channel_id: 0,
+				channel_features: ChannelFeatures::empty(),
+				fee_msat: hop_fees[idx],
+				cltv_expiry_delta: hop_cltv_deltas[idx],
+			});
+		}
+	}
+	Ok(route)
+}
+
 #[cfg(test)]
 mod tests {
-	use routing::router::{get_route, RouteHint, RoutingFees};
+	use routing::router::{get_blinded_route, get_route, get_route_with_scorer, get_trampoline_route, BlindedHop, BlindedRoute, RouteHint, RouteParameters, RoutingFees};
 	use routing::network_graph::NetGraphMsgHandler;
+	use routing::scorer::{ProbabilisticScorer, Score};
 	use ln::features::{ChannelFeatures, InitFeatures, NodeFeatures};
 	use ln::msgs::{ErrorAction, LightningError, OptionalField, UnsignedChannelAnnouncement, ChannelAnnouncement, RoutingMessageHandler,
-	   NodeAnnouncement, UnsignedNodeAnnouncement, ChannelUpdate, UnsignedChannelUpdate};
+	   NodeAnnouncement, UnsignedNodeAnnouncement, ChannelUpdate, UnsignedChannelUpdate, HTLCFailChannelUpdate};
 	use ln::channelmanager;
 	use util::test_utils;
-	use util::ser::Writeable;
+	use util::ser::{Readable, Writeable};
 
 	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 	use bitcoin::hashes::Hash;
 	use bitcoin::network::constants::Network;
 	use bitcoin::blockdata::constants::genesis_block;
+	use bitcoin::blockdata::transaction::TxOut;
+	use bitcoin::blockdata::script::Builder;
+	use bitcoin::blockdata::opcodes;
 
 	use hex;
 
@@ -533,7 +874,7 @@ mod tests {
 	fn build_graph() -> (Secp256k1<All>, NetGraphMsgHandler<std::sync::Arc<crate::util::test_utils::TestChainSource>, std::sync::Arc<crate::util::test_utils::TestLogger>>, std::sync::Arc<test_utils::TestLogger>) {
 		let secp_ctx = Secp256k1::new();
 		let logger = Arc::new(test_utils::TestLogger::new());
-		let net_graph_msg_handler = NetGraphMsgHandler::new(None, Arc::clone(&logger));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), None, Arc::clone(&logger));
 		// Build network from our_id to node7:
 		//
 		//        -1(1)2-  node0  -1(3)2-
@@ -1044,6 +1385,7 @@ mod tests {
 			fees: zero_fees,
 			cltv_expiry_delta: (8 << 8) | 1,
 			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
 		}, RouteHint {
 			src_node_id: nodes[4].clone(),
 			short_channel_id: 9,
@@ -1053,12 +1395,14 @@ mod tests {
 			},
 			cltv_expiry_delta: (9 << 8) | 1,
 			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
 		}, RouteHint {
 			src_node_id: nodes[5].clone(),
 			short_channel_id: 10,
 			fees: zero_fees,
 			cltv_expiry_delta: (10 << 8) | 1,
 			htlc_minimum_msat: 0,
+			htlc_maximum_msat: None,
 		})
 	}
 
@@ -1221,4 +1565,308 @@ mod tests {
 		assert_eq!(route.paths[0][4].node_features.le_flags(), &Vec::<u8>::new()); // We dont pass flags in from invoices yet
 		assert_eq!(route.paths[0][4].channel_features.le_flags(), &Vec::<u8>::new()); // We can't learn any flags from invoices, sadly
 	}
+
+	#[test]
+	fn scorer_avoids_failed_channels_test() {
+		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let mut scorer = ProbabilisticScorer::default();
+
+		// Without any knowledge, we take the cheapest route to 3 via 2
+		let route = get_route_with_scorer(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), 100, 42, &RouteParameters::default(), &scorer, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![2, 4]);
+
+		// Once channel 4 has failed, we take the pricier route via 8 instead
+		scorer.payment_path_failed(&route.paths[0], &HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 4, is_permanent: false });
+		let route = get_route_with_scorer(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), 100, 42, &RouteParameters::default(), &scorer, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![12, 13]);
+		// Penalties are not included in the fees paid
+		assert_eq!(route.paths[0][0].fee_msat, 200);
+		assert_eq!(route.paths[0][1].fee_msat, 100);
+
+		// get_route ignores what the scorer learned
+		let route = get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), 100, 42, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>(), vec![2, 4]);
+	}
+
+	#[test]
+	fn route_parameters_test() {
+		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let network_graph = net_graph_msg_handler.network_graph.read().unwrap();
+		let scorer = ProbabilisticScorer::default();
+		macro_rules! get_route_scids {
+			($params: expr) => {
+				get_route_with_scorer(&our_id, &network_graph, &nodes[2], None, &Vec::new(), 100, 42, &$params, &scorer, Arc::clone(&logger))
+					.map(|route| route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>())
+			}
+		}
+
+		// The cheapest route to 3 is via 2, paying 100 msat in fees and with a total CLTV delta of
+		// 1025 + 42. The next cheapest one, via 8, pays 200 msat with a delta of 3329 + 42.
+		assert_eq!(get_route_scids!(RouteParameters::default()).unwrap(), vec![2, 4]);
+
+		// Excluding channels or nodes on the cheapest route makes us use the other one
+		let mut params = RouteParameters::default();
+		params.excluded_channels.insert(4);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+		let mut params = RouteParameters::default();
+		params.excluded_nodes.insert(nodes[1]);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+		let mut params = RouteParameters::default();
+		params.excluded_channels.insert(2);
+		params.excluded_channels.insert(12);
+		assert!(get_route_scids!(params).is_err());
+		let mut params = RouteParameters::default();
+		params.excluded_nodes.insert(nodes[2]);
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_route_scids!(params) {
+			assert_eq!(err, "Cannot generate a route to an excluded node");
+		} else { panic!(); }
+
+		// Constraints are honoured while searching, so we fall back to the other route if the
+		// cheapest one violates them.
+		let mut params = RouteParameters::default();
+		params.excluded_channels.insert(4);
+		params.max_total_fee_msat = Some(200);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+		params.max_total_fee_msat = Some(199);
+		assert!(get_route_scids!(params).is_err());
+
+		let mut params = RouteParameters::default();
+		params.max_total_cltv_expiry_delta = Some(1025 + 42);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![2, 4]);
+		params.excluded_channels.insert(4);
+		assert!(get_route_scids!(params).is_err());
+		params.max_total_cltv_expiry_delta = Some(3329 + 42);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+		params.max_total_cltv_expiry_delta = Some(41);
+		assert!(get_route_scids!(params).is_err());
+
+		let mut params = RouteParameters::default();
+		params.max_path_length = Some(2);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![2, 4]);
+		params.max_path_length = Some(1);
+		assert!(get_route_scids!(params).is_err());
+
+		// Preferred channels are used even if they are more expensive, and their fees are still paid
+		let mut params = RouteParameters::default();
+		params.preferred_channels.insert(13);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+		let route = get_route_with_scorer(&our_id, &network_graph, &nodes[2], None, &Vec::new(), 100, 42, &params, &scorer, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths[0][0].fee_msat, 200);
+		params.max_total_fee_msat = Some(199);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![2, 4]);
+		let mut params = RouteParameters::default();
+		params.preferred_nodes.insert(nodes[7]);
+		assert_eq!(get_route_scids!(params).unwrap(), vec![12, 13]);
+	}
+
+	#[test]
+	fn htlc_maximum_test() {
+		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
+		let (our_privkey, our_id, privkeys, nodes) = get_nodes(&secp_ctx);
+		macro_rules! get_route_scids {
+			($value_msat: expr) => {
+				get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), $value_msat, 42, Arc::clone(&logger))
+					.map(|route| route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>())
+			}
+		}
+		macro_rules! set_htlc_maximum {
+			($privkey: expr, $short_channel_id: expr, $timestamp: expr, $cltv_expiry_delta: expr, $fee_base_msat: expr, $fee_proportional_millionths: expr, $htlc_maximum_msat: expr) => {
+				update_channel(&net_graph_msg_handler, &secp_ctx, $privkey, UnsignedChannelUpdate {
+					chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+					short_channel_id: $short_channel_id,
+					timestamp: $timestamp,
+					flags: 0,
+					cltv_expiry_delta: $cltv_expiry_delta,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: $htlc_maximum_msat,
+					fee_base_msat: $fee_base_msat,
+					fee_proportional_millionths: $fee_proportional_millionths,
+					excess_data: Vec::new()
+				});
+			}
+		}
+
+		// Sending 100 msat, 100 msat go over channel 4 and 200 msat over channel 2, while 100 msat
+		// go over channel 13 and 300 msat over channel 12 on the alternative route.
+		assert_eq!(get_route_scids!(100).unwrap(), vec![2, 4]);
+
+		// A maximum below the amount sent makes us use the other route, whose channels have none
+		set_htlc_maximum!(&privkeys[1], 4, 2, (4 << 8) | 1, 0, 1000000, OptionalField::Present(99));
+		assert_eq!(get_route_scids!(100).unwrap(), vec![12, 13]);
+		// Smaller payments may still use the channel
+		assert_eq!(get_route_scids!(99).unwrap(), vec![2, 4]);
+		set_htlc_maximum!(&privkeys[1], 4, 3, (4 << 8) | 1, 0, 1000000, OptionalField::Present(100));
+		assert_eq!(get_route_scids!(100).unwrap(), vec![2, 4]);
+
+		// The maximum of channels from us applies to the amount including all fees
+		set_htlc_maximum!(&our_privkey, 2, 2, u16::max_value(), u32::max_value(), u32::max_value(), OptionalField::Present(199));
+		assert_eq!(get_route_scids!(100).unwrap(), vec![12, 13]);
+		set_htlc_maximum!(&our_privkey, 12, 2, u16::max_value(), u32::max_value(), u32::max_value(), OptionalField::Present(299));
+		assert!(get_route_scids!(100).is_err());
+		set_htlc_maximum!(&our_privkey, 12, 3, u16::max_value(), u32::max_value(), u32::max_value(), OptionalField::Absent);
+		assert_eq!(get_route_scids!(100).unwrap(), vec![12, 13]);
+	}
+
+	#[test]
+	fn channel_capacity_test() {
+		let secp_ctx = Secp256k1::new();
+		let logger = Arc::new(test_utils::TestLogger::new());
+		let chain_source = Arc::new(test_utils::TestChainSource::new(Network::Testnet));
+		let net_graph_msg_handler = NetGraphMsgHandler::new(genesis_block(Network::Testnet).header.block_hash(), Some(Arc::clone(&chain_source)), Arc::clone(&logger));
+		let (our_privkey, our_id, privkeys, nodes) = get_nodes(&secp_ctx);
+
+		// Build two routes from us to node2, one over channels 1 and 3, the other over the pricier
+		// channels 2 and 4:
+		//
+		//        -1(1)2-  node0  -1(3)2-
+		//       /                       \
+		// our_id                         node2
+		//       \                       /
+		//        -1(2)2-  node1  -1(4)2-
+		macro_rules! add_channel_with_capacity {
+			($node_1_privkey: expr, $node_2_privkey: expr, $short_channel_id: expr, $capacity_sats: expr, $fee_base_msat: expr, $htlc_maximum_msat: expr) => {
+				let funding_script = Builder::new().push_opcode(opcodes::all::OP_PUSHNUM_2)
+					.push_slice(&PublicKey::from_secret_key(&secp_ctx, $node_1_privkey).serialize())
+					.push_slice(&PublicKey::from_secret_key(&secp_ctx, $node_2_privkey).serialize())
+					.push_opcode(opcodes::all::OP_PUSHNUM_2)
+					.push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script().to_v0_p2wsh();
+				*chain_source.utxo_ret.lock().unwrap() = Ok(TxOut { value: $capacity_sats, script_pubkey: funding_script });
+				add_channel(&net_graph_msg_handler, &secp_ctx, $node_1_privkey, $node_2_privkey, ChannelFeatures::empty(), $short_channel_id);
+				update_channel(&net_graph_msg_handler, &secp_ctx, $node_1_privkey, UnsignedChannelUpdate {
+					chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+					short_channel_id: $short_channel_id,
+					timestamp: 1,
+					flags: 0,
+					cltv_expiry_delta: 0,
+					htlc_minimum_msat: 0,
+					htlc_maximum_msat: $htlc_maximum_msat,
+					fee_base_msat: $fee_base_msat,
+					fee_proportional_millionths: 0,
+					excess_data: Vec::new()
+				});
+			}
+		}
+		// Channel 1 reports an absurd capacity, as a broken UTXO provider might, which must not
+		// overflow when converted to msat.
+		add_channel_with_capacity!(&our_privkey, &privkeys[0], 1, u64::max_value(), 0, OptionalField::Absent);
+		add_channel_with_capacity!(&privkeys[0], &privkeys[2], 3, 1, 0, OptionalField::Absent);
+		add_channel_with_capacity!(&our_privkey, &privkeys[1], 2, 1_000_000, 0, OptionalField::Present(10_000_000));
+		add_channel_with_capacity!(&privkeys[1], &privkeys[2], 4, 1_000, 100, OptionalField::Present(900_000));
+
+		{
+			let network = net_graph_msg_handler.network_graph.read().unwrap();
+			assert_eq!(network.get_channels().get(&3).unwrap().capacity_sats, Some(1));
+			assert_eq!(network.get_channels().get(&4).unwrap().capacity_sats, Some(1_000));
+		}
+		macro_rules! get_route_scids {
+			($value_msat: expr) => {
+				get_route(&our_id, &net_graph_msg_handler.network_graph.read().unwrap(), &nodes[2], None, &Vec::new(), $value_msat, 42, Arc::clone(&logger))
+					.map(|route| route.paths[0].iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>())
+			}
+		}
+
+		// Channel 3 can only carry up to its 1 sat capacity
+		assert_eq!(get_route_scids!(1_000).unwrap(), vec![1, 3]);
+		assert_eq!(get_route_scids!(1_001).unwrap(), vec![2, 4]);
+		// Channel 4 is limited by its htlc_maximum_msat, below its capacity
+		assert_eq!(get_route_scids!(900_000).unwrap(), vec![2, 4]);
+		assert!(get_route_scids!(900_001).is_err());
+	}
+
+	#[test]
+	fn trampoline_route_test() {
+		let secp_ctx = Secp256k1::new();
+		let (_, our_id, _, nodes) = get_nodes(&secp_ctx);
+		let trampoline_fees = RoutingFees { base_msat: 1000, proportional_millionths: 100_000 };
+
+		let mut our_chans = vec![channelmanager::ChannelDetails {
+			channel_id: [0; 32],
+			short_channel_id: Some(42),
+			remote_network_id: nodes[0].clone(),
+			counterparty_features: InitFeatures::known(),
+			channel_value_satoshis: 0,
+			user_id: 0,
+			outbound_capacity_msat: 111_000,
+			inbound_capacity_msat: 0,
+			is_live: true,
+		}];
+
+		let route = get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[0], &trampoline_fees, 144, &nodes[1], 100_000, 42).unwrap();
+		assert_eq!(route.first_hop.pubkey, nodes[0]);
+		assert_eq!(route.first_hop.short_channel_id, 42);
+		assert_eq!(route.first_hop.fee_msat, 111_000);
+		assert_eq!(route.first_hop.cltv_expiry_delta, 42 + 144);
+		assert_eq!(route.trampoline_hops.len(), 1);
+		assert_eq!(route.trampoline_hops[0].pubkey, nodes[1]);
+		assert_eq!(route.trampoline_hops[0].fee_msat, 100_000);
+		assert_eq!(route.trampoline_hops[0].cltv_expiry_delta, 42);
+
+		// We can't use the trampoline node as the destination, nor route to ourselves
+		assert!(get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[0], &trampoline_fees, 144, &nodes[0], 100_000, 42).is_err());
+		assert!(get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[0], &trampoline_fees, 144, &our_id, 100_000, 42).is_err());
+		// We can't route via a node we have no channel with
+		assert!(get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[1], &trampoline_fees, 144, &nodes[2], 100_000, 42).is_err());
+		// The channel must be able to carry the payment and the trampoline fee
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[0], &trampoline_fees, 144, &nodes[1], 100_001, 42) {
+			assert_eq!(err, "No channel to the trampoline node has enough capacity for the payment");
+		} else { panic!(); }
+
+		// Nor can we route via a peer which doesn't support trampoline routing
+		our_chans[0].counterparty_features = InitFeatures::known().clear_trampoline_routing();
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_trampoline_route(&our_id, &our_chans.iter().collect::<Vec<_>>(), &nodes[0], &trampoline_fees, 144, &nodes[1], 100_000, 42) {
+			assert_eq!(err, "Trampoline node does not support trampoline routing");
+		} else { panic!(); }
+	}
+
+	#[test]
+	fn blinded_route_test() {
+		let (secp_ctx, net_graph_msg_handler, logger) = build_graph();
+		let (_, our_id, privkeys, nodes) = get_nodes(&secp_ctx);
+		let network = net_graph_msg_handler.network_graph.read().unwrap();
+
+		// The blinded node ids are opaque to the sender, so any keys will do here
+		let mut blinded_route = BlindedRoute {
+			introduction_node_id: nodes[2],
+			blinding_point: PublicKey::from_secret_key(&secp_ctx, &privkeys[5]),
+			blinded_hops: vec![BlindedHop {
+				blinded_node_id: PublicKey::from_secret_key(&secp_ctx, &privkeys[6]),
+				encrypted_data: vec![1; 32],
+				fees: RoutingFees { base_msat: 1000, proportional_millionths: 100_000 },
+				cltv_expiry_delta: 40,
+			}, BlindedHop {
+				blinded_node_id: PublicKey::from_secret_key(&secp_ctx, &privkeys[7]),
+				encrypted_data: vec![2; 32],
+				fees: RoutingFees { base_msat: 0, proportional_millionths: 0 },
+				cltv_expiry_delta: 0,
+			}],
+		};
+
+		let route = get_blinded_route(&our_id, &network, &blinded_route, None, 10_000, 42, Arc::clone(&logger)).unwrap();
+		let intro_route = get_route(&our_id, &network, &nodes[2], None, &Vec::new(), 12_000, 82, Arc::clone(&logger)).unwrap();
+		assert_eq!(route.paths.len(), 1);
+		assert_eq!(route.paths[0].len(), 3);
+		assert_eq!(route.paths[0][0], intro_route.paths[0][0]);
+
+		assert_eq!(route.paths[0][1].pubkey, nodes[2]);
+		assert_eq!(route.paths[0][1].short_channel_id, intro_route.paths[0][1].short_channel_id);
+		assert_eq!(route.paths[0][1].fee_msat, 2_000);
+		assert_eq!(route.paths[0][1].cltv_expiry_delta, 40);
+
+		assert_eq!(route.paths[0][2].pubkey, blinded_route.blinded_hops[1].blinded_node_id);
+		assert_eq!(route.paths[0][2].short_channel_id, 0);
+		assert_eq!(route.paths[0][2].fee_msat, 10_000);
+		assert_eq!(route.paths[0][2].cltv_expiry_delta, 42);
+
+		// Blinded routes survive a serialization round trip
+		let decoded: BlindedRoute = Readable::read(&mut ::std::io::Cursor::new(&blinded_route.encode()[..])).unwrap();
+		assert_eq!(decoded, blinded_route);
+
+		// A blinded route must have at least the recipient in it
+		blinded_route.blinded_hops.clear();
+		if let Err(LightningError{err, action: ErrorAction::IgnoreError}) = get_blinded_route(&our_id, &network, &blinded_route, None, 10_000, 42, Arc::clone(&logger)) {
+			assert_eq!(err, "Cannot route via a blinded route without any hops");
+		} else { panic!(); }
+	}
 }
@@ -0,0 +1,595 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! Scorers which penalize channels during pathfinding based on what we learned from past
+//! payments live here.
+//!
+//! A Score is consulted by get_route_with_scorer for every channel it considers, and is fed the
+//! outcome of each payment path by the user once the payment succeeds or fails, using the path
+//! and network update carried by Event::PaymentSent and Event::PaymentFailed.
+
+use bitcoin::secp256k1::key::PublicKey;
+
+use ln::msgs::{DecodeError, HTLCFailChannelUpdate, MAX_VALUE_MSAT};
+use routing::router::RouteHop;
+use util::ser::{Writeable, Readable, Writer};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An interface used to score payment channels for path finding.
+///
+/// Penalties are added to the fees of a path when comparing candidate paths, so a channel with a
+/// penalty of 1000 msat is only used if it saves more than a satoshi in fees over alternatives.
+/// Penalties never change the fees actually paid along the returned route.
+pub trait Score {
+	/// Returns the penalty, in msat, for sending amount_msat over the given channel from source to
+	/// target. capacity_msat is the channel's on-chain capacity, if known.
+	fn channel_penalty_msat(&self, short_channel_id: u64, source: &PublicKey, target: &PublicKey, amount_msat: u64, capacity_msat: Option<u64>) -> u64;
+
+	/// Handles a failure to pay along the given path, as reported by the HTLCFailChannelUpdate
+	/// generated for the failure (see the path and network_update of Event::PaymentFailed).
+	fn payment_path_failed(&mut self, path: &[RouteHop], update: &HTLCFailChannelUpdate);
+
+	/// Handles a successful payment along the given path (see the path of Event::PaymentSent).
+	fn payment_path_successful(&mut self, path: &[RouteHop]);
+
+	/// Handles a probe along the given path which failed at the given channel (see
+	/// Event::ProbeFailed).
+	fn probe_failed(&mut self, path: &[RouteHop], short_channel_id: u64);
+
+	/// Handles a probe which made it all the way along the given path (see
+	/// Event::ProbeSuccessful).
+	fn probe_successful(&mut self, path: &[RouteHop]);
+}
+
+/// Parameters for configuring a ProbabilisticScorer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProbabilisticScoringParameters {
+	/// A fixed penalty, in msat, applied to every channel, making shorter paths preferable when
+	/// fees are otherwise similar.
+	///
+	/// Default value: 500 msat
+	pub base_penalty_msat: u64,
+
+	/// The penalty, in msat, applied per factor of ten by which the estimated success probability
+	/// of sending the amount over a channel drops, ie multiplied by -log10(success_probability).
+	/// The penalty is capped at twice this value, which is reached once the success probability
+	/// is 1% or lower.
+	///
+	/// Default value: 40,000 msat
+	pub liquidity_penalty_multiplier_msat: u64,
+
+	/// The time, in seconds, after which what we learned about a channel's liquidity is only half
+	/// as certain. Every half-life, the lower bound on a channel's liquidity is halved and the
+	/// upper bound moves halfway back to the channel's capacity, as liquidity shifts with payments
+	/// we don't see.
+	///
+	/// Default value: 1 hour
+	pub liquidity_offset_half_life_secs: u64,
+}
+
+impl Default for ProbabilisticScoringParameters {
+	fn default() -> Self {
+		ProbabilisticScoringParameters {
+			base_penalty_msat: 500,
+			liquidity_penalty_multiplier_msat: 40_000,
+			liquidity_offset_half_life_secs: 60 * 60,
+		}
+	}
+}
+
+impl_writeable!(ProbabilisticScoringParameters, 8 * 3, {
+	base_penalty_msat,
+	liquidity_penalty_multiplier_msat,
+	liquidity_offset_half_life_secs
+});
+
+/// What we know of the liquidity available in one direction of a channel.
+///
+/// The upper bound decays towards the channel's capacity, which is only known when the channel is
+/// scored, so each bound is kept as observed along with the time it was last observed, and only
+/// decayed when it is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DirectedChannelLiquidity {
+	/// The largest amount we know could be sent, as of min_liquidity_updated.
+	min_liquidity_msat: u64,
+	/// The amount we know could not be sent, less one, as of max_liquidity_updated.
+	max_liquidity_msat: u64,
+	/// The unix time, in seconds, as of which min_liquidity_msat holds.
+	min_liquidity_updated: u64,
+	/// The unix time, in seconds, as of which max_liquidity_msat holds.
+	max_liquidity_updated: u64,
+}
+
+impl_writeable!(DirectedChannelLiquidity, 8 * 4, {
+	min_liquidity_msat,
+	max_liquidity_msat,
+	min_liquidity_updated,
+	max_liquidity_updated
+});
+
+/// Returns the number of whole half-lives elapsed between last_updated and current_time.
+fn half_lives_since(last_updated: u64, half_life_secs: u64, current_time: u64) -> u64 {
+	current_time.saturating_sub(last_updated) / cmp::max(half_life_secs, 1)
+}
+
+/// Halves value once for each half-life elapsed.
+fn decay_value(value: u64, half_lives: u64) -> u64 {
+	if half_lives >= 64 { 0 } else { value >> half_lives }
+}
+
+impl DirectedChannelLiquidity {
+	fn new(current_time: u64) -> Self {
+		DirectedChannelLiquidity {
+			min_liquidity_msat: 0,
+			max_liquidity_msat: u64::max_value(),
+			min_liquidity_updated: current_time,
+			max_liquidity_updated: current_time,
+		}
+	}
+
+	/// Returns the lower bound decayed to the given time.
+	fn decayed_min_liquidity_msat(&self, half_life_secs: u64, current_time: u64) -> u64 {
+		decay_value(self.min_liquidity_msat, half_lives_since(self.min_liquidity_updated, half_life_secs, current_time))
+	}
+
+	/// Returns the bounds decayed to the given time for a channel of the given capacity. The upper
+	/// bound's offset from the capacity is halved every half-life, so it decays towards the
+	/// capacity even after the channel was found unable to forward anything.
+	fn decayed_bounds(&self, half_life_secs: u64, capacity_msat: u64, current_time: u64) -> (u64, u64) {
+		let max_liquidity_offset_msat = capacity_msat - cmp::min(self.max_liquidity_msat, capacity_msat);
+		let half_lives = half_lives_since(self.max_liquidity_updated, half_life_secs, current_time);
+		let max_liquidity_msat = capacity_msat - decay_value(max_liquidity_offset_msat, half_lives);
+		let min_liquidity_msat = self.decayed_min_liquidity_msat(half_life_secs, current_time);
+		(cmp::min(min_liquidity_msat, max_liquidity_msat), max_liquidity_msat)
+	}
+
+	/// Sets the lower bound as of the given time. Its time is only moved forward by the whole
+	/// half-lives it was decayed by, so that frequent updates don't keep it from decaying.
+	fn set_min_liquidity_msat(&mut self, min_liquidity_msat: u64, half_life_secs: u64, current_time: u64) {
+		let half_lives = half_lives_since(self.min_liquidity_updated, half_life_secs, current_time);
+		if min_liquidity_msat > decay_value(self.min_liquidity_msat, half_lives) {
+			self.min_liquidity_updated = cmp::max(self.min_liquidity_updated, current_time);
+		} else {
+			self.min_liquidity_updated += half_lives * cmp::max(half_life_secs, 1);
+		}
+		self.min_liquidity_msat = min_liquidity_msat;
+	}
+
+	/// Records that amount_msat could be sent over the channel at the given time.
+	fn successful(&mut self, amount_msat: u64, half_life_secs: u64, current_time: u64) {
+		let min_liquidity_msat = cmp::max(self.decayed_min_liquidity_msat(half_life_secs, current_time), amount_msat);
+		self.set_min_liquidity_msat(min_liquidity_msat, half_life_secs, current_time);
+		if self.max_liquidity_msat < min_liquidity_msat {
+			// Liquidity shifted since we learned the upper bound, so it no longer tells us much.
+			self.max_liquidity_msat = u64::max_value();
+			self.max_liquidity_updated = cmp::max(self.max_liquidity_updated, current_time);
+		}
+	}
+
+	/// Records that amount_msat could not be sent over the channel at the given time.
+	fn failed(&mut self, amount_msat: u64, half_life_secs: u64, current_time: u64) {
+		let max_liquidity_msat = amount_msat.saturating_sub(1);
+		// The upper bound only ever decays upwards, so a failure below the bound we know of is
+		// tighter whatever it decayed to. Otherwise, we keep decaying the known bound, which may
+		// be somewhat looser than the failure by now.
+		if max_liquidity_msat <= self.max_liquidity_msat {
+			self.max_liquidity_msat = max_liquidity_msat;
+			self.max_liquidity_updated = cmp::max(self.max_liquidity_updated, current_time);
+		}
+		let min_liquidity_msat = cmp::min(self.decayed_min_liquidity_msat(half_life_secs, current_time), max_liquidity_msat);
+		self.set_min_liquidity_msat(min_liquidity_msat, half_life_secs, current_time);
+	}
+}
+
+/// A Score which estimates the probability of successfully sending an amount over a channel and
+/// penalizes channels accordingly.
+///
+/// The liquidity available in each direction of a channel is assumed to be uniformly distributed
+/// between bounds learned from past payments: a payment which made it over a channel raises the
+/// lower bound, while one which failed at a channel lowers its upper bound. Initially, the bounds
+/// are zero and the channel's capacity. As these observations become stale, the bounds decay back
+/// towards their initial values (see ProbabilisticScoringParameters::liquidity_offset_half_life_secs).
+pub struct ProbabilisticScorer {
+	params: ProbabilisticScoringParameters,
+	/// Liquidity bounds by short_channel_id and the node at the receiving end of the direction.
+	channel_liquidities: HashMap<(u64, PublicKey), DirectedChannelLiquidity>,
+}
+
+impl ProbabilisticScorer {
+	/// Creates a new scorer, with no knowledge of any channel's liquidity.
+	pub fn new(params: ProbabilisticScoringParameters) -> Self {
+		ProbabilisticScorer {
+			params,
+			channel_liquidities: HashMap::new(),
+		}
+	}
+
+	fn channel_penalty_msat_with_time(&self, short_channel_id: u64, target: &PublicKey, amount_msat: u64, capacity_msat: Option<u64>, current_time: u64) -> u64 {
+		let capacity_msat = capacity_msat.unwrap_or(MAX_VALUE_MSAT);
+		let (min_liquidity_msat, max_liquidity_msat) = match self.channel_liquidities.get(&(short_channel_id, *target)) {
+			Some(liquidity) => liquidity.decayed_bounds(self.params.liquidity_offset_half_life_secs, capacity_msat, current_time),
+			None => (0, capacity_msat),
+		};
+
+		let max_penalty_msat = self.params.liquidity_penalty_multiplier_msat.saturating_mul(2);
+		let liquidity_penalty_msat = if amount_msat <= min_liquidity_msat {
+			0
+		} else if amount_msat > max_liquidity_msat {
+			max_penalty_msat
+		} else {
+			let success_probability = ((max_liquidity_msat - amount_msat) as f64 + 1.0) / ((max_liquidity_msat - min_liquidity_msat) as f64 + 1.0);
+			let penalty = -success_probability.log10() * self.params.liquidity_penalty_multiplier_msat as f64;
+			if penalty >= max_penalty_msat as f64 { max_penalty_msat } else { penalty as u64 }
+		};
+		self.params.base_penalty_msat.saturating_add(liquidity_penalty_msat)
+	}
+
+	/// Applies f to the liquidity of each channel in the path along with the amount sent over it,
+	/// stopping after the channel for which f returns false.
+	fn update_path_with_time<F: FnMut(&mut DirectedChannelLiquidity, &RouteHop, u64) -> bool>(&mut self, path: &[RouteHop], current_time: u64, mut f: F) {
+		// The amount sent over the channel to each hop is the value received by the final hop plus
+		// the fees of every hop following it.
+		let mut amount_msat = path.iter().fold(0u64, |total, hop| total.saturating_add(hop.fee_msat));
+		for hop in path.iter() {
+			let liquidity = self.channel_liquidities.entry((hop.short_channel_id, hop.pubkey))
+				.or_insert_with(|| DirectedChannelLiquidity::new(current_time));
+			if !f(liquidity, hop, amount_msat) {
+				return;
+			}
+			amount_msat = amount_msat.saturating_sub(hop.fee_msat);
+		}
+	}
+
+	fn payment_path_failed_with_time(&mut self, path: &[RouteHop], update: &HTLCFailChannelUpdate, current_time: u64) {
+		let (failed_scid, failed_amount_msat) = match update {
+			&HTLCFailChannelUpdate::ChannelUpdateMessage { ref msg } => (msg.contents.short_channel_id, None),
+			// A closed channel can't be used for any amount
+			&HTLCFailChannelUpdate::ChannelClosed { short_channel_id, .. } => (short_channel_id, Some(0)),
+			&HTLCFailChannelUpdate::NodeFailure { ref node_id, .. } => {
+				// The HTLC made it to the failing node, so the channels up to it had enough
+				// liquidity, but we learn nothing about the channels after it.
+				if path.iter().any(|hop| hop.pubkey == *node_id) {
+					let half_life_secs = self.params.liquidity_offset_half_life_secs;
+					self.update_path_with_time(path, current_time, |liquidity, hop, amount_msat| {
+						liquidity.successful(amount_msat, half_life_secs, current_time);
+						hop.pubkey != *node_id
+					});
+				}
+				return;
+			},
+		};
+		self.channel_failed_with_time(path, failed_scid, failed_amount_msat, current_time);
+	}
+
+	/// Records that the given channel in the path could not forward failed_amount_msat (or the
+	/// amount sent over it, if None), while the channels before it could.
+	fn channel_failed_with_time(&mut self, path: &[RouteHop], failed_scid: u64, failed_amount_msat: Option<u64>, current_time: u64) {
+		if !path.iter().any(|hop| hop.short_channel_id == failed_scid) {
+			return;
+		}
+		let half_life_secs = self.params.liquidity_offset_half_life_secs;
+		self.update_path_with_time(path, current_time, |liquidity, hop, amount_msat| {
+			if hop.short_channel_id == failed_scid {
+				liquidity.failed(failed_amount_msat.unwrap_or(amount_msat), half_life_secs, current_time);
+				false
+			} else {
+				liquidity.successful(amount_msat, half_life_secs, current_time);
+				true
+			}
+		});
+	}
+
+	fn payment_path_successful_with_time(&mut self, path: &[RouteHop], current_time: u64) {
+		let half_life_secs = self.params.liquidity_offset_half_life_secs;
+		self.update_path_with_time(path, current_time, |liquidity, _, amount_msat| {
+			liquidity.successful(amount_msat, half_life_secs, current_time);
+			true
+		});
+	}
+}
+
+impl Default for ProbabilisticScorer {
+	fn default() -> Self {
+		ProbabilisticScorer::new(ProbabilisticScoringParameters::default())
+	}
+}
+
+fn current_time() -> u64 {
+	match SystemTime::now().duration_since(UNIX_EPOCH) {
+		Ok(duration) => duration.as_secs(),
+		Err(_) => 0,
+	}
+}
+
+impl Score for ProbabilisticScorer {
+	fn channel_penalty_msat(&self, short_channel_id: u64, _source: &PublicKey, target: &PublicKey, amount_msat: u64, capacity_msat: Option<u64>) -> u64 {
+		self.channel_penalty_msat_with_time(short_channel_id, target, amount_msat, capacity_msat, current_time())
+	}
+
+	fn payment_path_failed(&mut self, path: &[RouteHop], update: &HTLCFailChannelUpdate) {
+		self.payment_path_failed_with_time(path, update, current_time())
+	}
+
+	fn payment_path_successful(&mut self, path: &[RouteHop]) {
+		self.payment_path_successful_with_time(path, current_time())
+	}
+
+	fn probe_failed(&mut self, path: &[RouteHop], short_channel_id: u64) {
+		self.channel_failed_with_time(path, short_channel_id, None, current_time())
+	}
+
+	fn probe_successful(&mut self, path: &[RouteHop]) {
+		self.payment_path_successful_with_time(path, current_time())
+	}
+}
+
+impl Writeable for ProbabilisticScorer {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ::std::io::Error> {
+		self.params.write(writer)?;
+		(self.channel_liquidities.len() as u64).write(writer)?;
+		for (&(short_channel_id, ref target), liquidity) in self.channel_liquidities.iter() {
+			short_channel_id.write(writer)?;
+			target.write(writer)?;
+			liquidity.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for ProbabilisticScorer {
+	fn read<R: ::std::io::Read>(reader: &mut R) -> Result<ProbabilisticScorer, DecodeError> {
+		let params = Readable::read(reader)?;
+		let liquidities_count: u64 = Readable::read(reader)?;
+		let mut channel_liquidities = HashMap::new();
+		for _ in 0..liquidities_count {
+			let short_channel_id: u64 = Readable::read(reader)?;
+			let target: PublicKey = Readable::read(reader)?;
+			let liquidity = Readable::read(reader)?;
+			channel_liquidities.insert((short_channel_id, target), liquidity);
+		}
+		Ok(ProbabilisticScorer {
+			params,
+			channel_liquidities,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use routing::scorer::{ProbabilisticScorer, ProbabilisticScoringParameters, Score};
+	use routing::router::RouteHop;
+	use ln::features::{ChannelFeatures, NodeFeatures};
+	use ln::msgs::{ChannelUpdate, HTLCFailChannelUpdate, OptionalField, UnsignedChannelUpdate};
+	use util::ser::{Readable, Writeable};
+
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::hashes::Hash;
+	use bitcoin::network::constants::Network;
+	use bitcoin::blockdata::constants::genesis_block;
+
+	use bitcoin::secp256k1::key::{PublicKey, SecretKey};
+	use bitcoin::secp256k1::Secp256k1;
+
+	const NOW: u64 = 1_600_000_000;
+	const HALF_LIFE: u64 = 60 * 60;
+	const CAPACITY_MSAT: Option<u64> = Some(1_000_000);
+
+	fn node_id(n: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[n; 32]).unwrap())
+	}
+
+	fn get_scorer() -> ProbabilisticScorer {
+		ProbabilisticScorer::new(ProbabilisticScoringParameters {
+			base_penalty_msat: 500,
+			liquidity_penalty_multiplier_msat: 1000,
+			liquidity_offset_half_life_secs: HALF_LIFE,
+		})
+	}
+
+	/// A path over channels 1, 2 and 3 to nodes 1, 2 and 3, delivering 100_000 msat and paying a
+	/// 1000 msat fee at each of nodes 1 and 2.
+	fn get_path() -> Vec<RouteHop> {
+		(1..4).map(|n| RouteHop {
+			pubkey: node_id(n),
+			node_features: NodeFeatures::empty(),
+			short_channel_id: n as u64,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat: if n == 3 { 100_000 } else { 1000 },
+			cltv_expiry_delta: 18,
+		}).collect()
+	}
+
+	fn get_channel_update(short_channel_id: u64) -> ChannelUpdate {
+		let secp_ctx = Secp256k1::new();
+		let contents = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Testnet).header.block_hash(),
+			short_channel_id,
+			timestamp: 1,
+			flags: 0,
+			cltv_expiry_delta: 18,
+			htlc_minimum_msat: 0,
+			htlc_maximum_msat: OptionalField::Absent,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 0,
+			excess_data: Vec::new(),
+		};
+		let msghash = hash_to_message!(&Sha256dHash::hash(&contents.encode()[..])[..]);
+		ChannelUpdate {
+			signature: secp_ctx.sign(&msghash, &SecretKey::from_slice(&[2; 32]).unwrap()),
+			contents,
+		}
+	}
+
+	fn penalty(scorer: &ProbabilisticScorer, n: u8, amount_msat: u64, time: u64) -> u64 {
+		scorer.channel_penalty_msat_with_time(n as u64, &node_id(n), amount_msat, CAPACITY_MSAT, time)
+	}
+
+	#[test]
+	fn penalizes_by_success_probability() {
+		let scorer = get_scorer();
+		assert_eq!(penalty(&scorer, 1, 0, NOW), 500);
+		// -log10(500_001 / 1_000_001) * 1000 ~= 301
+		assert_eq!(penalty(&scorer, 1, 500_000, NOW), 500 + 301);
+		// -log10(100_001 / 1_000_001) * 1000 ~= 1000
+		assert_eq!(penalty(&scorer, 1, 900_000, NOW), 500 + 999);
+		// The liquidity penalty is capped at twice the multiplier
+		assert_eq!(penalty(&scorer, 1, 999_999, NOW), 500 + 2000);
+		assert_eq!(penalty(&scorer, 1, 1_000_001, NOW), 500 + 2000);
+
+		// Without a known capacity, any amount is presumed likely to succeed
+		assert_eq!(scorer.channel_penalty_msat_with_time(1, &node_id(1), 900_000, None, NOW), 500);
+	}
+
+	#[test]
+	fn learns_from_failed_channel() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::ChannelUpdateMessage { msg: get_channel_update(2) }, NOW);
+
+		// Channel 1 forwarded 102_000 msat, so it's certain to forward up to that
+		assert_eq!(penalty(&scorer, 1, 102_000, NOW), 500);
+		// -log10(449_001 / 898_001) * 1000 ~= 301
+		assert_eq!(penalty(&scorer, 1, 551_000, NOW), 500 + 301);
+		// Channel 2 couldn't forward 101_000 msat, so it's very unlikely to forward as much
+		assert_eq!(penalty(&scorer, 2, 101_000, NOW), 500 + 2000);
+		// -log10(51_000 / 101_000) * 1000 ~= 296
+		assert_eq!(penalty(&scorer, 2, 50_000, NOW), 500 + 296);
+		// We learn nothing about the channel past the failure
+		assert_eq!(penalty(&scorer, 3, 100_000, NOW), penalty(&get_scorer(), 3, 100_000, NOW));
+		// Nor about the opposite direction of a channel
+		assert_eq!(scorer.channel_penalty_msat_with_time(2, &node_id(1), 101_000, CAPACITY_MSAT, NOW),
+			penalty(&get_scorer(), 2, 101_000, NOW));
+
+		// A closed channel fails any amount
+		scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 3, is_permanent: false }, NOW);
+		assert_eq!(penalty(&scorer, 3, 1, NOW), 500 + 2000);
+		// The path made it past channel 2 this time
+		assert_eq!(penalty(&scorer, 2, 101_000, NOW), 500);
+
+		// Failures of channels not in the path are ignored
+		let mut other_scorer = get_scorer();
+		other_scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 4, is_permanent: false }, NOW);
+		assert_eq!(penalty(&other_scorer, 1, 102_000, NOW), penalty(&get_scorer(), 1, 102_000, NOW));
+	}
+
+	#[test]
+	fn learns_from_failed_node_and_success() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::NodeFailure { node_id: node_id(2), is_permanent: false }, NOW);
+		assert_eq!(penalty(&scorer, 1, 102_000, NOW), 500);
+		assert_eq!(penalty(&scorer, 2, 101_000, NOW), 500);
+		assert_eq!(penalty(&scorer, 3, 100_000, NOW), penalty(&get_scorer(), 3, 100_000, NOW));
+
+		scorer.payment_path_successful_with_time(&path, NOW);
+		assert_eq!(penalty(&scorer, 3, 100_000, NOW), 500);
+
+		// A later failure for a larger amount keeps what we know could be sent
+		let mut larger_path = path.clone();
+		larger_path[2].fee_msat = 200_000;
+		scorer.payment_path_failed_with_time(&larger_path, &HTLCFailChannelUpdate::ChannelUpdateMessage { msg: get_channel_update(3) }, NOW);
+		assert_eq!(penalty(&scorer, 3, 100_000, NOW), 500);
+		assert_eq!(penalty(&scorer, 3, 200_000, NOW), 500 + 2000);
+	}
+
+	#[test]
+	fn learns_from_probes() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.channel_failed_with_time(&path, 2, None, NOW);
+		assert_eq!(penalty(&scorer, 1, 102_000, NOW), 500);
+		assert_eq!(penalty(&scorer, 2, 101_000, NOW), 500 + 2000);
+		assert_eq!(penalty(&scorer, 3, 100_000, NOW), penalty(&get_scorer(), 3, 100_000, NOW));
+
+		let mut scorer = get_scorer();
+		scorer.probe_successful(&path);
+		// The scorer ignores the source of each hop
+		let source = node_id(42);
+		for n in 1..4 {
+			assert_eq!(scorer.channel_penalty_msat(n as u64, &source, &node_id(n), 100_000, CAPACITY_MSAT), 500);
+		}
+	}
+
+	#[test]
+	fn decays_liquidity_bounds() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::ChannelUpdateMessage { msg: get_channel_update(2) }, NOW);
+		assert_eq!(penalty(&scorer, 1, 102_000, NOW + HALF_LIFE - 1), 500);
+		assert_eq!(penalty(&scorer, 2, 101_000, NOW + HALF_LIFE - 1), 500 + 2000);
+
+		// After one half-life, the lower bound is halved and the upper bound moves halfway to the
+		// capacity, ie to 550_500 msat
+		assert_eq!(penalty(&scorer, 1, 51_000, NOW + HALF_LIFE), 500);
+		assert!(penalty(&scorer, 1, 551_000, NOW + HALF_LIFE) > penalty(&scorer, 1, 551_000, NOW));
+		assert!(penalty(&scorer, 2, 101_000, NOW + HALF_LIFE) < 500 + 2000);
+		// -log10(250_501 / 550_501) * 1000 ~= 341
+		assert_eq!(penalty(&scorer, 2, 300_000, NOW + HALF_LIFE), 500 + 341);
+		assert_eq!(penalty(&scorer, 2, 550_501, NOW + HALF_LIFE), 500 + 2000);
+
+		// Eventually, we forget what we learned entirely
+		for &(n, amount_msat) in [(1, 102_000), (2, 101_000)].iter() {
+			assert_eq!(penalty(&scorer, n, amount_msat, NOW + 64 * HALF_LIFE), penalty(&get_scorer(), n, amount_msat, NOW));
+		}
+
+		// New observations apply on top of the decayed bounds
+		scorer.payment_path_successful_with_time(&path, NOW + HALF_LIFE);
+		assert_eq!(penalty(&scorer, 1, 102_000, NOW + HALF_LIFE), 500);
+	}
+
+	#[test]
+	fn decays_closed_channel_to_capacity() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.payment_path_failed_with_time(&path, &HTLCFailChannelUpdate::ChannelClosed { short_channel_id: 3, is_permanent: false }, NOW);
+		assert_eq!(penalty(&scorer, 3, 1, NOW), 500 + 2000);
+
+		// A channel which couldn't forward anything recovers half its capacity every half-life
+		assert_eq!(penalty(&scorer, 3, 500_001, NOW + HALF_LIFE), 500 + 2000);
+		// -log10(250_001 / 500_001) * 1000 ~= 301
+		assert_eq!(penalty(&scorer, 3, 250_000, NOW + HALF_LIFE), 500 + 301);
+		// -log10(500_001 / 750_001) * 1000 ~= 176
+		assert_eq!(penalty(&scorer, 3, 250_000, NOW + 2 * HALF_LIFE), 500 + 176);
+		assert_eq!(penalty(&scorer, 3, 250_000, NOW + 64 * HALF_LIFE), penalty(&get_scorer(), 3, 250_000, NOW));
+	}
+
+	#[test]
+	fn decays_despite_frequent_updates() {
+		let mut scorer = get_scorer();
+		let path = get_path();
+		scorer.payment_path_successful_with_time(&path, NOW);
+		let mut reference_scorer = get_scorer();
+		reference_scorer.payment_path_successful_with_time(&path, NOW);
+
+		// Updates which don't raise the lower bound don't hold off its decay
+		let mut smaller_path = path.clone();
+		smaller_path[2].fee_msat = 1;
+		for i in 1..4 {
+			scorer.payment_path_successful_with_time(&smaller_path, NOW + i * HALF_LIFE / 2 - 1);
+		}
+		assert!(penalty(&scorer, 3, 500_000, NOW + 2 * HALF_LIFE) > penalty(&scorer, 3, 500_000, NOW));
+		assert_eq!(penalty(&scorer, 3, 500_000, NOW + 2 * HALF_LIFE), penalty(&reference_scorer, 3, 500_000, NOW + 2 * HALF_LIFE));
+	}
+
+	#[test]
+	fn scorer_serialization() {
+		let mut scorer = get_scorer();
+		scorer.payment_path_failed_with_time(&get_path(), &HTLCFailChannelUpdate::ChannelUpdateMessage { msg: get_channel_update(2) }, NOW);
+		let encoded = scorer.encode();
+		let decoded = <ProbabilisticScorer>::read(&mut ::std::io::Cursor::new(&encoded)).unwrap();
+		assert_eq!(decoded.params, scorer.params);
+		assert_eq!(decoded.channel_liquidities, scorer.channel_liquidities);
+		for n in 1..4 {
+			assert_eq!(penalty(&decoded, n, 101_000, NOW), penalty(&scorer, n, 101_000, NOW));
+		}
+	}
+}
@@ -217,6 +217,14 @@ pub struct UserConfig {
 	pub peer_channel_config_limits: ChannelHandshakeLimits,
 	/// Channel config which affects behavior during channel lifetime.
 	pub channel_options: ChannelConfig,
+	/// If set, we advertise trampoline_routing and act as a trampoline node, finding routes for
+	/// HTLCs which ask us to forward them to another node. Doing so needs a reasonably complete
+	/// network graph, so this should only be set on nodes which sync one.
+	///
+	/// If unset, HTLCs asking us to act as a trampoline node are failed back immediately.
+	///
+	/// Default value: false.
+	pub accept_trampoline_forwards: bool,
 }
 
 impl Default for UserConfig {
@@ -225,6 +233,7 @@ impl Default for UserConfig {
 			own_channel_config: ChannelHandshakeConfig::default(),
 			peer_channel_config_limits: ChannelHandshakeLimits::default(),
 			channel_options: ChannelConfig::default(),
+			accept_trampoline_forwards: false,
 		}
 	}
 }
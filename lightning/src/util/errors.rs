@@ -62,7 +62,7 @@ impl fmt::Debug for APIError {
 #[inline]
 pub(crate) fn get_onion_debug_field(error_code: u16) -> (&'static str, usize) {
 	match error_code & 0xff {
-		4|5|6 => ("sha256_of_onion", 32),
+		4|5|6|24 => ("sha256_of_onion", 32),
 		11|12 => ("htlc_msat", 8),
 		13|18 => ("cltv_expiry", 4),
 		19 => ("incoming_htlc_msat", 8),
@@ -100,6 +100,9 @@ pub(crate) fn get_onion_error_description(error_code: u16) -> (&'static str, &'s
 		_c if _c == 19 => ("The final node indicated the amount in the HTLC does not match the value in the onion", "final_incorrect_htlc_amount"),
 		_c if _c == UPDATE|20 => ("Node indicated the outbound channel has been disabled", "channel_disabled"),
 		_c if _c == 21 => ("Node indicated the CLTV expiry in the HTLC is too far in the future", "expiry_too_far"),
+		_c if _c == BADONION|PERM|24 => ("Node indicated the HTLC could not be processed within a blinded route", "invalid_onion_blinding"),
+		_c if _c == NODE|51 => ("The trampoline node indicated the fee left to it does not cover the route it found", "trampoline_fee_insufficient"),
+		_c if _c == NODE|52 => ("The trampoline node indicated the CLTV delta left to it does not cover the route it found", "trampoline_expiry_too_soon"),
 		_ => ("Unknown", ""),
 	}
 }
@@ -16,6 +16,7 @@
 
 use ln::msgs;
 use ln::channelmanager::{PaymentPreimage, PaymentHash, PaymentSecret};
+use routing::router::RouteHop;
 use chain::transaction::OutPoint;
 use chain::keysinterface::SpendableOutputDescriptor;
 use util::ser::{Writeable, Writer, MaybeReadable, Readable};
@@ -91,11 +92,15 @@ pub enum Event {
 	/// and we got back the payment preimage for it).
 	/// Note that duplicative PaymentSent Events may be generated - it is your responsibility to
 	/// deduplicate them by payment_preimage (which MUST be unique)!
+	/// A payment sent over several paths generates one PaymentSent per path.
 	PaymentSent {
 		/// The preimage to the hash given to ChannelManager::send_payment.
 		/// Note that this serves as a payment receipt, if you wish to have such a thing, you must
 		/// store it somehow!
 		payment_preimage: PaymentPreimage,
+		/// The path the payment (or this part of it) was sent along, which may be passed to
+		/// routing::scorer::Score::payment_path_successful.
+		path: Vec<RouteHop>,
 	},
 	/// Indicates an outbound payment we made failed. Probably some intermediary node dropped
 	/// something. You may wish to retry with a different route.
@@ -108,11 +113,36 @@ pub enum Event {
 		/// the payment has failed, not just the route in question. If this is not set, you may
 		/// retry the payment via a different route.
 		rejected_by_dest: bool,
+		/// The path the failed payment (or part of it) was sent along.
+		path: Vec<RouteHop>,
+		/// The update to our network graph implied by the failure, if any. It is also provided to
+		/// the RoutingMessageHandler by way of a MessageSendEvent::PaymentFailureNetworkUpdate,
+		/// and may be passed along with the path to routing::scorer::Score::payment_path_failed.
+		network_update: Option<msgs::HTLCFailChannelUpdate>,
 #[cfg(test)]
 		error_code: Option<u16>,
 #[cfg(test)]
 		error_data: Option<Vec<u8>>,
 	},
+	/// Indicates a probe sent via ChannelManager::send_probe reached its destination, ie every
+	/// channel along the path had enough liquidity to forward it.
+	/// Probes never generate PaymentSent or PaymentFailed events.
+	ProbeSuccessful {
+		/// The hash which was returned by ChannelManager::send_probe.
+		payment_hash: PaymentHash,
+		/// The path the probe was sent along.
+		path: Vec<RouteHop>,
+	},
+	/// Indicates a probe sent via ChannelManager::send_probe failed before reaching its
+	/// destination.
+	ProbeFailed {
+		/// The hash which was returned by ChannelManager::send_probe.
+		payment_hash: PaymentHash,
+		/// The path the probe was sent along.
+		path: Vec<RouteHop>,
+		/// The channel which failed to forward the probe, if it could be determined.
+		short_channel_id: Option<u64>,
+	},
 	/// Used to indicate that ChannelManager::process_pending_htlc_forwards should be called at a
 	/// time in the future.
 	PendingHTLCsForwardable {
@@ -151,11 +181,12 @@ impl Writeable for Event {
 				payment_secret.write(writer)?;
 				amt.write(writer)?;
 			},
-			&Event::PaymentSent { ref payment_preimage } => {
+			&Event::PaymentSent { ref payment_preimage, ref path } => {
 				3u8.write(writer)?;
 				payment_preimage.write(writer)?;
+				path.write(writer)?;
 			},
-			&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest,
+			&Event::PaymentFailed { ref payment_hash, ref rejected_by_dest, ref path, ref network_update,
 				#[cfg(test)]
 				ref error_code,
 				#[cfg(test)]
@@ -164,6 +195,8 @@ impl Writeable for Event {
 				4u8.write(writer)?;
 				payment_hash.write(writer)?;
 				rejected_by_dest.write(writer)?;
+				path.write(writer)?;
+				network_update.write(writer)?;
 				#[cfg(test)]
 				error_code.write(writer)?;
 				#[cfg(test)]
@@ -181,6 +214,17 @@ impl Writeable for Event {
 					output.write(writer)?;
 				}
 			},
+			&Event::ProbeSuccessful { ref payment_hash, ref path } => {
+				7u8.write(writer)?;
+				payment_hash.write(writer)?;
+				path.write(writer)?;
+			},
+			&Event::ProbeFailed { ref payment_hash, ref path, ref short_channel_id } => {
+				8u8.write(writer)?;
+				payment_hash.write(writer)?;
+				path.write(writer)?;
+				short_channel_id.write(writer)?;
+			},
 		}
 		Ok(())
 	}
@@ -200,10 +244,13 @@ impl MaybeReadable for Event {
 				})),
 			3u8 => Ok(Some(Event::PaymentSent {
 					payment_preimage: Readable::read(reader)?,
+					path: Readable::read(reader)?,
 				})),
 			4u8 => Ok(Some(Event::PaymentFailed {
 					payment_hash: Readable::read(reader)?,
 					rejected_by_dest: Readable::read(reader)?,
+					path: Readable::read(reader)?,
+					network_update: Readable::read(reader)?,
 					#[cfg(test)]
 					error_code: Readable::read(reader)?,
 					#[cfg(test)]
@@ -220,6 +267,15 @@ impl MaybeReadable for Event {
 				}
 				Ok(Some(Event::SpendableOutputs { outputs }))
 			},
+			7u8 => Ok(Some(Event::ProbeSuccessful {
+					payment_hash: Readable::read(reader)?,
+					path: Readable::read(reader)?,
+				})),
+			8u8 => Ok(Some(Event::ProbeFailed {
+					payment_hash: Readable::read(reader)?,
+					path: Readable::read(reader)?,
+					short_channel_id: Readable::read(reader)?,
+				})),
 			_ => Err(msgs::DecodeError::InvalidValue)
 		}
 	}
@@ -21,6 +21,7 @@ pub(crate) mod chacha20;
 #[cfg(not(feature = "fuzztarget"))]
 pub(crate) mod poly1305;
 pub(crate) mod chacha20poly1305rfc;
+pub(crate) mod zlib;
 pub(crate) mod transaction_utils;
 
 #[macro_use]
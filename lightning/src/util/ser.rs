@@ -424,6 +424,7 @@ impl_array!(16); // for IPv6
 impl_array!(32); // for channel id & hmac
 impl_array!(33); // for PublicKey
 impl_array!(64); // for Signature
+impl_array!(400); // for TrampolineOnionPacket.hop_data
 impl_array!(1300); // for OnionPacket.hop_data
 
 // HashMap
@@ -113,6 +113,28 @@ macro_rules! decode_tlv {
 	} }
 }
 
+/// Writes the version of a serialization format followed by the minimum version which can read
+/// it, to be checked by read_ver_prefix.
+macro_rules! write_ver_prefix {
+	($stream: expr, $current_version: expr, $min_version_that_can_read_this: expr) => {
+		$stream.write_all(&[$current_version; 1])?;
+		$stream.write_all(&[$min_version_that_can_read_this; 1])?;
+	}
+}
+
+/// Reads a version prefix written by write_ver_prefix, failing with UnknownVersion if the data
+/// requires a version newer than ours. Evaluates to the version the data was written with.
+macro_rules! read_ver_prefix {
+	($stream: expr, $our_current_version: expr) => { {
+		let ver: u8 = ::util::ser::Readable::read($stream)?;
+		let min_ver: u8 = ::util::ser::Readable::read($stream)?;
+		if min_ver > $our_current_version {
+			return Err(::ln::msgs::DecodeError::UnknownVersion);
+		}
+		ver
+	} }
+}
+
 macro_rules! impl_writeable {
 	($st:ident, $len: expr, {$($field:ident),*}) => {
 		impl ::util::ser::Writeable for $st {
@@ -14,6 +14,7 @@ use chain::chainmonitor;
 use chain::channelmonitor;
 use chain::channelmonitor::MonitorEvent;
 use chain::transaction::OutPoint;
+use chain::watchtower;
 use chain::keysinterface;
 use ln::features::{ChannelFeatures, InitFeatures};
 use ln::msgs;
@@ -63,6 +64,8 @@ impl chaininterface::FeeEstimator for TestFeeEstimator {
 pub struct TestChainMonitor<'a> {
 	pub added_monitors: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitor<EnforcingChannelKeys>)>>,
 	pub latest_monitor_update_id: Mutex<HashMap<[u8; 32], (OutPoint, u64)>>,
+	/// Every update applied, in order, for replaying into e.g. a watchtower client.
+	pub applied_updates: Mutex<Vec<(OutPoint, channelmonitor::ChannelMonitorUpdate)>>,
 	pub chain_monitor: chainmonitor::ChainMonitor<EnforcingChannelKeys, &'a TestChainSource, &'a chaininterface::BroadcasterInterface, &'a TestFeeEstimator, &'a TestLogger>,
 	pub update_ret: Mutex<Result<(), channelmonitor::ChannelMonitorUpdateErr>>,
 	// If this is set to Some(), after the next return, we'll always return this until update_ret
@@ -74,6 +77,7 @@ impl<'a> TestChainMonitor<'a> {
 		Self {
 			added_monitors: Mutex::new(Vec::new()),
 			latest_monitor_update_id: Mutex::new(HashMap::new()),
+			applied_updates: Mutex::new(Vec::new()),
 			chain_monitor: chainmonitor::ChainMonitor::new(chain_source, broadcaster, logger, fee_estimator),
 			update_ret: Mutex::new(Ok(())),
 			next_update_ret: Mutex::new(None),
@@ -110,6 +114,7 @@ impl<'a> chain::Watch for TestChainMonitor<'a> {
 				&mut ::std::io::Cursor::new(&w.0)).unwrap() == update);
 
 		self.latest_monitor_update_id.lock().unwrap().insert(funding_txo.to_channel_id(), (funding_txo, update.update_id));
+		self.applied_updates.lock().unwrap().push((funding_txo, update.clone()));
 		assert!(self.chain_monitor.update_channel(funding_txo, update).is_ok());
 		// At every point where we get a monitor update, we should be able to send a useful monitor
 		// to a watchtower and disk...
@@ -134,6 +139,28 @@ impl<'a> chain::Watch for TestChainMonitor<'a> {
 	}
 }
 
+pub struct TestPersister {
+	pub archived_channels: Mutex<Vec<OutPoint>>,
+	pub archive_ret: Mutex<Result<(), ()>>,
+}
+impl TestPersister {
+	pub fn new() -> Self {
+		Self {
+			archived_channels: Mutex::new(Vec::new()),
+			archive_ret: Mutex::new(Ok(())),
+		}
+	}
+}
+impl<Keys: keysinterface::ChannelKeys> channelmonitor::Persist<Keys> for TestPersister {
+	fn archive_persisted_channel(&self, funding_txo: OutPoint, _monitor: &channelmonitor::ChannelMonitor<Keys>) -> Result<(), ()> {
+		let ret = self.archive_ret.lock().unwrap().clone();
+		if ret.is_ok() {
+			self.archived_channels.lock().unwrap().push(funding_txo);
+		}
+		ret
+	}
+}
+
 pub struct TestBroadcaster {
 	pub txn_broadcasted: Mutex<Vec<Transaction>>,
 }
@@ -266,6 +293,10 @@ impl msgs::ChannelMessageHandler for ChannelMessageHandlerTestSpy {
 		self.called.lock().unwrap().peer_connected = true;
 	}
 
+	fn provided_init_features(&self) -> InitFeatures {
+		InitFeatures::known()
+	}
+
 	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &ChannelReestablish) {
 		self.called.lock().unwrap().handle_channel_reestablish = true;
 	}
@@ -312,6 +343,7 @@ impl msgs::ChannelMessageHandler for TestChannelMessageHandler {
 	fn handle_channel_reestablish(&self, _their_node_id: &PublicKey, _msg: &msgs::ChannelReestablish) {}
 	fn peer_disconnected(&self, _their_node_id: &PublicKey, _no_connection_possible: bool) {}
 	fn peer_connected(&self, _their_node_id: &PublicKey, _msg: &msgs::Init) {}
+	fn provided_init_features(&self) -> InitFeatures { InitFeatures::known() }
 	fn handle_error(&self, _their_node_id: &PublicKey, _msg: &msgs::ErrorMessage) {}
 }
 
@@ -394,6 +426,10 @@ impl<'a> ChannelMessageHandler for &'a TestChannelMessageHandler {
 		TestChannelMessageHandler::peer_connected(self, their_node_id, msg);
 	}
 
+	fn provided_init_features(&self) -> InitFeatures {
+		TestChannelMessageHandler::provided_init_features(self)
+	}
+
 	fn handle_channel_reestablish(&self, their_node_id: &PublicKey, msg: &ChannelReestablish) {
 		TestChannelMessageHandler::handle_channel_reestablish(self, their_node_id, msg);
 	}
@@ -462,7 +498,8 @@ pub struct RoutingMessageHandlerTestStub {
 	pub handle_node_announcement_return: Result<bool, LightningError>,
 	pub handle_channel_announcement_return: Result<bool, LightningError>,
 	pub handle_channel_update_return: Result<bool, LightningError>,
-	pub should_request_full_sync_return: bool
+	pub should_request_full_sync_return: bool,
+	pub sync_routing_table_return: (Option<GossipTimestampFilter>, Option<QueryChannelRange>),
 }
 
 impl RoutingMessageHandlerTestStub {
@@ -471,7 +508,8 @@ impl RoutingMessageHandlerTestStub {
 			handle_node_announcement_return: Ok(true),
 			handle_channel_announcement_return: Ok(true),
 			handle_channel_update_return: Ok(true),
-			should_request_full_sync_return: true
+			should_request_full_sync_return: true,
+			sync_routing_table_return: (None, None),
 		}
 	}
 }
@@ -502,6 +540,30 @@ impl RoutingMessageHandler for RoutingMessageHandlerTestStub {
 	fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool {
 		self.should_request_full_sync_return
 	}
+
+	fn sync_routing_table(&self, _their_node_id: &PublicKey, _full_sync: bool) -> (Option<GossipTimestampFilter>, Option<QueryChannelRange>) {
+		self.sync_routing_table_return.clone()
+	}
+
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, LightningError> {
+		Ok(vec![])
+	}
+
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &ReplyChannelRange) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		Ok(None)
+	}
+
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(Vec<(Option<ChannelAnnouncement>, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>, ReplyShortChannelIdsEnd), LightningError> {
+		Ok((vec![], vec![], ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: false }))
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &ReplyShortChannelIdsEnd) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		Ok(None)
+	}
+
+	fn handle_gossip_timestamp_filter(&self, _their_node_id: &PublicKey, _msg: &GossipTimestampFilter) -> Result<(), LightningError> {
+		Ok(())
+	}
 }
 
 /// Test Spy infrastructure for the RoutingMessageHandler trait that tests can use to validate
@@ -533,6 +595,12 @@ generate_routing_handler_called_fns!(
 	get_next_channel_announcements,
 	get_next_node_announcements,
 	should_request_full_sync,
+	sync_routing_table,
+	handle_query_channel_range,
+	handle_reply_channel_range,
+	handle_query_short_channel_ids,
+	handle_reply_short_channel_ids_end,
+	handle_gossip_timestamp_filter,
 );
 
 pub struct RoutingMessageHandlerTestSpy {
@@ -581,6 +649,36 @@ impl RoutingMessageHandler for RoutingMessageHandlerTestSpy {
 		self.called.lock().unwrap().should_request_full_sync = true;
 		true
 	}
+
+	fn sync_routing_table(&self, _their_node_id: &PublicKey, _full_sync: bool) -> (Option<GossipTimestampFilter>, Option<QueryChannelRange>) {
+		self.called.lock().unwrap().sync_routing_table = true;
+		(None, None)
+	}
+
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &QueryChannelRange) -> Result<Vec<ReplyChannelRange>, LightningError> {
+		self.called.lock().unwrap().handle_query_channel_range = true;
+		Ok(vec![])
+	}
+
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &ReplyChannelRange) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		self.called.lock().unwrap().handle_reply_channel_range = true;
+		Ok(None)
+	}
+
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> Result<(Vec<(Option<ChannelAnnouncement>, Option<ChannelUpdate>, Option<ChannelUpdate>)>, Vec<NodeAnnouncement>, ReplyShortChannelIdsEnd), LightningError> {
+		self.called.lock().unwrap().handle_query_short_channel_ids = true;
+		Ok((vec![], vec![], ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: false }))
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &ReplyShortChannelIdsEnd) -> Result<Option<QueryShortChannelIds>, LightningError> {
+		self.called.lock().unwrap().handle_reply_short_channel_ids_end = true;
+		Ok(None)
+	}
+
+	fn handle_gossip_timestamp_filter(&self, _their_node_id: &PublicKey, _msg: &GossipTimestampFilter) -> Result<(), LightningError> {
+		self.called.lock().unwrap().handle_gossip_timestamp_filter = true;
+		Ok(())
+	}
 }
 
 pub struct TestRoutingMessageHandler {
@@ -636,6 +734,40 @@ impl msgs::RoutingMessageHandler for TestRoutingMessageHandler {
 	fn should_request_full_sync(&self, _node_id: &PublicKey) -> bool {
 		self.request_full_sync.load(Ordering::Acquire)
 	}
+
+	fn sync_routing_table(&self, _their_node_id: &PublicKey, full_sync: bool) -> (Option<msgs::GossipTimestampFilter>, Option<msgs::QueryChannelRange>) {
+		// Ask for all of the peer's gossip if we want a full sync
+		if full_sync {
+			(Some(msgs::GossipTimestampFilter { chain_hash: Default::default(), first_timestamp: 0, timestamp_range: 0xffff_ffff }), None)
+		} else {
+			(None, None)
+		}
+	}
+
+	fn handle_query_channel_range(&self, _their_node_id: &PublicKey, _msg: &msgs::QueryChannelRange) -> Result<Vec<msgs::ReplyChannelRange>, msgs::LightningError> {
+		Ok(Vec::new())
+	}
+
+	fn handle_reply_channel_range(&self, _their_node_id: &PublicKey, _msg: &msgs::ReplyChannelRange) -> Result<Option<msgs::QueryShortChannelIds>, msgs::LightningError> {
+		Ok(None)
+	}
+
+	fn handle_query_short_channel_ids(&self, _their_node_id: &PublicKey, msg: &msgs::QueryShortChannelIds) -> Result<(Vec<(Option<msgs::ChannelAnnouncement>, Option<msgs::ChannelUpdate>, Option<msgs::ChannelUpdate>)>, Vec<msgs::NodeAnnouncement>, msgs::ReplyShortChannelIdsEnd), msgs::LightningError> {
+		// Answer each query with a channel announcement and both updates
+		let chan_anns: Vec<_> = msg.short_channel_ids.iter().map(|scid| {
+			(Some(get_dummy_channel_announcement(*scid)), Some(get_dummy_channel_update(*scid)), Some(get_dummy_channel_update(*scid)))
+		}).collect();
+		self.chan_anns_sent.fetch_add(chan_anns.len(), Ordering::AcqRel);
+		Ok((chan_anns, Vec::new(), msgs::ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: true }))
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, _their_node_id: &PublicKey, _msg: &msgs::ReplyShortChannelIdsEnd) -> Result<Option<msgs::QueryShortChannelIds>, msgs::LightningError> {
+		Ok(None)
+	}
+
+	fn handle_gossip_timestamp_filter(&self, _their_node_id: &PublicKey, _msg: &msgs::GossipTimestampFilter) -> Result<(), msgs::LightningError> {
+		Ok(())
+	}
 }
 
 pub struct TestLogger {
@@ -644,6 +776,10 @@ pub struct TestLogger {
 	pub lines: Mutex<HashMap<(String, String), usize>>,
 }
 
+impl Default for TestLogger {
+	fn default() -> TestLogger { Self::new() }
+}
+
 impl TestLogger {
 	pub fn new() -> TestLogger {
 		Self::with_id("".to_owned())
@@ -701,16 +837,22 @@ pub struct TestKeysInterface {
 	backing: keysinterface::KeysManager,
 	pub override_session_priv: Mutex<Option<[u8; 32]>>,
 	pub override_channel_id_priv: Mutex<Option<[u8; 32]>>,
+	pub override_destination_script: Mutex<Option<Script>>,
 }
 
 impl keysinterface::KeysInterface for TestKeysInterface {
 	type ChanKeySigner = EnforcingChannelKeys;
 
 	fn get_node_secret(&self) -> SecretKey { self.backing.get_node_secret() }
-	fn get_destination_script(&self) -> Script { self.backing.get_destination_script() }
+	fn get_destination_script(&self) -> Script {
+		match *self.override_destination_script.lock().unwrap() {
+			Some(ref script) => script.clone(),
+			None => self.backing.get_destination_script(),
+		}
+	}
 	fn get_shutdown_pubkey(&self) -> PublicKey { self.backing.get_shutdown_pubkey() }
-	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> EnforcingChannelKeys {
-		EnforcingChannelKeys::new(self.backing.get_channel_keys(inbound, channel_value_satoshis))
+	fn get_channel_keys(&self, inbound: bool, channel_value_satoshis: u64) -> Result<EnforcingChannelKeys, ()> {
+		Ok(EnforcingChannelKeys::new(self.backing.get_channel_keys(inbound, channel_value_satoshis)?))
 	}
 
 	fn get_secure_random_bytes(&self) -> [u8; 32] {
@@ -736,11 +878,15 @@ impl TestKeysInterface {
 			backing: keysinterface::KeysManager::new(seed, network, now.as_secs(), now.subsec_nanos()),
 			override_session_priv: Mutex::new(None),
 			override_channel_id_priv: Mutex::new(None),
+			override_destination_script: Mutex::new(None),
 		}
 	}
 	pub fn derive_channel_keys(&self, channel_value_satoshis: u64, user_id_1: u64, user_id_2: u64) -> EnforcingChannelKeys {
 		EnforcingChannelKeys::new(self.backing.derive_channel_keys(channel_value_satoshis, user_id_1, user_id_2))
 	}
+	pub fn keys_manager(&self) -> &keysinterface::KeysManager {
+		&self.backing
+	}
 }
 
 pub struct TestChainSource {
@@ -781,3 +927,19 @@ impl chain::Filter for TestChainSource {
 		self.watched_outputs.lock().unwrap().insert((*outpoint, script_pubkey.clone()));
 	}
 }
+
+pub struct TestTower {
+	pub blobs: Mutex<Vec<watchtower::JusticeBlob>>,
+}
+
+impl TestTower {
+	pub fn new() -> Self {
+		Self { blobs: Mutex::new(Vec::new()) }
+	}
+}
+
+impl watchtower::Tower for TestTower {
+	fn send_blob(&self, blob: &watchtower::JusticeBlob) {
+		self.blobs.lock().unwrap().push(blob.clone());
+	}
+}
@@ -0,0 +1,482 @@
+// This file is Copyright its original authors, visible in version control
+// history.
+//
+// This file is licensed under the Apache License, Version 2.0 <LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your option.
+// You may not use this file except in accordance with one or both of these
+// licenses.
+
+//! A minimal zlib (RFC 1950) / DEFLATE (RFC 1951) implementation, used for the zlib encoding of
+//! collections in gossip queries (BOLT #7 encoding_type 1).
+//!
+//! Decompression supports all three DEFLATE block types and enforces a maximum output length, so
+//! that a peer cannot make us allocate unbounded memory. Compression uses a simple greedy LZ77
+//! matcher and emits a single block with the fixed Huffman codes, which is more than enough for
+//! the sorted integer lists we compress.
+
+use std::cmp;
+
+const MAX_BITS: usize = 15;
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order in which code length code lengths are given in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn adler32(data: &[u8]) -> u32 {
+	let (mut a, mut b) = (1u32, 0u32);
+	// 5552 is the largest n such that 255n(n+1)/2 + (n+1)(65520) fits in a u32
+	for chunk in data.chunks(5552) {
+		for byte in chunk {
+			a += *byte as u32;
+			b += a;
+		}
+		a %= 65521;
+		b %= 65521;
+	}
+	(b << 16) | a
+}
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	bit_buf: u32,
+	bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+	fn bits(&mut self, count: u32) -> Result<u32, ()> {
+		while self.bit_count < count {
+			if self.pos >= self.data.len() { return Err(()); }
+			self.bit_buf |= (self.data[self.pos] as u32) << self.bit_count;
+			self.pos += 1;
+			self.bit_count += 8;
+		}
+		let res = self.bit_buf & ((1u32 << count) - 1);
+		self.bit_buf >>= count;
+		self.bit_count -= count;
+		Ok(res)
+	}
+
+	/// Discards any bits left in the current byte.
+	fn align(&mut self) {
+		self.bit_buf = 0;
+		self.bit_count = 0;
+	}
+
+	fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ()> {
+		if self.data.len() - self.pos < len { return Err(()); }
+		let res = &self.data[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(res)
+	}
+}
+
+/// A canonical Huffman decoding table, stored as the number of codes of each length and the
+/// symbols ordered by code.
+struct Huffman {
+	counts: [u16; MAX_BITS + 1],
+	symbols: Vec<u16>,
+}
+
+impl Huffman {
+	fn new(lengths: &[u8]) -> Result<Huffman, ()> {
+		let mut counts = [0u16; MAX_BITS + 1];
+		for len in lengths {
+			counts[*len as usize] += 1;
+		}
+		// Reject over-subscribed code sets. Incomplete sets are permitted, as they are legitimately
+		// produced for distance codes with a single used symbol.
+		let mut left: i32 = 1;
+		for len in 1..MAX_BITS + 1 {
+			left <<= 1;
+			left -= counts[len] as i32;
+			if left < 0 { return Err(()); }
+		}
+
+		let mut offsets = [0u16; MAX_BITS + 1];
+		for len in 1..MAX_BITS {
+			offsets[len + 1] = offsets[len] + counts[len];
+		}
+		let mut symbols = vec![0u16; lengths.len()];
+		for (symbol, len) in lengths.iter().enumerate() {
+			if *len != 0 {
+				symbols[offsets[*len as usize] as usize] = symbol as u16;
+				offsets[*len as usize] += 1;
+			}
+		}
+		Ok(Huffman { counts, symbols })
+	}
+
+	fn decode(&self, reader: &mut BitReader) -> Result<u16, ()> {
+		let mut code: i32 = 0;
+		let mut first: i32 = 0;
+		let mut index: i32 = 0;
+		for len in 1..MAX_BITS + 1 {
+			code |= reader.bits(1)? as i32;
+			let count = self.counts[len] as i32;
+			if code - count < first {
+				return Ok(self.symbols[(index + (code - first)) as usize]);
+			}
+			index += count;
+			first += count;
+			first <<= 1;
+			code <<= 1;
+		}
+		Err(())
+	}
+}
+
+fn fixed_huffman() -> (Huffman, Huffman) {
+	let mut lengths = [0u8; 288];
+	for (symbol, len) in lengths.iter_mut().enumerate() {
+		*len = match symbol {
+			0...143 => 8,
+			144...255 => 9,
+			256...279 => 7,
+			_ => 8,
+		};
+	}
+	(Huffman::new(&lengths).unwrap(), Huffman::new(&[5u8; 30]).unwrap())
+}
+
+fn dynamic_huffman(reader: &mut BitReader) -> Result<(Huffman, Huffman), ()> {
+	let lit_count = reader.bits(5)? as usize + 257;
+	let dist_count = reader.bits(5)? as usize + 1;
+	let code_length_count = reader.bits(4)? as usize + 4;
+	if lit_count > 286 || dist_count > 30 { return Err(()); }
+
+	let mut code_length_lengths = [0u8; 19];
+	for idx in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+		code_length_lengths[*idx] = reader.bits(3)? as u8;
+	}
+	let code_length_code = Huffman::new(&code_length_lengths)?;
+
+	let mut lengths = vec![0u8; lit_count + dist_count];
+	let mut idx = 0;
+	while idx < lengths.len() {
+		let symbol = code_length_code.decode(reader)?;
+		if symbol < 16 {
+			lengths[idx] = symbol as u8;
+			idx += 1;
+			continue;
+		}
+		let (value, repeat) = match symbol {
+			16 => {
+				if idx == 0 { return Err(()); }
+				(lengths[idx - 1], 3 + reader.bits(2)? as usize)
+			},
+			17 => (0, 3 + reader.bits(3)? as usize),
+			_ => (0, 11 + reader.bits(7)? as usize),
+		};
+		if idx + repeat > lengths.len() { return Err(()); }
+		for len in lengths[idx..idx + repeat].iter_mut() {
+			*len = value;
+		}
+		idx += repeat;
+	}
+	// Without an end-of-block code the block could never terminate
+	if lengths[END_OF_BLOCK as usize] == 0 { return Err(()); }
+
+	Ok((Huffman::new(&lengths[..lit_count])?, Huffman::new(&lengths[lit_count..])?))
+}
+
+fn inflate_block(reader: &mut BitReader, lit_code: &Huffman, dist_code: &Huffman, out: &mut Vec<u8>, max_len: usize) -> Result<(), ()> {
+	loop {
+		let symbol = lit_code.decode(reader)?;
+		if symbol < 256 {
+			if out.len() >= max_len { return Err(()); }
+			out.push(symbol as u8);
+		} else if symbol == END_OF_BLOCK {
+			return Ok(());
+		} else {
+			let symbol = (symbol - 257) as usize;
+			if symbol >= LENGTH_BASE.len() { return Err(()); }
+			let len = LENGTH_BASE[symbol] as usize + reader.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+			let dist_symbol = dist_code.decode(reader)? as usize;
+			if dist_symbol >= DIST_BASE.len() { return Err(()); }
+			let dist = DIST_BASE[dist_symbol] as usize + reader.bits(DIST_EXTRA[dist_symbol] as u32)? as usize;
+			if dist > out.len() || out.len() + len > max_len { return Err(()); }
+			let start = out.len() - dist;
+			for i in 0..len {
+				let byte = out[start + i];
+				out.push(byte);
+			}
+		}
+	}
+}
+
+/// Decompresses a zlib stream, failing if it is malformed, carries a preset dictionary, fails its
+/// checksum, has trailing data or would decompress to more than max_len bytes.
+pub(crate) fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, ()> {
+	if data.len() < 2 + 4 { return Err(()); }
+	let (cmf, flg) = (data[0], data[1]);
+	if cmf & 0x0f != 8 || cmf >> 4 > 7 || ((cmf as u16) << 8 | flg as u16) % 31 != 0 || flg & 0x20 != 0 {
+		return Err(());
+	}
+
+	let mut reader = BitReader { data: &data[2..], pos: 0, bit_buf: 0, bit_count: 0 };
+	let mut out = Vec::new();
+	loop {
+		let last_block = reader.bits(1)? == 1;
+		match reader.bits(2)? {
+			0 => {
+				reader.align();
+				let header = reader.read_bytes(4)?;
+				let len = header[0] as usize | (header[1] as usize) << 8;
+				let nlen = header[2] as usize | (header[3] as usize) << 8;
+				if len != !nlen & 0xffff || out.len() + len > max_len { return Err(()); }
+				out.extend_from_slice(reader.read_bytes(len)?);
+			},
+			1 => {
+				let (lit_code, dist_code) = fixed_huffman();
+				inflate_block(&mut reader, &lit_code, &dist_code, &mut out, max_len)?;
+			},
+			2 => {
+				let (lit_code, dist_code) = dynamic_huffman(&mut reader)?;
+				inflate_block(&mut reader, &lit_code, &dist_code, &mut out, max_len)?;
+			},
+			_ => return Err(()),
+		}
+		if last_block { break; }
+	}
+
+	reader.align();
+	let trailer = reader.read_bytes(4)?;
+	if reader.pos != reader.data.len() { return Err(()); }
+	let checksum = (trailer[0] as u32) << 24 | (trailer[1] as u32) << 16 | (trailer[2] as u32) << 8 | trailer[3] as u32;
+	if checksum != adler32(&out) { return Err(()); }
+	Ok(out)
+}
+
+struct BitWriter {
+	out: Vec<u8>,
+	bit_buf: u32,
+	bit_count: u32,
+}
+
+impl BitWriter {
+	fn bits(&mut self, value: u32, count: u32) {
+		self.bit_buf |= value << self.bit_count;
+		self.bit_count += count;
+		while self.bit_count >= 8 {
+			self.out.push(self.bit_buf as u8);
+			self.bit_buf >>= 8;
+			self.bit_count -= 8;
+		}
+	}
+
+	/// Huffman codes are packed starting from their most significant bit.
+	fn huffman_code(&mut self, code: u32, len: u32) {
+		let mut reversed = 0;
+		for i in 0..len {
+			reversed |= ((code >> i) & 1) << (len - 1 - i);
+		}
+		self.bits(reversed, len);
+	}
+
+	fn literal(&mut self, symbol: u16) {
+		let symbol = symbol as u32;
+		match symbol {
+			0...143 => self.huffman_code(0x30 + symbol, 8),
+			144...255 => self.huffman_code(0x190 + symbol - 144, 9),
+			256...279 => self.huffman_code(symbol - 256, 7),
+			_ => self.huffman_code(0xc0 + symbol - 280, 8),
+		}
+	}
+
+	fn flush(&mut self) {
+		if self.bit_count > 0 {
+			self.out.push(self.bit_buf as u8);
+			self.bit_buf = 0;
+			self.bit_count = 0;
+		}
+	}
+}
+
+fn code_index(base: &[u16], value: usize) -> usize {
+	base.iter().rposition(|b| *b as usize <= value).unwrap()
+}
+
+fn push_adler32(out: &mut Vec<u8>, data: &[u8]) {
+	let checksum = adler32(data);
+	out.extend_from_slice(&[(checksum >> 24) as u8, (checksum >> 16) as u8, (checksum >> 8) as u8, checksum as u8]);
+}
+
+/// Wraps data in stored (uncompressed) blocks.
+fn compress_stored(data: &[u8]) -> Vec<u8> {
+	let block_count = cmp::max(1, (data.len() + 0xfffe) / 0xffff);
+	let mut out = Vec::with_capacity(2 + block_count * 5 + data.len() + 4);
+	out.extend_from_slice(&[0x78, 0x01]);
+	for block in 0..block_count {
+		let chunk = &data[block * 0xffff..cmp::min(data.len(), (block + 1) * 0xffff)];
+		out.push(if block == block_count - 1 { 1 } else { 0 });
+		let len = chunk.len() as u16;
+		out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+		out.extend_from_slice(chunk);
+	}
+	push_adler32(&mut out, data);
+	out
+}
+
+/// Compresses data into a zlib stream, which is never more than a few bytes larger than the input.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+	let compressed = compress_fixed(data);
+	let stored_len = 2 + cmp::max(1, (data.len() + 0xfffe) / 0xffff) * 5 + data.len() + 4;
+	if compressed.len() > stored_len {
+		compress_stored(data)
+	} else {
+		compressed
+	}
+}
+
+fn compress_fixed(data: &[u8]) -> Vec<u8> {
+	let mut writer = BitWriter { out: vec![0x78, 0x01], bit_buf: 0, bit_count: 0 };
+	// A single, final block using the fixed Huffman codes
+	writer.bits(1, 1);
+	writer.bits(1, 2);
+
+	// The most recent position at which each 3-byte hash was seen
+	const HASH_BITS: u32 = 12;
+	let mut last_pos = vec![usize::max_value(); 1 << HASH_BITS];
+	let hash = |pos: usize| -> usize {
+		let v = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+		(v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+	};
+
+	let mut pos = 0;
+	while pos < data.len() {
+		let mut match_len = 0;
+		let mut match_dist = 0;
+		if pos + MIN_MATCH <= data.len() {
+			let h = hash(pos);
+			let candidate = last_pos[h];
+			last_pos[h] = pos;
+			if candidate != usize::max_value() && pos - candidate <= WINDOW_SIZE {
+				let max_len = cmp::min(MAX_MATCH, data.len() - pos);
+				let mut len = 0;
+				while len < max_len && data[candidate + len] == data[pos + len] {
+					len += 1;
+				}
+				if len >= MIN_MATCH {
+					match_len = len;
+					match_dist = pos - candidate;
+				}
+			}
+		}
+
+		if match_len == 0 {
+			writer.literal(data[pos] as u16);
+			pos += 1;
+			continue;
+		}
+
+		let len_idx = code_index(&LENGTH_BASE, match_len);
+		writer.literal(257 + len_idx as u16);
+		writer.bits((match_len - LENGTH_BASE[len_idx] as usize) as u32, LENGTH_EXTRA[len_idx] as u32);
+		let dist_idx = code_index(&DIST_BASE, match_dist);
+		writer.huffman_code(dist_idx as u32, 5);
+		writer.bits((match_dist - DIST_BASE[dist_idx] as usize) as u32, DIST_EXTRA[dist_idx] as u32);
+
+		// Index the positions we skipped over so later matches can refer back to them
+		for skipped in pos + 1..pos + match_len {
+			if skipped + MIN_MATCH <= data.len() {
+				last_pos[hash(skipped)] = skipped;
+			}
+		}
+		pos += match_len;
+	}
+	writer.literal(END_OF_BLOCK);
+	writer.flush();
+	push_adler32(&mut writer.out, data);
+	writer.out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{compress, decompress};
+
+	use util::byte_utils::be64_to_array;
+
+	use hex;
+
+	#[test]
+	fn decompress_bolt7_short_channel_ids() {
+		// The zlib-encoded short_channel_ids used in the BOLT #7 encoding examples
+		let compressed = hex::decode("789c636000833e08659309a65878be010010a9023a").unwrap();
+		assert_eq!(decompress(&compressed, 1000).unwrap(), hex::decode("000000000000008e0000000000003c690000000000040cf6").unwrap());
+	}
+
+	#[test]
+	fn decompress_stored_and_dynamic_blocks() {
+		// A stored block, as produced by zlib with compression level 0
+		let stored = hex::decode("7801010500faff68656c6c6f062c0215").unwrap();
+		assert_eq!(decompress(&stored, 5).unwrap(), b"hello");
+
+		// A dynamic Huffman block produced by zlib with its default settings
+		let dynamic = hex::decode(DYNAMIC_BLOCK).unwrap();
+		assert_eq!(dynamic[2] & 0b110, 0b100);
+		assert_eq!(decompress(&dynamic, 10000).unwrap(), dynamic_block_contents());
+	}
+
+	const DYNAMIC_BLOCK: &str = "789cedd0471100410c03412a03c139f027768be010f8ed92a56aa1d1c0141b3c0923962cca69a19b0956d9215143172bdc09219a0c4aa9a1933166d97745056d2c70c58748d2c8a58a764698660365d0c40c5bbc0827856c2a68a58749d6d897441d13acf12094788392326ae9629c15b6795f151d2c71c39728d229a19a0ee6950efb5a59b430c7057fc120951c2a69a39729d691133aa1133aa1133aa1133aa1133aa15fa10f522a0487";
+	fn dynamic_block_contents() -> Vec<u8> {
+		let mut res = Vec::new();
+		for i in 0..1000u32 {
+			res.extend_from_slice(format!("{} ", i * 7 % 100).as_bytes());
+		}
+		res
+	}
+
+	#[test]
+	fn decompress_rejects_malformed_data() {
+		let compressed = hex::decode("789c636000833e08659309a65878be010010a9023a").unwrap();
+		// Output exceeding the maximum length
+		assert!(decompress(&compressed, 23).is_err());
+		// Bad header check bits
+		let mut bad_header = compressed.clone();
+		bad_header[1] = 0x9d;
+		assert!(decompress(&bad_header, 1000).is_err());
+		// Bad adler32 checksum
+		let mut bad_checksum = compressed.clone();
+		let last = bad_checksum.len() - 1;
+		bad_checksum[last] ^= 1;
+		assert!(decompress(&bad_checksum, 1000).is_err());
+		// Truncated and trailing data
+		assert!(decompress(&compressed[..compressed.len() - 1], 1000).is_err());
+		let mut trailing = compressed.clone();
+		trailing.push(0);
+		assert!(decompress(&trailing, 1000).is_err());
+		// Reserved block type
+		assert!(decompress(&hex::decode("78010700000001").unwrap(), 1000).is_err());
+	}
+
+	#[test]
+	fn compress_round_trips() {
+		let mut sorted_scids = Vec::new();
+		for i in 0..8000u64 {
+			sorted_scids.extend_from_slice(&be64_to_array((600_000 + i / 3) << 40 | (i % 3) << 16 | (i % 2)));
+		}
+		let compressed = compress(&sorted_scids);
+		assert!(compressed.len() < sorted_scids.len() / 2);
+		assert_eq!(decompress(&compressed, sorted_scids.len()).unwrap(), sorted_scids);
+
+		let long_run = vec![0x42; 100_000];
+		assert_eq!(decompress(&compress(&long_run), long_run.len()).unwrap(), long_run);
+
+		// Incompressible data falls back to stored blocks rather than growing
+		let noise: Vec<u8> = (0..70000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+		let compressed = compress(&noise);
+		assert!(compressed.len() <= noise.len() + 16);
+		assert_eq!(decompress(&compressed, noise.len()).unwrap(), noise);
+
+		assert_eq!(decompress(&compress(&[]), 0).unwrap(), Vec::<u8>::new());
+	}
+}